@@ -1,15 +1,35 @@
-use std::{env, error::Error, net::SocketAddr, sync::Arc, time::Duration};
+use std::{cell::RefCell, env, error::Error, net::SocketAddr};
 
 use chrono::Local;
-use flip_flop_app::{CommandRequest, EventOf, EventReply, NoEE};
-use tokio::{
-    net::UdpSocket,
-    time::{self, Instant},
+use flip_flop_app::{
+    client::{ClientConfig, ClientEngine, Delivery},
+    transport::{
+        run_client,
+        udp::{TokioClock, UdpClientTransport},
+    },
+    EventOf, Logged, MultiCommandRequest, NoEE,
 };
 
 #[path = "../common/lib.rs"]
 mod common;
-use crate::common::{Command, Event};
+use crate::common::{Command, Event, CLIENT_TICK_RATE};
+
+// This size should never exceed what can be sent in one packet. If you
+// have needs that exceed this constraint then you will need to consider
+// framing.
+const MAX_DATAGRAM_SIZE: usize = 32;
+// The most events that a server replies with at a time.
+const MAX_EVENTS_PER_REPLY: usize = 4;
+// The most commands that we send in a request.
+const MAX_COMMANDS_PER_REQUEST: usize = 4;
+
+type Transport = UdpClientTransport<MAX_DATAGRAM_SIZE>;
+
+// Our requests must fit within a datagram, and so this fails to compile
+// should our commands grow too large.
+const _: () = assert!(Transport::fits::<
+    MultiCommandRequest<Command, MAX_COMMANDS_PER_REQUEST>,
+>());
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -17,103 +37,56 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .nth(1)
         .unwrap_or_else(|| "127.0.0.1:8080".into())
         .parse()?;
+    let mut transport = Transport::connect(remote_addr).await?;
+    println!("CLIENT: listening on {:?}", transport.local_addr()?);
 
-    let local_addr: SocketAddr = if remote_addr.is_ipv4() {
-        "0.0.0.0:0"
-    } else {
-        "[::]:0"
-    }
-    .parse()?;
-
-    let socket = UdpSocket::bind(local_addr).await?;
-
-    let r = Arc::new(socket);
-    let s = r.clone();
-
-    // This size should never exceed what can be sent in one packet. If you
-    // have needs that exceed this constraint then you will need to consider
-    // framing.
-    const MAX_DATAGRAM_SIZE: usize = 32;
-
-    let mut last_event_offset = None;
-    let mut event_count = 0_u32;
+    // The engine decides when to poll our server, conveying our time with
+    // each request, being our ticks since we started, so that the times of
+    // events are told with our clock.
+    let engine = RefCell::new(ClientEngine::<
+        _,
+        _,
+        Event,
+        1,
+        MAX_COMMANDS_PER_REQUEST,
+        MAX_EVENTS_PER_REPLY,
+        MAX_DATAGRAM_SIZE,
+    >::new(ClientConfig {
+        tick_rate: CLIENT_TICK_RATE,
+        reply_timeout_ticks: 100,
+        client_time: true,
+        max_reply_len: false,
+    }));
+    engine.borrow_mut().add_server(remote_addr, 1_000).unwrap();
+    let (epoch, clock) = (Local::now(), TokioClock::new(CLIENT_TICK_RATE));
 
-    println!("CLIENT: listening on {:?}", local_addr);
-
-    let mut next_send_time = Instant::now();
-
-    let mut init_mode = true;
-    let mut recovery_event_offset = None;
-
-    loop {
-        // Wake at a regular interval which is what we need to do
-        // to cycle predictably through our servers when operating in
-        // half duplex mode such that they all get some airtime.
-        time::sleep_until(next_send_time).await;
-
-        // Tell the server to do something and let it know what we know
-        // of its state by communicating the last event offset we received
-        // for it.
-        let mut send_buf = [0; MAX_DATAGRAM_SIZE];
-        let command = if init_mode {
-            None
-        } else {
-            Some(Command::SomeCommand)
-        };
-        let request = CommandRequest {
-            last_event_offset,
-            command,
-        };
-        if let Ok(encoded_buf) = postcard::to_slice(&request, &mut send_buf) {
-            let _ = s.send_to(encoded_buf, remote_addr).await;
-            println!("CLIENT: {:?} command sent to {:?}", request, remote_addr);
-        }
-
-        // Receive an event from the server. If we don't get anything within
-        // a short timeout then we move on.
-        let mut recv_buf = [0; MAX_DATAGRAM_SIZE];
-        if let Ok(Ok((len, remote_addr))) =
-            time::timeout(Duration::from_millis(100), r.recv_from(&mut recv_buf)).await
-        {
-            if let Ok(reply) =
-                postcard::from_bytes::<EventReply<EventOf<Event, NoEE>>>(&recv_buf[..len])
-            {
-                if let Some(local_time) = Local::now().checked_sub_signed(
-                    chrono::Duration::from_std(Duration::from_secs(reply.delta_ticks))
-                        .unwrap_or(chrono::Duration::seconds(0)),
-                ) {
-                    println!(
-                        "CLIENT: event time {:?} {:?} event {} received from {:?}",
-                        local_time, reply, event_count, remote_addr
-                    );
-                }
-                match reply.event {
-                    Some(EventOf::Recovery(start, end)) => {
-                        println!("CLIENT: Previous events for this server are now forgotten given an offset != what we expected.");
-                        init_mode = true;
-                        event_count = 0;
-                        last_event_offset = Some(start);
-                        recovery_event_offset = if start != end {
-                            println!("CLIENT: Recovering.");
-                            Some(end)
-                        } else {
-                            println!("CLIENT: Recovery complete.");
-                            None
-                        };
-                    }
-                    Some(EventOf::Logged(_, offset)) => {
-                        event_count = event_count.wrapping_add(1);
-                        last_event_offset = Some(offset);
-                        if last_event_offset == recovery_event_offset {
-                            println!("CLIENT: Recovery complete.");
-                            init_mode = true;
-                        }
-                    }
-                    _ => (),
-                }
+    let deliver = |delivery: Delivery<_, EventOf<Event, NoEE>, u32, MAX_EVENTS_PER_REPLY>| {
+        let addr = delivery.address;
+        for (reply, observation) in &delivery.replies {
+            // We can only tell the time of events once we know the rate of
+            // the server's ticks.
+            let local_time = delivery.clock.and_then(|clock| {
+                let time = clock.event_time(reply.delta_ticks);
+                let since_epoch = CLIENT_TICK_RATE.to_duration(time.ticks);
+                epoch.checked_add_signed(chrono::Duration::from_std(since_epoch).ok()?)
+            });
+            match reply.event.clone().map(Logged::try_from) {
+                Some(Ok(Logged(event, offset))) => println!(
+                    "CLIENT: event time {local_time:?} {event:?} at offset {offset} received from {addr:?}, {observation:?}"
+                ),
+                _ => println!(
+                    "CLIENT: event time {local_time:?} {reply:?} received from {addr:?}, {observation:?}"
+                ),
             }
         }
+        // We only command a server once our state reflects its own.
+        let mut engine = engine.borrow_mut();
+        if engine.tracker(&addr).is_some_and(|t| t.is_synchronised()) {
+            let _ = engine.command(&addr, Command::SomeCommand);
+        }
+    };
 
-        next_send_time += Duration::from_secs(1); // Bit of a problem when we reach the end of time... ;-)
-    }
+    Err(run_client(&engine, &mut transport, &clock, deliver)
+        .await
+        .into())
 }
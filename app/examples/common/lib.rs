@@ -1,11 +1,16 @@
+use flip_flop_app::TickRate;
+use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+/// The rate of the client's ticks, which it conveys its time in.
+pub const CLIENT_TICK_RATE: TickRate = TickRate::MILLISECONDS;
+
+#[derive(Debug, Deserialize, MaxSize, Serialize)]
 pub enum Command {
     SomeCommand,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, MaxSize, Serialize)]
 pub enum Event {
     SomeEvent,
 }
@@ -0,0 +1,180 @@
+//! A server run by embassy without allocating, its engine being shared by
+//! the tasks that log events and convey datagrams, and a task replying to a
+//! client's discovery. The client is run by another task here so that the
+//! example runs on the host, with a pipe in place of the halves of a UART
+//! that a device would use, e.g. those of `embassy_stm32::usart::Uart`
+//! once split.
+
+use core::{cell::RefCell, time::Duration};
+
+use embassy_executor::Spawner;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel, pipe::Pipe};
+use embassy_time::Timer;
+use flip_flop_app::{
+    client::{ClientConfig, ClientEngine, Delivery},
+    clock::Clock,
+    event_log::EventLog,
+    server::ServerEngine,
+    status::StatusReporter,
+    transport::{run_client, run_server, EmbassyClock, Halves, SerialTransport},
+    EventOf, NoEE, ResetCause,
+};
+use flip_flop_data::{
+    discovery::{Identified, Identify},
+    port::{Port, PortSet},
+};
+use serde::{Deserialize, Serialize};
+use static_cell::StaticCell;
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Command {
+    SomeCommand,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum Event {
+    SomeEvent,
+}
+
+const MAX_DATAGRAM_SIZE: usize = 32;
+const MAX_EVENTS: usize = 10;
+const MAX_EVENTS_PER_REPLY: usize = 4;
+const MAX_COMMANDS_PER_REQUEST: usize = 4;
+
+type Server = ServerEngine<
+    Command,
+    Event,
+    MAX_EVENTS,
+    MAX_COMMANDS_PER_REQUEST,
+    MAX_EVENTS_PER_REPLY,
+    MAX_DATAGRAM_SIZE,
+>;
+type Client = ClientEngine<
+    (),
+    Command,
+    Event,
+    1,
+    MAX_COMMANDS_PER_REQUEST,
+    MAX_EVENTS_PER_REPLY,
+    MAX_DATAGRAM_SIZE,
+>;
+
+// One direction of the bus. A device would instead read from the receiver
+// of its UART and write to its transmitter.
+type Wire = Pipe<NoopRawMutex, 64>;
+type Transport = SerialTransport<Halves<&'static Wire, &'static Wire>, { MAX_DATAGRAM_SIZE + 1 }>;
+
+// Discovery is conveyed on a port of its own, which is modelled here by
+// channels.
+type Discovery = (
+    Channel<NoopRawMutex, Identify, 1>,
+    Channel<NoopRawMutex, Identified, 1>,
+);
+
+static SERVER: StaticCell<RefCell<Server>> = StaticCell::new();
+static CLIENT: StaticCell<RefCell<Client>> = StaticCell::new();
+static REQUESTS: StaticCell<Wire> = StaticCell::new();
+static REPLIES: StaticCell<Wire> = StaticCell::new();
+static DISCOVERY: StaticCell<Discovery> = StaticCell::new();
+
+// Convey the datagrams of the server. The engine is borrowed only while it
+// is not awaiting, and so the other tasks may use it meanwhile.
+#[embassy_executor::task]
+async fn serve(server: &'static RefCell<Server>, mut transport: Transport) {
+    let execute = |command: &Command, _: &mut EventLog<Event, MAX_EVENTS>| {
+        println!("SERVER: executing {command:?}");
+        Ok::<(), ()>(())
+    };
+    let _ = run_server(server, &mut transport, &EmbassyClock, execute).await;
+}
+
+// Log an event each second, as a sensor might.
+#[embassy_executor::task]
+async fn sense(server: &'static RefCell<Server>) {
+    loop {
+        Timer::after_secs(1).await;
+        let now = EmbassyClock.now_ticks();
+        let offset = server.borrow_mut().log_mut().push(Event::SomeEvent, now);
+        println!("SERVER: event stored for offset {offset}");
+    }
+}
+
+// Reply to discovery with the first address unknown to the client and not
+// contested.
+#[embassy_executor::task]
+async fn respond_to_discovery(discovery: &'static Discovery) {
+    loop {
+        let identify = discovery.0.receive().await;
+        let server_address = identify
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(address, known)| !known && !identify.is_contested(*address as u8))
+            .map(|(address, _)| address as u8);
+        if let Some(server_address) = server_address {
+            let identified = Identified {
+                server_address,
+                server_ports: PortSet::of(Port::new(0).unwrap()),
+                details: None,
+            };
+            discovery.1.send(identified).await;
+        }
+    }
+}
+
+// Discover the server and then poll it, as a client on the bus would.
+#[embassy_executor::task]
+async fn poll(
+    client: &'static RefCell<Client>,
+    mut transport: Transport,
+    discovery: &'static Discovery,
+) {
+    discovery.0.send(Identify::new()).await;
+    let identified = discovery.1.receive().await;
+    println!("CLIENT: discovered {identified:?}");
+
+    let poll_interval_ticks = EmbassyClock::TICK_RATE.from_duration(Duration::from_millis(100));
+    client
+        .borrow_mut()
+        .add_server((), poll_interval_ticks)
+        .unwrap();
+    client
+        .borrow_mut()
+        .command(&(), Command::SomeCommand)
+        .unwrap();
+    let deliver = |delivery: Delivery<(), EventOf<Event, NoEE>, u32, MAX_EVENTS_PER_REPLY>| {
+        for (reply, observation) in delivery.replies {
+            println!("CLIENT: {:?} observed as {observation:?}", reply.event);
+        }
+    };
+    let _ = run_client(client, &mut transport, &EmbassyClock, deliver).await;
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let server = SERVER.init(RefCell::new(Server::new(
+        EventLog::new(0),
+        StatusReporter::new(0, EmbassyClock::TICK_RATE, ResetCause::PowerOn),
+    )));
+    let client = CLIENT.init(RefCell::new(Client::new(ClientConfig {
+        tick_rate: EmbassyClock::TICK_RATE,
+        reply_timeout_ticks: EmbassyClock::TICK_RATE.from_duration(Duration::from_millis(50)),
+        client_time: false,
+        max_reply_len: false,
+    })));
+    let requests: &'static Wire = REQUESTS.init(Pipe::new());
+    let replies: &'static Wire = REPLIES.init(Pipe::new());
+    let discovery = DISCOVERY.init((Channel::new(), Channel::new()));
+
+    spawner.must_spawn(serve(
+        server,
+        SerialTransport::from_halves(requests, replies),
+    ));
+    spawner.must_spawn(sense(server));
+    spawner.must_spawn(respond_to_discovery(discovery));
+    spawner.must_spawn(poll(
+        client,
+        SerialTransport::from_halves(replies, requests),
+        discovery,
+    ));
+}
@@ -0,0 +1,144 @@
+use flip_flop_app::{
+    event_log::EventLog,
+    ports::{Port, PortSuite},
+    progress::Progress,
+    server::{Output, ServerEngine},
+    status::StatusReporter,
+    EventBatchReply, EventOf, MultiCommandRequest, NoEE, ResetCause, TickRate,
+};
+use serde::{Deserialize, Serialize};
+
+// A device exposing its lights on one port, and its pump on another, each
+// with its own commands and events.
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum LightsCommand {
+    On,
+    Off,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum LightsEvent {
+    Switched(bool),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum PumpCommand {
+    Run { seconds: u8 },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum PumpEvent {
+    Ran { seconds: u8 },
+}
+
+#[derive(PortSuite)]
+enum Ports {
+    #[port(1)]
+    Lights(LightsCommand, LightsEvent, NoEE),
+    #[port(2)]
+    Pump(PumpCommand, PumpEvent, Progress<u8>),
+}
+
+const LIGHTS_PORT: Port = Ports::PORTS[0];
+const PUMP_PORT: Port = Ports::PORTS[1];
+
+const MAX_DATAGRAM_SIZE: usize = 32;
+const MAX_EVENTS: usize = 8;
+const MAX_EVENTS_PER_REPLY: usize = 4;
+const MAX_COMMANDS_PER_REQUEST: usize = 2;
+
+fn main() {
+    let status = || StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn);
+    let mut lights = ServerEngine::<
+        LightsCommand,
+        LightsEvent,
+        MAX_EVENTS,
+        MAX_COMMANDS_PER_REQUEST,
+        MAX_EVENTS_PER_REPLY,
+        MAX_DATAGRAM_SIZE,
+    >::new(EventLog::new(0), status());
+    let mut pump = ServerEngine::<
+        PumpCommand,
+        PumpEvent,
+        MAX_EVENTS,
+        MAX_COMMANDS_PER_REQUEST,
+        MAX_EVENTS_PER_REPLY,
+        MAX_DATAGRAM_SIZE,
+        Progress<u8>,
+    >::new(EventLog::new(0), status());
+
+    // The requests that a client sends to each port, as the port and the
+    // bytes received on it.
+    let mut lights_request = MultiCommandRequest::<_, MAX_COMMANDS_PER_REQUEST>::new(None);
+    let _ = lights_request.commands.push(LightsCommand::On);
+    let _ = lights_request.commands.push(LightsCommand::Off);
+    let mut pump_request = MultiCommandRequest::<_, MAX_COMMANDS_PER_REQUEST>::new(None);
+    let _ = pump_request.commands.push(PumpCommand::Run { seconds: 5 });
+    let lights_bytes = postcard::to_vec::<_, MAX_DATAGRAM_SIZE>(&lights_request).unwrap();
+    let pump_bytes = postcard::to_vec::<_, MAX_DATAGRAM_SIZE>(&pump_request).unwrap();
+    let received = [
+        (LIGHTS_PORT, &lights_bytes),
+        (PUMP_PORT, &pump_bytes),
+        (LIGHTS_PORT, &lights_bytes),
+        (PUMP_PORT, &pump_bytes),
+        (Port::new(3).unwrap(), &pump_bytes),
+    ];
+
+    for (now, (port, bytes)) in received.iter().enumerate() {
+        let now = now as u64;
+
+        // Each request is dispatched to the engine of the port that it was
+        // received on, which executes the port's commands.
+        let output = match Ports::dispatch_request(*port, bytes) {
+            Ok(PortsRequest::Lights(request)) => lights.handle_request(
+                request,
+                now,
+                |command, log: &mut EventLog<_, MAX_EVENTS>| {
+                    log.push(
+                        LightsEvent::Switched(matches!(command, LightsCommand::On)),
+                        now,
+                    );
+                    Ok::<_, ()>(())
+                },
+            ),
+            Ok(PortsRequest::Pump(request)) => pump.handle_request(
+                request,
+                now,
+                |PumpCommand::Run { seconds }, log: &mut EventLog<_, MAX_EVENTS>| {
+                    log.push(PumpEvent::Ran { seconds: *seconds }, now);
+                    Ok::<_, ()>(())
+                },
+            ),
+            Err(e) => {
+                println!("PORT {port}: ignoring request given {e:?}");
+                continue;
+            }
+        };
+        let Output::Reply(reply_bytes) = output else {
+            println!("PORT {port}: ignoring request given {output:?}");
+            continue;
+        };
+
+        // A client decodes each reply as the events of the port that it was
+        // received on.
+        match *port {
+            LIGHTS_PORT => {
+                let batch: EventBatchReply<EventOf<LightsEvent, NoEE>, MAX_EVENTS_PER_REPLY> =
+                    postcard::from_bytes(reply_bytes).unwrap();
+                println!("PORT {port}: replied {:?}", batch.replies);
+            }
+            _ => {
+                let batch: EventBatchReply<EventOf<PumpEvent, Progress<u8>>, MAX_EVENTS_PER_REPLY> =
+                    postcard::from_bytes(reply_bytes).unwrap();
+                println!("PORT {port}: replied {:?}", batch.replies);
+            }
+        }
+    }
+
+    // Replies may also be encoded for their port by the suite.
+    let mut reply_buf = [0; MAX_DATAGRAM_SIZE];
+    let reply = PortsReply::<MAX_EVENTS_PER_REPLY>::Pump(EventBatchReply::default());
+    let (port, bytes) = Ports::encode_reply(&reply, &mut reply_buf).unwrap();
+    println!("PORT {port}: an empty reply is {bytes:?}");
+}
@@ -1,17 +1,22 @@
-use circular_queue::CircularQueue;
 use rand::prelude::*;
 use std::{env, error::Error, net::SocketAddr, time::Duration};
 
-use flip_flop_app::{CommandRequest, EventOf, NoEE};
+use flip_flop_app::{
+    clock::ClockSync,
+    event_log::EventLog,
+    server::{Output, ServerEngine},
+    status::StatusReporter,
+    transport::{udp::UdpServerTransport, Transport},
+    EventOf, EventReply, NoEE, ResetCause, TickRate,
+};
 use tokio::{
-    net::UdpSocket,
     sync::mpsc,
     time::{self, Instant},
 };
 
 #[path = "../common/lib.rs"]
 mod common;
-use crate::common::{Command, Event};
+use crate::common::{Command, Event, CLIENT_TICK_RATE};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -20,7 +25,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .unwrap_or_else(|| "127.0.0.1:8080".into())
         .parse()?;
 
-    let socket = UdpSocket::bind(local_addr).await?;
+    // This size should never exceed what can be sent in one packet. If you
+    // have needs that exceed this constraint then you will need to consider
+    // framing.
+    const MAX_DATAGRAM_SIZE: usize = 32;
+    let mut transport = UdpServerTransport::<MAX_DATAGRAM_SIZE>::bind(local_addr).await?;
 
     println!("SERVER: listening on {:?}", local_addr);
 
@@ -40,86 +49,86 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     });
 
-    // This size should never exceed what can be sent in one packet. If you
-    // have needs that exceed this constraint then you will need to consider
-    // framing.
-    const MAX_DATAGRAM_SIZE: usize = 32;
     const MAX_EVENTS: usize = 10;
+    // The most events that we reply with at a time, so long as they fit
+    // within a datagram.
+    const MAX_EVENTS_PER_REPLY: usize = 4;
+    // The most commands that we accept in a request.
+    const MAX_COMMANDS_PER_REQUEST: usize = 4;
+    // A reply of at least one event must fit within a datagram, and so this
+    // fails to compile should our events grow too large.
+    const _: () = assert!(UdpServerTransport::<MAX_DATAGRAM_SIZE>::fits::<
+        EventReply<EventOf<Event, NoEE>>,
+    >());
 
     let mut recv_buf = [0; MAX_DATAGRAM_SIZE];
-    let mut events = CircularQueue::<(Event, u32, Instant)>::with_capacity(MAX_EVENTS);
-    let mut start: Option<u32> = None;
-    let mut end: Option<u32> = None;
+    // Events are logged with the milliseconds since we started as their
+    // ticks.
+    const TICK_RATE: TickRate = TickRate::MILLISECONDS;
+    let mut started = Instant::now();
 
     // Randomise the starting offset to increase the probably of a client
-    // detecting that a server has started up.
-    let mut event_offset = rand::thread_rng().gen_range(0..MAX_EVENTS) as u32;
+    // detecting that a server has started up. Our client is aware of the
+    // metadata conveyed with recovery. We reply our status to the first
+    // request since we started, and to any request asking for it, and tell
+    // the client's time of our events given the time conveyed by its
+    // requests.
+    let new_engine = |last_reset_cause| {
+        ServerEngine::<
+            Command,
+            Event,
+            MAX_EVENTS,
+            MAX_COMMANDS_PER_REQUEST,
+            MAX_EVENTS_PER_REPLY,
+            MAX_DATAGRAM_SIZE,
+        >::new(
+            EventLog::new(rand::thread_rng().gen_range(0..MAX_EVENTS) as u32)
+                .with_recovery_metadata(),
+            StatusReporter::new(0, TICK_RATE, last_reset_cause),
+        )
+        .with_clock(ClockSync::new(TICK_RATE, CLIENT_TICK_RATE))
+    };
+    let mut engine = new_engine(ResetCause::PowerOn);
 
     loop {
         tokio::select! {
-            Ok((len, remote_addr)) = socket.recv_from(&mut recv_buf) => {
-                if let Ok(request) = postcard::from_bytes::<CommandRequest<Command>>(&recv_buf[..len]) {
-                    println!(
-                        "SERVER: {:?} command received from {:?}. Replying.",
-                        request, remote_addr
-                    );
-
-                    // We optimise searching for events by going backward in our
-                    // circular buffer until we find the latest event where its
-                    // offset is adjacent to the last one observed by the client. In the
-                    // case where we have nothing in relation to the last offset expressed
-                    // by the client then we provide the oldest one we have. See the
-                    // offset-rules.md doc for details.
-                    let maybe_event: Option<(EventOf<Event, NoEE>, Instant)> = if let (Some(start), Some(end)) = (start, end) {
-                        if request.last_event_offset.is_none() || (request.last_event_offset >= Some(start) && request.last_event_offset <= Some(end)) {
-                            let next_event_offset = request.last_event_offset.map(|o| o.wrapping_add(1));
-                            let mut events_iter =
-                                events
-                                .iter()
-                                .skip_while(|(_, o, _)| Some(*o) != next_event_offset && Some(*o) != request.last_event_offset);
-                            let next_e = events_iter.next();
-                            let last_e = events_iter.next();
-                            let e = match (next_e, last_e) {
-                                (Some((_, o, _)), _) if Some(*o) == next_event_offset => next_e.cloned(),
-                                (_, Some((_, o, _))) if Some(*o) == request.last_event_offset => None,
-                                (Some((_, o, _)), _) if Some(*o) == request.last_event_offset => None,
-                                _ => events.iter().last().cloned(),
-                            };
-                            e.map(|(e, o, t)|(EventOf::Logged(e, o), t))
-                        } else {
-                            Some((EventOf::Recovery(start, end), Instant::now()))
-                        }
-                    } else {
-                        None
-                    };
-
-                    let reply = flip_flop_app::event_reply(maybe_event, |t|Instant::now().duration_since(t).as_secs());
-
-                    let mut send_buf = [0; MAX_DATAGRAM_SIZE];
-                    if let Ok(encoded_buf) = postcard::to_slice(&reply, &mut send_buf) {
-                        let _ = socket.send_to(encoded_buf, remote_addr).await;
-                        println!("SERVER: {:?} event replied to {:?}", reply, remote_addr);
+            Ok((len, remote_addr)) = transport.recv(&mut recv_buf) => {
+                // Our commands always succeed. We reply with our status if
+                // it is due, or otherwise the event following the last one
+                // observed by the client, along with those that follow it.
+                // See the offset-rules.md doc for details.
+                let now = TICK_RATE.from_duration(started.elapsed());
+                let output = engine.handle_frame(&recv_buf[..len], now, |command, _| {
+                    println!("SERVER: executing {:?} received from {:?}", command, remote_addr);
+                    Ok::<(), ()>(())
+                });
+                match output {
+                    Output::Reply(bytes) => {
+                        let _ = transport.send(&remote_addr, bytes).await;
+                        println!("SERVER: {:?} replied to {:?}", bytes, remote_addr);
+                    }
+                    Output::Ignore(reason) => {
+                        println!("SERVER: ignoring {:?} given {:?}", remote_addr, reason);
                     }
                 }
             }
 
             Some(event_instant) = event_r.recv() => {
-                // For this example, we will reset the event offset periodically
-                // so that a client can demonstrate how it forgets state.
+                // For this example, we will simulate a reboot periodically so
+                // that a client can demonstrate how it forgets state, and that
+                // the times of our events remain consistent with its clock.
                 if rand::thread_rng().gen_range(0..40) == 0 {
-                    println!("SERVER: Resetting events");
-                    events.clear();
-                    event_offset = rand::thread_rng().gen_range(0..MAX_EVENTS) as u32;
-                    start = Some(event_offset);
-                    end = Some(event_offset);
+                    println!("SERVER: Rebooting");
+                    started = Instant::now();
+                    engine = new_engine(ResetCause::Software);
                 } else {
-                    println!("SERVER: event stored for offset {}", event_offset);
-                    events.push((Event::SomeEvent, event_offset, event_instant));
-                    if start.is_none() {
-                        start = Some(event_offset);
-                    }
-                    end = Some(event_offset);
-                    event_offset = event_offset.wrapping_add(1);
+                    let ticks = TICK_RATE.from_duration(event_instant.saturating_duration_since(started));
+                    let event_offset = engine.log_mut().push(Event::SomeEvent, ticks);
+                    println!(
+                        "SERVER: event stored for offset {} at client time {:?}",
+                        event_offset,
+                        engine.clock().and_then(|clock| clock.client_time(ticks))
+                    );
                 }
             }
         }
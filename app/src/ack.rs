@@ -0,0 +1,242 @@
+//! Acknowledging commands that a server understood but refuses to execute,
+//! conveyed as ephemeral events so as not to occupy the event log. A
+//! command's failure to execute may be an [Ack] of its rejection, correlated
+//! with the command by an identifier of the application's choosing, which a
+//! [crate::server::ServerEngine] replies in place of an event. A client is
+//! told of the rejection through the replies delivered to it, see
+//! [crate::client::Delivery::completion].
+
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+/// Why a server refuses a command, being compact enough for every product to
+/// reply without defining events of its own for it. Reasons are encoded by
+/// their position, and so further reasons are only ever added following
+/// those here. Clients unaware of a reason fail to decode it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum RejectReason {
+    /// A parameter of the command is beyond what the server accepts.
+    OutOfRange,
+    /// The server is not in a mode in which the command may be executed.
+    WrongMode,
+    /// The hardware needed to execute the command has failed.
+    HardwareFault,
+    /// A reason defined by the application.
+    Other(u8),
+}
+
+/// What has become of a command acknowledged by a server. Statuses are
+/// encoded by their position, as per [RejectReason].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum AckStatus {
+    /// The command was refused for the reason given, and so was not
+    /// executed.
+    Rejected(RejectReason),
+}
+
+/// The acknowledgement of a command, conveyed as an ephemeral event. The
+/// command is identified as per the application e.g. by an identifier
+/// conveyed with the command.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+pub struct Ack<I> {
+    /// The command acknowledged.
+    pub command_id: I,
+    /// What has become of it.
+    pub status: AckStatus,
+}
+
+impl<I> Ack<I> {
+    /// The rejection of the command identified for the reason given.
+    pub const fn rejected(command_id: I, reason: RejectReason) -> Self {
+        Self {
+            command_id,
+            status: AckStatus::Rejected(reason),
+        }
+    }
+}
+
+/// Ephemeral events that may convey the acknowledgement of a command.
+pub trait AckOf<I> {
+    /// The acknowledgement conveyed, if any.
+    fn ack(&self) -> Option<&Ack<I>>;
+}
+
+impl<I> AckOf<I> for Ack<I> {
+    fn ack(&self) -> Option<&Ack<I>> {
+        Some(self)
+    }
+}
+
+/// The failure of a command to execute, as returned by the function
+/// executing the commands of a [crate::server::ServerEngine], which may be
+/// replied to the client as an ephemeral event of type `EE`. Failures of
+/// `()` are replied as nothing, while those of an `Option` are replied as
+/// the ephemeral event that they hold, if any.
+pub trait CommandFailure<EE> {
+    /// The ephemeral event to reply, if any.
+    fn into_ack(self) -> Option<EE>;
+}
+
+impl<EE> CommandFailure<EE> for () {
+    fn into_ack(self) -> Option<EE> {
+        None
+    }
+}
+
+impl<EE> CommandFailure<EE> for Option<EE> {
+    fn into_ack(self) -> Option<EE> {
+        self
+    }
+}
+
+impl<I, EE> CommandFailure<EE> for Ack<I>
+where
+    Ack<I>: Into<EE>,
+{
+    fn into_ack(self) -> Option<EE> {
+        Some(self.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        client::{Action, ClientConfig, ClientEngine},
+        event_log::EventLog,
+        harness::{Faults, VirtualBus},
+        progress::{Completed, CompletionOf, Rejected},
+        server::{Output, ServerEngine},
+        status::StatusReporter,
+        EventOf, ResetCause, TickRate,
+    };
+
+    // Sets a level of up to 100, being a command of the identifier given.
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Set(u8, u8);
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Done(u8);
+
+    impl CompletionOf<u8> for Done {
+        fn completed(&self) -> Option<&u8> {
+            Some(&self.0)
+        }
+    }
+
+    type Server = ServerEngine<Set, Done, 8, 3, 4, 32, Ack<u8>>;
+
+    const CONFIG: ClientConfig = ClientConfig {
+        tick_rate: TickRate::MILLISECONDS,
+        reply_timeout_ticks: 10,
+        client_time: false,
+        max_reply_len: false,
+    };
+
+    fn server() -> Server {
+        Server::new(
+            EventLog::new(0),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        )
+    }
+
+    fn execute(Set(command_id, level): &Set, log: &mut EventLog<Done, 8>) -> Result<(), Ack<u8>> {
+        if *level > 100 {
+            return Err(Server::reject(*command_id, RejectReason::OutOfRange));
+        }
+        log.push(Done(*command_id), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejection_mid_batch() {
+        let mut client = ClientEngine::<u8, Set, Done, 1, 3, 4, 32, Ack<u8>>::new(CONFIG);
+        client.add_server(1, 100).unwrap();
+        let mut server = server();
+        for command in [Set(1, 10), Set(2, 200), Set(3, 20)] {
+            client.command(&1, command).unwrap();
+        }
+
+        // The second command is rejected in reply to the request conveying
+        // it, ahead of the server's status, and so the third is not
+        // executed, while the first completes once its event is replied
+        // following the status.
+        let mut completions = std::vec::Vec::new();
+        for now in [0, 100, 200] {
+            let Action::Transmit { bytes, .. } = client.next_action(now) else {
+                panic!("no request at {now}");
+            };
+            let Output::Reply(bytes) = server.handle_frame(bytes, now, execute) else {
+                panic!("no reply at {now}");
+            };
+            let delivery = client.handle_frame(&1, bytes, now).unwrap();
+            completions.push([1, 2, 3].map(|id| delivery.completion(&id)));
+        }
+        assert_eq!(
+            completions,
+            [
+                [None, Some(Err(Rejected(RejectReason::OutOfRange))), None],
+                [None, None, None],
+                [Some(Ok(Completed)), None, None],
+            ]
+        );
+        assert_eq!(server.log().len(), 1);
+    }
+
+    #[test]
+    fn test_rejection_of_duplicated_request() {
+        let mut bus = VirtualBus::<u8, Set, Done, 1, 3, 4, 32, 8, Ack<u8>>::new(CONFIG, 0);
+        bus.add_server(1, 100, server()).unwrap();
+        bus.set_execute(|_, command, log| execute(command, log).map_err(Some));
+        bus.run_for(50);
+
+        // Commands are never conveyed again by the client, but a request
+        // duplicated on the bus is executed again, and so its command is
+        // rejected again. The reply to the duplicate is no longer awaited,
+        // and so the client is told of the rejection once only.
+        bus.set_faults(
+            Faults {
+                duplication: 1.0,
+                ..Faults::NONE
+            },
+            Faults::NONE,
+        );
+        bus.client_mut().command(&1, Set(5, 101)).unwrap();
+        bus.run_for(100);
+        assert_eq!(bus.executed(), [(1, Set(5, 101)), (1, Set(5, 101))]);
+        let rejections = bus
+            .delivered()
+            .iter()
+            .filter(|(_, reply, _)| matches!(reply.event, Some(EventOf::Ephemeral(_))))
+            .map(|(_, reply, _)| reply.event.clone())
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(
+            rejections,
+            [Some(EventOf::Ephemeral(Ack::rejected(
+                5,
+                RejectReason::OutOfRange
+            )))]
+        );
+        bus.assert_no_unsolicited_server_tx();
+    }
+
+    #[test]
+    fn test_encoding() {
+        // Reasons and statuses are encoded by their position, which must
+        // never change.
+        for (ack, bytes) in [
+            (Ack::rejected(7u8, RejectReason::OutOfRange), &[7, 0, 0][..]),
+            (Ack::rejected(7, RejectReason::WrongMode), &[7, 0, 1]),
+            (Ack::rejected(7, RejectReason::HardwareFault), &[7, 0, 2]),
+            (Ack::rejected(7, RejectReason::Other(200)), &[7, 0, 3, 200]),
+        ] {
+            assert_eq!(postcard::to_vec::<_, 8>(&ack).unwrap(), bytes);
+            assert_eq!(postcard::from_bytes::<Ack<u8>>(bytes), Ok(ack));
+        }
+        assert!(postcard::from_bytes::<Ack<u8>>(&[7, 0, 4]).is_err());
+        assert_eq!(Ack::<u8>::POSTCARD_MAX_SIZE, 4);
+    }
+}
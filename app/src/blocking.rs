@@ -0,0 +1,496 @@
+//! Conveying the datagrams of a [ClientEngine] or [ServerEngine] without an
+//! async executor e.g. for a synchronous provisioning script, being the
+//! blocking counterparts of [crate::transport]. A [BlockingTransport]
+//! conveys datagrams, receiving them until a timeout, and a
+//! [BlockingTimer] tells the time remaining until the ticks that an engine
+//! waits for, and [run_client_blocking] and [run_server_blocking] drive an
+//! engine with them, the thread sleeping while it waits to receive.
+//!
+//! UDP is provided by [UdpTransport], and serial ports by
+//! [SerialPortTransport] given the `serialport` feature. Requires the
+//! `blocking` feature, which does not require tokio.
+
+use std::{
+    cell::RefCell,
+    io,
+    net::{SocketAddr, UdpSocket},
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    ack::CommandFailure,
+    client::{Action, ClientEngine, Delivery},
+    clock::{Clock, InstantClock},
+    event_log::EventLog,
+    observer::ProtocolObserver,
+    server::{Output, ServerEngine},
+    EventOf, Offset, TemporalEvent,
+};
+
+/// Conveys datagrams to and from the addresses of a transport, blocking the
+/// thread while it does so.
+pub trait BlockingTransport {
+    /// Where datagrams are conveyed to and from.
+    type Address;
+    /// Problems conveying datagrams.
+    type Error;
+
+    /// Send a datagram to an address.
+    fn send(&mut self, address: &Self::Address, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Receive a datagram into the buffer given, returning its length and
+    /// where it was received from, or `None` should the timeout given
+    /// elapse first. A timeout of zero receives only a datagram that has
+    /// arrived already, and no timeout waits for as long as it takes.
+    /// Datagrams partly received when the timeout elapses are not lost.
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<Option<(usize, Self::Address)>, Self::Error>;
+}
+
+/// Keeps the time of an engine with a [Clock], telling how long remains
+/// until the ticks that it is given.
+pub trait BlockingTimer: Clock {
+    /// The time remaining until the ticks given have elapsed, being zero
+    /// once they have, or `None` should they never elapse.
+    fn remaining(&self, ticks: u64) -> Option<Duration>;
+}
+
+impl BlockingTimer for InstantClock {
+    fn remaining(&self, ticks: u64) -> Option<Duration> {
+        // An engine waits until `u64::MAX` when it has nothing to await.
+        if ticks == u64::MAX {
+            return None;
+        }
+        self.instant_at(ticks)
+            .map(|instant| instant.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Drive a client engine with a transport and clock, delivering the events
+/// received to the function given, as per [crate::transport::run_client].
+/// The engine is not borrowed while delivering, and so commands may be
+/// queued with it then, see [ClientEngine::command]. Returns once the
+/// function given breaks, with the value that it breaks with, or given a
+/// problem with the transport.
+#[allow(clippy::type_complexity)]
+pub fn run_client_blocking<
+    T,
+    K,
+    D,
+    B,
+    C,
+    E,
+    const SERVERS: usize,
+    const COMMANDS: usize,
+    const EVENTS: usize,
+    const N: usize,
+    EE,
+    O,
+    S,
+    P,
+>(
+    engine: &RefCell<ClientEngine<T::Address, C, E, SERVERS, COMMANDS, EVENTS, N, EE, O, S, P>>,
+    transport: &mut T,
+    clock: &K,
+    mut deliver: D,
+) -> Result<B, T::Error>
+where
+    T: BlockingTransport,
+    T::Address: Clone + Eq,
+    K: BlockingTimer,
+    D: FnMut(Delivery<T::Address, EventOf<E, EE, O, S>, O, EVENTS>) -> ControlFlow<B>,
+    C: Serialize,
+    O: Offset + postcard::experimental::max_size::MaxSize,
+    EventOf<E, EE, O, S>: TemporalEvent + DeserializeOwned,
+    P: ProtocolObserver<T::Address>,
+{
+    let mut buf = [0; N];
+    loop {
+        let until = match engine.borrow_mut().next_action(clock.now_ticks()) {
+            Action::Transmit { address, bytes, .. } => {
+                transport.send(&address, bytes)?;
+                continue;
+            }
+            Action::Wait { until } => until,
+        };
+        match transport.recv_timeout(&mut buf, clock.remaining(until))? {
+            Some((len, address)) => {
+                let delivery =
+                    engine
+                        .borrow_mut()
+                        .handle_frame(&address, &buf[..len], clock.now_ticks());
+                if let Some(delivery) = delivery {
+                    if let ControlFlow::Break(b) = deliver(delivery) {
+                        return Ok(b);
+                    }
+                }
+            }
+            None => {
+                engine.borrow_mut().handle_timeout(clock.now_ticks());
+            }
+        }
+    }
+}
+
+/// Drive a server engine with a transport and clock, executing the commands
+/// received with the function given, as per
+/// [crate::transport::run_server]. Returns only given a problem with the
+/// transport.
+pub fn run_server_blocking<
+    T,
+    K,
+    X,
+    F,
+    C,
+    E,
+    const LOG: usize,
+    const COMMANDS: usize,
+    const EVENTS: usize,
+    const N: usize,
+    EE,
+    O,
+    S,
+    P,
+>(
+    engine: &RefCell<ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE, O, S, P>>,
+    transport: &mut T,
+    clock: &K,
+    mut execute: X,
+) -> T::Error
+where
+    T: BlockingTransport,
+    K: Clock,
+    X: FnMut(&C, &mut EventLog<E, LOG, O, S>) -> Result<(), F>,
+    F: CommandFailure<EE>,
+    C: DeserializeOwned + Serialize,
+    E: Clone,
+    O: Offset,
+    S: Clone,
+    EventOf<E, EE, O, S>: TemporalEvent,
+    P: ProtocolObserver<()>,
+{
+    let mut buf = [0; N];
+    loop {
+        let (len, address) = match transport.recv_timeout(&mut buf, None) {
+            Ok(Some(received)) => received,
+            Ok(None) => continue,
+            Err(e) => return e,
+        };
+        let mut engine = engine.borrow_mut();
+        let bytes = match engine.handle_frame(&buf[..len], clock.now_ticks(), &mut execute) {
+            Output::Reply(bytes) => bytes,
+            Output::Ignore(_) => continue,
+        };
+        if let Err(e) = transport.send(&address, bytes) {
+            return e;
+        }
+    }
+}
+
+/// Conveys datagrams over UDP with a socket of std.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// A transport conveying datagrams with the socket given, whose read
+    /// timeout is then set as each datagram is received.
+    pub fn new(socket: UdpSocket) -> Self {
+        Self { socket }
+    }
+
+    /// The local address of the transport e.g. given a port of 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl BlockingTransport for UdpTransport {
+    type Address = SocketAddr;
+    type Error = io::Error;
+
+    fn send(&mut self, address: &SocketAddr, bytes: &[u8]) -> io::Result<()> {
+        self.socket.send_to(bytes, address).map(|_| ())
+    }
+
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<(usize, SocketAddr)>> {
+        // A read timeout of zero is not permitted, and so receiving only
+        // what has arrived is done without blocking instead.
+        let received = if timeout == Some(Duration::ZERO) {
+            self.socket.set_nonblocking(true)?;
+            let received = self.socket.recv_from(buf);
+            self.socket.set_nonblocking(false)?;
+            received
+        } else {
+            self.socket.set_read_timeout(timeout)?;
+            self.socket.recv_from(buf)
+        };
+        match received {
+            Ok(received) => Ok(Some(received)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "serialport")]
+pub use self::serialport::SerialPortTransport;
+
+#[cfg(feature = "serialport")]
+mod serialport {
+    use std::{
+        boxed::Box,
+        io::{self, Read, Write},
+        time::{Duration, Instant},
+    };
+
+    use serialport::SerialPort;
+
+    use super::BlockingTransport;
+    use crate::transport::SerialError;
+
+    // How long a read waits at most when receiving without a timeout,
+    // reading again should it elapse.
+    const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Conveys datagrams of up to `N - 1` bytes over a serial port, each
+    /// preceded by a byte of its length, as per
+    /// [crate::transport::SerialTransport]. Requires the `serialport`
+    /// feature.
+    pub struct SerialPortTransport<const N: usize> {
+        port: Box<dyn SerialPort>,
+        // Bytes received and yet to be returned, so that a timeout may
+        // elapse without losing them.
+        buf: [u8; N],
+        filled: usize,
+    }
+
+    impl<const N: usize> SerialPortTransport<N> {
+        /// A transport conveying datagrams with the port given, whose
+        /// timeout is then set as each datagram is received.
+        pub fn new(port: Box<dyn SerialPort>) -> Self {
+            const { assert!(N > 1) };
+            Self {
+                port,
+                buf: [0; N],
+                filled: 0,
+            }
+        }
+    }
+
+    impl<const N: usize> BlockingTransport for SerialPortTransport<N> {
+        type Address = ();
+        type Error = SerialError<io::Error>;
+
+        fn send(&mut self, _: &(), bytes: &[u8]) -> Result<(), Self::Error> {
+            let len = u8::try_from(bytes.len())
+                .ok()
+                .filter(|len| (*len as usize) < N)
+                .ok_or(SerialError::TooLong)?;
+            self.port.write_all(&[len]).map_err(SerialError::Io)?;
+            self.port.write_all(bytes).map_err(SerialError::Io)?;
+            self.port.flush().map_err(SerialError::Io)
+        }
+
+        fn recv_timeout(
+            &mut self,
+            buf: &mut [u8],
+            timeout: Option<Duration>,
+        ) -> Result<Option<(usize, ())>, Self::Error> {
+            let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+            loop {
+                if let Some(&len) = self.buf[..self.filled].first() {
+                    let (len, frame_len) = (len as usize, len as usize + 1);
+                    if frame_len > N || len > buf.len() {
+                        self.filled = 0;
+                        return Err(SerialError::TooLong);
+                    }
+                    if self.filled >= frame_len {
+                        buf[..len].copy_from_slice(&self.buf[1..frame_len]);
+                        self.buf.copy_within(frame_len..self.filled, 0);
+                        self.filled -= frame_len;
+                        return Ok(Some((len, ())));
+                    }
+                }
+                let read_timeout = match deadline {
+                    Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                    None => READ_TIMEOUT,
+                };
+                self.port
+                    .set_timeout(read_timeout)
+                    .map_err(|e| SerialError::Io(e.into()))?;
+                match self.port.read(&mut self.buf[self.filled..]) {
+                    Ok(0) => return Err(SerialError::Closed),
+                    Ok(read) => self.filled += read,
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        if deadline.is_some() {
+                            return Ok(None);
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return Err(SerialError::Io(e)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+    use crate::{client::ClientConfig, status::StatusReporter, NoEE, ResetCause, TickRate};
+
+    type Client = ClientEngine<SocketAddr, u8, u8, 1, 2, 4, 32>;
+    type Server = ServerEngine<u8, u8, 4, 2, 4, 32>;
+
+    #[test]
+    fn test_udp_loopback() {
+        let server_socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut transport = UdpTransport::new(server_socket);
+            let server = RefCell::new(Server::new(
+                EventLog::new(10),
+                StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+            ));
+            server.borrow_mut().log_mut().push(1, 0);
+            let clock = InstantClock::new(TickRate::MILLISECONDS);
+            let execute = |command: &u8, log: &mut EventLog<u8, 4>| {
+                log.push(*command, 0);
+                Ok::<_, ()>(())
+            };
+            run_server_blocking(&server, &mut transport, &clock, execute)
+        });
+
+        let mut transport = UdpTransport::new(UdpSocket::bind(("127.0.0.1", 0)).unwrap());
+        let clock = InstantClock::new(TickRate::MILLISECONDS);
+        let client = RefCell::new(Client::new(ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 100,
+            client_time: false,
+            max_reply_len: false,
+        }));
+        client.borrow_mut().add_server(server_addr, 10).unwrap();
+
+        // The server's status and event are delivered over the loopback
+        // interface, and then the event logged by a command queued as they
+        // are delivered.
+        let (delivered_tx, delivered) = mpsc::channel();
+        let deliver = |delivery: Delivery<_, EventOf<u8, NoEE>, u32, 4>| {
+            for (reply, _) in delivery.replies {
+                if reply.event == Some(EventOf::Logged(1, 10)) {
+                    client.borrow_mut().command(&server_addr, 7).unwrap();
+                }
+                if reply.event == Some(EventOf::Logged(7, 11)) {
+                    return ControlFlow::Break(());
+                }
+                delivered_tx.send(reply.event).unwrap();
+            }
+            ControlFlow::Continue(())
+        };
+        run_client_blocking(&client, &mut transport, &clock, deliver).unwrap();
+        assert!(matches!(delivered.recv(), Ok(Some(EventOf::Status(_)))));
+        assert_eq!(delivered.recv(), Ok(Some(EventOf::Logged(1, 10))));
+        assert!(client
+            .borrow()
+            .tracker(&server_addr)
+            .unwrap()
+            .is_synchronised());
+    }
+
+    #[test]
+    fn test_udp_timeouts() {
+        let mut transport = UdpTransport::new(UdpSocket::bind(("127.0.0.1", 0)).unwrap());
+        let addr = transport.local_addr().unwrap();
+        let mut buf = [0; 8];
+
+        // Nothing is received before each timeout elapses, and then what
+        // has arrived is received without waiting.
+        assert_eq!(
+            transport
+                .recv_timeout(&mut buf, Some(Duration::ZERO))
+                .unwrap(),
+            None
+        );
+        let started = Instant::now();
+        assert_eq!(
+            transport
+                .recv_timeout(&mut buf, Some(Duration::from_millis(20)))
+                .unwrap(),
+            None
+        );
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        transport.send(&addr, &[1, 2, 3]).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            transport
+                .recv_timeout(&mut buf, Some(Duration::ZERO))
+                .unwrap(),
+            Some((3, addr))
+        );
+        assert_eq!(buf[..3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_instant_clock_remaining() {
+        let clock = InstantClock::new(TickRate::MILLISECONDS);
+        assert_eq!(clock.remaining(0), Some(Duration::ZERO));
+        assert!(clock.remaining(1_000).unwrap() > Duration::from_millis(900));
+        assert_eq!(clock.remaining(u64::MAX), None);
+    }
+
+    #[cfg(all(feature = "serialport", unix))]
+    #[test]
+    fn test_serial_port_framing() {
+        use std::io::{Read, Write};
+
+        use ::serialport::{SerialPort, TTYPort};
+
+        use crate::transport::SerialError;
+
+        // Datagrams are conveyed over one end of a pseudo-terminal, the
+        // other end being read and written directly.
+        let (a, mut b) = TTYPort::pair().unwrap();
+        b.set_timeout(Duration::from_secs(1)).unwrap();
+        let mut a = SerialPortTransport::<8>::new(Box::new(a));
+        a.send(&(), &[1, 2, 3, 4]).unwrap();
+        assert!(matches!(a.send(&(), &[0; 8]), Err(SerialError::TooLong)));
+        let mut sent = [0; 5];
+        b.read_exact(&mut sent).unwrap();
+        assert_eq!(sent, [4, 1, 2, 3, 4]);
+
+        // A datagram partly received when a timeout elapses is received
+        // once the rest of it arrives.
+        let mut buf = [0; 8];
+        let timeout = Some(Duration::from_secs(1));
+        b.write_all(&[2, 5]).unwrap();
+        assert_eq!(
+            a.recv_timeout(&mut buf, Some(Duration::from_millis(20)))
+                .unwrap(),
+            None
+        );
+        b.write_all(&[6, 0]).unwrap();
+        assert_eq!(a.recv_timeout(&mut buf, timeout).unwrap(), Some((2, ())));
+        assert_eq!(buf[..2], [5, 6]);
+        assert_eq!(a.recv_timeout(&mut buf, timeout).unwrap(), Some((0, ())));
+    }
+}
@@ -0,0 +1,462 @@
+//! Bridging the servers of a client to an MQTT broker e.g. where a gateway
+//! publishes their events for dashboards, and forwards the commands that it
+//! subscribes to. An [MqttBridge] maps the events delivered by a
+//! [ClientEngine] to messages of JSON ready to publish with any MQTT client,
+//! and the messages of commands back to commands queued with the engine.
+//! Requires the `std` feature.
+//!
+//! The topics of a server are those of its address and port beneath a
+//! prefix:
+//!
+//! ```text
+//! <prefix>/<address>/<port>/events
+//! <prefix>/<address>/<port>/commands
+//! ```
+//!
+//! Each logged or ephemeral event is published as an object of its kind, its
+//! offset if logged, its age in the server's ticks, its time in milliseconds
+//! since the UNIX epoch along with the most that it may be in error by, if
+//! told, and the event itself as serde encodes it to JSON:
+//!
+//! ```json
+//! {"kind":"logged","offset":12,"delta_ticks":10,"time_ms":1700000000000,"error_ms":5,"event":{"Temperature":21}}
+//! {"kind":"ephemeral","offset":null,"delta_ticks":0,"time_ms":null,"error_ms":null,"event":"Pressed"}
+//! ```
+//!
+//! Logged events are published retained, so that a dashboard subscribing
+//! later receives the last of each server, whereas ephemeral events are not.
+//! The time of events is told once the rate of a server's ticks is known
+//! from its status, see [Delivery::clock]. The other replies of a server
+//! e.g. its status and recovery concern the client alone, and so are not
+//! published. A command is a message of the command as serde decodes it from
+//! JSON, published to the commands topic of its server.
+
+use std::{
+    fmt,
+    string::String,
+    time::{SystemTime, UNIX_EPOCH},
+    vec::Vec,
+};
+
+use postcard::experimental::max_size::MaxSize;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    client::{ClientEngine, Delivery},
+    clock::ClientTime,
+    observer::ProtocolObserver,
+    EventOf, Offset, TemporalEvent, TickRate,
+};
+
+/// A message to publish, being its topic, its payload of JSON and whether
+/// the broker is to retain it.
+pub type MqttMessage = (String, Vec<u8>, bool);
+
+/// Problems mapping a message of a command.
+#[derive(Debug)]
+pub enum BridgeError {
+    /// The topic is not the commands topic of a server beneath the prefix.
+    UnknownTopic,
+    /// The payload is not the JSON of a command.
+    CannotDecode(serde_json::Error),
+    /// The server is not polled by the engine, or its queue of commands is
+    /// full, see [crate::client::QueuePolicy].
+    NotQueued,
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BridgeError::UnknownTopic => f.write_str("not a commands topic"),
+            BridgeError::CannotDecode(e) => write!(f, "cannot decode the command: {e}"),
+            BridgeError::NotQueued => f.write_str("the command cannot be queued"),
+        }
+    }
+}
+
+impl std::error::Error for BridgeError {}
+
+// The payload of an event's message, see the module's documentation.
+#[derive(Serialize)]
+struct EventMessage<'a, E, O> {
+    kind: &'static str,
+    offset: Option<O>,
+    delta_ticks: u64,
+    time_ms: Option<u64>,
+    error_ms: Option<u64>,
+    event: &'a E,
+}
+
+/// Maps the events of servers to messages of a topic prefix, and messages
+/// of commands back to commands.
+#[derive(Clone, Debug)]
+pub struct MqttBridge {
+    prefix: String,
+    epoch: SystemTime,
+    client_tick_rate: TickRate,
+}
+
+impl MqttBridge {
+    /// A bridge of the topic prefix given e.g. `flip-flop/site-1`, telling
+    /// the wall time of events from the client's ticks, being those elapsed
+    /// at the rate given since the epoch given.
+    pub fn new(prefix: impl Into<String>, epoch: SystemTime, client_tick_rate: TickRate) -> Self {
+        Self {
+            prefix: prefix.into(),
+            epoch,
+            client_tick_rate,
+        }
+    }
+
+    /// The topic that the events of a server are published to.
+    pub fn events_topic(&self, server_address: u8, server_port: u8) -> String {
+        format!("{}/{server_address}/{server_port}/events", self.prefix)
+    }
+
+    /// The topic that the commands of a server are published to, to be
+    /// subscribed to by the gateway, along with those of every server e.g.
+    /// `<prefix>/+/+/commands`.
+    pub fn commands_topic(&self, server_address: u8, server_port: u8) -> String {
+        format!("{}/{server_address}/{server_port}/commands", self.prefix)
+    }
+
+    /// The messages of the events of a delivery of the server at the
+    /// address and port given, in the order delivered.
+    pub fn delivery<A, E, EE, O, S, const EVENTS: usize>(
+        &self,
+        server_address: u8,
+        server_port: u8,
+        delivery: &Delivery<A, EventOf<E, EE, O, S>, O, EVENTS>,
+    ) -> Vec<MqttMessage>
+    where
+        E: Serialize,
+        EE: Serialize,
+        O: Serialize + Copy,
+        EventOf<E, EE, O, S>: TemporalEvent,
+    {
+        delivery
+            .replies
+            .iter()
+            .filter_map(|(reply, _)| {
+                // Replies of the absolute form convey the server's ticks as
+                // of their event rather than its age.
+                let delta_ticks = match delivery.server_ticks {
+                    Some(server_ticks) => server_ticks.saturating_sub(reply.delta_ticks),
+                    None => reply.delta_ticks,
+                };
+                let time = delivery.clock.map(|clock| clock.event_time(delta_ticks));
+                let event = reply.event.as_ref()?;
+                self.event(server_address, server_port, delta_ticks, event, time)
+            })
+            .collect()
+    }
+
+    /// The message of an event replied by the server at the address and
+    /// port given, with its age in the server's ticks, and the client's time
+    /// of it if told, or None if it is neither a logged nor an ephemeral
+    /// event.
+    pub fn event<E, EE, O, S>(
+        &self,
+        server_address: u8,
+        server_port: u8,
+        delta_ticks: u64,
+        event: &EventOf<E, EE, O, S>,
+        time: Option<ClientTime>,
+    ) -> Option<MqttMessage>
+    where
+        E: Serialize,
+        EE: Serialize,
+        O: Serialize + Copy,
+    {
+        let time_ms = time.map(|time| {
+            let time = self.epoch + self.client_tick_rate.to_duration(time.ticks);
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64)
+        });
+        let error_ms = time.map(|time| {
+            let error = self.client_tick_rate.to_duration(time.error_ticks);
+            error.as_nanos().div_ceil(1_000_000) as u64
+        });
+        let payload = match event {
+            EventOf::Logged(event, offset) => serde_json::to_vec(&EventMessage {
+                kind: "logged",
+                offset: Some(*offset),
+                delta_ticks,
+                time_ms,
+                error_ms,
+                event,
+            }),
+            EventOf::Ephemeral(event) => serde_json::to_vec(&EventMessage::<_, O> {
+                kind: "ephemeral",
+                offset: None,
+                delta_ticks,
+                time_ms,
+                error_ms,
+                event,
+            }),
+            _ => return None,
+        };
+        let retain = matches!(event, EventOf::Logged(..));
+        Some((
+            self.events_topic(server_address, server_port),
+            payload.ok()?,
+            retain,
+        ))
+    }
+
+    /// The address and port of the server of a commands topic, if it is
+    /// one beneath the prefix.
+    pub fn parse_commands_topic(&self, topic: &str) -> Option<(u8, u8)> {
+        let mut levels = topic
+            .strip_prefix(self.prefix.as_str())?
+            .strip_prefix('/')?
+            .split('/');
+        let server_address = levels.next()?.parse().ok()?;
+        let server_port = levels.next()?.parse().ok()?;
+        match (levels.next(), levels.next()) {
+            (Some("commands"), None) => Some((server_address, server_port)),
+            _ => None,
+        }
+    }
+
+    /// The address and port of the server, and the command, of a message
+    /// published to a commands topic.
+    pub fn command<C: DeserializeOwned>(
+        &self,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(u8, u8, C), BridgeError> {
+        let (server_address, server_port) = self
+            .parse_commands_topic(topic)
+            .ok_or(BridgeError::UnknownTopic)?;
+        let command = serde_json::from_slice(payload).map_err(BridgeError::CannotDecode)?;
+        Ok((server_address, server_port, command))
+    }
+
+    /// Queue the command of a message published to a commands topic with an
+    /// engine, to convey with the next [crate::CommandRequest] of its
+    /// server, given the address that the engine knows the server by.
+    #[allow(clippy::type_complexity)]
+    pub fn queue_command<
+        A,
+        C,
+        E,
+        const SERVERS: usize,
+        const COMMANDS: usize,
+        const EVENTS: usize,
+        const N: usize,
+        EE,
+        O,
+        S,
+        P,
+    >(
+        &self,
+        engine: &mut ClientEngine<A, C, E, SERVERS, COMMANDS, EVENTS, N, EE, O, S, P>,
+        topic: &str,
+        payload: &[u8],
+        address_of: impl FnOnce(u8, u8) -> A,
+    ) -> Result<(), BridgeError>
+    where
+        A: Clone + Eq,
+        C: Serialize + DeserializeOwned,
+        O: Offset + MaxSize,
+        EventOf<E, EE, O, S>: TemporalEvent + DeserializeOwned,
+        P: ProtocolObserver<A>,
+    {
+        let (server_address, server_port, command) = self.command(topic, payload)?;
+        engine
+            .command(&address_of(server_address, server_port), command)
+            .map_err(|_| BridgeError::NotQueued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        client::ClientConfig, clock::ExchangeClock, offset_tracker::Observation, EventReply,
+        ResetCause, ServerStatus,
+    };
+
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    enum Reading {
+        Temperature(i16),
+    }
+
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    enum Button {
+        Pressed,
+    }
+
+    type Event = EventOf<Reading, Button>;
+
+    fn bridge() -> MqttBridge {
+        MqttBridge::new(
+            "flip-flop/site-1",
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            TickRate::MILLISECONDS,
+        )
+    }
+
+    fn json(message: &MqttMessage) -> &str {
+        std::str::from_utf8(&message.1).unwrap()
+    }
+
+    #[test]
+    fn test_event_messages() {
+        // The shape of the JSON is relied upon by dashboards.
+        let time = ClientTime {
+            ticks: 12_000,
+            error_ticks: 5,
+        };
+        let logged = bridge()
+            .event(
+                1,
+                2,
+                10,
+                &Event::Logged(Reading::Temperature(21), 12),
+                Some(time),
+            )
+            .unwrap();
+        assert_eq!(logged.0, "flip-flop/site-1/1/2/events");
+        assert_eq!(
+            json(&logged),
+            r#"{"kind":"logged","offset":12,"delta_ticks":10,"time_ms":1700000012000,"error_ms":5,"event":{"Temperature":21}}"#
+        );
+        assert!(logged.2);
+
+        let ephemeral = bridge()
+            .event(1, 2, 0, &Event::Ephemeral(Button::Pressed), None)
+            .unwrap();
+        assert_eq!(
+            json(&ephemeral),
+            r#"{"kind":"ephemeral","offset":null,"delta_ticks":0,"time_ms":null,"error_ms":null,"event":"Pressed"}"#
+        );
+        assert!(!ephemeral.2);
+
+        assert_eq!(bridge().event(1, 2, 0, &Event::Recovery(0, 1), None), None);
+    }
+
+    #[test]
+    fn test_delivery_messages() {
+        let clock = ExchangeClock {
+            tick_rate: TickRate::SECONDS,
+            client_tick_rate: TickRate::MILLISECONDS,
+            sent_ticks: 10_000,
+            received_ticks: 10_010,
+        };
+        let reply = |delta_ticks, event| {
+            (
+                EventReply {
+                    delta_ticks,
+                    event: Some(event),
+                },
+                Observation::NewEvent,
+            )
+        };
+        let mut delivery = Delivery::<u8, Event, u32, 4> {
+            address: 1,
+            replies: [
+                reply(
+                    0,
+                    Event::Status(ServerStatus {
+                        uptime_ticks: 1,
+                        log_occupancy: 0,
+                        last_reset_cause: ResetCause::PowerOn,
+                        tick_rate: TickRate::SECONDS,
+                    }),
+                ),
+                reply(2, Event::Logged(Reading::Temperature(21), 1)),
+                reply(0, Event::Ephemeral(Button::Pressed)),
+            ]
+            .into_iter()
+            .collect(),
+            pending: 0,
+            clock: Some(clock),
+            server_ticks: None,
+        };
+
+        // The status concerns the client alone, and the time of each event is
+        // told from the exchange's clock.
+        let messages = bridge().delivery(1, 0, &delivery);
+        assert_eq!(messages.len(), 2);
+        let logged_time = clock.event_time(2);
+        assert_eq!(
+            bridge().event(
+                1,
+                0,
+                2,
+                &Event::Logged(Reading::Temperature(21), 1),
+                Some(logged_time)
+            ),
+            Some(messages[0].clone())
+        );
+        assert!(messages[0].2);
+        assert!(!messages[1].2);
+
+        // Events of the absolute form are told by their age.
+        delivery.server_ticks = Some(100);
+        delivery.replies[1].0.delta_ticks = 98;
+        assert_eq!(bridge().delivery(1, 0, &delivery)[0], messages[0]);
+
+        // Events are not timed until the server's rate of ticks is known.
+        delivery.clock = None;
+        assert!(json(&bridge().delivery(1, 0, &delivery)[0]).contains(r#""time_ms":null"#));
+    }
+
+    #[test]
+    fn test_commands() {
+        let bridge = bridge();
+        assert_eq!(bridge.commands_topic(1, 2), "flip-flop/site-1/1/2/commands");
+        assert_eq!(
+            bridge.parse_commands_topic("flip-flop/site-1/1/2/commands"),
+            Some((1, 2))
+        );
+        for topic in [
+            "flip-flop/site-1/1/2/events",
+            "flip-flop/site-1/1/2/commands/x",
+            "flip-flop/site-1/256/2/commands",
+            "flip-flop/site-12/1/2/commands",
+            "flip-flop/site-1/1/commands",
+        ] {
+            assert_eq!(bridge.parse_commands_topic(topic), None, "{topic}");
+        }
+
+        assert_eq!(
+            bridge
+                .command::<Reading>("flip-flop/site-1/1/2/commands", br#"{"Temperature":20}"#)
+                .unwrap(),
+            (1, 2, Reading::Temperature(20))
+        );
+        assert!(matches!(
+            bridge.command::<Reading>("flip-flop/site-1/1/2/commands", b"20"),
+            Err(BridgeError::CannotDecode(_))
+        ));
+
+        // Commands are queued for the server of the address of the topic.
+        let mut engine = ClientEngine::<u8, u8, u8, 1, 1, 4, 32>::new(ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 10,
+            client_time: false,
+            max_reply_len: false,
+        });
+        engine.add_server(1, 100).unwrap();
+        let queue = |engine: &mut ClientEngine<_, _, _, 1, 1, 4, 32>, topic| {
+            bridge.queue_command(engine, topic, b"7", |address, _| address)
+        };
+        assert!(queue(&mut engine, "flip-flop/site-1/1/0/commands").is_ok());
+        assert_eq!(engine.queue_depth(&1), Some(1));
+        assert!(matches!(
+            queue(&mut engine, "flip-flop/site-1/2/0/commands"),
+            Err(BridgeError::NotQueued)
+        ));
+        assert!(matches!(
+            queue(&mut engine, "flip-flop/site-2/1/0/commands"),
+            Err(BridgeError::UnknownTopic)
+        ));
+    }
+}
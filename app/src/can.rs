@@ -0,0 +1,491 @@
+//! Conveying datagrams over classic CAN, each being fragmented into frames
+//! of up to 8 bytes. A datagram is conveyed by a first frame of its length
+//! and its first bytes, followed by consecutive frames each of a sequence
+//! number and the bytes following, akin to a minimal ISO-TP. The identifier
+//! of each frame conveys the address of a server and whether the frame is to
+//! or from it, so that a controller's filters may accept only the frames of
+//! interest, see [can_id]. A [Reassembler] reassembles the datagrams of
+//! several servers at once, their frames interleaving on the bus. Requires
+//! the `can` feature.
+
+use core::{future::poll_fn, task::Poll};
+
+use embedded_can::{nb::Can, Frame, Id, StandardId};
+use heapless::Vec;
+
+use crate::transport::Transport;
+
+// The bit of a frame's identifier telling that it is from a server.
+const FROM_SERVER: u16 = 1 << 8;
+
+// The bit of a frame's first byte telling that it is a first frame, the
+// remaining bits being the upper bits of the datagram's length, or else the
+// sequence number of a consecutive frame.
+const FIRST_FRAME: u8 = 0x80;
+
+/// The most bytes that a datagram conveyed over CAN may be, its length being
+/// conveyed in 15 bits.
+pub const MAX_DATAGRAM_LEN: usize = 0x7fff;
+
+/// Whether a frame is conveyed to a server or from it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// A frame of a client's request.
+    ToServer,
+    /// A frame of a server's reply.
+    FromServer,
+}
+
+/// The standard identifier of the frames conveyed to or from the server at
+/// an address. Identifiers are laid out as follows:
+/// 0..=7   server address
+/// 8..=8   direction 0 = to server, 1 = from server
+/// 9..=10  reserved - must be zero
+pub fn can_id(server_address: u8, direction: Direction) -> StandardId {
+    let direction = match direction {
+        Direction::ToServer => 0,
+        Direction::FromServer => FROM_SERVER,
+    };
+    StandardId::new(direction | server_address as u16).unwrap()
+}
+
+/// The server address and direction conveyed by an identifier, if it is one
+/// of those of [can_id].
+pub fn parse_can_id(id: Id) -> Option<(u8, Direction)> {
+    let Id::Standard(id) = id else {
+        return None;
+    };
+    let raw = id.as_raw();
+    let direction = match raw & !0xff {
+        0 => Direction::ToServer,
+        FROM_SERVER => Direction::FromServer,
+        _ => return None,
+    };
+    Some((raw as u8, direction))
+}
+
+/// The data of the frames conveying a datagram, in the order that they are
+/// to be transmitted.
+pub struct Fragments<'a> {
+    datagram: &'a [u8],
+    sequence: Option<u8>,
+}
+
+impl<'a> Fragments<'a> {
+    /// The fragments of a datagram of up to [MAX_DATAGRAM_LEN] bytes.
+    pub fn new(datagram: &'a [u8]) -> Option<Self> {
+        (datagram.len() <= MAX_DATAGRAM_LEN).then_some(Self {
+            datagram,
+            sequence: None,
+        })
+    }
+}
+
+impl Iterator for Fragments<'_> {
+    type Item = Vec<u8, 8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut data = Vec::new();
+        let taken = match self.sequence {
+            None => {
+                let len = self.datagram.len() as u16;
+                let _ = data.extend_from_slice(&[FIRST_FRAME | (len >> 8) as u8, len as u8]);
+                self.sequence = Some(0);
+                6
+            }
+            Some(_) if self.datagram.is_empty() => return None,
+            Some(sequence) => {
+                let sequence = (sequence + 1) & !FIRST_FRAME;
+                let _ = data.push(sequence);
+                self.sequence = Some(sequence);
+                7
+            }
+        };
+        let (taken, rest) = self.datagram.split_at(taken.min(self.datagram.len()));
+        let _ = data.extend_from_slice(taken);
+        self.datagram = rest;
+        Some(data)
+    }
+}
+
+// A datagram being reassembled.
+struct Partial<const N: usize> {
+    id: Id,
+    buf: [u8; N],
+    len: usize,
+    expected: usize,
+    sequence: u8,
+}
+
+/// Reassembles the datagrams of up to `N` bytes conveyed by the frames
+/// received, for up to `SOURCES` identifiers at once. A datagram is
+/// discarded should a frame of it be missed, and the oldest partial
+/// datagram is discarded to begin another should there be no room for it.
+pub struct Reassembler<const SOURCES: usize, const N: usize> {
+    partials: Vec<Partial<N>, SOURCES>,
+}
+
+impl<const SOURCES: usize, const N: usize> Reassembler<SOURCES, N> {
+    /// A reassembler of no datagrams.
+    pub const fn new() -> Self {
+        Self {
+            partials: Vec::new(),
+        }
+    }
+
+    /// Reassemble a frame received, returning the datagram that it
+    /// completes along with the identifier of its frames.
+    pub fn receive(&mut self, frame: &impl Frame) -> Option<(Id, &[u8])> {
+        // A datagram returned when the last frame was received is done with.
+        self.partials.retain(|p| p.len < p.expected);
+        let (&pci, data) = frame.data().split_first()?;
+        let id = frame.id();
+        let i = self.partials.iter().position(|p| p.id == id);
+        let i = if pci & FIRST_FRAME != 0 {
+            let (&len, data) = data.split_first()?;
+            let expected = ((pci & !FIRST_FRAME) as usize) << 8 | len as usize;
+            if let Some(i) = i {
+                self.partials.remove(i);
+            }
+            if expected > N {
+                return None;
+            }
+            if self.partials.is_full() {
+                self.partials.remove(0);
+            }
+            let partial = Partial {
+                id,
+                buf: [0; N],
+                len: 0,
+                expected,
+                sequence: 0,
+            };
+            let _ = self.partials.push(partial);
+            let i = self.partials.len() - 1;
+            self.partials[i].append(data);
+            i
+        } else {
+            let i = i?;
+            let partial = &mut self.partials[i];
+            partial.sequence = (partial.sequence + 1) & !FIRST_FRAME;
+            if pci != partial.sequence {
+                self.partials.remove(i);
+                return None;
+            }
+            partial.append(data);
+            i
+        };
+        if self.partials[i].len < self.partials[i].expected {
+            return None;
+        }
+        // The datagram is complete, and remains in place until the next frame
+        // is received.
+        let partial = &self.partials[i];
+        Some((partial.id, &partial.buf[..partial.len]))
+    }
+}
+
+impl<const N: usize> Partial<N> {
+    fn append(&mut self, data: &[u8]) {
+        let len = data.len().min(self.expected - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&data[..len]);
+        self.len += len;
+    }
+}
+
+impl<const SOURCES: usize, const N: usize> Default for Reassembler<SOURCES, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Problems conveying datagrams over CAN.
+#[derive(Debug, Eq, PartialEq)]
+pub enum CanError<E> {
+    /// The controller failed.
+    Can(E),
+    /// A datagram is longer than may be sent.
+    TooLong,
+}
+
+/// Conveys datagrams of up to `N` bytes over CAN with a controller of
+/// `embedded-can`, as a client of servers at any address, or as the server
+/// at an address. Datagrams are addressed by the address of the server
+/// whichever the role, and the datagrams of up to `SOURCES` servers are
+/// reassembled at once. The controller is polled while it is not ready,
+/// yielding to the executor meanwhile. Requires the `can` feature.
+pub struct CanTransport<C, const SOURCES: usize, const N: usize> {
+    can: C,
+    // The address of the server, or none for a client.
+    server_address: Option<u8>,
+    reassembler: Reassembler<SOURCES, N>,
+}
+
+impl<C, const SOURCES: usize, const N: usize> CanTransport<C, SOURCES, N> {
+    /// A transport conveying the requests of a client and the replies of its
+    /// servers.
+    pub fn client(can: C) -> Self {
+        Self {
+            can,
+            server_address: None,
+            reassembler: Reassembler::new(),
+        }
+    }
+
+    /// A transport conveying the requests to the server at the address
+    /// given, and its replies.
+    pub fn server(can: C, server_address: u8) -> Self {
+        Self {
+            can,
+            server_address: Some(server_address),
+            reassembler: Reassembler::new(),
+        }
+    }
+}
+
+impl<C: Can, const SOURCES: usize, const N: usize> Transport for CanTransport<C, SOURCES, N> {
+    type Address = u8;
+    type Error = CanError<C::Error>;
+
+    async fn send(&mut self, server_address: &u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        let direction = match self.server_address {
+            None => Direction::ToServer,
+            Some(_) => Direction::FromServer,
+        };
+        let id = can_id(*server_address, direction);
+        for data in Fragments::new(bytes).ok_or(CanError::TooLong)? {
+            let mut frame = C::Frame::new(id, &data).ok_or(CanError::TooLong)?;
+            // A frame of lower priority replaced by this one is transmitted
+            // again.
+            while let Some(replaced) = poll_nb(|| self.can.transmit(&frame))
+                .await
+                .map_err(CanError::Can)?
+            {
+                frame = replaced;
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, u8), Self::Error> {
+        loop {
+            let frame = poll_nb(|| self.can.receive())
+                .await
+                .map_err(CanError::Can)?;
+            let wanted = match (parse_can_id(frame.id()), self.server_address) {
+                (Some((_, Direction::FromServer)), None) => true,
+                (Some((address, Direction::ToServer)), Some(server_address)) => {
+                    address == server_address
+                }
+                _ => false,
+            };
+            if !wanted || frame.is_remote_frame() {
+                continue;
+            }
+            if let Some((id, datagram)) = self.reassembler.receive(&frame) {
+                let (address, _) = parse_can_id(id).unwrap();
+                if let Some(buf) = buf.get_mut(..datagram.len()) {
+                    buf.copy_from_slice(datagram);
+                    return Ok((datagram.len(), address));
+                }
+            }
+        }
+    }
+}
+
+// Poll an operation of a controller until it is no longer blocked.
+async fn poll_nb<T, E>(mut f: impl FnMut() -> nb::Result<T, E>) -> Result<T, E> {
+    poll_fn(|cx| match f() {
+        Ok(t) => Poll::Ready(Ok(t)),
+        Err(nb::Error::Other(e)) => Poll::Ready(Err(e)),
+        Err(nb::Error::WouldBlock) => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{cell::RefCell, rc::Rc, vec::Vec};
+
+    use embedded_can::ExtendedId;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct MockFrame {
+        id: Id,
+        data: Vec<u8>,
+    }
+
+    impl Frame for MockFrame {
+        fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+            (data.len() <= 8).then(|| MockFrame {
+                id: id.into(),
+                data: data.to_vec(),
+            })
+        }
+
+        fn new_remote(_id: impl Into<Id>, _dlc: usize) -> Option<Self> {
+            None
+        }
+
+        fn is_extended(&self) -> bool {
+            matches!(self.id, Id::Extended(_))
+        }
+
+        fn is_remote_frame(&self) -> bool {
+            false
+        }
+
+        fn id(&self) -> Id {
+            self.id
+        }
+
+        fn dlc(&self) -> usize {
+            self.data.len()
+        }
+
+        fn data(&self) -> &[u8] {
+            &self.data
+        }
+    }
+
+    // The frames transmitted on the bus, in order, along with the node
+    // transmitting each.
+    type Bus = Rc<RefCell<Vec<(usize, MockFrame)>>>;
+
+    // A node hears every frame but its own, and is busy every other time
+    // that it transmits so that the transmissions of nodes interleave.
+    struct MockCan {
+        node: usize,
+        bus: Bus,
+        heard: usize,
+        busy: bool,
+    }
+
+    impl MockCan {
+        fn new(node: usize, bus: &Bus) -> Self {
+            Self {
+                node,
+                bus: bus.clone(),
+                heard: 0,
+                busy: false,
+            }
+        }
+    }
+
+    impl Can for MockCan {
+        type Frame = MockFrame;
+        type Error = core::convert::Infallible;
+
+        fn transmit(&mut self, frame: &MockFrame) -> nb::Result<Option<MockFrame>, Self::Error> {
+            self.busy = !self.busy;
+            if self.busy {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.bus.borrow_mut().push((self.node, frame.clone()));
+            Ok(None)
+        }
+
+        fn receive(&mut self) -> nb::Result<MockFrame, Self::Error> {
+            let bus = self.bus.borrow();
+            while let Some((node, frame)) = bus.get(self.heard) {
+                self.heard += 1;
+                if *node != self.node {
+                    return Ok(frame.clone());
+                }
+            }
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    type Node = CanTransport<MockCan, 2, 32>;
+
+    #[test]
+    fn test_fragmentation() {
+        // A first frame conveys 6 bytes and each consecutive frame 7.
+        let datagram = (0..20).collect::<Vec<u8>>();
+        let fragments = Fragments::new(&datagram).unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            fragments,
+            [
+                &[0x80, 20, 0, 1, 2, 3, 4, 5][..],
+                &[1, 6, 7, 8, 9, 10, 11, 12],
+                &[2, 13, 14, 15, 16, 17, 18, 19],
+            ]
+        );
+        assert_eq!(Fragments::new(&[]).unwrap().count(), 1);
+        assert!(Fragments::new(&[0; MAX_DATAGRAM_LEN + 1]).is_none());
+
+        let id = can_id(5, Direction::FromServer);
+        assert_eq!(id.as_raw(), 0x105);
+        assert_eq!(parse_can_id(id.into()), Some((5, Direction::FromServer)));
+        assert_eq!(parse_can_id(StandardId::new(0x205).unwrap().into()), None);
+        assert_eq!(parse_can_id(ExtendedId::new(5).unwrap().into()), None);
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_servers() {
+        let bus = Bus::default();
+        let mut client = Node::client(MockCan::new(0, &bus));
+        let mut server_1 = Node::server(MockCan::new(1, &bus), 1);
+        let mut server_2 = Node::server(MockCan::new(2, &bus), 2);
+
+        client.send(&1, &[1; 10]).await.unwrap();
+        let mut buf = [0; 32];
+        assert_eq!(server_1.recv(&mut buf).await, Ok((10, 1)));
+        assert_eq!(buf[..10], [1; 10]);
+
+        // Both servers reply at once, their frames interleaving, and the
+        // client reassembles each.
+        let reply_1 = (0..20).collect::<Vec<u8>>();
+        let reply_2 = (100..125).collect::<Vec<u8>>();
+        let (sent_1, sent_2) =
+            tokio::join!(server_1.send(&1, &reply_1), server_2.send(&2, &reply_2));
+        assert_eq!((sent_1, sent_2), (Ok(()), Ok(())));
+        let nodes = bus.borrow().iter().map(|(n, _)| *n).collect::<Vec<_>>();
+        let switches = nodes[2..].windows(2).filter(|n| n[0] != n[1]).count();
+        assert!(switches > 1, "{nodes:?}");
+
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let (len, address) = client.recv(&mut buf).await.unwrap();
+            received.push((address, buf[..len].to_vec()));
+        }
+        received.sort();
+        assert_eq!(received, [(1, reply_1), (2, reply_2)]);
+    }
+
+    #[test]
+    fn test_lost_frame() {
+        let mut reassembler = Reassembler::<1, 32>::new();
+        let id = can_id(1, Direction::FromServer);
+        let frame = |data: &[u8]| MockFrame::new(id, data).unwrap();
+        let fragments = |datagram| {
+            Fragments::new(datagram)
+                .unwrap()
+                .map(|data| frame(&data))
+                .collect::<Vec<_>>()
+        };
+
+        // A datagram missing a consecutive frame is discarded, whereas the
+        // first frame of another begins it afresh.
+        let lossy = fragments(&[1; 20]);
+        assert_eq!(reassembler.receive(&lossy[0]), None);
+        assert_eq!(reassembler.receive(&lossy[2]), None);
+        let whole = fragments(&[2; 10]);
+        assert_eq!(reassembler.receive(&lossy[0]), None);
+        assert_eq!(reassembler.receive(&whole[0]), None);
+        assert_eq!(
+            reassembler.receive(&whole[1]),
+            Some((id.into(), &[2; 10][..]))
+        );
+        assert_eq!(reassembler.receive(&whole[1]), None);
+
+        // A datagram longer than may be reassembled is discarded.
+        assert_eq!(reassembler.receive(&fragments(&[3; 33])[0]), None);
+    }
+}
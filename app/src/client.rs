@@ -0,0 +1,1484 @@
+//! The behaviour of a client polling its servers on a half-duplex bus,
+//! independent of how datagrams are conveyed and how time is kept. An
+//! application asks a [ClientEngine] what to do next, conveys the requests
+//! that it transmits, and feeds it the replies received and the timeouts
+//! elapsed, whether under an async runtime or a bare-metal superloop.
+
+use heapless::{Deque, Vec};
+use postcard::experimental::max_size::MaxSize;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    clock::ExchangeClock,
+    observer::{NoObserver, ObservedError, ObservedState, ProtocolObserver},
+    offset_store::{Debounce, OffsetStore, OffsetTable, Unsaved},
+    offset_tracker::{Observation, OffsetTracker},
+    shutdown::{Shutdown, ShutdownOf},
+    EventBatchReply, EventOf, EventReply, MultiCommandRequest, NoEE, NoSnapshot, Offset,
+    RequestHeader, TemporalEvent, TickRate,
+};
+
+#[cfg(feature = "data")]
+use {
+    aead::AeadCore,
+    flip_flop_data::{from_datagram_with_provider, DataSource, Header, KeyProvider, NonceScheme},
+};
+
+/// How a [ClientEngine] polls its servers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientConfig {
+    /// The rate of the client's ticks, in which the times given to the
+    /// engine are expressed.
+    pub tick_rate: TickRate,
+    /// How long to await a reply before the server polled is considered
+    /// silent and the next is polled.
+    pub reply_timeout_ticks: u64,
+    /// Whether to convey the client's time with each request, see
+    /// [crate::CommandRequest::client_time]. Servers unaware of the client's
+    /// time refuse requests conveying it.
+    pub client_time: bool,
+    /// Whether to ask that replies take no more than the `N` bytes that the
+    /// engine decodes, up to 255, see [crate::CommandRequest::max_reply_len].
+    /// Servers unaware of the length asked for refuse requests conveying it.
+    pub max_reply_len: bool,
+}
+
+/// What an application is to do next, as told by [ClientEngine::next_action].
+#[derive(Debug, Eq, PartialEq)]
+pub enum Action<'a, A> {
+    /// Transmit the bytes of a request to the server at an address, wrapping
+    /// them in a datagram with the frame counter given if conveyed by the
+    /// data link layer.
+    Transmit {
+        address: A,
+        frame_counter: u16,
+        bytes: &'a [u8],
+    },
+    /// Await a reply, or the next poll, until the client's ticks given, then
+    /// call [ClientEngine::handle_timeout]. `u64::MAX` is given when there
+    /// are no servers to poll.
+    Wait { until: u64 },
+}
+
+/// The events replied by a server, as delivered by
+/// [ClientEngine::handle_frame].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Delivery<A, E: TemporalEvent, O, const EVENTS: usize> {
+    /// The address of the server.
+    pub address: A,
+    /// The replies to consume, being those of the batch for as long as they
+    /// are consecutive, see [EventBatchReply::consecutive], each with what
+    /// the client has learnt from it. Replies of events received before are
+    /// not delivered unless asked for, see
+    /// [ClientEngine::set_deliver_duplicates].
+    pub replies: Vec<(EventReply<E>, Observation<O>), EVENTS>,
+    /// The number of events pending following those replied.
+    pub pending: u8,
+    /// Tells the client's time of the events replied, once the rate of the
+    /// server's ticks is known from its status.
+    pub clock: Option<ExchangeClock>,
+    /// The server's ticks as of replying, if asked for, in which case the
+    /// ticks of each reply are the server's ticks as of its event rather
+    /// than its age, see [ClientEngine::set_absolute_ticks].
+    pub server_ticks: Option<u64>,
+}
+
+/// The events of a [ClientEngine] as delivered by
+/// [ClientEngine::handle_frame].
+pub type EventDelivery<A, E, EE, O, S, const EVENTS: usize> =
+    Delivery<A, EventOf<E, EE, O, S>, O, EVENTS>;
+
+/// Whether a server is answering its polls, as told by
+/// [ClientEngine::next_liveness_change].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Liveness {
+    /// The server is answering. Servers are presumed online when added.
+    Online,
+    /// The server has not answered its last few polls.
+    Suspect,
+    /// The server has not answered for long enough to be considered gone,
+    /// and so is only probed now and then until it returns.
+    Offline,
+}
+
+/// When a server's [Liveness] changes, given the polls that it answers or
+/// not in succession.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LivenessConfig {
+    /// The polls unanswered in succession for an online server to be
+    /// suspect. A suspect server is online again once it answers.
+    pub suspect_after: u32,
+    /// The polls unanswered in succession for a server to be offline.
+    pub offline_after: u32,
+    /// The polls answered in succession for an offline server to be online
+    /// again, so that a server that comes and goes remains offline.
+    pub online_after: u32,
+    /// An offline server is probed every so many of its poll intervals,
+    /// until it answers.
+    pub probe_every: u32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        Self {
+            suspect_after: 2,
+            offline_after: 5,
+            online_after: 3,
+            probe_every: 10,
+        }
+    }
+}
+
+/// The priority of a command queued for a server, see
+/// [ClientEngine::command_with_priority]. Urgent commands are conveyed ahead
+/// of normal ones, and each in the order queued.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    /// Commands conveyed in the order queued.
+    Normal,
+    /// Commands that cannot wait e.g. to stop a machine, which are conveyed
+    /// with the server's next request.
+    Urgent,
+}
+
+/// What becomes of a command queued for a server whose queue is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum QueuePolicy {
+    /// The command is refused.
+    #[default]
+    Reject,
+    /// The oldest normal command is overwritten, the command being refused
+    /// only if all those queued are urgent.
+    OverwriteOldestNormal,
+}
+
+struct Server<A, C, O, const COMMANDS: usize> {
+    address: A,
+    poll_interval_ticks: u64,
+    next_poll_ticks: u64,
+    tracker: OffsetTracker<O>,
+    unsaved: Unsaved<O>,
+    frame_counter: u16,
+    // Ordered by priority, and then by when queued.
+    commands: Vec<(Priority, C), COMMANDS>,
+    queue_policy: QueuePolicy,
+    // Known from the server's status, which is requested until it is.
+    tick_rate: Option<TickRate>,
+    status_requested: bool,
+    absolute_ticks: bool,
+    liveness: Liveness,
+    liveness_config: LivenessConfig,
+    silent_polls: u32,
+    answered_polls: u32,
+    // Polls unanswered until then are not counted, the server having
+    // announced that it sleeps.
+    sleeps_until: Option<u64>,
+    // The server has announced that it shuts down, and so is expected to
+    // have reset once it returns.
+    restart_expected: bool,
+}
+
+impl<A, C, O, const COMMANDS: usize> Server<A, C, O, COMMANDS> {
+    fn poll_interval_ticks(&self) -> u64 {
+        if self.liveness == Liveness::Offline && self.answered_polls == 0 {
+            self.poll_interval_ticks
+                .saturating_mul(self.liveness_config.probe_every.max(1) as u64)
+        } else {
+            self.poll_interval_ticks
+        }
+    }
+
+    // Note that a poll was answered as of the ticks given, returning the
+    // server's liveness should it change.
+    fn answered(&mut self, now_ticks: u64) -> Option<Liveness> {
+        self.silent_polls = 0;
+        self.answered_polls = self.answered_polls.saturating_add(1);
+        if self.liveness == Liveness::Offline {
+            // A server returning is polled at its interval rather than
+            // probed, so that it is soon known to be online.
+            self.next_poll_ticks = self
+                .next_poll_ticks
+                .min(now_ticks.saturating_add(self.poll_interval_ticks));
+        }
+        let liveness = match self.liveness {
+            Liveness::Offline if self.answered_polls < self.liveness_config.online_after => {
+                Liveness::Offline
+            }
+            _ => Liveness::Online,
+        };
+        self.change_liveness(liveness)
+    }
+
+    // Note that a poll was unanswered as of the ticks given, returning the
+    // server's liveness should it change.
+    fn unanswered(&mut self, now_ticks: u64) -> Option<Liveness> {
+        if self.sleeps_until.is_some_and(|until| now_ticks <= until) {
+            return None;
+        }
+        self.answered_polls = 0;
+        self.silent_polls = self.silent_polls.saturating_add(1);
+        let liveness = if self.silent_polls >= self.liveness_config.offline_after {
+            Liveness::Offline
+        } else if self.silent_polls >= self.liveness_config.suspect_after
+            && self.liveness == Liveness::Online
+        {
+            Liveness::Suspect
+        } else {
+            self.liveness
+        };
+        let changed = self.change_liveness(liveness);
+        if liveness == Liveness::Offline {
+            // The server may reboot while offline, and so its status is
+            // requested again once it returns.
+            self.tick_rate = None;
+            self.next_poll_ticks = now_ticks.saturating_add(self.poll_interval_ticks());
+        }
+        changed
+    }
+
+    // Expect the server to be silent for the rest of the downtime that it
+    // announced as of the client's ticks given, the announcement being of
+    // the age given in the server's ticks.
+    fn expect_shutdown(
+        &mut self,
+        shutdown: &Shutdown,
+        age_ticks: u64,
+        client_tick_rate: TickRate,
+        now_ticks: u64,
+    ) {
+        let remaining_ticks = shutdown.expected_downtime_ticks.saturating_sub(age_ticks);
+        if remaining_ticks == 0 {
+            return;
+        }
+        let tick_rate = self.tick_rate.unwrap_or(client_tick_rate);
+        let remaining_ticks =
+            client_tick_rate.from_duration(tick_rate.to_duration(remaining_ticks));
+        self.sleeps_until = Some(now_ticks.saturating_add(remaining_ticks));
+        self.restart_expected = true;
+    }
+
+    fn change_liveness(&mut self, liveness: Liveness) -> Option<Liveness> {
+        (self.liveness != liveness).then(|| {
+            self.liveness = liveness;
+            liveness
+        })
+    }
+
+    // Queue a command behind those of the same priority or higher, returning
+    // the command overwritten, if any.
+    fn queue(&mut self, command: C, priority: Priority) -> Result<Option<C>, C> {
+        let overwritten = if self.commands.is_full() {
+            let oldest_normal = self
+                .commands
+                .iter()
+                .position(|(p, _)| *p == Priority::Normal);
+            match (self.queue_policy, oldest_normal) {
+                (QueuePolicy::OverwriteOldestNormal, Some(i)) => Some(self.commands.remove(i).1),
+                _ => return Err(command),
+            }
+        } else {
+            None
+        };
+        let i = self
+            .commands
+            .iter()
+            .position(|(p, _)| *p < priority)
+            .unwrap_or(self.commands.len());
+        let _ = self.commands.insert(i, (priority, command));
+        Ok(overwritten)
+    }
+}
+
+// The request transmitted and awaiting a reply.
+struct Exchange<A> {
+    address: A,
+    sent_ticks: u64,
+    deadline_ticks: u64,
+}
+
+/// Polls up to `SERVERS` servers, keyed by their addresses, each at its own
+/// interval and one at a time given a half-duplex bus. The engine tracks the
+/// events received from each server, see [OffsetTracker], and conveys up to
+/// `COMMANDS` of the commands queued for a server with each request of it.
+/// Replies of up to `EVENTS` events are decoded, and requests are encoded
+/// within `N` bytes.
+///
+/// Commands are conveyed at most once, being dequeued when transmitted
+/// whether or not the server replies.
+///
+/// The offsets of the servers may be saved to an [OffsetStore] as they
+/// change, see [ClientEngine::persist_offsets], and restored from it once
+/// the client restarts, see [ClientEngine::restore_offsets], so that every
+/// server need not be recovered at once.
+///
+/// The engine tells a [ProtocolObserver] of what it does, being a
+/// [NoObserver] unless given otherwise, see [ClientEngine::with_observer].
+pub struct ClientEngine<
+    A,
+    C,
+    E,
+    const SERVERS: usize,
+    const COMMANDS: usize,
+    const EVENTS: usize,
+    const N: usize,
+    EE = NoEE,
+    O = u32,
+    S = NoSnapshot,
+    P = NoObserver,
+> where
+    C: Serialize,
+{
+    config: ClientConfig,
+    servers: Vec<Server<A, C, O, COMMANDS>, SERVERS>,
+    awaiting: Option<Exchange<A>>,
+    liveness_changes: Deque<(A, Liveness), SERVERS>,
+    deliver_duplicates: bool,
+    debounce: Debounce,
+    shutdown_of: Option<fn(&E) -> Option<Shutdown>>,
+    buf: [u8; N],
+    observer: P,
+    _events: core::marker::PhantomData<(E, EE, S)>,
+}
+
+impl<
+        A,
+        C,
+        E,
+        const SERVERS: usize,
+        const COMMANDS: usize,
+        const EVENTS: usize,
+        const N: usize,
+        EE,
+        O,
+        S,
+    > ClientEngine<A, C, E, SERVERS, COMMANDS, EVENTS, N, EE, O, S>
+where
+    A: Clone + Eq,
+    C: Serialize,
+    O: Offset + MaxSize,
+    EventOf<E, EE, O, S>: TemporalEvent + DeserializeOwned,
+{
+    /// An engine yet to know of any servers. A request of no commands must
+    /// fit within `N` bytes.
+    pub fn new(config: ClientConfig) -> Self {
+        const { assert!(RequestHeader::<O>::POSTCARD_MAX_SIZE <= N) };
+        Self {
+            config,
+            servers: Vec::new(),
+            awaiting: None,
+            liveness_changes: Deque::new(),
+            deliver_duplicates: false,
+            debounce: Debounce::default(),
+            shutdown_of: None,
+            buf: [0; N],
+            observer: NoObserver,
+            _events: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<
+        A,
+        C,
+        E,
+        const SERVERS: usize,
+        const COMMANDS: usize,
+        const EVENTS: usize,
+        const N: usize,
+        EE,
+        O,
+        S,
+        P,
+    > ClientEngine<A, C, E, SERVERS, COMMANDS, EVENTS, N, EE, O, S, P>
+where
+    A: Clone + Eq,
+    C: Serialize,
+    O: Offset + MaxSize,
+    EventOf<E, EE, O, S>: TemporalEvent + DeserializeOwned,
+    P: ProtocolObserver<A>,
+{
+    /// The engine, telling the observer given of what it does, see
+    /// [crate::observer].
+    pub fn with_observer<Q: ProtocolObserver<A>>(
+        self,
+        observer: Q,
+    ) -> ClientEngine<A, C, E, SERVERS, COMMANDS, EVENTS, N, EE, O, S, Q> {
+        ClientEngine {
+            config: self.config,
+            servers: self.servers,
+            awaiting: self.awaiting,
+            liveness_changes: self.liveness_changes,
+            deliver_duplicates: self.deliver_duplicates,
+            debounce: self.debounce,
+            shutdown_of: self.shutdown_of,
+            buf: self.buf,
+            observer,
+            _events: core::marker::PhantomData,
+        }
+    }
+
+    /// The observer told of what the engine does.
+    pub fn observer_mut(&mut self) -> &mut P {
+        &mut self.observer
+    }
+
+    /// Poll the server at an address every so many of the client's ticks,
+    /// starting with the next action. The address is returned if `SERVERS`
+    /// are already polled.
+    pub fn add_server(&mut self, address: A, poll_interval_ticks: u64) -> Result<(), A> {
+        if let Some(server) = self.server_mut(&address) {
+            server.poll_interval_ticks = poll_interval_ticks;
+            return Ok(());
+        }
+        self.servers
+            .push(Server {
+                address,
+                poll_interval_ticks,
+                next_poll_ticks: 0,
+                tracker: OffsetTracker::new(),
+                unsaved: Unsaved::new(),
+                frame_counter: 0,
+                commands: Vec::new(),
+                queue_policy: QueuePolicy::default(),
+                tick_rate: None,
+                status_requested: false,
+                absolute_ticks: false,
+                liveness: Liveness::Online,
+                liveness_config: LivenessConfig::default(),
+                silent_polls: 0,
+                answered_polls: 0,
+                sleeps_until: None,
+                restart_expected: false,
+            })
+            .map_err(|server| server.address)
+    }
+
+    /// No longer poll the server at an address, forgetting its events and
+    /// the commands queued for it.
+    pub fn remove_server(&mut self, address: &A) {
+        self.servers.retain(|s| s.address != *address);
+        if self
+            .awaiting
+            .as_ref()
+            .is_some_and(|e| e.address == *address)
+        {
+            self.awaiting = None;
+        }
+    }
+
+    /// Queue a command of normal priority to convey with the next request of
+    /// the server at an address. The command is returned if the server is not
+    /// polled or its queue is full, see [QueuePolicy].
+    pub fn command(&mut self, address: &A, command: C) -> Result<(), C> {
+        self.command_with_priority(address, command, Priority::Normal)
+            .map(|_| ())
+    }
+
+    /// Queue a command to convey with the next request of the server at an
+    /// address, ahead of those queued of a lower priority. Up to `COMMANDS`
+    /// are queued, and so a command refused is returned if the server is not
+    /// polled or its queue is full, while the command overwritten, if any, is
+    /// returned otherwise, see [QueuePolicy].
+    pub fn command_with_priority(
+        &mut self,
+        address: &A,
+        command: C,
+        priority: Priority,
+    ) -> Result<Option<C>, C> {
+        match self.server_mut(address) {
+            Some(server) => server.queue(command, priority),
+            None => Err(command),
+        }
+    }
+
+    /// Ask the server at an address to reply the ticks of its events as of
+    /// its own ticks rather than their ages, see
+    /// [crate::CommandRequest::absolute_ticks], e.g. for a client relaying
+    /// events that may be queued before they are consumed. Servers declaring
+    /// the capability during discovery may be asked, while others refuse
+    /// requests asking for it. The server's clock is then told from a
+    /// delivery, see [ExchangeClock::server_clock].
+    pub fn set_absolute_ticks(&mut self, address: &A, absolute_ticks: bool) {
+        if let Some(server) = self.server_mut(address) {
+            server.absolute_ticks = absolute_ticks;
+        }
+    }
+
+    /// Change what becomes of the commands queued for the server at an
+    /// address once its queue is full.
+    pub fn set_queue_policy(&mut self, address: &A, policy: QueuePolicy) {
+        if let Some(server) = self.server_mut(address) {
+            server.queue_policy = policy;
+        }
+    }
+
+    /// Deliver the replies of events received before, observed as
+    /// [Observation::Duplicate], e.g. to audit them. They are not delivered
+    /// by default, and so each of a server's events is delivered once only
+    /// barring its events being recovered.
+    pub fn set_deliver_duplicates(&mut self, deliver: bool) {
+        self.deliver_duplicates = deliver;
+    }
+
+    /// Expect servers to announce that they shut down with events conveying
+    /// a [Shutdown], see [crate::shutdown]. A server's silence is then not
+    /// counted against its liveness for the downtime announced, as of the
+    /// announcement's delivery, while it continues to be polled so that its
+    /// return is soon known. Its recovery once it returns having reset is
+    /// expected, and so its status is then requested again.
+    pub fn expect_shutdowns(&mut self)
+    where
+        E: ShutdownOf,
+    {
+        self.shutdown_of = Some(|event| event.shutdown().copied());
+    }
+
+    /// Change how often the offsets of servers are saved by
+    /// [ClientEngine::persist_offsets]. Every offset is saved by default.
+    pub fn set_debounce(&mut self, debounce: Debounce) {
+        self.debounce = debounce;
+    }
+
+    /// Restore the offsets saved for the servers polled, as saved before the
+    /// client restarted, returning the number restored. The events following
+    /// each offset restored are requested, the offset being stale until the
+    /// server's reply confirms it or sees it recovered, see
+    /// [OffsetTracker::restore].
+    pub fn restore_offsets(&mut self, store: &mut impl OffsetStore<A, O>) -> usize {
+        let mut restored = 0;
+        for server in self.servers.iter_mut() {
+            if let Some(offset) = store.load(&server.address) {
+                server.tracker.restore(offset);
+                server.unsaved = Unsaved::loaded(offset);
+                restored += 1;
+            }
+        }
+        restored
+    }
+
+    /// Save the offsets of the servers polled that have changed since last
+    /// saved, and are due to be saved as of the client's ticks given, see
+    /// [Debounce]. This is to be called now and then e.g. having handled a
+    /// reply.
+    pub fn persist_offsets(&mut self, store: &mut impl OffsetStore<A, O>, now_ticks: u64) {
+        for server in self.servers.iter_mut() {
+            server.unsaved.save(
+                store,
+                &server.address,
+                server.tracker.request(),
+                self.debounce,
+                now_ticks,
+            );
+        }
+    }
+
+    /// The offsets of the servers polled, to be persisted as a whole.
+    pub fn offsets(&self) -> OffsetTable<A, SERVERS, O> {
+        let mut table = OffsetTable::new();
+        for server in &self.servers {
+            if let Some(offset) = server.tracker.request() {
+                table.save(&server.address, offset);
+            }
+        }
+        table
+    }
+
+    /// The number of commands queued for the server at an address, if
+    /// polled, so that an application may hold back further commands.
+    pub fn queue_depth(&self, address: &A) -> Option<usize> {
+        self.server(address).map(|s| s.commands.len())
+    }
+
+    /// Ask for the status of the server at an address with its next request.
+    pub fn request_status(&mut self, address: &A) {
+        if let Some(server) = self.server_mut(address) {
+            server.status_requested = true;
+        }
+    }
+
+    /// The events received from the server at an address, if polled.
+    pub fn tracker(&self, address: &A) -> Option<&OffsetTracker<O>> {
+        self.server(address).map(|s| &s.tracker)
+    }
+
+    /// The number of consecutive polls of the server at an address that it
+    /// has not replied to, if polled. Polls unanswered while the server is
+    /// expected to sleep are not counted, see [ClientEngine::expect_sleep].
+    pub fn silent_polls(&self, address: &A) -> Option<u32> {
+        self.server(address).map(|s| s.silent_polls)
+    }
+
+    /// Change when the server at an address is considered suspect or
+    /// offline, see [Liveness].
+    pub fn set_liveness_config(&mut self, address: &A, config: LivenessConfig) {
+        if let Some(server) = self.server_mut(address) {
+            server.liveness_config = config;
+        }
+    }
+
+    /// Whether the server at an address is answering its polls, if polled.
+    pub fn liveness(&self, address: &A) -> Option<Liveness> {
+        self.server(address).map(|s| s.liveness)
+    }
+
+    /// The next change of a server's liveness, in the order that they
+    /// occurred, if any. The oldest changes are forgotten should more than
+    /// `SERVERS` be yet to be told.
+    pub fn next_liveness_change(&mut self) -> Option<(A, Liveness)> {
+        self.liveness_changes.pop_front()
+    }
+
+    /// Expect the server at an address to be silent until the client's ticks
+    /// given, having announced that it sleeps until then e.g. with an event
+    /// of the application. The server is not polled until then, and the
+    /// polls that it leaves unanswered until then are not counted against
+    /// its liveness.
+    pub fn expect_sleep(&mut self, address: &A, until_ticks: u64) {
+        if let Some(server) = self.server_mut(address) {
+            server.sleeps_until = Some(until_ticks);
+            server.next_poll_ticks = server.next_poll_ticks.max(until_ticks);
+        }
+    }
+
+    /// What to do next as of the client's ticks given, being to transmit a
+    /// request to the server due to be polled, or to wait for a reply or the
+    /// next poll.
+    pub fn next_action(&mut self, now_ticks: u64) -> Action<'_, A> {
+        if let Some(exchange) = &self.awaiting {
+            return Action::Wait {
+                until: exchange.deadline_ticks,
+            };
+        }
+        let Some(server) = self.servers.iter_mut().min_by_key(|s| s.next_poll_ticks) else {
+            return Action::Wait { until: u64::MAX };
+        };
+        if server.next_poll_ticks > now_ticks {
+            return Action::Wait {
+                until: server.next_poll_ticks,
+            };
+        }
+
+        let mut request = MultiCommandRequest::<_, COMMANDS, O>::new(server.tracker.request());
+        request.filter = server.tracker.filter();
+        request.status_requested = server.tick_rate.is_none() || server.status_requested;
+        request.absolute_ticks = server.absolute_ticks;
+        request.client_time = self.config.client_time.then_some(now_ticks);
+        request.max_reply_len = self
+            .config
+            .max_reply_len
+            .then_some(N.min(u8::MAX as usize) as u8);
+        let mut priorities = Vec::<_, COMMANDS>::new();
+        while !request.commands.is_full() && !server.commands.is_empty() {
+            let (priority, command) = server.commands.remove(0);
+            let _ = priorities.push(priority);
+            let _ = request.commands.push(command);
+        }
+        // Commands not fitting are returned to the queue for the next
+        // request, a request of no commands always fitting.
+        let len = loop {
+            match postcard::to_slice(&request, &mut self.buf) {
+                Ok(bytes) => break bytes.len(),
+                Err(_) => match request.commands.pop().zip(priorities.pop()) {
+                    Some((command, priority)) => {
+                        let _ = server.commands.insert(0, (priority, command));
+                    }
+                    None => break 0,
+                },
+            }
+        };
+        server.status_requested = false;
+
+        // Polls keep to their interval, unless the client has fallen behind
+        // by more than an interval.
+        let poll_interval_ticks = server.poll_interval_ticks();
+        let next_poll_ticks = server.next_poll_ticks.saturating_add(poll_interval_ticks);
+        server.next_poll_ticks = if next_poll_ticks > now_ticks {
+            next_poll_ticks
+        } else {
+            now_ticks.saturating_add(poll_interval_ticks)
+        };
+        let frame_counter = server.frame_counter;
+        server.frame_counter = frame_counter.wrapping_add(1);
+
+        self.awaiting = Some(Exchange {
+            address: server.address.clone(),
+            sent_ticks: now_ticks,
+            deadline_ticks: now_ticks.saturating_add(self.config.reply_timeout_ticks),
+        });
+        self.observer.on_tx(&server.address, len);
+        Action::Transmit {
+            address: server.address.clone(),
+            frame_counter,
+            bytes: &self.buf[..len],
+        }
+    }
+
+    /// Handle the bytes of a reply received from the server at an address
+    /// as of the client's ticks given, returning the events to consume.
+    /// Nothing is returned for replies that are not awaited or that cannot be
+    /// decoded, the reply continuing to be awaited.
+    pub fn handle_frame(
+        &mut self,
+        address: &A,
+        bytes: &[u8],
+        now_ticks: u64,
+    ) -> Option<EventDelivery<A, E, EE, O, S, EVENTS>> {
+        self.observer.on_rx(address, bytes.len());
+        if !self
+            .awaiting
+            .as_ref()
+            .is_some_and(|e| e.address == *address)
+        {
+            self.observer
+                .on_error(Some(address), &ObservedError::Unexpected);
+            return None;
+        }
+        let Ok(batch) =
+            postcard::from_bytes::<EventBatchReply<EventOf<E, EE, O, S>, EVENTS>>(bytes)
+        else {
+            self.observer
+                .on_error(Some(address), &ObservedError::CannotDecode);
+            return None;
+        };
+        let exchange = self.awaiting.take()?;
+        let client_tick_rate = self.config.tick_rate;
+        let server = self.servers.iter_mut().find(|s| s.address == *address)?;
+        if let Some(liveness) = server.answered(now_ticks) {
+            Self::tell_liveness(
+                &mut self.liveness_changes,
+                &mut self.observer,
+                address.clone(),
+                liveness,
+            );
+        }
+
+        let consecutive = batch.consecutive().count();
+        let server_ticks = batch.server_ticks;
+        let mut replies = Vec::new();
+        for reply in batch.replies.into_iter().take(consecutive) {
+            if let Some(EventOf::Status(status)) = &reply.event {
+                server.tick_rate = Some(status.tick_rate);
+            }
+            let last_event_offset = server.tracker.request();
+            let observation = server.tracker.observe(&reply);
+            if server.tracker.request() != last_event_offset {
+                server.unsaved.received();
+            }
+            match (&observation, &reply.event) {
+                (Observation::NewEvent, Some(EventOf::Logged(event, _))) => {
+                    if let Some(shutdown) = self.shutdown_of.and_then(|of| of(event)) {
+                        let age_ticks = server_ticks
+                            .map_or(reply.delta_ticks, |t| t.saturating_sub(reply.delta_ticks));
+                        server.expect_shutdown(&shutdown, age_ticks, client_tick_rate, now_ticks);
+                    }
+                }
+                // The server's tick rate may have changed as it reset.
+                (Observation::RecoveryNeeded { .. }, _) if server.restart_expected => {
+                    server.restart_expected = false;
+                    server.tick_rate = None;
+                }
+                _ => {}
+            }
+            if matches!(observation, Observation::Duplicate) && !self.deliver_duplicates {
+                continue;
+            }
+            let _ = replies.push((reply, observation));
+        }
+        Some(Delivery {
+            address: exchange.address,
+            replies,
+            pending: batch.pending,
+            clock: server.tick_rate.map(|tick_rate| ExchangeClock {
+                tick_rate,
+                client_tick_rate,
+                sent_ticks: exchange.sent_ticks,
+                received_ticks: now_ticks,
+            }),
+            server_ticks: batch.server_ticks,
+        })
+    }
+
+    /// As per [ClientEngine::handle_frame], but for a datagram of the data
+    /// link layer, decrypting the reply with the cipher that the provider
+    /// has for its header. The header is parsed once, and the address of the
+    /// server that replied is given by the function given of it. Requires the
+    /// `data` feature.
+    #[cfg(feature = "data")]
+    pub fn handle_datagram<K, const D: usize>(
+        &mut self,
+        provider: &K,
+        datagram_buf: &[u8; D],
+        address_of: impl FnOnce(&Header) -> A,
+        now_ticks: u64,
+    ) -> Option<EventDelivery<A, E, EE, O, S, EVENTS>>
+    where
+        K: KeyProvider,
+        <K::Cipher as AeadCore>::NonceSize: NonceScheme,
+    {
+        let is_reply = |header: &Header| header.source == DataSource::Server;
+        match from_datagram_with_provider(datagram_buf, is_reply, provider) {
+            Ok((header, payload_buf)) => {
+                self.handle_frame(&address_of(&header), &payload_buf, now_ticks)
+            }
+            Err(e) => {
+                self.observer
+                    .on_error(None, &ObservedError::CannotDecodeDatagram(e));
+                None
+            }
+        }
+    }
+
+    /// Handle the passing of time as of the client's ticks given, returning
+    /// the address of the server polled if its reply is no longer awaited.
+    pub fn handle_timeout(&mut self, now_ticks: u64) -> Option<A> {
+        let exchange = self.awaiting.take_if(|e| now_ticks >= e.deadline_ticks)?;
+        self.observer
+            .on_state(&exchange.address, ObservedState::TimedOut);
+        let server = self
+            .servers
+            .iter_mut()
+            .find(|s| s.address == exchange.address);
+        if let Some(liveness) = server.and_then(|s| s.unanswered(now_ticks)) {
+            Self::tell_liveness(
+                &mut self.liveness_changes,
+                &mut self.observer,
+                exchange.address.clone(),
+                liveness,
+            );
+        }
+        Some(exchange.address)
+    }
+
+    fn tell_liveness(
+        changes: &mut Deque<(A, Liveness), SERVERS>,
+        observer: &mut P,
+        address: A,
+        liveness: Liveness,
+    ) {
+        observer.on_state(&address, ObservedState::Liveness(liveness));
+        if changes.is_full() {
+            changes.pop_front();
+        }
+        let _ = changes.push_back((address, liveness));
+    }
+
+    fn server(&self, address: &A) -> Option<&Server<A, C, O, COMMANDS>> {
+        self.servers.iter().find(|s| s.address == *address)
+    }
+
+    fn server_mut(&mut self, address: &A) -> Option<&mut Server<A, C, O, COMMANDS>> {
+        self.servers.iter_mut().find(|s| s.address == *address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        observer::tests::{Observed, Recorder},
+        offset_tracker::Backlog,
+        ResetCause, ServerStatus,
+    };
+
+    type Engine = ClientEngine<u8, u8, u8, 2, 2, 4, 32>;
+    type Request = MultiCommandRequest<u8, 2>;
+    type Batch = EventBatchReply<EventOf<u8, NoEE>, 4>;
+
+    const CONFIG: ClientConfig = ClientConfig {
+        tick_rate: TickRate::MILLISECONDS,
+        reply_timeout_ticks: 10,
+        client_time: false,
+        max_reply_len: false,
+    };
+
+    // Returns the address and request transmitted, if any.
+    fn transmit(engine: &mut Engine, now_ticks: u64) -> Option<(u8, Request)> {
+        match engine.next_action(now_ticks) {
+            Action::Transmit { address, bytes, .. } => {
+                Some((address, postcard::from_bytes(bytes).unwrap()))
+            }
+            Action::Wait { .. } => None,
+        }
+    }
+
+    fn reply(
+        engine: &mut Engine,
+        address: u8,
+        events: &[EventOf<u8, NoEE>],
+        now_ticks: u64,
+    ) -> Option<Delivery<u8, EventOf<u8, NoEE>, u32, 4>> {
+        let mut batch = Batch::default();
+        for event in events {
+            let _ = batch.replies.push(EventReply {
+                delta_ticks: 0,
+                event: Some(event.clone()),
+            });
+        }
+        let bytes = postcard::to_vec::<_, 32>(&batch).unwrap();
+        engine.handle_frame(&address, &bytes, now_ticks)
+    }
+
+    fn status() -> EventOf<u8, NoEE> {
+        EventOf::Status(ServerStatus {
+            uptime_ticks: 1,
+            log_occupancy: 0,
+            last_reset_cause: ResetCause::PowerOn,
+            tick_rate: TickRate::SECONDS,
+        })
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut engine = Engine::new(ClientConfig {
+            client_time: true,
+            max_reply_len: true,
+            ..CONFIG
+        });
+        assert_eq!(engine.next_action(0), Action::Wait { until: u64::MAX });
+        assert_eq!(engine.add_server(1, 100), Ok(()));
+        assert_eq!(engine.add_server(2, 200), Ok(()));
+        assert_eq!(engine.add_server(3, 100), Err(3));
+
+        // Servers are polled one at a time, asking for their status until
+        // it is known.
+        let (address, request) = transmit(&mut engine, 0).unwrap();
+        assert_eq!(address, 1);
+        assert!(request.status_requested);
+        assert_eq!(request.client_time, Some(0));
+        assert_eq!(request.max_reply_len, Some(32));
+        assert_eq!(engine.next_action(1), Action::Wait { until: 10 });
+        assert_eq!(reply(&mut engine, 2, &[status()], 2), None);
+        let delivery = reply(&mut engine, 1, &[status()], 2).unwrap();
+        assert_eq!(delivery.replies[0].1, Observation::NewEvent);
+        assert_eq!(
+            delivery.clock,
+            Some(ExchangeClock {
+                tick_rate: TickRate::SECONDS,
+                client_tick_rate: TickRate::MILLISECONDS,
+                sent_ticks: 0,
+                received_ticks: 2,
+            })
+        );
+
+        assert_eq!(transmit(&mut engine, 2).unwrap().0, 2);
+        assert!(reply(&mut engine, 2, &[], 3).unwrap().replies.is_empty());
+        assert_eq!(engine.next_action(3), Action::Wait { until: 100 });
+
+        // Each server keeps to its own interval.
+        let mut polled = std::vec::Vec::new();
+        for now in 100..=401 {
+            if let Some((address, request)) = transmit(&mut engine, now) {
+                assert_eq!(request.status_requested, address == 2);
+                polled.push((now, address));
+                reply(&mut engine, address, &[], now);
+            }
+        }
+        assert_eq!(
+            polled,
+            [(100, 1), (200, 1), (201, 2), (300, 1), (400, 1), (401, 2)]
+        );
+    }
+
+    #[test]
+    fn test_recovery() {
+        let mut engine = Engine::new(CONFIG);
+        engine.add_server(1, 100).unwrap();
+
+        let (_, request) = transmit(&mut engine, 0).unwrap();
+        assert_eq!(request.last_event_offset, None);
+        let delivery = reply(&mut engine, 1, &[EventOf::Recovery(5, 7)], 1).unwrap();
+        assert_eq!(
+            delivery.replies[0].1,
+            Observation::RecoveryNeeded {
+                start: 5,
+                end: 7,
+                backlog: Backlog {
+                    remaining: 2,
+                    dropped: None,
+                    snapshot_available: false,
+                },
+            }
+        );
+        assert!(engine.tracker(&1).unwrap().is_recovering());
+
+        // The events following the start of the server's log are requested,
+        // consuming those of a batch for as long as they are consecutive.
+        let (_, request) = transmit(&mut engine, 100).unwrap();
+        assert_eq!(request.last_event_offset, Some(5));
+        let delivery = reply(
+            &mut engine,
+            1,
+            &[
+                EventOf::Logged(0, 6),
+                EventOf::Logged(1, 7),
+                EventOf::Logged(2, 9),
+            ],
+            101,
+        )
+        .unwrap();
+        assert_eq!(delivery.replies.len(), 2);
+        assert!(engine.tracker(&1).unwrap().is_synchronised());
+
+        let (_, request) = transmit(&mut engine, 200).unwrap();
+        assert_eq!(request.last_event_offset, Some(7));
+    }
+
+    #[test]
+    fn test_server_silence() {
+        let mut engine = Engine::new(CONFIG);
+        engine.add_server(1, 100).unwrap();
+        engine.add_server(2, 100).unwrap();
+
+        assert_eq!(transmit(&mut engine, 0).unwrap().0, 1);
+        assert_eq!(engine.handle_timeout(9), None);
+        assert_eq!(engine.handle_timeout(10), Some(1));
+        assert_eq!(engine.silent_polls(&1), Some(1));
+
+        // A late reply is ignored, and the next server is polled.
+        assert_eq!(reply(&mut engine, 1, &[status()], 11), None);
+        assert_eq!(transmit(&mut engine, 11).unwrap().0, 2);
+        assert_eq!(engine.handle_timeout(21), Some(2));
+
+        // The silent server is polled again at its interval, and its status
+        // is still requested.
+        assert_eq!(engine.next_action(21), Action::Wait { until: 100 });
+        let (address, request) = transmit(&mut engine, 100).unwrap();
+        assert_eq!(address, 1);
+        assert!(request.status_requested);
+        reply(&mut engine, 1, &[], 101).unwrap();
+        assert_eq!(engine.silent_polls(&1), Some(0));
+    }
+
+    #[test]
+    fn test_command_injection() {
+        let mut engine = Engine::new(CONFIG);
+        engine.add_server(1, 100).unwrap();
+        engine.add_server(2, 100).unwrap();
+        assert_eq!(engine.command(&3, 0), Err(0));
+
+        // Commands queued while awaiting a reply are conveyed with the next
+        // request of their server.
+        let (_, request) = transmit(&mut engine, 0).unwrap();
+        assert!(request.commands.is_empty());
+        assert_eq!(engine.command(&1, 10), Ok(()));
+        assert_eq!(engine.command(&1, 11), Ok(()));
+        assert_eq!(engine.command(&1, 12), Err(12));
+        assert_eq!(engine.command(&2, 20), Ok(()));
+        reply(&mut engine, 1, &[], 1).unwrap();
+
+        let (address, request) = transmit(&mut engine, 1).unwrap();
+        assert_eq!(address, 2);
+        assert_eq!(request.commands, [20]);
+        reply(&mut engine, 2, &[], 2).unwrap();
+
+        let (address, request) = transmit(&mut engine, 100).unwrap();
+        assert_eq!(address, 1);
+        assert_eq!(request.commands, [10, 11]);
+
+        // Commands are conveyed at most once, even if unanswered.
+        assert_eq!(engine.handle_timeout(110), Some(1));
+        engine.command(&1, 12).unwrap();
+        assert_eq!(transmit(&mut engine, 110).unwrap().0, 2);
+        reply(&mut engine, 2, &[], 111).unwrap();
+        let (address, request) = transmit(&mut engine, 200).unwrap();
+        assert_eq!(address, 1);
+        assert_eq!(request.commands, [12]);
+    }
+
+    #[test]
+    fn test_command_priority() {
+        let mut engine = ClientEngine::<u8, u8, u8, 1, 4, 4, 32>::new(CONFIG);
+        engine.add_server(1, 100).unwrap();
+        let transmit = |engine: &mut ClientEngine<_, _, _, 1, 4, 4, 32>, now_ticks| {
+            let Action::Transmit { bytes, .. } = engine.next_action(now_ticks) else {
+                panic!("nothing transmitted");
+            };
+            let request: MultiCommandRequest<u8, 4> = postcard::from_bytes(bytes).unwrap();
+            engine.handle_timeout(now_ticks + 10);
+            request.commands
+        };
+
+        // Urgent commands pre-empt those of normal priority, each in the
+        // order queued.
+        assert_eq!(engine.command(&1, 10), Ok(()));
+        assert_eq!(engine.command(&1, 11), Ok(()));
+        assert_eq!(
+            engine.command_with_priority(&1, 90, Priority::Urgent),
+            Ok(None)
+        );
+        assert_eq!(engine.command(&1, 12), Ok(()));
+        assert_eq!(engine.queue_depth(&1), Some(4));
+        assert_eq!(
+            engine.command_with_priority(&1, 91, Priority::Urgent),
+            Err(91)
+        );
+        assert_eq!(transmit(&mut engine, 0), [90, 10, 11, 12]);
+        assert_eq!(engine.queue_depth(&1), Some(0));
+
+        // The oldest commands of normal priority may be overwritten, while
+        // urgent commands never are.
+        engine.set_queue_policy(&1, QueuePolicy::OverwriteOldestNormal);
+        for command in 10..14 {
+            assert_eq!(engine.command(&1, command), Ok(()));
+        }
+        assert_eq!(
+            engine.command_with_priority(&1, 90, Priority::Urgent),
+            Ok(Some(10))
+        );
+        assert_eq!(
+            engine.command_with_priority(&1, 91, Priority::Urgent),
+            Ok(Some(11))
+        );
+        assert_eq!(engine.command(&1, 14), Ok(()));
+        assert_eq!(
+            engine.command_with_priority(&1, 92, Priority::Urgent),
+            Ok(Some(13))
+        );
+        assert_eq!(
+            engine.command_with_priority(&1, 93, Priority::Urgent),
+            Ok(Some(14))
+        );
+        assert_eq!(engine.command(&1, 15), Err(15));
+        assert_eq!(engine.queue_depth(&1), Some(4));
+        assert_eq!(transmit(&mut engine, 100), [90, 91, 92, 93]);
+        assert_eq!(engine.queue_depth(&1), Some(0));
+        assert_eq!(engine.queue_depth(&2), None);
+    }
+
+    // Polls the server due as of the ticks given, leaving the poll
+    // unanswered.
+    fn unanswered(engine: &mut Engine, now_ticks: u64) -> Option<u8> {
+        transmit(engine, now_ticks)?;
+        engine.handle_timeout(now_ticks + 10)
+    }
+
+    #[test]
+    fn test_liveness() {
+        let mut engine = Engine::new(CONFIG);
+        engine.add_server(1, 100).unwrap();
+        transmit(&mut engine, 0).unwrap();
+        reply(&mut engine, 1, &[status()], 1).unwrap();
+        assert_eq!(engine.liveness(&1), Some(Liveness::Online));
+
+        // A server answering every other poll remains online.
+        for now in [100, 300, 500] {
+            assert_eq!(unanswered(&mut engine, now), Some(1));
+            transmit(&mut engine, now + 100).unwrap();
+            reply(&mut engine, 1, &[], now + 101).unwrap();
+        }
+        assert_eq!(engine.next_liveness_change(), None);
+
+        // A server leaving polls unanswered is suspect, then offline, and
+        // only probed thereafter.
+        for now in [700, 800, 900, 1000, 1100] {
+            unanswered(&mut engine, now).unwrap();
+        }
+        assert_eq!(engine.next_liveness_change(), Some((1, Liveness::Suspect)));
+        assert_eq!(engine.next_liveness_change(), Some((1, Liveness::Offline)));
+        assert_eq!(engine.next_liveness_change(), None);
+        assert_eq!(engine.next_action(1110), Action::Wait { until: 2110 });
+
+        // An offline server answering a probe is polled at its interval,
+        // and its status is requested given that it may have rebooted, but
+        // it remains offline until it answers several polls in succession.
+        let (_, request) = transmit(&mut engine, 2110).unwrap();
+        assert!(request.status_requested);
+        reply(&mut engine, 1, &[], 2111).unwrap();
+        assert_eq!(engine.liveness(&1), Some(Liveness::Offline));
+        assert_eq!(unanswered(&mut engine, 2211), Some(1));
+        assert_eq!(engine.next_action(2221), Action::Wait { until: 3221 });
+        for now in [3221, 3322, 3423] {
+            transmit(&mut engine, now).unwrap();
+            reply(&mut engine, 1, &[status()], now + 1).unwrap();
+        }
+        assert_eq!(engine.next_liveness_change(), Some((1, Liveness::Online)));
+        assert_eq!(engine.liveness(&1), Some(Liveness::Online));
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut recorder = Recorder::default();
+        let mut engine = Engine::new(CONFIG).with_observer(&mut recorder);
+        engine.add_server(1, 100).unwrap();
+        let Action::Transmit { bytes, .. } = engine.next_action(0) else {
+            panic!("nothing transmitted");
+        };
+        let len = bytes.len();
+        assert!(engine.handle_frame(&1, &[0, 9], 1).is_none());
+        assert!(engine.handle_frame(&2, &[0], 2).is_none());
+        assert_eq!(engine.handle_timeout(10), Some(1));
+        assert_eq!(
+            recorder.0,
+            [
+                Observed::Tx(1, len),
+                Observed::Rx(1, 2),
+                Observed::Error(Some(1), ObservedError::CannotDecode),
+                Observed::Rx(2, 1),
+                Observed::Error(Some(2), ObservedError::Unexpected),
+                Observed::State(1, ObservedState::TimedOut),
+            ]
+        );
+    }
+
+    #[cfg(feature = "data")]
+    #[test]
+    fn test_datagrams() {
+        use aead::{generic_array::GenericArray, KeyInit};
+        use aes::Aes128;
+        use ccm::{
+            consts::{U4, U7},
+            Ccm,
+        };
+        use flip_flop_data::{port::Port, to_datagram, FromDatagramError};
+
+        let cipher = |key: &[u8; 16]| Ccm::<Aes128, U4, U7>::new(GenericArray::from_slice(key));
+        let provider = [
+            (1, cipher(b"0123456789ABCDEF")),
+            (2, cipher(b"FEDCBA9876543210")),
+        ];
+        let datagram = |server_address, key| {
+            let mut batch = Batch::default();
+            let _ = batch.replies.push(EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::Logged(7, 0)),
+            });
+            let header = Header {
+                version: 0,
+                source: DataSource::Server,
+                server_address,
+                server_port: Port::new(0).unwrap(),
+                frame_counter: 0,
+            };
+            let payload = postcard::to_vec::<_, 32>(&batch).unwrap();
+            let mut datagram_buf = [0; 48];
+            to_datagram(&cipher(key), &header, &payload, &mut datagram_buf);
+            datagram_buf
+        };
+
+        let mut recorder = Recorder::default();
+        let mut engine = Engine::new(CONFIG).with_observer(&mut recorder);
+        engine.add_server(1, 100).unwrap();
+        engine.add_server(2, 100).unwrap();
+        let address_of = |h: &Header| h.server_address;
+
+        // The third server is unknown, as is a reply under another's key.
+        let Action::Transmit { address: 1, .. } = engine.next_action(0) else {
+            panic!("server 1 not polled");
+        };
+        let unknown = datagram(3, b"0123456789ABCDEF");
+        assert!(engine
+            .handle_datagram(&provider, &unknown, address_of, 1)
+            .is_none());
+        let miskeyed = datagram(1, b"FEDCBA9876543210");
+        assert!(engine
+            .handle_datagram(&provider, &miskeyed, address_of, 1)
+            .is_none());
+
+        let delivery = engine
+            .handle_datagram(&provider, &datagram(1, b"0123456789ABCDEF"), address_of, 2)
+            .unwrap();
+        assert_eq!(delivery.address, 1);
+        assert_eq!(delivery.replies.len(), 1);
+
+        let Action::Transmit { address: 2, .. } = engine.next_action(100) else {
+            panic!("server 2 not polled");
+        };
+        let delivery = engine
+            .handle_datagram(
+                &provider,
+                &datagram(2, b"FEDCBA9876543210"),
+                address_of,
+                101,
+            )
+            .unwrap();
+        assert_eq!(delivery.address, 2);
+
+        assert!(recorder.0.contains(&Observed::Error(
+            None,
+            ObservedError::CannotDecodeDatagram(FromDatagramError::UnknownPeer)
+        )));
+        assert!(recorder.0.contains(&Observed::Error(
+            None,
+            ObservedError::CannotDecodeDatagram(FromDatagramError::CannotDecrypt)
+        )));
+    }
+
+    #[test]
+    fn test_return_after_reboot() {
+        let mut engine = Engine::new(CONFIG);
+        engine.add_server(1, 100).unwrap();
+        engine.set_liveness_config(
+            &1,
+            LivenessConfig {
+                suspect_after: 1,
+                offline_after: 2,
+                online_after: 1,
+                probe_every: 5,
+            },
+        );
+        transmit(&mut engine, 0).unwrap();
+        reply(&mut engine, 1, &[EventOf::Logged(1, 10)], 1).unwrap();
+        unanswered(&mut engine, 100).unwrap();
+        unanswered(&mut engine, 200).unwrap();
+        assert_eq!(engine.liveness(&1), Some(Liveness::Offline));
+
+        // Having rebooted, the server no longer has the client's offset, and
+        // so the client recovers.
+        let (_, request) = transmit(&mut engine, 710).unwrap();
+        assert_eq!(request.last_event_offset, Some(10));
+        let delivery = reply(&mut engine, 1, &[EventOf::Recovery(50, 52)], 711).unwrap();
+        assert!(matches!(
+            delivery.replies[0].1,
+            Observation::RecoveryNeeded { .. }
+        ));
+        assert_eq!(engine.liveness(&1), Some(Liveness::Online));
+        let (_, request) = transmit(&mut engine, 811).unwrap();
+        assert_eq!(request.last_event_offset, Some(50));
+    }
+
+    #[test]
+    fn test_expect_sleep() {
+        let mut engine = Engine::new(CONFIG);
+        engine.add_server(1, 100).unwrap();
+        engine.set_liveness_config(
+            &1,
+            LivenessConfig {
+                suspect_after: 1,
+                ..LivenessConfig::default()
+            },
+        );
+
+        // The server announces its sleep while its poll is awaited, which is
+        // then not counted, and it is not polled while asleep.
+        transmit(&mut engine, 0).unwrap();
+        engine.expect_sleep(&1, 1_000);
+        assert_eq!(engine.handle_timeout(10), Some(1));
+        assert_eq!(engine.silent_polls(&1), Some(0));
+        assert_eq!(engine.next_action(10), Action::Wait { until: 1_000 });
+
+        // Polls unanswered once it should have woken are counted.
+        unanswered(&mut engine, 1_000).unwrap();
+        assert_eq!(engine.liveness(&1), Some(Liveness::Suspect));
+    }
+
+    // Polls two servers logging events until there are none left to
+    // receive, persisting their offsets, and returns what was observed of
+    // the events logged.
+    fn poll_until_idle(
+        engine: &mut Engine,
+        servers: &mut [crate::server::ServerEngine<u8, u8, 8, 2, 4, 32>; 2],
+        store: &mut OffsetTable<u8, 2>,
+        now: u64,
+    ) -> std::vec::Vec<(u8, Observation)> {
+        use crate::server::Output;
+
+        let mut observed = std::vec::Vec::new();
+        for now in now..now + 1_000 {
+            if let Action::Transmit { address, bytes, .. } = engine.next_action(now) {
+                let server = &mut servers[address as usize - 1];
+                let Output::Reply(bytes) = server.handle_frame(bytes, now, |_, _| Ok::<_, ()>(()))
+                else {
+                    panic!("no reply");
+                };
+                let bytes = heapless::Vec::<u8, 32>::from_slice(bytes).unwrap();
+                let delivery = engine.handle_frame(&address, &bytes, now).unwrap();
+                for (reply, observation) in delivery.replies {
+                    if !matches!(reply.event, Some(EventOf::Status(_)) | None) {
+                        observed.push((address, observation));
+                    }
+                }
+                engine.persist_offsets(store, now);
+            }
+        }
+        observed
+    }
+
+    #[test]
+    fn test_restore_offsets() {
+        use crate::{event_log::EventLog, server::ServerEngine, status::StatusReporter};
+
+        let server = || {
+            let mut server = ServerEngine::<u8, u8, 8, 2, 4, 32>::new(
+                EventLog::new(0),
+                StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+            );
+            for i in 0..6 {
+                server.log_mut().push(i, 0);
+            }
+            server
+        };
+        let new_engine = || {
+            let mut engine = Engine::new(CONFIG);
+            engine.add_server(1, 10).unwrap();
+            engine.add_server(2, 10).unwrap();
+            engine.set_debounce(Debounce {
+                events: 4,
+                ticks: u64::MAX,
+            });
+            engine
+        };
+        let count = |observed: &[(u8, Observation)], address, observation: Observation| {
+            observed
+                .iter()
+                .filter(|(a, o)| *a == address && *o == observation)
+                .count()
+        };
+
+        // The client receives every event, saving its offsets once four
+        // events have been received, and then reboots along with the second
+        // server.
+        let run = |restore| {
+            let mut servers = [server(), server()];
+            let mut store = OffsetTable::new();
+            let mut engine = new_engine();
+            let observed = poll_until_idle(&mut engine, &mut servers, &mut store, 0);
+            assert_eq!(count(&observed, 1, Observation::NewEvent), 6);
+            assert_eq!(store.load(&1), Some(3));
+            let bytes = postcard::to_vec::<_, 32>(&store).unwrap();
+
+            servers[1].log_mut().reset(100);
+            servers[1].log_mut().push(9, 0);
+            let mut store = postcard::from_bytes::<OffsetTable<u8, 2>>(&bytes).unwrap();
+            let mut engine = new_engine();
+            if restore {
+                assert_eq!(engine.restore_offsets(&mut store), 2);
+                assert!(engine.tracker(&1).unwrap().is_stale());
+            }
+            poll_until_idle(&mut engine, &mut servers, &mut store, 1_000)
+        };
+
+        // Without the offsets persisted, every event retained is received
+        // again, and the second server's reboot goes unnoticed.
+        let observed = run(false);
+        assert_eq!(count(&observed, 1, Observation::NewEvent), 6);
+        assert_eq!(count(&observed, 2, Observation::NewEvent), 1);
+        assert!(!observed
+            .iter()
+            .any(|(_, o)| matches!(o, Observation::RecoveryNeeded { .. })));
+
+        // With them, only those received since the offset was last saved are
+        // received again, and the second server alone is recovered.
+        let observed = run(true);
+        assert_eq!(count(&observed, 1, Observation::NewEvent), 2);
+        assert_eq!(
+            observed
+                .iter()
+                .filter(|(_, o)| matches!(o, Observation::RecoveryNeeded { .. }))
+                .collect::<std::vec::Vec<_>>(),
+            [&(
+                2,
+                Observation::RecoveryNeeded {
+                    start: 100,
+                    end: 100,
+                    backlog: Backlog {
+                        remaining: 0,
+                        dropped: None,
+                        snapshot_available: false,
+                    },
+                }
+            )]
+        );
+    }
+}
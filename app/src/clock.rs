@@ -0,0 +1,570 @@
+//! Keeping time in ticks with a [Clock], being the times of events logged,
+//! the deadlines of a client and the liveness of its servers, and telling
+//! the times of events with a client's clock, so that the times of every
+//! server's events are comparable, and remain so across a server's reboot.
+
+use core::cell::Cell;
+
+use crate::TickRate;
+
+/// A monotonic source of time in ticks since some epoch e.g. since booting,
+/// whatever the platform. The engines are given the ticks of a clock, see
+/// [crate::transport::run_client].
+pub trait Clock {
+    /// The ticks elapsed since the clock's epoch, never decreasing.
+    fn now_ticks(&self) -> u64;
+}
+
+impl<K: Clock + ?Sized> Clock for &K {
+    fn now_ticks(&self) -> u64 {
+        (**self).now_ticks()
+    }
+}
+
+/// A clock that is set manually e.g. for tests that are to behave the same
+/// each time that they are run.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    ticks: Cell<u64>,
+}
+
+impl MockClock {
+    /// A clock at the ticks given.
+    pub const fn new(ticks: u64) -> Self {
+        Self {
+            ticks: Cell::new(ticks),
+        }
+    }
+
+    /// Set the clock to the ticks given, unless it has passed them given
+    /// that a clock never goes backwards.
+    pub fn set(&self, ticks: u64) {
+        self.ticks.set(self.ticks.get().max(ticks));
+    }
+
+    /// Advance the clock by the ticks given.
+    pub fn advance(&self, ticks: u64) {
+        self.ticks.set(self.ticks.get().saturating_add(ticks));
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ticks(&self) -> u64 {
+        self.ticks.get()
+    }
+}
+
+/// A clock reading the ticks of a counter with the function given e.g. an
+/// RTC or timer peripheral of an embedded device, counting at the rate of
+/// the ticks that it is to keep.
+#[derive(Clone, Copy, Debug)]
+pub struct CounterClock<F>(pub F);
+
+impl<F: Fn() -> u64> Clock for CounterClock<F> {
+    fn now_ticks(&self) -> u64 {
+        (self.0)()
+    }
+}
+
+#[cfg(any(test, feature = "blocking", feature = "std"))]
+pub use self::instant::InstantClock;
+
+#[cfg(any(test, feature = "blocking", feature = "std"))]
+mod instant {
+    use std::time::Instant;
+
+    use super::Clock;
+    use crate::TickRate;
+
+    /// Keeps time with [Instant], in ticks of the rate given since the clock
+    /// was created. Requires the `std` or `blocking` feature.
+    #[derive(Clone, Copy, Debug)]
+    pub struct InstantClock {
+        started: Instant,
+        tick_rate: TickRate,
+    }
+
+    impl InstantClock {
+        /// A clock starting now.
+        pub fn new(tick_rate: TickRate) -> Self {
+            Self::starting_at(Instant::now(), tick_rate)
+        }
+
+        /// A clock starting at the instant given.
+        pub fn starting_at(started: Instant, tick_rate: TickRate) -> Self {
+            Self { started, tick_rate }
+        }
+
+        /// The ticks at the instant given, or zero if before the clock
+        /// started.
+        pub fn ticks_at(&self, instant: Instant) -> u64 {
+            self.tick_rate
+                .from_duration(instant.saturating_duration_since(self.started))
+        }
+
+        /// The instant of the ticks given, or `None` should it be beyond
+        /// those that may be told.
+        pub fn instant_at(&self, ticks: u64) -> Option<Instant> {
+            self.started.checked_add(self.tick_rate.to_duration(ticks))
+        }
+    }
+
+    impl Clock for InstantClock {
+        fn now_ticks(&self) -> u64 {
+            self.ticks_at(Instant::now())
+        }
+    }
+}
+
+/// Records the client's time conveyed by its requests so that a server can
+/// tell the client's time of its events, see
+/// [CommandRequest::client_time]. The client's time is that of sending its
+/// request, and so the times told are early by the time taken to convey it.
+///
+/// [CommandRequest::client_time]: crate::CommandRequest::client_time
+pub struct ClockSync {
+    tick_rate: TickRate,
+    client_tick_rate: TickRate,
+    // The server's ticks and the client's time as of the last request
+    // conveying it.
+    synced_at: Option<(u64, u64)>,
+}
+
+impl ClockSync {
+    /// A clock yet to be synchronised with the client, given the rates of the
+    /// server's ticks and the client's ticks.
+    pub const fn new(tick_rate: TickRate, client_tick_rate: TickRate) -> Self {
+        Self {
+            tick_rate,
+            client_tick_rate,
+            synced_at: None,
+        }
+    }
+
+    /// Record the client's time conveyed by a request received as of the
+    /// server's ticks given, if it is conveyed.
+    pub fn record(&mut self, client_time: Option<u64>, now_ticks: u64) {
+        if let Some(client_time) = client_time {
+            self.synced_at = Some((now_ticks, client_time));
+        }
+    }
+
+    /// Whether a request has conveyed the client's time.
+    pub fn is_synchronised(&self) -> bool {
+        self.synced_at.is_some()
+    }
+
+    /// The client's time, in its ticks since its epoch, as of the server's
+    /// ticks given. None is returned until a request has conveyed the
+    /// client's time.
+    pub fn client_time(&self, ticks: u64) -> Option<u64> {
+        let (synced_ticks, client_time) = self.synced_at?;
+        let to_client_ticks = |ticks| {
+            self.client_tick_rate
+                .from_duration(self.tick_rate.to_duration(ticks))
+        };
+        Some(if ticks >= synced_ticks {
+            client_time.saturating_add(to_client_ticks(ticks - synced_ticks))
+        } else {
+            client_time.saturating_sub(to_client_ticks(synced_ticks - ticks))
+        })
+    }
+}
+
+/// The client's time of an event, in its ticks since its epoch, along with
+/// the most that it may be in error by in either direction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientTime {
+    /// The client's time of the event.
+    pub ticks: u64,
+    /// The most that the time may be in error by.
+    pub error_ticks: u64,
+}
+
+/// Tells the client's time of the events replied in an exchange with a
+/// server given the client's times of sending its request and receiving the
+/// reply. The server replied at some time between the two, and so an event
+/// occurred its age before then, see [crate::EventReply::delta_ticks]. The
+/// error of the times told is therefore half of the exchange's duration, along
+/// with a tick of the server given that it conveys whole ticks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExchangeClock {
+    /// The rate of the server's ticks, as conveyed by its status.
+    pub tick_rate: TickRate,
+    /// The rate of the client's ticks.
+    pub client_tick_rate: TickRate,
+    /// The client's time of sending its request.
+    pub sent_ticks: u64,
+    /// The client's time of receiving the reply.
+    pub received_ticks: u64,
+}
+
+impl ExchangeClock {
+    /// The client's time of an event replied with the age given in the
+    /// server's ticks.
+    pub fn event_time(&self, delta_ticks: u64) -> ClientTime {
+        let to_client_ticks = |ticks| {
+            self.client_tick_rate
+                .from_duration(self.tick_rate.to_duration(ticks))
+        };
+        // The event's age is at least the ticks conveyed, but less than one
+        // more, with the most being rounded up to the client's next tick.
+        let least_age = to_client_ticks(delta_ticks);
+        let most_age = to_client_ticks(delta_ticks.saturating_add(1)).saturating_add(1);
+        let earliest = self.sent_ticks.saturating_sub(most_age);
+        let latest = self.received_ticks.saturating_sub(least_age).max(earliest);
+        let error_ticks = (latest - earliest).div_ceil(2);
+        ClientTime {
+            ticks: latest - error_ticks,
+            error_ticks,
+        }
+    }
+
+    /// The server's clock as of the exchange given the server's ticks as of
+    /// replying, as conveyed by a batch of the absolute form, see
+    /// [crate::EventBatchReply::server_ticks].
+    pub fn server_clock(&self, server_ticks: u64) -> ServerClock {
+        // The server replied at some time between sending the request and
+        // receiving the reply, and within a tick of its ticks.
+        let duration = self.received_ticks.saturating_sub(self.sent_ticks);
+        let error_ticks = duration.div_ceil(2);
+        ServerClock {
+            tick_rate: self.tick_rate,
+            client_tick_rate: self.client_tick_rate,
+            server_ticks,
+            client_ticks: self.sent_ticks.saturating_add(error_ticks),
+            error_ticks: error_ticks
+                .saturating_add(server_tick(self.tick_rate, self.client_tick_rate)),
+        }
+    }
+}
+
+// A tick of the server in the client's ticks, rounded up.
+fn server_tick(tick_rate: TickRate, client_tick_rate: TickRate) -> u64 {
+    client_tick_rate
+        .from_duration(tick_rate.to_duration(1))
+        .saturating_add(1)
+}
+
+/// Tells the client's time of events conveyed with the server's ticks as of
+/// them, see [crate::CommandRequest::absolute_ticks], being the client's
+/// counterpart of a server's [ClockSync]. Unlike the ages of events, the
+/// server's ticks do not depend on when a reply is received, and so a clock
+/// told from a prompt exchange tells the times of events however late their
+/// replies are consumed e.g. once queued by a gateway. Those of a late reply
+/// are instead told from it with an error of its delay. The server's ticks
+/// restart when it reboots, and so its clock is told again once its status
+/// conveys that it has, see [crate::ServerStatus::uptime_ticks].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ServerClock {
+    tick_rate: TickRate,
+    client_tick_rate: TickRate,
+    // The server's ticks and the client's time as of the same instant.
+    server_ticks: u64,
+    client_ticks: u64,
+    error_ticks: u64,
+}
+
+impl ServerClock {
+    /// The most that the times told may be in error by in either direction,
+    /// in the client's ticks, so that the clock of the most prompt exchange
+    /// may be kept.
+    pub fn error_ticks(&self) -> u64 {
+        self.error_ticks
+    }
+
+    /// The client's time of an event as of the server's ticks given.
+    pub fn event_time(&self, event_ticks: u64) -> ClientTime {
+        let to_client_ticks = |ticks| {
+            self.client_tick_rate
+                .from_duration(self.tick_rate.to_duration(ticks))
+        };
+        let ticks = if event_ticks <= self.server_ticks {
+            self.client_ticks
+                .saturating_sub(to_client_ticks(self.server_ticks - event_ticks))
+        } else {
+            self.client_ticks
+                .saturating_add(to_client_ticks(event_ticks - self.server_ticks))
+        };
+        // The event is within a tick of its ticks, as is the server's clock.
+        ClientTime {
+            ticks,
+            error_ticks: self
+                .error_ticks
+                .saturating_add(server_tick(self.tick_rate, self.client_tick_rate)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        client::{Action, ClientConfig, ClientEngine, Liveness},
+        clocked_event_reply,
+        event_log::EventLog,
+        server::{Output, ServerEngine},
+        status::StatusReporter,
+        EventOf, EventReply, MultiCommandRequest, NoEE, ResetCause,
+    };
+
+    type Event = EventOf<u8, NoEE>;
+    type Client = ClientEngine<u8, u8, u8, 1, 2, 4, 32>;
+    type Server = ServerEngine<u8, u8, 4, 2, 4, 32>;
+
+    #[test]
+    fn test_clocks() {
+        let clock = MockClock::new(10);
+        clock.advance(5);
+        assert_eq!(clock.now_ticks(), 15);
+        clock.set(12);
+        assert_eq!(clock.now_ticks(), 15);
+        clock.set(20);
+        assert_eq!(clock.now_ticks(), 20);
+
+        let counter = Cell::new(7);
+        assert_eq!(CounterClock(|| counter.get()).now_ticks(), 7);
+
+        #[cfg(feature = "std")]
+        {
+            let started = std::time::Instant::now();
+            let clock = InstantClock::starting_at(started, TickRate::MILLISECONDS);
+            assert_eq!(
+                clock.ticks_at(started + core::time::Duration::from_secs(2)),
+                2_000
+            );
+            assert_eq!(clock.ticks_at(started), 0);
+        }
+
+        // Event replies are aged by the clock.
+        assert_eq!(
+            clocked_event_reply(Some((Event::Logged(1, 0), 8)), &clock),
+            EventReply {
+                delta_ticks: 12,
+                event: Some(EventOf::Logged(1, 0)),
+            }
+        );
+        assert_eq!(
+            clocked_event_reply(Some((Event::Logged(1, 0), 30)), &clock).delta_ticks,
+            0
+        );
+        assert_eq!(clocked_event_reply::<Event>(None, &clock).event, None);
+    }
+
+    #[test]
+    fn test_engines_with_mock_clock() {
+        let clock = MockClock::new(0);
+        let mut client = Client::new(ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 10,
+            client_time: false,
+            max_reply_len: false,
+        });
+        client.add_server(1, 100).unwrap();
+        let mut server = Server::new(
+            EventLog::new(0),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        );
+        let exchange = |client: &mut Client, server: &mut Server, answer: bool| {
+            let Action::Transmit { bytes, .. } = client.next_action(clock.now_ticks()) else {
+                return None;
+            };
+            let Output::Reply(bytes) =
+                server.handle_frame(bytes, clock.now_ticks(), |_, _| Ok::<_, ()>(()))
+            else {
+                panic!("the server did not reply");
+            };
+            clock.advance(2);
+            if answer {
+                client.handle_frame(&1, bytes, clock.now_ticks())
+            } else {
+                clock.advance(10);
+                client.handle_timeout(clock.now_ticks());
+                None
+            }
+        };
+
+        // The status is replied first, and then an event logged at the
+        // clock's ticks, aged by the ticks since.
+        assert!(exchange(&mut client, &mut server, true).is_some());
+        assert_eq!(
+            client.next_action(clock.now_ticks()),
+            Action::Wait { until: 100 }
+        );
+        clock.set(40);
+        server.log_mut().push_now(7, &clock);
+        clock.set(100);
+        let delivery = exchange(&mut client, &mut server, true).unwrap();
+        assert_eq!(
+            delivery.replies[0].0,
+            EventReply {
+                delta_ticks: 60,
+                event: Some(EventOf::Logged(7, 0)),
+            }
+        );
+
+        // Polls are due at the clock's ticks, and a server whose replies
+        // are lost is suspect after the same polls each time.
+        for poll in 2..4 {
+            assert_eq!(
+                client.next_action(clock.now_ticks()),
+                Action::Wait { until: poll * 100 }
+            );
+            clock.set(poll * 100);
+            assert!(exchange(&mut client, &mut server, false).is_none());
+        }
+        assert_eq!(client.next_liveness_change(), Some((1, Liveness::Suspect)));
+        assert_eq!(clock.now_ticks(), 312);
+    }
+
+    #[test]
+    fn test_delayed_delivery() {
+        // The client's ticks are ahead of the server's, and replies reach the
+        // client well after the server replies to them e.g. queued by a
+        // gateway, being that of the second server conveying ages.
+        const AHEAD: u64 = 5_000;
+        const DELAY: u64 = 1_000;
+        let mut client = ClientEngine::<u8, u8, u8, 2, 2, 4, 32>::new(ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 2 * DELAY,
+            client_time: false,
+            max_reply_len: false,
+        });
+        client.add_server(1, 100).unwrap();
+        client.add_server(2, 100).unwrap();
+        client.set_absolute_ticks(&1, true);
+        let mut servers = [1, 2].map(|_| {
+            Server::new(
+                EventLog::new(0),
+                StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+            )
+        });
+        let mut exchange = |servers: &mut [Server; 2], now: u64, delay: u64| {
+            let Action::Transmit { address, bytes, .. } = client.next_action(now) else {
+                panic!("no server polled");
+            };
+            let request = postcard::from_bytes::<MultiCommandRequest<u8, 2>>(bytes).unwrap();
+            assert_eq!(request.absolute_ticks, address == 1);
+            let server = &mut servers[address as usize - 1];
+            let Output::Reply(bytes) =
+                server.handle_frame(bytes, now - AHEAD, |_, _| Ok::<_, ()>(()))
+            else {
+                panic!("the server did not reply");
+            };
+            client.handle_frame(&address, bytes, now + delay).unwrap()
+        };
+
+        // The server's clock is told from a prompt exchange.
+        let delivery = exchange(&mut servers, AHEAD, 2);
+        assert_eq!(delivery.address, 1);
+        let server_clock = delivery
+            .clock
+            .unwrap()
+            .server_clock(delivery.server_ticks.unwrap());
+        assert!(server_clock.error_ticks() <= 3);
+        let delivery = exchange(&mut servers, AHEAD + 2, 2);
+        assert_eq!(delivery.server_ticks, None);
+
+        // An event logged by each server, the reply of which is delayed.
+        for server in &mut servers {
+            server.log_mut().push(7, 1_000);
+        }
+        let occurred_at = 1_000 + AHEAD;
+
+        // The time of the event conveyed with the server's ticks is told
+        // within the error of the prompt exchange.
+        let delivery = exchange(&mut servers, AHEAD + 1_100, DELAY);
+        assert_eq!(delivery.address, 1);
+        assert_eq!(delivery.server_ticks, Some(1_100));
+        let time = server_clock.event_time(delivery.replies[0].0.delta_ticks);
+        assert!(occurred_at.abs_diff(time.ticks) <= time.error_ticks);
+        assert!(time.error_ticks <= 5);
+
+        // That of the event conveyed with its age drifts by the delay, being
+        // told within the error of the delayed exchange at best.
+        let delivery = exchange(&mut servers, AHEAD + 2_100, DELAY);
+        assert_eq!(delivery.address, 2);
+        let age = delivery.replies[0].0.delta_ticks;
+        assert_eq!(age, 1_100);
+        assert_eq!((AHEAD + 2_100 + DELAY - age) - occurred_at, DELAY);
+        let time = delivery.clock.unwrap().event_time(age);
+        assert!(occurred_at.abs_diff(time.ticks) <= time.error_ticks);
+        assert!(time.error_ticks >= DELAY / 2);
+    }
+
+    #[test]
+    fn test_clock_sync() {
+        let mut clock = ClockSync::new(TickRate::SECONDS, TickRate::MILLISECONDS);
+        assert!(!clock.is_synchronised());
+        assert_eq!(clock.client_time(10), None);
+
+        clock.record(None, 10);
+        assert_eq!(clock.client_time(10), None);
+
+        clock.record(Some(50_000), 10);
+        assert!(clock.is_synchronised());
+        assert_eq!(clock.client_time(10), Some(50_000));
+        assert_eq!(clock.client_time(12), Some(52_000));
+        assert_eq!(clock.client_time(7), Some(47_000));
+        assert_eq!(clock.client_time(0), Some(40_000));
+
+        // Requests without the client's time leave it as it was.
+        clock.record(None, 20);
+        assert_eq!(clock.client_time(20), Some(60_000));
+
+        // Client times before its epoch saturate.
+        clock.record(Some(1_000), 10);
+        assert_eq!(clock.client_time(0), Some(0));
+
+        // Following a reboot, the server's ticks restart while the client's
+        // time continues, and so the times told remain comparable.
+        let mut rebooted = ClockSync::new(TickRate::SECONDS, TickRate::MILLISECONDS);
+        rebooted.record(Some(70_000), 1);
+        assert_eq!(rebooted.client_time(3), Some(72_000));
+    }
+
+    #[test]
+    fn test_event_time() {
+        // An event 5 seconds old replied within an exchange of 20 ms.
+        let clock = ExchangeClock {
+            tick_rate: TickRate::SECONDS,
+            client_tick_rate: TickRate::MILLISECONDS,
+            sent_ticks: 100_000,
+            received_ticks: 100_020,
+        };
+        let time = clock.event_time(5);
+        assert_eq!(
+            time,
+            ClientTime {
+                ticks: 94_509,
+                error_ticks: 511,
+            }
+        );
+
+        // The time told is within its error of when the event occurred,
+        // whenever the server replied and whatever its fraction of a tick.
+        for replied_at in [100_000, 100_010, 100_020] {
+            for age in [5_000, 5_500, 5_999] {
+                let occurred_at: u64 = replied_at - age;
+                assert!(occurred_at.abs_diff(time.ticks) <= time.error_ticks);
+            }
+        }
+
+        // Events of no age are as exact as the exchange.
+        let clock = ExchangeClock {
+            tick_rate: TickRate::MILLISECONDS,
+            ..clock
+        };
+        assert_eq!(
+            clock.event_time(0),
+            ClientTime {
+                ticks: 100_009,
+                error_ticks: 11,
+            }
+        );
+
+        // Times before the client's epoch saturate.
+        assert_eq!(clock.event_time(u64::MAX).ticks, 0);
+    }
+}
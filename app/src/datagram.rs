@@ -0,0 +1,334 @@
+//! Sending commands and events within the datagrams of the data link layer,
+//! and receiving them, each in a single call. Each call has a counterpart
+//! telling a [ProtocolObserver] of the datagram, see [crate::observer].
+//! Requires the `data` feature.
+
+use aead::{generic_array::typenum::Unsigned, AeadInPlace};
+use flip_flop_data::{
+    from_datagram, to_datagram, FromDatagramError, Header, NonceScheme, HEADER_SIZE,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    observer::{NoObserver, ObservedError, ProtocolObserver},
+    CommandRequest, EventOf, EventReply, Offset, TemporalEvent,
+};
+
+/// Problems in relation to sending or receiving a datagram.
+#[derive(Debug, Eq, PartialEq)]
+pub enum DatagramError {
+    /// The payload could not be encoded within the datagram e.g. it is too
+    /// large.
+    CannotEncodePayload(postcard::Error),
+    /// The datagram could not be decoded, see [FromDatagramError].
+    CannotDecodeDatagram(FromDatagramError),
+    /// The payload of the datagram could not be decoded.
+    CannotDecodePayload(postcard::Error),
+}
+
+impl From<FromDatagramError> for DatagramError {
+    fn from(e: FromDatagramError) -> Self {
+        DatagramError::CannotDecodeDatagram(e)
+    }
+}
+
+/// The header of a datagram received and the message that it conveys.
+pub type Received<T> = Result<(Header, T), DatagramError>;
+
+/// Encodes a command request and encrypts it into a datagram with a fixed
+/// length of N, as per [to_datagram].
+pub fn send_command<C: Serialize, O: Offset, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    header: &Header,
+    request: &CommandRequest<C, O>,
+    datagram_buf: &mut [u8; N],
+) -> Result<(), DatagramError> {
+    send(cipher, header, request, datagram_buf, &mut NoObserver)
+}
+
+/// As per [send_command], telling the observer given of the datagram.
+pub fn send_command_observed<C: Serialize, O: Offset, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    header: &Header,
+    request: &CommandRequest<C, O>,
+    datagram_buf: &mut [u8; N],
+    observer: &mut impl ProtocolObserver<Header>,
+) -> Result<(), DatagramError> {
+    send(cipher, header, request, datagram_buf, observer)
+}
+
+/// Decodes a datagram with a fixed length of N given a condition, as per
+/// [from_datagram], and then the command request that it conveys.
+pub fn recv_command<C: DeserializeOwned + Serialize, O: Offset, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    filter: impl FnOnce(&Header) -> bool,
+    datagram_buf: &[u8; N],
+) -> Received<CommandRequest<C, O>> {
+    recv(cipher, filter, datagram_buf, &mut NoObserver)
+}
+
+/// As per [recv_command], telling the observer given of the datagram.
+pub fn recv_command_observed<C: DeserializeOwned + Serialize, O: Offset, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    filter: impl FnOnce(&Header) -> bool,
+    datagram_buf: &[u8; N],
+    observer: &mut impl ProtocolObserver<Header>,
+) -> Received<CommandRequest<C, O>> {
+    recv(cipher, filter, datagram_buf, observer)
+}
+
+/// Encodes an event reply and encrypts it into a datagram with a fixed length
+/// of N, as per [to_datagram].
+pub fn send_event<E, EE, O, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    header: &Header,
+    reply: &EventReply<EventOf<E, EE, O>>,
+    datagram_buf: &mut [u8; N],
+) -> Result<(), DatagramError>
+where
+    EventOf<E, EE, O>: TemporalEvent,
+{
+    send(cipher, header, reply, datagram_buf, &mut NoObserver)
+}
+
+/// As per [send_event], telling the observer given of the datagram.
+pub fn send_event_observed<E, EE, O, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    header: &Header,
+    reply: &EventReply<EventOf<E, EE, O>>,
+    datagram_buf: &mut [u8; N],
+    observer: &mut impl ProtocolObserver<Header>,
+) -> Result<(), DatagramError>
+where
+    EventOf<E, EE, O>: TemporalEvent,
+{
+    send(cipher, header, reply, datagram_buf, observer)
+}
+
+/// Decodes a datagram with a fixed length of N given a condition, as per
+/// [from_datagram], and then the event reply that it conveys.
+pub fn recv_event<E, EE, O, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    filter: impl FnOnce(&Header) -> bool,
+    datagram_buf: &[u8; N],
+) -> Received<EventReply<EventOf<E, EE, O>>>
+where
+    EventOf<E, EE, O>: TemporalEvent + DeserializeOwned,
+{
+    recv(cipher, filter, datagram_buf, &mut NoObserver)
+}
+
+/// As per [recv_event], telling the observer given of the datagram.
+pub fn recv_event_observed<E, EE, O, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    filter: impl FnOnce(&Header) -> bool,
+    datagram_buf: &[u8; N],
+    observer: &mut impl ProtocolObserver<Header>,
+) -> Received<EventReply<EventOf<E, EE, O>>>
+where
+    EventOf<E, EE, O>: TemporalEvent + DeserializeOwned,
+{
+    recv(cipher, filter, datagram_buf, observer)
+}
+
+// The payload is encoded within a buffer of what a datagram of N conveys,
+// which is then encrypted into the datagram.
+pub(crate) fn send<A, T: Serialize, const N: usize>(
+    cipher: &A,
+    header: &Header,
+    payload: &T,
+    datagram_buf: &mut [u8; N],
+    observer: &mut impl ProtocolObserver<Header>,
+) -> Result<(), DatagramError>
+where
+    A: AeadInPlace,
+    A::NonceSize: NonceScheme,
+{
+    let mut payload_buf = [0; N];
+    let payload_len = N.saturating_sub(HEADER_SIZE + A::TagSize::USIZE);
+    let payload_buf = match postcard::to_slice(payload, &mut payload_buf[..payload_len]) {
+        Ok(payload_buf) => payload_buf,
+        Err(e) => {
+            observer.on_error(Some(header), &ObservedError::CannotEncode);
+            return Err(DatagramError::CannotEncodePayload(e));
+        }
+    };
+    to_datagram(cipher, header, payload_buf, datagram_buf);
+    observer.on_tx(header, N);
+    Ok(())
+}
+
+fn recv<T: DeserializeOwned, const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    filter: impl FnOnce(&Header) -> bool,
+    datagram_buf: &[u8; N],
+    observer: &mut impl ProtocolObserver<Header>,
+) -> Received<T> {
+    let (header, payload_buf) = match from_datagram(datagram_buf, filter, cipher) {
+        Ok(received) => received,
+        Err(e) => {
+            observer.on_error(None, &ObservedError::CannotDecodeDatagram(e.clone()));
+            return Err(e.into());
+        }
+    };
+    observer.on_rx(&header, N);
+    match postcard::from_bytes(&payload_buf) {
+        Ok(payload) => Ok((header, payload)),
+        Err(e) => {
+            observer.on_error(Some(&header), &ObservedError::CannotDecode);
+            Err(DatagramError::CannotDecodePayload(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aead::{generic_array::GenericArray, KeyInit};
+    use aes::Aes128;
+    use ccm::{
+        consts::{U4, U7},
+        Ccm,
+    };
+    use flip_flop_data::{port::Port, DataSource};
+    use serde::Deserialize;
+
+    use crate::NoEE;
+
+    type AesCcm = Ccm<Aes128, U4, U7>;
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum Command {
+        A,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum Event {
+        A(u32),
+    }
+
+    fn header(source: DataSource) -> Header {
+        Header {
+            version: 0,
+            source,
+            server_address: 9,
+            server_port: Port::new(1).unwrap(),
+            frame_counter: 7,
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let cipher = AesCcm::new(GenericArray::from_slice(b"0123456789ABCDEF"));
+        let request = CommandRequest::<_> {
+            last_event_offset: Some(3),
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: Some(Command::A),
+        };
+
+        // The composed helpers produce the same datagram as each step.
+        let mut datagram_buf = [0; 32];
+        send_command(
+            &cipher,
+            &header(DataSource::Client),
+            &request,
+            &mut datagram_buf,
+        )
+        .unwrap();
+        let mut payload_buf = [0; 32];
+        let payload_buf = postcard::to_slice(&request, &mut payload_buf).unwrap();
+        let mut expected_buf = [0; 32];
+        to_datagram(
+            &cipher,
+            &header(DataSource::Client),
+            payload_buf,
+            &mut expected_buf,
+        );
+        assert_eq!(datagram_buf, expected_buf);
+
+        let (received_header, received) = recv_command::<Command, u32, 32>(
+            &cipher,
+            |h| h.source == DataSource::Client,
+            &datagram_buf,
+        )
+        .unwrap();
+        assert_eq!(received_header, header(DataSource::Client));
+        assert_eq!(received, request);
+
+        assert_eq!(
+            recv_command::<Command, u32, 32>(
+                &cipher,
+                |h| h.source == DataSource::Server,
+                &datagram_buf
+            ),
+            Err(DatagramError::CannotDecodeDatagram(
+                FromDatagramError::FilterDoesNotMatch
+            ))
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let cipher = AesCcm::new(GenericArray::from_slice(b"0123456789ABCDEF"));
+        let reply = EventReply {
+            delta_ticks: 2,
+            event: Some(EventOf::<_, NoEE>::Logged(Event::A(300), 4)),
+        };
+
+        let mut datagram_buf = [0; 32];
+        send_event(
+            &cipher,
+            &header(DataSource::Server),
+            &reply,
+            &mut datagram_buf,
+        )
+        .unwrap();
+        let mut payload_buf = [0; 32];
+        let payload_buf = postcard::to_slice(&reply, &mut payload_buf).unwrap();
+        let mut expected_buf = [0; 32];
+        to_datagram(
+            &cipher,
+            &header(DataSource::Server),
+            payload_buf,
+            &mut expected_buf,
+        );
+        assert_eq!(datagram_buf, expected_buf);
+
+        let (_, received) = recv_event::<Event, NoEE, u32, 32>(
+            &cipher,
+            |h| h.source == DataSource::Server,
+            &datagram_buf,
+        )
+        .unwrap();
+        assert_eq!(received, reply);
+
+        // Payloads not fitting a datagram are not sent.
+        let mut datagram_buf = [0; 12];
+        assert_eq!(
+            send_event(
+                &cipher,
+                &header(DataSource::Server),
+                &reply,
+                &mut datagram_buf
+            ),
+            Err(DatagramError::CannotEncodePayload(
+                postcard::Error::SerializeBufferFull
+            ))
+        );
+
+        // Corruption is detected.
+        let mut datagram_buf = expected_buf;
+        datagram_buf[8] ^= 1;
+        assert_eq!(
+            recv_event::<Event, NoEE, u32, 32>(&cipher, |_| true, &datagram_buf),
+            Err(DatagramError::CannotDecodeDatagram(
+                FromDatagramError::CannotDecrypt
+            ))
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Rendering requests and replies as JSON e.g. to describe a hexdump
+//! attached to a bug report, as the data crate renders its headers and
+//! payloads. Each is rendered as an object of its `decoded` fields and its
+//! `raw` bytes as they are encoded, written as lowercase hex. Commands and
+//! events are rendered as serde encodes them to JSON:
+//!
+//! ```json
+//! {"decoded":{"absolute_ticks":false,"client_time":null,"command":"C","filter":null,"last_event_offset":9,"max_reply_len":null,"status_requested":false},"raw":"010902"}
+//! ```
+//!
+//! The names of fields are those of the types rendered, and their order is
+//! alphabetical. The structure is a maintained contract that tools parse,
+//! and so is pinned by the tests of this module. Requires the `std`
+//! feature.
+
+extern crate std;
+
+use std::{fmt::Write, string::String, vec::Vec};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{CommandRequest, EventReply, Offset, TemporalEvent};
+
+// Bytes written as lowercase hex digits, two for each.
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+// The object of the fields decoded and the bytes that encode them, being
+// those of the value as encoded by postcard.
+fn rendered(decoded: Value, value: &impl Serialize) -> Value {
+    let raw = postcard::to_extend(value, Vec::new()).unwrap_or_default();
+    json!({ "decoded": decoded, "raw": hex(&raw) })
+}
+
+impl<C: Serialize, O: Offset> CommandRequest<C, O> {
+    /// The request's fields along with its bytes, see the
+    /// [module's documentation](crate::debug_json).
+    pub fn to_debug_json(&self) -> Value {
+        rendered(
+            json!({
+                "absolute_ticks": self.absolute_ticks,
+                "client_time": self.client_time,
+                "command": self.command,
+                "filter": self.filter,
+                "last_event_offset": self.last_event_offset,
+                "max_reply_len": self.max_reply_len,
+                "status_requested": self.status_requested,
+            }),
+            self,
+        )
+    }
+}
+
+impl<E: TemporalEvent> EventReply<E> {
+    /// The reply's fields along with its bytes, see the
+    /// [module's documentation](crate::debug_json).
+    pub fn to_debug_json(&self) -> Value {
+        rendered(
+            json!({
+                "delta_ticks": self.delta_ticks,
+                "event": self.event,
+            }),
+            self,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{EventOf, NoEE};
+
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    enum Command {
+        A,
+        B,
+        C,
+    }
+
+    #[test]
+    fn test_command_request() {
+        let mut request = CommandRequest::<Command> {
+            last_event_offset: Some(9),
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: Some(Command::C),
+        };
+        assert_eq!(
+            request.to_debug_json().to_string(),
+            r#"{"decoded":{"absolute_ticks":false,"client_time":null,"command":"C","filter":null,"last_event_offset":9,"max_reply_len":null,"status_requested":false},"raw":"010902"}"#
+        );
+
+        request.status_requested = true;
+        request.client_time = Some(1_000);
+        request.command = None;
+        assert_eq!(
+            request.to_debug_json().to_string(),
+            r#"{"decoded":{"absolute_ticks":false,"client_time":1000,"command":null,"filter":null,"last_event_offset":9,"max_reply_len":null,"status_requested":true},"raw":"0b09e807"}"#
+        );
+    }
+
+    #[test]
+    fn test_event_reply() {
+        let reply = EventReply {
+            delta_ticks: 10,
+            event: Some(EventOf::<u8, NoEE>::Logged(21, 12)),
+        };
+        assert_eq!(
+            reply.to_debug_json().to_string(),
+            r#"{"decoded":{"delta_ticks":10,"event":{"Logged":[21,12]}},"raw":"0a00150c"}"#
+        );
+
+        let reply = EventReply::<EventOf<u8, NoEE>> {
+            delta_ticks: 0,
+            event: None,
+        };
+        assert_eq!(
+            reply.to_debug_json().to_string(),
+            r#"{"decoded":{"delta_ticks":0,"event":null},"raw":"00"}"#
+        );
+    }
+}
@@ -0,0 +1,888 @@
+//! A server's history of logged events, replying to each [CommandRequest]
+//! as described by the offset rules.
+//!
+//! [CommandRequest]: crate::CommandRequest
+
+use heapless::Deque;
+
+use crate::{
+    absolute_event_batch_reply, clock::Clock, event_batch_reply, Classified, EventBatchReply,
+    EventOf, EventReply, NoSnapshot, Offset, TemporalEvent,
+};
+
+/// Retains the most recent `N` events logged by a server along with the
+/// ticks at which they were logged, assigning each an offset. The first event
+/// is assigned the offset that the log is created or reset with, which should
+/// be random so that a client is able to detect a restart, and each event
+/// thereafter the successor of the one before it, wrapping at the maximum
+/// offset. Once full, the oldest event is forgotten for each event logged.
+///
+/// Recovery is replied as an [EventOf::Recovery] unless the log is created
+/// [EventLog::with_recovery_metadata], in which case it is replied as an
+/// [EventOf::RecoveryV1] conveying the number of events that a client has
+/// missed and can no longer receive.
+///
+/// A snapshot of the server's state, of type `S`, may be installed so that a
+/// client whose offset precedes the events retained is replied the snapshot
+/// in place of recovery, see [EventLog::install_snapshot].
+pub struct EventLog<E, const N: usize, O = u32, S = NoSnapshot> {
+    events: Deque<(E, u64), N>,
+    start_offset: O,
+    overwritten: u64,
+    recovery_metadata: bool,
+    snapshot_available: bool,
+    snapshot: Option<(S, O)>,
+}
+
+// What a server is to reply given the last offset of a client.
+enum Next<O> {
+    Event(O),
+    Nothing,
+    Recovery(O, O),
+    Snapshot,
+}
+
+impl<E: Clone, const N: usize, O: Offset, S: Clone> EventLog<E, N, O, S> {
+    /// An empty log, assigning the offset given to the first event logged.
+    pub fn new(start_offset: O) -> Self {
+        const { assert!(N > 0) };
+        Self {
+            events: Deque::new(),
+            start_offset,
+            overwritten: 0,
+            recovery_metadata: false,
+            snapshot_available: false,
+            snapshot: None,
+        }
+    }
+
+    /// Reply recovery as an [EventOf::RecoveryV1], for servers whose clients
+    /// are aware of it.
+    pub fn with_recovery_metadata(mut self) -> Self {
+        self.recovery_metadata = true;
+        self
+    }
+
+    /// Declare whether the server has a snapshot of its state available, as
+    /// conveyed by an [EventOf::RecoveryV1].
+    pub fn set_snapshot_available(&mut self, snapshot_available: bool) {
+        self.snapshot_available = snapshot_available;
+    }
+
+    /// Install a snapshot of the server's state as of the event of the offset
+    /// given, replacing any installed before. The snapshot is replied to a
+    /// client whose offset precedes the events retained for as long as the
+    /// events following the snapshot are retained, and so should be
+    /// installed now and then as events are logged. A snapshot of the
+    /// latest event logged is always of use.
+    pub fn install_snapshot(&mut self, snapshot: S, offset: O) {
+        self.snapshot = Some((snapshot, offset));
+    }
+
+    /// The snapshot installed and the offset of the event that it is as of,
+    /// if any.
+    pub fn snapshot(&self) -> Option<(&S, O)> {
+        self.snapshot.as_ref().map(|(s, o)| (s, *o))
+    }
+
+    /// Log an event at the ticks given, returning the offset assigned.
+    pub fn push(&mut self, event: E, now_ticks: u64) -> O {
+        if self.events.is_full() {
+            self.events.pop_front();
+            self.start_offset = self.start_offset.successor();
+            self.overwritten = self.overwritten.saturating_add(1);
+        }
+        // Cannot fail given that there is room.
+        let _ = self.events.push_back((event, now_ticks));
+        self.start_offset.advanced_by(self.events.len() as u64 - 1)
+    }
+
+    /// Log an event at the clock's current ticks, returning the offset
+    /// assigned.
+    pub fn push_now(&mut self, event: E, clock: &impl Clock) -> O {
+        self.push(event, clock.now_ticks())
+    }
+
+    /// Forget all of the events logged, assigning the offset given to the
+    /// next event logged e.g. when the server's state is reset. Any snapshot
+    /// is forgotten.
+    pub fn reset(&mut self, new_start_offset: O) {
+        self.events.clear();
+        self.start_offset = new_start_offset;
+        self.overwritten = 0;
+        self.snapshot = None;
+    }
+
+    /// The offset of the oldest event retained, if any.
+    pub fn start_offset(&self) -> Option<O> {
+        (!self.events.is_empty()).then_some(self.start_offset)
+    }
+
+    /// The offset of the latest event logged, if any.
+    pub fn end_offset(&self) -> Option<O> {
+        (!self.events.is_empty())
+            .then(|| self.start_offset.advanced_by(self.events.len() as u64 - 1))
+    }
+
+    /// The number of events retained.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Whether no events are retained.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// The percentage of the log's capacity that is occupied, rounded up so
+    /// that a log retaining any events is never reported as empty.
+    pub fn occupancy(&self) -> u8 {
+        (self.events.len() * 100).div_ceil(N) as u8
+    }
+
+    /// The number of events forgotten to make room for those logged since
+    /// the log was created or last reset.
+    pub fn overwritten(&self) -> u64 {
+        self.overwritten
+    }
+
+    /// The number of events following an offset that are no longer retained,
+    /// or all of those forgotten if the offset is unknown.
+    pub fn dropped_since(&self, offset: O) -> u64 {
+        let dropped = self.start_offset.distance_from(offset.successor());
+        if dropped <= self.overwritten {
+            dropped
+        } else {
+            self.overwritten
+        }
+    }
+
+    /// Whether the event of an offset is retained.
+    pub fn contains(&self, offset: O) -> bool {
+        self.end_offset()
+            .is_some_and(|end| offset.is_within(self.start_offset, end))
+    }
+
+    /// The reply to a client given the offset of the last event it received,
+    /// as of the ticks given. The reply is the event following that offset
+    /// if it is retained, no event if the offset is of the latest event, or
+    /// otherwise a recovery event conveying the offsets retained. A client
+    /// without an offset, or with that preceding the oldest event retained,
+    /// is replied the oldest event, the latter being how a client replays
+    /// every event retained following a recovery, see
+    /// [crate::offset_tracker::OffsetTracker::begin_recovery]. No event is
+    /// replied while the log is empty, and so a server must log an event
+    /// following a restart for the restart to be detected.
+    pub fn reply_for<EE>(
+        &self,
+        last_event_offset: Option<O>,
+        now_ticks: u64,
+    ) -> EventReply<EventOf<E, EE, O, S>>
+    where
+        EventOf<E, EE, O, S>: TemporalEvent,
+    {
+        let event = match self.next(last_event_offset) {
+            Next::Event(offset) => self.event(offset, now_ticks),
+            Next::Nothing => None,
+            Next::Recovery(start, end) => Some((self.recovery(last_event_offset, start, end), 0)),
+            Next::Snapshot => self
+                .snapshot
+                .clone()
+                .map(|(s, o)| (EventOf::Snapshot(s, o), 0)),
+        };
+        match event {
+            Some((event, delta_ticks)) => EventReply {
+                delta_ticks,
+                event: Some(event),
+            },
+            None => EventReply {
+                delta_ticks: 0,
+                event: None,
+            },
+        }
+    }
+
+    /// As per [EventLog::reply_for], but a reply of the event following the
+    /// offset is followed by those logged after it, for as many as `fits`
+    /// permits, see [event_batch_reply]. The batch conveys the number of
+    /// events logged after those replied as pending.
+    pub fn batch_reply_for<EE, F, const M: usize>(
+        &self,
+        last_event_offset: Option<O>,
+        now_ticks: u64,
+        fits: F,
+    ) -> EventBatchReply<EventOf<E, EE, O, S>, M>
+    where
+        EventOf<E, EE, O, S>: TemporalEvent,
+        F: FnMut(&EventBatchReply<EventOf<E, EE, O, S>, M>) -> bool,
+    {
+        let events = self.batch_events(last_event_offset, now_ticks);
+        event_batch_reply(events, |t| now_ticks.saturating_sub(t), fits)
+    }
+
+    /// As per [EventLog::batch_reply_for], but for a client asking for the
+    /// ticks of events as of the server's ticks rather than their ages, see
+    /// [absolute_event_batch_reply].
+    pub fn absolute_batch_reply_for<EE, F, const M: usize>(
+        &self,
+        last_event_offset: Option<O>,
+        now_ticks: u64,
+        fits: F,
+    ) -> EventBatchReply<EventOf<E, EE, O, S>, M>
+    where
+        EventOf<E, EE, O, S>: TemporalEvent,
+        F: FnMut(&EventBatchReply<EventOf<E, EE, O, S>, M>) -> bool,
+    {
+        let events = self.batch_events(last_event_offset, now_ticks);
+        absolute_event_batch_reply(events, now_ticks, fits)
+    }
+
+    // The events to batch in reply to a client given the offset of the last
+    // event it received, each with the ticks at which it was logged. Events
+    // that are not logged are of the ticks given.
+    fn batch_events<'a, EE: 'a>(
+        &'a self,
+        last_event_offset: Option<O>,
+        now_ticks: u64,
+    ) -> impl Iterator<Item = (EventOf<E, EE, O, S>, u64)> + 'a {
+        let (first_offset, other) = match self.next(last_event_offset) {
+            Next::Event(offset) => (Some(offset), None),
+            Next::Nothing => (None, None),
+            Next::Recovery(start, end) => {
+                (None, Some(self.recovery(last_event_offset, start, end)))
+            }
+            Next::Snapshot => (
+                None,
+                self.snapshot.clone().map(|(s, o)| EventOf::Snapshot(s, o)),
+            ),
+        };
+        let logged = first_offset.into_iter().flat_map(move |offset| {
+            self.events
+                .iter()
+                .skip(offset.distance_from(self.start_offset) as usize)
+                .zip(0..)
+                .map(move |((e, t), i)| (EventOf::Logged(e.clone(), offset.advanced_by(i)), *t))
+        });
+        logged.chain(other.map(|event| (event, now_ticks)))
+    }
+
+    /// As per [EventLog::reply_for], but for a client filtering events by
+    /// their class, see [CommandRequest::filter]. The reply is the first of
+    /// the events following the offset that the filter matches, skipping over
+    /// the others, and so the client's offset jumps to that of the event
+    /// replied. Should the filter match none of them then an
+    /// [EventOf::Filtered] conveys the offset of the latest event, so that
+    /// the client's offset still advances and the events skipped are not
+    /// lost to an overrun. Recovery is replied as with [EventLog::reply_for],
+    /// whatever the filter.
+    ///
+    /// [CommandRequest::filter]: crate::CommandRequest::filter
+    pub fn reply_filtered<EE>(
+        &self,
+        last_event_offset: Option<O>,
+        filter: u8,
+        now_ticks: u64,
+    ) -> EventReply<EventOf<E, EE, O, S>>
+    where
+        E: Classified,
+        EventOf<E, EE, O, S>: TemporalEvent,
+    {
+        let Next::Event(offset) = self.next(last_event_offset) else {
+            return self.reply_for(last_event_offset, now_ticks);
+        };
+        let skipped = offset.distance_from(self.start_offset) as usize;
+        let matched = self
+            .events
+            .iter()
+            .skip(skipped)
+            .position(|(e, _)| e.is_matched_by(filter));
+        match matched.and_then(|i| self.event(offset.advanced_by(i as u64), now_ticks)) {
+            Some((event, delta_ticks)) => EventReply {
+                delta_ticks,
+                event: Some(event),
+            },
+            None => EventReply {
+                delta_ticks: 0,
+                event: self.end_offset().map(EventOf::Filtered),
+            },
+        }
+    }
+
+    fn next(&self, last_event_offset: Option<O>) -> Next<O> {
+        let (Some(start), Some(end)) = (self.start_offset(), self.end_offset()) else {
+            return Next::Nothing;
+        };
+        match last_event_offset {
+            None => Next::Event(start),
+            // The offset preceding the start is that of an event forgotten,
+            // or of none if the log has yet to fill, and either way the event
+            // following it is the oldest retained.
+            Some(offset) if self.contains(offset.successor()) => Next::Event(offset.successor()),
+            Some(offset) if offset == end => Next::Nothing,
+            Some(_) if self.snapshot_follows() => Next::Snapshot,
+            Some(_) => Next::Recovery(start, end),
+        }
+    }
+
+    // Whether the events retained follow on from the snapshot, if any.
+    fn snapshot_follows(&self) -> bool {
+        self.snapshot
+            .as_ref()
+            .is_some_and(|(_, o)| self.end_offset() == Some(*o) || self.contains(o.successor()))
+    }
+
+    fn recovery<EE>(&self, last_event_offset: Option<O>, start: O, end: O) -> EventOf<E, EE, O, S> {
+        if !self.recovery_metadata {
+            return EventOf::Recovery(start, end);
+        }
+        let dropped = last_event_offset.map_or(self.overwritten, |o| self.dropped_since(o));
+        EventOf::RecoveryV1 {
+            start,
+            end,
+            dropped: dropped.try_into().unwrap_or(u32::MAX),
+            snapshot_available: self.snapshot_available,
+        }
+    }
+
+    fn event<EE>(&self, offset: O, now_ticks: u64) -> Option<(EventOf<E, EE, O, S>, u64)> {
+        self.events
+            .iter()
+            .nth(offset.distance_from(self.start_offset) as usize)
+            .map(|(e, t)| {
+                (
+                    EventOf::Logged(e.clone(), offset),
+                    now_ticks.saturating_sub(*t),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::NoEE;
+
+    // The log of a server that logs event `o * 10` at tick `o`, from the
+    // start offset for a number of events.
+    fn logged_from<const N: usize>(start_offset: u32, events: u32) -> EventLog<u64, N> {
+        let mut log = EventLog::new(start_offset);
+        for i in 0..events {
+            let offset = start_offset.wrapping_add(i);
+            assert_eq!(log.push(offset as u64 * 10, offset as u64), offset);
+        }
+        log
+    }
+
+    fn logged(offset: u32) -> Option<EventOf<u64, NoEE>> {
+        Some(EventOf::Logged(offset as u64 * 10, offset))
+    }
+
+    #[test]
+    fn test_offset_rules() {
+        const MAX: u32 = u32::MAX;
+        let retaining_10_to_13 = logged_from::<4>(10, 4);
+        let overrun_12_to_15 = logged_from::<4>(10, 6);
+        let wrapped = logged_from::<4>(MAX - 1, 4);
+        let empty = EventLog::<u64, 4>::new(10);
+        let mut reset = logged_from::<4>(10, 4);
+        reset.reset(50);
+        reset.push(500, 50);
+
+        // The cases of offset-rules.md given a log and the client's offset.
+        for (case, log, last_event_offset, expected) in [
+            // 1: event s(n) is present in history.
+            ("1", &retaining_10_to_13, Some(9), logged(10)),
+            ("1", &retaining_10_to_13, Some(10), logged(11)),
+            ("1", &retaining_10_to_13, Some(12), logged(13)),
+            ("1", &overrun_12_to_15, Some(11), logged(12)),
+            ("1", &wrapped, Some(MAX - 1), logged(MAX)),
+            ("1", &wrapped, Some(MAX), logged(0)),
+            ("1", &reset, Some(49), logged(50)),
+            // 2: event n is present in history and event s(n) is not.
+            ("2", &retaining_10_to_13, Some(13), None),
+            ("2", &overrun_12_to_15, Some(15), None),
+            ("2", &wrapped, Some(1), None),
+            ("2", &reset, Some(50), None),
+            // 3: otherwise.
+            (
+                "3",
+                &retaining_10_to_13,
+                Some(8),
+                Some(EventOf::Recovery(10, 13)),
+            ),
+            (
+                "3",
+                &retaining_10_to_13,
+                Some(14),
+                Some(EventOf::Recovery(10, 13)),
+            ),
+            (
+                "3",
+                &overrun_12_to_15,
+                Some(10),
+                Some(EventOf::Recovery(12, 15)),
+            ),
+            ("3", &wrapped, Some(2), Some(EventOf::Recovery(MAX - 1, 1))),
+            (
+                "3",
+                &wrapped,
+                Some(MAX - 3),
+                Some(EventOf::Recovery(MAX - 1, 1)),
+            ),
+            ("3", &reset, Some(13), Some(EventOf::Recovery(50, 50))),
+            // A client without an offset is given the oldest event.
+            ("-", &retaining_10_to_13, None, logged(10)),
+            ("-", &wrapped, None, logged(MAX - 1)),
+            // Nothing is replied until an event is logged.
+            ("-", &empty, None, None),
+            ("-", &empty, Some(10), None),
+        ] {
+            assert_eq!(
+                log.reply_for::<NoEE>(last_event_offset, 100).event,
+                expected,
+                "case {case} given {last_event_offset:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_offset_matrix() {
+        const MAX: u32 = u32::MAX;
+        let mut reset = logged_from::<4>(10, 4);
+        reset.reset(50);
+        reset.push(500, 50);
+
+        // The reply given the client's offset relative to the offsets that a
+        // log retains, whether or not events have been forgotten, the offsets
+        // wrap or a single event is retained.
+        for (log, start, end) in [
+            (logged_from::<4>(10, 4), 10, 13),
+            (logged_from::<4>(10, 6), 12, 15),
+            (logged_from::<4>(MAX - 1, 4), MAX - 1, 1),
+            (logged_from::<4>(0, 4), 0, 3),
+            (reset, 50, 50),
+        ] {
+            let recovery = || Some(EventOf::Recovery(start, end));
+            for (position, last_event_offset, expected) in [
+                ("none", None, logged(start)),
+                ("before start", Some(start.wrapping_sub(2)), recovery()),
+                (
+                    "preceding start",
+                    Some(start.wrapping_sub(1)),
+                    logged(start),
+                ),
+                ("preceding end", Some(end.wrapping_sub(1)), logged(end)),
+                ("end", Some(end), None),
+                ("following end", Some(end.wrapping_add(1)), recovery()),
+                ("far after end", Some(end.wrapping_add(1 << 31)), recovery()),
+            ] {
+                assert_eq!(
+                    log.reply_for::<NoEE>(last_event_offset, 100).event,
+                    expected,
+                    "{position} of {start}..={end} given {last_event_offset:?}"
+                );
+            }
+            for offset in (0..end.wrapping_sub(start)).map(|i| start.wrapping_add(i)) {
+                assert_eq!(
+                    log.reply_for::<NoEE>(Some(offset), 100).event,
+                    logged(offset.wrapping_add(1)),
+                    "within {start}..={end} given {offset}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_event_log_offsets() {
+        let mut log = logged_from::<4>(u32::MAX - 1, 3);
+        assert_eq!(log.start_offset(), Some(u32::MAX - 1));
+        assert_eq!(log.end_offset(), Some(0));
+        assert_eq!(log.len(), 3);
+        assert!(log.contains(u32::MAX));
+        assert!(!log.contains(1));
+
+        // The oldest event is forgotten once full.
+        assert_eq!(log.push(10, 1), 1);
+        assert_eq!(log.push(20, 2), 2);
+        assert_eq!(log.start_offset(), Some(u32::MAX));
+        assert_eq!(log.end_offset(), Some(2));
+        assert!(!log.contains(u32::MAX - 1));
+
+        assert_eq!(log.occupancy(), 100);
+        log.reset(7);
+        assert!(log.is_empty());
+        assert_eq!(log.occupancy(), 0);
+        assert_eq!(log.start_offset(), None);
+        assert_eq!(log.end_offset(), None);
+        assert_eq!(log.push(30, 3), 7);
+        assert_eq!(log.start_offset(), Some(7));
+        assert_eq!(log.occupancy(), 25);
+        assert_eq!(logged_from::<3>(0, 1).occupancy(), 34);
+    }
+
+    #[test]
+    fn test_event_log_replies() {
+        let log = logged_from::<4>(10, 4);
+
+        // Events convey their age.
+        assert_eq!(
+            log.reply_for::<NoEE>(Some(10), 100),
+            EventReply {
+                delta_ticks: 89,
+                event: logged(11),
+            }
+        );
+        assert_eq!(
+            log.reply_for::<NoEE>(Some(20), 100),
+            EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::Recovery(10, 13)),
+            }
+        );
+
+        // Batches follow the event replied with those logged after it.
+        let batch = log.batch_reply_for::<NoEE, _, 4>(Some(10), 100, |_| true);
+        assert_eq!(
+            batch
+                .replies
+                .iter()
+                .map(|r| (r.delta_ticks, r.event.clone()))
+                .collect::<std::vec::Vec<_>>(),
+            [(89, logged(11)), (88, logged(12)), (87, logged(13))]
+        );
+        let batch = log.batch_reply_for::<NoEE, _, 2>(None, 100, |_| true);
+        assert_eq!(batch.last_event_offset(), Some(11));
+        assert_eq!(batch.pending, 2);
+        let batch = log.batch_reply_for::<NoEE, _, 4>(None, 100, |_| true);
+        assert_eq!(batch.pending, 0);
+        let batch = log.batch_reply_for::<NoEE, _, 4>(Some(13), 100, |_| true);
+        assert!(batch.replies.is_empty());
+        assert_eq!(batch.pending, 0);
+        assert_eq!(
+            log.batch_reply_for::<NoEE, _, 4>(Some(20), 100, |_| false)
+                .replies[0]
+                .event,
+            Some(EventOf::Recovery(10, 13))
+        );
+
+        // Batches wrap along with offsets.
+        let log = logged_from::<4>(u32::MAX - 1, 4);
+        let batch = log.batch_reply_for::<NoEE, _, 4>(Some(u32::MAX - 1), 100, |_| true);
+        assert_eq!(batch.last_event_offset(), Some(1));
+        assert_eq!(batch.replies.len(), 3);
+    }
+
+    #[test]
+    fn test_event_log_wide_offsets() {
+        const MAX: u64 = u64::MAX;
+        let mut log = EventLog::<(), 4, u64>::new(MAX - 1);
+        for _ in 0..6 {
+            log.push((), 0);
+        }
+        assert_eq!(log.start_offset(), Some(0));
+        assert_eq!(log.end_offset(), Some(3));
+
+        let mut log = EventLog::<(), 4, u64>::new(MAX - 1);
+        for _ in 0..4 {
+            log.push((), 0);
+        }
+        for (last_event_offset, expected) in [
+            (Some(MAX), Some(EventOf::Logged((), 0))),
+            (Some(1), None),
+            (Some(MAX - 2), Some(EventOf::Logged((), MAX - 1))),
+            (Some(u32::MAX as u64), Some(EventOf::Recovery(MAX - 1, 1))),
+        ] {
+            assert_eq!(
+                log.reply_for::<NoEE>(last_event_offset, 0).event,
+                expected,
+                "given {last_event_offset:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_event_log_spanning_wrap() {
+        // A log spanning 0xFFFFFFF0 to 0x0000000F accepts the offsets of
+        // each event retained.
+        let log = logged_from::<32>(0xFFFF_FFF0, 32);
+        assert_eq!(log.start_offset(), Some(0xFFFF_FFF0));
+        assert_eq!(log.end_offset(), Some(0x0000_000F));
+        for (last_event_offset, expected) in [
+            (Some(0xFFFF_FFF0), logged(0xFFFF_FFF1)),
+            (Some(0xFFFF_FFF5), logged(0xFFFF_FFF6)),
+            (Some(0xFFFF_FFFF), logged(0)),
+            (Some(0x0000_0005), logged(0x0000_0006)),
+            (Some(0x0000_000F), None),
+            (Some(0xFFFF_FFEF), logged(0xFFFF_FFF0)),
+            (
+                Some(0xFFFF_FFEE),
+                Some(EventOf::Recovery(0xFFFF_FFF0, 0x0000_000F)),
+            ),
+            (
+                Some(0x0000_0010),
+                Some(EventOf::Recovery(0xFFFF_FFF0, 0x0000_000F)),
+            ),
+        ] {
+            assert_eq!(
+                log.reply_for::<NoEE>(last_event_offset, 100).event,
+                expected,
+                "given {last_event_offset:?}"
+            );
+        }
+        assert!(log.contains(0xFFFF_FFF0));
+        assert!(log.contains(0x0000_000F));
+        assert!(!log.contains(0x0000_0010));
+        assert!(!log.contains(0xFFFF_FFEF));
+    }
+
+    #[test]
+    fn test_recovery_metadata() {
+        // Events 10 to 19 logged, of which 16 to 19 are retained.
+        let mut overrun = logged_from::<4>(10, 10).with_recovery_metadata();
+        assert_eq!(overrun.overwritten(), 6);
+        let mut reset = logged_from::<4>(10, 10).with_recovery_metadata();
+        reset.reset(50);
+        reset.push(500, 50);
+        reset.push(510, 51);
+        let wrapped = logged_from::<4>(u32::MAX - 5, 10).with_recovery_metadata();
+
+        for (log, last_event_offset, expected) in [
+            // Those following the client's offset that were overwritten.
+            (&overrun, Some(12), Some((16, 19, 3))),
+            (&overrun, Some(14), Some((16, 19, 1))),
+            (&overrun, Some(9), Some((16, 19, 6))),
+            (&wrapped, Some(u32::MAX - 1), Some((0, 3, 1))),
+            (&wrapped, Some(u32::MAX - 6), Some((0, 3, 6))),
+            // All of those overwritten if the client's offset is unknown.
+            (&overrun, Some(8), Some((16, 19, 6))),
+            (&overrun, Some(25), Some((16, 19, 6))),
+            (&reset, Some(17), Some((50, 51, 0))),
+            // Recovery is not needed.
+            (&overrun, Some(15), None),
+            (&overrun, Some(19), None),
+            (&wrapped, Some(u32::MAX), None),
+        ] {
+            let expected = expected.map(|(start, end, dropped)| EventOf::RecoveryV1 {
+                start,
+                end,
+                dropped,
+                snapshot_available: false,
+            });
+            let event = log.reply_for::<NoEE>(last_event_offset, 100).event;
+            assert_eq!(
+                event.filter(|e| e.logged_offset().is_none()),
+                expected,
+                "given {last_event_offset:?}"
+            );
+        }
+
+        overrun.set_snapshot_available(true);
+        assert_eq!(
+            overrun
+                .batch_reply_for::<NoEE, _, 4>(Some(12), 100, |_| true)
+                .replies[0]
+                .event,
+            Some(EventOf::RecoveryV1 {
+                start: 16,
+                end: 19,
+                dropped: 3,
+                snapshot_available: true
+            })
+        );
+
+        // Recovery is conveyed without metadata by default.
+        let log = logged_from::<4>(10, 10);
+        assert_eq!(
+            log.reply_for::<NoEE>(Some(12), 100).event,
+            Some(EventOf::Recovery(16, 19))
+        );
+    }
+
+    #[derive(Clone, Debug, serde::Deserialize, PartialEq, serde::Serialize)]
+    enum ClassifiedEvent {
+        Alarm,
+        Diagnostic,
+    }
+
+    impl Classified for ClassifiedEvent {
+        fn class(&self) -> u8 {
+            match self {
+                ClassifiedEvent::Alarm => 0,
+                ClassifiedEvent::Diagnostic => 1,
+            }
+        }
+    }
+
+    const ALARMS: u8 = 1 << 0;
+
+    #[test]
+    fn test_snapshots() {
+        // Events 10 to 19 logged, of which 16 to 19 are retained, with the
+        // snapshot being the sum of the events up to the one of its offset.
+        let mut log = EventLog::<u64, 4, u32, u64>::new(10);
+        for offset in 10..20 {
+            log.push(offset as u64 * 10, offset as u64);
+        }
+        assert_eq!(log.snapshot(), None);
+        assert_eq!(
+            log.reply_for::<NoEE>(Some(12), 100).event,
+            Some(EventOf::Recovery(16, 19))
+        );
+
+        // A snapshot that the events retained follow on from is replied in
+        // place of recovery, events following it being replied as normal.
+        log.install_snapshot(1500, 15);
+        assert_eq!(log.snapshot(), Some((&1500, 15)));
+        for (last_event_offset, expected) in [
+            (Some(12), Some(EventOf::Snapshot(1500, 15))),
+            (Some(25), Some(EventOf::Snapshot(1500, 15))),
+            (Some(15), Some(EventOf::Logged(160, 16))),
+            (Some(19), None),
+            (None, Some(EventOf::Logged(160, 16))),
+        ] {
+            assert_eq!(
+                log.reply_for::<NoEE>(last_event_offset, 100).event,
+                expected,
+                "given {last_event_offset:?}"
+            );
+        }
+        let batch = log.batch_reply_for::<NoEE, _, 4>(Some(12), 100, |_| true);
+        assert_eq!(batch.replies.len(), 1);
+        assert_eq!(batch.replies[0].event, Some(EventOf::Snapshot(1500, 15)));
+
+        // A snapshot of the latest event is of use too.
+        log.install_snapshot(1900, 19);
+        assert_eq!(
+            log.reply_for::<NoEE>(Some(12), 100).event,
+            Some(EventOf::Snapshot(1900, 19))
+        );
+
+        // A snapshot that the events retained no longer follow on from is
+        // not replied.
+        log.install_snapshot(1500, 15);
+        log.push(200, 20);
+        assert_eq!(
+            log.reply_for::<NoEE>(Some(12), 100).event,
+            Some(EventOf::Recovery(17, 20))
+        );
+
+        // Snapshots are forgotten along with the events.
+        log.install_snapshot(2000, 20);
+        log.reset(50);
+        log.push(500, 50);
+        assert_eq!(log.snapshot(), None);
+        assert_eq!(
+            log.reply_for::<NoEE>(Some(12), 100).event,
+            Some(EventOf::Recovery(50, 50))
+        );
+    }
+
+    #[test]
+    fn test_filtered_replies() {
+        use ClassifiedEvent::*;
+
+        // Events 10 to 13 logged at ticks 1 to 4, of which 11 is an alarm.
+        let mut log = EventLog::<_, 4>::new(10);
+        for (t, event) in [Diagnostic, Alarm, Diagnostic, Diagnostic]
+            .into_iter()
+            .enumerate()
+        {
+            log.push(event, t as u64 + 1);
+        }
+
+        for (last_event_offset, filter, expected) in [
+            // The events skipped over precede the event replied.
+            (None, ALARMS, Some(EventOf::Logged(Alarm, 11))),
+            (Some(9), ALARMS, Some(EventOf::Logged(Alarm, 11))),
+            (Some(10), ALARMS, Some(EventOf::Logged(Alarm, 11))),
+            // The offset of the latest event is conveyed when none match.
+            (Some(11), ALARMS, Some(EventOf::Filtered(13))),
+            (Some(12), ALARMS, Some(EventOf::Filtered(13))),
+            (Some(11), 0, Some(EventOf::Filtered(13))),
+            (Some(11), 1 << 7, Some(EventOf::Filtered(13))),
+            // Nothing is replied to a client that is up to date.
+            (Some(13), ALARMS, None),
+            // Recovery is replied whatever the filter.
+            (Some(8), ALARMS, Some(EventOf::Recovery(10, 13))),
+            (Some(14), ALARMS, Some(EventOf::Recovery(10, 13))),
+            // Events of every class are replied as without a filter.
+            (Some(11), 0xff, Some(EventOf::Logged(Diagnostic, 12))),
+        ] {
+            assert_eq!(
+                log.reply_filtered::<NoEE>(last_event_offset, filter, 10)
+                    .event,
+                expected,
+                "given {last_event_offset:?} and {filter:#b}"
+            );
+        }
+
+        // Events replied convey their own age.
+        assert_eq!(
+            log.reply_filtered::<NoEE>(Some(9), ALARMS, 10).delta_ticks,
+            8
+        );
+
+        // The offsets skipped over wrap along with the log.
+        let mut log = EventLog::<_, 4>::new(u32::MAX - 1);
+        for event in [Diagnostic, Diagnostic, Diagnostic, Alarm] {
+            log.push(event, 0);
+        }
+        assert_eq!(
+            log.reply_filtered::<NoEE>(Some(u32::MAX - 2), ALARMS, 0)
+                .event,
+            Some(EventOf::Logged(Alarm, 1))
+        );
+    }
+
+    #[test]
+    fn test_filtered_recovery() {
+        use crate::offset_tracker::{Observation, OffsetTracker};
+        use ClassifiedEvent::*;
+
+        // Events 20 to 29 logged, of which 26 to 29 are retained and 27 is an
+        // alarm.
+        let mut log = EventLog::<_, 4>::new(20);
+        for offset in 20..30 {
+            log.push(if offset == 27 { Alarm } else { Diagnostic }, 0);
+        }
+
+        // A client having fallen behind recovers, skipping over diagnostics.
+        let mut tracker = OffsetTracker::new();
+        tracker.set_filter(Some(ALARMS));
+        tracker.observe(&EventReply::<EventOf<_, NoEE>> {
+            delta_ticks: 0,
+            event: Some(EventOf::Logged(Alarm, 21)),
+        });
+        let mut observations = std::vec::Vec::new();
+        for _ in 0..4 {
+            let reply = log.reply_filtered::<NoEE>(tracker.request(), ALARMS, 0);
+            observations.push((
+                reply.event.clone(),
+                tracker.observe(&reply),
+                tracker.is_recovering(),
+            ));
+        }
+        assert!(matches!(
+            observations[0],
+            (
+                Some(EventOf::Recovery(26, 29)),
+                Observation::RecoveryNeeded { .. },
+                true
+            )
+        ));
+        assert_eq!(
+            observations[1..],
+            [
+                (
+                    Some(EventOf::Logged(Alarm, 27)),
+                    Observation::NewEvent,
+                    true
+                ),
+                (Some(EventOf::Filtered(29)), Observation::NothingNew, false),
+                (None, Observation::NothingNew, false),
+            ]
+        );
+        assert_eq!(tracker.request(), Some(29));
+    }
+}
@@ -0,0 +1,365 @@
+//! Receiving the datagrams of the data link layer from a serial connection
+//! in chunks of bytes of any length e.g. as DMA delivers them into a ring
+//! buffer on its half and complete interrupts. A [FrameReceiver] locates the
+//! frames of the chunks fed to it, handing out the header of each datagram
+//! and a view of its encrypted payload, see
+//! [flip_flop_data::parse_datagram]. A datagram lying within a chunk is not
+//! copied, while one straddling chunks e.g. the wrap of a ring buffer is
+//! reassembled. A [RingReader] reads a connection of `embedded-io-async`
+//! into a ring buffer for connections without DMA. Requires the
+//! `embedded-io` feature.
+
+use embedded_io_async::Read;
+use flip_flop_data::{parse_datagram, FromDatagramError, Header};
+
+/// How the frames of a connection are delimited.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Framing {
+    /// Each frame is preceded by a byte of its length, as per
+    /// the `SerialTransport` of the `serial` feature.
+    LengthPrefixed,
+    /// Each frame is encoded with COBS and followed by a zero byte, as per
+    /// [crate::rs485::Rs485Transport]. Frames are decoded in place, and so
+    /// the chunks fed are overwritten.
+    Cobs,
+}
+
+/// Problems receiving a frame.
+#[derive(Debug, Eq, PartialEq)]
+pub enum FrameError {
+    /// A frame is longer than may be reassembled, and so is discarded.
+    TooLong,
+    /// A frame could not be decoded with COBS e.g. having been received
+    /// from part way through.
+    Corrupt,
+    /// A frame does not convey a datagram.
+    Datagram(FromDatagramError),
+}
+
+/// Locates the frames of the chunks of bytes fed to it, reassembling those
+/// of up to `N` bytes that straddle chunks.
+pub struct FrameReceiver<const N: usize> {
+    framing: Framing,
+    // The frame straddling chunks, of the length expected given a length
+    // prefix, or still encoded with COBS.
+    buf: [u8; N],
+    len: usize,
+    expected: Option<usize>,
+    // The bytes of a frame too long yet to be discarded given a length
+    // prefix, or whether bytes are discarded until a delimiter with COBS.
+    skipping: usize,
+}
+
+impl<const N: usize> FrameReceiver<N> {
+    /// A receiver of frames delimited as given.
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            buf: [0; N],
+            len: 0,
+            expected: None,
+            skipping: 0,
+        }
+    }
+
+    /// Feed the chunk of bytes received next, handing out each datagram
+    /// completed by it, or the problem with its frame, to the function
+    /// given.
+    pub fn feed<F>(&mut self, chunk: &mut [u8], on_frame: F)
+    where
+        F: FnMut(Result<(Header, &[u8]), FrameError>),
+    {
+        match self.framing {
+            Framing::LengthPrefixed => self.feed_length_prefixed(chunk, on_frame),
+            Framing::Cobs => self.feed_cobs(chunk, on_frame),
+        }
+    }
+
+    fn feed_length_prefixed<F>(&mut self, mut chunk: &mut [u8], mut on_frame: F)
+    where
+        F: FnMut(Result<(Header, &[u8]), FrameError>),
+    {
+        while !chunk.is_empty() {
+            if self.skipping > 0 {
+                let skipped = self.skipping.min(chunk.len());
+                self.skipping -= skipped;
+                chunk = &mut core::mem::take(&mut chunk)[skipped..];
+                continue;
+            }
+            match self.expected {
+                None => {
+                    let (len, rest) = core::mem::take(&mut chunk).split_first_mut().unwrap();
+                    let len = *len as usize;
+                    if len > N {
+                        self.skipping = len;
+                        on_frame(Err(FrameError::TooLong));
+                        chunk = rest;
+                    } else if len <= rest.len() {
+                        let (frame, rest) = rest.split_at_mut(len);
+                        on_frame(parse_datagram(frame).map_err(FrameError::Datagram));
+                        chunk = rest;
+                    } else {
+                        self.buf[..rest.len()].copy_from_slice(rest);
+                        self.len = rest.len();
+                        self.expected = Some(len);
+                        chunk = &mut [];
+                    }
+                }
+                Some(expected) => {
+                    let copied = (expected - self.len).min(chunk.len());
+                    self.buf[self.len..self.len + copied].copy_from_slice(&chunk[..copied]);
+                    self.len += copied;
+                    chunk = &mut core::mem::take(&mut chunk)[copied..];
+                    if self.len == expected {
+                        let frame = &self.buf[..expected];
+                        on_frame(parse_datagram(frame).map_err(FrameError::Datagram));
+                        (self.len, self.expected) = (0, None);
+                    }
+                }
+            }
+        }
+    }
+
+    fn feed_cobs<F>(&mut self, mut chunk: &mut [u8], mut on_frame: F)
+    where
+        F: FnMut(Result<(Header, &[u8]), FrameError>),
+    {
+        while !chunk.is_empty() {
+            let Some(end) = chunk.iter().position(|b| *b == 0) else {
+                self.reassemble(chunk);
+                return;
+            };
+            let (frame, rest) = core::mem::take(&mut chunk).split_at_mut(end);
+            chunk = &mut rest[1..];
+            if self.len == 0 && self.skipping == 0 {
+                if !frame.is_empty() {
+                    on_frame(decode_in_place(frame));
+                }
+            } else {
+                self.reassemble(frame);
+                if self.skipping > 0 {
+                    on_frame(Err(FrameError::TooLong));
+                } else {
+                    on_frame(decode_in_place(&mut self.buf[..self.len]));
+                }
+                (self.len, self.skipping) = (0, 0);
+            }
+        }
+    }
+
+    // Add bytes to the frame encoded with COBS that straddles chunks.
+    fn reassemble(&mut self, bytes: &[u8]) {
+        if self.skipping > 0 || self.len + bytes.len() > N {
+            (self.len, self.skipping) = (0, 1);
+        } else {
+            self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+    }
+}
+
+fn decode_in_place(frame: &mut [u8]) -> Result<(Header, &[u8]), FrameError> {
+    let len = cobs::decode_in_place(frame).map_err(|_| FrameError::Corrupt)?;
+    parse_datagram(&frame[..len]).map_err(FrameError::Datagram)
+}
+
+/// Reads a connection into a ring buffer of `RING` bytes, feeding each
+/// chunk read to a [FrameReceiver], as DMA would otherwise. Chunks end
+/// where the ring wraps.
+pub struct RingReader<R, const RING: usize> {
+    io: R,
+    ring: [u8; RING],
+    at: usize,
+}
+
+impl<R: Read, const RING: usize> RingReader<R, RING> {
+    /// A reader of the connection given.
+    pub fn new(io: R) -> Self {
+        const { assert!(RING > 0) };
+        Self {
+            io,
+            ring: [0; RING],
+            at: 0,
+        }
+    }
+
+    /// Read the bytes available next, feeding them to the receiver given,
+    /// see [FrameReceiver::feed]. Returns the number of bytes read, being
+    /// 0 once the connection is closed.
+    pub async fn read<const N: usize, F>(
+        &mut self,
+        receiver: &mut FrameReceiver<N>,
+        on_frame: F,
+    ) -> Result<usize, R::Error>
+    where
+        F: FnMut(Result<(Header, &[u8]), FrameError>),
+    {
+        let read = self.io.read(&mut self.ring[self.at..]).await?;
+        receiver.feed(&mut self.ring[self.at..self.at + read], on_frame);
+        self.at = (self.at + read) % RING;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec};
+
+    use aes::Aes128;
+    use ccm::{
+        aead::{generic_array::GenericArray, KeyInit},
+        consts::{U4, U7},
+        Ccm,
+    };
+    use embedded_io_async::Write;
+    use flip_flop_data::{decrypt_payload, port::Port, to_datagram, DataSource};
+
+    type AesCcm = Ccm<Aes128, U4, U7>;
+
+    // An in-memory pipe, bytes written to one end being read from the
+    // other, which is closed once they are all read.
+    #[derive(Clone, Default)]
+    struct Pipe(Rc<RefCell<VecDeque<u8>>>);
+
+    impl embedded_io_async::ErrorType for Pipe {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for Pipe {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let mut bytes = self.0.borrow_mut();
+            let len = buf.len().min(bytes.len());
+            for (b, byte) in buf.iter_mut().zip(bytes.drain(..len)) {
+                *b = byte;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Pipe {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.0.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn cipher() -> AesCcm {
+        AesCcm::new(GenericArray::from_slice(b"0123456789ABCDEF"))
+    }
+
+    // Datagrams of 24 bytes, each conveying its frame counter.
+    fn datagram(frame_counter: u16) -> [u8; 24] {
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 1,
+            server_port: Port::new(0).unwrap(),
+            frame_counter,
+        };
+        let mut datagram_buf = [0; 24];
+        to_datagram(
+            &cipher(),
+            &header,
+            &frame_counter.to_le_bytes(),
+            &mut datagram_buf,
+        );
+        datagram_buf
+    }
+
+    // Writes 4 datagrams framed as given to a pipe and then receives them
+    // through a ring of 64 bytes, returning the payload of each and whether
+    // it was handed out without being copied.
+    async fn loopback(framing: Framing) -> Vec<Result<([u8; 2], bool), FrameError>> {
+        let mut pipe = Pipe::default();
+        for frame_counter in 1..=4 {
+            let datagram = datagram(frame_counter);
+            match framing {
+                Framing::LengthPrefixed => {
+                    pipe.write_all(&[datagram.len() as u8]).await.unwrap();
+                    pipe.write_all(&datagram).await.unwrap();
+                }
+                Framing::Cobs => {
+                    let mut encoded = [0; 32];
+                    let len = cobs::encode(&datagram, &mut encoded);
+                    pipe.write_all(&encoded[..len]).await.unwrap();
+                    pipe.write_all(&[0]).await.unwrap();
+                }
+            }
+        }
+
+        let mut reader = RingReader::<_, 64>::new(pipe);
+        let ring = reader.ring.as_ptr_range();
+        let mut receiver = FrameReceiver::<32>::new(framing);
+        let mut received = Vec::new();
+        let mut on_frame = |frame: Result<(Header, &[u8]), FrameError>| {
+            received.push(frame.map(|(header, encrypted_payload)| {
+                let mut payload = [0; 2];
+                decrypt_payload(&cipher(), &header, encrypted_payload, &mut payload).unwrap();
+                (payload, ring.contains(&encrypted_payload.as_ptr()))
+            }))
+        };
+        while reader.read(&mut receiver, &mut on_frame).await.unwrap() > 0 {}
+        received
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        // The third frame straddles the wrap of the ring, and so only it is
+        // reassembled.
+        for framing in [Framing::LengthPrefixed, Framing::Cobs] {
+            assert_eq!(
+                loopback(framing).await,
+                [
+                    Ok(([1, 0], true)),
+                    Ok(([2, 0], true)),
+                    Ok(([3, 0], false)),
+                    Ok(([4, 0], true)),
+                ],
+                "{framing:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_discarding() {
+        // Frames too long are discarded without losing those following
+        // them, as are frames received from part way through.
+        let mut datagrams = Vec::new();
+        datagrams.extend([33]);
+        datagrams.extend([0; 33]);
+        datagrams.extend([24]);
+        datagrams.extend(datagram(5));
+        let mut cobs_datagrams = Vec::new();
+        cobs_datagrams.extend([4, 0]);
+        cobs_datagrams.extend([1; 33]);
+        cobs_datagrams.extend([0]);
+        let mut encoded = [0; 32];
+        let len = cobs::encode(&datagram(5), &mut encoded);
+        cobs_datagrams.extend(&encoded[..len]);
+        cobs_datagrams.extend([0]);
+
+        for (framing, mut bytes, expected) in [
+            (
+                Framing::LengthPrefixed,
+                datagrams,
+                &[Err(FrameError::TooLong), Ok(5)][..],
+            ),
+            (
+                Framing::Cobs,
+                cobs_datagrams,
+                &[Err(FrameError::Corrupt), Err(FrameError::TooLong), Ok(5)],
+            ),
+        ] {
+            let mut receiver = FrameReceiver::<32>::new(framing);
+            let mut received = Vec::new();
+            for chunk in bytes.chunks_mut(10) {
+                receiver.feed(chunk, |frame| {
+                    received.push(frame.map(|(header, _)| header.frame_counter))
+                });
+            }
+            assert_eq!(received, expected, "{framing:?}");
+        }
+    }
+}
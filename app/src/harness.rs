@@ -0,0 +1,1181 @@
+//! A bus held in memory connecting a [ClientEngine] to its [ServerEngine]s,
+//! for testing how the two behave together as frames are lost, duplicated,
+//! corrupted and delayed. Faults are decided by a generator seeded by the
+//! test, and time is kept by a [MockClock], so that a test behaves the same
+//! each time. Requires the `test-harness` feature and the standard library.
+//!
+//! Frames are conveyed between the engines as bytes, as an application
+//! would convey them, and so the bus stands in for both the data link layer
+//! and the transport. A frame corrupted is received with a bit flipped, as
+//! though the data link layer failed to detect it.
+//!
+//! The same faults may be applied to the datagrams of any [Transport] with
+//! a [FaultyTransport], so that an application's engines are tested as
+//! they run in production, see [crate::transport::run_client] and
+//! [crate::transport::run_server].
+//!
+//! An engine may instead be tested against the exact datagrams that it is
+//! to send and receive with a [MockTransport], scripted in the manner of
+//! embedded-hal-mock.
+
+extern crate std;
+
+use std::{
+    boxed::Box,
+    collections::VecDeque,
+    fmt::{Debug, Write},
+    format,
+    string::String,
+    vec::Vec,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    client::{Action, ClientConfig, ClientEngine},
+    clock::{Clock, MockClock},
+    event_log::EventLog,
+    offset_tracker::Observation,
+    server::{Output, ServerEngine},
+    transport::{timeout_at, Timer, Transport},
+    EventOf, EventReply, NoEE, TemporalEvent,
+};
+
+/// What becomes of the frames conveyed in one direction of a [VirtualBus],
+/// each fault being decided per frame. The faults of a frame are decided in
+/// order, and so a frame lost is neither duplicated nor corrupted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// The probability of a frame being lost, from 0 to 1.
+    pub loss: f64,
+    /// The probability of a frame being received twice, each copy with its
+    /// own latency.
+    pub duplication: f64,
+    /// The probability of a copy of a frame being received with one of its
+    /// bits flipped.
+    pub corruption: f64,
+    /// The least ticks that a copy of a frame takes to be received.
+    pub min_latency_ticks: u64,
+    /// The most ticks that a copy of a frame takes to be received, the
+    /// latency of each being uniform between the least and the most.
+    pub max_latency_ticks: u64,
+    /// The probability of a copy of a frame being held until up to
+    /// `reorder_window` frames conveyed after it have been conveyed, the
+    /// number of them being uniform, so that it is received after them.
+    pub reorder: f64,
+    /// The most frames that may be received ahead of a copy of a frame
+    /// held.
+    pub reorder_window: usize,
+}
+
+impl Faults {
+    /// Every frame is received at once, as transmitted.
+    pub const NONE: Self = Self {
+        loss: 0.0,
+        duplication: 0.0,
+        corruption: 0.0,
+        min_latency_ticks: 0,
+        max_latency_ticks: 0,
+        reorder: 0.0,
+        reorder_window: 0,
+    };
+}
+
+// A deterministic generator of the faults of frames, being SplitMix64.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Whether an outcome of the probability given occurs.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next() >> 11) as f64) < probability * (1u64 << 53) as f64
+    }
+
+    // A number from the least to the most given, inclusive.
+    fn between(&mut self, least: u64, most: u64) -> u64 {
+        match most.saturating_sub(least).checked_add(1) {
+            Some(range) => least + self.next() % range,
+            None => self.next(),
+        }
+    }
+
+    // The copies of a frame to be received as per the faults given, none
+    // should it be lost, along with the latency of each and how many frames
+    // conveyed after it are to be received ahead of it.
+    fn copies(&mut self, faults: &Faults, bytes: &[u8]) -> Vec<(u64, usize, Vec<u8>)> {
+        if self.chance(faults.loss) {
+            return Vec::new();
+        }
+        let copies = if self.chance(faults.duplication) {
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut bytes = bytes.to_vec();
+                if !bytes.is_empty() && self.chance(faults.corruption) {
+                    let bit = self.between(0, bytes.len() as u64 * 8 - 1);
+                    bytes[(bit / 8) as usize] ^= 1 << (bit % 8);
+                }
+                let latency = self.between(faults.min_latency_ticks, faults.max_latency_ticks);
+                let held = if faults.reorder_window > 0 && self.chance(faults.reorder) {
+                    self.between(1, faults.reorder_window as u64) as usize
+                } else {
+                    0
+                };
+                (latency, held, bytes)
+            })
+            .collect()
+    }
+}
+
+// A copy of a frame yet to be received.
+struct Frame<A> {
+    arrives_ticks: u64,
+    sequence: u64,
+    // The frames yet to be conveyed after it in the same direction that are
+    // to be received ahead of it.
+    held: usize,
+    address: A,
+    to_server: bool,
+    bytes: Vec<u8>,
+}
+
+// Convey a copy of a frame, releasing those held for it to be conveyed so
+// that they are received after it.
+fn convey<A>(in_flight: &mut Vec<Frame<A>>, sequence: &mut u64, mut frame: Frame<A>) {
+    frame.sequence = *sequence;
+    *sequence += 1;
+    for held in in_flight
+        .iter_mut()
+        .filter(|f| f.held > 0 && f.to_server == frame.to_server)
+    {
+        held.held -= 1;
+        if held.held == 0 {
+            held.arrives_ticks = held.arrives_ticks.max(frame.arrives_ticks);
+            held.sequence = *sequence;
+            *sequence += 1;
+        }
+    }
+    in_flight.push(frame);
+}
+
+// The next copy of a frame received as of the ticks given, if any.
+fn next_arrival<A>(in_flight: &mut Vec<Frame<A>>, now_ticks: u64) -> Option<Frame<A>> {
+    let next = in_flight
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.arrives_ticks <= now_ticks && f.held == 0)
+        .min_by_key(|(_, f)| (f.arrives_ticks, f.sequence))
+        .map(|(i, _)| i)?;
+    Some(in_flight.remove(next))
+}
+
+// When the next copy of a frame is to be received, if any are not held.
+fn next_arrival_ticks<A>(in_flight: &[Frame<A>]) -> Option<u64> {
+    in_flight
+        .iter()
+        .filter(|f| f.held == 0)
+        .map(|f| f.arrives_ticks)
+        .min()
+}
+
+// What a server does on the bus.
+#[derive(Debug, Eq, PartialEq)]
+enum Activity<A> {
+    Received(A),
+    Transmitted(A),
+}
+
+// Executes a command received by the server at an address, failing with
+// the ephemeral event to reply, if any.
+type Execute<A, C, E, EE, const LOG: usize> =
+    Box<dyn FnMut(&A, &C, &mut EventLog<E, LOG>) -> Result<(), Option<EE>>>;
+
+/// The replies delivered by a [VirtualBus]'s client, being the address of
+/// the server, the reply and what the client learnt from it.
+pub type Delivered<A, E, EE> = (A, EventReply<EventOf<E, EE>>, Observation);
+
+/// Connects a client to up to `SERVERS` servers, conveying the frames of
+/// each as per the [Faults] of its direction. The client and servers are
+/// engines of the types given, each server replying from a log of `LOG`
+/// events, and sharing the bus's clock.
+///
+/// The bus records the replies delivered to the client and the commands
+/// executed by the servers, along with when each server receives and
+/// transmits, so that the properties of the protocol may be asserted of a
+/// run.
+pub struct VirtualBus<
+    A,
+    C,
+    E,
+    const SERVERS: usize,
+    const COMMANDS: usize,
+    const EVENTS: usize,
+    const N: usize,
+    const LOG: usize,
+    EE = NoEE,
+> where
+    C: Serialize,
+    EventOf<E, EE>: TemporalEvent,
+{
+    client: ClientEngine<A, C, E, SERVERS, COMMANDS, EVENTS, N, EE>,
+    servers: Vec<(A, ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE>)>,
+    execute: Execute<A, C, E, EE, LOG>,
+    clock: MockClock,
+    rng: Rng,
+    request_faults: Faults,
+    reply_faults: Faults,
+    in_flight: Vec<Frame<A>>,
+    sequence: u64,
+    activity: Vec<Activity<A>>,
+    delivered: Vec<Delivered<A, E, EE>>,
+    executed: Vec<(A, C)>,
+}
+
+impl<
+        A,
+        C,
+        E,
+        const SERVERS: usize,
+        const COMMANDS: usize,
+        const EVENTS: usize,
+        const N: usize,
+        const LOG: usize,
+        EE,
+    > VirtualBus<A, C, E, SERVERS, COMMANDS, EVENTS, N, LOG, EE>
+where
+    A: Clone + Debug + Eq,
+    C: Clone + DeserializeOwned + Serialize,
+    E: Clone,
+    EventOf<E, EE>: TemporalEvent + DeserializeOwned,
+{
+    /// A bus without faults, connecting a client of the configuration given
+    /// to no servers yet, its faults decided by the seed given. Time starts
+    /// at 0.
+    pub fn new(config: ClientConfig, seed: u64) -> Self {
+        Self {
+            client: ClientEngine::new(config),
+            servers: Vec::new(),
+            execute: Box::new(|_, _, _| Ok(())),
+            clock: MockClock::new(0),
+            rng: Rng(seed),
+            request_faults: Faults::NONE,
+            reply_faults: Faults::NONE,
+            in_flight: Vec::new(),
+            sequence: 0,
+            activity: Vec::new(),
+            delivered: Vec::new(),
+            executed: Vec::new(),
+        }
+    }
+
+    /// Connect a server at an address, which the client polls every so many
+    /// ticks. The address is returned if `SERVERS` are already connected.
+    pub fn add_server(
+        &mut self,
+        address: A,
+        poll_interval_ticks: u64,
+        engine: ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE>,
+    ) -> Result<(), A> {
+        self.client
+            .add_server(address.clone(), poll_interval_ticks)?;
+        self.servers.retain(|(a, _)| *a != address);
+        self.servers.push((address, engine));
+        Ok(())
+    }
+
+    /// Disconnect the server at an address e.g. as it shuts down, returning
+    /// its engine. Frames conveyed to it are lost until a server is
+    /// connected at the address again, see [VirtualBus::add_server], which
+    /// the client continues to poll meanwhile.
+    pub fn disconnect(
+        &mut self,
+        address: &A,
+    ) -> Option<ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE>> {
+        let server = self.servers.iter().position(|(a, _)| a == address)?;
+        Some(self.servers.remove(server).1)
+    }
+
+    /// Change the faults of the requests conveyed to the servers, and of the
+    /// replies conveyed to the client, from now on.
+    pub fn set_faults(&mut self, requests: Faults, replies: Faults) {
+        self.request_faults = requests;
+        self.reply_faults = replies;
+    }
+
+    /// Execute the commands received by the servers with the function given,
+    /// which is given the address of the server and may log events, or fail
+    /// with the ephemeral event to reply, if any, e.g. the rejection of the
+    /// command, see [crate::ack::CommandFailure]. Commands are otherwise
+    /// executed without logging any.
+    pub fn set_execute(
+        &mut self,
+        execute: impl FnMut(&A, &C, &mut EventLog<E, LOG>) -> Result<(), Option<EE>> + 'static,
+    ) {
+        self.execute = Box::new(execute);
+    }
+
+    /// The client, e.g. for telling the events that it has received.
+    pub fn client(&self) -> &ClientEngine<A, C, E, SERVERS, COMMANDS, EVENTS, N, EE> {
+        &self.client
+    }
+
+    /// The client, e.g. for queueing commands.
+    pub fn client_mut(&mut self) -> &mut ClientEngine<A, C, E, SERVERS, COMMANDS, EVENTS, N, EE> {
+        &mut self.client
+    }
+
+    /// The server at an address, if connected.
+    pub fn server(&self, address: &A) -> Option<&ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE>> {
+        self.servers
+            .iter()
+            .find(|(a, _)| a == address)
+            .map(|(_, s)| s)
+    }
+
+    /// The server at an address, if connected, e.g. for logging events.
+    pub fn server_mut(
+        &mut self,
+        address: &A,
+    ) -> Option<&mut ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE>> {
+        self.servers
+            .iter_mut()
+            .find(|(a, _)| a == address)
+            .map(|(_, s)| s)
+    }
+
+    /// The clock shared by the client and servers.
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// The replies delivered to the client, in the order delivered.
+    pub fn delivered(&self) -> &[Delivered<A, E, EE>] {
+        &self.delivered
+    }
+
+    /// The offsets of the logged events of the server at an address that
+    /// the client has delivered as new, in the order delivered.
+    pub fn delivered_offsets(&self, address: &A) -> Vec<u32> {
+        self.delivered
+            .iter()
+            .filter(|(a, _, o)| a == address && *o == Observation::NewEvent)
+            .filter_map(|(_, reply, _)| match reply.event {
+                Some(EventOf::Logged(_, offset)) => Some(offset),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The commands executed by the servers, along with their addresses, in
+    /// the order executed.
+    pub fn executed(&self) -> &[(A, C)] {
+        &self.executed
+    }
+
+    /// Run the client and servers for the ticks given.
+    pub fn run_for(&mut self, ticks: u64) {
+        self.run_until(self.clock.now_ticks().saturating_add(ticks));
+    }
+
+    /// Run the client and servers until the ticks given, conveying the
+    /// client's requests and the servers' replies as they are transmitted,
+    /// and advancing the clock to the next thing to happen each time.
+    pub fn run_until(&mut self, until_ticks: u64) {
+        loop {
+            let now = self.clock.now_ticks();
+            while let Some(frame) = next_arrival(&mut self.in_flight, now) {
+                self.receive(frame, now);
+            }
+            self.client.handle_timeout(now);
+            let next_ticks = match self.client.next_action(now) {
+                Action::Transmit { address, bytes, .. } => {
+                    let bytes = bytes.to_vec();
+                    self.transmit(address, true, bytes, now);
+                    continue;
+                }
+                Action::Wait { until } => until,
+            };
+            let next_ticks =
+                next_arrival_ticks(&self.in_flight).map_or(next_ticks, |t| t.min(next_ticks));
+            if next_ticks > until_ticks {
+                self.clock.set(until_ticks);
+                break;
+            }
+            self.clock.set(next_ticks);
+        }
+    }
+
+    /// Assert that each transmission of a server was in reply to the
+    /// request that it had just received, which is the core invariant of
+    /// flip-flop.
+    pub fn assert_no_unsolicited_server_tx(&self) {
+        let mut received = None;
+        for activity in &self.activity {
+            match activity {
+                Activity::Received(address) => received = Some(address),
+                Activity::Transmitted(address) => {
+                    assert_eq!(
+                        received.take(),
+                        Some(address),
+                        "server {address:?} transmitted unsolicited"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Assert that the logged events of each server were delivered in the
+    /// order of their offsets and once only, barring those following a
+    /// recovery or snapshot, from which a server's events are delivered
+    /// afresh.
+    pub fn assert_events_delivered_in_order(&self) {
+        let mut last_offsets = Vec::<(&A, u32)>::new();
+        for (address, reply, observation) in &self.delivered {
+            let last = last_offsets.iter().position(|(a, _)| *a == address);
+            match (observation, &reply.event) {
+                (Observation::RecoveryNeeded { .. } | Observation::SnapshotReceived { .. }, _) => {
+                    if let Some(last) = last {
+                        last_offsets.remove(last);
+                    }
+                }
+                (
+                    Observation::NewEvent | Observation::GapDetected,
+                    Some(EventOf::Logged(_, offset)),
+                ) => match last {
+                    Some(last) => {
+                        let (_, last_offset) = &mut last_offsets[last];
+                        assert!(
+                            *offset > *last_offset,
+                            "server {address:?} delivered {offset} following {last_offset}"
+                        );
+                        *last_offset = *offset;
+                    }
+                    None => last_offsets.push((address, *offset)),
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // Convey a frame to or from the server at an address, as per the faults
+    // of its direction.
+    fn transmit(&mut self, server: A, to_server: bool, bytes: Vec<u8>, now_ticks: u64) {
+        let faults = if to_server {
+            self.request_faults
+        } else {
+            self.reply_faults
+        };
+        for (latency, held, bytes) in self.rng.copies(&faults, &bytes) {
+            let frame = Frame {
+                arrives_ticks: now_ticks.saturating_add(latency),
+                sequence: 0,
+                held,
+                address: server.clone(),
+                to_server,
+                bytes,
+            };
+            convey(&mut self.in_flight, &mut self.sequence, frame);
+        }
+    }
+
+    // Receive a copy of a frame, a server replying to a request and the
+    // client delivering the events of a reply.
+    fn receive(&mut self, frame: Frame<A>, now_ticks: u64) {
+        if !frame.to_server {
+            let Some(delivery) = self
+                .client
+                .handle_frame(&frame.address, &frame.bytes, now_ticks)
+            else {
+                return;
+            };
+            self.delivered.extend(
+                delivery
+                    .replies
+                    .into_iter()
+                    .map(|(reply, observation)| (frame.address.clone(), reply, observation)),
+            );
+            return;
+        }
+
+        let Some((address, engine)) = self.servers.iter_mut().find(|(a, _)| *a == frame.address)
+        else {
+            return;
+        };
+        self.activity.push(Activity::Received(address.clone()));
+        let execute = &mut self.execute;
+        let executed = &mut self.executed;
+        let output = engine.handle_frame(&frame.bytes, now_ticks, |command, log| {
+            executed.push((address.clone(), command.clone()));
+            execute(address, command, log)
+        });
+        let Output::Reply(bytes) = output else {
+            return;
+        };
+        let bytes = bytes.to_vec();
+        self.activity
+            .push(Activity::Transmitted(frame.address.clone()));
+        self.transmit(frame.address, false, bytes, now_ticks);
+    }
+}
+
+/// Conveys datagrams with another transport, the datagrams received being
+/// lost, duplicated, corrupted, delayed and reordered as per its [Faults],
+/// as decided by a generator of the seed given. A test failing given a
+/// seed therefore fails the same way again given it, so long as its time
+/// is kept the same way e.g. as paused by tokio. The transports of both a
+/// client and its servers are wrapped to fault both directions.
+///
+/// Datagrams are held until they are due to be received, as kept by the
+/// timer given, and so datagrams delayed or reordered are not lost when
+/// receiving is abandoned in favour of a timeout.
+pub struct FaultyTransport<T: Transport, K> {
+    inner: T,
+    timer: K,
+    seed: u64,
+    rng: Rng,
+    faults: Faults,
+    in_flight: Vec<Frame<T::Address>>,
+    sequence: u64,
+}
+
+impl<T: Transport, K: Timer> FaultyTransport<T, K> {
+    /// A transport without faults, wrapping the transport given, keeping
+    /// the time of datagrams delayed with the timer given, and deciding
+    /// faults by the seed given.
+    pub fn new(inner: T, timer: K, seed: u64) -> Self {
+        Self {
+            inner,
+            timer,
+            seed,
+            rng: Rng(seed),
+            faults: Faults::NONE,
+            in_flight: Vec::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Change the faults of the datagrams received from now on.
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.faults = faults;
+    }
+
+    /// The seed that faults are decided by, e.g. to tell of on failure.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The transport wrapped.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, K> Transport for FaultyTransport<T, K>
+where
+    T: Transport,
+    T::Address: Clone,
+    K: Timer,
+{
+    type Address = T::Address;
+    type Error = T::Error;
+
+    async fn send(&mut self, address: &Self::Address, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.inner.send(address, bytes).await
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, Self::Address), Self::Error> {
+        loop {
+            let now = self.timer.now_ticks();
+            if let Some(frame) = next_arrival(&mut self.in_flight, now) {
+                let len = frame.bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&frame.bytes[..len]);
+                return Ok((len, frame.address));
+            }
+            let received = match next_arrival_ticks(&self.in_flight) {
+                Some(due) => timeout_at(&self.timer, due, self.inner.recv(buf)).await,
+                None => Some(self.inner.recv(buf).await),
+            };
+            let Some((len, address)) = received.transpose()? else {
+                continue;
+            };
+            let now = self.timer.now_ticks();
+            for (latency, held, bytes) in self.rng.copies(&self.faults, &buf[..len]) {
+                let frame = Frame {
+                    arrives_ticks: now.saturating_add(latency),
+                    sequence: 0,
+                    held,
+                    address: address.clone(),
+                    // Datagrams received are all of the one direction.
+                    to_server: false,
+                    bytes,
+                };
+                convey(&mut self.in_flight, &mut self.sequence, frame);
+            }
+        }
+    }
+}
+
+/// A datagram that a [MockTransport] is scripted to send or receive.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expectation<A> {
+    /// A datagram of the bytes given is expected to be sent to the address.
+    Send(A, Vec<u8>),
+    /// A datagram of the bytes given is received from the address.
+    Recv(A, Vec<u8>),
+}
+
+/// The failure of a [MockTransport] to receive once its script is done.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScriptDone;
+
+/// Conveys only the datagrams of its script, in order, so that an engine
+/// is tested against exactly what it is to send and receive. A datagram
+/// sent other than as scripted panics, telling of both the datagram
+/// expected, marked with `-`, and the one sent, marked with `+`, their
+/// bytes in hex. Receiving waits for as long as a datagram is expected to
+/// be sent next, and so an engine's timeouts elapse as they would of a
+/// quiet bus. Receiving once the script is done fails with [ScriptDone],
+/// stopping an engine run against the transport.
+///
+/// A test should call [MockTransport::done] once its engine is to have
+/// conveyed all that was scripted.
+pub struct MockTransport<A> {
+    script: VecDeque<Expectation<A>>,
+    conveyed: usize,
+}
+
+impl<A: Clone + Debug + PartialEq> MockTransport<A> {
+    /// A transport of the script given.
+    pub fn new(script: impl IntoIterator<Item = Expectation<A>>) -> Self {
+        Self {
+            script: script.into_iter().collect(),
+            conveyed: 0,
+        }
+    }
+
+    /// Assert that all that was scripted has been conveyed.
+    #[track_caller]
+    pub fn done(&self) {
+        if let Some(expected) = self.script.front() {
+            panic!(
+                "datagram {} of the script was not conveyed:\n{}",
+                self.conveyed,
+                describe('-', expected)
+            );
+        }
+    }
+
+    fn send(&mut self, address: &A, bytes: &[u8]) {
+        match self.script.front() {
+            Some(Expectation::Send(a, b)) if a == address && b == bytes => {
+                self.script.pop_front();
+                self.conveyed += 1;
+            }
+            expected => {
+                let actual = Expectation::Send(address.clone(), bytes.to_vec());
+                let mut diff = expected.map_or_else(String::new, |e| describe('-', e));
+                diff.push_str(&describe('+', &actual));
+                panic!(
+                    "datagram {} differs from the script (-expected +actual):\n{diff}",
+                    self.conveyed
+                );
+            }
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> Result<Option<(usize, A)>, ScriptDone> {
+        match self.script.front() {
+            Some(Expectation::Recv(..)) => (),
+            Some(Expectation::Send(..)) => return Ok(None),
+            None => return Err(ScriptDone),
+        }
+        let Some(Expectation::Recv(address, bytes)) = self.script.pop_front() else {
+            unreachable!();
+        };
+        self.conveyed += 1;
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        Ok(Some((len, address)))
+    }
+}
+
+// A line telling of a datagram, the bytes in hex.
+fn describe<A: Debug>(mark: char, expectation: &Expectation<A>) -> String {
+    let (mut line, bytes) = match expectation {
+        Expectation::Send(address, bytes) => (format!("{mark} send to {address:?}:"), bytes),
+        Expectation::Recv(address, bytes) => (format!("{mark} recv from {address:?}:"), bytes),
+    };
+    for byte in bytes {
+        let _ = write!(line, " {byte:02x}");
+    }
+    line.push('\n');
+    line
+}
+
+impl<A: Clone + Debug + PartialEq> Transport for MockTransport<A> {
+    type Address = A;
+    type Error = ScriptDone;
+
+    async fn send(&mut self, address: &A, bytes: &[u8]) -> Result<(), ScriptDone> {
+        MockTransport::send(self, address, bytes);
+        Ok(())
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, A), ScriptDone> {
+        match MockTransport::recv(self, buf)? {
+            Some(received) => Ok(received),
+            None => core::future::pending().await,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "blocking"))]
+impl<A: Clone + Debug + PartialEq> crate::blocking::BlockingTransport for MockTransport<A> {
+    type Address = A;
+    type Error = ScriptDone;
+
+    fn send(&mut self, address: &A, bytes: &[u8]) -> Result<(), ScriptDone> {
+        MockTransport::send(self, address, bytes);
+        Ok(())
+    }
+
+    fn recv_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Option<(usize, A)>, ScriptDone> {
+        let received = MockTransport::recv(self, buf)?;
+        if received.is_none() {
+            let Some(timeout) = timeout else {
+                panic!("datagram {} would be waited for forever", self.conveyed);
+            };
+            std::thread::sleep(timeout);
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{status::StatusReporter, ResetCause, TickRate};
+
+    type Bus = VirtualBus<u8, u8, u8, 2, 2, 4, 32, 256>;
+    type Server = ServerEngine<u8, u8, 256, 2, 4, 32>;
+
+    const CONFIG: ClientConfig = ClientConfig {
+        tick_rate: TickRate::MILLISECONDS,
+        reply_timeout_ticks: 10,
+        client_time: false,
+        max_reply_len: false,
+    };
+
+    // Requests and replies are lost and duplicated, and replies may be
+    // received late, once their exchange has timed out.
+    const LOSSY: Faults = Faults {
+        loss: 0.2,
+        duplication: 0.1,
+        corruption: 0.0,
+        min_latency_ticks: 0,
+        max_latency_ticks: 2,
+        reorder: 0.0,
+        reorder_window: 0,
+    };
+    const LATE: Faults = Faults {
+        loss: 0.2,
+        duplication: 0.2,
+        corruption: 0.0,
+        min_latency_ticks: 1,
+        max_latency_ticks: 15,
+        reorder: 0.0,
+        reorder_window: 0,
+    };
+
+    fn server() -> Server {
+        Server::new(
+            EventLog::new(0),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        )
+    }
+
+    // Runs a bus of two servers, each logging an event every so many ticks
+    // until they have logged the events given, and then without faults
+    // until every event has been delivered.
+    fn log_events(bus: &mut Bus, events: usize, every_ticks: u64) {
+        while (1..=2).any(|a| bus.server(&a).unwrap().log().len() < events) {
+            for address in 1..=2 {
+                let now = bus.clock().now_ticks();
+                let log = bus.server_mut(&address).unwrap().log_mut();
+                if log.len() < events {
+                    log.push(address, now);
+                }
+            }
+            bus.run_for(every_ticks);
+        }
+        bus.set_faults(Faults::NONE, Faults::NONE);
+        bus.run_for(1_000);
+    }
+
+    fn lossy_bus(seed: u64) -> Bus {
+        let mut bus = Bus::new(CONFIG, seed);
+        bus.add_server(1, 5, server()).unwrap();
+        bus.add_server(2, 7, server()).unwrap();
+        bus.set_faults(LOSSY, LATE);
+        bus
+    }
+
+    #[test]
+    fn test_exactly_once() {
+        // Each event is delivered once, in order, despite requests and
+        // replies being lost, duplicated and received late.
+        for seed in 0..4 {
+            let mut bus = lossy_bus(seed);
+            log_events(&mut bus, 200, 23);
+            for address in 1..=2 {
+                assert_eq!(
+                    bus.delivered_offsets(&address),
+                    (0..200).collect::<Vec<_>>(),
+                    "seed {seed}"
+                );
+            }
+            bus.assert_events_delivered_in_order();
+            bus.assert_no_unsolicited_server_tx();
+        }
+
+        // Duplicates may be delivered too, such as for auditing.
+        let mut bus = lossy_bus(0);
+        bus.client_mut().set_deliver_duplicates(true);
+        log_events(&mut bus, 200, 23);
+        assert_eq!(bus.delivered_offsets(&1), (0..200).collect::<Vec<_>>());
+        assert!(bus
+            .delivered()
+            .iter()
+            .any(|(_, _, o)| *o == Observation::Duplicate));
+    }
+
+    #[test]
+    fn test_recovery_after_log_reset() {
+        let mut bus = lossy_bus(1);
+        log_events(&mut bus, 20, 11);
+
+        // Having reset its log, the server's events are recovered once, and
+        // those logged thereafter delivered in order as before.
+        bus.set_faults(LOSSY, LATE);
+        let server = bus.server_mut(&1).unwrap();
+        server.log_mut().reset(1_000);
+        server.log_mut().push(1, 0);
+        log_events(&mut bus, 20, 11);
+        let recoveries = bus
+            .delivered()
+            .iter()
+            .filter(|(_, _, o)| matches!(o, Observation::RecoveryNeeded { .. }))
+            .map(|(a, _, _)| *a)
+            .collect::<Vec<_>>();
+        assert_eq!(recoveries, [1]);
+        assert_eq!(
+            bus.delivered_offsets(&1),
+            (0..20).chain(1_001..1_020).collect::<Vec<_>>()
+        );
+        assert_eq!(bus.delivered_offsets(&2), (0..20).collect::<Vec<_>>());
+        bus.assert_events_delivered_in_order();
+        bus.assert_no_unsolicited_server_tx();
+    }
+
+    #[test]
+    fn test_commands_conveyed_once() {
+        // Each server logs the commands that it executes.
+        let mut bus = lossy_bus(2);
+        bus.set_execute(|_, command, log| {
+            log.push(*command, 0);
+            Ok(())
+        });
+        bus.set_faults(
+            Faults {
+                duplication: 0.0,
+                ..LOSSY
+            },
+            LATE,
+        );
+
+        // Commands are never conveyed again, even if the request conveying
+        // them, or its reply, is lost, and so each is executed once at most.
+        // Commands refused while the queue is full are not conveyed at all.
+        let mut queued = Vec::new();
+        for command in 0..100 {
+            if bus.client_mut().command(&1, command).is_ok() {
+                queued.push(command);
+            }
+            bus.run_for(20);
+        }
+        bus.set_faults(Faults::NONE, Faults::NONE);
+        bus.run_for(1_000);
+        let executed = bus.executed().iter().map(|(_, c)| *c).collect::<Vec<_>>();
+        assert!(executed.len() < queued.len());
+        assert!(executed.windows(2).all(|w| w[0] < w[1]));
+        assert!(executed.iter().all(|c| queued.contains(c)));
+        assert_eq!(
+            bus.delivered()
+                .iter()
+                .filter_map(|(_, reply, o)| match (&reply.event, o) {
+                    (Some(EventOf::Logged(command, _)), Observation::NewEvent) => Some(*command),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            executed
+        );
+        bus.assert_events_delivered_in_order();
+
+        // Commands are executed again should the bus duplicate the request
+        // conveying them, which the data link layer's frame counter is to
+        // guard against.
+        bus.set_faults(
+            Faults {
+                duplication: 1.0,
+                ..Faults::NONE
+            },
+            Faults::NONE,
+        );
+        bus.client_mut().command(&2, 7).unwrap();
+        bus.run_for(100);
+        assert_eq!(&bus.executed()[executed.len()..], [(2, 7), (2, 7)]);
+        bus.assert_no_unsolicited_server_tx();
+    }
+
+    #[test]
+    fn test_corruption() {
+        // Frames corrupted undetected are decoded as whatever they may be,
+        // and yet the engines carry on, the client catching up once frames
+        // are no longer corrupted.
+        let mut bus = lossy_bus(3);
+        let corrupt = Faults {
+            corruption: 0.3,
+            ..LOSSY
+        };
+        bus.set_faults(corrupt, corrupt);
+        log_events(&mut bus, 50, 13);
+        bus.server_mut(&1).unwrap().log_mut().push(1, 0);
+        bus.run_for(1_000);
+        for address in 1..=2 {
+            assert_eq!(
+                bus.client().tracker(&address).unwrap().request(),
+                bus.server(&address).unwrap().log().end_offset(),
+                "server {address}"
+            );
+        }
+        bus.assert_no_unsolicited_server_tx();
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut rng = Rng(0);
+        assert!(!(0..1_000).any(|_| rng.chance(0.0)));
+        assert!((0..1_000).all(|_| rng.chance(1.0)));
+        let heads = (0..10_000).filter(|_| rng.chance(0.25)).count();
+        assert!((2_300..2_700).contains(&heads));
+        assert!((0..1_000).all(|_| (3..=5).contains(&rng.between(3, 5))));
+        assert_eq!(rng.between(7, 7), 7);
+
+        // A frame received late arrives once the client no longer awaits it.
+        let mut bus = Bus::new(CONFIG, 0);
+        bus.add_server(1, 100, server()).unwrap();
+        bus.set_faults(
+            Faults::NONE,
+            Faults {
+                min_latency_ticks: 20,
+                max_latency_ticks: 20,
+                ..Faults::NONE
+            },
+        );
+        bus.run_until(99);
+        assert!(bus.delivered().is_empty());
+        assert_eq!(bus.client().silent_polls(&1), Some(1));
+        assert_eq!(bus.clock().now_ticks(), 99);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_faulty_transport() {
+        use tokio::{
+            sync::mpsc::{self, UnboundedReceiver},
+            time::{timeout, Duration, Instant},
+        };
+
+        // Receives the datagrams sent to it by the test.
+        struct Queue(UnboundedReceiver<Vec<u8>>);
+
+        impl Transport for Queue {
+            type Address = ();
+            type Error = ();
+
+            async fn send(&mut self, _: &(), _: &[u8]) -> Result<(), ()> {
+                Ok(())
+            }
+
+            async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, ()), ()> {
+                let bytes = self.0.recv().await.ok_or(())?;
+                buf[..bytes.len()].copy_from_slice(&bytes);
+                Ok((bytes.len(), ()))
+            }
+        }
+
+        // Milliseconds since the clock was created.
+        struct TestClock(Instant);
+
+        impl Clock for TestClock {
+            fn now_ticks(&self) -> u64 {
+                self.0.elapsed().as_millis() as u64
+            }
+        }
+
+        impl Timer for TestClock {
+            async fn sleep_until(&self, ticks: u64) {
+                tokio::time::sleep_until(self.0 + Duration::from_millis(ticks)).await
+            }
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let clock = TestClock(Instant::now());
+        let mut transport = FaultyTransport::new(Queue(rx), TestClock(clock.0), 7);
+        assert_eq!(transport.seed(), 7);
+        let mut buf = [0; 4];
+
+        // A datagram held is not received until the one conveyed after it
+        // is, and is not lost when receiving is abandoned meanwhile.
+        transport.set_faults(Faults {
+            reorder: 1.0,
+            reorder_window: 1,
+            ..Faults::NONE
+        });
+        tx.send(vec![1]).unwrap();
+        assert!(timeout(Duration::from_millis(50), transport.recv(&mut buf))
+            .await
+            .is_err());
+        transport.set_faults(Faults {
+            min_latency_ticks: 20,
+            max_latency_ticks: 20,
+            ..Faults::NONE
+        });
+        tx.send(vec![2]).unwrap();
+        tx.send(vec![3]).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let (len, ()) = transport.recv(&mut buf).await.unwrap();
+            received.push(buf[..len].to_vec());
+        }
+        assert_eq!(received, [vec![2], vec![1], vec![3]]);
+        assert_eq!(clock.now_ticks(), 70);
+
+        // Datagrams lost are never received.
+        transport.set_faults(Faults {
+            loss: 1.0,
+            ..Faults::NONE
+        });
+        tx.send(vec![4]).unwrap();
+        assert!(timeout(Duration::from_millis(50), transport.recv(&mut buf))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_transport() {
+        use tokio::time::{timeout, Duration};
+
+        let mut transport = MockTransport::new([
+            Expectation::Recv(1, vec![1, 2]),
+            Expectation::Send(1, vec![3]),
+            Expectation::Recv(2, vec![4]),
+        ]);
+        let mut buf = [0; 4];
+        assert_eq!(Transport::recv(&mut transport, &mut buf).await, Ok((2, 1)));
+        assert_eq!(buf[..2], [1, 2]);
+
+        // Nothing is received while a datagram is to be sent.
+        assert!(timeout(
+            Duration::from_millis(50),
+            Transport::recv(&mut transport, &mut buf)
+        )
+        .await
+        .is_err());
+        Transport::send(&mut transport, &1, &[3]).await.unwrap();
+        assert_eq!(Transport::recv(&mut transport, &mut buf).await, Ok((1, 2)));
+        transport.done();
+        assert_eq!(
+            Transport::recv(&mut transport, &mut buf).await,
+            Err(ScriptDone)
+        );
+    }
+
+    #[test]
+    fn test_blocking_mock_transport() {
+        use crate::blocking::BlockingTransport;
+        use std::time::Duration;
+
+        let mut transport = MockTransport::new([
+            Expectation::Send((), vec![]),
+            Expectation::Recv((), vec![5]),
+        ]);
+        let mut buf = [0; 4];
+        assert_eq!(
+            transport.recv_timeout(&mut buf, Some(Duration::ZERO)),
+            Ok(None)
+        );
+        BlockingTransport::send(&mut transport, &(), &[]).unwrap();
+        assert_eq!(transport.recv_timeout(&mut buf, None), Ok(Some((1, ()))));
+        transport.done();
+    }
+
+    #[test]
+    #[should_panic(expected = "datagram 1 differs from the script (-expected +actual):
+- send to 1: 00 01
++ send to 2: 00 01 02
+")]
+    fn test_mock_transport_sent_otherwise() {
+        let mut transport = MockTransport::new([
+            Expectation::Recv(1, vec![]),
+            Expectation::Send(1, vec![0, 1]),
+        ]);
+        transport.recv(&mut []).unwrap().unwrap();
+        MockTransport::send(&mut transport, &2, &[0, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "datagram 0 of the script was not conveyed:
+- recv from 1: ff
+")]
+    fn test_mock_transport_not_done() {
+        MockTransport::new([Expectation::Recv(1, vec![0xff])]).done();
+    }
+
+    #[test]
+    fn test_server_against_script() {
+        use std::cell::RefCell;
+
+        use crate::blocking::run_server_blocking;
+
+        let server = RefCell::new(Server::new(
+            EventLog::new(10),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        ));
+        server.borrow_mut().log_mut().push(1, 0);
+        // A request of nothing in particular, from a client yet to hear of
+        // the server, is replied with the server's status.
+        let mut transport = MockTransport::new([
+            Expectation::Recv(1, vec![0]),
+            Expectation::Send(1, vec![0, 4, 0, 1, 1, 0xe8, 0x07]),
+        ]);
+        let execute = |_: &u8, _: &mut EventLog<u8, 256>| Ok::<_, ()>(());
+        let e = run_server_blocking(&server, &mut transport, &MockClock::default(), execute);
+        assert_eq!(e, ScriptDone);
+        transport.done();
+    }
+}
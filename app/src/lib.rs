@@ -1,42 +1,690 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "blocking", feature = "std")), no_std)]
 #![doc = include_str!("../../README.md")]
 
-use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use core::{
+    fmt::{self, Write},
+    marker::PhantomData,
+    num::NonZeroU32,
+    time::Duration,
+};
+
+use heapless::Vec;
+use postcard::experimental::max_size::MaxSize;
+use serde::{
+    de::{self, DeserializeOwned, SeqAccess, Visitor},
+    ser::{SerializeTuple, SerializeTupleVariant},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+pub mod ack;
+#[cfg(any(test, feature = "blocking"))]
+pub mod blocking;
+#[cfg(feature = "std")]
+pub mod bridge;
+#[cfg(feature = "can")]
+pub mod can;
+pub mod client;
+pub mod clock;
+#[cfg(feature = "data")]
+pub mod datagram;
+#[cfg(any(test, feature = "std"))]
+pub mod debug_json;
+pub mod event_log;
+#[cfg(feature = "embedded-io")]
+pub mod frames;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod harness;
+pub mod observer;
+pub mod offset_store;
+pub mod offset_tracker;
+pub mod poller;
+#[cfg(feature = "derive")]
+pub mod ports;
+pub mod progress;
+#[cfg(feature = "embedded-io")]
+pub mod rs485;
+pub mod server;
+pub mod shutdown;
+pub mod status;
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod transport;
+
+// The code derived for a port suite names this crate, including within its
+// own tests.
+#[cfg(all(test, feature = "derive"))]
+extern crate self as flip_flop_app;
+
+// The derived implementations of Arbitrary refer to std.
+#[cfg(all(feature = "arbitrary", not(any(test, feature = "std"))))]
+extern crate std;
+
+/// The offset of a logged event, which wraps at its maximum value. Offsets
+/// are a `u32` by default, or a `u64` for servers that would otherwise wrap
+/// them within their service life. Offsets are encoded as varints and so a
+/// `u64` offset within the range of a `u32` is encoded exactly as the `u32`,
+/// permitting servers of either to interoperate with clients of either until
+/// an offset exceeds `u32::MAX`.
+pub trait Offset: Copy + fmt::Debug + DeserializeOwned + Eq + Serialize {
+    /// The offset following this one.
+    fn successor(self) -> Self {
+        self.advanced_by(1)
+    }
+    /// The offset `n` offsets after this one.
+    fn advanced_by(self, n: u64) -> Self;
+    /// The number of offsets from the offset given to this one.
+    fn distance_from(self, origin: Self) -> u64;
+    /// Whether this offset is within the range of offsets from the start
+    /// given up to and including the end given, the range wrapping at the
+    /// maximum offset. The range is that of the events following the start
+    /// offset, and so is always well defined, with a start of the end's
+    /// successor conveying all offsets.
+    fn is_within(self, start: Self, end: Self) -> bool {
+        self.distance_from(start) <= end.distance_from(start)
+    }
+    /// Whether this offset is before the one given, being the case if the
+    /// other is nearer by following this offset than by preceding it. This
+    /// is only well defined for offsets less than half of the range of
+    /// offsets apart e.g. 2^31 for a `u32`, and neither offset is before the
+    /// other if they are equal or exactly half of the range apart.
+    fn precedes(self, other: Self) -> bool {
+        let forward = other.distance_from(self);
+        forward != 0 && forward < self.distance_from(other)
+    }
+}
+
+impl Offset for u32 {
+    fn advanced_by(self, n: u64) -> Self {
+        self.wrapping_add(n as u32)
+    }
+    fn distance_from(self, origin: Self) -> u64 {
+        self.wrapping_sub(origin) as u64
+    }
+}
+
+impl Offset for u64 {
+    fn advanced_by(self, n: u64) -> Self {
+        self.wrapping_add(n)
+    }
+    fn distance_from(self, origin: Self) -> u64 {
+        self.wrapping_sub(origin)
+    }
+}
 
 /// A Command may only be sent by a client, of which there is only one
 /// client on the bus. Command requests take a type that provides their
 /// command; usually an enum. Command requests convey the last [EventReply]
 /// offset that the client has processed for the associated server, starting at
-/// 0 as the default.
+/// 0 as the default. The offset is a `u32` unless given otherwise, see
+/// [Offset]. Commands may borrow from the bytes they are decoded from e.g. a
+/// `&str` label, and so need not be allocated in a no_std environment.
 /// Note that the addressing of servers is left to a lower layer e.g. UDP, or a
 /// serial-based transport.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-pub struct CommandRequest<C: DeserializeOwned + Serialize> {
+///
+/// A client may also ask the server to reply with its [ServerStatus]. The
+/// request is conveyed along with the offset, and so a request of the status
+/// is not understood by servers unaware of it. Clients should therefore only
+/// ask servers that declare their awareness of it, and requests not asking
+/// for the status are encoded as they always have been. The same applies to
+/// a client filtering the events replied by their class, see [Classified],
+/// to a client conveying its time, to a client limiting the length of
+/// replies, and to a client asking for the server's ticks as of its events.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct CommandRequest<C: Serialize, O: Offset = u32> {
     /// The last offset of the server recorded by the client.
-    pub last_event_offset: Option<u32>,
+    pub last_event_offset: Option<O>,
+    /// Whether the server is to reply with its status in place of an event.
+    pub status_requested: bool,
+    /// The classes of logged event that the server is to reply, each being a
+    /// bit of the filter, or None for events of every class. Events of other
+    /// classes are skipped.
+    pub filter: Option<u8>,
+    /// The client's time as of sending the request in its ticks since its
+    /// epoch, or None if not conveyed. A server may record it so that it can
+    /// tell the client's time of its events, see [clock::ClockSync].
+    pub client_time: Option<u64>,
+    /// The most bytes that the reply to the request may take, or None if the
+    /// server's own limit applies, e.g. given a client bridged over a link
+    /// conveying less than the server's. The server replies fewer events to
+    /// keep within it, see [EventBatchReply::fits_within].
+    pub max_reply_len: Option<u8>,
+    /// Whether the server is to reply the ticks of its events as of its own
+    /// ticks rather than their ages, along with its ticks as of replying,
+    /// see [EventBatchReply::server_ticks]. The times of events are then
+    /// told correctly however long a reply takes to reach whoever consumes
+    /// it e.g. when queued by a gateway.
+    pub absolute_ticks: bool,
     /// The command to issue, or None if we wish to just get the next event
     /// available.
-    #[serde(
-        deserialize_with = "deserialise_last_field",
-        serialize_with = "serialise_last_field"
-    )]
     pub command: Option<C>,
 }
 
-/// A temporal event is one that has its durability conveyed.
-pub trait TemporalEvent: DeserializeOwned + Serialize {}
+impl<C: Serialize, O: Offset> Serialize for CommandRequest<C, O> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut t = s.serialize_tuple(2)?;
+        t.serialize_element(&RequestHeader {
+            last_event_offset: self.last_event_offset,
+            status_requested: self.status_requested,
+            filter: self.filter,
+            client_time: self.client_time,
+            max_reply_len: self.max_reply_len,
+            absolute_ticks: self.absolute_ticks,
+        })?;
+        if let Some(command) = &self.command {
+            t.serialize_element(command)?;
+        }
+        t.end()
+    }
+}
+
+/// The command is trailing and so is conveyed without an `Option`'s tag.
+impl<C: MaxSize + Serialize, O: Offset + MaxSize> MaxSize for CommandRequest<C, O> {
+    const POSTCARD_MAX_SIZE: usize = RequestHeader::<O>::POSTCARD_MAX_SIZE + C::POSTCARD_MAX_SIZE;
+}
+
+impl<'de, C: Deserialize<'de> + Serialize, O: Offset> Deserialize<'de> for CommandRequest<C, O> {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RequestVisitor<C, O>(PhantomData<(C, O)>);
+
+        impl<'de, C: Deserialize<'de> + Serialize, O: Offset> Visitor<'de> for RequestVisitor<C, O> {
+            type Value = CommandRequest<C, O>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a last event offset followed by a command")
+            }
+
+            // The command is the last field and so is absent for a poll.
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let header: RequestHeader<O> = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                Ok(CommandRequest {
+                    last_event_offset: header.last_event_offset,
+                    status_requested: header.status_requested,
+                    filter: header.filter,
+                    client_time: header.client_time,
+                    max_reply_len: header.max_reply_len,
+                    absolute_ticks: header.absolute_ticks,
+                    command: last_field(seq.next_element())?,
+                })
+            }
+        }
+
+        d.deserialize_tuple(2, RequestVisitor::<C, O>(PhantomData))
+    }
+}
+
+// The flags of the byte that a request starts with. The byte is otherwise
+// that of the last event offset's `Option`, and so requests not asking for
+// anything further are encoded as an `Option` followed by the command.
+const OFFSET_PRESENT: u8 = 1 << 0;
+const STATUS_REQUESTED: u8 = 1 << 1;
+const FILTERED: u8 = 1 << 2;
+const CLIENT_TIME: u8 = 1 << 3;
+const MAX_REPLY_LEN: u8 = 1 << 4;
+const ABSOLUTE_TICKS: u8 = 1 << 5;
+
+// What a request conveys ahead of its commands.
+struct RequestHeader<O> {
+    last_event_offset: Option<O>,
+    status_requested: bool,
+    filter: Option<u8>,
+    client_time: Option<u64>,
+    max_reply_len: Option<u8>,
+    absolute_ticks: bool,
+}
+
+impl<O: Offset> Serialize for RequestHeader<O> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut flags = 0;
+        if self.last_event_offset.is_some() {
+            flags |= OFFSET_PRESENT;
+        }
+        if self.status_requested {
+            flags |= STATUS_REQUESTED;
+        }
+        if self.filter.is_some() {
+            flags |= FILTERED;
+        }
+        if self.client_time.is_some() {
+            flags |= CLIENT_TIME;
+        }
+        if self.max_reply_len.is_some() {
+            flags |= MAX_REPLY_LEN;
+        }
+        if self.absolute_ticks {
+            flags |= ABSOLUTE_TICKS;
+        }
+        let mut t = s.serialize_tuple(5)?;
+        t.serialize_element(&flags)?;
+        if let Some(offset) = &self.last_event_offset {
+            t.serialize_element(offset)?;
+        }
+        if let Some(filter) = &self.filter {
+            t.serialize_element(filter)?;
+        }
+        if let Some(client_time) = &self.client_time {
+            t.serialize_element(client_time)?;
+        }
+        if let Some(max_reply_len) = &self.max_reply_len {
+            t.serialize_element(max_reply_len)?;
+        }
+        t.end()
+    }
+}
+
+impl<O: MaxSize> MaxSize for RequestHeader<O> {
+    const POSTCARD_MAX_SIZE: usize = u8::POSTCARD_MAX_SIZE
+        + O::POSTCARD_MAX_SIZE
+        + u8::POSTCARD_MAX_SIZE
+        + u64::POSTCARD_MAX_SIZE
+        + u8::POSTCARD_MAX_SIZE;
+}
+
+impl<'de, O: Offset> Deserialize<'de> for RequestHeader<O> {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct HeaderVisitor<O>(PhantomData<O>);
+
+        impl<'de, O: Offset> Visitor<'de> for HeaderVisitor<O> {
+            type Value = RequestHeader<O>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("request flags followed by a last event offset")
+            }
+
+            // Flags that are unknown are refused given that what they convey
+            // cannot be skipped.
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let flags: u8 = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                let known = OFFSET_PRESENT
+                    | STATUS_REQUESTED
+                    | FILTERED
+                    | CLIENT_TIME
+                    | MAX_REPLY_LEN
+                    | ABSOLUTE_TICKS;
+                if flags & !known != 0 {
+                    return Err(serde::de::Error::invalid_value(
+                        serde::de::Unexpected::Unsigned(flags as u64),
+                        &self,
+                    ));
+                }
+                let last_event_offset = if flags & OFFSET_PRESENT != 0 {
+                    Some(
+                        seq.next_element()?
+                            .ok_or_else(|| serde::de::Error::invalid_length(1, &self))?,
+                    )
+                } else {
+                    None
+                };
+                let filter = if flags & FILTERED != 0 {
+                    Some(
+                        seq.next_element()?
+                            .ok_or_else(|| serde::de::Error::invalid_length(2, &self))?,
+                    )
+                } else {
+                    None
+                };
+                let client_time = if flags & CLIENT_TIME != 0 {
+                    Some(
+                        seq.next_element()?
+                            .ok_or_else(|| serde::de::Error::invalid_length(3, &self))?,
+                    )
+                } else {
+                    None
+                };
+                let max_reply_len = if flags & MAX_REPLY_LEN != 0 {
+                    Some(
+                        seq.next_element()?
+                            .ok_or_else(|| serde::de::Error::invalid_length(4, &self))?,
+                    )
+                } else {
+                    None
+                };
+                Ok(RequestHeader {
+                    last_event_offset,
+                    status_requested: flags & STATUS_REQUESTED != 0,
+                    filter,
+                    client_time,
+                    max_reply_len,
+                    absolute_ticks: flags & ABSOLUTE_TICKS != 0,
+                })
+            }
+        }
+
+        d.deserialize_tuple(5, HeaderVisitor::<O>(PhantomData))
+    }
+}
+
+/// The most commands that a [MultiCommandRequest] may convey, so that it
+/// fits within the payload of a typical packet.
+pub const MAX_COMMANDS: usize = 8;
+
+/// Several commands sent by a client to a server in a single request, which
+/// the server executes in the order given. A server stops executing commands
+/// at the first that fails, and so the commands that follow it are not
+/// executed, see [CommandOutcomes].
+///
+/// A request of one command is encoded exactly as a [CommandRequest] of it,
+/// and a request of no commands as a [CommandRequest] of none i.e. a poll.
+/// Further commands follow the first, and so a server may decode every
+/// [CommandRequest] as a request of up to `N` commands. `N` may not exceed
+/// [MAX_COMMANDS]. A request conveying more than `N` commands is refused
+/// rather than its further commands being dropped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultiCommandRequest<C: Serialize, const N: usize, O: Offset = u32> {
+    /// The last offset of the server recorded by the client.
+    pub last_event_offset: Option<O>,
+    /// Whether the server is to reply with its status in place of an event,
+    /// see [CommandRequest::status_requested].
+    pub status_requested: bool,
+    /// The classes of logged event that the server is to reply, see
+    /// [CommandRequest::filter].
+    pub filter: Option<u8>,
+    /// The client's time as of sending the request, see
+    /// [CommandRequest::client_time].
+    pub client_time: Option<u64>,
+    /// The most bytes that the reply to the request may take, see
+    /// [CommandRequest::max_reply_len].
+    pub max_reply_len: Option<u8>,
+    /// Whether the server is to reply the ticks of its events as of its own
+    /// ticks, see [CommandRequest::absolute_ticks].
+    pub absolute_ticks: bool,
+    /// The commands to issue in order, or none if we wish to just get the
+    /// next event available.
+    pub commands: Vec<C, N>,
+}
+
+impl<C: Serialize, const N: usize, O: Offset> MultiCommandRequest<C, N, O> {
+    /// A request conveying the last offset of the server recorded by the
+    /// client, and no commands.
+    pub fn new(last_event_offset: Option<O>) -> Self {
+        const { assert!(N <= MAX_COMMANDS) };
+        Self {
+            last_event_offset,
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Execute the commands in order with the function given, stopping at
+    /// the first that fails, and return their outcomes e.g. for replying
+    /// them.
+    pub fn execute<F, X>(&self, mut execute: X) -> CommandOutcomes<F>
+    where
+        X: FnMut(&C) -> Result<(), F>,
+    {
+        let mut outcomes = CommandOutcomes {
+            executed: 0,
+            failure: None,
+        };
+        for command in &self.commands {
+            if let Err(e) = execute(command) {
+                outcomes.failure = Some(e);
+                break;
+            }
+            outcomes.executed += 1;
+        }
+        outcomes
+    }
+}
+
+impl<C: Serialize, const N: usize, O: Offset> Serialize for MultiCommandRequest<C, N, O> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        const { assert!(N <= MAX_COMMANDS) };
+        let mut t = s.serialize_tuple(1 + self.commands.len())?;
+        t.serialize_element(&RequestHeader {
+            last_event_offset: self.last_event_offset,
+            status_requested: self.status_requested,
+            filter: self.filter,
+            client_time: self.client_time,
+            max_reply_len: self.max_reply_len,
+            absolute_ticks: self.absolute_ticks,
+        })?;
+        for command in &self.commands {
+            t.serialize_element(command)?;
+        }
+        t.end()
+    }
+}
+
+impl<C: MaxSize + Serialize, const N: usize, O: Offset + MaxSize> MaxSize
+    for MultiCommandRequest<C, N, O>
+{
+    const POSTCARD_MAX_SIZE: usize =
+        RequestHeader::<O>::POSTCARD_MAX_SIZE + N * C::POSTCARD_MAX_SIZE;
+}
+
+impl<'de, C: Deserialize<'de> + Serialize, const N: usize, O: Offset> Deserialize<'de>
+    for MultiCommandRequest<C, N, O>
+{
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RequestVisitor<C, const N: usize, O>(PhantomData<(C, O)>);
+
+        impl<'de, C: Deserialize<'de> + Serialize, const N: usize, O: Offset> Visitor<'de>
+            for RequestVisitor<C, N, O>
+        {
+            type Value = MultiCommandRequest<C, N, O>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a last event offset followed by commands")
+            }
+
+            // Commands are decoded until there are no more, with those beyond
+            // the capacity of the request being refused.
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let header: RequestHeader<O> = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                let mut request = MultiCommandRequest::new(header.last_event_offset);
+                request.status_requested = header.status_requested;
+                request.filter = header.filter;
+                request.client_time = header.client_time;
+                request.max_reply_len = header.max_reply_len;
+                request.absolute_ticks = header.absolute_ticks;
+                while let Some(command) = last_field(seq.next_element::<C>())? {
+                    if request.commands.push(command).is_err() {
+                        return Err(serde::de::Error::invalid_length(1 + N + 1, &self));
+                    }
+                }
+                Ok(request)
+            }
+        }
+
+        d.deserialize_tuple(1 + N + 1, RequestVisitor::<C, N, O>(PhantomData))
+    }
+}
+
+/// The outcomes of executing the commands of a [MultiCommandRequest], which
+/// a server may reply e.g. as an ephemeral event. The commands are executed
+/// in order up to the first that fails, and so the outcomes are the number
+/// of commands executed successfully, followed by the failure of the next
+/// command, if any. Commands following a failure are not executed.
+#[derive(Clone, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+pub struct CommandOutcomes<F> {
+    /// The number of commands executed successfully.
+    pub executed: u8,
+    /// Why the command following those executed successfully failed, if it
+    /// did.
+    pub failure: Option<F>,
+}
+
+/// The outcome of executing a command of a [MultiCommandRequest].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandOutcome<'a, F> {
+    /// The command was executed successfully.
+    Executed,
+    /// The command failed, and so those that follow it were not executed.
+    Failed(&'a F),
+    /// The command was not executed given the failure of one before it, or
+    /// it was not requested.
+    NotExecuted,
+}
+
+impl<F> CommandOutcomes<F> {
+    /// The outcome of the command at the index given.
+    pub fn outcome(&self, index: usize) -> CommandOutcome<'_, F> {
+        let executed = self.executed as usize;
+        match &self.failure {
+            _ if index < executed => CommandOutcome::Executed,
+            Some(failure) if index == executed => CommandOutcome::Failed(failure),
+            _ => CommandOutcome::NotExecuted,
+        }
+    }
+}
+
+/// The health of a server as conveyed by an [EventOf::Status], being
+/// compact enough for every product to reply without defining events of its
+/// own for it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+pub struct ServerStatus {
+    /// The ticks since the server booted.
+    pub uptime_ticks: u64,
+    /// The percentage of the server's event log that is occupied.
+    pub log_occupancy: u8,
+    /// Why the server last reset.
+    pub last_reset_cause: ResetCause,
+    /// The rate at which the server's ticks elapse, including those of each
+    /// [EventReply::delta_ticks].
+    pub tick_rate: TickRate,
+}
+
+/// The rate at which a server's ticks elapse, in ticks per second. A server
+/// conveys its rate with its [ServerStatus], and so a client learns of it
+/// with the first reply following the server's boot. Ticks are then converted
+/// to and from durations at the rate, rather than a client assuming what a
+/// tick represents.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+pub struct TickRate(NonZeroU32);
+
+impl TickRate {
+    /// Ticks that are seconds.
+    pub const SECONDS: Self = Self(NonZeroU32::MIN);
+    /// Ticks that are milliseconds.
+    pub const MILLISECONDS: Self = Self(NonZeroU32::new(1_000).unwrap());
+
+    /// A rate of the number of ticks per second given, if not 0.
+    pub const fn from_hz(hz: u32) -> Option<Self> {
+        match NonZeroU32::new(hz) {
+            Some(hz) => Some(Self(hz)),
+            None => None,
+        }
+    }
+
+    /// The number of ticks per second.
+    pub const fn hz(self) -> u32 {
+        self.0.get()
+    }
+
+    /// The duration of a number of ticks, rounded up to the nanosecond so
+    /// that converting the duration back yields the same number of ticks.
+    pub fn to_duration(self, ticks: u64) -> Duration {
+        let hz = self.hz() as u64;
+        let nanos = (ticks % hz * 1_000_000_000).div_ceil(hz);
+        Duration::from_secs(ticks / hz) + Duration::from_nanos(nanos)
+    }
+
+    /// The number of whole ticks elapsed within a duration, saturating at
+    /// `u64::MAX`.
+    pub fn from_duration(self, duration: Duration) -> u64 {
+        (duration.as_nanos() * self.hz() as u128 / 1_000_000_000)
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+/// Why a server last reset, as conveyed by its [ServerStatus].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum ResetCause {
+    /// The cause is not known to the server.
+    Unknown,
+    /// Power was applied.
+    PowerOn,
+    /// The supply voltage dropped below that required.
+    Brownout,
+    /// A watchdog expired.
+    Watchdog,
+    /// The server's software requested it e.g. to apply an update.
+    Software,
+    /// A reset pin was asserted.
+    External,
+    /// A fault was detected e.g. a lockup of the processor.
+    Fault,
+}
+
+/// An event of one of up to 8 classes defined by an application e.g. alarms
+/// and diagnostics, so that a client may request events of only some of the
+/// classes, see [CommandRequest::filter].
+pub trait Classified {
+    /// The class of the event, being the bit of a filter that matches it, and
+    /// so from 0 to 7. Events of other classes are never matched.
+    fn class(&self) -> u8;
+
+    /// Whether the event is matched by a filter.
+    fn is_matched_by(&self, filter: u8) -> bool {
+        1_u8.checked_shl(self.class() as u32)
+            .is_some_and(|class| filter & class != 0)
+    }
+}
+
+/// A temporal event is one that has its durability conveyed. Events may
+/// borrow from the bytes they are decoded from e.g. a `&[u8]` payload, and
+/// so need not be owned.
+pub trait TemporalEvent: Serialize {}
 
 /// A type representing that there are no ephemeral events.
 pub type NoEE = ();
 
-/// The types of event that can be returned.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// A type representing that there are no snapshots of a server's state, see
+/// [EventOf::Snapshot].
+pub type NoSnapshot = ();
+
+/// The types of event that can be returned, conveying offsets that are a
+/// `u32` unless given otherwise, see [Offset], and snapshots of a server's
+/// state that are [NoSnapshot] unless given otherwise.
+#[derive(Clone, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
 #[non_exhaustive]
-pub enum EventOf<E, EE> {
+pub enum EventOf<E, EE, O = u32, S = NoSnapshot> {
     /// An event that has been logged, providing their identifier; usually an enum. These replies convey
     /// the offset they are associated with. If an offset overflows to zero then it is the
     /// server's responsibility to convey any important events that the client may need.
-    Logged(E, u32),
+    Logged(E, O),
     /// An event that has not been logged by the server and may be consumed by the client,
     /// often to convey some instantaneous event that does not need to be recorded. Events
     /// of this category should be benign if they are not consumed by a client.
@@ -45,21 +693,185 @@ pub enum EventOf<E, EE> {
     /// cannot be returned. The server's existing log start and end offsets
     /// are returned so that a client may determine what
     /// events constitute a recovery of state.
-    Recovery(u32, u32),
+    Recovery(O, O),
+    /// As per [EventOf::Recovery], but also conveying how far behind the
+    /// client is so that it may decide between replaying the events retained
+    /// and obtaining a snapshot of the server's state by other means. Clients
+    /// unaware of this event fail to decode it, and so servers should only
+    /// reply it once their clients are aware of it.
+    RecoveryV1 {
+        /// The offset of the oldest event retained.
+        start: O,
+        /// The offset of the latest event logged.
+        end: O,
+        /// The number of events following the client's offset that are no
+        /// longer retained. This is the number of events no longer retained
+        /// since the server's log was last reset if the client's offset is
+        /// unknown to it.
+        dropped: u32,
+        /// Whether the server has a snapshot of its state available.
+        snapshot_available: bool,
+    },
+    /// The health of the server, which is replied in place of an event to
+    /// the first request following the server's boot and to each request
+    /// asking for it, see [CommandRequest::status_requested]. Status is not
+    /// logged and so conveys no offset. Clients unaware of this event fail to
+    /// decode it.
+    Status(ServerStatus),
+    /// Replied in place of an event to a client filtering events by their
+    /// class when none of the events following its offset are of the classes
+    /// requested, conveying the offset of the last of them so that the client
+    /// may skip over them, see [CommandRequest::filter]. Clients unaware of
+    /// this event fail to decode it.
+    Filtered(O),
+    /// Replied in place of recovery when the client's offset precedes the
+    /// events that the server retains, but the server has a snapshot of its
+    /// state as of the event of the offset conveyed, which the events that it
+    /// retains follow on from. The client replaces its state with the
+    /// snapshot, and then receives the events that follow it as normal, see
+    /// [event_log::EventLog::install_snapshot]. Clients unaware of this event
+    /// fail to decode it.
+    Snapshot(S, O),
 }
-impl<E: Clone + DeserializeOwned + Serialize, EE: Clone + DeserializeOwned + Serialize>
-    TemporalEvent for EventOf<E, EE>
+impl<E, EE, O: Copy, S> EventOf<E, EE, O, S> {
+    /// The offset of the event if it has been logged.
+    pub fn logged_offset(&self) -> Option<O> {
+        match self {
+            EventOf::Logged(_, offset) => Some(*offset),
+            _ => None,
+        }
+    }
+}
+impl<E: Clone + Serialize, EE: Clone + Serialize, O: Offset, S: Clone + Serialize> TemporalEvent
+    for EventOf<E, EE, O, S>
 {
 }
 
+/// A logged event along with its offset, for applications that pattern match
+/// on logged events alone e.g. `Logged(event, offset)`. A logged event is
+/// encoded exactly as an [EventOf::Logged], and so may be replied in place of
+/// an [EventOf] by a server that only replies logged events. Decoding any
+/// other kind of event fails.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Logged<E, O = u32>(pub E, pub O);
+
+impl<E: Serialize, O: Serialize> Serialize for Logged<E, O> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut v = s.serialize_tuple_variant("EventOf", 0, "Logged", 2)?;
+        v.serialize_field(&self.0)?;
+        v.serialize_field(&self.1)?;
+        v.end()
+    }
+}
+
+impl<'de, E: Deserialize<'de>, O: Deserialize<'de>> Deserialize<'de> for Logged<E, O> {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match EventOf::<E, NoEE, O>::deserialize(d)? {
+            EventOf::Logged(event, offset) => Ok(Logged(event, offset)),
+            _ => Err(de::Error::custom("not a logged event")),
+        }
+    }
+}
+
+/// The variant's tag precedes the event and offset.
+impl<E: MaxSize, O: MaxSize> MaxSize for Logged<E, O> {
+    const POSTCARD_MAX_SIZE: usize =
+        u8::POSTCARD_MAX_SIZE + E::POSTCARD_MAX_SIZE + O::POSTCARD_MAX_SIZE;
+}
+
+impl<E: Clone + Serialize, O: Offset> TemporalEvent for Logged<E, O> {}
+
+impl<E, EE, O, S> From<Logged<E, O>> for EventOf<E, EE, O, S> {
+    fn from(Logged(event, offset): Logged<E, O>) -> Self {
+        EventOf::Logged(event, offset)
+    }
+}
+
+/// The event is returned if it is not a logged event.
+impl<E, EE, O, S> TryFrom<EventOf<E, EE, O, S>> for Logged<E, O> {
+    type Error = EventOf<E, EE, O, S>;
+
+    fn try_from(event: EventOf<E, EE, O, S>) -> Result<Self, Self::Error> {
+        match event {
+            EventOf::Logged(event, offset) => Ok(Logged(event, offset)),
+            event => Err(event),
+        }
+    }
+}
+
+/// An ephemeral event, for applications that pattern match on ephemeral
+/// events alone e.g. `Ephemeral(event)`. An ephemeral event is encoded
+/// exactly as an [EventOf::Ephemeral], and so may be replied in place of an
+/// [EventOf] by a server that only replies ephemeral events. Decoding any
+/// other kind of event fails.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Ephemeral<EE>(pub EE);
+
+impl<EE: Serialize> Serialize for Ephemeral<EE> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_newtype_variant("EventOf", 1, "Ephemeral", &self.0)
+    }
+}
+
+impl<'de, EE: Deserialize<'de>> Deserialize<'de> for Ephemeral<EE> {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match EventOf::<(), EE, u64>::deserialize(d)? {
+            EventOf::Ephemeral(event) => Ok(Ephemeral(event)),
+            _ => Err(de::Error::custom("not an ephemeral event")),
+        }
+    }
+}
+
+/// The variant's tag precedes the event.
+impl<EE: MaxSize> MaxSize for Ephemeral<EE> {
+    const POSTCARD_MAX_SIZE: usize = u8::POSTCARD_MAX_SIZE + EE::POSTCARD_MAX_SIZE;
+}
+
+impl<EE: Clone + Serialize> TemporalEvent for Ephemeral<EE> {}
+
+impl<E, EE, O, S> From<Ephemeral<EE>> for EventOf<E, EE, O, S> {
+    fn from(Ephemeral(event): Ephemeral<EE>) -> Self {
+        EventOf::Ephemeral(event)
+    }
+}
+
+/// The event is returned if it is not an ephemeral event.
+impl<E, EE, O, S> TryFrom<EventOf<E, EE, O, S>> for Ephemeral<EE> {
+    type Error = EventOf<E, EE, O, S>;
+
+    fn try_from(event: EventOf<E, EE, O, S>) -> Result<Self, Self::Error> {
+        match event {
+            EventOf::Ephemeral(event) => Ok(Ephemeral(event)),
+            event => Err(event),
+        }
+    }
+}
+
 /// An EventRequest may only be emitted by a server, of which there can be many, and
 /// only in relation to having received a [CommandRequest] from a client. Event replies
 /// take a temporal type that conveys their durability.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(bound(deserialize = "E: Deserialize<'de>"))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct EventReply<E: TemporalEvent> {
     /// The age of this event in relation to the server's notion of current time,
-    /// expressed in a manner agreed between a client and server e.g. ticks can
-    /// represent seconds.
+    /// expressed in the server's ticks, see [TickRate]. Within a batch
+    /// conveying [EventBatchReply::server_ticks], these are instead the
+    /// server's ticks as of the event.
     pub delta_ticks: u64,
     /// The event to reply.
     #[serde(
@@ -69,10 +881,20 @@ pub struct EventReply<E: TemporalEvent> {
     pub event: Option<E>,
 }
 
-/// Given an event and its time, return an event reply containing it.
-pub fn event_reply<E, T, DS>(maybe_event: Option<(E, T)>, duration_since: DS) -> EventReply<E>
+/// The event is trailing and so is conveyed without an `Option`'s tag.
+impl<E: TemporalEvent + MaxSize> MaxSize for EventReply<E> {
+    const POSTCARD_MAX_SIZE: usize = u64::POSTCARD_MAX_SIZE + E::POSTCARD_MAX_SIZE;
+}
+
+/// Given an event and its time, return an event reply containing it, its age
+/// being converted to ticks at the server's rate.
+pub fn event_reply<E, T, DS>(
+    maybe_event: Option<(E, T)>,
+    tick_rate: TickRate,
+    duration_since: DS,
+) -> EventReply<E>
 where
-    DS: FnOnce(T) -> u64,
+    DS: FnOnce(T) -> Duration,
     E: TemporalEvent,
     T: Copy,
 {
@@ -80,7 +902,7 @@ where
     // reply with a "no more events" enum and delta ticks of 0.
     maybe_event
         .map(|(e, t)| EventReply {
-            delta_ticks: duration_since(t),
+            delta_ticks: tick_rate.from_duration(duration_since(t)),
             event: Some(e),
         })
         .unwrap_or_else(|| EventReply {
@@ -89,82 +911,537 @@ where
         })
 }
 
-fn deserialise_last_field<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
-where
-    D: Deserializer<'de>,
-    T: Deserialize<'de>,
-{
-    T::deserialize(d).map_or_else(|_| Ok(None), |v| Ok(Some(v)))
+/// Given an event and its time in the ticks of the clock given, as logged
+/// with [event_log::EventLog], return an event reply containing it, its age
+/// being the ticks elapsed since. Events of the clock's future are of no
+/// age.
+pub fn clocked_event_reply<E: TemporalEvent>(
+    maybe_event: Option<(E, u64)>,
+    clock: &impl clock::Clock,
+) -> EventReply<E> {
+    let now_ticks = clock.now_ticks();
+    maybe_event
+        .map(|(e, ticks)| EventReply {
+            delta_ticks: now_ticks.saturating_sub(ticks),
+            event: Some(e),
+        })
+        .unwrap_or_else(|| EventReply {
+            delta_ticks: 0,
+            event: None,
+        })
 }
 
-fn serialise_last_field<S, T>(o: &Option<T>, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-    T: Serialize,
-{
-    if let Some(o) = o {
-        o.serialize(s)
-    } else {
-        s.serialize_unit()
-    }
+/// Given an event and the server's ticks as of it, return an event reply of
+/// the absolute form containing it, to be conveyed by a batch along with the
+/// server's ticks as of replying, see [EventBatchReply::server_ticks].
+pub fn absolute_event_reply<E: TemporalEvent>(maybe_event: Option<(E, u64)>) -> EventReply<E> {
+    maybe_event
+        .map(|(e, ticks)| EventReply {
+            delta_ticks: ticks,
+            event: Some(e),
+        })
+        .unwrap_or_else(|| EventReply {
+            delta_ticks: 0,
+            event: None,
+        })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Up to `N` logged events replied by a server in relation to a single
+/// [CommandRequest], being the event that would otherwise be replied
+/// followed by those logged after it, each with their own time and offset.
+/// Servers declaring the batched events capability during discovery may
+/// reply with batches.
+///
+/// A batch of one event is encoded exactly as an [EventReply] of it, and a
+/// batch of no events as an [EventReply] of none. Further events follow the
+/// first, each encoded as an [EventReply], and so clients unaware of batches
+/// decode the first event only, receiving the others when next polling.
+///
+/// The events of a batch are consecutive i.e. each is the successor of the
+/// one before it, and only the first may be other than a logged event. A
+/// server's log may be reset at any time, and so a client consumes the events
+/// of a batch for as long as they are consecutive, ignoring the remainder.
+/// Its next request then conveys the offset of the last event consumed, and
+/// the server replies with a recovery event if that offset is no longer
+/// present in its log, see [EventOf::Recovery].
+///
+/// A batch also conveys the number of logged events pending following its
+/// own, so that a client may poll a server falling behind more often, see
+/// [poller::AdaptivePoller]. The number follows the last event, encoded as
+/// an [EventReply] of none with the number as its ticks, and so clients
+/// unaware of it decode it as the end of the batch. It is conveyed only when
+/// events are both replied and pending.
+///
+/// A batch replied to a client asking for absolute ticks is of the absolute
+/// form, conveying the server's ticks as of each event in place of its age,
+/// along with the server's ticks as of replying, see
+/// [CommandRequest::absolute_ticks]. The server's ticks are conveyed in place
+/// of the number pending, as a value of at least 256 being that of the
+/// ticks plus one shifted left by 8 bits with the number pending in the low
+/// bits. Numbers pending never exceed 255, and so the forms are told apart
+/// whatever the request that a batch replies to. Clients unaware of the
+/// absolute form mistake the server's ticks of events for their ages, and so
+/// servers only reply it to those asking for it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EventBatchReply<E: TemporalEvent, const N: usize> {
+    /// The replies, each conveying an event.
+    pub replies: Vec<EventReply<E>, N>,
+    /// The number of logged events pending following those replied,
+    /// saturating at `u8::MAX`.
+    pub pending: u8,
+    /// The server's ticks as of replying for a batch of the absolute form,
+    /// saturating at [EventBatchReply::MAX_SERVER_TICKS], or None for a batch
+    /// conveying the ages of its events.
+    pub server_ticks: Option<u64>,
+}
 
-    #[test]
-    fn test_command_serialisation_with_a_command() {
-        #[derive(Debug, Deserialize, PartialEq, Serialize)]
-        enum Command {
-            A,
-            B,
-            C,
+impl<E: TemporalEvent, const N: usize> Default for EventBatchReply<E, N> {
+    fn default() -> Self {
+        Self {
+            replies: Vec::new(),
+            pending: 0,
+            server_ticks: None,
         }
+    }
+}
 
-        let request = CommandRequest {
-            last_event_offset: Some(9),
-            command: Some(Command::C),
-        };
+impl<E: TemporalEvent, const N: usize> EventBatchReply<E, N> {
+    /// The most server ticks that a batch of the absolute form conveys.
+    pub const MAX_SERVER_TICKS: u64 = MAX_SERVER_TICKS;
 
-        let mut buf = [0; 32];
-        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
-        assert_eq!(serialised, [1, 9, 2]);
-        assert_eq!(
-            postcard::from_bytes::<CommandRequest<Command>>(serialised).unwrap(),
-            CommandRequest {
-                last_event_offset: Some(9),
-                command: Some(Command::C),
-            }
-        );
+    /// The age of the event of a reply of the batch as of the server
+    /// replying, in the server's ticks, whatever the form of the batch.
+    pub fn age_ticks(&self, reply: &EventReply<E>) -> u64 {
+        match self.server_ticks {
+            Some(server_ticks) => server_ticks.saturating_sub(reply.delta_ticks),
+            None => reply.delta_ticks,
+        }
     }
 
-    #[test]
-    fn test_command_serialisation_with_no_command() {
-        #[derive(Debug, Deserialize, PartialEq, Serialize)]
-        enum Command {
-            A,
-            B,
-        }
+    /// The server's ticks as of the event of a reply of the batch, if the
+    /// batch is of the absolute form.
+    pub fn event_ticks(&self, reply: &EventReply<E>) -> Option<u64> {
+        self.server_ticks.map(|_| reply.delta_ticks)
+    }
 
-        let request = CommandRequest::<Command> {
-            last_event_offset: None,
-            command: None,
-        };
+    /// Whether the batch takes no more than the bytes given once encoded,
+    /// for use with [event_batch_reply] e.g. given the payload of a datagram
+    /// or the [CommandRequest::max_reply_len] of a client.
+    pub fn fits_within(&self, max_len: usize) -> bool {
+        postcard::experimental::serialized_size(self).is_ok_and(|len| len <= max_len)
+    }
+}
 
-        let mut buf = [0; 32];
-        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
-        assert_eq!(serialised, [0]);
-        assert_eq!(
-            postcard::from_bytes::<CommandRequest<Command>>(serialised).unwrap(),
-            CommandRequest {
-                last_event_offset: None,
-                command: None,
-            }
-        );
+impl<E, EE, O: Offset, S, const N: usize> EventBatchReply<EventOf<E, EE, O, S>, N>
+where
+    EventOf<E, EE, O, S>: TemporalEvent,
+{
+    /// The events of the batch that a client is to consume, being those for
+    /// as long as they are consecutive.
+    pub fn consecutive(&self) -> impl Iterator<Item = &EventReply<EventOf<E, EE, O, S>>> {
+        // The offset of the event before, once there is one.
+        let mut last_offset: Option<Option<O>> = None;
+        self.replies.iter().take_while(move |reply| {
+            let offset = reply.event.as_ref().and_then(EventOf::logged_offset);
+            let consecutive = last_offset.is_none_or(|last_offset| {
+                last_offset.is_some_and(|o| offset == Some(o.successor()))
+            });
+            last_offset = Some(offset);
+            consecutive
+        })
     }
 
-    #[test]
+    /// The offset of the last logged event that a client is to consume, and
+    /// so convey in its next [CommandRequest], if any.
+    pub fn last_event_offset(&self) -> Option<O> {
+        self.consecutive()
+            .last()
+            .and_then(|reply| reply.event.as_ref())
+            .and_then(EventOf::logged_offset)
+    }
+}
+
+/// A batch of no events is conveyed as an [EventReply] of none.
+/// The number of events pending, along with any server ticks, is encoded as a
+/// varint of up to 10 bytes.
+impl<E: TemporalEvent + MaxSize, const N: usize> MaxSize for EventBatchReply<E, N> {
+    const POSTCARD_MAX_SIZE: usize = if N == 0 {
+        EventReply::<E>::POSTCARD_MAX_SIZE
+    } else {
+        N * EventReply::<E>::POSTCARD_MAX_SIZE + u64::POSTCARD_MAX_SIZE
+    };
+}
+
+// The most server ticks conveyed along with the number pending.
+const MAX_SERVER_TICKS: u64 = (u64::MAX >> 8) - 1;
+
+// The value of the reply of none following the events of a batch, being the
+// number pending, or that of the absolute form also conveying the server's
+// ticks.
+fn batch_trailer(pending: u8, server_ticks: Option<u64>) -> u64 {
+    server_ticks.map_or(0, |ticks| (ticks.min(MAX_SERVER_TICKS) + 1) << 8) | pending as u64
+}
+
+impl<E: TemporalEvent, const N: usize> Serialize for EventBatchReply<E, N> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.replies.is_empty() {
+            let mut t = s.serialize_tuple(1)?;
+            t.serialize_element(&EventReply::<E> {
+                delta_ticks: batch_trailer(0, self.server_ticks),
+                event: None,
+            })?;
+            return t.end();
+        }
+        let trailer = batch_trailer(self.pending, self.server_ticks);
+        let trailed = trailer > 0;
+        let mut t = s.serialize_tuple(self.replies.len() + trailed as usize)?;
+        for reply in &self.replies {
+            t.serialize_element(reply)?;
+        }
+        if trailed {
+            t.serialize_element(&EventReply::<E> {
+                delta_ticks: trailer,
+                event: None,
+            })?;
+        }
+        t.end()
+    }
+}
+
+impl<'de, E: TemporalEvent + Deserialize<'de>, const N: usize> Deserialize<'de>
+    for EventBatchReply<E, N>
+{
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct BatchVisitor<E, const N: usize>(PhantomData<E>);
+
+        impl<'de, E: TemporalEvent + Deserialize<'de>, const N: usize> Visitor<'de> for BatchVisitor<E, N> {
+            type Value = EventBatchReply<E, N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("event replies")
+            }
+
+            // Replies are decoded until there are no more, or an event is
+            // absent, which is how a batch of no events is conveyed, and how
+            // the number of events pending and the server's ticks follow
+            // those of a batch. Events beyond those that the batch holds are
+            // counted as pending.
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut batch = EventBatchReply::default();
+                while let Some(reply) = last_field(seq.next_element::<EventReply<E>>())? {
+                    if reply.event.is_none() {
+                        let trailer = reply.delta_ticks;
+                        if trailer > u8::MAX as u64 {
+                            batch.server_ticks = Some((trailer >> 8) - 1);
+                            batch.pending = batch.pending.saturating_add(trailer as u8);
+                        } else if !batch.replies.is_empty() {
+                            batch.pending = batch.pending.saturating_add(trailer as u8);
+                        }
+                        break;
+                    }
+                    if batch.replies.push(reply).is_err() {
+                        batch.pending = batch.pending.saturating_add(1);
+                    }
+                }
+                Ok(batch)
+            }
+        }
+
+        d.deserialize_tuple(
+            N.max(1) + u8::MAX as usize + 1,
+            BatchVisitor::<E, N>(PhantomData),
+        )
+    }
+}
+
+/// Given consecutive logged events and their times, return a batch reply
+/// containing as many of them as `fits` permits e.g. given the payload of a
+/// datagram. `fits` is asked whether a batch fits each time that an event is
+/// added to it. The first event is always contained, as with [event_reply],
+/// and may be of any kind. Subsequent events are contained only while they
+/// are logged events that are consecutive. Those not contained are conveyed
+/// as pending, and so `fits` is asked again of the batch conveying them,
+/// with events removed from it until it fits.
+pub fn event_batch_reply<E, EE, O, S, T, I, DS, F, const N: usize>(
+    events: I,
+    duration_since: DS,
+    fits: F,
+) -> EventBatchReply<EventOf<E, EE, O, S>, N>
+where
+    EventOf<E, EE, O, S>: TemporalEvent,
+    O: Offset,
+    I: IntoIterator<Item = (EventOf<E, EE, O, S>, T)>,
+    DS: FnMut(T) -> u64,
+    F: FnMut(&EventBatchReply<EventOf<E, EE, O, S>, N>) -> bool,
+    T: Copy,
+{
+    fill_batch(EventBatchReply::default(), events, duration_since, fits)
+}
+
+/// As per [event_batch_reply], but given the server's ticks as of each event
+/// and as of replying, return a batch reply of the absolute form, see
+/// [EventBatchReply::server_ticks].
+pub fn absolute_event_batch_reply<E, EE, O, S, I, F, const N: usize>(
+    events: I,
+    now_ticks: u64,
+    fits: F,
+) -> EventBatchReply<EventOf<E, EE, O, S>, N>
+where
+    EventOf<E, EE, O, S>: TemporalEvent,
+    O: Offset,
+    I: IntoIterator<Item = (EventOf<E, EE, O, S>, u64)>,
+    F: FnMut(&EventBatchReply<EventOf<E, EE, O, S>, N>) -> bool,
+{
+    let batch = EventBatchReply {
+        server_ticks: Some(now_ticks),
+        ..EventBatchReply::default()
+    };
+    fill_batch(batch, events, |ticks| ticks, fits)
+}
+
+// Fill a batch with events for as long as they fit, each with the ticks
+// given of its time.
+fn fill_batch<E, EE, O, S, T, I, TS, F, const N: usize>(
+    mut batch: EventBatchReply<EventOf<E, EE, O, S>, N>,
+    events: I,
+    mut ticks_of: TS,
+    mut fits: F,
+) -> EventBatchReply<EventOf<E, EE, O, S>, N>
+where
+    EventOf<E, EE, O, S>: TemporalEvent,
+    O: Offset,
+    I: IntoIterator<Item = (EventOf<E, EE, O, S>, T)>,
+    TS: FnMut(T) -> u64,
+    F: FnMut(&EventBatchReply<EventOf<E, EE, O, S>, N>) -> bool,
+    T: Copy,
+{
+    let mut events = events.into_iter();
+    let mut last_offset: Option<O> = None;
+    let mut uncontained = 0;
+    for (event, t) in events.by_ref() {
+        let offset = event.logged_offset();
+        if let Some(last_offset) = last_offset {
+            if offset != Some(last_offset.successor()) {
+                uncontained = 1;
+                break;
+            }
+        } else if !batch.replies.is_empty() {
+            uncontained = 1;
+            break;
+        }
+        let reply = EventReply {
+            delta_ticks: ticks_of(t),
+            event: Some(event),
+        };
+        if batch.replies.push(reply).is_err() {
+            uncontained = 1;
+            break;
+        }
+        if batch.replies.len() > 1 && !fits(&batch) {
+            batch.replies.pop();
+            uncontained = 1;
+            break;
+        }
+        last_offset = offset;
+    }
+    let pending = uncontained + events.take(u8::MAX as usize).count();
+    batch.pending = pending.try_into().unwrap_or(u8::MAX);
+    while batch.pending > 0 && batch.replies.len() > 1 && !fits(&batch) {
+        batch.replies.pop();
+        batch.pending = batch.pending.saturating_add(1);
+    }
+    batch
+}
+
+fn deserialise_last_field<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    last_field(T::deserialize(d).map(Some))
+}
+
+// A trailing field is absent when the input ends before it. Any other error
+// conveys that the field is malformed, and so is returned.
+fn last_field<T, E: fmt::Display>(r: Result<Option<T>, E>) -> Result<Option<T>, E> {
+    match r {
+        Err(e) if is_end_of_input(&e) => Ok(None),
+        r => r,
+    }
+}
+
+// Errors are only known to be that of the input ending by their description,
+// which is compared with postcard's without allocating. The version of
+// postcard is pinned so that its description does not change unnoticed.
+fn is_end_of_input(e: &impl fmt::Display) -> bool {
+    struct Remaining<'a>(&'a str);
+
+    impl fmt::Write for Remaining<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let mut expected = heapless::String::<64>::new();
+    if write!(expected, "{}", postcard::Error::DeserializeUnexpectedEnd).is_err() {
+        return false;
+    }
+    let mut remaining = Remaining(&expected);
+    write!(remaining, "{e}").is_ok() && remaining.0.is_empty()
+}
+
+fn serialise_last_field<S, T>(o: &Option<T>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Serialize,
+{
+    if let Some(o) = o {
+        o.serialize(s)
+    } else {
+        s.serialize_unit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_serialisation_with_a_command() {
+        #[derive(Debug, Deserialize, PartialEq, Serialize)]
+        enum Command {
+            A,
+            B,
+            C,
+        }
+
+        let request = CommandRequest::<Command> {
+            last_event_offset: Some(9),
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: Some(Command::C),
+        };
+
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(serialised, [1, 9, 2]);
+        assert_eq!(
+            postcard::from_bytes::<CommandRequest<Command>>(serialised).unwrap(),
+            CommandRequest {
+                last_event_offset: Some(9),
+                status_requested: false,
+                filter: None,
+                client_time: None,
+                max_reply_len: None,
+                absolute_ticks: false,
+                command: Some(Command::C),
+            }
+        );
+    }
+
+    #[test]
+    fn test_command_serialisation_with_no_command() {
+        #[derive(Debug, Deserialize, PartialEq, Serialize)]
+        enum Command {
+            A,
+            B,
+        }
+
+        let request = CommandRequest::<Command> {
+            last_event_offset: None,
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: None,
+        };
+
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(serialised, [0]);
+        assert_eq!(
+            postcard::from_bytes::<CommandRequest<Command>>(serialised).unwrap(),
+            CommandRequest {
+                last_event_offset: None,
+                status_requested: false,
+                filter: None,
+                client_time: None,
+                max_reply_len: None,
+                absolute_ticks: false,
+                command: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_corrupted_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Command {
+            A,
+            B,
+        }
+
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Event {
+            A,
+        }
+
+        // A command out of range is not mistaken for a poll.
+        assert_eq!(
+            postcard::from_bytes::<CommandRequest<Command>>(&[1, 9, 2]),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+        assert_eq!(
+            postcard::from_bytes::<MultiCommandRequest<Command, 2>>(&[1, 9, 1, 2]),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+
+        // Nor is an event out of range mistaken for there being none.
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Event, NoEE>>>(&[10, 0, 1, 9]),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Event, NoEE>>>(&[10, 9]),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+        assert_eq!(
+            postcard::from_bytes::<EventBatchReply<EventOf<Event, NoEE>, 2>>(&[
+                10, 0, 0, 9, 10, 0, 1, 9
+            ]),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+
+        // Whereas input ending before the last field conveys its absence.
+        assert_eq!(
+            postcard::from_bytes::<CommandRequest<Command>>(&[1, 9])
+                .unwrap()
+                .command,
+            None
+        );
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Event, NoEE>>>(&[10])
+                .unwrap()
+                .event,
+            None
+        );
+    }
+
+    #[test]
     fn test_event_serialisation() {
         #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
         enum Event {
@@ -172,7 +1449,11 @@ mod tests {
             B,
         }
 
-        let reply = event_reply(Some((EventOf::<_, NoEE>::Logged(Event::B, 9), 0)), |_| 10);
+        let reply = event_reply(
+            Some((EventOf::<_, NoEE>::Logged(Event::B, 9), 0)),
+            TickRate::SECONDS,
+            |_| Duration::from_secs(10),
+        );
 
         let mut buf = [0; 32];
         let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
@@ -194,7 +1475,8 @@ mod tests {
             SomeOtherEvent,
         }
 
-        let reply: EventReply<EventOf<Event, NoEE>> = event_reply(None, |_: i32| 10);
+        let reply: EventReply<EventOf<Event, NoEE>> =
+            event_reply(None, TickRate::SECONDS, |_: i32| Duration::from_secs(10));
 
         let mut buf = [0; 32];
         let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
@@ -221,8 +1503,11 @@ mod tests {
             A,
         }
 
-        let reply: EventReply<EventOf<Event, Telemetry>> =
-            event_reply(Some((EventOf::Logged(Event::B, 9), 0)), |_| 10);
+        let reply: EventReply<EventOf<Event, Telemetry>> = event_reply(
+            Some((EventOf::Logged(Event::B, 9), 0)),
+            TickRate::SECONDS,
+            |_| Duration::from_secs(10),
+        );
 
         let mut buf = [0; 32];
         let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
@@ -235,8 +1520,11 @@ mod tests {
             }
         );
 
-        let reply: EventReply<EventOf<Event, Telemetry>> =
-            event_reply(Some((EventOf::Ephemeral(Telemetry::A), 0)), |_| 10);
+        let reply: EventReply<EventOf<Event, Telemetry>> = event_reply(
+            Some((EventOf::Ephemeral(Telemetry::A), 0)),
+            TickRate::SECONDS,
+            |_| Duration::from_secs(10),
+        );
 
         let mut buf = [0; 32];
         let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
@@ -249,4 +1537,1140 @@ mod tests {
             }
         );
     }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum BatchedEvent {
+        A,
+        B,
+    }
+
+    type BatchedEvents = EventBatchReply<EventOf<BatchedEvent, NoEE>, 4>;
+
+    fn logged(offsets: &[u32]) -> std::vec::Vec<(EventOf<BatchedEvent, NoEE>, u64)> {
+        offsets
+            .iter()
+            .map(|o| (EventOf::Logged(BatchedEvent::B, *o), *o as u64))
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot_serialisation() {
+        let reply = EventReply {
+            delta_ticks: 0,
+            event: Some(EventOf::<u8, NoEE, u32, [u8; 2]>::Snapshot([5, 6], 9)),
+        };
+
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
+        assert_eq!(serialised, [0, 6, 5, 6, 9]);
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<u8, NoEE, u32, [u8; 2]>>>(serialised)
+                .unwrap(),
+            reply
+        );
+    }
+
+    #[test]
+    fn test_logged_and_ephemeral_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Event {
+            A,
+            B,
+        }
+
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Telemetry {
+            A,
+        }
+
+        // The wrappers are encoded just as the variants of EventOf.
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&Logged(Event::B, 9u32), &mut buf).unwrap();
+        assert_eq!(serialised, [0, 1, 9]);
+        assert_eq!(
+            postcard::from_bytes::<EventOf<Event, Telemetry>>(serialised).unwrap(),
+            EventOf::Logged(Event::B, 9)
+        );
+        assert_eq!(
+            postcard::from_bytes::<Logged<Event>>(serialised).unwrap(),
+            Logged(Event::B, 9)
+        );
+
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&Ephemeral(Telemetry::A), &mut buf).unwrap();
+        assert_eq!(serialised, [1, 0]);
+        assert_eq!(
+            postcard::from_bytes::<EventOf<Event, Telemetry>>(serialised).unwrap(),
+            EventOf::Ephemeral(Telemetry::A)
+        );
+        assert_eq!(
+            postcard::from_bytes::<Ephemeral<Telemetry>>(serialised).unwrap(),
+            Ephemeral(Telemetry::A)
+        );
+        assert!(postcard::from_bytes::<Logged<Event>>(serialised).is_err());
+
+        let reply = EventReply {
+            delta_ticks: 10,
+            event: Some(Logged::<_>(Event::A, 3)),
+        };
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Event, Telemetry>>>(serialised).unwrap(),
+            EventReply {
+                delta_ticks: 10,
+                event: Some(EventOf::Logged(Event::A, 3)),
+            }
+        );
+
+        // Conversions keep what cannot be converted.
+        let event: EventOf<Event, Telemetry> = Logged(Event::A, 3).into();
+        assert_eq!(Logged::try_from(event), Ok(Logged(Event::A, 3)));
+        let event: EventOf<Event, Telemetry> = Ephemeral(Telemetry::A).into();
+        assert_eq!(
+            Logged::try_from(event.clone()),
+            Err(EventOf::Ephemeral(Telemetry::A))
+        );
+        assert_eq!(Ephemeral::try_from(event), Ok(Ephemeral(Telemetry::A)));
+
+        assert_eq!(Logged::<u8>::POSTCARD_MAX_SIZE, 7);
+        assert_eq!(Ephemeral::<u8>::POSTCARD_MAX_SIZE, 2);
+    }
+
+    #[test]
+    fn test_event_batch_serialisation() {
+        // A batch of one event is no larger than a reply of it, and a batch
+        // of none no larger than a reply of none.
+        let batch: BatchedEvents = event_batch_reply(logged(&[9]), |t| 10 + t, |_| true);
+        let reply = event_reply(
+            Some((EventOf::<_, NoEE>::Logged(BatchedEvent::B, 9), 9)),
+            TickRate::SECONDS,
+            |t| Duration::from_secs(10 + t),
+        );
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&batch, &mut buf).unwrap();
+        assert_eq!(serialised, [19, 0, 1, 9]);
+        let mut reply_buf = [0; 32];
+        assert_eq!(
+            serialised,
+            postcard::to_slice(&reply, &mut reply_buf).unwrap()
+        );
+        assert_eq!(
+            postcard::from_bytes::<BatchedEvents>(serialised).unwrap(),
+            batch
+        );
+
+        let batch: BatchedEvents = event_batch_reply(logged(&[]), |t| t, |_| true);
+        let serialised = postcard::to_slice(&batch, &mut buf).unwrap();
+        assert_eq!(serialised, [0]);
+        let no_event: EventReply<EventOf<BatchedEvent, NoEE>> =
+            event_reply(None, TickRate::SECONDS, |_: u64| Duration::from_secs(0));
+        assert_eq!(
+            serialised,
+            postcard::to_slice(&no_event, &mut reply_buf).unwrap()
+        );
+        assert!(postcard::from_bytes::<BatchedEvents>(serialised)
+            .unwrap()
+            .replies
+            .is_empty());
+
+        // Further events cost a reply each, and clients unaware of batches
+        // decode the first event only.
+        let batch: BatchedEvents = event_batch_reply(logged(&[9, 10, 11]), |t| t, |_| true);
+        let serialised = postcard::to_slice(&batch, &mut buf).unwrap();
+        assert_eq!(serialised, [9, 0, 1, 9, 10, 0, 1, 10, 11, 0, 1, 11]);
+        assert_eq!(
+            postcard::from_bytes::<BatchedEvents>(serialised).unwrap(),
+            batch
+        );
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<BatchedEvent, NoEE>>>(serialised).unwrap(),
+            batch.replies[0]
+        );
+        assert_eq!(batch.last_event_offset(), Some(11));
+
+        // Batches are decoded up to their capacity, with the events beyond
+        // it counted as pending.
+        let batch =
+            postcard::from_bytes::<EventBatchReply<EventOf<BatchedEvent, NoEE>, 2>>(serialised)
+                .unwrap();
+        assert_eq!(batch.last_event_offset(), Some(10));
+        assert_eq!(batch.pending, 1);
+
+        // The number of events pending follows the last event as a reply of
+        // none, which clients unaware of it decode as the end of the batch.
+        let batch: BatchedEvents =
+            event_batch_reply(logged(&[9, 10, 11, 12, 13, 14]), |t| t, |_| true);
+        assert_eq!(batch.pending, 2);
+        let serialised = postcard::to_slice(&batch, &mut buf).unwrap();
+        assert_eq!(
+            serialised,
+            [9, 0, 1, 9, 10, 0, 1, 10, 11, 0, 1, 11, 12, 0, 1, 12, 2]
+        );
+        assert_eq!(
+            postcard::from_bytes::<BatchedEvents>(serialised).unwrap(),
+            batch
+        );
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<BatchedEvent, NoEE>>>(serialised).unwrap(),
+            batch.replies[0]
+        );
+        let mut unaware = batch.clone();
+        unaware.pending = 0;
+        let mut unaware_buf = [0; 32];
+        assert_eq!(
+            postcard::to_slice(&unaware, &mut unaware_buf).unwrap(),
+            &serialised[..serialised.len() - 1]
+        );
+    }
+
+    #[test]
+    fn test_event_batch_reply() {
+        // Capacity bounds a batch, as does what fits e.g. a datagram.
+        let batch: BatchedEvents = event_batch_reply(logged(&[1, 2, 3, 4, 5]), |t| t, |_| true);
+        assert_eq!(batch.last_event_offset(), Some(4));
+        let batch: BatchedEvents = event_batch_reply(
+            logged(&[1, 2, 3, 4, 5]),
+            |t| t,
+            |batch| postcard::experimental::serialized_size(batch).unwrap() <= 9,
+        );
+        assert_eq!(batch.last_event_offset(), Some(2));
+        assert_eq!(batch.pending, 3);
+
+        // The number of events pending takes a byte, and so may displace an
+        // event that would otherwise fit.
+        let batch: BatchedEvents = event_batch_reply(
+            logged(&[1, 2, 3, 4, 5]),
+            |t| t,
+            |batch| postcard::experimental::serialized_size(batch).unwrap() <= 8,
+        );
+        assert_eq!(batch.last_event_offset(), Some(1));
+        assert_eq!(batch.pending, 4);
+
+        // Batches are of consecutive events, and so end at a reset of the log.
+        let batch: BatchedEvents = event_batch_reply(logged(&[6, 7, 2, 3]), |t| t, |_| true);
+        assert_eq!(batch.replies.len(), 2);
+        assert_eq!(batch.last_event_offset(), Some(7));
+        let batch: BatchedEvents = event_batch_reply(logged(&[u32::MAX, 0, 1]), |t| t, |_| true);
+        assert_eq!(batch.last_event_offset(), Some(1));
+
+        // A recovery event is replied alone, even if nothing else would fit.
+        let mut events = vec![(EventOf::Recovery(2, 3), 0)];
+        events.extend(logged(&[2, 3]));
+        let batch: BatchedEvents = event_batch_reply(events, |t| t, |_| false);
+        assert_eq!(
+            batch.replies,
+            [EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::Recovery(2, 3))
+            }]
+        );
+        assert_eq!(batch.last_event_offset(), None);
+    }
+
+    #[test]
+    fn test_event_batch_straddling_reset() {
+        // A client consumes events for as long as they are consecutive,
+        // ignoring those following a reset of the log.
+        let mut batch = BatchedEvents::default();
+        for (event, t) in logged(&[5, 6, 2, 3]) {
+            batch
+                .replies
+                .push(EventReply {
+                    delta_ticks: t,
+                    event: Some(event),
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            batch
+                .consecutive()
+                .filter_map(|r| r.event.as_ref().and_then(EventOf::logged_offset))
+                .collect::<std::vec::Vec<_>>(),
+            [5, 6]
+        );
+        assert_eq!(batch.last_event_offset(), Some(6));
+
+        // Nothing follows anything other than a logged event.
+        batch.replies[0].event = Some(EventOf::Recovery(5, 6));
+        assert_eq!(batch.consecutive().count(), 1);
+        assert_eq!(batch.last_event_offset(), None);
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum Setting {
+        Brightness(u8),
+        Volume(u8),
+    }
+
+    type Settings = MultiCommandRequest<Setting, 4>;
+
+    #[test]
+    fn test_multi_command_serialisation() {
+        // A request of one command is encoded as a command request of it.
+        let mut request = Settings::new(Some(9));
+        request.commands.push(Setting::Volume(3)).unwrap();
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(serialised, [1, 9, 1, 3]);
+        let mut command_buf = [0; 32];
+        assert_eq!(
+            serialised,
+            postcard::to_slice(
+                &CommandRequest::<Setting> {
+                    last_event_offset: Some(9),
+                    status_requested: false,
+                    filter: None,
+                    client_time: None,
+                    max_reply_len: None,
+                    absolute_ticks: false,
+                    command: Some(Setting::Volume(3)),
+                },
+                &mut command_buf
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            postcard::from_bytes::<Settings>(serialised).unwrap(),
+            request
+        );
+
+        // Further commands follow the first.
+        request.commands.push(Setting::Brightness(7)).unwrap();
+        request.commands.push(Setting::Volume(4)).unwrap();
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(serialised, [1, 9, 1, 3, 0, 7, 1, 4]);
+        assert_eq!(
+            postcard::from_bytes::<Settings>(serialised).unwrap(),
+            request
+        );
+        assert!(postcard::from_bytes::<Settings>(&[]).is_err());
+    }
+
+    #[test]
+    fn test_multi_command_overflow() {
+        // A request of more commands than the capacity decoding it is
+        // refused rather than those beyond it being dropped.
+        let mut request = MultiCommandRequest::<Setting, 3>::new(Some(9));
+        request.commands.push(Setting::Volume(3)).unwrap();
+        request.commands.push(Setting::Brightness(7)).unwrap();
+        request.commands.push(Setting::Volume(4)).unwrap();
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<MultiCommandRequest<Setting, 2>>(serialised),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+        assert_eq!(
+            postcard::from_bytes::<MultiCommandRequest<Setting, 3>>(serialised).unwrap(),
+            request
+        );
+
+        // As is a request of a command for a capacity of none.
+        assert_eq!(
+            postcard::from_bytes::<MultiCommandRequest<Setting, 0>>(&[1, 9, 1, 3]),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+    }
+
+    #[test]
+    fn test_multi_command_empty() {
+        // A request of no commands is a poll.
+        let request = Settings::new(None);
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(serialised, [0]);
+        let poll = postcard::from_bytes::<CommandRequest<Setting>>(serialised).unwrap();
+        assert_eq!(
+            poll,
+            CommandRequest {
+                last_event_offset: None,
+                status_requested: false,
+                filter: None,
+                client_time: None,
+                max_reply_len: None,
+                absolute_ticks: false,
+                command: None,
+            }
+        );
+        assert_eq!(
+            postcard::from_bytes::<Settings>(serialised).unwrap(),
+            request
+        );
+
+        let outcomes = request.execute(|_| Err::<(), ()>(()));
+        assert_eq!(
+            outcomes,
+            CommandOutcomes {
+                executed: 0,
+                failure: None
+            }
+        );
+        assert_eq!(outcomes.outcome(0), CommandOutcome::NotExecuted);
+    }
+
+    #[test]
+    fn test_multi_command_partial_failure() {
+        let mut request = Settings::new(Some(1));
+        for command in [
+            Setting::Volume(3),
+            Setting::Brightness(200),
+            Setting::Volume(4),
+        ] {
+            request.commands.push(command).unwrap();
+        }
+
+        // Execution stops at the first command that fails.
+        let mut executed = std::vec::Vec::new();
+        let outcomes = request.execute(|command| match command {
+            Setting::Brightness(b) if *b > 100 => Err("too bright"),
+            command => {
+                executed.push(command.clone());
+                Ok(())
+            }
+        });
+        assert_eq!(executed, [Setting::Volume(3)]);
+        assert_eq!(
+            outcomes,
+            CommandOutcomes {
+                executed: 1,
+                failure: Some("too bright")
+            }
+        );
+        assert_eq!(outcomes.outcome(0), CommandOutcome::Executed);
+        assert_eq!(outcomes.outcome(1), CommandOutcome::Failed(&"too bright"));
+        assert_eq!(outcomes.outcome(2), CommandOutcome::NotExecuted);
+
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&outcomes, &mut buf).unwrap();
+        assert_eq!(
+            serialised,
+            [1, 1, 10, b't', b'o', b'o', b' ', b'b', b'r', b'i', b'g', b'h', b't']
+        );
+
+        let outcomes = request.execute(|_| Ok::<(), ()>(()));
+        assert_eq!(outcomes.executed, 3);
+        assert_eq!(outcomes.outcome(2), CommandOutcome::Executed);
+        assert_eq!(outcomes.outcome(3), CommandOutcome::NotExecuted);
+    }
+
+    #[test]
+    fn test_wide_offset_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Message {
+            A,
+            B,
+        }
+
+        fn serialised<T: Serialize>(value: &T) -> std::vec::Vec<u8> {
+            let mut buf = [0; 32];
+            postcard::to_slice(value, &mut buf).unwrap().to_vec()
+        }
+
+        // Offsets within the range of a u32 are encoded identically by u64s.
+        for offset in [None, Some(0), Some(9), Some(u32::MAX)] {
+            let request = CommandRequest::<Message, u32> {
+                last_event_offset: offset,
+                status_requested: false,
+                filter: None,
+                client_time: None,
+                max_reply_len: None,
+                absolute_ticks: false,
+                command: Some(Message::B),
+            };
+            let wide_request = CommandRequest::<Message, u64> {
+                last_event_offset: offset.map(u64::from),
+                status_requested: false,
+                filter: None,
+                client_time: None,
+                max_reply_len: None,
+                absolute_ticks: false,
+                command: Some(Message::B),
+            };
+            assert_eq!(serialised(&request), serialised(&wide_request));
+            assert_eq!(
+                postcard::from_bytes::<CommandRequest<Message, u64>>(&serialised(&request))
+                    .unwrap(),
+                wide_request
+            );
+            assert_eq!(
+                postcard::from_bytes::<CommandRequest<Message, u32>>(&serialised(&wide_request))
+                    .unwrap(),
+                request
+            );
+
+            let mut request = MultiCommandRequest::<Message, 2>::new(offset);
+            let mut wide_request =
+                MultiCommandRequest::<Message, 2, u64>::new(offset.map(u64::from));
+            request.commands.push(Message::A).unwrap();
+            wide_request.commands.push(Message::A).unwrap();
+            assert_eq!(serialised(&request), serialised(&wide_request));
+        }
+        for (event, wide_event) in [
+            (
+                EventOf::<_, NoEE>::Logged(Message::B, 9),
+                EventOf::<_, NoEE, u64>::Logged(Message::B, 9),
+            ),
+            (
+                EventOf::Logged(Message::A, u32::MAX),
+                EventOf::Logged(Message::A, u32::MAX as u64),
+            ),
+            (EventOf::Recovery(3, 7), EventOf::Recovery(3, 7)),
+        ] {
+            let reply = event_reply(Some((event, 0)), TickRate::SECONDS, |_| {
+                Duration::from_secs(10)
+            });
+            let wide_reply = event_reply(Some((wide_event, 0)), TickRate::SECONDS, |_| {
+                Duration::from_secs(10)
+            });
+            assert_eq!(serialised(&reply), serialised(&wide_reply));
+            assert_eq!(
+                postcard::from_bytes::<EventReply<EventOf<Message, NoEE, u64>>>(&serialised(
+                    &reply
+                ))
+                .unwrap(),
+                wide_reply
+            );
+        }
+
+        // Offsets beyond the range of a u32 are not mistaken for one.
+        let wide_reply = event_reply(
+            Some((
+                EventOf::<_, NoEE, u64>::Logged(Message::B, u32::MAX as u64 + 1),
+                0,
+            )),
+            TickRate::SECONDS,
+            |_| Duration::from_secs(10),
+        );
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Message, NoEE>>>(&serialised(&wide_reply)),
+            Err(postcard::Error::DeserializeBadVarint)
+        );
+    }
+
+    #[test]
+    fn test_recovery_v1_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Event {
+            A,
+        }
+
+        // EventOf as known to clients unaware of recovery metadata.
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum OldEventOf {
+            Logged(Event, u32),
+            Ephemeral(NoEE),
+            Recovery(u32, u32),
+        }
+
+        let reply = EventReply {
+            delta_ticks: 0,
+            event: Some(EventOf::<Event, NoEE>::RecoveryV1 {
+                start: 3,
+                end: 7,
+                dropped: 200,
+                snapshot_available: true,
+            }),
+        };
+
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
+        assert_eq!(serialised, [0, 3, 3, 7, 200, 1, 1]);
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Event, NoEE>>>(serialised).unwrap(),
+            reply
+        );
+
+        // Clients unaware of recovery metadata fail to decode the event.
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct OldEventReply {
+            delta_ticks: u64,
+            #[serde(deserialize_with = "deserialise_last_field")]
+            event: Option<OldEventOf>,
+        }
+        assert_eq!(
+            postcard::from_bytes::<OldEventReply>(serialised),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+    }
+
+    #[test]
+    fn test_status_request_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Command {
+            A,
+            B,
+        }
+
+        // Asking for the status is conveyed along with the offset.
+        for (last_event_offset, command, expected) in [
+            (None, None, &[2][..]),
+            (Some(9), None, &[3, 9]),
+            (Some(9), Some(Command::B), &[3, 9, 1]),
+        ] {
+            let request = CommandRequest::<Command> {
+                last_event_offset,
+                status_requested: true,
+                filter: None,
+                client_time: None,
+                max_reply_len: None,
+                absolute_ticks: false,
+                command,
+            };
+            let mut buf = [0; 32];
+            let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+            assert_eq!(serialised, expected);
+            assert_eq!(
+                postcard::from_bytes::<CommandRequest<Command>>(serialised).unwrap(),
+                request
+            );
+        }
+
+        let mut request = MultiCommandRequest::<Command, 2>::new(Some(9));
+        request.status_requested = true;
+        request.commands.push(Command::A).unwrap();
+        request.commands.push(Command::B).unwrap();
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(serialised, [3, 9, 0, 1]);
+        assert_eq!(
+            postcard::from_bytes::<MultiCommandRequest<Command, 2>>(serialised).unwrap(),
+            request
+        );
+
+        // Flags that are unknown are refused.
+        assert!(postcard::from_bytes::<CommandRequest<Command>>(&[4]).is_err());
+        assert!(postcard::from_bytes::<MultiCommandRequest<Command, 2>>(&[5, 9]).is_err());
+    }
+
+    #[test]
+    fn test_status_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Event {
+            A,
+        }
+
+        // EventOf as known to clients unaware of status.
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum OldEventOf {
+            Logged(Event, u32),
+            Ephemeral(NoEE),
+            Recovery(u32, u32),
+        }
+
+        fn status_reply(uptime_ticks: u64) -> EventReply<EventOf<Event, NoEE>> {
+            event_reply(
+                Some((
+                    EventOf::Status(ServerStatus {
+                        uptime_ticks,
+                        log_occupancy: 100,
+                        last_reset_cause: ResetCause::Watchdog,
+                        tick_rate: TickRate::MILLISECONDS,
+                    }),
+                    0,
+                )),
+                TickRate::MILLISECONDS,
+                |_| Duration::ZERO,
+            )
+        }
+
+        let reply = status_reply(300);
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
+        assert_eq!(serialised, [0, 4, 172, 2, 100, 3, 232, 7]);
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Event, NoEE>>>(serialised).unwrap(),
+            reply
+        );
+
+        // A reply of the status fits within 12 bytes given a century of
+        // uptime in milliseconds.
+        let century_of_millis = 100 * 366 * 24 * 60 * 60 * 1000;
+        assert!(
+            postcard::to_slice(&status_reply(century_of_millis), &mut buf)
+                .unwrap()
+                .len()
+                <= 12
+        );
+        assert_eq!(
+            postcard::to_slice(&status_reply(u64::MAX), &mut buf)
+                .unwrap()
+                .len(),
+            16
+        );
+
+        // Clients unaware of status fail to decode the event.
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct OldEventReply {
+            delta_ticks: u64,
+            #[serde(deserialize_with = "deserialise_last_field")]
+            event: Option<OldEventOf>,
+        }
+        let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<OldEventReply>(serialised),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+    }
+
+    #[test]
+    fn test_filter_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Command {
+            A,
+            B,
+        }
+
+        // A filter follows the offset.
+        for (last_event_offset, status_requested, command, expected) in [
+            (None, false, None, &[4, 0b10][..]),
+            (Some(9), false, None, &[5, 9, 0b10]),
+            (Some(9), true, Some(Command::B), &[7, 9, 0b10, 1]),
+        ] {
+            let request = CommandRequest::<Command> {
+                last_event_offset,
+                status_requested,
+                filter: Some(0b10),
+                client_time: None,
+                max_reply_len: None,
+                absolute_ticks: false,
+                command,
+            };
+            let mut buf = [0; 32];
+            let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+            assert_eq!(serialised, expected);
+            assert_eq!(
+                postcard::from_bytes::<CommandRequest<Command>>(serialised).unwrap(),
+                request
+            );
+            assert_eq!(
+                postcard::from_bytes::<MultiCommandRequest<Command, 2>>(serialised)
+                    .unwrap()
+                    .filter,
+                Some(0b10)
+            );
+        }
+        assert!(postcard::from_bytes::<CommandRequest<Command>>(&[5, 9]).is_err());
+
+        // Skipping is conveyed by the offset skipped to.
+        let reply = EventReply {
+            delta_ticks: 0,
+            event: Some(EventOf::<Command, NoEE>::Filtered(13)),
+        };
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&reply, &mut buf).unwrap();
+        assert_eq!(serialised, [0, 5, 13]);
+        assert_eq!(
+            postcard::from_bytes::<EventReply<EventOf<Command, NoEE>>>(serialised).unwrap(),
+            reply
+        );
+    }
+
+    #[test]
+    fn test_client_time_serialisation() {
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Command {
+            A,
+            B,
+        }
+
+        // The client's time follows the filter.
+        for (filter, command, expected) in [
+            (None, None, &[9, 3, 172, 2][..]),
+            (Some(0b10), Some(Command::B), &[13, 3, 0b10, 172, 2, 1]),
+        ] {
+            let request = CommandRequest::<Command> {
+                last_event_offset: Some(3),
+                status_requested: false,
+                filter,
+                client_time: Some(300),
+                max_reply_len: None,
+                absolute_ticks: false,
+                command,
+            };
+            let mut buf = [0; 32];
+            let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+            assert_eq!(serialised, expected);
+            assert_eq!(
+                postcard::from_bytes::<CommandRequest<Command>>(serialised).unwrap(),
+                request
+            );
+            assert_eq!(
+                postcard::from_bytes::<MultiCommandRequest<Command, 2>>(serialised)
+                    .unwrap()
+                    .client_time,
+                Some(300)
+            );
+        }
+        assert!(postcard::from_bytes::<CommandRequest<Command>>(&[8]).is_err());
+    }
+
+    #[test]
+    fn test_max_reply_len_serialisation() {
+        // The length of replies asked for follows the client's time.
+        for (client_time, expected) in [
+            (None, &[17, 3, 64, 1][..]),
+            (Some(300), &[25, 3, 172, 2, 64, 1]),
+        ] {
+            let request = CommandRequest::<u8> {
+                last_event_offset: Some(3),
+                status_requested: false,
+                filter: None,
+                client_time,
+                max_reply_len: Some(64),
+                absolute_ticks: false,
+                command: Some(1),
+            };
+            let mut buf = [0; 32];
+            let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+            assert_eq!(serialised, expected);
+            assert_eq!(
+                postcard::from_bytes::<CommandRequest<u8>>(serialised).unwrap(),
+                request
+            );
+            assert_eq!(
+                postcard::from_bytes::<MultiCommandRequest<u8, 2>>(serialised)
+                    .unwrap()
+                    .max_reply_len,
+                Some(64)
+            );
+        }
+        assert!(postcard::from_bytes::<CommandRequest<u8>>(&[16]).is_err());
+    }
+
+    #[test]
+    fn test_absolute_ticks_serialisation() {
+        // Asking for absolute ticks is a flag alone.
+        let mut request = MultiCommandRequest::<u8, 2>::new(Some(3));
+        request.absolute_ticks = true;
+        let mut buf = [0; 32];
+        let serialised = postcard::to_slice(&request, &mut buf).unwrap();
+        assert_eq!(serialised, [33, 3]);
+        assert_eq!(
+            postcard::from_bytes::<MultiCommandRequest<u8, 2>>(serialised).unwrap(),
+            request
+        );
+        assert!(
+            postcard::from_bytes::<CommandRequest<u8>>(serialised)
+                .unwrap()
+                .absolute_ticks
+        );
+
+        // The events of the absolute form convey their ticks, and the server's
+        // ticks follow them along with the number pending.
+        let batch: BatchedEvents =
+            absolute_event_batch_reply(logged(&[9, 10, 11, 12, 13]), 20, |_| true);
+        assert_eq!(batch.server_ticks, Some(20));
+        assert_eq!(batch.pending, 1);
+        assert_eq!(batch.event_ticks(&batch.replies[1]), Some(10));
+        assert_eq!(batch.age_ticks(&batch.replies[1]), 10);
+        let serialised = postcard::to_slice(&batch, &mut buf).unwrap();
+        assert_eq!(
+            serialised,
+            [9, 0, 1, 9, 10, 0, 1, 10, 11, 0, 1, 11, 12, 0, 1, 12, 129, 42]
+        );
+        assert_eq!(
+            postcard::from_bytes::<BatchedEvents>(serialised).unwrap(),
+            batch
+        );
+        assert_eq!(
+            absolute_event_reply(Some((EventOf::<_, NoEE>::Logged(BatchedEvent::B, 9), 9))),
+            batch.replies[0]
+        );
+
+        // The forms are told apart whether events are pending or not, and
+        // even when no events are replied.
+        for (events, server_ticks) in [
+            (&[9][..], None),
+            (&[9], Some(20)),
+            (&[], None),
+            (&[], Some(0)),
+            (&[], Some(u64::MAX)),
+        ] {
+            let batch: BatchedEvents = match server_ticks {
+                Some(ticks) => absolute_event_batch_reply(logged(events), ticks, |_| true),
+                None => event_batch_reply(logged(events), |t| 20 - t, |_| true),
+            };
+            let serialised = postcard::to_slice(&batch, &mut buf).unwrap();
+            let decoded = postcard::from_bytes::<BatchedEvents>(serialised).unwrap();
+            assert_eq!(
+                decoded.server_ticks,
+                server_ticks.map(|t| t.min(BatchedEvents::MAX_SERVER_TICKS))
+            );
+            assert_eq!(decoded.replies, batch.replies);
+            assert_eq!(decoded.pending, 0);
+            assert_eq!(
+                decoded.replies.first().map(|r| decoded.age_ticks(r)),
+                events.first().map(|_| 11)
+            );
+        }
+    }
+
+    #[test]
+    fn test_classified() {
+        struct Event(u8);
+
+        impl Classified for Event {
+            fn class(&self) -> u8 {
+                self.0
+            }
+        }
+
+        assert!(Event(0).is_matched_by(0b1));
+        assert!(Event(7).is_matched_by(0b1000_0000));
+        assert!(!Event(1).is_matched_by(0b1));
+        assert!(!Event(8).is_matched_by(0xff));
+    }
+
+    #[test]
+    fn test_wrapping_offsets() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        // Offsets are compared with a model of them that does not wrap,
+        // being positions either side of where a u32 wraps.
+        const WRAP: i64 = 1 << 32;
+        const HALF: i64 = 1 << 31;
+        let offset = |position: i64| position.rem_euclid(WRAP) as u32;
+        let mut rng = StdRng::seed_from_u64(0);
+        let position = |rng: &mut StdRng| match rng.gen_range(0..3) {
+            0 => WRAP + rng.gen_range(-64..64),
+            1 => WRAP + rng.gen_range(-HALF..HALF),
+            _ => rng.gen_range(0..WRAP),
+        };
+
+        for _ in 0..10_000 {
+            let a = position(&mut rng);
+            let n = rng.gen_range(0..HALF);
+            let b = a + n * if rng.gen() { 1 } else { -1 };
+
+            assert_eq!(offset(a).successor(), offset(a + 1));
+            assert_eq!(offset(a).advanced_by(n as u64), offset(a + n));
+            assert_eq!(offset(a + n).distance_from(offset(a)), n as u64);
+            assert_eq!(offset(a).precedes(offset(b)), a < b, "{a} and {b}");
+            assert_eq!(offset(b).precedes(offset(a)), b < a, "{a} and {b}");
+
+            let (start, end) = (a.min(b), a.max(b));
+            let c = start + rng.gen_range(-HALF / 2..(end - start) + HALF / 2);
+            assert_eq!(
+                offset(c).is_within(offset(start), offset(end)),
+                (start..=end).contains(&c),
+                "{c} within {start} to {end}"
+            );
+        }
+
+        // Neither of two offsets half of the range apart is before the other.
+        assert!(!0u32.precedes(1 << 31));
+        assert!(!(1u32 << 31).precedes(0));
+        assert!(!7u32.precedes(7));
+        assert!(u64::MAX.precedes(0));
+        assert!(0u64.is_within(u64::MAX, 1));
+        assert!(!2u64.is_within(u64::MAX, 1));
+        assert!(9u32.is_within(10, 9));
+    }
+
+    #[test]
+    fn test_tick_rate_conversions() {
+        let kilohertz_32 = TickRate::from_hz(32_768).unwrap();
+        for (rate, ticks, duration) in [
+            (TickRate::SECONDS, 0, Duration::ZERO),
+            (TickRate::SECONDS, 90, Duration::from_secs(90)),
+            (TickRate::MILLISECONDS, 1, Duration::from_millis(1)),
+            (
+                TickRate::MILLISECONDS,
+                90_500,
+                Duration::from_millis(90_500),
+            ),
+            // A tick at 32.768 kHz is 30517.578125 ns.
+            (kilohertz_32, 1, Duration::from_nanos(30_518)),
+            (kilohertz_32, 32_768, Duration::from_secs(1)),
+            (kilohertz_32, 49_152, Duration::from_millis(1_500)),
+            (kilohertz_32, 32_769, Duration::from_nanos(1_000_030_518)),
+        ] {
+            assert_eq!(rate.to_duration(ticks), duration, "{rate:?} {ticks}");
+            assert_eq!(rate.from_duration(duration), ticks, "{rate:?} {ticks}");
+        }
+
+        // Converting durations back and forth yields the same ticks.
+        for rate in [TickRate::SECONDS, TickRate::MILLISECONDS, kilohertz_32] {
+            for ticks in [1, 7, 32_767, 1_000_001, u32::MAX as u64, u64::MAX] {
+                assert_eq!(rate.from_duration(rate.to_duration(ticks)), ticks);
+            }
+        }
+
+        // Partial ticks are not counted, and the count saturates.
+        assert_eq!(
+            TickRate::SECONDS.from_duration(Duration::from_millis(1_999)),
+            1
+        );
+        assert_eq!(
+            TickRate::MILLISECONDS.from_duration(Duration::from_nanos(999_999)),
+            0
+        );
+        assert_eq!(kilohertz_32.from_duration(Duration::from_nanos(30_517)), 0);
+        assert_eq!(kilohertz_32.from_duration(Duration::MAX), u64::MAX);
+        assert_eq!(TickRate::from_hz(0), None);
+        assert_eq!(kilohertz_32.hz(), 32_768);
+
+        // Replies convey the age of their event at the rate given.
+        let reply = event_reply(
+            Some((EventOf::<(), _>::Ephemeral(()), Duration::from_secs(3))),
+            kilohertz_32,
+            |t| Duration::from_secs(5) - t,
+        );
+        assert_eq!(reply.delta_ticks, 65_536);
+    }
+
+    #[test]
+    fn test_max_size() {
+        #[derive(Clone, Debug, Deserialize, MaxSize, PartialEq, Serialize)]
+        enum Command {
+            A,
+            B(u32),
+        }
+
+        #[derive(Clone, Debug, Deserialize, MaxSize, PartialEq, Serialize)]
+        enum Event {
+            A,
+            B(u16),
+        }
+
+        type Request = CommandRequest<Command, u64>;
+        let request = Request {
+            last_event_offset: Some(u64::MAX),
+            status_requested: true,
+            filter: Some(0xff),
+            client_time: Some(u64::MAX),
+            max_reply_len: Some(u8::MAX),
+            absolute_ticks: false,
+            command: Some(Command::B(u32::MAX)),
+        };
+        let serialised = postcard::to_vec::<_, { Request::POSTCARD_MAX_SIZE }>(&request).unwrap();
+        assert_eq!(serialised.len(), Request::POSTCARD_MAX_SIZE);
+
+        type MultiRequest = MultiCommandRequest<Command, 2, u64>;
+        let mut request = MultiRequest::new(Some(u64::MAX));
+        request.status_requested = true;
+        request.filter = Some(0xff);
+        request.client_time = Some(u64::MAX);
+        request.max_reply_len = Some(u8::MAX);
+        request.commands.push(Command::B(u32::MAX)).unwrap();
+        request.commands.push(Command::B(u32::MAX)).unwrap();
+        let serialised =
+            postcard::to_vec::<_, { MultiRequest::POSTCARD_MAX_SIZE }>(&request).unwrap();
+        assert_eq!(serialised.len(), MultiRequest::POSTCARD_MAX_SIZE);
+
+        // The largest event is a recovery conveying how far behind a client is.
+        type Reply = EventReply<EventOf<Event, NoEE, u64>>;
+        let reply = Reply {
+            delta_ticks: u64::MAX,
+            event: Some(EventOf::RecoveryV1 {
+                start: u64::MAX,
+                end: u64::MAX,
+                dropped: u32::MAX,
+                snapshot_available: true,
+            }),
+        };
+        let serialised = postcard::to_vec::<_, { Reply::POSTCARD_MAX_SIZE }>(&reply).unwrap();
+        assert_eq!(serialised.len(), Reply::POSTCARD_MAX_SIZE);
+
+        type BatchReply = EventBatchReply<EventOf<Event, NoEE, u64>, 2>;
+        let batch = BatchReply {
+            replies: Vec::from_slice(&[reply.clone(), reply]).unwrap(),
+            pending: u8::MAX,
+            server_ticks: Some(u64::MAX),
+        };
+        let serialised = postcard::to_vec::<_, { BatchReply::POSTCARD_MAX_SIZE }>(&batch).unwrap();
+        assert_eq!(serialised.len(), BatchReply::POSTCARD_MAX_SIZE);
+        assert_eq!(
+            EventBatchReply::<EventOf<Event, NoEE, u64>, 0>::POSTCARD_MAX_SIZE,
+            Reply::POSTCARD_MAX_SIZE
+        );
+    }
+
+    #[test]
+    fn test_borrowed_serialisation() {
+        #[derive(Debug, Deserialize, PartialEq, Serialize)]
+        enum Command<'a> {
+            SetLabel(&'a str),
+        }
+
+        #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+        enum Event<'a> {
+            Blob(&'a [u8]),
+        }
+
+        let mut buf = [0; 32];
+
+        let request = CommandRequest {
+            last_event_offset: Some(1),
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: Some(Command::SetLabel("pump")),
+        };
+        let len = postcard::to_slice(&request, &mut buf).unwrap().len();
+        assert_eq!(&buf[..len], [1, 1, 0, 4, b'p', b'u', b'm', b'p']);
+        let decoded: CommandRequest<Command> = postcard::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(decoded, request);
+        let Some(Command::SetLabel(label)) = decoded.command else {
+            panic!("no label");
+        };
+        assert!(buf.as_ptr_range().contains(&label.as_ptr()));
+
+        let mut buf = [0; 32];
+        let mut request = MultiCommandRequest::<_, 2>::new(None);
+        request.commands.push(Command::SetLabel("a")).unwrap();
+        request.commands.push(Command::SetLabel("b")).unwrap();
+        let len = postcard::to_slice(&request, &mut buf).unwrap().len();
+        let decoded: MultiCommandRequest<Command, 2> = postcard::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(decoded, request);
+
+        let mut buf = [0; 32];
+        let reply = EventReply {
+            delta_ticks: 2,
+            event: Some(EventOf::<_, NoEE>::Logged(Event::Blob(&[7, 8, 9]), 3)),
+        };
+        let len = postcard::to_slice(&reply, &mut buf).unwrap().len();
+        assert_eq!(&buf[..len], [2, 0, 0, 3, 7, 8, 9, 3]);
+        let decoded: EventReply<EventOf<Event, NoEE>> = postcard::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(decoded, reply);
+        let Some(EventOf::Logged(Event::Blob(blob), _)) = decoded.event else {
+            panic!("no blob");
+        };
+        assert!(buf.as_ptr_range().contains(&blob.as_ptr()));
+
+        let mut buf = [0; 32];
+        let batch = EventBatchReply::<_, 2> {
+            replies: Vec::from_slice(&[reply.clone(), reply]).unwrap(),
+            pending: 0,
+            server_ticks: None,
+        };
+        let len = postcard::to_slice(&batch, &mut buf).unwrap().len();
+        let decoded: EventBatchReply<EventOf<Event, NoEE>, 2> =
+            postcard::from_bytes(&buf[..len]).unwrap();
+        assert_eq!(decoded, batch);
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        // The input ending is told apart by postcard's description of it,
+        // which must remain that of its error.
+        let end = postcard::Error::DeserializeUnexpectedEnd;
+        assert!(is_end_of_input(&end));
+        assert!(is_end_of_input(&end.to_string()));
+        assert!(is_end_of_input(
+            &postcard::from_bytes::<u32>(&[]).unwrap_err()
+        ));
+        assert!(!is_end_of_input(&postcard::Error::DeserializeBadVarint));
+        assert!(!is_end_of_input(&format_args!("{end}!")));
+        assert!(!is_end_of_input(&""));
+
+        assert_eq!(last_field::<u8, _>(Err(end)), Ok(None));
+        assert_eq!(
+            last_field::<u8, _>(Err(postcard::Error::DeserializeBadVarint)),
+            Err(postcard::Error::DeserializeBadVarint)
+        );
+    }
 }
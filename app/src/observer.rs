@@ -0,0 +1,246 @@
+//! Observing the protocol as it runs e.g. to trace the exchanges of a host
+//! gateway, or to log them on a device. The engines and the data link layer
+//! helpers tell a [ProtocolObserver] of the frames that they transmit and
+//! receive, of the problems that they encounter and of the states that the
+//! exchanges with their peers reach. They are observed by a [NoObserver]
+//! unless given otherwise, whose calls compile away.
+//!
+//! A [TracingObserver] traces with the `tracing` crate, given the `tracing`
+//! feature, and a [DefmtObserver] logs with `defmt`, given the `defmt`
+//! feature.
+
+#[cfg(feature = "data")]
+use flip_flop_data::FromDatagramError;
+
+use crate::client::Liveness;
+
+/// A problem encountered by the protocol, as told to
+/// [ProtocolObserver::on_error].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ObservedError {
+    /// A frame received could not be decoded.
+    CannotDecode,
+    /// A frame could not be encoded e.g. it is too long.
+    CannotEncode,
+    /// A reply was received that was not awaited e.g. one that arrived
+    /// after its request timed out.
+    Unexpected,
+    /// A request asked for what the server does not do, see
+    /// [crate::server::IgnoreReason::Unsupported].
+    Unsupported,
+    /// Even the least reply to a request exceeds the length asked for, see
+    /// [crate::server::IgnoreReason::ExceedsMaxReplyLen].
+    ExceedsMaxReplyLen,
+    /// A datagram could not be decoded by the data link layer. Requires the
+    /// `data` feature.
+    #[cfg(feature = "data")]
+    CannotDecodeDatagram(FromDatagramError),
+}
+
+/// A state reached by an exchange with a peer, as told to
+/// [ProtocolObserver::on_state].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum ObservedState {
+    /// A reply was awaited no longer.
+    TimedOut,
+    /// A server's liveness changed, see [crate::client::Liveness].
+    Liveness(Liveness),
+    /// The client received the shutdown announced by the server, see
+    /// [crate::server::ServerEngine::is_shutdown_acknowledged].
+    ShutdownAcknowledged,
+}
+
+/// Told of what the protocol does with the peers at addresses of type `A`.
+/// Servers have the one client as their peer, and so the
+/// [crate::server::ServerEngine] tells of it with an address of `()`,
+/// whereas the data link layer helpers tell of each datagram's [Header].
+/// Each call does nothing unless implemented.
+///
+/// [Header]: flip_flop_data::Header
+pub trait ProtocolObserver<A> {
+    /// A frame of `len` bytes is to be transmitted to the address given.
+    #[inline(always)]
+    fn on_tx(&mut self, _address: &A, _len: usize) {}
+
+    /// A frame of `len` bytes was received from the address given.
+    #[inline(always)]
+    fn on_rx(&mut self, _address: &A, _len: usize) {}
+
+    /// A problem was encountered with a frame of the address given, if
+    /// known.
+    #[inline(always)]
+    fn on_error(&mut self, _address: Option<&A>, _error: &ObservedError) {}
+
+    /// The exchange with the address given reached a state.
+    #[inline(always)]
+    fn on_state(&mut self, _address: &A, _state: ObservedState) {}
+}
+
+impl<A, T: ProtocolObserver<A>> ProtocolObserver<A> for &mut T {
+    #[inline(always)]
+    fn on_tx(&mut self, address: &A, len: usize) {
+        (**self).on_tx(address, len)
+    }
+
+    #[inline(always)]
+    fn on_rx(&mut self, address: &A, len: usize) {
+        (**self).on_rx(address, len)
+    }
+
+    #[inline(always)]
+    fn on_error(&mut self, address: Option<&A>, error: &ObservedError) {
+        (**self).on_error(address, error)
+    }
+
+    #[inline(always)]
+    fn on_state(&mut self, address: &A, state: ObservedState) {
+        (**self).on_state(address, state)
+    }
+}
+
+/// Observes nothing, taking no space and its calls compiling away.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NoObserver;
+
+impl<A> ProtocolObserver<A> for NoObserver {}
+
+/// Traces the protocol with the `tracing` crate, with a span for each
+/// exchange: from a request transmitted to the reply received or it timing
+/// out, or from a request received to the reply transmitted. Frames are
+/// traced at the trace level, states at the debug level and problems at the
+/// warn level. Requires the `tracing` feature.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Default)]
+pub struct TracingObserver {
+    // The exchange underway, and whether it began with a transmission.
+    exchange: Option<(tracing::Span, bool)>,
+}
+
+#[cfg(feature = "tracing")]
+impl TracingObserver {
+    /// An observer yet to trace an exchange.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The span of the exchange that a frame is of, being the one underway
+    // should the frame be in the other direction, in which case the exchange
+    // ends, or otherwise a new one.
+    fn exchange_of(&mut self, address: &impl core::fmt::Debug, tx: bool) -> tracing::Span {
+        match self.exchange.take() {
+            Some((span, began_tx)) if began_tx != tx => span,
+            _ => {
+                let span = tracing::debug_span!("exchange", ?address);
+                self.exchange = Some((span.clone(), tx));
+                span
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl<A: core::fmt::Debug> ProtocolObserver<A> for TracingObserver {
+    fn on_tx(&mut self, address: &A, len: usize) {
+        let span = self.exchange_of(address, true);
+        tracing::trace!(parent: &span, len, "tx");
+    }
+
+    fn on_rx(&mut self, address: &A, len: usize) {
+        let span = self.exchange_of(address, false);
+        tracing::trace!(parent: &span, len, "rx");
+    }
+
+    fn on_error(&mut self, address: Option<&A>, error: &ObservedError) {
+        let parent = self.exchange.as_ref().and_then(|(span, _)| span.id());
+        tracing::warn!(parent: parent, ?address, ?error, "error");
+    }
+
+    fn on_state(&mut self, address: &A, state: ObservedState) {
+        let parent = self.exchange.as_ref().and_then(|(span, _)| span.id());
+        tracing::debug!(parent: parent, ?address, ?state, "state");
+        if state == ObservedState::TimedOut {
+            self.exchange = None;
+        }
+    }
+}
+
+/// Logs the protocol with `defmt`. Frames are logged at the trace level,
+/// states at the debug level and problems at the warn level. Requires the
+/// `defmt` feature.
+#[cfg(feature = "defmt")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefmtObserver;
+
+#[cfg(feature = "defmt")]
+impl<A: defmt::Format> ProtocolObserver<A> for DefmtObserver {
+    fn on_tx(&mut self, address: &A, len: usize) {
+        defmt::trace!("tx {} {=usize}", address, len);
+    }
+
+    fn on_rx(&mut self, address: &A, len: usize) {
+        defmt::trace!("rx {} {=usize}", address, len);
+    }
+
+    fn on_error(&mut self, address: Option<&A>, error: &ObservedError) {
+        defmt::warn!("error {} {}", address, error);
+    }
+
+    fn on_state(&mut self, address: &A, state: ObservedState) {
+        defmt::debug!("state {} {}", address, state);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // What an observer was told, in order.
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum Observed<A> {
+        Tx(A, usize),
+        Rx(A, usize),
+        Error(Option<A>, ObservedError),
+        State(A, ObservedState),
+    }
+
+    // Records what it is told.
+    #[derive(Debug)]
+    pub(crate) struct Recorder<A>(pub(crate) Vec<Observed<A>>);
+
+    impl<A> Default for Recorder<A> {
+        fn default() -> Self {
+            Self(Vec::new())
+        }
+    }
+
+    impl<A: Clone> ProtocolObserver<A> for Recorder<A> {
+        fn on_tx(&mut self, address: &A, len: usize) {
+            self.0.push(Observed::Tx(address.clone(), len));
+        }
+
+        fn on_rx(&mut self, address: &A, len: usize) {
+            self.0.push(Observed::Rx(address.clone(), len));
+        }
+
+        fn on_error(&mut self, address: Option<&A>, error: &ObservedError) {
+            self.0
+                .push(Observed::Error(address.cloned(), error.clone()));
+        }
+
+        fn on_state(&mut self, address: &A, state: ObservedState) {
+            self.0.push(Observed::State(address.clone(), state));
+        }
+    }
+
+    #[test]
+    fn test_no_observer() {
+        // Observing nothing takes no space, its calls doing nothing.
+        assert_eq!(core::mem::size_of::<NoObserver>(), 0);
+        let mut observer = NoObserver;
+        ProtocolObserver::<u8>::on_tx(&mut observer, &1, 2);
+    }
+}
@@ -0,0 +1,272 @@
+//! Persisting the offsets of the last events that a client has received from
+//! its servers, so that a client restarting requests the events following
+//! them rather than every server's events being replayed or recovered at
+//! once.
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::Offset;
+
+/// Where a client persists the offset of the last event received from each
+/// of its servers, keyed by their addresses e.g. a file or a flash sector.
+pub trait OffsetStore<A, O = u32> {
+    /// The offset saved for the server at an address, if any.
+    fn load(&mut self, address: &A) -> Option<O>;
+
+    /// Save the offset of the last event received from the server at an
+    /// address, replacing any saved before.
+    fn save(&mut self, address: &A, offset: O);
+}
+
+/// How often the offset of each server is saved, so that a store is not
+/// written for every event received e.g. given the wear of flash. An offset
+/// is saved once `events` events have been received since it was last saved,
+/// or once `ticks` have passed since then, whichever is first. The first
+/// offset of a server is saved as soon as it is received.
+///
+/// A client restarting requests the events following the offset saved, and
+/// so those received since are received again.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Debounce {
+    /// The events received for an offset to be saved.
+    pub events: u32,
+    /// The ticks passed since an offset was last saved for it to be saved
+    /// again.
+    pub ticks: u64,
+}
+
+impl Debounce {
+    /// Save the offset of every event received.
+    pub const EVERY_EVENT: Self = Self {
+        events: 1,
+        ticks: 0,
+    };
+}
+
+impl Default for Debounce {
+    fn default() -> Self {
+        Self::EVERY_EVENT
+    }
+}
+
+/// The offsets of up to `SERVERS` servers, keyed by their addresses, being an
+/// [OffsetStore] held in memory that may be serialised as a whole e.g. for a
+/// host persisting a client's offsets in a single file. Offsets of servers
+/// beyond `SERVERS` are not saved.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct OffsetTable<A, const SERVERS: usize, O = u32> {
+    offsets: Vec<(A, O), SERVERS>,
+}
+
+impl<A, const SERVERS: usize, O> Default for OffsetTable<A, SERVERS, O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A, const SERVERS: usize, O> OffsetTable<A, SERVERS, O> {
+    /// A table yet to save any offsets.
+    pub const fn new() -> Self {
+        Self {
+            offsets: Vec::new(),
+        }
+    }
+
+    /// The addresses and offsets saved, in the order first saved.
+    pub fn iter(&self) -> impl Iterator<Item = &(A, O)> {
+        self.offsets.iter()
+    }
+
+    /// The number of offsets saved.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Whether no offsets are saved.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+}
+
+impl<A: Clone + Eq, const SERVERS: usize, O: Copy> OffsetStore<A, O>
+    for OffsetTable<A, SERVERS, O>
+{
+    fn load(&mut self, address: &A) -> Option<O> {
+        self.offsets
+            .iter()
+            .find(|(a, _)| a == address)
+            .map(|(_, o)| *o)
+    }
+
+    fn save(&mut self, address: &A, offset: O) {
+        match self.offsets.iter_mut().find(|(a, _)| a == address) {
+            Some((_, o)) => *o = offset,
+            None => {
+                let _ = self.offsets.push((address.clone(), offset));
+            }
+        }
+    }
+}
+
+// What of a server's offset is yet to be saved.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Unsaved<O> {
+    saved: Option<O>,
+    events: u32,
+    saved_ticks: Option<u64>,
+}
+
+impl<O: Offset> Unsaved<O> {
+    // An offset yet to be received.
+    pub(crate) const fn new() -> Self {
+        Self {
+            saved: None,
+            events: 0,
+            saved_ticks: None,
+        }
+    }
+
+    // An offset loaded from a store, and so already saved.
+    pub(crate) fn loaded(offset: O) -> Self {
+        Self {
+            saved: Some(offset),
+            events: 0,
+            saved_ticks: None,
+        }
+    }
+
+    // Note that the offset of the last event received has changed.
+    pub(crate) fn received(&mut self) {
+        self.events = self.events.saturating_add(1);
+    }
+
+    // Save the offset of the last event received if it differs from that
+    // saved and is due to be saved as of the ticks given.
+    pub(crate) fn save<A>(
+        &mut self,
+        store: &mut impl OffsetStore<A, O>,
+        address: &A,
+        offset: Option<O>,
+        debounce: Debounce,
+        now_ticks: u64,
+    ) {
+        let Some(offset) = offset.filter(|o| self.saved != Some(*o)) else {
+            return;
+        };
+        let due = self.events >= debounce.events
+            || self
+                .saved_ticks
+                .is_none_or(|t| now_ticks.saturating_sub(t) >= debounce.ticks);
+        if due {
+            store.save(address, offset);
+            *self = Self {
+                saved: Some(offset),
+                events: 0,
+                saved_ticks: Some(now_ticks),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        offset_tracker::{MultiTracker, Observation},
+        EventOf, EventReply, NoEE,
+    };
+
+    fn logged(offset: u32) -> EventReply<EventOf<(), NoEE>> {
+        EventReply {
+            delta_ticks: 0,
+            event: Some(EventOf::Logged((), offset)),
+        }
+    }
+
+    #[test]
+    fn test_offset_table() {
+        let mut table = OffsetTable::<u8, 2>::new();
+        assert_eq!(table.load(&1), None);
+        table.save(&1, 10);
+        table.save(&2, 20);
+        table.save(&1, 11);
+        table.save(&3, 30);
+        assert_eq!(table.load(&1), Some(11));
+        assert_eq!(table.load(&3), None);
+        assert_eq!(
+            table.iter().collect::<std::vec::Vec<_>>(),
+            [&(1, 11), &(2, 20)]
+        );
+
+        // The table is persisted as a whole.
+        let bytes = postcard::to_vec::<_, 16>(&table).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<OffsetTable<u8, 2>>(&bytes).unwrap(),
+            table
+        );
+    }
+
+    #[test]
+    fn test_debounce() {
+        // Counts the offsets saved.
+        #[derive(Default)]
+        struct Counted(OffsetTable<u8, 2>, usize);
+        impl OffsetStore<u8> for Counted {
+            fn load(&mut self, address: &u8) -> Option<u32> {
+                self.0.load(address)
+            }
+            fn save(&mut self, address: &u8, offset: u32) {
+                self.1 += 1;
+                self.0.save(address, offset)
+            }
+        }
+
+        let mut store = Counted::default();
+        let mut trackers = MultiTracker::<u8, 2>::new();
+        trackers.set_debounce(Debounce {
+            events: 3,
+            ticks: 100,
+        });
+
+        // The first offset is saved at once, and then every third event.
+        for (now, offset, saved) in [
+            (0, 10, Some(10)),
+            (1, 11, Some(10)),
+            (2, 12, Some(10)),
+            (3, 13, Some(13)),
+            (4, 14, Some(13)),
+        ] {
+            trackers.observe(1, &logged(offset));
+            trackers.persist(&mut store, now);
+            assert_eq!(store.0.load(&1), saved, "at {now}");
+        }
+        assert_eq!(store.1, 2);
+
+        // Or once enough ticks have passed.
+        trackers.persist(&mut store, 102);
+        assert_eq!(store.0.load(&1), Some(13));
+        trackers.persist(&mut store, 103);
+        assert_eq!(store.0.load(&1), Some(14));
+        trackers.persist(&mut store, 500);
+        assert_eq!(store.1, 3);
+
+        // Offsets are restored stale, and not saved again until they change.
+        let mut trackers = MultiTracker::<u8, 2>::new();
+        assert_eq!(trackers.restore(1, &mut store), Some(14));
+        assert_eq!(trackers.restore(2, &mut store), None);
+        assert_eq!(trackers.request(&1), Some(14));
+        assert!(trackers.get(&1).unwrap().is_stale());
+        trackers.persist(&mut store, 0);
+        assert_eq!(store.1, 3);
+        assert_eq!(
+            trackers.observe(1, &logged(15)),
+            Some(Observation::NewEvent)
+        );
+        assert!(trackers.get(&1).unwrap().is_synchronised());
+        trackers.persist(&mut store, 1);
+        assert_eq!(store.0.load(&1), Some(15));
+        assert_eq!(trackers.offsets(), store.0);
+    }
+}
@@ -0,0 +1,1111 @@
+//! A client's knowledge of the events it has received from each server,
+//! interpreting each [EventReply] as described by the offset rules.
+
+use heapless::LinearMap;
+
+use crate::{
+    offset_store::{Debounce, OffsetStore, OffsetTable, Unsaved},
+    EventOf, EventReply, Offset, TemporalEvent,
+};
+
+/// What a client has learnt from an event reply, as observed by an
+/// [OffsetTracker].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Observation<O = u32> {
+    /// The reply conveys an event that the client has not received before,
+    /// being the successor of the last one received, the first received, an
+    /// ephemeral event or the server's status.
+    NewEvent,
+    /// The reply conveys an event received before, again, e.g. a reply
+    /// received late having been awaited no longer, or a recovery already
+    /// underway. Nothing is learnt from it.
+    Duplicate,
+    /// The reply conveys an event other than the successor of the last one
+    /// received, and so the events in between are lost. Any state derived
+    /// from the events received before it should be forgotten.
+    GapDetected,
+    /// The server no longer has the successor of the last event received,
+    /// perhaps because it has restarted. Any state derived from the events
+    /// received should be forgotten and then recovered from the server's
+    /// events, the last of which has the end offset. The backlog conveys how
+    /// far behind the client is.
+    RecoveryNeeded { start: O, end: O, backlog: Backlog },
+    /// The reply conveys a snapshot of the server's state as of the event of
+    /// the offset given, see [EventOf::Snapshot]. Any state derived from the
+    /// events received before it should be replaced by the snapshot, the
+    /// events following it being received as normal.
+    SnapshotReceived { offset: O },
+    /// The reply conveys no event.
+    NothingNew,
+}
+
+/// How far behind a server a client is when recovering its state, so that an
+/// application may decide between replaying the events that the server
+/// retains and obtaining a snapshot of the server's state by other means.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Backlog {
+    /// The number of events retained by the server that are yet to be
+    /// received.
+    pub remaining: u64,
+    /// The number of events missed that the server no longer retains, if
+    /// conveyed by the server, see [EventOf::RecoveryV1].
+    pub dropped: Option<u32>,
+    /// Whether the server has a snapshot of its state available.
+    pub snapshot_available: bool,
+}
+
+impl Backlog {
+    /// The number of events that the client is behind the server by, as far
+    /// as is known.
+    pub fn total(&self) -> u64 {
+        self.remaining + self.dropped.unwrap_or_default() as u64
+    }
+}
+
+/// Tracks the offset of the last event that a client has received from a
+/// server, and whether the client is recovering its state from the server's
+/// events.
+///
+/// A client filtering the events of a server by their class sets the filter
+/// of its tracker, see [OffsetTracker::set_filter]. The server skips over the
+/// events of other classes, and so an event other than the successor of the
+/// last one received is then not a gap.
+///
+/// Events at or before the last one received, and a recovery repeated, are
+/// observed as duplicates rather than advancing the tracker, and so each
+/// event is observed as new once only barring the server's events being
+/// recovered.
+///
+/// Following [Observation::RecoveryNeeded], a client may choose the offset
+/// from which the server's events are replayed rather than replaying them
+/// all, see [OffsetTracker::begin_recovery].
+///
+/// The offset saved before a client restarted may be restored, see
+/// [OffsetTracker::restore], and is then stale until the server's next reply
+/// confirms it.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OffsetTracker<O = u32> {
+    last_event_offset: Option<O>,
+    recovery_end_offset: Option<O>,
+    recovery: Option<Recovery<O>>,
+    dropped: Option<u32>,
+    snapshot_available: bool,
+    filter: Option<u8>,
+    stale: bool,
+}
+
+// The last recovery observed, for as long as the events since follow on
+// from it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Recovery<O> {
+    start: O,
+    end: O,
+    // The offset that the events replayed follow, being the start unless
+    // chosen otherwise.
+    from: O,
+    // Whether the event following `from` is yet to be received.
+    resuming: bool,
+    // The last event received before the recovery, if any.
+    superseded: Option<O>,
+}
+
+impl<O: Offset> OffsetTracker<O> {
+    /// A tracker yet to receive an event.
+    pub const fn new() -> Self {
+        Self {
+            last_event_offset: None,
+            recovery_end_offset: None,
+            recovery: None,
+            dropped: None,
+            snapshot_available: false,
+            filter: None,
+            stale: false,
+        }
+    }
+
+    /// The last event offset to convey with the next request of the server.
+    pub fn request(&self) -> Option<O> {
+        self.last_event_offset
+    }
+
+    /// The filter to convey with the next request of the server, see
+    /// [CommandRequest::filter].
+    ///
+    /// [CommandRequest::filter]: crate::CommandRequest::filter
+    pub fn filter(&self) -> Option<u8> {
+        self.filter
+    }
+
+    /// Filter the events of the server by their class, or not given None.
+    pub fn set_filter(&mut self, filter: Option<u8>) {
+        self.filter = filter;
+    }
+
+    /// Whether the client's state is being recovered from the server's
+    /// events following [Observation::RecoveryNeeded].
+    pub fn is_recovering(&self) -> bool {
+        self.recovery_end_offset.is_some()
+    }
+
+    /// Choose the offset that the server's events are to be replayed from
+    /// following [Observation::RecoveryNeeded], given the start and end
+    /// offsets of the recovery, e.g. that of the last event persisted by the
+    /// client. The offset chosen is conveyed with the next request, and so
+    /// the server replays the events following it. An offset of the start's
+    /// predecessor replays every event that the server retains, and one of
+    /// the end replays none.
+    ///
+    /// The offset chosen is returned, or None if there is no recovery to
+    /// choose for or the offset is not within the predecessor of the start up
+    /// to and including the end, in which case the tracker is unchanged.
+    /// Should the server then reply an event other than the one following
+    /// the offset chosen, the recovery is needed again.
+    pub fn begin_recovery(&mut self, choose: impl FnOnce(O, O) -> O) -> Option<O> {
+        let recovery = self.recovery.as_mut()?;
+        let offset = choose(recovery.start, recovery.end);
+        if !offset
+            .successor()
+            .is_within(recovery.start, recovery.end.successor())
+        {
+            return None;
+        }
+        recovery.from = offset;
+        recovery.resuming = offset != recovery.end;
+        self.last_event_offset = Some(offset);
+        self.recovery_end_offset = recovery.resuming.then_some(recovery.end);
+        Some(offset)
+    }
+
+    /// How far behind the server the client is while recovering its state.
+    pub fn backlog(&self) -> Option<Backlog> {
+        let end = self.recovery_end_offset?;
+        Some(Backlog {
+            remaining: self
+                .last_event_offset
+                .map_or(0, |last| end.distance_from(last)),
+            dropped: self.dropped,
+            snapshot_available: self.snapshot_available,
+        })
+    }
+
+    /// Whether the client has received an event from the server and is not
+    /// recovering its state, and so may consider its state to reflect that of
+    /// the server.
+    pub fn is_synchronised(&self) -> bool {
+        self.last_event_offset.is_some() && !self.is_recovering() && !self.stale
+    }
+
+    /// Whether the offset was restored and is yet to be confirmed by the
+    /// server, see [OffsetTracker::restore].
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Restore the offset of the last event received, as saved before the
+    /// client restarted e.g. by an [OffsetStore], so that the events
+    /// following it are requested rather than recovering those of the
+    /// server. The offset is stale until the server's next reply of an event,
+    /// or of none, confirms it. Should the server reply other than its
+    /// successor, or a recovery, the events since are lost or to be
+    /// recovered as usual, and no event is taken as a duplicate given that
+    /// the client has forgotten which it has received. The filter is
+    /// retained.
+    ///
+    /// [OffsetStore]: crate::offset_store::OffsetStore
+    pub fn restore(&mut self, offset: O) {
+        *self = Self {
+            last_event_offset: Some(offset),
+            filter: self.filter,
+            stale: true,
+            ..Self::new()
+        };
+    }
+
+    /// Forget the events received e.g. when the client has restarted. The
+    /// filter is retained.
+    pub fn forget(&mut self) {
+        *self = Self {
+            filter: self.filter,
+            ..Self::new()
+        };
+    }
+
+    /// Observe a reply from the server, noting the offset of the event it
+    /// conveys. The events of a batch should be observed in turn.
+    pub fn observe<E, EE, S>(&mut self, reply: &EventReply<EventOf<E, EE, O, S>>) -> Observation<O>
+    where
+        EventOf<E, EE, O, S>: TemporalEvent,
+    {
+        match reply.event {
+            Some(EventOf::Logged(_, offset)) => {
+                let stale = core::mem::take(&mut self.stale);
+                let observation = match (self.last_event_offset, self.resuming()) {
+                    (Some(last), _)
+                        if !stale
+                            && (offset == last
+                                || offset.precedes(last)
+                                || self.is_superseded(last, offset)) =>
+                    {
+                        return Observation::Duplicate
+                    }
+                    (Some(last), Some(Recovery { start, end, .. }))
+                        if offset != last.successor() =>
+                    {
+                        // The server has not replayed the events following
+                        // the offset chosen, and so they are to be recovered
+                        // again.
+                        self.recovery = None;
+                        return self.recover(start, end, self.dropped, self.snapshot_available);
+                    }
+                    (Some(last), _)
+                        if offset != last.successor()
+                            && (self.filter.is_none() || (stale && !last.precedes(offset))) =>
+                    {
+                        self.recovery_end_offset = None;
+                        self.recovery = None;
+                        Observation::GapDetected
+                    }
+                    _ => Observation::NewEvent,
+                };
+                if let Some(recovery) = self.recovery.as_mut() {
+                    recovery.resuming = false;
+                }
+                self.advance(offset);
+                observation
+            }
+            Some(EventOf::Filtered(offset)) => {
+                self.stale = false;
+                if self.last_event_offset != Some(offset) {
+                    self.advance(offset);
+                }
+                Observation::NothingNew
+            }
+            Some(EventOf::Recovery(start, end)) => self.recover(start, end, None, false),
+            Some(EventOf::RecoveryV1 {
+                start,
+                end,
+                dropped,
+                snapshot_available,
+            }) => self.recover(start, end, Some(dropped), snapshot_available),
+            Some(EventOf::Snapshot(_, offset)) => {
+                // The snapshot stands in for the events up to its offset, and
+                // so any recovery is complete, unless those events have been
+                // received already.
+                let stale = core::mem::take(&mut self.stale);
+                if let (Some(last), false) = (self.last_event_offset, self.is_recovering() || stale)
+                {
+                    if offset == last || offset.precedes(last) {
+                        return Observation::Duplicate;
+                    }
+                }
+                self.last_event_offset = Some(offset);
+                self.recovery_end_offset = None;
+                self.recovery = None;
+                Observation::SnapshotReceived { offset }
+            }
+            Some(EventOf::Ephemeral(_)) | Some(EventOf::Status(_)) => Observation::NewEvent,
+            None => {
+                self.stale = false;
+                Observation::NothingNew
+            }
+        }
+    }
+
+    // Note the offset of the event received, completing recovery once the
+    // end offset has been reached or skipped over.
+    fn advance(&mut self, offset: O) {
+        if let (Some(last), Some(end)) = (self.last_event_offset, self.recovery_end_offset) {
+            if end.is_within(last, offset) {
+                self.recovery_end_offset = None;
+            }
+        }
+        self.last_event_offset = Some(offset);
+    }
+
+    // The recovery whose events following the offset chosen to recover from
+    // are yet to be replayed. Events are skipped over given a filter, and so any of
+    // those following the offset chosen may be replayed first.
+    fn resuming(&self) -> Option<Recovery<O>> {
+        self.recovery
+            .filter(|r| r.resuming && self.filter.is_none())
+    }
+
+    // Whether an event other than the successor of the last one received is
+    // one received before a recovery, replied late, being after the end of
+    // the recovery and no later than the last event received before it.
+    // Events are skipped over given a filter, and so this is not known.
+    fn is_superseded(&self, last: O, offset: O) -> bool {
+        let Some(Recovery {
+            from,
+            end,
+            superseded: Some(superseded),
+            ..
+        }) = self.recovery
+        else {
+            return false;
+        };
+        self.filter.is_none()
+            && offset != last.successor()
+            && last.is_within(from, end)
+            && end.precedes(superseded)
+            && offset.is_within(end.successor(), superseded)
+    }
+
+    fn recover(
+        &mut self,
+        start: O,
+        end: O,
+        dropped: Option<u32>,
+        snapshot_available: bool,
+    ) -> Observation<O> {
+        // A recovery received again would otherwise see the events received
+        // since it received again too.
+        if self
+            .recovery
+            .is_some_and(|r| r.start == start && r.end == end)
+        {
+            return Observation::Duplicate;
+        }
+
+        self.stale = false;
+
+        // The event of the start offset is the one that the server is unable
+        // to reply the successor of and so its successors are to be
+        // requested, with nothing further to recover if there are none.
+        self.recovery = Some(Recovery {
+            start,
+            end,
+            from: start,
+            resuming: false,
+            superseded: self.last_event_offset,
+        });
+        self.last_event_offset = Some(start);
+        self.recovery_end_offset = (start != end).then_some(end);
+        self.dropped = dropped;
+        self.snapshot_available = snapshot_available;
+        Observation::RecoveryNeeded {
+            start,
+            end,
+            backlog: Backlog {
+                remaining: end.distance_from(start),
+                dropped,
+                snapshot_available,
+            },
+        }
+    }
+}
+
+/// Tracks the offsets of up to `SERVERS` servers that a client polls, keyed
+/// by their addresses.
+///
+/// The offsets may be saved to an [OffsetStore] as they change, see
+/// [MultiTracker::persist], and restored from it once the client restarts,
+/// see [MultiTracker::restore].
+pub struct MultiTracker<A: Eq, const SERVERS: usize, O = u32> {
+    trackers: LinearMap<A, (OffsetTracker<O>, Unsaved<O>), SERVERS>,
+    filter: Option<u8>,
+    debounce: Debounce,
+}
+
+impl<A: Eq, const SERVERS: usize, O: Offset> Default for MultiTracker<A, SERVERS, O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Eq, const SERVERS: usize, O: Offset> MultiTracker<A, SERVERS, O> {
+    /// A tracker yet to know of any servers.
+    pub fn new() -> Self {
+        Self {
+            trackers: LinearMap::new(),
+            filter: None,
+            debounce: Debounce::default(),
+        }
+    }
+
+    /// Filter the events of every server by their class, or not given None,
+    /// see [OffsetTracker::set_filter].
+    pub fn set_filter(&mut self, filter: Option<u8>) {
+        self.filter = filter;
+        for (tracker, _) in self.trackers.values_mut() {
+            tracker.set_filter(filter);
+        }
+    }
+
+    /// Change how often the offsets of servers are saved by
+    /// [MultiTracker::persist]. Every offset is saved by default.
+    pub fn set_debounce(&mut self, debounce: Debounce) {
+        self.debounce = debounce;
+    }
+
+    /// The last event offset to convey with the next request of the server
+    /// at an address. Servers yet to be observed have none.
+    pub fn request(&self, address: &A) -> Option<O> {
+        self.get(address).and_then(OffsetTracker::request)
+    }
+
+    /// The tracker for the server at an address, if observed.
+    pub fn get(&self, address: &A) -> Option<&OffsetTracker<O>> {
+        self.trackers.get(address).map(|(t, _)| t)
+    }
+
+    /// Choose the offset that the events of the server at an address are to
+    /// be replayed from, see [OffsetTracker::begin_recovery].
+    pub fn begin_recovery(&mut self, address: &A, choose: impl FnOnce(O, O) -> O) -> Option<O> {
+        self.trackers.get_mut(address)?.0.begin_recovery(choose)
+    }
+
+    /// Observe a reply from the server at an address. Nothing is observed if
+    /// `SERVERS` are already tracked and the address is not one of them.
+    pub fn observe<E, EE, S>(
+        &mut self,
+        address: A,
+        reply: &EventReply<EventOf<E, EE, O, S>>,
+    ) -> Option<Observation<O>>
+    where
+        EventOf<E, EE, O, S>: TemporalEvent,
+    {
+        let (tracker, unsaved) = self.tracker_mut(address)?;
+        let last_event_offset = tracker.request();
+        let observation = tracker.observe(reply);
+        if tracker.request() != last_event_offset {
+            unsaved.received();
+        }
+        Some(observation)
+    }
+
+    /// Restore the offset saved for the server at an address, tracking it
+    /// if not already tracked, see [OffsetTracker::restore]. The offset
+    /// restored is returned, or None if there is none saved or `SERVERS` are
+    /// already tracked and the address is not one of them.
+    pub fn restore(&mut self, address: A, store: &mut impl OffsetStore<A, O>) -> Option<O> {
+        let offset = store.load(&address)?;
+        let (tracker, unsaved) = self.tracker_mut(address)?;
+        tracker.restore(offset);
+        *unsaved = Unsaved::loaded(offset);
+        Some(offset)
+    }
+
+    /// Save the offsets of the servers that have changed since last saved,
+    /// and are due to be saved as of the client's ticks given, see
+    /// [Debounce].
+    pub fn persist(&mut self, store: &mut impl OffsetStore<A, O>, now_ticks: u64) {
+        for (address, (tracker, unsaved)) in self.trackers.iter_mut() {
+            unsaved.save(store, address, tracker.request(), self.debounce, now_ticks);
+        }
+    }
+
+    /// The offsets of the servers tracked, to be persisted as a whole.
+    pub fn offsets(&self) -> OffsetTable<A, SERVERS, O>
+    where
+        A: Clone,
+    {
+        let mut table = OffsetTable::new();
+        for (address, (tracker, _)) in self.trackers.iter() {
+            if let Some(offset) = tracker.request() {
+                table.save(address, offset);
+            }
+        }
+        table
+    }
+
+    /// Forget the events received from the server at an address, no longer
+    /// tracking it.
+    pub fn forget(&mut self, address: &A) {
+        self.trackers.remove(address);
+    }
+
+    /// The number of servers tracked.
+    pub fn len(&self) -> usize {
+        self.trackers.len()
+    }
+
+    /// Whether no servers are tracked.
+    pub fn is_empty(&self) -> bool {
+        self.trackers.is_empty()
+    }
+
+    // The tracker of the server at an address, tracking it if there is room.
+    fn tracker_mut(&mut self, address: A) -> Option<&mut (OffsetTracker<O>, Unsaved<O>)> {
+        if !self.trackers.contains_key(&address) {
+            let mut tracker = OffsetTracker::new();
+            tracker.set_filter(self.filter);
+            self.trackers
+                .insert(address, (tracker, Unsaved::new()))
+                .ok()?;
+            return self.trackers.values_mut().last();
+        }
+        self.trackers.get_mut(&address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{NoEE, ResetCause, ServerStatus, TickRate};
+
+    fn reply(event: Option<EventOf<(), NoEE>>) -> EventReply<EventOf<(), NoEE>> {
+        EventReply {
+            delta_ticks: 0,
+            event,
+        }
+    }
+
+    fn logged(offset: u32) -> EventReply<EventOf<(), NoEE>> {
+        reply(Some(EventOf::Logged((), offset)))
+    }
+
+    fn recovery(start: u32, end: u32) -> EventReply<EventOf<(), NoEE>> {
+        reply(Some(EventOf::Recovery(start, end)))
+    }
+
+    fn recovery_needed(start: u32, end: u32) -> Observation {
+        Observation::RecoveryNeeded {
+            start,
+            end,
+            backlog: Backlog {
+                remaining: end.distance_from(start),
+                dropped: None,
+                snapshot_available: false,
+            },
+        }
+    }
+
+    #[test]
+    fn test_offset_tracker() {
+        const MAX: u32 = u32::MAX;
+
+        // Replies observed in turn by a new tracker, followed by the
+        // observation, request and whether it is then synchronised.
+        for (replies, expected) in [
+            // The first event is new whatever its offset.
+            (
+                &[logged(10)][..],
+                [(Observation::NewEvent, Some(10), true)].as_slice(),
+            ),
+            // Nothing is learnt of the server without an event.
+            (&[reply(None)], &[(Observation::NothingNew, None, false)]),
+            (
+                &[reply(Some(EventOf::Ephemeral(())))],
+                &[(Observation::NewEvent, None, false)],
+            ),
+            // Status does not advance the offset.
+            (
+                &[
+                    logged(10),
+                    reply(Some(EventOf::Status(ServerStatus {
+                        uptime_ticks: 5,
+                        log_occupancy: 50,
+                        last_reset_cause: ResetCause::PowerOn,
+                        tick_rate: TickRate::SECONDS,
+                    }))),
+                    logged(11),
+                ],
+                &[
+                    (Observation::NewEvent, Some(10), true),
+                    (Observation::NewEvent, Some(10), true),
+                    (Observation::NewEvent, Some(11), true),
+                ],
+            ),
+            // Successors are new, wrapping at u32::MAX.
+            (
+                &[logged(MAX - 1), logged(MAX), logged(0)],
+                &[
+                    (Observation::NewEvent, Some(MAX - 1), true),
+                    (Observation::NewEvent, Some(MAX), true),
+                    (Observation::NewEvent, Some(0), true),
+                ],
+            ),
+            // A transport error may see an event replied again.
+            (
+                &[logged(10), logged(10), reply(None)],
+                &[
+                    (Observation::NewEvent, Some(10), true),
+                    (Observation::Duplicate, Some(10), true),
+                    (Observation::NothingNew, Some(10), true),
+                ],
+            ),
+            // As may a reply received late, conveying events before the last
+            // one received.
+            (
+                &[logged(10), logged(11), logged(12), logged(11), logged(13)],
+                &[
+                    (Observation::NewEvent, Some(10), true),
+                    (Observation::NewEvent, Some(11), true),
+                    (Observation::NewEvent, Some(12), true),
+                    (Observation::Duplicate, Some(12), true),
+                    (Observation::NewEvent, Some(13), true),
+                ],
+            ),
+            // Events skipped are a gap, continuing from the event replied.
+            (
+                &[logged(10), logged(12), logged(13)],
+                &[
+                    (Observation::NewEvent, Some(10), true),
+                    (Observation::GapDetected, Some(12), true),
+                    (Observation::NewEvent, Some(13), true),
+                ],
+            ),
+            (
+                &[logged(MAX), logged(1)],
+                &[
+                    (Observation::NewEvent, Some(MAX), true),
+                    (Observation::GapDetected, Some(1), true),
+                ],
+            ),
+            // Recovery polls for the successors of the start offset up to and
+            // including the end offset.
+            (
+                &[logged(10), recovery(3, 5), logged(4), logged(5), logged(6)],
+                &[
+                    (Observation::NewEvent, Some(10), true),
+                    (recovery_needed(3, 5), Some(3), false),
+                    (Observation::NewEvent, Some(4), false),
+                    (Observation::NewEvent, Some(5), true),
+                    (Observation::NewEvent, Some(6), true),
+                ],
+            ),
+            // A recovery received again does not see the events recovered
+            // since received again, nor does an event from before it.
+            (
+                &[
+                    logged(10),
+                    recovery(3, 5),
+                    logged(4),
+                    recovery(3, 5),
+                    logged(10),
+                    logged(5),
+                    logged(6),
+                ],
+                &[
+                    (Observation::NewEvent, Some(10), true),
+                    (recovery_needed(3, 5), Some(3), false),
+                    (Observation::NewEvent, Some(4), false),
+                    (Observation::Duplicate, Some(4), false),
+                    (Observation::Duplicate, Some(4), false),
+                    (Observation::NewEvent, Some(5), true),
+                    (Observation::NewEvent, Some(6), true),
+                ],
+            ),
+            (
+                &[recovery(MAX, 0), logged(0)],
+                &[
+                    (recovery_needed(MAX, 0), Some(MAX), false),
+                    (Observation::NewEvent, Some(0), true),
+                ],
+            ),
+            // A server with a single event has nothing further to recover
+            // e.g. having just restarted.
+            (
+                &[logged(10), recovery(3, 3), logged(4)],
+                &[
+                    (Observation::NewEvent, Some(10), true),
+                    (recovery_needed(3, 3), Some(3), true),
+                    (Observation::NewEvent, Some(4), true),
+                ],
+            ),
+            // A server restarting again during recovery requires recovery
+            // from its new events.
+            (
+                &[recovery(3, 5), recovery(7, 9), logged(8), logged(9)],
+                &[
+                    (recovery_needed(3, 5), Some(3), false),
+                    (recovery_needed(7, 9), Some(7), false),
+                    (Observation::NewEvent, Some(8), false),
+                    (Observation::NewEvent, Some(9), true),
+                ],
+            ),
+            // A gap during recovery abandons it.
+            (
+                &[recovery(3, 5), logged(5)],
+                &[
+                    (recovery_needed(3, 5), Some(3), false),
+                    (Observation::GapDetected, Some(5), true),
+                ],
+            ),
+        ] {
+            let mut tracker = OffsetTracker::new();
+            assert!(!tracker.is_synchronised());
+            for (reply, (observation, request, synchronised)) in replies.iter().zip(expected) {
+                assert_eq!(tracker.observe(reply), *observation, "{replies:?}");
+                assert_eq!(tracker.request(), *request, "{replies:?}");
+                assert_eq!(tracker.is_synchronised(), *synchronised, "{replies:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_offset_tracker_forget() {
+        let mut tracker = OffsetTracker::new();
+        tracker.observe(&recovery(3, 5));
+        assert!(tracker.is_recovering());
+        tracker.forget();
+        assert_eq!(tracker, OffsetTracker::new());
+        assert_eq!(tracker.observe(&logged(7)), Observation::NewEvent);
+    }
+
+    #[test]
+    fn test_offset_tracker_restore() {
+        // An offset restored is stale, but requested.
+        let restored = |offset| {
+            let mut tracker = OffsetTracker::new();
+            tracker.restore(offset);
+            tracker
+        };
+        let mut tracker = restored(10);
+        assert!(tracker.is_stale());
+        assert!(!tracker.is_synchronised());
+        assert_eq!(tracker.request(), Some(10));
+
+        // It is confirmed by the server replying its successor, or nothing.
+        assert_eq!(tracker.observe(&reply(None)), Observation::NothingNew);
+        assert!(tracker.is_synchronised());
+        let mut tracker = restored(10);
+        assert_eq!(
+            tracker.observe(&reply(Some(EventOf::Ephemeral(())))),
+            Observation::NewEvent
+        );
+        assert!(tracker.is_stale());
+        assert_eq!(tracker.observe(&logged(11)), Observation::NewEvent);
+        assert!(tracker.is_synchronised());
+
+        // Or otherwise recovered.
+        let mut tracker = restored(10);
+        assert_eq!(tracker.observe(&recovery(50, 52)), recovery_needed(50, 52));
+        assert!(!tracker.is_stale());
+
+        // An event before the offset restored is not taken as a duplicate,
+        // the server's history having diverged from the client's.
+        let mut tracker = restored(10);
+        assert_eq!(tracker.observe(&logged(5)), Observation::GapDetected);
+        assert!(tracker.is_synchronised());
+        let mut tracker = restored(10);
+        tracker.set_filter(Some(1));
+        assert_eq!(tracker.observe(&logged(10)), Observation::GapDetected);
+        tracker.restore(10);
+        assert_eq!(tracker.filter(), Some(1));
+        assert_eq!(tracker.observe(&logged(14)), Observation::NewEvent);
+    }
+
+    #[test]
+    fn test_offset_tracker_snapshot() {
+        fn snapshot(offset: u32) -> EventReply<EventOf<(), NoEE, u32, u8>> {
+            EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::Snapshot(7, offset)),
+            }
+        }
+        fn logged(offset: u32) -> EventReply<EventOf<(), NoEE, u32, u8>> {
+            EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::Logged((), offset)),
+            }
+        }
+
+        // A client that has fallen behind receives a snapshot, and then the
+        // events following it.
+        let mut tracker = OffsetTracker::new();
+        assert_eq!(tracker.observe(&logged(3)), Observation::NewEvent);
+        assert_eq!(
+            tracker.observe(&snapshot(15)),
+            Observation::SnapshotReceived { offset: 15 }
+        );
+        assert_eq!(tracker.request(), Some(15));
+        assert!(tracker.is_synchronised());
+        assert_eq!(tracker.observe(&logged(16)), Observation::NewEvent);
+        assert_eq!(tracker.observe(&logged(16)), Observation::Duplicate);
+
+        // A snapshot stands in for the events being recovered.
+        tracker.observe(&EventReply {
+            delta_ticks: 0,
+            event: Some(EventOf::<(), NoEE, u32, u8>::Recovery(20, 30)),
+        });
+        assert!(tracker.is_recovering());
+        assert_eq!(
+            tracker.observe(&snapshot(25)),
+            Observation::SnapshotReceived { offset: 25 }
+        );
+        assert!(!tracker.is_recovering());
+        assert_eq!(tracker.request(), Some(25));
+    }
+
+    #[test]
+    fn test_multi_tracker() {
+        let mut trackers = MultiTracker::<u8, 2>::new();
+        assert_eq!(trackers.request(&1), None);
+
+        assert_eq!(
+            trackers.observe(1, &logged(10)),
+            Some(Observation::NewEvent)
+        );
+        assert_eq!(
+            trackers.observe(2, &logged(20)),
+            Some(Observation::NewEvent)
+        );
+        assert_eq!(
+            trackers.observe(1, &logged(11)),
+            Some(Observation::NewEvent)
+        );
+        assert_eq!(
+            trackers.observe(2, &logged(20)),
+            Some(Observation::Duplicate)
+        );
+        assert_eq!(trackers.request(&1), Some(11));
+        assert_eq!(trackers.request(&2), Some(20));
+
+        // No more servers than there is room for are tracked.
+        assert_eq!(trackers.observe(3, &logged(30)), None);
+        assert_eq!(trackers.request(&3), None);
+        assert_eq!(trackers.len(), 2);
+
+        trackers.forget(&1);
+        assert_eq!(trackers.request(&1), None);
+        assert_eq!(
+            trackers.observe(3, &logged(30)),
+            Some(Observation::NewEvent)
+        );
+        assert_eq!(trackers.request(&3), Some(30));
+        assert!(trackers.get(&2).is_some_and(OffsetTracker::is_synchronised));
+    }
+
+    #[test]
+    fn test_offset_tracker_wide_offsets() {
+        const MAX: u64 = u64::MAX;
+        let mut tracker = OffsetTracker::<u64>::new();
+        for (offset, observation) in [
+            (u32::MAX as u64, Observation::NewEvent),
+            (u32::MAX as u64 + 1, Observation::NewEvent),
+            (1 << 63, Observation::GapDetected),
+            (MAX, Observation::GapDetected),
+            (0, Observation::NewEvent),
+        ] {
+            let reply = EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::<(), NoEE, u64>::Logged((), offset)),
+            };
+            assert_eq!(tracker.observe(&reply), observation);
+            assert_eq!(tracker.request(), Some(offset));
+        }
+    }
+
+    #[test]
+    fn test_offset_tracker_backlog() {
+        let mut tracker = OffsetTracker::new();
+        tracker.observe(&logged(10));
+        assert_eq!(tracker.backlog(), None);
+
+        let backlog = Backlog {
+            remaining: 3,
+            dropped: Some(40),
+            snapshot_available: true,
+        };
+        assert_eq!(
+            tracker.observe(&reply(Some(EventOf::RecoveryV1 {
+                start: 60,
+                end: 63,
+                dropped: 40,
+                snapshot_available: true,
+            }))),
+            Observation::RecoveryNeeded {
+                start: 60,
+                end: 63,
+                backlog
+            }
+        );
+        assert_eq!(backlog.total(), 43);
+        assert_eq!(tracker.backlog(), Some(backlog));
+
+        // The backlog diminishes as events are replayed.
+        tracker.observe(&logged(61));
+        tracker.observe(&logged(62));
+        assert_eq!(
+            tracker.backlog(),
+            Some(Backlog {
+                remaining: 1,
+                ..backlog
+            })
+        );
+        tracker.observe(&logged(63));
+        assert_eq!(tracker.backlog(), None);
+
+        // Recovery without metadata conveys nothing of what was dropped.
+        tracker.observe(&recovery(70, 72));
+        assert_eq!(
+            tracker.backlog(),
+            Some(Backlog {
+                remaining: 2,
+                dropped: None,
+                snapshot_available: false
+            })
+        );
+
+        // A server may have dropped events with none further to replay.
+        let observation = tracker.observe(&reply(Some(EventOf::RecoveryV1 {
+            start: 80,
+            end: 80,
+            dropped: 5,
+            snapshot_available: false,
+        })));
+        assert!(matches!(
+            observation,
+            Observation::RecoveryNeeded { backlog, .. } if backlog.total() == 5
+        ));
+        assert!(tracker.is_synchronised());
+    }
+
+    #[test]
+    fn test_offset_tracker_begin_recovery() {
+        // A tracker having received event 300 of a server that now retains
+        // events 90 to 200.
+        let recovering = || {
+            let mut tracker = OffsetTracker::new();
+            tracker.observe(&logged(300));
+            tracker.observe(&recovery(90, 200));
+            tracker
+        };
+
+        // There is nothing to choose for without a recovery.
+        assert_eq!(OffsetTracker::<u32>::new().begin_recovery(|s, _| s), None);
+
+        // Events are replayed from the offset chosen, that of the last event
+        // persisted by the client say.
+        let mut tracker = recovering();
+        assert_eq!(tracker.begin_recovery(|_, _| 100), Some(100));
+        assert_eq!(tracker.request(), Some(100));
+        assert_eq!(tracker.backlog().map(|b| b.remaining), Some(100));
+        assert_eq!(tracker.observe(&logged(100)), Observation::Duplicate);
+        assert_eq!(tracker.observe(&logged(101)), Observation::NewEvent);
+        assert_eq!(tracker.observe(&logged(102)), Observation::NewEvent);
+        assert!(tracker.is_recovering());
+
+        // Offsets outside of the predecessor of the start up to the end are
+        // not chosen.
+        let mut tracker = recovering();
+        assert_eq!(tracker.begin_recovery(|s, _| s - 2), None);
+        assert_eq!(tracker.begin_recovery(|_, e| e + 1), None);
+        assert_eq!(tracker.request(), Some(90));
+
+        // The predecessor of the start replays every event retained, and the
+        // end none of them.
+        assert_eq!(tracker.begin_recovery(|s, _| s - 1), Some(89));
+        assert_eq!(tracker.backlog().map(|b| b.remaining), Some(111));
+        assert_eq!(tracker.observe(&logged(90)), Observation::NewEvent);
+        let mut tracker = recovering();
+        assert_eq!(tracker.begin_recovery(|_, e| e), Some(200));
+        assert!(tracker.is_synchronised());
+        assert_eq!(tracker.observe(&logged(201)), Observation::NewEvent);
+
+        // A reply other than the event following the offset chosen sees the
+        // recovery needed again, unless received late.
+        let mut tracker = recovering();
+        tracker.begin_recovery(|s, _| s - 1);
+        assert_eq!(tracker.observe(&logged(250)), Observation::Duplicate);
+        assert_eq!(tracker.observe(&logged(150)), recovery_needed(90, 200));
+        assert_eq!(tracker.request(), Some(90));
+        assert_eq!(tracker.begin_recovery(|_, _| 120), Some(120));
+        assert_eq!(tracker.observe(&logged(121)), Observation::NewEvent);
+
+        // Once resumed, a gap is no longer a recovery.
+        assert_eq!(tracker.observe(&logged(123)), Observation::GapDetected);
+
+        // Any event following the offset chosen may be replayed first given a
+        // filter.
+        let mut tracker = recovering();
+        tracker.set_filter(Some(1));
+        tracker.begin_recovery(|_, _| 100);
+        assert_eq!(tracker.observe(&logged(150)), Observation::NewEvent);
+
+        let mut trackers = MultiTracker::<u8, 2>::new();
+        trackers.observe(1, &recovery(90, 200));
+        assert_eq!(trackers.begin_recovery(&1, |_, _| 95), Some(95));
+        assert_eq!(trackers.request(&1), Some(95));
+        assert_eq!(trackers.begin_recovery(&2, |_, _| 95), None);
+    }
+
+    #[test]
+    fn test_offset_tracker_filtered() {
+        let filtered = || reply(Some(EventOf::Filtered(15)));
+
+        // Replies observed in turn by a tracker filtering events, followed by
+        // the observation, request and whether it is then recovering.
+        for (replies, expected) in [
+            // Events skipped over by the server are not a gap.
+            (
+                &[logged(10), logged(12), filtered(), reply(None)][..],
+                [
+                    (Observation::NewEvent, Some(10), false),
+                    (Observation::NewEvent, Some(12), false),
+                    (Observation::NothingNew, Some(15), false),
+                    (Observation::NothingNew, Some(15), false),
+                ]
+                .as_slice(),
+            ),
+            (
+                &[filtered(), filtered(), logged(15)],
+                &[
+                    (Observation::NothingNew, Some(15), false),
+                    (Observation::NothingNew, Some(15), false),
+                    (Observation::Duplicate, Some(15), false),
+                ],
+            ),
+            // Recovery completes once its end offset is reached or skipped
+            // over.
+            (
+                &[recovery(11, 15), logged(13), filtered()],
+                &[
+                    (recovery_needed(11, 15), Some(11), true),
+                    (Observation::NewEvent, Some(13), true),
+                    (Observation::NothingNew, Some(15), false),
+                ],
+            ),
+            (
+                &[recovery(11, 15), logged(17)],
+                &[
+                    (recovery_needed(11, 15), Some(11), true),
+                    (Observation::NewEvent, Some(17), false),
+                ],
+            ),
+        ] {
+            let mut tracker = OffsetTracker::new();
+            tracker.set_filter(Some(1));
+            for (reply, (observation, request, recovering)) in replies.iter().zip(expected) {
+                assert_eq!(tracker.observe(reply), *observation, "{replies:?}");
+                assert_eq!(tracker.request(), *request, "{replies:?}");
+                assert_eq!(tracker.is_recovering(), *recovering, "{replies:?}");
+            }
+        }
+
+        // The filter outlives forgetting the server's events, and applies to
+        // every server of a multi tracker.
+        let mut tracker = OffsetTracker::new();
+        tracker.set_filter(Some(1));
+        tracker.observe(&logged(10));
+        tracker.forget();
+        assert_eq!(tracker.filter(), Some(1));
+        let mut trackers = MultiTracker::<u8, 2>::new();
+        trackers.observe(1, &logged(10));
+        trackers.set_filter(Some(1));
+        trackers.observe(2, &logged(20));
+        assert!([1, 2]
+            .iter()
+            .all(|a| trackers.get(a).unwrap().filter() == Some(1)));
+        assert_eq!(
+            trackers.observe(2, &logged(22)),
+            Some(Observation::NewEvent)
+        );
+    }
+
+    #[test]
+    fn test_offset_tracker_unfiltered_skip() {
+        // Trackers not filtering still advance over events skipped.
+        let mut tracker = OffsetTracker::new();
+        tracker.observe(&logged(10));
+        assert_eq!(
+            tracker.observe(&reply(Some(EventOf::Filtered(12)))),
+            Observation::NothingNew
+        );
+        assert_eq!(tracker.request(), Some(12));
+        assert_eq!(tracker.observe(&logged(14)), Observation::GapDetected);
+    }
+}
@@ -0,0 +1,610 @@
+//! Deciding which server a client polls next on a half-duplex bus, polling
+//! servers falling behind more often than those that are quiet, or each at
+//! an interval of its own.
+
+use heapless::Vec;
+
+use crate::{EventBatchReply, TemporalEvent};
+
+#[cfg(feature = "data")]
+use flip_flop_data::timing::LinkTiming;
+
+// How far a server of weight 1 advances each time that it is polled, with
+// servers of greater weight advancing in proportion.
+const STRIDE: u64 = 1 << 16;
+
+/// Decides which of up to `SERVERS` servers, keyed by their addresses, a
+/// client polls in each of its slots on the bus. A server is polled in
+/// proportion to its weight, which lies between a minimum and a maximum.
+/// A server's weight rises by the number of events pending following its
+/// reply, see [EventBatchReply::pending], and falls back to the minimum as
+/// its replies convey fewer events.
+///
+/// Every slot polls a server, and so the slots of a bus are reallocated to
+/// servers falling behind at the expense of quiet servers, rather than being
+/// added. Quiet servers are still polled in proportion to the minimum weight,
+/// and so are never starved.
+pub struct AdaptivePoller<A, const SERVERS: usize> {
+    servers: Vec<Polled<A>, SERVERS>,
+    min_weight: u8,
+    max_weight: u8,
+}
+
+struct Polled<A> {
+    address: A,
+    weight: u8,
+    // The server with the least pass is polled next.
+    pass: u64,
+}
+
+impl<A: Eq, const SERVERS: usize> AdaptivePoller<A, SERVERS> {
+    /// A poller yet to know of any servers, weighting them between the
+    /// weights given e.g. between 1 and 8 for a server falling behind to be
+    /// polled up to 8 times as often as a quiet server. The minimum is at
+    /// least 1, and the maximum at least the minimum.
+    pub fn new(min_weight: u8, max_weight: u8) -> Self {
+        let min_weight = min_weight.max(1);
+        Self {
+            servers: Vec::new(),
+            min_weight,
+            max_weight: max_weight.max(min_weight),
+        }
+    }
+
+    /// Poll the server at an address, starting at the minimum weight. The
+    /// address is returned if `SERVERS` are already polled.
+    pub fn add(&mut self, address: A) -> Result<(), A> {
+        if self.contains(&address) {
+            return Ok(());
+        }
+        // A server joins as if it had been polled all along, rather than
+        // being polled for the slots that it missed.
+        let pass = self.servers.iter().map(|s| s.pass).min().unwrap_or(0);
+        self.servers
+            .push(Polled {
+                address,
+                weight: self.min_weight,
+                pass,
+            })
+            .map_err(|polled| polled.address)
+    }
+
+    /// No longer poll the server at an address.
+    pub fn remove(&mut self, address: &A) {
+        self.servers.retain(|s| s.address != *address);
+    }
+
+    /// Whether the server at an address is polled.
+    pub fn contains(&self, address: &A) -> bool {
+        self.servers.iter().any(|s| s.address == *address)
+    }
+
+    /// The weight of the server at an address, if polled.
+    pub fn weight(&self, address: &A) -> Option<u8> {
+        self.servers
+            .iter()
+            .find(|s| s.address == *address)
+            .map(|s| s.weight)
+    }
+
+    /// The address of the server to poll in the next slot, being none only
+    /// if there are no servers to poll.
+    pub fn next_to_poll(&mut self) -> Option<&A> {
+        let server = self.servers.iter_mut().min_by_key(|s| s.pass)?;
+        server.pass += STRIDE / server.weight as u64;
+        Some(&server.address)
+    }
+
+    /// Observe a reply from the server at an address, adjusting its weight
+    /// given the events replied and pending. A server that does not reply
+    /// should be observed as replying no events.
+    pub fn observe<E: TemporalEvent, const N: usize>(
+        &mut self,
+        address: &A,
+        reply: &EventBatchReply<E, N>,
+    ) {
+        let (min_weight, max_weight) = (self.min_weight, self.max_weight);
+        let Some(server) = self.servers.iter_mut().find(|s| s.address == *address) else {
+            return;
+        };
+        server.weight = if reply.pending > 0 {
+            server.weight.saturating_add(reply.pending).min(max_weight)
+        } else if reply.replies.is_empty() {
+            (server.weight / 2).max(min_weight)
+        } else {
+            server.weight.saturating_sub(1).max(min_weight)
+        };
+    }
+
+    /// The number of servers polled.
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Whether no servers are polled.
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+}
+
+/// Problems in relation to adding a server to a [PollSchedule].
+#[derive(Debug, Eq, PartialEq)]
+pub enum ScheduleError {
+    /// `SERVERS` are already scheduled.
+    Full,
+    /// Polling the server at its interval would take more of the bus than
+    /// remains, given in parts per million of the bus.
+    Oversubscribed { remaining_ppm: u32 },
+}
+
+/// Schedules the polls of up to `SERVERS` servers, keyed by their addresses,
+/// each at an interval of its own on a bus of one exchange at a time e.g. a
+/// motion sensor every 100ms and a battery meter every 10s. Each exchange
+/// takes a slot of the bus, and so a server is only added should the polls
+/// of all of the servers fit within the slots of the bus, which is then
+/// never oversubscribed.
+///
+/// Polls are due on a grid of each server's interval from when it is first
+/// polled, and so they do not drift as a poll is delayed by another. The
+/// poll that is due the earliest takes the next slot, with the server of
+/// the greater weight going first given polls due at once, which spreads
+/// the polls of servers added together over consecutive slots.
+///
+/// A server may also be re-polled ahead of its next poll e.g. given that it
+/// replied with events pending, see [PollSchedule::observe], or a command is
+/// queued for it. Re-polls take the slots that no poll is due within, and
+/// so never delay those due, with the server of the greater weight going
+/// first.
+pub struct PollSchedule<A, const SERVERS: usize> {
+    servers: Vec<Scheduled<A>, SERVERS>,
+    slot_ticks: u64,
+    // When the bus is free of the last exchange scheduled.
+    free_ticks: u64,
+}
+
+struct Scheduled<A> {
+    address: A,
+    interval_ticks: u64,
+    weight: u8,
+    // When the next poll is due, being none until first polled.
+    due_ticks: Option<u64>,
+    repoll: bool,
+}
+
+// The parts per million of the bus taken by polling every interval given
+// within slots of the ticks given, rounded up.
+fn bus_ppm(interval_ticks: u64, slot_ticks: u64) -> u64 {
+    (slot_ticks * 1_000_000).div_ceil(interval_ticks.max(1))
+}
+
+impl<A: Eq, const SERVERS: usize> PollSchedule<A, SERVERS> {
+    /// A schedule yet to know of any servers, polling them in slots of the
+    /// ticks given, being those taken by an exchange of a request and its
+    /// reply along with the time for the bus to turn around in between.
+    pub fn new(slot_ticks: u64) -> Self {
+        Self {
+            servers: Vec::new(),
+            slot_ticks: slot_ticks.max(1),
+            free_ticks: 0,
+        }
+    }
+
+    /// A schedule of slots taken by the exchange of a request and reply of
+    /// up to the bytes given on a link, each followed by the ticks given
+    /// for the bus to turn around, in ticks of the rate given. A link without
+    /// a bit rate is taken as occupying the wire for `u32::MAX` ticks.
+    /// Requires the `data` feature.
+    #[cfg(feature = "data")]
+    pub fn for_link(
+        link: &LinkTiming,
+        request_len: usize,
+        reply_len: usize,
+        turnaround_ticks: u32,
+        ticks_per_second: u32,
+    ) -> Self {
+        let request_ticks = link
+            .time_on_wire(request_len, ticks_per_second)
+            .unwrap_or(u32::MAX);
+        let reply_ticks = link
+            .time_on_wire(reply_len, ticks_per_second)
+            .unwrap_or(u32::MAX);
+        Self::new(request_ticks as u64 + reply_ticks as u64 + 2 * turnaround_ticks as u64)
+    }
+
+    /// The ticks of each slot.
+    pub fn slot_ticks(&self) -> u64 {
+        self.slot_ticks
+    }
+
+    /// Poll the server at an address every interval given, with a weight
+    /// deciding which of the servers due at once is polled first. A server
+    /// already scheduled is rescheduled at the interval and weight given.
+    /// The server is not added should the bus not fit its polls.
+    pub fn add(
+        &mut self,
+        address: A,
+        interval_ticks: u64,
+        weight: u8,
+    ) -> Result<(), ScheduleError> {
+        let others_ppm = self
+            .servers
+            .iter()
+            .filter(|s| s.address != address)
+            .map(|s| bus_ppm(s.interval_ticks, self.slot_ticks))
+            .sum::<u64>();
+        let remaining_ppm = 1_000_000_u64.saturating_sub(others_ppm);
+        if bus_ppm(interval_ticks, self.slot_ticks) > remaining_ppm {
+            return Err(ScheduleError::Oversubscribed {
+                remaining_ppm: remaining_ppm as u32,
+            });
+        }
+        if let Some(server) = self.server_mut(&address) {
+            server.interval_ticks = interval_ticks;
+            server.weight = weight;
+            return Ok(());
+        }
+        self.servers
+            .push(Scheduled {
+                address,
+                interval_ticks,
+                weight,
+                due_ticks: None,
+                repoll: false,
+            })
+            .map_err(|_| ScheduleError::Full)
+    }
+
+    /// No longer poll the server at an address.
+    pub fn remove(&mut self, address: &A) {
+        self.servers.retain(|s| s.address != *address);
+    }
+
+    /// Whether the server at an address is scheduled.
+    pub fn contains(&self, address: &A) -> bool {
+        self.servers.iter().any(|s| s.address == *address)
+    }
+
+    /// The parts per million of the bus taken by the polls scheduled,
+    /// excluding re-polls.
+    pub fn utilisation_ppm(&self) -> u32 {
+        self.servers
+            .iter()
+            .map(|s| bus_ppm(s.interval_ticks, self.slot_ticks))
+            .sum::<u64>() as u32
+    }
+
+    /// Re-poll the server at an address in the next slot that no poll is
+    /// due within, in addition to its polls.
+    pub fn repoll(&mut self, address: &A) {
+        if let Some(server) = self.server_mut(address) {
+            server.repoll = true;
+        }
+    }
+
+    /// Observe a reply from the server at an address, re-polling it should
+    /// events be pending following those replied, as per [AdaptivePoller].
+    pub fn observe<E: TemporalEvent, const N: usize>(
+        &mut self,
+        address: &A,
+        reply: &EventBatchReply<E, N>,
+    ) {
+        if reply.pending > 0 {
+            self.repoll(address);
+        }
+    }
+
+    /// The address of the server to poll next and when to poll it, as of
+    /// the ticks given, being none only if there are no servers to poll.
+    /// The poll is then scheduled, and so is to be transmitted at the time
+    /// told.
+    pub fn next(&mut self, now_ticks: u64) -> Option<(&A, u64)> {
+        let slot_ticks = self.slot_ticks;
+        let start_ticks = now_ticks.max(self.free_ticks);
+
+        // Servers yet to be polled are due as soon as possible.
+        let due = |s: &Scheduled<A>| s.due_ticks.unwrap_or(start_ticks);
+        let earliest = self
+            .servers
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, s)| (due(s), u8::MAX - s.weight))
+            .map(|(i, s)| (i, due(s)))?;
+        let repolled = self
+            .servers
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.repoll)
+            .max_by_key(|(_, s)| s.weight)
+            .map(|(i, _)| i);
+        let (i, at_ticks) = match (earliest, repolled) {
+            ((_, due_ticks), Some(i)) if due_ticks >= start_ticks + slot_ticks => (i, start_ticks),
+            ((i, due_ticks), _) => (i, due_ticks.max(start_ticks)),
+        };
+
+        let server = &mut self.servers[i];
+        if at_ticks >= due(server) {
+            // Polls missed while the schedule was not asked are skipped.
+            let due_ticks = due(server) + server.interval_ticks;
+            server.due_ticks = Some(if due_ticks > at_ticks {
+                due_ticks
+            } else {
+                at_ticks + server.interval_ticks
+            });
+        }
+        server.repoll = false;
+        self.free_ticks = at_ticks + slot_ticks;
+        Some((&server.address, at_ticks))
+    }
+
+    /// The number of servers scheduled.
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Whether no servers are scheduled.
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    fn server_mut(&mut self, address: &A) -> Option<&mut Scheduled<A>> {
+        self.servers.iter_mut().find(|s| s.address == *address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{EventOf, EventReply, NoEE};
+
+    type Batch = EventBatchReply<EventOf<(), NoEE>, 1>;
+
+    fn batch(events: u32, pending: u32) -> Batch {
+        let mut batch = Batch::default();
+        if events > 0 {
+            let _ = batch.replies.push(EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::Logged((), 0)),
+            });
+        }
+        batch.pending = pending.try_into().unwrap_or(u8::MAX);
+        batch
+    }
+
+    #[test]
+    fn test_weights() {
+        let mut poller = AdaptivePoller::<u8, 2>::new(0, 8);
+        assert_eq!(poller.next_to_poll(), None);
+        assert_eq!(poller.add(1), Ok(()));
+        assert_eq!(poller.add(1), Ok(()));
+        assert_eq!(poller.add(2), Ok(()));
+        assert_eq!(poller.add(3), Err(3));
+        assert_eq!(poller.len(), 2);
+        assert_eq!(poller.weight(&1), Some(1));
+
+        // Weights rise with the events pending, up to the maximum, and fall
+        // back to the minimum as fewer events are replied.
+        poller.observe(&1, &batch(1, 3));
+        assert_eq!(poller.weight(&1), Some(4));
+        poller.observe(&1, &batch(1, 200));
+        assert_eq!(poller.weight(&1), Some(8));
+        poller.observe(&1, &batch(1, 0));
+        assert_eq!(poller.weight(&1), Some(7));
+        poller.observe(&1, &batch(0, 0));
+        assert_eq!(poller.weight(&1), Some(3));
+        poller.observe(&1, &batch(0, 0));
+        poller.observe(&1, &batch(0, 0));
+        assert_eq!(poller.weight(&1), Some(1));
+
+        // Servers are polled in proportion to their weights.
+        poller.observe(&1, &batch(1, 2));
+        let polled = (0..30)
+            .filter(|_| poller.next_to_poll() == Some(&1))
+            .count();
+        assert_eq!(polled, 22);
+
+        poller.remove(&1);
+        assert!(!poller.contains(&1));
+        assert_eq!(poller.next_to_poll(), Some(&2));
+    }
+
+    // Polls servers for the slots given, each server replying with one of
+    // the events queued for it, if any, and the number still queued. Quiet
+    // servers queue an event every so many slots while the first server
+    // queues a burst. Returns the slots taken to drain the burst and the
+    // most slots between polls of a quiet server.
+    fn simulate<const SERVERS: usize>(
+        mut poller: AdaptivePoller<usize, SERVERS>,
+        burst: u32,
+        slots: usize,
+    ) -> (Option<usize>, usize) {
+        const QUIET_INTERVAL: usize = 50;
+        let mut queued = [0_u32; SERVERS];
+        let mut last_polled = [0_usize; SERVERS];
+        let mut most_between_polls = 0;
+        let mut drained = None;
+        for server in 0..SERVERS {
+            poller.add(server).unwrap();
+        }
+        queued[0] = burst;
+        for slot in 0..slots {
+            for (server, queued) in queued.iter_mut().enumerate().skip(1) {
+                if (slot + server) % QUIET_INTERVAL == 0 {
+                    *queued += 1;
+                }
+            }
+            // Every slot polls a server.
+            let server = *poller.next_to_poll().unwrap();
+            let events = queued[server].min(1);
+            queued[server] -= events;
+            poller.observe(&server, &batch(events, queued[server]));
+
+            if server > 0 {
+                most_between_polls = most_between_polls.max(slot - last_polled[server]);
+            }
+            last_polled[server] = slot;
+            if server == 0 && queued[0] == 0 && drained.is_none() {
+                drained = Some(slot + 1);
+            }
+        }
+        (drained, most_between_polls)
+    }
+
+    #[test]
+    fn test_bursty_server_drains_faster() {
+        const SERVERS: usize = 10;
+        const BURST: u32 = 100;
+        const SLOTS: usize = 2_000;
+
+        // A fixed weight polls the servers in turn.
+        let (fixed_drained, fixed_between_polls) =
+            simulate(AdaptivePoller::<_, SERVERS>::new(1, 1), BURST, SLOTS);
+        let (adaptive_drained, adaptive_between_polls) =
+            simulate(AdaptivePoller::<_, SERVERS>::new(1, 8), BURST, SLOTS);
+
+        assert_eq!(fixed_drained, Some(991));
+        assert_eq!(fixed_between_polls, SERVERS);
+        assert!(adaptive_drained.unwrap() < fixed_drained.unwrap() / 2);
+
+        // Quiet servers are still polled, being at least half as often as
+        // when polled in turn.
+        assert!(adaptive_between_polls <= 2 * SERVERS);
+    }
+
+    // Polls the servers of the intervals given for the ticks given, each
+    // exchange taking a slot, with the first server always replying with
+    // events pending if it is to be re-polled. Returns the polls of each
+    // server, and the most that an interval between the regular polls of a
+    // server differed from that asked for.
+    fn simulate_schedule<const SERVERS: usize>(
+        schedule: &mut PollSchedule<usize, SERVERS>,
+        intervals: &[u64],
+        repoll_first: bool,
+        ticks: u64,
+    ) -> (std::vec::Vec<usize>, u64) {
+        for (server, interval_ticks) in intervals.iter().enumerate() {
+            schedule.add(server, *interval_ticks, 1).unwrap();
+        }
+        let mut polls = std::vec![0; intervals.len()];
+        let mut last_polled = std::vec![None; intervals.len()];
+        let mut most_error = 0;
+        let mut now = 0;
+        while now < ticks {
+            let (&server, at_ticks) = schedule.next(now).unwrap();
+            assert!(at_ticks >= now);
+            polls[server] += 1;
+            if server == 0 && repoll_first {
+                schedule.observe(&server, &batch(1, 1));
+            } else {
+                if let Some(last) = last_polled[server] {
+                    let interval: u64 = at_ticks - last;
+                    most_error = most_error.max(interval.abs_diff(intervals[server]));
+                }
+                last_polled[server] = Some(at_ticks);
+            }
+            now = at_ticks + schedule.slot_ticks();
+        }
+        (polls, most_error)
+    }
+
+    #[test]
+    fn test_poll_schedule() {
+        const SLOT_TICKS: u64 = 10;
+        const TICKS: u64 = 60_000;
+
+        // One server every 50ms, 4 every 100ms, 10 every 500ms and 20 every
+        // 2s, taking 90% of the bus, are each polled at exactly their
+        // interval.
+        let mut intervals = std::vec![50, 100, 100, 100, 100];
+        intervals.extend([500; 10]);
+        intervals.extend([2_000; 20]);
+        let mut schedule = PollSchedule::<_, 35>::new(SLOT_TICKS);
+        let (polls, most_error) = simulate_schedule(&mut schedule, &intervals, false, TICKS);
+        assert_eq!(schedule.utilisation_ppm(), 900_000);
+        assert_eq!(most_error, 0);
+        for (polls, interval) in polls.iter().zip(&intervals) {
+            assert!((TICKS / interval).abs_diff(*polls as u64) <= 1);
+        }
+
+        // Re-polls take the remaining 10% of the bus, and so do not delay
+        // the others.
+        let mut schedule = PollSchedule::<_, 35>::new(SLOT_TICKS);
+        let (repolls, most_error) = simulate_schedule(&mut schedule, &intervals, true, TICKS);
+        assert_eq!(most_error, 0);
+        assert_eq!(repolls[0], 1_800);
+        assert_eq!(repolls[1..], polls[1..]);
+
+        // Intervals that are not multiples of each other are polled within a
+        // few slots of their interval.
+        let intervals = [70, 130, 130, 330, 330, 330, 770, 770, 1_000, 1_000, 3_100];
+        for repoll in [false, true] {
+            let mut schedule = PollSchedule::<_, 11>::new(SLOT_TICKS);
+            let (polls, most_error) = simulate_schedule(&mut schedule, &intervals, repoll, TICKS);
+            assert!(most_error <= 4 * SLOT_TICKS);
+            for (polls, interval) in polls.iter().zip(&intervals).skip(1) {
+                assert!((TICKS / interval).abs_diff(*polls as u64) <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_poll_schedule_capacity() {
+        let mut schedule = PollSchedule::<u8, 3>::new(10);
+        assert_eq!(schedule.next(0), None);
+
+        // Servers are only added while their polls fit the bus.
+        assert_eq!(schedule.add(1, 20, 1), Ok(()));
+        assert_eq!(
+            schedule.add(2, 15, 1),
+            Err(ScheduleError::Oversubscribed {
+                remaining_ppm: 500_000
+            })
+        );
+        assert_eq!(schedule.add(2, 40, 1), Ok(()));
+        assert_eq!(schedule.add(1, 30, 1), Ok(()));
+        assert_eq!(schedule.utilisation_ppm(), 583_334);
+        assert_eq!(schedule.add(3, 1_000, 2), Ok(()));
+        assert_eq!(schedule.add(4, 1_000, 1), Err(ScheduleError::Full));
+        assert_eq!(schedule.len(), 3);
+
+        // Servers due at once are polled in order of weight, and then each
+        // at its interval.
+        assert_eq!(schedule.next(0), Some((&3, 0)));
+        assert_eq!(schedule.next(0), Some((&1, 10)));
+        assert_eq!(schedule.next(0), Some((&2, 20)));
+        assert_eq!(schedule.next(30), Some((&1, 40)));
+        assert_eq!(schedule.next(50), Some((&2, 60)));
+        assert_eq!(schedule.next(70), Some((&1, 70)));
+
+        // Polls missed are skipped rather than caught up.
+        assert_eq!(schedule.next(200), Some((&1, 200)));
+        assert_eq!(schedule.next(210), Some((&2, 210)));
+        assert_eq!(schedule.next(220), Some((&1, 230)));
+
+        // Re-polls take the slots that no poll is due within.
+        schedule.repoll(&3);
+        assert_eq!(schedule.next(240), Some((&3, 240)));
+        schedule.repoll(&3);
+        assert_eq!(schedule.next(250), Some((&2, 250)));
+        assert_eq!(schedule.next(260), Some((&1, 260)));
+        assert_eq!(schedule.next(270), Some((&3, 270)));
+
+        schedule.remove(&1);
+        assert!(!schedule.contains(&1));
+        assert_eq!(schedule.next(280), Some((&2, 290)));
+    }
+
+    #[cfg(feature = "data")]
+    #[test]
+    fn test_poll_schedule_for_link() {
+        let link = LinkTiming {
+            bits_per_second: 115200,
+            bits_per_byte: 9,
+        };
+
+        // 32 bytes each way take 2.5ms, rounded up, and the bus turns
+        // around twice.
+        let schedule = PollSchedule::<u8, 1>::for_link(&link, 32, 32, 1, 1000);
+        assert_eq!(schedule.slot_ticks(), 8);
+    }
+}
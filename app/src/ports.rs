@@ -0,0 +1,190 @@
+//! Serving several ports, each conveying its own commands and events, e.g.
+//! a device exposing a function on each port. Requires the `derive`
+//! feature.
+//!
+//! A suite of ports is declared as an enum deriving [PortSuite], whose
+//! variants each bind a port to the types of the commands, events and
+//! ephemeral events conveyed on it. Ports are those of the data link layer's
+//! header, being no more than [Port::MAX], and each is bound once only, which is
+//! checked as the suite is compiled. The suite then dispatches the bytes of
+//! a request received on a port to a request of the commands conveyed on
+//! it, which may be handled by the port's [crate::server::ServerEngine], see
+//! [crate::server::ServerEngine::handle_request], and encodes a reply for
+//! the port it is transmitted on.
+
+pub use flip_flop_data::port::Port;
+pub use flip_flop_derive::PortSuite;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{EventBatchReply, MultiCommandRequest, TemporalEvent};
+
+/// Problems in relation to dispatching a request to its port, or encoding
+/// the reply.
+#[derive(Debug, Eq, PartialEq)]
+pub enum DispatchError {
+    /// The port is not one of the suite's.
+    UnknownPort(Port),
+    /// The request could not be decoded as one of the port's commands.
+    CannotDecode(postcard::Error),
+    /// The reply could not be encoded within the buffer given e.g. it is too
+    /// small.
+    CannotEncode(postcard::Error),
+}
+
+/// Decodes the bytes of a request of the commands conveyed on a port.
+pub fn decode_request<C: DeserializeOwned + Serialize, const COMMANDS: usize>(
+    bytes: &[u8],
+) -> Result<MultiCommandRequest<C, COMMANDS>, DispatchError> {
+    postcard::from_bytes(bytes).map_err(DispatchError::CannotDecode)
+}
+
+/// Encodes a reply of the events conveyed on a port within the buffer
+/// given, returning its bytes.
+pub fn encode_reply<'a, E: TemporalEvent, const EVENTS: usize>(
+    batch: &EventBatchReply<E, EVENTS>,
+    buf: &'a mut [u8],
+) -> Result<&'a mut [u8], DispatchError> {
+    postcard::to_slice(batch, buf).map_err(DispatchError::CannotEncode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    use crate::{
+        event_log::EventLog,
+        progress::Progress,
+        server::{Output, ServerEngine},
+        status::StatusReporter,
+        EventOf, EventReply, NoEE, ResetCause, TickRate,
+    };
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum LightsCommand {
+        On,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum LightsEvent {
+        On,
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum PumpCommand {
+        Run(u8),
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum PumpEvent {
+        Ran(u8),
+    }
+
+    #[derive(PortSuite)]
+    enum Ports {
+        #[port(1)]
+        Lights(LightsCommand, LightsEvent, NoEE),
+        #[port(7)]
+        Pump(PumpCommand, PumpEvent, Progress<u8>),
+    }
+
+    fn request<C: Serialize>(command: C) -> heapless::Vec<u8, 32> {
+        let mut request = MultiCommandRequest::<C, 2>::new(None);
+        request.commands.push(command).ok().unwrap();
+        postcard::to_vec(&request).unwrap()
+    }
+
+    fn port(port: u8) -> Port {
+        Port::new(port).unwrap()
+    }
+
+    #[test]
+    fn test_dispatch() {
+        assert_eq!(Ports::PORTS, [port(1), Port::MAX]);
+
+        // Requests are decoded as the commands of their port.
+        let bytes = request(PumpCommand::Run(3));
+        let PortsRequest::<2>::Pump(pump) = Ports::dispatch_request(port(7), &bytes).unwrap()
+        else {
+            panic!("not dispatched to the pump");
+        };
+        assert_eq!(pump.commands, [PumpCommand::Run(3)]);
+        assert_eq!(
+            Ports::dispatch_request::<2>(port(1), &request(LightsCommand::On))
+                .unwrap()
+                .port(),
+            port(1)
+        );
+        assert!(matches!(
+            Ports::dispatch_request::<2>(port(2), &bytes),
+            Err(DispatchError::UnknownPort(p)) if p == port(2)
+        ));
+        assert!(matches!(
+            Ports::dispatch_request::<2>(port(1), &bytes),
+            Err(DispatchError::CannotDecode(_))
+        ));
+
+        // Replies are encoded for their port.
+        let mut batch = EventBatchReply::default();
+        batch
+            .replies
+            .push(EventReply {
+                delta_ticks: 0,
+                event: Some(EventOf::Logged(LightsEvent::On, 4)),
+            })
+            .unwrap();
+        let reply = PortsReply::<1>::Lights(batch.clone());
+        let mut buf = [0; 32];
+        let (reply_port, bytes) = Ports::encode_reply(&reply, &mut buf).unwrap();
+        assert_eq!(reply_port, port(1));
+        assert_eq!(bytes, postcard::to_vec::<_, 32>(&batch).unwrap());
+        assert!(matches!(
+            Ports::encode_reply(&reply, &mut [0; 1]),
+            Err(DispatchError::CannotEncode(_))
+        ));
+    }
+
+    #[test]
+    fn test_dispatch_to_engines() {
+        let status = || StatusReporter::new(0, TickRate::SECONDS, ResetCause::PowerOn);
+        let mut lights = ServerEngine::<LightsCommand, LightsEvent, 4, 2, 4, 32>::new(
+            EventLog::new(0),
+            status(),
+        );
+        let mut pump = ServerEngine::<PumpCommand, PumpEvent, 4, 2, 4, 32, Progress<u8>>::new(
+            EventLog::new(0),
+            status(),
+        );
+
+        // Each port's requests are handled by its engine.
+        let mut handle = |p, bytes: &[u8]| match Ports::dispatch_request(port(p), bytes).unwrap() {
+            PortsRequest::Lights(request) => {
+                lights.handle_request(request, 0, |_, log: &mut EventLog<_, 4>| {
+                    log.push(LightsEvent::On, 0);
+                    Ok::<_, ()>(())
+                });
+                lights.log().len()
+            }
+            PortsRequest::Pump(request) => {
+                pump.handle_request(
+                    request,
+                    0,
+                    |PumpCommand::Run(n), log: &mut EventLog<_, 4>| {
+                        log.push(PumpEvent::Ran(*n), 0);
+                        Ok::<_, ()>(())
+                    },
+                );
+                pump.log().len()
+            }
+        };
+        assert_eq!(handle(1, &request(LightsCommand::On)), 1);
+        assert_eq!(handle(7, &request(PumpCommand::Run(2))), 1);
+        assert_eq!(handle(7, &request(PumpCommand::Run(3))), 2);
+        assert!(matches!(
+            lights.handle_frame(&request(LightsCommand::On), 0, |_, _| Ok::<_, ()>(())),
+            Output::Reply(_)
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+//! The progress of long-running commands, conveyed as ephemeral events so as
+//! not to occupy the event log. A server executing a command that takes a
+//! while reports its [Progress] with a [ProgressReporter], correlated with
+//! the command by an identifier of the application's choosing, and logs an
+//! event once the command completes. A client follows the command's progress
+//! through the replies delivered to it, see [Delivery::progress], until it
+//! completes or is rejected, see [Delivery::completion].
+
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ack::{AckOf, AckStatus, RejectReason},
+    client::Delivery,
+    offset_tracker::Observation,
+    EventOf, TemporalEvent,
+};
+
+/// How far a command has progressed, as a percentage, conveyed as an
+/// ephemeral event. The command is identified as per the application e.g.
+/// by an identifier conveyed with the command.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+pub struct Progress<I> {
+    /// The command progressing.
+    pub command_id: I,
+    /// How much of the command is performed, being no more than 100.
+    pub percent: u8,
+}
+
+/// Ephemeral events that may convey the progress of a command.
+pub trait ProgressOf<I> {
+    /// The progress conveyed, if any.
+    fn progress(&self) -> Option<&Progress<I>>;
+}
+
+impl<I> ProgressOf<I> for Progress<I> {
+    fn progress(&self) -> Option<&Progress<I>> {
+        Some(self)
+    }
+}
+
+/// Logged events that may convey the completion of a command.
+pub trait CompletionOf<I> {
+    /// The identifier of the command completed, if any.
+    fn completed(&self) -> Option<&I>;
+}
+
+/// The progress of a command as told by [Delivery::progress].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandProgress {
+    /// The percentage of the command performed so far.
+    InProgress(u8),
+    /// The command has completed, its event having been logged, and so no
+    /// more progress is told.
+    Completed,
+}
+
+/// A command has completed, its event having been logged, as told by
+/// [Delivery::completion].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Completed;
+
+/// A command was rejected by the server for the reason given, and so was
+/// not executed, as told by [Delivery::completion].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rejected(pub RejectReason);
+
+/// Decides when a server replies the progress of a command: to the first
+/// request following the progress being reported, in place of an event.
+/// Progress reported while awaiting a request replaces that reported before,
+/// and so a client is told the latest progress only. Progress is only
+/// replied once, whether or not the client receives it.
+pub struct ProgressReporter<EE> {
+    latest: Option<EE>,
+}
+
+impl<EE> ProgressReporter<EE> {
+    /// A reporter yet to report any progress.
+    pub const fn new() -> Self {
+        Self { latest: None }
+    }
+
+    /// Report how far the command identified has progressed, as a
+    /// percentage of no more than 100.
+    pub fn report<I>(&mut self, command_id: I, percent: u8)
+    where
+        Progress<I>: Into<EE>,
+    {
+        self.latest = Some(
+            Progress {
+                command_id,
+                percent: percent.min(100),
+            }
+            .into(),
+        );
+    }
+
+    /// The progress to reply to a request, if any has been reported since
+    /// it was last replied.
+    pub fn progress_for(&mut self) -> Option<EE> {
+        self.latest.take()
+    }
+}
+
+impl<EE> Default for ProgressReporter<EE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A, E, EE, O, S, const EVENTS: usize> Delivery<A, EventOf<E, EE, O, S>, O, EVENTS>
+where
+    EventOf<E, EE, O, S>: TemporalEvent,
+{
+    /// The progress of the command identified as conveyed by the replies
+    /// delivered, in order, ending with its completion. Replies received
+    /// again are skipped so that a command completes only once.
+    pub fn progress<'a, I>(
+        &'a self,
+        command_id: &'a I,
+    ) -> impl Iterator<Item = CommandProgress> + 'a
+    where
+        E: CompletionOf<I>,
+        EE: ProgressOf<I>,
+        I: PartialEq,
+    {
+        let mut completed = false;
+        self.replies
+            .iter()
+            .filter(|(_, observation)| !matches!(observation, Observation::Duplicate))
+            .filter_map(move |(reply, _)| match reply.event.as_ref()? {
+                EventOf::Ephemeral(event) => event
+                    .progress()
+                    .filter(|p| p.command_id == *command_id)
+                    .map(|p| CommandProgress::InProgress(p.percent)),
+                EventOf::Logged(event, _) => {
+                    (event.completed() == Some(command_id)).then_some(CommandProgress::Completed)
+                }
+                _ => None,
+            })
+            .take_while(move |progress| {
+                let more = !completed;
+                completed = *progress == CommandProgress::Completed;
+                more
+            })
+    }
+
+    /// Whether the command identified has completed or has been rejected,
+    /// as conveyed by the replies delivered, if either. Replies received
+    /// again are skipped so that a command is rejected only once.
+    pub fn completion<I>(&self, command_id: &I) -> Option<Result<Completed, Rejected>>
+    where
+        E: CompletionOf<I>,
+        EE: AckOf<I>,
+        I: PartialEq,
+    {
+        self.replies
+            .iter()
+            .filter(|(_, observation)| !matches!(observation, Observation::Duplicate))
+            .find_map(|(reply, _)| match reply.event.as_ref()? {
+                EventOf::Ephemeral(event) => event
+                    .ack()
+                    .filter(|a| a.command_id == *command_id)
+                    .map(|a| match a.status {
+                        AckStatus::Rejected(reason) => Err(Rejected(reason)),
+                    }),
+                EventOf::Logged(event, _) => {
+                    (event.completed() == Some(command_id)).then_some(Ok(Completed))
+                }
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        client::{Action, ClientConfig, ClientEngine},
+        event_log::EventLog,
+        server::{Output, ServerEngine},
+        status::StatusReporter,
+        ResetCause, TickRate,
+    };
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum Command {
+        Calibrate(u8),
+    }
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum Event {
+        Calibrated(u8),
+    }
+
+    impl CompletionOf<u8> for Event {
+        fn completed(&self) -> Option<&u8> {
+            match self {
+                Event::Calibrated(command_id) => Some(command_id),
+            }
+        }
+    }
+
+    #[test]
+    fn test_end_to_end() {
+        let mut client =
+            ClientEngine::<u8, Command, Event, 1, 1, 4, 32, Progress<u8>>::new(ClientConfig {
+                tick_rate: TickRate::MILLISECONDS,
+                reply_timeout_ticks: 10,
+                client_time: false,
+                max_reply_len: false,
+            });
+        client.add_server(1, 100).unwrap();
+        let mut server = ServerEngine::<Command, Event, 4, 1, 4, 32, Progress<u8>>::new(
+            EventLog::new(0),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        );
+        client.command(&1, Command::Calibrate(7)).unwrap();
+
+        // The command is performed in five steps, one between each poll,
+        // its progress being reported with each step and its completion
+        // logged with the last.
+        let mut calibrating = None;
+        let mut told = std::vec::Vec::new();
+        for now in (0..1_000).step_by(100) {
+            let Action::Transmit { bytes, .. } = client.next_action(now) else {
+                panic!("no request at {now}");
+            };
+            let execute = |Command::Calibrate(command_id): &Command, _: &mut EventLog<_, 4>| {
+                calibrating = Some((*command_id, 0));
+                Ok::<_, ()>(())
+            };
+            let Output::Reply(bytes) = server.handle_frame(bytes, now, execute) else {
+                panic!("no reply at {now}");
+            };
+            let delivery = client.handle_frame(&1, bytes, now).unwrap();
+            told.extend(delivery.progress(&7));
+            told.extend(delivery.progress(&8));
+
+            if let Some((command_id, step)) = calibrating.take() {
+                let step = step + 1;
+                server.progress_mut().report(command_id, step * 20);
+                if step < 5 {
+                    calibrating = Some((command_id, step));
+                } else {
+                    server.log_mut().push(Event::Calibrated(command_id), now);
+                }
+            }
+        }
+        assert_eq!(
+            told,
+            [
+                CommandProgress::InProgress(20),
+                CommandProgress::InProgress(40),
+                CommandProgress::InProgress(60),
+                CommandProgress::InProgress(80),
+                CommandProgress::InProgress(100),
+                CommandProgress::Completed,
+            ]
+        );
+    }
+}
@@ -0,0 +1,391 @@
+//! Conveying datagrams over a half-duplex RS-485 bus with a UART of
+//! `embedded-io-async` and a pin enabling the transceiver's driver. Each
+//! datagram is framed with COBS, being delimited by a zero byte, so that a
+//! receiver resynchronises with the frames of the bus following noise, and
+//! the driver is enabled only for the time that a frame occupies the wire
+//! plus a turnaround, see [Rs485Config]. Requires the `embedded-io`
+//! feature.
+
+use cobs::{DecodeResult, DecoderState};
+use embedded_io_async::{Read, Write};
+use flip_flop_data::timing::LinkTiming;
+
+use crate::{
+    transport::{Timer, Transport},
+    TickRate,
+};
+
+/// The pin enabling the driver of an RS-485 transceiver, known as DE, which
+/// is to be enabled while transmitting only. Transceivers disabling their
+/// receiver while driving, their RE pin being tied to DE, should not be
+/// configured to echo, see [Rs485Config::echo].
+pub trait DriverEnable {
+    /// Enable or disable the driver.
+    fn set_enabled(&mut self, enabled: bool);
+}
+
+/// The timing of an RS-485 bus and whether a transmission is heard back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rs485Config {
+    /// The characteristics of the link, by which the time that a frame
+    /// occupies the wire is known.
+    pub link: LinkTiming,
+    /// The rate of the ticks of the clock keeping the transport's time.
+    pub tick_rate: TickRate,
+    /// The ticks for which the driver remains enabled once a frame is on
+    /// the wire, so that its last byte is transmitted in full.
+    pub turnaround_ticks: u32,
+    /// Whether the transceiver's receiver hears its own transmission, as is
+    /// the case for a two-wire bus with the receiver always enabled. The
+    /// echo of a transmission is then discarded.
+    pub echo: bool,
+}
+
+/// Problems conveying datagrams over an RS-485 bus.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Rs485Error<E> {
+    /// The UART failed.
+    Io(E),
+    /// The UART was closed.
+    Closed,
+    /// A datagram is longer than may be sent or received. The bytes of a
+    /// frame received that is too long are discarded.
+    TooLong,
+}
+
+/// Conveys datagrams over an RS-485 bus, framing them with COBS in up to `N`
+/// bytes, being the datagram's length plus an overhead of a byte for every
+/// 254 bytes and a byte delimiting the frame. Frames received that are
+/// corrupt are discarded. A bus has no addresses of its own, and so the data
+/// link layer conveys the address of a server where several share it.
+/// Requires the `embedded-io` feature.
+pub struct Rs485Transport<U, D, K, const N: usize> {
+    uart: U,
+    de: D,
+    clock: K,
+    config: Rs485Config,
+    // The frame last transmitted, and how much of its echo is yet to be
+    // received.
+    sent: [u8; N],
+    sent_len: usize,
+    echoed: Option<usize>,
+    // Bytes read and yet to be decoded, so that receiving may be abandoned
+    // without losing them.
+    read: [u8; N],
+    read_start: usize,
+    read_end: usize,
+    // The datagram being decoded.
+    decoder: DecoderState,
+    frame: [u8; N],
+    frame_len: usize,
+    discarding: bool,
+}
+
+impl<U, D, K, const N: usize> Rs485Transport<U, D, K, N> {
+    /// A transport conveying datagrams with the UART and driver enable pin
+    /// given, keeping time with the clock given. The driver is disabled.
+    pub fn new(uart: U, mut de: D, clock: K, config: Rs485Config) -> Self
+    where
+        D: DriverEnable,
+    {
+        const { assert!(N > 1) };
+        de.set_enabled(false);
+        Self {
+            uart,
+            de,
+            clock,
+            config,
+            sent: [0; N],
+            sent_len: 0,
+            echoed: None,
+            read: [0; N],
+            read_start: 0,
+            read_end: 0,
+            decoder: DecoderState::Idle,
+            frame: [0; N],
+            frame_len: 0,
+            discarding: false,
+        }
+    }
+
+    // Receive a byte, returning the length of a datagram completed, or an
+    // error given one too long.
+    fn receive(&mut self, byte: u8) -> Option<Result<usize, ()>> {
+        if let Some(echoed) = self.echoed {
+            if self.sent[echoed] == byte {
+                self.echoed = (echoed + 1 < self.sent_len).then_some(echoed + 1);
+                return None;
+            }
+            // What is heard is not the echo, and so the transmission was
+            // not heard back or collided with another. The bytes taken for
+            // the echo are decoded after all, never completing a frame given
+            // that only its delimiter is zero.
+            self.echoed = None;
+            for i in 0..echoed {
+                self.decode(self.sent[i]);
+            }
+        }
+        self.decode(byte)
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<Result<usize, ()>> {
+        match self.decoder.feed(byte) {
+            Ok(DecodeResult::NoData) => None,
+            Ok(DecodeResult::DataContinue(byte)) => {
+                if let Some(b) = self.frame.get_mut(self.frame_len) {
+                    *b = byte;
+                    self.frame_len += 1;
+                } else {
+                    self.discarding = true;
+                }
+                None
+            }
+            Ok(DecodeResult::DataComplete) => {
+                let len = core::mem::take(&mut self.frame_len);
+                let discarding = core::mem::take(&mut self.discarding);
+                Some(if discarding { Err(()) } else { Ok(len) })
+            }
+            Err(_) => {
+                // A corrupt frame, which the decoder has given up on.
+                self.frame_len = 0;
+                self.discarding = false;
+                None
+            }
+        }
+    }
+}
+
+impl<U, D, K, const N: usize> Transport for Rs485Transport<U, D, K, N>
+where
+    U: Read + Write,
+    D: DriverEnable,
+    K: Timer,
+{
+    type Address = ();
+    type Error = Rs485Error<U::Error>;
+
+    async fn send(&mut self, _: &(), bytes: &[u8]) -> Result<(), Self::Error> {
+        self.echoed = None;
+        let len = cobs::try_encode(bytes, &mut self.sent)
+            .ok()
+            .filter(|len| *len < N)
+            .ok_or(Rs485Error::TooLong)?;
+        self.sent[len] = 0;
+        self.sent_len = len + 1;
+
+        self.de.set_enabled(true);
+        let started = self.clock.now_ticks();
+        let written = self.uart.write_all(&self.sent[..self.sent_len]).await;
+        let flushed = match written {
+            Ok(()) => self.uart.flush().await,
+            Err(e) => Err(e),
+        };
+        // The UART may return before its bytes are on the wire, and so the
+        // driver remains enabled for as long as they take to transmit. A
+        // link whose time on the wire is unknown relies on the flush alone.
+        let wire_ticks = self
+            .config
+            .link
+            .time_on_wire(self.sent_len, self.config.tick_rate.hz())
+            .unwrap_or(0);
+        let until = started + wire_ticks as u64 + self.config.turnaround_ticks as u64;
+        self.clock.sleep_until(until).await;
+        self.de.set_enabled(false);
+
+        self.echoed = self.config.echo.then_some(0);
+        flushed.map_err(Rs485Error::Io)
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, ()), Self::Error> {
+        loop {
+            while self.read_start < self.read_end {
+                let byte = self.read[self.read_start];
+                self.read_start += 1;
+                match self.receive(byte) {
+                    Some(Ok(len)) if len <= buf.len() => {
+                        buf[..len].copy_from_slice(&self.frame[..len]);
+                        return Ok((len, ()));
+                    }
+                    Some(_) => return Err(Rs485Error::TooLong),
+                    None => {}
+                }
+            }
+            let read = self
+                .uart
+                .read(&mut self.read)
+                .await
+                .map_err(Rs485Error::Io)?;
+            if read == 0 {
+                return Err(Rs485Error::Closed);
+            }
+            (self.read_start, self.read_end) = (0, read);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec};
+
+    use crate::clock::{Clock, MockClock};
+
+    // What happened on the bus, and when.
+    #[derive(Debug, PartialEq)]
+    enum Activity {
+        Enabled(bool),
+        Written(Vec<u8>),
+    }
+
+    type Activities = Rc<RefCell<Vec<(u64, Activity)>>>;
+
+    // Sleeping advances the clock to the ticks slept until.
+    struct Sleeper(Rc<MockClock>);
+
+    impl Clock for Sleeper {
+        fn now_ticks(&self) -> u64 {
+            self.0.now_ticks()
+        }
+    }
+
+    impl Timer for Sleeper {
+        async fn sleep_until(&self, ticks: u64) {
+            self.0.set(ticks);
+        }
+    }
+
+    struct Pin(Rc<MockClock>, Activities);
+
+    impl DriverEnable for Pin {
+        fn set_enabled(&mut self, enabled: bool) {
+            let now = self.0.now_ticks();
+            self.1.borrow_mut().push((now, Activity::Enabled(enabled)));
+        }
+    }
+
+    // Bytes written are recorded, and heard back given an echo, and bytes
+    // to be received are read a few at a time.
+    struct Uart {
+        clock: Rc<MockClock>,
+        activities: Activities,
+        echo: bool,
+        rx: VecDeque<u8>,
+    }
+
+    impl embedded_io_async::ErrorType for Uart {
+        type Error = core::convert::Infallible;
+    }
+
+    impl Read for Uart {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = buf.len().min(self.rx.len()).min(3);
+            for (b, byte) in buf.iter_mut().zip(self.rx.drain(..len)) {
+                *b = byte;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Uart {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let now = self.clock.now_ticks();
+            let written = Activity::Written(buf.to_vec());
+            self.activities.borrow_mut().push((now, written));
+            if self.echo {
+                self.rx.extend(buf);
+            }
+            Ok(buf.len())
+        }
+    }
+
+    // 9600 baud of 10 bits a byte, in microseconds.
+    const CONFIG: Rs485Config = Rs485Config {
+        link: LinkTiming {
+            bits_per_second: 9_600,
+            bits_per_byte: 10,
+        },
+        tick_rate: TickRate::from_hz(1_000_000).unwrap(),
+        turnaround_ticks: 500,
+        echo: true,
+    };
+
+    fn transport(echo: bool) -> (Rs485Transport<Uart, Pin, Sleeper, 16>, Activities) {
+        let clock = Rc::new(MockClock::new(1_000));
+        let activities = Activities::default();
+        let uart = Uart {
+            clock: clock.clone(),
+            activities: activities.clone(),
+            echo,
+            rx: VecDeque::new(),
+        };
+        let pin = Pin(clock.clone(), activities.clone());
+        let config = Rs485Config { echo, ..CONFIG };
+        let transport = Rs485Transport::new(uart, pin, Sleeper(clock), config);
+        (transport, activities)
+    }
+
+    #[tokio::test]
+    async fn test_driver_enable_timing() {
+        let (mut transport, activities) = transport(false);
+        transport.send(&(), &[1, 0, 2]).await.unwrap();
+
+        // The driver is enabled before the frame is written, and disabled
+        // once its 5 bytes have taken 5,209us on the wire and the bus has
+        // turned around.
+        assert_eq!(
+            activities.take(),
+            [
+                (1_000, Activity::Enabled(false)),
+                (1_000, Activity::Enabled(true)),
+                (1_000, Activity::Written(std::vec![2, 1, 2, 2, 0])),
+                (6_709, Activity::Enabled(false)),
+            ]
+        );
+        assert_eq!(
+            transport.send(&(), &[0; 15]).await,
+            Err(Rs485Error::TooLong)
+        );
+        assert!(activities.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_framing() {
+        let (mut transport, _) = transport(true);
+        transport.send(&(), &[1, 0, 2]).await.unwrap();
+
+        // The echo of the transmission is discarded, as are noise and
+        // corrupt frames, while frames are received however they are read.
+        let rx = &mut transport.uart.rx;
+        rx.extend([7, 7, 0]);
+        rx.extend([3, 5, 6, 0]);
+        rx.extend([0, 2, 9, 0]);
+        rx.extend([1, 1, 0]);
+        rx.extend([6, 1, 2, 3, 4, 5, 0]);
+        rx.extend([2, 8, 0]);
+        let mut buf = [0; 4];
+        assert_eq!(transport.recv(&mut buf).await, Ok((2, ())));
+        assert_eq!(buf[..2], [5, 6]);
+        assert_eq!(transport.recv(&mut buf).await, Ok((1, ())));
+        assert_eq!(buf[..1], [9]);
+        assert_eq!(transport.recv(&mut buf).await, Ok((1, ())));
+        assert_eq!(buf[..1], [0]);
+        assert_eq!(transport.recv(&mut buf).await, Err(Rs485Error::TooLong));
+        assert_eq!(transport.recv(&mut buf).await, Ok((1, ())));
+        assert_eq!(buf[..1], [8]);
+        assert_eq!(transport.recv(&mut buf).await, Err(Rs485Error::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_collision() {
+        let (mut transport, _) = transport(true);
+        transport.send(&(), &[1, 2]).await.unwrap();
+
+        // What is heard differs from the echo, and so is received.
+        transport.uart.rx.clear();
+        transport.uart.rx.extend([3, 1, 3, 0]);
+        let mut buf = [0; 4];
+        assert_eq!(transport.recv(&mut buf).await, Ok((2, ())));
+        assert_eq!(buf[..2], [1, 3]);
+    }
+}
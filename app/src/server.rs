@@ -0,0 +1,751 @@
+//! The behaviour of a server replying to its client, independent of how
+//! datagrams are conveyed and how time is kept. A [ServerEngine] only ever
+//! produces bytes to transmit in response to a request received, which is
+//! the core invariant of flip-flop, and so a server using it cannot transmit
+//! unsolicited.
+
+use core::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    absolute_event_batch_reply,
+    ack::{Ack, CommandFailure, RejectReason},
+    clock::ClockSync,
+    event_batch_reply,
+    event_log::EventLog,
+    observer::{NoObserver, ObservedError, ObservedState, ProtocolObserver},
+    progress::ProgressReporter,
+    shutdown::{Shutdown, ShutdownReason},
+    status::StatusReporter,
+    EventBatchReply, EventOf, MultiCommandRequest, NoEE, NoSnapshot, Offset, TemporalEvent,
+};
+
+#[cfg(feature = "data")]
+use {
+    aead::{generic_array::typenum::Unsigned, AeadInPlace},
+    flip_flop_data::{
+        from_datagram, port::Port, DataSource, FromDatagramError, Header, NonceScheme, HEADER_SIZE,
+    },
+};
+
+/// What a server is to do with a datagram received, as told by
+/// [ServerEngine::handle_frame].
+#[derive(Debug, Eq, PartialEq)]
+pub enum Output<'a> {
+    /// Transmit the bytes of the reply to the client that sent the request.
+    Reply(&'a [u8]),
+    /// Transmit nothing, for the reason given.
+    Ignore(IgnoreReason),
+}
+
+/// Why a datagram received is not replied to.
+#[derive(Debug, Eq, PartialEq)]
+pub enum IgnoreReason {
+    /// The datagram is not a request.
+    CannotDecode,
+    /// The request filters events by their class, which the server does not
+    /// do, see [crate::CommandRequest::filter]. Servers not filtering events
+    /// should not declare the filtered events capability, and so clients
+    /// should not send such requests.
+    Unsupported,
+    /// The reply could not be encoded within a datagram e.g. an event is too
+    /// large.
+    CannotEncode,
+    /// Even the least reply to the request exceeds the most bytes that the
+    /// client asks replies to take, see
+    /// [crate::CommandRequest::max_reply_len], and so the client must ask
+    /// for more.
+    ExceedsMaxReplyLen,
+    /// The datagram could not be decoded by the data link layer, see
+    /// [ServerEngine::handle_datagram].
+    #[cfg(feature = "data")]
+    CannotDecodeDatagram(FromDatagramError),
+}
+
+impl From<&IgnoreReason> for ObservedError {
+    fn from(reason: &IgnoreReason) -> Self {
+        match reason {
+            IgnoreReason::CannotDecode => ObservedError::CannotDecode,
+            IgnoreReason::Unsupported => ObservedError::Unsupported,
+            IgnoreReason::CannotEncode => ObservedError::CannotEncode,
+            IgnoreReason::ExceedsMaxReplyLen => ObservedError::ExceedsMaxReplyLen,
+            #[cfg(feature = "data")]
+            IgnoreReason::CannotDecodeDatagram(e) => ObservedError::CannotDecodeDatagram(e.clone()),
+        }
+    }
+}
+
+// What a reply is given, being distinct from the buffer that it is encoded
+// within.
+struct State<E, const LOG: usize, O, EE, S> {
+    log: EventLog<E, LOG, O, S>,
+    status: StatusReporter,
+    progress: ProgressReporter<EE>,
+    clock: Option<ClockSync>,
+    // The offset of the shutdown announced, if any, and whether the client
+    // has since conveyed it.
+    shutdown: Option<(O, bool)>,
+}
+
+/// Replies to requests of up to `COMMANDS` commands with its status or up
+/// to `EVENTS` of the events retained by its [EventLog], encoding replies
+/// within `N` bytes. Commands are executed by a function given with each
+/// request, which may log events so that they are replied along with any
+/// others. The progress of commands taking a while is replied in place of
+/// events, see [ProgressReporter].
+///
+/// The engine tells a [ProtocolObserver] of what it does, being a
+/// [NoObserver] unless given otherwise, see [ServerEngine::with_observer].
+/// The client is told of with an address of `()`, being the server's only
+/// peer.
+pub struct ServerEngine<
+    C,
+    E,
+    const LOG: usize,
+    const COMMANDS: usize,
+    const EVENTS: usize,
+    const N: usize,
+    EE = NoEE,
+    O = u32,
+    S = NoSnapshot,
+    P = NoObserver,
+> {
+    state: State<E, LOG, O, EE, S>,
+    buf: [u8; N],
+    #[cfg(feature = "data")]
+    frame_counter: u16,
+    observer: P,
+    _commands: PhantomData<C>,
+}
+
+impl<
+        C,
+        E,
+        const LOG: usize,
+        const COMMANDS: usize,
+        const EVENTS: usize,
+        const N: usize,
+        EE,
+        O,
+        S,
+    > ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE, O, S>
+where
+    C: DeserializeOwned + Serialize,
+    E: Clone,
+    O: Offset,
+    S: Clone,
+    EventOf<E, EE, O, S>: TemporalEvent,
+{
+    /// An engine replying the events of the log given, and its status as
+    /// decided by the reporter given.
+    pub fn new(log: EventLog<E, LOG, O, S>, status: StatusReporter) -> Self {
+        Self {
+            state: State {
+                log,
+                status,
+                progress: ProgressReporter::new(),
+                clock: None,
+                shutdown: None,
+            },
+            buf: [0; N],
+            #[cfg(feature = "data")]
+            frame_counter: 0,
+            observer: NoObserver,
+            _commands: PhantomData,
+        }
+    }
+}
+
+impl<
+        C,
+        E,
+        const LOG: usize,
+        const COMMANDS: usize,
+        const EVENTS: usize,
+        const N: usize,
+        EE,
+        O,
+        S,
+        P,
+    > ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE, O, S, P>
+where
+    C: DeserializeOwned + Serialize,
+    E: Clone,
+    O: Offset,
+    S: Clone,
+    EventOf<E, EE, O, S>: TemporalEvent,
+    P: ProtocolObserver<()>,
+{
+    /// The engine, telling the observer given of what it does, see
+    /// [crate::observer].
+    pub fn with_observer<Q: ProtocolObserver<()>>(
+        self,
+        observer: Q,
+    ) -> ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE, O, S, Q> {
+        ServerEngine {
+            state: self.state,
+            buf: self.buf,
+            #[cfg(feature = "data")]
+            frame_counter: self.frame_counter,
+            observer,
+            _commands: PhantomData,
+        }
+    }
+
+    /// The observer told of what the engine does.
+    pub fn observer_mut(&mut self) -> &mut P {
+        &mut self.observer
+    }
+
+    /// Record the client's time conveyed by each request with the clock
+    /// given, so that the client's time of events may be told.
+    pub fn with_clock(mut self, clock: ClockSync) -> Self {
+        self.state.clock = Some(clock);
+        self
+    }
+
+    /// The events logged, which are replied to the client.
+    pub fn log(&self) -> &EventLog<E, LOG, O, S> {
+        &self.state.log
+    }
+
+    /// The events logged, for logging more of them.
+    pub fn log_mut(&mut self) -> &mut EventLog<E, LOG, O, S> {
+        &mut self.state.log
+    }
+
+    /// The progress of commands, for reporting it.
+    pub fn progress_mut(&mut self) -> &mut ProgressReporter<EE> {
+        &mut self.state.progress
+    }
+
+    /// Announce that the server is about to shut down for no more than the
+    /// downtime given in its ticks, logging the announcement as of the ticks
+    /// given and returning its offset, see [crate::shutdown]. The server
+    /// should shut down once the client has received the announcement, see
+    /// [ServerEngine::is_shutdown_acknowledged], or once it can wait no
+    /// longer, logging no further events meanwhile.
+    pub fn announce_shutdown(
+        &mut self,
+        reason: ShutdownReason,
+        expected_downtime_ticks: u64,
+        now_ticks: u64,
+    ) -> O
+    where
+        Shutdown: Into<E>,
+    {
+        let shutdown = Shutdown {
+            reason,
+            expected_downtime_ticks,
+        };
+        let offset = self.state.log.push(shutdown.into(), now_ticks);
+        self.state.shutdown = Some((offset, false));
+        offset
+    }
+
+    /// Whether a request has conveyed the offset of the shutdown announced,
+    /// and so the client has received the announcement.
+    pub fn is_shutdown_acknowledged(&self) -> bool {
+        self.state
+            .shutdown
+            .is_some_and(|(_, acknowledged)| acknowledged)
+    }
+
+    /// The rejection of the command identified for the reason given, to be
+    /// returned by the function executing commands as the command's
+    /// failure, see [ServerEngine::handle_frame]. Ephemeral events are to
+    /// convey it.
+    pub fn reject<I>(command_id: I, reason: RejectReason) -> Ack<I>
+    where
+        Ack<I>: Into<EE>,
+    {
+        Ack::rejected(command_id, reason)
+    }
+
+    /// The clock synchronised with the client, if given.
+    pub fn clock(&self) -> Option<&ClockSync> {
+        self.state.clock.as_ref()
+    }
+
+    /// Handle the bytes of a request received as of the server's ticks
+    /// given, executing its commands in order with the function given, and
+    /// return the reply to transmit. Commands are executed as per
+    /// [MultiCommandRequest::execute], and the function may log events, see
+    /// [EventLog::push]. A command that the function refuses with an [Ack]
+    /// of its rejection, see [ServerEngine::reject], is acknowledged in
+    /// reply, those following it not being executed.
+    pub fn handle_frame<X, F>(&mut self, bytes: &[u8], now_ticks: u64, execute: X) -> Output<'_>
+    where
+        X: FnMut(&C, &mut EventLog<E, LOG, O, S>) -> Result<(), F>,
+        F: CommandFailure<EE>,
+    {
+        self.observer.on_rx(&(), bytes.len());
+        let Ok(request) = postcard::from_bytes::<MultiCommandRequest<C, COMMANDS, O>>(bytes) else {
+            return self.ignore(IgnoreReason::CannotDecode);
+        };
+        self.handle_request(request, now_ticks, execute)
+    }
+
+    /// As per [ServerEngine::handle_frame], but for a request already
+    /// decoded e.g. one dispatched to the port that it was received on by a
+    /// port suite, which requires the `derive` feature.
+    pub fn handle_request<X, F>(
+        &mut self,
+        request: MultiCommandRequest<C, COMMANDS, O>,
+        now_ticks: u64,
+        execute: X,
+    ) -> Output<'_>
+    where
+        X: FnMut(&C, &mut EventLog<E, LOG, O, S>) -> Result<(), F>,
+        F: CommandFailure<EE>,
+    {
+        let batch: EventBatchReply<EventOf<E, EE, O, S>, EVENTS> =
+            match self.reply(request, now_ticks, execute, N) {
+                Ok(batch) => batch,
+                Err(reason) => return self.ignore(reason),
+            };
+        let len = match postcard::to_slice(&batch, &mut self.buf) {
+            Ok(bytes) => bytes.len(),
+            Err(_) => return self.ignore(IgnoreReason::CannotEncode),
+        };
+        self.observer.on_tx(&(), len);
+        Output::Reply(&self.buf[..len])
+    }
+
+    /// As per [ServerEngine::handle_frame], but for a datagram of the data
+    /// link layer addressed to the server at an address and port, decrypting
+    /// the request and encrypting the reply with the cipher given. Requires
+    /// the `data` feature.
+    #[cfg(feature = "data")]
+    pub fn handle_datagram<A, X, F>(
+        &mut self,
+        cipher: &A,
+        server_address: u8,
+        server_port: Port,
+        datagram_buf: &[u8; N],
+        now_ticks: u64,
+        execute: X,
+    ) -> Output<'_>
+    where
+        A: AeadInPlace,
+        A::NonceSize: NonceScheme,
+        X: FnMut(&C, &mut EventLog<E, LOG, O, S>) -> Result<(), F>,
+        F: CommandFailure<EE>,
+    {
+        self.observer.on_rx(&(), N);
+        let is_request = |header: &Header| {
+            header.source == DataSource::Client
+                && header.server_address == server_address
+                && header.server_port == server_port
+        };
+        let (_, payload_buf) = match from_datagram(datagram_buf, is_request, cipher) {
+            Ok(received) => received,
+            Err(e) => return self.ignore(IgnoreReason::CannotDecodeDatagram(e)),
+        };
+        let Ok(request) = postcard::from_bytes::<MultiCommandRequest<C, COMMANDS, O>>(&payload_buf)
+        else {
+            return self.ignore(IgnoreReason::CannotDecode);
+        };
+        let max_len = N.saturating_sub(HEADER_SIZE + A::TagSize::USIZE);
+        let batch: EventBatchReply<EventOf<E, EE, O, S>, EVENTS> =
+            match self.reply(request, now_ticks, execute, max_len) {
+                Ok(batch) => batch,
+                Err(reason) => return self.ignore(reason),
+            };
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address,
+            server_port,
+            frame_counter: self.frame_counter,
+        };
+        if crate::datagram::send(cipher, &header, &batch, &mut self.buf, &mut NoObserver).is_err() {
+            return self.ignore(IgnoreReason::CannotEncode);
+        }
+        self.frame_counter = self.frame_counter.wrapping_add(1);
+        self.observer.on_tx(&(), N);
+        Output::Reply(&self.buf)
+    }
+
+    // Reply to a request as per State::reply, telling the observer should
+    // the request acknowledge the shutdown announced.
+    fn reply<X, F>(
+        &mut self,
+        request: MultiCommandRequest<C, COMMANDS, O>,
+        now_ticks: u64,
+        execute: X,
+        max_len: usize,
+    ) -> Result<EventBatchReply<EventOf<E, EE, O, S>, EVENTS>, IgnoreReason>
+    where
+        X: FnMut(&C, &mut EventLog<E, LOG, O, S>) -> Result<(), F>,
+        F: CommandFailure<EE>,
+    {
+        let acknowledged = self.is_shutdown_acknowledged();
+        let batch = self.state.reply(request, now_ticks, execute, max_len);
+        if !acknowledged && self.is_shutdown_acknowledged() {
+            self.observer
+                .on_state(&(), ObservedState::ShutdownAcknowledged);
+        }
+        batch
+    }
+
+    fn ignore(&mut self, reason: IgnoreReason) -> Output<'static> {
+        self.observer.on_error(Some(&()), &(&reason).into());
+        Output::Ignore(reason)
+    }
+}
+
+impl<E: Clone, const LOG: usize, O: Offset, EE, S: Clone> State<E, LOG, O, EE, S> {
+    // Execute the commands of a request and return the batch to reply to it,
+    // being no more than the bytes given once encoded, unless its first
+    // event alone is more. A batch whose first event alone is more than the
+    // client asks for is refused.
+    fn reply<C, X, F, const COMMANDS: usize, const EVENTS: usize>(
+        &mut self,
+        request: MultiCommandRequest<C, COMMANDS, O>,
+        now_ticks: u64,
+        mut execute: X,
+        max_len: usize,
+    ) -> Result<EventBatchReply<EventOf<E, EE, O, S>, EVENTS>, IgnoreReason>
+    where
+        C: Serialize,
+        EventOf<E, EE, O, S>: TemporalEvent,
+        X: FnMut(&C, &mut EventLog<E, LOG, O, S>) -> Result<(), F>,
+        F: CommandFailure<EE>,
+    {
+        if request.filter.is_some() {
+            return Err(IgnoreReason::Unsupported);
+        }
+        if let Some(clock) = &mut self.clock {
+            clock.record(request.client_time, now_ticks);
+        }
+        if let Some((offset, acknowledged)) = &mut self.shutdown {
+            *acknowledged |= request.last_event_offset == Some(*offset);
+        }
+        let outcomes = request.execute(|command| execute(command, &mut self.log));
+
+        // Reply with the rejection of a command refused, so that it is
+        // replied to the request conveying the command, or otherwise the
+        // status if it is due, or otherwise any progress reported, or
+        // otherwise the event following the last one observed by the
+        // client, along with those that follow it, within the length that
+        // the client asks for, if any.
+        let max_len = request
+            .max_reply_len
+            .map_or(max_len, |len| max_len.min(len as usize));
+        // The ticks of events are their ages unless the client asks for them
+        // as of the server's ticks.
+        let fits = |batch: &EventBatchReply<_, EVENTS>| batch.fits_within(max_len);
+        let unlogged = |event| {
+            if request.absolute_ticks {
+                absolute_event_batch_reply([(event, now_ticks)], now_ticks, fits)
+            } else {
+                event_batch_reply([(event, now_ticks)], |_| 0, fits)
+            }
+        };
+        let batch = match outcomes.failure.and_then(CommandFailure::into_ack) {
+            Some(ack) => unlogged(EventOf::Ephemeral(ack)),
+            None => match self
+                .status
+                .status_for(request.status_requested, &self.log, now_ticks)
+            {
+                Some(status) => unlogged(EventOf::Status(status)),
+                None => match self.progress.progress_for() {
+                    Some(progress) => unlogged(EventOf::Ephemeral(progress)),
+                    None if request.absolute_ticks => self.log.absolute_batch_reply_for(
+                        request.last_event_offset,
+                        now_ticks,
+                        fits,
+                    ),
+                    None => self
+                        .log
+                        .batch_reply_for(request.last_event_offset, now_ticks, fits),
+                },
+            },
+        };
+        if request.max_reply_len.is_some() && !fits(&batch) {
+            return Err(IgnoreReason::ExceedsMaxReplyLen);
+        }
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        observer::tests::{Observed, Recorder},
+        CommandRequest, EventReply, ResetCause, ServerStatus, TickRate,
+    };
+
+    type Engine = ServerEngine<u8, u8, 4, 2, 4, 32>;
+    type Batch = EventBatchReply<EventOf<u8, NoEE>, 4>;
+
+    fn engine() -> Engine {
+        Engine::new(
+            EventLog::new(10),
+            StatusReporter::new(0, TickRate::SECONDS, ResetCause::PowerOn),
+        )
+    }
+
+    fn request(last_event_offset: Option<u32>, commands: &[u8]) -> heapless::Vec<u8, 32> {
+        let mut request = MultiCommandRequest::<u8, 2>::new(last_event_offset);
+        request.commands.extend_from_slice(commands).unwrap();
+        postcard::to_vec(&request).unwrap()
+    }
+
+    fn reply(output: Output) -> Batch {
+        match output {
+            Output::Reply(bytes) => postcard::from_bytes(bytes).unwrap(),
+            Output::Ignore(reason) => panic!("ignored given {reason:?}"),
+        }
+    }
+
+    fn logged(event: u8, offset: u32, delta_ticks: u64) -> EventReply<EventOf<u8, NoEE>> {
+        EventReply {
+            delta_ticks,
+            event: Some(EventOf::Logged(event, offset)),
+        }
+    }
+
+    // Logs each command executed as an event, failing command 0.
+    fn execute(command: &u8, log: &mut EventLog<u8, 4>) -> Result<(), ()> {
+        if *command == 0 {
+            return Err(());
+        }
+        log.push(*command, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replies() {
+        let mut engine = engine();
+        engine.log_mut().push(1, 0);
+
+        // The first request following boot is replied the status.
+        let batch = reply(engine.handle_frame(&request(None, &[]), 2, execute));
+        assert_eq!(
+            batch.replies[0].event,
+            Some(EventOf::Status(ServerStatus {
+                uptime_ticks: 2,
+                log_occupancy: 25,
+                last_reset_cause: ResetCause::PowerOn,
+                tick_rate: TickRate::SECONDS,
+            }))
+        );
+
+        // Thereafter events are replied, including those logged by the
+        // commands executed, which stop at the first to fail.
+        let batch = reply(engine.handle_frame(&request(None, &[2, 3]), 6, execute));
+        assert_eq!(
+            batch.replies,
+            [logged(1, 10, 6), logged(2, 11, 1), logged(3, 12, 1)]
+        );
+        let batch = reply(engine.handle_frame(&request(Some(12), &[0, 4]), 7, execute));
+        assert!(batch.replies.is_empty());
+        assert_eq!(engine.log().len(), 3);
+
+        // Requests of a single command are requests of several.
+        let single = CommandRequest::<u8> {
+            last_event_offset: Some(12),
+            status_requested: true,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: Some(4),
+        };
+        let batch =
+            reply(engine.handle_frame(&postcard::to_vec::<_, 32>(&single).unwrap(), 8, execute));
+        assert!(matches!(batch.replies[0].event, Some(EventOf::Status(_))));
+        let batch = reply(engine.handle_frame(&request(Some(12), &[]), 9, execute));
+        assert_eq!(batch.replies, [logged(4, 13, 4)]);
+    }
+
+    #[test]
+    fn test_ignored() {
+        let mut engine =
+            engine().with_clock(ClockSync::new(TickRate::SECONDS, TickRate::MILLISECONDS));
+
+        // Nothing is transmitted other than in reply to a request.
+        assert_eq!(
+            engine.handle_frame(&[0xff, 0xff], 0, execute),
+            Output::Ignore(IgnoreReason::CannotDecode)
+        );
+        let mut filtered = MultiCommandRequest::<u8, 2>::new(None);
+        filtered.filter = Some(1);
+        filtered.client_time = Some(1_000);
+        assert_eq!(
+            engine.handle_frame(&postcard::to_vec::<_, 32>(&filtered).unwrap(), 0, execute),
+            Output::Ignore(IgnoreReason::Unsupported)
+        );
+        assert!(!engine.clock().unwrap().is_synchronised());
+
+        // The client's time is recorded.
+        filtered.filter = None;
+        reply(engine.handle_frame(&postcard::to_vec::<_, 32>(&filtered).unwrap(), 1, execute));
+        assert_eq!(engine.clock().unwrap().client_time(2), Some(2_000));
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut recorder = Recorder::default();
+        let mut engine = engine().with_observer(&mut recorder);
+        let request = request(None, &[]);
+        let Output::Reply(bytes) = engine.handle_frame(&request, 0, execute) else {
+            panic!("no reply");
+        };
+        let len = bytes.len();
+        engine.handle_frame(&[0xff, 0xff], 1, execute);
+        assert_eq!(
+            recorder.0,
+            [
+                Observed::Rx((), request.len()),
+                Observed::Tx((), len),
+                Observed::Rx((), 2),
+                Observed::Error(Some(()), ObservedError::CannotDecode),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replies_fit() {
+        let mut engine = ServerEngine::<u8, u8, 4, 2, 4, 8>::new(
+            EventLog::new(10),
+            StatusReporter::new(0, TickRate::SECONDS, ResetCause::PowerOn),
+        );
+        for _ in 0..4 {
+            engine.log_mut().push(200, 0);
+        }
+        reply(engine.handle_frame(&request(None, &[]), 0, execute));
+
+        // Each event is encoded as 4 bytes, and so only one fits along with
+        // the number pending.
+        let Output::Reply(bytes) = engine.handle_frame(&request(None, &[]), 0, execute) else {
+            panic!("ignored");
+        };
+        assert_eq!(bytes, [0, 0, 200, 10, 3]);
+    }
+
+    #[test]
+    fn test_replies_within_max_reply_len() {
+        type Event = heapless::Vec<u8, 30>;
+        type Batch = EventBatchReply<EventOf<Event, NoEE>, 4>;
+
+        let ignore = |_: &u8, _: &mut EventLog<Event, 8>| Ok::<_, ()>(());
+        for (max_reply_len, expected_batches, expected_reason) in [
+            (
+                8,
+                [[1].as_slice(), &[2]].as_slice(),
+                Some(IgnoreReason::ExceedsMaxReplyLen),
+            ),
+            (
+                16,
+                &[&[1, 2], &[8], &[3]],
+                Some(IgnoreReason::ExceedsMaxReplyLen),
+            ),
+            (64, &[&[1, 2, 8, 3], &[30, 1]], None),
+        ] {
+            let mut engine = ServerEngine::<u8, Event, 8, 2, 4, 128>::new(
+                EventLog::new(0),
+                StatusReporter::new(0, TickRate::SECONDS, ResetCause::PowerOn),
+            );
+            for len in [1, 2, 8, 3, 30, 1] {
+                let event = Event::from_slice(&[7; 30][..len]).unwrap();
+                engine.log_mut().push(event, 0);
+            }
+            let status = engine.handle_frame(&request(None, &[]), 0, ignore);
+            assert!(matches!(status, Output::Reply(_)));
+            let mut request = MultiCommandRequest::<u8, 2>::new(None);
+            request.max_reply_len = Some(max_reply_len);
+
+            // Batches shrink to take no more than the length asked for,
+            // until an event alone takes more.
+            let mut batches = std::vec::Vec::new();
+            let reason = loop {
+                let bytes = postcard::to_vec::<_, 32>(&request).unwrap();
+                let bytes = match engine.handle_frame(&bytes, 0, ignore) {
+                    Output::Reply(bytes) => bytes,
+                    Output::Ignore(reason) => break Some(reason),
+                };
+                assert!(bytes.len() <= max_reply_len as usize);
+                let batch: Batch = postcard::from_bytes(bytes).unwrap();
+                if batch.replies.is_empty() {
+                    break None;
+                }
+                let mut lens = std::vec::Vec::new();
+                for reply in batch.replies {
+                    let Some(EventOf::Logged(event, offset)) = reply.event else {
+                        panic!("not logged");
+                    };
+                    lens.push(event.len());
+                    request.last_event_offset = Some(offset);
+                }
+                batches.push(lens);
+            };
+            assert_eq!(batches, expected_batches);
+            assert_eq!(reason, expected_reason);
+        }
+    }
+
+    #[cfg(feature = "data")]
+    #[test]
+    fn test_datagrams() {
+        use aead::{generic_array::GenericArray, KeyInit};
+        use aes::Aes128;
+        use ccm::{
+            consts::{U4, U7},
+            Ccm,
+        };
+
+        use crate::datagram::{recv_event, send_command};
+
+        let cipher = Ccm::<Aes128, U4, U7>::new(GenericArray::from_slice(b"0123456789ABCDEF"));
+        let mut engine = engine();
+        engine.log_mut().push(1, 0);
+
+        let port = Port::new(1).unwrap();
+        let header = |server_address| Header {
+            version: 0,
+            source: DataSource::Client,
+            server_address,
+            server_port: port,
+            frame_counter: 0,
+        };
+        let request = CommandRequest::<u8> {
+            last_event_offset: Some(9),
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: Some(2),
+        };
+        let mut datagram_buf = [0; 32];
+        send_command(&cipher, &header(9), &request, &mut datagram_buf).unwrap();
+
+        // Requests of other servers are ignored.
+        assert_eq!(
+            engine.handle_datagram(&cipher, 8, port, &datagram_buf, 3, execute),
+            Output::Ignore(IgnoreReason::CannotDecodeDatagram(
+                FromDatagramError::FilterDoesNotMatch
+            ))
+        );
+
+        let Output::Reply(reply_buf) =
+            engine.handle_datagram(&cipher, 9, port, &datagram_buf, 3, execute)
+        else {
+            panic!("ignored");
+        };
+        let (header, reply) = recv_event::<u8, NoEE, u32, 32>(
+            &cipher,
+            |h| h.source == DataSource::Server,
+            reply_buf.try_into().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(header.server_address, 9);
+        assert!(matches!(reply.event, Some(EventOf::Status(_))));
+    }
+}
@@ -0,0 +1,189 @@
+//! Announcing that a server is about to shut down e.g. to reboot or to apply
+//! an update, so that its client tells a planned restart from a failure. The
+//! announcement is a logged event, and so is delivered in order with the
+//! others and retained until the client has received it. Events of the
+//! application convey a [Shutdown] by way of [ShutdownOf], a server
+//! announcing it with [crate::server::ServerEngine::announce_shutdown], and a
+//! client expecting it, see [crate::client::ClientEngine::expect_shutdowns],
+//! does not count the server's silence against its liveness for the
+//! downtime announced.
+
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+/// Why a server shuts down. Reasons are encoded by their position, and so
+/// further reasons are only ever added following those here. Clients
+/// unaware of a reason fail to decode it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum ShutdownReason {
+    /// An operator asked for the server to reboot.
+    Reboot,
+    /// The server is to apply an update of its firmware.
+    FirmwareUpdate,
+    /// A watchdog is about to expire.
+    WatchdogImminent,
+    /// A reason defined by the application.
+    Other(u8),
+}
+
+/// The announcement of a server shutting down, for no more than the
+/// downtime given in the server's ticks from when it is logged.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+pub struct Shutdown {
+    /// Why the server shuts down.
+    pub reason: ShutdownReason,
+    /// The most ticks that the server expects to be silent for.
+    pub expected_downtime_ticks: u64,
+}
+
+/// Logged events that may convey the announcement of a shutdown.
+pub trait ShutdownOf {
+    /// The shutdown announced, if any.
+    fn shutdown(&self) -> Option<&Shutdown>;
+}
+
+impl ShutdownOf for Shutdown {
+    fn shutdown(&self) -> Option<&Shutdown> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        client::{ClientConfig, Liveness},
+        clock::Clock,
+        event_log::EventLog,
+        harness::VirtualBus,
+        offset_tracker::Observation,
+        server::ServerEngine,
+        status::StatusReporter,
+        ResetCause, TickRate,
+    };
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    enum Event {
+        Reading(u8),
+        Shutdown(Shutdown),
+    }
+
+    impl From<Shutdown> for Event {
+        fn from(shutdown: Shutdown) -> Self {
+            Event::Shutdown(shutdown)
+        }
+    }
+
+    impl ShutdownOf for Event {
+        fn shutdown(&self) -> Option<&Shutdown> {
+            match self {
+                Event::Shutdown(shutdown) => Some(shutdown),
+                _ => None,
+            }
+        }
+    }
+
+    type Server = ServerEngine<u8, Event, 8, 1, 4, 32>;
+    type Bus = VirtualBus<u8, u8, Event, 1, 1, 4, 32, 8>;
+
+    fn server(start_offset: u32) -> Server {
+        let mut server = Server::new(
+            EventLog::new(start_offset),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        );
+        server.log_mut().push(Event::Reading(1), 0);
+        server
+    }
+
+    #[test]
+    fn test_encoding() {
+        // Reasons are encoded by their position, which must never change.
+        for (reason, bytes) in [
+            (ShutdownReason::Reboot, &[0, 100][..]),
+            (ShutdownReason::FirmwareUpdate, &[1, 100]),
+            (ShutdownReason::WatchdogImminent, &[2, 100]),
+            (ShutdownReason::Other(7), &[3, 7, 100]),
+        ] {
+            let shutdown = Shutdown {
+                reason,
+                expected_downtime_ticks: 100,
+            };
+            assert_eq!(postcard::to_vec::<_, 16>(&shutdown).unwrap(), bytes);
+            assert_eq!(postcard::from_bytes::<Shutdown>(bytes), Ok(shutdown));
+        }
+    }
+
+    // Runs a server that shuts down for 3s, announcing it or not, and
+    // returns with a fresh log. Returns the server's liveness as told while
+    // it was silent and once it returned, and what was observed of its
+    // events once it returned.
+    fn restart(announce: bool) -> (Option<Liveness>, Option<Liveness>, Vec<Observation>) {
+        let config = ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 10,
+            client_time: false,
+            max_reply_len: false,
+        };
+        let mut bus = Bus::new(config, 0);
+        bus.client_mut().expect_shutdowns();
+        bus.add_server(1, 100, server(0)).unwrap();
+        bus.run_for(1_000);
+
+        if announce {
+            let now = bus.clock().now_ticks();
+            let server = bus.server_mut(&1).unwrap();
+            let offset = server.announce_shutdown(ShutdownReason::FirmwareUpdate, 5_000, now);
+            assert_eq!(offset, 1);
+            assert!(!server.is_shutdown_acknowledged());
+            bus.run_for(250);
+            assert!(bus.server(&1).unwrap().is_shutdown_acknowledged());
+        }
+        bus.disconnect(&1);
+        bus.run_for(3_000);
+        let silent = bus.client_mut().next_liveness_change().map(|(_, l)| l);
+
+        let delivered = bus.delivered().len();
+        bus.add_server(1, 100, server(1_000)).unwrap();
+        bus.run_for(1_000);
+        let returned = bus.client_mut().next_liveness_change().map(|(_, l)| l);
+        let observed = bus.delivered()[delivered..]
+            .iter()
+            .map(|(_, _, o)| *o)
+            .collect();
+        bus.assert_no_unsolicited_server_tx();
+        (silent, returned, observed)
+    }
+
+    #[test]
+    fn test_planned_restart() {
+        // A server silent for the downtime that it announced is not alarmed
+        // as offline, and is recovered cleanly once it returns, its status
+        // being requested again.
+        let (silent, returned, observed) = restart(true);
+        assert_eq!((silent, returned), (None, None));
+        assert!(matches!(
+            observed[..],
+            [
+                Observation::NewEvent,
+                Observation::RecoveryNeeded { start: 1_000, .. },
+                Observation::NewEvent,
+            ]
+        ));
+
+        // Whereas one that did not announce it is.
+        let (silent, returned, observed) = restart(false);
+        assert_eq!(
+            (silent, returned),
+            (Some(Liveness::Offline), Some(Liveness::Online))
+        );
+        assert!(matches!(
+            observed[..],
+            [
+                Observation::NewEvent,
+                Observation::RecoveryNeeded { start: 1_000, .. },
+            ]
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+//! When a server replies its [ServerStatus] in place of an event.
+
+use crate::{event_log::EventLog, Offset, ResetCause, ServerStatus, TickRate};
+
+/// Decides when a server replies its status: to the first request following
+/// its boot, so that a client learns of the boot and why it occurred, and to
+/// each request asking for it, see [CommandRequest::status_requested].
+///
+/// [CommandRequest::status_requested]: crate::CommandRequest::status_requested
+pub struct StatusReporter {
+    boot_ticks: u64,
+    tick_rate: TickRate,
+    last_reset_cause: ResetCause,
+    reported: bool,
+}
+
+impl StatusReporter {
+    /// A reporter for a server that booted at the ticks given, elapsing at
+    /// the rate given, having last reset for the cause given.
+    pub const fn new(boot_ticks: u64, tick_rate: TickRate, last_reset_cause: ResetCause) -> Self {
+        Self {
+            boot_ticks,
+            tick_rate,
+            last_reset_cause,
+            reported: false,
+        }
+    }
+
+    /// The status to reply to a request as of the ticks given, if it is to
+    /// be replied in place of an event, given whether the request asks for
+    /// it. The status reports the occupancy of the log given.
+    pub fn status_for<E: Clone, const N: usize, O: Offset, S: Clone>(
+        &mut self,
+        status_requested: bool,
+        log: &EventLog<E, N, O, S>,
+        now_ticks: u64,
+    ) -> Option<ServerStatus> {
+        if self.reported && !status_requested {
+            return None;
+        }
+        self.reported = true;
+        Some(ServerStatus {
+            uptime_ticks: now_ticks.saturating_sub(self.boot_ticks),
+            log_occupancy: log.occupancy(),
+            last_reset_cause: self.last_reset_cause,
+            tick_rate: self.tick_rate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_reporter() {
+        let mut log = EventLog::<(), 4>::new(10);
+        log.push((), 105);
+        let mut reporter = StatusReporter::new(100, TickRate::SECONDS, ResetCause::Watchdog);
+
+        // The first request following boot is replied the status, whether
+        // or not it asks for it.
+        assert_eq!(
+            reporter.status_for(false, &log, 110),
+            Some(ServerStatus {
+                uptime_ticks: 10,
+                log_occupancy: 25,
+                last_reset_cause: ResetCause::Watchdog,
+                tick_rate: TickRate::SECONDS,
+            })
+        );
+        assert_eq!(reporter.status_for(false, &log, 111), None);
+
+        // Thereafter only requests asking for it are.
+        log.push((), 112);
+        assert_eq!(
+            reporter.status_for(true, &log, 120),
+            Some(ServerStatus {
+                uptime_ticks: 20,
+                log_occupancy: 50,
+                last_reset_cause: ResetCause::Watchdog,
+                tick_rate: TickRate::SECONDS,
+            })
+        );
+        assert_eq!(reporter.status_for(false, &log, 121), None);
+    }
+}
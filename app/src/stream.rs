@@ -0,0 +1,319 @@
+//! Conveying datagrams over a byte stream such as TCP e.g. where a gateway
+//! tunnels them to a service elsewhere. Each datagram is preceded by 2 bytes
+//! of its length, big-endian. A stream cannot be resynchronised once a
+//! length claims more bytes than a datagram may be, there being no telling
+//! where the next datagram begins, and so the stream is closed instead. A
+//! [FramedRead] and a [FramedWrite] convey datagrams over the halves of any
+//! stream, and a [TcpTransport] conveys them over TCP. Requires the `std`
+//! feature.
+
+use std::{io, net::SocketAddr, vec, vec::Vec};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+};
+
+use crate::transport::Transport;
+
+// The bytes of the length preceding each datagram.
+const PREFIX_LEN: usize = 2;
+
+/// The most bytes that a datagram conveyed over a stream may be, its length
+/// being conveyed in 2 bytes.
+pub const MAX_DATAGRAM_LEN: usize = u16::MAX as usize;
+
+/// Problems conveying datagrams over a stream.
+#[derive(Debug)]
+pub enum StreamError {
+    /// The stream failed.
+    Io(io::Error),
+    /// The stream was closed, either by its peer or following a datagram
+    /// that is too long.
+    Closed,
+    /// A datagram is longer than may be sent or received. A stream that
+    /// receives one is closed, with nothing further received from it.
+    TooLong {
+        /// The length of the datagram.
+        len: usize,
+    },
+}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> Self {
+        StreamError::Io(e)
+    }
+}
+
+/// Receives datagrams of up to `N` bytes from a stream, however the bytes
+/// of each are split across reads.
+pub struct FramedRead<R, const N: usize> {
+    io: R,
+    // Bytes received and yet to be returned, so that receiving may be
+    // abandoned without losing them.
+    buf: Vec<u8>,
+    filled: usize,
+    closed: bool,
+}
+
+impl<R: AsyncRead + Unpin, const N: usize> FramedRead<R, N> {
+    /// Receive the datagrams of the stream given.
+    pub fn new(io: R) -> Self {
+        const { assert!(N <= MAX_DATAGRAM_LEN) };
+        Self {
+            io,
+            buf: vec![0; PREFIX_LEN + N],
+            filled: 0,
+            closed: false,
+        }
+    }
+
+    /// Receive a datagram into the buffer given, returning its length.
+    /// Receiving may be abandoned without losing the bytes read meanwhile.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, StreamError> {
+        loop {
+            if self.closed {
+                return Err(StreamError::Closed);
+            }
+            if let Some(prefix) = self.buf[..self.filled].first_chunk::<PREFIX_LEN>() {
+                let len = u16::from_be_bytes(*prefix) as usize;
+                let frame_len = PREFIX_LEN + len;
+                if len > N || len > buf.len() {
+                    self.closed = true;
+                    return Err(StreamError::TooLong { len });
+                }
+                if self.filled >= frame_len {
+                    buf[..len].copy_from_slice(&self.buf[PREFIX_LEN..frame_len]);
+                    self.buf.copy_within(frame_len..self.filled, 0);
+                    self.filled -= frame_len;
+                    return Ok(len);
+                }
+            }
+            let read = self.io.read(&mut self.buf[self.filled..]).await?;
+            if read == 0 {
+                self.closed = true;
+                return Err(StreamError::Closed);
+            }
+            self.filled += read;
+        }
+    }
+
+    /// The stream received from.
+    pub fn get_ref(&self) -> &R {
+        &self.io
+    }
+}
+
+/// Sends datagrams of up to `N` bytes to a stream.
+pub struct FramedWrite<W, const N: usize> {
+    io: W,
+}
+
+impl<W: AsyncWrite + Unpin, const N: usize> FramedWrite<W, N> {
+    /// Send datagrams to the stream given.
+    pub fn new(io: W) -> Self {
+        const { assert!(N <= MAX_DATAGRAM_LEN) };
+        Self { io }
+    }
+
+    /// Send a datagram, preceded by its length.
+    pub async fn send(&mut self, bytes: &[u8]) -> Result<(), StreamError> {
+        if bytes.len() > N {
+            return Err(StreamError::TooLong { len: bytes.len() });
+        }
+        let prefix = (bytes.len() as u16).to_be_bytes();
+        self.io.write_all(&prefix).await?;
+        self.io.write_all(bytes).await?;
+        Ok(self.io.flush().await?)
+    }
+
+    /// Close the stream, once the datagrams sent have been.
+    pub async fn shutdown(&mut self) -> Result<(), StreamError> {
+        Ok(self.io.shutdown().await?)
+    }
+
+    /// The stream sent to.
+    pub fn get_ref(&self) -> &W {
+        &self.io
+    }
+}
+
+/// Conveys datagrams of up to `N` bytes over a TCP connection, addressed
+/// by the peer's address so that the same engines run over TCP as over UDP.
+/// The connection is closed should its peer send a datagram that is too
+/// long. Requires the `std` feature.
+pub struct TcpTransport<const N: usize> {
+    read: FramedRead<OwnedReadHalf, N>,
+    write: FramedWrite<OwnedWriteHalf, N>,
+    peer: SocketAddr,
+}
+
+impl<const N: usize> TcpTransport<N> {
+    /// A transport conveying datagrams with a connection to the address
+    /// given.
+    pub async fn connect(remote: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::new(TcpStream::connect(remote).await?)
+    }
+
+    /// A transport conveying datagrams with the connection given e.g. one
+    /// accepted by a server.
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let peer = stream.peer_addr()?;
+        let (read, write) = stream.into_split();
+        Ok(Self {
+            read: FramedRead::new(read),
+            write: FramedWrite::new(write),
+            peer,
+        })
+    }
+
+    /// Whether messages of the type given always fit within a datagram,
+    /// see [crate::transport::udp::UdpClientTransport::fits].
+    pub const fn fits<T: postcard::experimental::max_size::MaxSize>() -> bool {
+        T::POSTCARD_MAX_SIZE <= N
+    }
+
+    /// The local address of the connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.read.get_ref().local_addr()
+    }
+
+    /// The address of the connection's peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl<const N: usize> Transport for TcpTransport<N> {
+    type Address = SocketAddr;
+    type Error = StreamError;
+
+    async fn send(&mut self, address: &SocketAddr, bytes: &[u8]) -> Result<(), StreamError> {
+        if *address != self.peer {
+            return Err(StreamError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not the peer of the connection",
+            )));
+        }
+        self.write.send(bytes).await
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, SocketAddr), StreamError> {
+        match self.read.recv(buf).await {
+            Ok(len) => Ok((len, self.peer)),
+            Err(e @ StreamError::TooLong { .. }) => {
+                let _ = self.write.shutdown().await;
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        collections::VecDeque,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::{io::ReadBuf, net::TcpListener};
+
+    // Reads the segments given one at a time, as TCP may deliver them.
+    struct Segments(VecDeque<&'static [u8]>);
+
+    impl AsyncRead for Segments {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(segment) = self.0.pop_front() {
+                buf.put_slice(segment);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_reads() {
+        // Lengths are split across segments, as are datagrams, and several
+        // datagrams share a segment.
+        let segments = [
+            &[0][..],
+            &[3, 1],
+            &[2, 3, 0],
+            &[0, 0],
+            &[2, 4, 5, 0],
+            &[9, 6],
+        ];
+        let mut framed = FramedRead::<_, 8>::new(Segments(segments.into()));
+        let mut buf = [0; 8];
+        assert_eq!(framed.recv(&mut buf).await.unwrap(), 3);
+        assert_eq!(buf[..3], [1, 2, 3]);
+        assert_eq!(framed.recv(&mut buf).await.unwrap(), 0);
+        assert_eq!(framed.recv(&mut buf).await.unwrap(), 2);
+        assert_eq!(buf[..2], [4, 5]);
+
+        // A length longer than a datagram may be closes the stream.
+        assert!(matches!(
+            framed.recv(&mut buf).await,
+            Err(StreamError::TooLong { len: 9 })
+        ));
+        assert!(matches!(
+            framed.recv(&mut buf).await,
+            Err(StreamError::Closed)
+        ));
+
+        let mut framed = FramedRead::<_, 8>::new(Segments([&[0, 2, 1][..]].into()));
+        assert!(matches!(
+            framed.recv(&mut buf).await,
+            Err(StreamError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut client = TcpTransport::<32>::connect(server_addr).await.unwrap();
+        let (stream, client_addr) = listener.accept().await.unwrap();
+        let mut server = TcpTransport::<32>::new(stream).unwrap();
+        assert_eq!(client.local_addr().unwrap(), client_addr);
+        assert!(TcpTransport::<32>::fits::<crate::MultiCommandRequest<u8, 2>>());
+
+        let mut buf = [0; 32];
+        client.send(&server_addr, &[1, 2, 3]).await.unwrap();
+        assert_eq!(server.recv(&mut buf).await.unwrap(), (3, client_addr));
+        assert_eq!(buf[..3], [1, 2, 3]);
+        server.send(&client_addr, &[4; 32]).await.unwrap();
+        assert_eq!(client.recv(&mut buf).await.unwrap(), (32, server_addr));
+        assert!(matches!(
+            server.send(&client_addr, &[0; 33]).await,
+            Err(StreamError::TooLong { len: 33 })
+        ));
+        assert!(matches!(
+            server.send(&server_addr, &[]).await,
+            Err(StreamError::Io(_))
+        ));
+
+        // A peer claiming a datagram that is too long is disconnected.
+        let mut oversize = FramedWrite::<_, 64>::new(&mut client.write.io);
+        oversize.send(&[0; 33]).await.unwrap();
+        assert!(matches!(
+            server.recv(&mut buf).await,
+            Err(StreamError::TooLong { len: 33 })
+        ));
+        assert!(matches!(
+            client.recv(&mut buf).await,
+            Err(StreamError::Closed)
+        ));
+    }
+}
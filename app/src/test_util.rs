@@ -0,0 +1,222 @@
+//! Strategies generating requests and replies, given strategies of an
+//! application's own commands and events, for property testing with proptest
+//! that they are encoded and decoded as they should be within the bytes that
+//! an application allows for them. Requires the `test-util` feature and the
+//! standard library.
+//!
+//! A command or event that encodes to no bytes at all e.g. `()` is decoded as
+//! absent, being the last field of a request or reply, and so does not round
+//! trip.
+
+extern crate std;
+
+use std::{fmt::Debug, format, vec};
+
+use proptest::{prelude::*, test_runner::TestCaseError};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    CommandRequest, EventOf, EventReply, Logged, ResetCause, ServerStatus, TemporalEvent, TickRate,
+};
+
+/// Any offset, favouring those either side of it wrapping.
+pub fn offset_strategy() -> impl Strategy<Value = u32> {
+    prop_oneof![
+        3 => any::<u32>(),
+        1 => prop::sample::select(vec![0, 1, u32::MAX - 1, u32::MAX]),
+    ]
+}
+
+/// Any request of the commands given, whatever else it asks of a server.
+pub fn command_request_strategy<C>(
+    commands: impl Strategy<Value = C>,
+) -> impl Strategy<Value = CommandRequest<C>>
+where
+    C: Debug + Serialize,
+{
+    (
+        prop::option::of(offset_strategy()),
+        any::<bool>(),
+        any::<Option<u8>>(),
+        any::<Option<u64>>(),
+        any::<Option<u8>>(),
+        any::<bool>(),
+        prop::option::of(commands),
+    )
+        .prop_map(
+            |(
+                last_event_offset,
+                status_requested,
+                filter,
+                client_time,
+                max_reply_len,
+                absolute_ticks,
+                command,
+            )| CommandRequest {
+                last_event_offset,
+                status_requested,
+                filter,
+                client_time,
+                max_reply_len,
+                absolute_ticks,
+                command,
+            },
+        )
+}
+
+/// Any reply of the events given, or of none.
+pub fn event_reply_strategy<E>(
+    events: impl Strategy<Value = E>,
+) -> impl Strategy<Value = EventReply<E>>
+where
+    E: Debug + TemporalEvent,
+{
+    (any::<u64>(), prop::option::of(events))
+        .prop_map(|(delta_ticks, event)| EventReply { delta_ticks, event })
+}
+
+/// Any of the logged events given, at any offset.
+pub fn logged_strategy<E: Debug>(
+    events: impl Strategy<Value = E>,
+) -> impl Strategy<Value = Logged<E>> {
+    (events, offset_strategy()).prop_map(|(event, offset)| Logged(event, offset))
+}
+
+/// Any status of a server.
+pub fn server_status_strategy() -> impl Strategy<Value = ServerStatus> {
+    let last_reset_cause = prop::sample::select(vec![
+        ResetCause::Unknown,
+        ResetCause::PowerOn,
+        ResetCause::Brownout,
+        ResetCause::Watchdog,
+        ResetCause::Software,
+        ResetCause::External,
+        ResetCause::Fault,
+    ]);
+    (
+        any::<u64>(),
+        0..=100u8,
+        last_reset_cause,
+        (1..=u32::MAX).prop_filter_map("a rate of 0", TickRate::from_hz),
+    )
+        .prop_map(
+            |(uptime_ticks, log_occupancy, last_reset_cause, tick_rate)| ServerStatus {
+                uptime_ticks,
+                log_occupancy,
+                last_reset_cause,
+                tick_rate,
+            },
+        )
+}
+
+/// Any kind of event, being either of the logged and ephemeral events given
+/// or one that the protocol replies of its own accord.
+pub fn event_of_strategy<E, EE>(
+    events: impl Strategy<Value = E>,
+    ephemeral_events: impl Strategy<Value = EE>,
+) -> impl Strategy<Value = EventOf<E, EE>>
+where
+    E: Clone + Debug,
+    EE: Clone + Debug,
+{
+    prop_oneof![
+        (events, offset_strategy()).prop_map(|(event, offset)| EventOf::Logged(event, offset)),
+        ephemeral_events.prop_map(EventOf::Ephemeral),
+        (offset_strategy(), offset_strategy())
+            .prop_map(|(start, end)| EventOf::Recovery(start, end)),
+        (
+            offset_strategy(),
+            offset_strategy(),
+            any::<u32>(),
+            any::<bool>()
+        )
+            .prop_map(
+                |(start, end, dropped, snapshot_available)| EventOf::RecoveryV1 {
+                    start,
+                    end,
+                    dropped,
+                    snapshot_available,
+                }
+            ),
+        server_status_strategy().prop_map(EventOf::Status),
+        offset_strategy().prop_map(EventOf::Filtered),
+        offset_strategy().prop_map(|offset| EventOf::Snapshot((), offset)),
+    ]
+}
+
+/// Asserts that a value encodes within the bytes given, and decodes as the
+/// value encoded, for use within a proptest.
+pub fn assert_round_trip<T>(value: &T, budget: usize) -> Result<(), TestCaseError>
+where
+    T: Debug + DeserializeOwned + PartialEq + Serialize,
+{
+    let mut buf = vec![0; budget];
+    let encoded = postcard::to_slice(value, &mut buf)
+        .map_err(|e| TestCaseError::fail(format!("{value:?} does not encode: {e:?}")))?;
+    let decoded = postcard::from_bytes::<T>(encoded)
+        .map_err(|e| TestCaseError::fail(format!("{value:?} does not decode: {e:?}")))?;
+    prop_assert_eq!(&decoded, value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use heapless::Vec;
+    use postcard::experimental::max_size::MaxSize;
+
+    use crate::{Ephemeral, MultiCommandRequest};
+
+    proptest! {
+        #[test]
+        fn test_command_request_round_trip(
+            request in command_request_strategy(any::<u8>()),
+            bytes_request in command_request_strategy(
+                prop::collection::vec(any::<u8>(), 0..=8)
+                    .prop_map(|bytes| Vec::<u8, 8>::from_slice(&bytes).unwrap()),
+            ),
+        ) {
+            assert_round_trip(&request, CommandRequest::<u8>::POSTCARD_MAX_SIZE)?;
+            assert_round_trip(&bytes_request, 64)?;
+        }
+
+        #[test]
+        fn test_multi_command_request_decoding(
+            request in command_request_strategy(any::<u16>()),
+        ) {
+            // A server decodes a request of one command as one of several.
+            let mut buf = [0; CommandRequest::<u16>::POSTCARD_MAX_SIZE];
+            let encoded = postcard::to_slice(&request, &mut buf).unwrap();
+            let multi = postcard::from_bytes::<MultiCommandRequest<u16, 2>>(encoded).unwrap();
+            prop_assert_eq!(multi.last_event_offset, request.last_event_offset);
+            prop_assert_eq!(multi.commands.first(), request.command.as_ref());
+        }
+
+        #[test]
+        fn test_event_reply_round_trip(
+            reply in event_reply_strategy(event_of_strategy(any::<u8>(), any::<i16>())),
+            logged in event_reply_strategy(logged_strategy(any::<u32>())),
+            ephemeral in event_reply_strategy(any::<u8>().prop_map(Ephemeral)),
+        ) {
+            assert_round_trip(&reply, EventReply::<EventOf<u8, i16>>::POSTCARD_MAX_SIZE)?;
+            assert_round_trip(&logged, EventReply::<Logged<u32>>::POSTCARD_MAX_SIZE)?;
+            assert_round_trip(&ephemeral, 16)?;
+        }
+    }
+
+    #[test]
+    fn test_budget_exceeded() {
+        let request = CommandRequest::<u64> {
+            last_event_offset: None,
+            status_requested: false,
+            filter: None,
+            client_time: None,
+            max_reply_len: None,
+            absolute_ticks: false,
+            command: Some(u64::MAX),
+        };
+        assert!(assert_round_trip(&request, 4).is_err());
+        assert!(assert_round_trip(&request, 16).is_ok());
+    }
+}
@@ -0,0 +1,863 @@
+//! Conveying the datagrams of a [ClientEngine] or [ServerEngine] and keeping
+//! their time, so that the same engines run over any transport, and under
+//! any async executor e.g. tokio or embassy. A [Transport] conveys
+//! datagrams and a [Timer] keeps time, and [run_client] and [run_server]
+//! drive an engine with them.
+//!
+//! UDP is provided by the `std` feature using tokio, and serial
+//! communications by the `serial` feature using `embedded-io-async`, which is
+//! enabled by default, and time is kept with embassy by the `embassy`
+//! feature. A half-duplex RS-485 bus is provided by the
+//! `embedded-io` feature, see [crate::rs485], and TCP by the `std` feature,
+//! see [crate::stream]. Blocking counterparts, without an async executor,
+//! are provided by the `blocking` feature, see [crate::blocking].
+
+use core::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    pin::pin,
+    task::Poll,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    ack::CommandFailure,
+    client::{Action, ClientEngine, Delivery},
+    clock::Clock,
+    event_log::EventLog,
+    observer::ProtocolObserver,
+    server::{Output, ServerEngine},
+    EventOf, Offset, TemporalEvent,
+};
+
+/// Conveys datagrams to and from the addresses of a transport.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// Where datagrams are conveyed to and from.
+    type Address;
+    /// Problems conveying datagrams.
+    type Error;
+
+    /// Send a datagram to an address.
+    async fn send(&mut self, address: &Self::Address, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Receive a datagram into the buffer given, returning its length and
+    /// where it was received from. Receiving may be abandoned in favour of a
+    /// timeout, and so should not lose datagrams once abandoned.
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, Self::Address), Self::Error>;
+}
+
+/// Keeps the time of an engine with a [Clock], sleeping until the ticks
+/// that it is given.
+#[allow(async_fn_in_trait)]
+pub trait Timer: Clock {
+    /// Sleep until the ticks given have elapsed.
+    async fn sleep_until(&self, ticks: u64);
+}
+
+/// Drive a client engine with a transport and clock, delivering the events
+/// received to the function given. The engine is borrowed only while it is
+/// not awaiting, and so others may queue commands with it meanwhile, see
+/// [ClientEngine::command]. Returns only given a problem with the transport.
+#[allow(clippy::type_complexity)]
+pub async fn run_client<
+    T,
+    K,
+    D,
+    C,
+    E,
+    const SERVERS: usize,
+    const COMMANDS: usize,
+    const EVENTS: usize,
+    const N: usize,
+    EE,
+    O,
+    S,
+    P,
+>(
+    engine: &RefCell<ClientEngine<T::Address, C, E, SERVERS, COMMANDS, EVENTS, N, EE, O, S, P>>,
+    transport: &mut T,
+    clock: &K,
+    mut deliver: D,
+) -> T::Error
+where
+    T: Transport,
+    T::Address: Clone + Eq,
+    K: Timer,
+    D: FnMut(Delivery<T::Address, EventOf<E, EE, O, S>, O, EVENTS>),
+    C: Serialize,
+    O: Offset + postcard::experimental::max_size::MaxSize,
+    EventOf<E, EE, O, S>: TemporalEvent + DeserializeOwned,
+    P: ProtocolObserver<T::Address>,
+{
+    let mut buf = [0; N];
+    loop {
+        let action = match engine.borrow_mut().next_action(clock.now_ticks()) {
+            Action::Transmit { address, bytes, .. } => {
+                buf[..bytes.len()].copy_from_slice(bytes);
+                Ok((address, bytes.len()))
+            }
+            Action::Wait { until } => Err(until),
+        };
+        let until = match action {
+            Ok((address, len)) => {
+                if let Err(e) = transport.send(&address, &buf[..len]).await {
+                    return e;
+                }
+                continue;
+            }
+            Err(until) => until,
+        };
+        match timeout_at(clock, until, transport.recv(&mut buf)).await {
+            Some(Ok((len, address))) => {
+                let delivery =
+                    engine
+                        .borrow_mut()
+                        .handle_frame(&address, &buf[..len], clock.now_ticks());
+                if let Some(delivery) = delivery {
+                    deliver(delivery);
+                }
+            }
+            Some(Err(e)) => return e,
+            None => {
+                engine.borrow_mut().handle_timeout(clock.now_ticks());
+            }
+        }
+    }
+}
+
+/// Drive a server engine with a transport and clock, executing the commands
+/// received with the function given, see [ServerEngine::handle_frame]. The
+/// engine is borrowed only while it is not awaiting, and so others may log
+/// events with it meanwhile. Returns only given a problem with the transport.
+pub async fn run_server<
+    T,
+    K,
+    X,
+    F,
+    C,
+    E,
+    const LOG: usize,
+    const COMMANDS: usize,
+    const EVENTS: usize,
+    const N: usize,
+    EE,
+    O,
+    S,
+    P,
+>(
+    engine: &RefCell<ServerEngine<C, E, LOG, COMMANDS, EVENTS, N, EE, O, S, P>>,
+    transport: &mut T,
+    clock: &K,
+    mut execute: X,
+) -> T::Error
+where
+    T: Transport,
+    K: Timer,
+    X: FnMut(&C, &mut EventLog<E, LOG, O, S>) -> Result<(), F>,
+    F: CommandFailure<EE>,
+    C: DeserializeOwned + Serialize,
+    E: Clone,
+    O: Offset,
+    S: Clone,
+    EventOf<E, EE, O, S>: TemporalEvent,
+    P: ProtocolObserver<()>,
+{
+    let mut buf = [0; N];
+    loop {
+        let (len, address) = match transport.recv(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => return e,
+        };
+        let len =
+            match engine
+                .borrow_mut()
+                .handle_frame(&buf[..len], clock.now_ticks(), &mut execute)
+            {
+                Output::Reply(bytes) => {
+                    buf[..bytes.len()].copy_from_slice(bytes);
+                    bytes.len()
+                }
+                Output::Ignore(_) => continue,
+            };
+        if let Err(e) = transport.send(&address, &buf[..len]).await {
+            return e;
+        }
+    }
+}
+
+// The output of a future unless the ticks given elapse first, in which case
+// it is abandoned.
+pub(crate) async fn timeout_at<K: Timer, F: Future>(
+    clock: &K,
+    until: u64,
+    future: F,
+) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut sleep = pin!(clock.sleep_until(until));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            Poll::Ready(Some(output))
+        } else if sleep.as_mut().poll(cx).is_ready() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(feature = "std")]
+pub use self::udp::{TokioClock, UdpTransport};
+
+/// Conveying datagrams over UDP with tokio, and keeping time with it.
+/// Requires the `std` feature.
+#[cfg(feature = "std")]
+pub mod udp {
+    use std::{
+        io,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        vec,
+        vec::Vec,
+    };
+
+    use tokio::{net::UdpSocket, time::Instant};
+
+    use super::{Timer, Transport};
+    use crate::{clock::Clock, TickRate};
+
+    /// The most bytes that a datagram of UDP over IPv4 conveys.
+    pub const MAX_UDP_PAYLOAD: usize = 65_507;
+
+    /// Conveys datagrams over UDP. Requires the `std` feature.
+    pub struct UdpTransport {
+        socket: UdpSocket,
+    }
+
+    impl UdpTransport {
+        /// A transport conveying datagrams with the socket given.
+        pub fn new(socket: UdpSocket) -> Self {
+            Self { socket }
+        }
+    }
+
+    impl Transport for UdpTransport {
+        type Address = SocketAddr;
+        type Error = io::Error;
+
+        async fn send(&mut self, address: &SocketAddr, bytes: &[u8]) -> io::Result<()> {
+            self.socket.send_to(bytes, address).await.map(|_| ())
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.socket.recv_from(buf).await
+        }
+    }
+
+    // Conveys datagrams of up to `N` bytes over UDP, so that datagrams
+    // longer than expected are discarded rather than received truncated.
+    struct Bounded<const N: usize> {
+        socket: UdpSocket,
+        // One byte more than a datagram may be, so as to tell when one is
+        // longer.
+        buf: Vec<u8>,
+    }
+
+    impl<const N: usize> Bounded<N> {
+        async fn bind(local: SocketAddr) -> io::Result<Self> {
+            const { assert!(N > 0 && N <= MAX_UDP_PAYLOAD) };
+            Ok(Self {
+                socket: UdpSocket::bind(local).await?,
+                buf: vec![0; N + 1],
+            })
+        }
+
+        async fn send(&self, address: &SocketAddr, bytes: &[u8]) -> io::Result<()> {
+            if bytes.len() > N {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "datagram too long",
+                ));
+            }
+            self.socket.send_to(bytes, address).await.map(|_| ())
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            loop {
+                let (len, address) = self.socket.recv_from(&mut self.buf).await?;
+                if len <= N && len <= buf.len() {
+                    buf[..len].copy_from_slice(&self.buf[..len]);
+                    return Ok((len, address));
+                }
+            }
+        }
+    }
+
+    /// Conveys the datagrams of a client of up to `N` bytes over UDP to and
+    /// from a server at a remote address. Datagrams from elsewhere, and those
+    /// longer than `N`, are discarded. Requires the `std` feature.
+    pub struct UdpClientTransport<const N: usize> {
+        bounded: Bounded<N>,
+        remote: SocketAddr,
+    }
+
+    impl<const N: usize> UdpClientTransport<N> {
+        /// A transport conveying datagrams to and from the server at the
+        /// remote address given, from a local address of any port.
+        pub async fn connect(remote: SocketAddr) -> io::Result<Self> {
+            let local = if remote.is_ipv4() {
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+            } else {
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+            };
+            let bounded = Bounded::bind(local).await?;
+            bounded.socket.connect(remote).await?;
+            Ok(Self { bounded, remote })
+        }
+
+        /// Whether messages of the type given always fit within a datagram
+        /// e.g. the requests of a client, see
+        /// [crate::MultiCommandRequest].
+        pub const fn fits<T: postcard::experimental::max_size::MaxSize>() -> bool {
+            T::POSTCARD_MAX_SIZE <= N
+        }
+
+        /// The local address of the transport.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.bounded.socket.local_addr()
+        }
+
+        /// Receive a datagram as per [Transport::recv] unless the instant
+        /// given passes first, for those driving a client engine without
+        /// [super::run_client], see [crate::client::Action::Wait].
+        pub async fn recv_until(
+            &mut self,
+            buf: &mut [u8],
+            until: Instant,
+        ) -> io::Result<Option<(usize, SocketAddr)>> {
+            match tokio::time::timeout_at(until, self.recv(buf)).await {
+                Ok(received) => received.map(Some),
+                Err(_) => Ok(None),
+            }
+        }
+    }
+
+    impl<const N: usize> Transport for UdpClientTransport<N> {
+        type Address = SocketAddr;
+        type Error = io::Error;
+
+        async fn send(&mut self, address: &SocketAddr, bytes: &[u8]) -> io::Result<()> {
+            if *address != self.remote {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not the remote address connected to",
+                ));
+            }
+            self.bounded.send(address, bytes).await
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.bounded.recv(buf).await
+        }
+    }
+
+    /// Conveys the datagrams of a server of up to `N` bytes over UDP,
+    /// receiving requests from clients at any address and replying to them.
+    /// Datagrams longer than `N` are discarded. Requires the `std` feature.
+    pub struct UdpServerTransport<const N: usize> {
+        bounded: Bounded<N>,
+    }
+
+    impl<const N: usize> UdpServerTransport<N> {
+        /// A transport receiving the datagrams conveyed to the local
+        /// address given.
+        pub async fn bind(local: SocketAddr) -> io::Result<Self> {
+            Ok(Self {
+                bounded: Bounded::bind(local).await?,
+            })
+        }
+
+        /// Whether messages of the type given always fit within a datagram
+        /// e.g. the replies of a server of at least one event, see
+        /// [crate::EventReply].
+        pub const fn fits<T: postcard::experimental::max_size::MaxSize>() -> bool {
+            T::POSTCARD_MAX_SIZE <= N
+        }
+
+        /// The local address of the transport e.g. given a port of 0.
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.bounded.socket.local_addr()
+        }
+    }
+
+    impl<const N: usize> Transport for UdpServerTransport<N> {
+        type Address = SocketAddr;
+        type Error = io::Error;
+
+        async fn send(&mut self, address: &SocketAddr, bytes: &[u8]) -> io::Result<()> {
+            self.bounded.send(address, bytes).await
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.bounded.recv(buf).await
+        }
+    }
+
+    /// Keeps time with tokio, in ticks of the rate given since the clock was
+    /// created. Requires the `std` feature.
+    pub struct TokioClock {
+        started: Instant,
+        tick_rate: TickRate,
+    }
+
+    impl TokioClock {
+        /// A clock starting now.
+        pub fn new(tick_rate: TickRate) -> Self {
+            Self {
+                started: Instant::now(),
+                tick_rate,
+            }
+        }
+    }
+
+    impl Clock for TokioClock {
+        fn now_ticks(&self) -> u64 {
+            self.tick_rate.from_duration(self.started.elapsed())
+        }
+    }
+
+    impl Timer for TokioClock {
+        async fn sleep_until(&self, ticks: u64) {
+            match self.started.checked_add(self.tick_rate.to_duration(ticks)) {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => core::future::pending().await,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embassy")]
+pub use self::embassy::EmbassyClock;
+
+#[cfg(feature = "embassy")]
+mod embassy {
+    use embassy_time::{Instant, Timer as EmbassyTimer, TICK_HZ};
+
+    use super::Timer;
+    use crate::{clock::Clock, TickRate};
+
+    /// Keeps time with embassy, in the ticks of its time driver since it
+    /// started. Requires the `embassy` feature.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct EmbassyClock;
+
+    impl EmbassyClock {
+        /// The rate of the ticks of embassy's time driver.
+        pub const TICK_RATE: TickRate = TickRate::from_hz(TICK_HZ as u32).unwrap();
+    }
+
+    impl Clock for EmbassyClock {
+        fn now_ticks(&self) -> u64 {
+            Instant::now().as_ticks()
+        }
+    }
+
+    impl Timer for EmbassyClock {
+        async fn sleep_until(&self, ticks: u64) {
+            EmbassyTimer::at(Instant::from_ticks(ticks)).await
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+pub use self::serial::{Halves, SerialError, SerialTransport};
+
+#[cfg(feature = "serial")]
+mod serial {
+    use embedded_io_async::{ErrorType, Read, Write};
+
+    use super::Transport;
+
+    /// Problems conveying datagrams over a serial connection.
+    #[derive(Debug, Eq, PartialEq)]
+    pub enum SerialError<E> {
+        /// The connection failed.
+        Io(E),
+        /// The connection was closed.
+        Closed,
+        /// A datagram is longer than may be sent or received. Bytes received
+        /// ahead of a datagram that is too long are discarded.
+        TooLong,
+    }
+
+    /// A connection split into the half that is read from and the half
+    /// that is written to.
+    #[derive(Debug)]
+    pub struct Halves<R, W> {
+        /// The half that is read from.
+        pub rx: R,
+        /// The half that is written to.
+        pub tx: W,
+    }
+
+    impl<R: ErrorType, W: ErrorType<Error = R::Error>> ErrorType for Halves<R, W> {
+        type Error = R::Error;
+    }
+
+    impl<R: Read, W: ErrorType<Error = R::Error>> Read for Halves<R, W> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.rx.read(buf).await
+        }
+    }
+
+    impl<R: ErrorType, W: Write<Error = R::Error>> Write for Halves<R, W> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.tx.write(buf).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.tx.flush().await
+        }
+    }
+
+    /// Conveys datagrams of up to `N - 1` bytes over a serial connection
+    /// e.g. RS-485, each preceded by a byte of its length. A serial
+    /// connection has no addresses of its own, and so the data link layer
+    /// conveys the address of a server where several share a bus. Requires
+    /// the `serial` feature.
+    pub struct SerialTransport<T, const N: usize> {
+        io: T,
+        // Bytes received and yet to be returned, so that receiving may be
+        // abandoned without losing them.
+        buf: [u8; N],
+        filled: usize,
+    }
+
+    impl<T, const N: usize> SerialTransport<T, N> {
+        /// A transport conveying datagrams with the connection given.
+        pub fn new(io: T) -> Self {
+            const { assert!(N > 1) };
+            Self {
+                io,
+                buf: [0; N],
+                filled: 0,
+            }
+        }
+    }
+
+    impl<R, W, const N: usize> SerialTransport<Halves<R, W>, N> {
+        /// A transport conveying datagrams with the halves of a connection
+        /// that is split in two e.g. the receiver and transmitter of a UART
+        /// of embassy.
+        pub fn from_halves(rx: R, tx: W) -> Self {
+            Self::new(Halves { rx, tx })
+        }
+    }
+
+    impl<T: Read + Write, const N: usize> Transport for SerialTransport<T, N> {
+        type Address = ();
+        type Error = SerialError<T::Error>;
+
+        async fn send(&mut self, _: &(), bytes: &[u8]) -> Result<(), Self::Error> {
+            let len = u8::try_from(bytes.len())
+                .ok()
+                .filter(|len| (*len as usize) < N)
+                .ok_or(SerialError::TooLong)?;
+            self.io.write_all(&[len]).await.map_err(SerialError::Io)?;
+            self.io.write_all(bytes).await.map_err(SerialError::Io)?;
+            self.io.flush().await.map_err(SerialError::Io)
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, ()), Self::Error> {
+            loop {
+                if let Some(&len) = self.buf[..self.filled].first() {
+                    let (len, frame_len) = (len as usize, len as usize + 1);
+                    if frame_len > N || len > buf.len() {
+                        self.filled = 0;
+                        return Err(SerialError::TooLong);
+                    }
+                    if self.filled >= frame_len {
+                        buf[..len].copy_from_slice(&self.buf[1..frame_len]);
+                        self.buf.copy_within(frame_len..self.filled, 0);
+                        self.filled -= frame_len;
+                        return Ok((len, ()));
+                    }
+                }
+                let read = self
+                    .io
+                    .read(&mut self.buf[self.filled..])
+                    .await
+                    .map_err(SerialError::Io)?;
+                if read == 0 {
+                    return Err(SerialError::Closed);
+                }
+                self.filled += read;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::{
+        sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+        time::Instant,
+    };
+
+    use crate::{
+        client::ClientConfig, offset_tracker::Observation, status::StatusReporter, NoEE,
+        ResetCause, TickRate,
+    };
+
+    // One end of an in-memory connection.
+    struct Duplex {
+        tx: UnboundedSender<std::vec::Vec<u8>>,
+        rx: UnboundedReceiver<std::vec::Vec<u8>>,
+    }
+
+    fn duplex() -> (Duplex, Duplex) {
+        let (client_tx, server_rx) = mpsc::unbounded_channel();
+        let (server_tx, client_rx) = mpsc::unbounded_channel();
+        (
+            Duplex {
+                tx: client_tx,
+                rx: client_rx,
+            },
+            Duplex {
+                tx: server_tx,
+                rx: server_rx,
+            },
+        )
+    }
+
+    impl Transport for Duplex {
+        type Address = ();
+        type Error = ();
+
+        async fn send(&mut self, _: &(), bytes: &[u8]) -> Result<(), ()> {
+            self.tx.send(bytes.to_vec()).map_err(|_| ())
+        }
+
+        async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, ()), ()> {
+            let bytes = self.rx.recv().await.ok_or(())?;
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok((bytes.len(), ()))
+        }
+    }
+
+    // Milliseconds since the clock was created.
+    struct TestClock(Instant);
+
+    impl Clock for TestClock {
+        fn now_ticks(&self) -> u64 {
+            self.0.elapsed().as_millis() as u64
+        }
+    }
+
+    impl Timer for TestClock {
+        async fn sleep_until(&self, ticks: u64) {
+            tokio::time::sleep_until(self.0 + core::time::Duration::from_millis(ticks)).await
+        }
+    }
+
+    type Client = ClientEngine<(), u8, u8, 1, 2, 4, 32>;
+    type Server = ServerEngine<u8, u8, 4, 2, 4, 32>;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_end_to_end() {
+        let (mut client_transport, mut server_transport) = duplex();
+        let clock = TestClock(Instant::now());
+        let client = RefCell::new(Client::new(ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 10,
+            client_time: false,
+            max_reply_len: false,
+        }));
+        client.borrow_mut().add_server((), 100).unwrap();
+        let server = RefCell::new(Server::new(
+            EventLog::new(10),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        ));
+        server.borrow_mut().log_mut().push(1, 0);
+
+        // The events delivered, and what the client learnt from them.
+        let (delivered_tx, mut delivered) = mpsc::unbounded_channel();
+        let deliver = |delivery: Delivery<(), EventOf<u8, NoEE>, u32, 4>| {
+            for reply in delivery.replies {
+                let _ = delivered_tx.send(reply);
+            }
+        };
+        // Commands are logged as events.
+        let execute = |command: &u8, log: &mut EventLog<u8, 4>| {
+            log.push(*command, 0);
+            Ok::<_, ()>(())
+        };
+
+        let scenario = async {
+            let mut next = async || {
+                let (reply, observation) = delivered.recv().await.unwrap();
+                (reply.event.unwrap(), observation)
+            };
+            assert!(matches!(next().await, (EventOf::Status(_), _)));
+            assert_eq!(
+                next().await,
+                (EventOf::Logged(1, 10), Observation::NewEvent)
+            );
+
+            // A command is conveyed, and the event it logs delivered.
+            client.borrow_mut().command(&(), 7).unwrap();
+            assert_eq!(
+                next().await,
+                (EventOf::Logged(7, 11), Observation::NewEvent)
+            );
+
+            // The server forgets its events, and so the client recovers.
+            server.borrow_mut().log_mut().reset(50);
+            server.borrow_mut().log_mut().push(9, 0);
+            assert!(matches!(
+                next().await,
+                (
+                    EventOf::Recovery(50, 50),
+                    Observation::RecoveryNeeded { .. }
+                )
+            ));
+            server.borrow_mut().log_mut().push(10, 0);
+            assert_eq!(
+                next().await,
+                (EventOf::Logged(10, 51), Observation::NewEvent)
+            );
+            assert!(client.borrow().tracker(&()).unwrap().is_synchronised());
+        };
+
+        tokio::select! {
+            _ = run_client(&client, &mut client_transport, &clock, deliver) => panic!("client stopped"),
+            _ = run_server(&server, &mut server_transport, &clock, execute) => panic!("server stopped"),
+            _ = scenario => (),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[tokio::test]
+    async fn test_udp_loopback() {
+        use udp::{UdpClientTransport, UdpServerTransport};
+
+        let mut server_transport = UdpServerTransport::<32>::bind(([127, 0, 0, 1], 0).into())
+            .await
+            .unwrap();
+        let server_addr = server_transport.local_addr().unwrap();
+        let mut client_transport = UdpClientTransport::<32>::connect(server_addr)
+            .await
+            .unwrap();
+        assert!(UdpClientTransport::<32>::fits::<
+            crate::MultiCommandRequest<u8, 2>,
+        >());
+
+        let clock = TokioClock::new(TickRate::MILLISECONDS);
+        let client = RefCell::new(ClientEngine::<_, u8, u8, 1, 2, 4, 32>::new(ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 100,
+            client_time: false,
+            max_reply_len: false,
+        }));
+        client.borrow_mut().add_server(server_addr, 10).unwrap();
+        let server = RefCell::new(Server::new(
+            EventLog::new(10),
+            StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+        ));
+        server.borrow_mut().log_mut().push(1, 0);
+
+        let (delivered_tx, mut delivered) = mpsc::unbounded_channel();
+        let deliver = |delivery: Delivery<_, EventOf<u8, NoEE>, u32, 4>| {
+            for (reply, _) in delivery.replies {
+                let _ = delivered_tx.send(reply.event);
+            }
+        };
+        let execute = |command: &u8, log: &mut EventLog<u8, 4>| {
+            log.push(*command, 0);
+            Ok::<_, ()>(())
+        };
+
+        // The server's status and event are delivered over the loopback
+        // interface, and then the event logged by a command.
+        let scenario = async {
+            assert!(matches!(
+                delivered.recv().await,
+                Some(Some(EventOf::Status(_)))
+            ));
+            assert_eq!(delivered.recv().await, Some(Some(EventOf::Logged(1, 10))));
+            client.borrow_mut().command(&server_addr, 7).unwrap();
+            assert_eq!(delivered.recv().await, Some(Some(EventOf::Logged(7, 11))));
+        };
+
+        tokio::select! {
+            e = run_client(&client, &mut client_transport, &clock, deliver) => panic!("client stopped: {e}"),
+            e = run_server(&server, &mut server_transport, &clock, execute) => panic!("server stopped: {e}"),
+            r = tokio::time::timeout(core::time::Duration::from_secs(5), scenario) => r.unwrap(),
+        }
+        assert!(client
+            .borrow()
+            .tracker(&server_addr)
+            .unwrap()
+            .is_synchronised());
+    }
+
+    #[cfg(feature = "embassy")]
+    #[tokio::test]
+    async fn test_embassy_clock() {
+        let started = EmbassyClock.now_ticks();
+        let until =
+            started + EmbassyClock::TICK_RATE.from_duration(core::time::Duration::from_millis(10));
+        EmbassyClock.sleep_until(until).await;
+        assert!(EmbassyClock.now_ticks() >= until);
+    }
+
+    #[cfg(feature = "serial")]
+    #[tokio::test]
+    async fn test_serial_framing() {
+        use std::collections::VecDeque;
+
+        // Bytes written are read back, a few at a time.
+        struct Loopback(VecDeque<u8>);
+
+        impl embedded_io_async::ErrorType for Loopback {
+            type Error = core::convert::Infallible;
+        }
+
+        impl embedded_io_async::Read for Loopback {
+            async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+                let len = buf.len().min(self.0.len()).min(3);
+                for (b, byte) in buf.iter_mut().zip(self.0.drain(..len)) {
+                    *b = byte;
+                }
+                Ok(len)
+            }
+        }
+
+        impl embedded_io_async::Write for Loopback {
+            async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+                self.0.extend(buf);
+                Ok(buf.len())
+            }
+        }
+
+        let mut transport = SerialTransport::<_, 8>::new(Loopback(VecDeque::new()));
+        transport.send(&(), &[1, 2, 3, 4]).await.unwrap();
+        transport.send(&(), &[]).await.unwrap();
+        transport.send(&(), &[5, 6]).await.unwrap();
+        assert_eq!(
+            transport.send(&(), &[0; 8]).await,
+            Err(SerialError::TooLong)
+        );
+
+        let mut buf = [0; 8];
+        assert_eq!(transport.recv(&mut buf).await, Ok((4, ())));
+        assert_eq!(buf[..4], [1, 2, 3, 4]);
+        assert_eq!(transport.recv(&mut buf).await, Ok((0, ())));
+        assert_eq!(transport.recv(&mut buf).await, Ok((2, ())));
+        assert_eq!(buf[..2], [5, 6]);
+        assert_eq!(transport.recv(&mut buf).await, Err(SerialError::Closed));
+    }
+}
@@ -0,0 +1,267 @@
+//! Runs a client and its server through 10k polls of a bus whose datagrams
+//! are lost, duplicated, reordered, delayed and corrupted, as conveyed by
+//! [FaultyTransport]s wrapping the transports that the engines are driven
+//! with, asserting that the invariants of the protocol hold. Each run is
+//! decided by its seed, which is told of should an assertion fail.
+
+use std::{cell::RefCell, time::Duration};
+
+use flip_flop_app::{
+    client::{ClientConfig, ClientEngine, Delivery},
+    clock::Clock,
+    event_log::EventLog,
+    harness::{Faults, FaultyTransport},
+    offset_tracker::Observation,
+    server::ServerEngine,
+    status::StatusReporter,
+    transport::{run_client, run_server, Timer, Transport},
+    EventOf, EventReply, NoEE, ResetCause, TickRate,
+};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::Instant,
+};
+
+type Client = ClientEngine<(), u8, u16, 1, 2, 4, 32>;
+type Server = ServerEngine<u8, u16, 256, 2, 4, 32>;
+type Delivered = (EventReply<EventOf<u16, NoEE>>, Observation);
+
+const POLLS: u64 = 10_000;
+const POLL_INTERVAL_TICKS: u64 = 10;
+// The server logs an event every so many ticks, and a command is queued
+// every so many events.
+const EVENT_EVERY_TICKS: u64 = 7;
+const COMMAND_EVERY_EVENTS: u32 = 70;
+
+// One end of an in-memory connection.
+struct Duplex {
+    tx: UnboundedSender<Vec<u8>>,
+    rx: UnboundedReceiver<Vec<u8>>,
+}
+
+fn duplex() -> (Duplex, Duplex) {
+    let (client_tx, server_rx) = mpsc::unbounded_channel();
+    let (server_tx, client_rx) = mpsc::unbounded_channel();
+    (
+        Duplex {
+            tx: client_tx,
+            rx: client_rx,
+        },
+        Duplex {
+            tx: server_tx,
+            rx: server_rx,
+        },
+    )
+}
+
+impl Transport for Duplex {
+    type Address = ();
+    type Error = ();
+
+    async fn send(&mut self, _: &(), bytes: &[u8]) -> Result<(), ()> {
+        self.tx.send(bytes.to_vec()).map_err(|_| ())
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<(usize, ()), ()> {
+        let bytes = self.rx.recv().await.ok_or(())?;
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok((bytes.len(), ()))
+    }
+}
+
+// Milliseconds since the clock was created.
+#[derive(Clone, Copy)]
+struct TestClock(Instant);
+
+impl Clock for TestClock {
+    fn now_ticks(&self) -> u64 {
+        self.0.elapsed().as_millis() as u64
+    }
+}
+
+impl Timer for TestClock {
+    async fn sleep_until(&self, ticks: u64) {
+        tokio::time::sleep_until(self.0 + Duration::from_millis(ticks)).await
+    }
+}
+
+// The client and server of a run, and what became of it.
+struct Run {
+    seed: u64,
+    clock: TestClock,
+    client: RefCell<Client>,
+    server: RefCell<Server>,
+    client_transport: FaultyTransport<Duplex, TestClock>,
+    server_transport: FaultyTransport<Duplex, TestClock>,
+    delivered: RefCell<Vec<Delivered>>,
+    queued: RefCell<Vec<u8>>,
+    executed: RefCell<Vec<u8>>,
+}
+
+impl Run {
+    fn new(seed: u64) -> Self {
+        let clock = TestClock(Instant::now());
+        let (client_transport, server_transport) = duplex();
+        let client = Client::new(ClientConfig {
+            tick_rate: TickRate::MILLISECONDS,
+            reply_timeout_ticks: 5,
+            client_time: false,
+            max_reply_len: false,
+        });
+        let mut client = RefCell::new(client);
+        client
+            .get_mut()
+            .add_server((), POLL_INTERVAL_TICKS)
+            .unwrap();
+        Self {
+            seed,
+            clock,
+            client,
+            server: RefCell::new(Server::new(
+                EventLog::new(0),
+                StatusReporter::new(0, TickRate::MILLISECONDS, ResetCause::PowerOn),
+            )),
+            client_transport: FaultyTransport::new(client_transport, clock, seed),
+            server_transport: FaultyTransport::new(server_transport, clock, !seed),
+            delivered: RefCell::new(Vec::new()),
+            queued: RefCell::new(Vec::new()),
+            executed: RefCell::new(Vec::new()),
+        }
+    }
+
+    // Runs the engines for the polls given, the server logging events and
+    // commands being queued as given.
+    async fn run(&mut self, polls: u64, log_events: bool, queue_commands: bool) {
+        let deliver = |delivery: Delivery<(), EventOf<u16, NoEE>, u32, 4>| {
+            self.delivered.borrow_mut().extend(delivery.replies);
+        };
+        let execute = |command: &u8, log: &mut EventLog<u16, 256>| {
+            self.executed.borrow_mut().push(*command);
+            log.push(u16::from(*command), 0);
+            Ok::<_, ()>(())
+        };
+        let scenario = async {
+            let until = self.clock.now_ticks() + polls * POLL_INTERVAL_TICKS;
+            if !log_events {
+                self.clock.sleep_until(until).await;
+                return;
+            }
+            let mut ticks = self.clock.now_ticks();
+            for event in 1.. {
+                ticks += EVENT_EVERY_TICKS;
+                if ticks >= until {
+                    break;
+                }
+                self.clock.sleep_until(ticks).await;
+                self.server.borrow_mut().log_mut().push(1_000, 0);
+                if queue_commands && event % COMMAND_EVERY_EVENTS == 0 {
+                    let command = (event / COMMAND_EVERY_EVENTS) as u8;
+                    if self.client.borrow_mut().command(&(), command).is_ok() {
+                        self.queued.borrow_mut().push(command);
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            _ = run_client(&self.client, &mut self.client_transport, &self.clock, deliver) => panic!("client stopped"),
+            _ = run_server(&self.server, &mut self.server_transport, &self.clock, execute) => panic!("server stopped"),
+            _ = scenario => (),
+        }
+    }
+
+    // Runs the engines without faults until the client has every event of
+    // the server.
+    async fn settle(&mut self) {
+        self.client_transport.set_faults(Faults::NONE);
+        self.server_transport.set_faults(Faults::NONE);
+        self.run(100, false, false).await;
+        assert_eq!(
+            self.client.borrow().tracker(&()).unwrap().request(),
+            self.server.borrow().log().end_offset(),
+            "seed {}",
+            self.seed
+        );
+    }
+
+    // The offsets of the logged events delivered as new, in the order
+    // delivered.
+    fn delivered_offsets(&self) -> Vec<u32> {
+        self.delivered
+            .borrow()
+            .iter()
+            .filter(|(_, o)| *o == Observation::NewEvent)
+            .filter_map(|(reply, _)| match reply.event {
+                Some(EventOf::Logged(_, offset)) => Some(offset),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Requests are lost, delayed and reordered, and replies are also
+// duplicated, the protocol relying on the data link layer's frame counter
+// to guard against requests being duplicated.
+const REQUESTS: Faults = Faults {
+    loss: 0.05,
+    duplication: 0.0,
+    corruption: 0.0,
+    min_latency_ticks: 0,
+    max_latency_ticks: 3,
+    reorder: 0.02,
+    reorder_window: 2,
+};
+const REPLIES: Faults = Faults {
+    duplication: 0.02,
+    ..REQUESTS
+};
+
+#[tokio::test(start_paused = true)]
+async fn test_exactly_once_given_faults() {
+    for seed in 1..=2 {
+        let mut run = Run::new(seed);
+        run.server_transport.set_faults(REQUESTS);
+        run.client_transport.set_faults(REPLIES);
+        run.run(POLLS, true, true).await;
+        run.settle().await;
+
+        // Each event is delivered once and in order, without the client
+        // having to recover, and each command is executed once at most and
+        // in the order queued.
+        let end_offset = run.server.borrow().log().end_offset().unwrap();
+        assert_eq!(
+            run.delivered_offsets(),
+            (0..=end_offset).collect::<Vec<_>>(),
+            "seed {seed}"
+        );
+        assert!(
+            !run.delivered
+                .borrow()
+                .iter()
+                .any(|(_, o)| matches!(o, Observation::RecoveryNeeded { .. })),
+            "seed {seed}"
+        );
+        let executed = run.executed.borrow();
+        let queued = run.queued.borrow();
+        assert!(executed.len() > 100, "seed {seed}");
+        assert!(executed.windows(2).all(|w| w[0] < w[1]), "seed {seed}");
+        assert!(executed.iter().all(|c| queued.contains(c)), "seed {seed}");
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_recovery_from_corruption() {
+    // Datagrams corrupted beneath the application layer are decoded as
+    // whatever they may be, and yet the engines carry on, the client
+    // catching up once datagrams are no longer corrupted.
+    for seed in 1..=2 {
+        let mut run = Run::new(seed);
+        let corrupt = Faults {
+            corruption: 0.01,
+            ..REQUESTS
+        };
+        run.server_transport.set_faults(corrupt);
+        run.client_transport.set_faults(corrupt);
+        run.run(POLLS, true, false).await;
+        run.settle().await;
+    }
+}
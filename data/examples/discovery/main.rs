@@ -9,115 +9,121 @@ use ccm::{
     Ccm,
 };
 use flip_flop_data::discovery::{
-    Identified, Identify, MAX_ADDRESSES, MIN_PACKET_SIZE, MIN_PAYLOAD_SIZE,
+    DiscoveryClient, DiscoveryServer, Identify, IdentifyReply, MAX_IDENTIFY_PAYLOAD_SIZE,
+    MIN_PAYLOAD_SIZE,
+};
+use flip_flop_data::port::{Port, PortSet};
+use flip_flop_data::{
+    from_datagram, to_datagram, DataSource, Header, NonceScheme, HEADER_SIZE, MIC_SIZE,
 };
-use flip_flop_data::{from_datagram, to_datagram, DataSource, Header};
 use futures::future;
+use postcard::experimental::max_size::MaxSize;
 use tokio::sync::broadcast;
 use tokio::time;
 
 type AesCcm = Ccm<Aes128, U4, U7>;
 
+// Packets are sized for the largest identify message, as the client reports
+// contested addresses within it.
+const PACKET_SIZE: usize = HEADER_SIZE + MAX_IDENTIFY_PAYLOAD_SIZE + MIC_SIZE;
+
+// Replies are sent within a single packet, and so must fit its payload.
+const _: () = assert!(IdentifyReply::POSTCARD_MAX_SIZE <= MIN_PAYLOAD_SIZE);
+
 const CLIENT_TIME_WINDOW: Duration = Duration::from_millis(1000);
-const SERVER_REPLY_WINDOW: Duration = Duration::from_millis(900);
+// Servers reply within a 900ms window divided into 300 slots of 3ms, each
+// being a 1ms guard followed by 2ms for a reply on the wire.
+const SERVER_REPLY_SLOTS: u32 = 300;
+const SERVER_REPLY_FRAME_MS: u32 = 2;
+const SERVER_REPLY_GUARD_MS: u32 = 1;
 
 mod client {
 
     use super::*;
 
     pub async fn task(
-        tx: &broadcast::Sender<[u8; MIN_PACKET_SIZE]>,
-        identify: &mut Identify,
+        tx: &broadcast::Sender<[u8; PACKET_SIZE]>,
+        discovery: &mut DiscoveryClient,
         frame_counter: u16,
-    ) -> bool {
-        let mut finished = true;
-
+    ) {
         let key = GenericArray::from_slice(b"0000000000000000");
         let cipher = AesCcm::new(key);
 
-        let mut datagram_buf = [0u8; MIN_PACKET_SIZE];
-
-        create_client_request(&cipher, identify, frame_counter, &mut datagram_buf);
-        if tx.send(datagram_buf).is_ok() {
-            println!("CLIENT {frame_counter}: sent identify request. Waiting one second for all replies.");
-
-            let mut rx = tx.subscribe();
-            let time_window = time::timeout(CLIENT_TIME_WINDOW, future::pending::<()>());
-            tokio::pin!(time_window);
-
-            let mut addresses = [0; MAX_ADDRESSES];
-            loop {
-                tokio::select! {
-                    r = rx.recv() => if let Ok(encrypted_payload) = r {
-                        if let Some(identified) = process_server_reply(&cipher, &encrypted_payload) {
-                            addresses[identified.server_address as usize] += 1;
+        let mut datagram_buf = [0u8; PACKET_SIZE];
+
+        if let Some(identify) = discovery.poll_transmit() {
+            create_client_request(&cipher, &identify, frame_counter, &mut datagram_buf);
+            if tx.send(datagram_buf).is_ok() {
+                println!("CLIENT {frame_counter}: sent identify request. Waiting one second for all replies.");
+
+                let mut rx = tx.subscribe();
+                let time_window = time::timeout(CLIENT_TIME_WINDOW, future::pending::<()>());
+                tokio::pin!(time_window);
+
+                loop {
+                    tokio::select! {
+                        r = rx.recv() => if let Ok(encrypted_payload) = r {
+                            match process_server_reply(&cipher, &encrypted_payload) {
+                                Some(IdentifyReply::Identified(identified)) => {
+                                    discovery.handle_reply(&identified);
+                                }
+                                Some(IdentifyReply::Deferred { retry_after_rounds }) => discovery.handle_deferral(retry_after_rounds),
+                                None => discovery.handle_corrupt_reply(),
+                            }
                         } else {
-                            finished = false;
+                            break
+                        },
+                        _ = &mut time_window => {
+                            println!("CLIENT {frame_counter}: time window finished.");
+                            break;
                         }
-                    } else {
-                        break
-                    },
-                    _ = &mut time_window => {
-                        println!("CLIENT {frame_counter}: time window finished.");
-                        break;
                     }
                 }
             }
-            for (address, count) in addresses.iter().enumerate() {
-                let count = *count;
-                if count == 1 {
-                    identify.set_address(address as u8);
-                } else if count > 1 {
-                    finished = false;
-                }
-            }
-            let found = identify.iter().fold(0, |mut a, e| {
-                if e {
-                    a += 1;
-                    a
-                } else {
-                    a
-                }
-            }) - 1;
+            discovery.window_elapsed();
+
+            let found = discovery.identify().count_set() - 1;
             println!("CLIENT {frame_counter}: Found: {found}.");
         }
-
-        finished
     }
 
     fn create_client_request(
-        cipher: &impl AeadInPlace,
+        cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
         identify: &Identify,
         frame_counter: u16,
-        datagram_buf: &mut [u8; MIN_PACKET_SIZE],
+        datagram_buf: &mut [u8; PACKET_SIZE],
     ) {
         let header = Header {
             version: 0,
             source: DataSource::Client,
             server_address: 0,
-            server_port: 0,
+            server_port: Port::new(0).unwrap(),
             frame_counter,
         };
 
         to_datagram(
             cipher,
             &header,
-            &postcard::to_vec::<Identify, MIN_PAYLOAD_SIZE>(identify).unwrap(),
+            &postcard::to_vec::<Identify, MAX_IDENTIFY_PAYLOAD_SIZE>(identify).unwrap(),
             datagram_buf,
         );
     }
 
     fn process_server_reply(
-        cipher: &impl AeadInPlace,
-        datagram_buf: &[u8; MIN_PACKET_SIZE],
-    ) -> Option<Identified> {
+        cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+        datagram_buf: &[u8; PACKET_SIZE],
+    ) -> Option<IdentifyReply> {
         from_datagram(
             datagram_buf,
-            |h| h.server_address == 0x00 && h.server_port == 0x00 && h.source == DataSource::Server,
+            |h| {
+                h.server_address == 0x00
+                    && h.server_port == Port::new(0x00).unwrap()
+                    && h.source == DataSource::Server
+            },
             cipher,
         )
         .ok()
-        .and_then(|(_, b)| postcard::from_bytes::<Identified>(&b).ok())
+        .and_then(|(_, b)| postcard::from_bytes::<IdentifyReply>(&b).ok())
     }
 }
 
@@ -125,32 +131,23 @@ mod server {
     use super::*;
 
     pub async fn task(
-        tx: broadcast::Sender<[u8; MIN_PACKET_SIZE]>,
+        tx: broadcast::Sender<[u8; PACKET_SIZE]>,
         frame_counter: u16,
-        server_address: &mut Option<u8>,
+        discovery: &mut DiscoveryServer,
     ) {
         let key = GenericArray::from_slice(b"0000000000000000");
         let cipher = AesCcm::new(key);
 
-        let mut datagram_buf = [0u8; MIN_PACKET_SIZE];
+        let mut datagram_buf = [0u8; PACKET_SIZE];
 
         let mut rx = tx.subscribe();
         if let Ok(encrypted_payload) = rx.recv().await {
             if let Some(identify) = process_client_request(&cipher, &encrypted_payload) {
-                if server_address
-                    .map(|sa| !identify.is_address_set(sa))
-                    .unwrap_or(true)
-                {
-                    if let Some(identified) = try_create_server_reply(
-                        &cipher,
-                        &identify,
-                        frame_counter,
-                        &mut datagram_buf,
-                    ) {
-                        *server_address = Some(identified.server_address);
-                        time::sleep(SERVER_REPLY_WINDOW).await;
-                        let _ = tx.send(datagram_buf);
-                    }
+                let reply = discovery.handle_identify(&identify, &mut rand::thread_rng());
+                if let Some(reply) = reply {
+                    create_server_reply(&cipher, &reply.reply, frame_counter, &mut datagram_buf);
+                    time::sleep(Duration::from_millis(reply.delay_ticks as u64)).await;
+                    let _ = tx.send(datagram_buf);
                 }
             }
         }
@@ -158,45 +155,41 @@ mod server {
 
     fn process_client_request(
         cipher: &AesCcm,
-        datagram_buf: &[u8; MIN_PACKET_SIZE],
+        datagram_buf: &[u8; PACKET_SIZE],
     ) -> Option<Identify> {
         from_datagram(
             datagram_buf,
-            |h| h.server_address == 0x00 && h.server_port == 0x00 && h.source == DataSource::Client,
+            |h| {
+                h.server_address == 0x00
+                    && h.server_port == Port::new(0x00).unwrap()
+                    && h.source == DataSource::Client
+            },
             cipher,
         )
         .ok()
         .and_then(|(_, b)| postcard::from_bytes::<Identify>(&b).ok())
     }
 
-    fn try_create_server_reply(
+    fn create_server_reply(
         cipher: &AesCcm,
-        identify: &Identify,
+        reply: &IdentifyReply,
         frame_counter: u16,
-        datagram_buf: &mut [u8; MIN_PACKET_SIZE],
-    ) -> Option<Identified> {
-        if let Some(identified) =
-            Identified::with_random_address(identify.iter(), &mut rand::thread_rng(), 0b00000010)
-        {
-            let header = Header {
-                version: 0,
-                source: DataSource::Server,
-                server_address: 0,
-                server_port: 0,
-                frame_counter,
-            };
-
-            to_datagram(
-                cipher,
-                &header,
-                &postcard::to_vec::<Identified, MIN_PAYLOAD_SIZE>(&identified).unwrap(),
-                datagram_buf,
-            );
-
-            Some(identified)
-        } else {
-            None
-        }
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) {
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 0,
+            server_port: Port::new(0).unwrap(),
+            frame_counter,
+        };
+
+        to_datagram(
+            cipher,
+            &header,
+            &postcard::to_vec::<IdentifyReply, MIN_PAYLOAD_SIZE>(reply).unwrap(),
+            datagram_buf,
+        );
     }
 }
 
@@ -208,24 +201,27 @@ async fn main() {
         let task_tx = tx.clone();
         tokio::spawn(async move {
             let mut frame_counter = 0u16;
-            let mut server_address = None;
+            let mut discovery = DiscoveryServer::new(
+                PortSet::from_bits(0b00000010),
+                SERVER_REPLY_SLOTS,
+                SERVER_REPLY_FRAME_MS,
+                SERVER_REPLY_GUARD_MS,
+            )
+            .unwrap();
             loop {
-                server::task(task_tx.clone(), frame_counter, &mut server_address).await;
+                server::task(task_tx.clone(), frame_counter, &mut discovery).await;
                 frame_counter = frame_counter.wrapping_add(1);
             }
         });
     }
 
-    let addresses = [0; MIN_PAYLOAD_SIZE];
-    let mut identify = Identify { addresses };
-    identify.set_address(0); // Address 0 is the client and is therefore reserved
+    // Address 0 is the client and is reserved by the discovery client.
+    let mut discovery = DiscoveryClient::new(<Identify>::new());
     let mut frame_counter = 0u16;
-    loop {
-        if client::task(&tx, &mut identify, frame_counter).await {
-            break;
-        }
+    while !discovery.is_complete() {
+        client::task(&tx, &mut discovery, frame_counter).await;
         frame_counter = frame_counter.wrapping_add(1);
     }
 
-    println!("Finished in {} seconds", frame_counter + 1);
+    println!("Finished in {} seconds", discovery.rounds());
 }
@@ -1,6 +1,11 @@
 use std::env;
 
-fn help(name: &str) -> i32 {
+use flip_flop_data::discovery::{
+    analysis::{self, Phase},
+    MAX_CONTESTED_ADDRESSES,
+};
+
+fn help(name: &str) -> u32 {
     {
         println!("usage: {} stations [time_slots [addresses]]", name);
         0
@@ -24,37 +29,39 @@ fn main() {
     };
 }
 
-fn simulate(mut stations: i32, slots: i32, mut addresses: i32) -> i32 {
-    let mut i = 1;
+fn simulate(stations: u32, slots: u32, addresses: u32) -> u32 {
+    let mut rounds = 0;
 
-    while stations > 0 {
+    let mut simulation = analysis::simulate(stations, slots, addresses);
+    for round in simulation.by_ref() {
         println!("-----");
-        println!("Round {i}:");
-        let received = round(stations, slots);
-        let assigned = round(received, addresses);
-        stations -= assigned;
-        addresses -= assigned;
-        i += 1;
+        println!("Round {}:", round.round);
+        print_phase(&round.received);
+        print_phase(&round.assigned);
+        // Servers that replied but were not assigned an address contested
+        // one. They are reported as such in the next identify message and
+        // rejoin discovery immediately, as do any not reported given the
+        // report's bounds and that their address remains unknown.
+        println!(
+            "Contested servers = {} (reported up to {MAX_CONTESTED_ADDRESSES} addresses)",
+            round.contested
+        );
+        rounds = round.round;
     }
-
-    i - 1
-}
-
-fn round(n: i32, m: i32) -> i32 {
-    // Probability of no collision.
-    let mut p_bar = 1.0;
-
-    for i in 1..n {
-        p_bar = p_bar * (1.0 - i as f64 / m as f64);
+    if simulation.unassigned() > 0 {
+        println!("-----");
+        println!(
+            "Discovery does not converge: {} servers are not expected to be assigned an address",
+            simulation.unassigned()
+        );
     }
 
-    // Probability of collision.
-    let p = 1.0 - p_bar;
-
-    // Expected non collisions
-    let e = n as f64 * (1.0 - 1.0 / m as f64).powi(n - 1);
-
-    println!("For {n} of {m} Prob collision = {p:0.2} Expected successes = {e:0.1}");
+    rounds
+}
 
-    e.round() as i32
+fn print_phase(phase: &Phase) {
+    println!(
+        "For {} of {} Prob collision = {:0.2} Expected successes = {:0.1}",
+        phase.n, phase.m, phase.collision_probability, phase.expected_successes
+    );
 }
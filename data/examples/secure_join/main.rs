@@ -0,0 +1,167 @@
+use aead::KeyInit;
+use aes::Aes128;
+use ccm::aead::generic_array::GenericArray;
+use ccm::{
+    consts::{U4, U7},
+    Ccm,
+};
+use flip_flop_data::discovery::join::{
+    Commissioner, JoinGrant, JoinRequest, Joiner, NetworkKey, ProvisioningKey, ServerKeys, Uid,
+};
+use flip_flop_data::discovery::{
+    DiscoveryClient, DiscoveryServer, Identify, MAX_IDENTIFY_PAYLOAD_SIZE,
+};
+use flip_flop_data::port::{Port, PortSet};
+use flip_flop_data::{
+    from_datagram, from_datagram_with_provider, to_datagram, DataSource, Header, HEADER_SIZE,
+    MIC_SIZE,
+};
+
+type AesCcm = Ccm<Aes128, U4, U7>;
+
+// Packets are sized for the largest identify message, as the client may
+// report contested addresses within it.
+const PACKET_SIZE: usize = HEADER_SIZE + MAX_IDENTIFY_PAYLOAD_SIZE + MIC_SIZE;
+
+// The provisioning keys that the client has been told of e.g. by an
+// installer scanning the QR code on each device's label.
+const KNOWN_DEVICES: [(Uid, ProvisioningKey); 2] = [
+    (*b"SN000001", ProvisioningKey(*b"label-key-000001")),
+    (*b"SN000002", ProvisioningKey(*b"label-key-000002")),
+];
+
+const NETWORK_KEYS: [NetworkKey; 2] = [
+    NetworkKey(*b"network-key-0001"),
+    NetworkKey(*b"network-key-0002"),
+];
+
+fn main() {
+    // Discovery frames still use the well known key, but requests to join
+    // are sealed under each device's provisioning key.
+    let discovery_cipher = AesCcm::new(GenericArray::from_slice(b"0000000000000000"));
+    let mut rng = rand::thread_rng();
+
+    let mut client = DiscoveryClient::new(<Identify>::new());
+    let mut commissioner = Commissioner::new(|uid: &Uid| {
+        KNOWN_DEVICES
+            .iter()
+            .find(|(known, _)| known == uid)
+            .map(|(_, key)| key.clone())
+    });
+
+    // The second device has been given the wrong provisioning key e.g. it
+    // is an impostor.
+    let mut devices = [
+        (
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1).unwrap(),
+            Joiner::new(*b"SN000001", ProvisioningKey(*b"label-key-000001")),
+        ),
+        (
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1).unwrap(),
+            Joiner::new(*b"SN000002", ProvisioningKey(*b"not-the-real-key")),
+        ),
+    ];
+
+    let identify = client.poll_transmit().unwrap();
+    let mut requests = Vec::new();
+    for (server, joiner) in devices.iter_mut() {
+        if let Some(reply) = server.handle_identify(&identify, &mut rng) {
+            let request = joiner.join_request::<AesCcm, _>(reply.identified().unwrap(), &mut rng);
+            let datagram = transmit(&discovery_cipher, DataSource::Server, 0, &request);
+            let request: JoinRequest = receive(&discovery_cipher, &datagram).unwrap();
+            match commissioner.open_request::<AesCcm>(&request) {
+                Ok(identified) => {
+                    println!(
+                        "CLIENT: {} requests address {}.",
+                        uid_str(&request.uid),
+                        identified.server_address
+                    );
+                    client.handle_reply(&identified);
+                    requests.push((identified.server_address, request));
+                }
+                Err(e) => println!("CLIENT: {} rejected: {e:?}.", uid_str(&request.uid)),
+            }
+        }
+    }
+    client.window_elapsed();
+
+    // The client decodes the datagrams of each server that has joined with
+    // the key it granted the server.
+    let mut server_keys = ServerKeys::<AesCcm, 2>::new();
+
+    for ((server_address, request), network_key) in requests.iter().zip(NETWORK_KEYS) {
+        if !client.identify().is_address_set(*server_address) {
+            continue;
+        }
+        let grant = commissioner
+            .grant::<AesCcm, _>(request, &network_key, &mut rng)
+            .unwrap();
+        server_keys.insert(*server_address, &network_key).unwrap();
+        let datagram = transmit(
+            &discovery_cipher,
+            DataSource::Client,
+            *server_address,
+            &grant,
+        );
+
+        for (server, joiner) in devices.iter_mut() {
+            if server.server_address() != Some(*server_address) {
+                continue;
+            }
+            let grant: JoinGrant = receive(&discovery_cipher, &datagram).unwrap();
+            match joiner.handle_grant::<AesCcm>(&grant) {
+                Ok(_) => {
+                    let cipher: AesCcm = joiner.cipher().unwrap();
+                    println!("SERVER {server_address}: joined, now using its network key.");
+                    let datagram = transmit(&cipher, DataSource::Server, *server_address, &"hello");
+                    let (header, _) =
+                        from_datagram_with_provider(&datagram, |_| true, &server_keys).unwrap();
+                    println!(
+                        "CLIENT: decoded a datagram of server {} with its key.",
+                        header.server_address
+                    );
+                }
+                Err(e) => println!("SERVER {server_address}: grant rejected: {e:?}."),
+            }
+        }
+    }
+
+    for (server, joiner) in devices.iter() {
+        println!(
+            "Server at {:?} has joined: {}",
+            server.server_address(),
+            joiner.network_key().is_some()
+        );
+    }
+}
+
+fn transmit(
+    cipher: &AesCcm,
+    source: DataSource,
+    server_address: u8,
+    payload: &impl serde::Serialize,
+) -> [u8; PACKET_SIZE] {
+    let header = Header {
+        version: 0,
+        source,
+        server_address,
+        server_port: Port::new(0).unwrap(),
+        frame_counter: 0,
+    };
+    let payload = postcard::to_vec::<_, PACKET_SIZE>(payload).unwrap();
+    let mut datagram = [0; PACKET_SIZE];
+    to_datagram(cipher, &header, &payload, &mut datagram);
+    datagram
+}
+
+fn receive<T>(cipher: &AesCcm, datagram: &[u8; PACKET_SIZE]) -> Option<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let (_, payload) = from_datagram(datagram, |_| true, cipher).ok()?;
+    postcard::from_bytes(&payload).ok()
+}
+
+fn uid_str(uid: &Uid) -> &str {
+    core::str::from_utf8(uid).unwrap_or("?")
+}
@@ -9,10 +9,21 @@ use ccm::{
     Ccm,
 };
 use flip_flop_data::{
-    discovery::{MIN_PACKET_SIZE, MIN_PAYLOAD_SIZE},
-    from_datagram, to_datagram,
-    update::{PrepareForUpdate, Update, UpdateKey, Version, UPDATE_BYTES_OVERHEAD},
-    DataSource, Header,
+    discovery::MIN_PAYLOAD_SIZE,
+    from_datagram,
+    port::{Port, PortSet},
+    to_datagram,
+    update::{
+        delta::{delta_updates, generate_patch, COPY_INSERT_PATCH_FORMAT},
+        signing::{sign_update, SigningKey, VerifyingKey},
+        to_update_datagram, Compression, Delta, EligibilityReport, EligibilitySurvey,
+        EligibilityTally, MissingRanges, Preparation, PrepareForUpdate, RetransmitPlan, SendStep,
+        ServerPacing, StagingWriter, Update, UpdateEvent, UpdateIntegrity, UpdateKey,
+        UpdateMessage, UpdatePacing, UpdateReceiver, UpdateSender, UpdateState, UpdateStatus,
+        UpdateStatusPoller, UpdateStatusRequest, UpdateVerifier, Version, MAX_MISSING_RANGES,
+        MAX_PREPARE_FOR_UPDATE_SIZE, PRIMARY_IMAGE_ID, UPDATE_BYTES_OVERHEAD,
+    },
+    DataFrame, DataSource, Header, NonceScheme, HEADER_SIZE, MIC_SIZE,
 };
 use rand::RngCore;
 use tokio::sync::broadcast;
@@ -23,8 +34,55 @@ type AesCcm = Ccm<Aes128, U4, U7>;
 // Our software update bytes.
 static UPDATE: [u8; 100 * 1024] = [0u8; 100 * 1024];
 
+// A later update that changes a few bytes of the first, which we send as a
+// patch of it.
+fn delta_update() -> Vec<u8> {
+    let mut update = UPDATE.to_vec();
+    update[10 * 1024..12 * 1024].fill(0x5a);
+    update.splice(50 * 1024..50 * 1024, *b"some more bytes");
+    update
+}
+
 // The port that a server is associated with.
-const MY_APP_PORT: u8 = 2;
+const MY_APP_PORT: Port = Port::new(2).unwrap();
+
+// The port that updates are conveyed on.
+const UPDATE_PORT: Port = Port::new(1).unwrap();
+
+// Datagrams must accommodate a prepare-for-update request.
+const PACKET_SIZE: usize = HEADER_SIZE + MAX_PREPARE_FOR_UPDATE_SIZE + MIC_SIZE;
+
+// The key used to sign our update, which would normally be held by the host
+// building it. Servers trust the key by its identifier.
+const SIGNING_KEY: [u8; 32] = [7; 32];
+const SIGNING_KEY_ID: u8 = 1;
+
+// The version that we update to.
+const UPDATE_VERSION: Version = Version {
+    major: 1,
+    minor: 2,
+    patch: 3,
+    pre: None,
+};
+
+// The version that we then update to with a patch.
+const DELTA_UPDATE_VERSION: Version = Version {
+    major: 1,
+    minor: 2,
+    patch: 4,
+    pre: None,
+};
+
+// The security epoch of our updates, which is raised whenever an update fixes
+// a vulnerability so that servers cannot be rolled back to before it.
+const SECURITY_EPOCH: u16 = 1;
+
+// The hardware revision of our servers, which the update is constrained to.
+const HARDWARE_REV: u8 = 3;
+
+// The percentage of servers that must accept the update for it to be
+// broadcast.
+const UPDATE_QUORUM_PERCENT: u8 = 50;
 
 // This would normally consider the time on wire for a request and the time taken
 // for a server to process it. Consideration for replies is not required as they
@@ -40,71 +98,297 @@ const UPDATE_PROCESSING_TIME: Duration = Duration::from_millis(100);
 // The number of bytes that gets sent with each update.
 const UPDATE_BYTES_SIZE: usize = MIN_PAYLOAD_SIZE - UPDATE_BYTES_OVERHEAD;
 
+// The messages conveying update bytes must fit within the smallest payload,
+// with the length of the bytes conveyed in a single byte.
+const _: () = assert!(UPDATE_BYTES_SIZE + UPDATE_BYTES_OVERHEAD <= MIN_PAYLOAD_SIZE);
+const _: () = assert!(UPDATE_BYTES_SIZE <= 127);
+
 // The number of bytes sent before we must pause and give the
 // servers more time to process. This value must not be exceeded.
 const UPDATE_BYTES_PROCESSING_THRESHOLD: usize = 4096;
 
+// Where our update is interrupted e.g. given a loss of power at the client.
+const UPDATE_INTERRUPTED_AT: usize = 80 * 1024 + 21;
+
+// The time from committing an update that servers wait before applying it.
+// Our servers count ticks in milliseconds.
+const UPDATE_ACTIVATE_DELAY: Duration = Duration::from_millis(500);
+
+// The time we allow for a server to reply to a status request.
+const SERVER_REPLY_TIME: Duration = Duration::from_millis(50);
+
+// The chance, out of 256, of a server missing an update e.g. given
+// interference on the wire.
+const UPDATE_LOSS_CHANCE: u8 = 3;
+
+// The percentage of an update that we allow for retransmitting when estimating
+// how long it takes to send, given the chance of servers missing it.
+const UPDATE_RETRANSMIT_PERCENT: u8 = 2;
+
+// The number of rounds of retransmitting missing ranges before giving up.
+const MAX_RETRANSMIT_ROUNDS: usize = 8;
+
+type Datagrams = broadcast::Sender<[u8; PACKET_SIZE]>;
+
 mod client {
 
     use super::*;
 
-    pub async fn task(tx: &broadcast::Sender<[u8; MIN_PACKET_SIZE]>, servers: &[(u8, [u8; 16])]) {
-        let mut datagram_buf = [0u8; MIN_PACKET_SIZE];
+    pub async fn task(tx: &Datagrams, reply_tx: &Datagrams, servers: &[(u8, [u8; 16])]) {
+        let mut reply_rx = reply_tx.subscribe();
+
+        let mut datagram_buf = [0u8; PACKET_SIZE];
         let mut frame_counter = 0;
 
         let mut rng = rand::thread_rng();
         let mut update_key = [0; 16];
         rng.fill_bytes(&mut update_key);
 
-        let update_len = UPDATE.len();
+        // Servers declare how they're to be paced, which we take the most
+        // conservative of.
+        let pacing = negotiate_pacing(
+            tx,
+            &mut reply_rx,
+            servers,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await;
+        println!("CLIENT: pacing the update as {pacing:?}.");
+
+        let prepare_for_update =
+            new_prepare_for_update(&update_key, &UPDATE_VERSION, &UPDATE, None);
+
+        // Before occupying the bus with the update, we ask the servers whether
+        // they'd accept it with a dry run under a key of its own, and
+        // broadcast the update only if enough of them would.
+        let mut dry_run_key = [0; 16];
+        rng.fill_bytes(&mut dry_run_key);
+        let dry_run = PrepareForUpdate {
+            update_key: UpdateKey(dry_run_key),
+            dry_run: true,
+            ..new_prepare_for_update(&update_key, &UPDATE_VERSION, &UPDATE, None)
+        };
+        prepare_servers_for_update(tx, servers, &dry_run, &mut frame_counter, &mut datagram_buf)
+            .await;
+        if !survey_eligibility(
+            tx,
+            &mut reply_rx,
+            servers,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await
+        {
+            println!("CLIENT: too few servers accept the update, and so it is not broadcast.");
+            return;
+        }
 
         prepare_servers_for_update(
             tx,
             servers,
-            &update_key,
-            update_len,
+            &prepare_for_update,
             &mut frame_counter,
             &mut datagram_buf,
         )
         .await;
 
+        // We're interrupted part way through the update...
         update_servers(
             tx,
             &update_key,
-            update_len,
+            &UPDATE[..UPDATE_INTERRUPTED_AT],
+            0,
+            pacing,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await;
+
+        // ...and so we ask the servers how far they got, and resume from there
+        // by preparing them again with the same update key.
+        if let Some(resume_from) = report_update_status(
+            tx,
+            &mut reply_rx,
+            servers,
+            &UPDATE_VERSION,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await
+        {
+            println!("CLIENT: resuming the update from {resume_from}.");
+
+            prepare_servers_for_update(
+                tx,
+                servers,
+                &prepare_for_update,
+                &mut frame_counter,
+                &mut datagram_buf,
+            )
+            .await;
+
+            update_servers(
+                tx,
+                &update_key,
+                &UPDATE,
+                resume_from,
+                pacing,
+                &mut frame_counter,
+                &mut datagram_buf,
+            )
+            .await;
+
+            // Servers may still have missed some of the update along the
+            // way, so we ask them what they're missing and send just that.
+            retransmit_missing(
+                tx,
+                &mut reply_rx,
+                servers,
+                &update_key,
+                &mut frame_counter,
+                &mut datagram_buf,
+            )
+            .await;
+
+            report_update_status(
+                tx,
+                &mut reply_rx,
+                servers,
+                &UPDATE_VERSION,
+                &mut frame_counter,
+                &mut datagram_buf,
+            )
+            .await;
+        }
+
+        // Those servers that have staged the update are now told to apply it.
+        commit_update(
+            tx,
+            &update_key,
+            &UPDATE_VERSION,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await;
+
+        report_update_status(
+            tx,
+            &mut reply_rx,
+            servers,
+            &UPDATE_VERSION,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await;
+
+        // We now update the servers again, sending just a patch of the update
+        // they're running. Servers not running that update decline it.
+        let delta_update = delta_update();
+        let mut patch = vec![];
+        generate_patch(&UPDATE, &delta_update, |bytes| {
+            patch.extend_from_slice(bytes)
+        });
+        println!(
+            "CLIENT: patching {} bytes with {} bytes.",
+            delta_update.len(),
+            patch.len()
+        );
+
+        rng.fill_bytes(&mut update_key);
+
+        let prepare_for_update = new_prepare_for_update(
+            &update_key,
+            &DELTA_UPDATE_VERSION,
+            &delta_update,
+            Some(Delta {
+                base_version: UPDATE_VERSION,
+                patch_format: COPY_INSERT_PATCH_FORMAT,
+            }),
+        );
+
+        prepare_servers_for_update(
+            tx,
+            servers,
+            &prepare_for_update,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await;
+
+        update_servers_with_patch(
+            tx,
+            &mut reply_rx,
+            servers,
+            &update_key,
+            &patch,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await;
+
+        commit_update(
+            tx,
+            &update_key,
+            &DELTA_UPDATE_VERSION,
+            &mut frame_counter,
+            &mut datagram_buf,
+        )
+        .await;
+
+        report_update_status(
+            tx,
+            &mut reply_rx,
+            servers,
+            &DELTA_UPDATE_VERSION,
             &mut frame_counter,
             &mut datagram_buf,
         )
         .await;
     }
 
+    fn new_prepare_for_update(
+        update_key: &[u8; 16],
+        version: &Version,
+        update: &[u8],
+        delta: Option<Delta>,
+    ) -> PrepareForUpdate {
+        // A delta update is signed as the update that it patches to.
+        let signature = sign_update(
+            &SigningKey::from_bytes(&SIGNING_KEY),
+            SIGNING_KEY_ID,
+            version,
+            SECURITY_EPOCH,
+            update,
+        );
+        PrepareForUpdate {
+            version: version.clone(),
+            server_ports: PortSet::of(MY_APP_PORT),
+            image_id: PRIMARY_IMAGE_ID,
+            security_epoch: SECURITY_EPOCH,
+            update_key: UpdateKey(*update_key),
+            update_byte_len: update.len() as u32,
+            integrity: UpdateIntegrity::Signed(signature),
+            compression: Compression::None,
+            delta,
+            hardware_rev: Some(HARDWARE_REV),
+            dry_run: false,
+        }
+    }
+
     async fn prepare_servers_for_update(
-        tx: &broadcast::Sender<[u8; MIN_PACKET_SIZE]>,
+        tx: &Datagrams,
         servers: &[(u8, [u8; 16])],
-        update_key: &[u8; 16],
-        update_len: usize,
+        prepare_for_update: &PrepareForUpdate,
         frame_counter: &mut u16,
-        datagram_buf: &mut [u8; MIN_PACKET_SIZE],
+        datagram_buf: &mut [u8; PACKET_SIZE],
     ) {
         for (server_address, server_network_key) in servers {
             let server_network_cipher = AesCcm::new(GenericArray::from_slice(server_network_key));
 
-            let prepare_for_update = PrepareForUpdate {
-                version: Version {
-                    major: 1,
-                    minor: 2,
-                    patch: 3,
-                    pre: None,
-                },
-                server_ports: 1 << MY_APP_PORT,
-                update_key: UpdateKey(*update_key),
-                update_byte_len: update_len as u32,
-                signed: false,
-            };
-
             create_prepare_update_request(
                 &server_network_cipher,
-                &prepare_for_update,
+                prepare_for_update,
                 *frame_counter,
                 datagram_buf,
             );
@@ -121,96 +405,391 @@ mod client {
     }
 
     fn create_prepare_update_request(
-        network_cipher: &impl AeadInPlace,
+        network_cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
         prepare_for_update: &PrepareForUpdate,
         frame_counter: u16,
-        datagram_buf: &mut [u8; MIN_PACKET_SIZE],
+        datagram_buf: &mut [u8; PACKET_SIZE],
     ) {
         let header = Header {
             version: 0,
             source: DataSource::Client,
             server_address: 0,
-            server_port: 1,
+            server_port: UPDATE_PORT,
             frame_counter,
         };
 
         to_datagram(
             network_cipher,
             &header,
-            &postcard::to_vec::<PrepareForUpdate, MIN_PAYLOAD_SIZE>(prepare_for_update).unwrap(),
+            &postcard::to_vec::<PrepareForUpdate, PACKET_SIZE>(prepare_for_update).unwrap(),
             datagram_buf,
         );
     }
 
+    async fn survey_eligibility(
+        tx: &Datagrams,
+        reply_rx: &mut broadcast::Receiver<[u8; PACKET_SIZE]>,
+        servers: &[(u8, [u8; 16])],
+        frame_counter: &mut u16,
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) -> bool {
+        let server_addresses = servers.iter().map(|(a, _)| *a).collect::<Vec<_>>();
+        let mut survey = EligibilitySurvey::<8>::new(PRIMARY_IMAGE_ID, &server_addresses).unwrap();
+        while let Some((server_address, request)) = survey.poll_transmit() {
+            if let Some(report) = request_update_status::<EligibilityReport>(
+                tx,
+                reply_rx,
+                servers,
+                server_address,
+                &request,
+                frame_counter,
+                datagram_buf,
+            )
+            .await
+            {
+                survey.handle_reply(server_address, report);
+            }
+        }
+        for (server_address, report) in survey.survey() {
+            println!("CLIENT: server {server_address} reports its eligibility as {report:?}.");
+        }
+        survey
+            .tally(EligibilityTally::new(UPDATE_QUORUM_PERCENT))
+            .proceed(servers.len())
+    }
+
+    async fn negotiate_pacing(
+        tx: &Datagrams,
+        reply_rx: &mut broadcast::Receiver<[u8; PACKET_SIZE]>,
+        servers: &[(u8, [u8; 16])],
+        frame_counter: &mut u16,
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) -> UpdatePacing {
+        let mut declared = vec![];
+        for (server_address, _) in servers {
+            if let Some(pacing) = request_update_status::<UpdateStatus>(
+                tx,
+                reply_rx,
+                servers,
+                *server_address,
+                &UpdateStatusRequest::default(),
+                frame_counter,
+                datagram_buf,
+            )
+            .await
+            .and_then(|status| status.pacing)
+            {
+                declared.push(pacing);
+            }
+        }
+
+        UpdatePacing {
+            chunk_size: UPDATE_BYTES_SIZE as u8,
+            threshold_bytes: UPDATE_BYTES_PROCESSING_THRESHOLD as u32,
+            processing_ticks: UPDATE_PROCESSING_TIME.as_millis() as u32,
+            chunk_ticks: SERVER_REQUEST_RECEIVE_TIME.as_millis() as u32,
+        }
+        .for_servers(&declared)
+    }
+
     async fn update_servers(
-        tx: &broadcast::Sender<[u8; MIN_PACKET_SIZE]>,
+        tx: &Datagrams,
         update_key: &[u8; 16],
-        update_len: usize,
+        update: &[u8],
+        byte_offset: u32,
+        pacing: UpdatePacing,
         frame_counter: &mut u16,
-        datagram_buf: &mut [u8; MIN_PACKET_SIZE],
+        datagram_buf: &mut [u8; PACKET_SIZE],
     ) {
-        let update_cipher = AesCcm::new(GenericArray::from_slice(update_key));
+        let update_key = UpdateKey(*update_key);
 
-        let mut update_byte_offset = 0;
-        let mut next_threshold_byte_offset = UPDATE_BYTES_PROCESSING_THRESHOLD.min(update_len);
+        // We report our progress with each threshold of bytes sent.
+        let mut on_progress = |sent: u32, of_total: u32, eta_ticks: u64| {
+            if sent.is_multiple_of(pacing.threshold_bytes.max(1)) || sent == of_total {
+                println!("CLIENT: sent {sent} of {of_total} bytes, finishing in {eta_ticks}ms.");
+            }
+        };
+        let mut sender =
+            UpdateSender::<UPDATE_BYTES_SIZE>::new(update, &update_key, byte_offset, pacing)
+                .with_frame_counter(*frame_counter)
+                .on_progress(&mut on_progress);
 
-        while update_byte_offset < UPDATE.len() {
-            let to_update_byte_offset =
-                (update_byte_offset + UPDATE_BYTES_SIZE).min(next_threshold_byte_offset);
-            let update_bytes = &UPDATE[update_byte_offset..to_update_byte_offset];
+        let estimate = sender
+            .estimate()
+            .with_retransmit_percent(UPDATE_RETRANSMIT_PERCENT);
+        println!(
+            "CLIENT: sending the update from {byte_offset}, estimated to take {}ms plus {}ms of retransmissions.",
+            estimate.ticks_from(byte_offset),
+            estimate.retransmit_ticks()
+        );
 
-            let update: Update<UPDATE_BYTES_SIZE> = Update {
-                byte_offset: update_byte_offset as u32,
-                bytes: heapless::Vec::from_slice(update_bytes).unwrap(),
-            };
+        while let Some(SendStep::Transmit(update, ticks)) = sender.next() {
+            println!(
+                "CLIENT {}: sending update with offset {} with len {}.",
+                sender.frame_counter(),
+                update.byte_offset,
+                update.bytes.len()
+            );
+            sender.to_datagram::<AesCcm, PACKET_SIZE>(UPDATE_PORT, update, datagram_buf);
+            if tx.send(*datagram_buf).is_err() {
+                break;
+            }
+            // Our ticks are milliseconds.
+            time::sleep(Duration::from_millis(ticks as u64)).await;
+        }
+        *frame_counter = sender.frame_counter();
+    }
 
-            create_update_request(&update_cipher, &update, *frame_counter, datagram_buf);
+    async fn report_update_status(
+        tx: &Datagrams,
+        reply_rx: &mut broadcast::Receiver<[u8; PACKET_SIZE]>,
+        servers: &[(u8, [u8; 16])],
+        version: &Version,
+        frame_counter: &mut u16,
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) -> Option<u32> {
+        let server_addresses = servers.iter().map(|(a, _)| *a).collect::<Vec<_>>();
+        let mut poller = UpdateStatusPoller::<8>::new(version.clone(), &server_addresses).unwrap();
 
-            if tx.send(*datagram_buf).is_err() {
+        while let Some((server_address, request)) = poller.poll_transmit() {
+            if let Some(status) = request_update_status::<UpdateStatus>(
+                tx,
+                reply_rx,
+                servers,
+                server_address,
+                &request,
+                frame_counter,
+                datagram_buf,
+            )
+            .await
+            {
+                poller.handle_reply(server_address, status);
+            }
+        }
+
+        for report in poller.report() {
+            println!(
+                "CLIENT: server {} has an outcome of {:?} given its status of {:?}.",
+                report.server_address, report.outcome, report.status
+            );
+        }
+
+        poller.resume_from()
+    }
+
+    async fn retransmit_missing(
+        tx: &Datagrams,
+        reply_rx: &mut broadcast::Receiver<[u8; PACKET_SIZE]>,
+        servers: &[(u8, [u8; 16])],
+        update_key: &[u8; 16],
+        frame_counter: &mut u16,
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) {
+        let update_cipher = AesCcm::new(GenericArray::from_slice(update_key));
+
+        let request = UpdateStatusRequest {
+            missing_ranges: Some(MAX_MISSING_RANGES as u8),
+            ..Default::default()
+        };
+
+        for _ in 0..MAX_RETRANSMIT_ROUNDS {
+            // The ranges missed by each server are merged so that bytes missed
+            // by several of them are sent just the once.
+            let mut plan = RetransmitPlan::<16>::new();
+            for (server_address, _) in servers {
+                if let Some(missing_ranges) = request_update_status::<MissingRanges>(
+                    tx,
+                    reply_rx,
+                    servers,
+                    *server_address,
+                    &request,
+                    frame_counter,
+                    datagram_buf,
+                )
+                .await
+                {
+                    plan.add(&missing_ranges);
+                }
+            }
+            if plan.is_empty() {
                 return;
             }
 
-            let delay = if to_update_byte_offset == next_threshold_byte_offset {
-                next_threshold_byte_offset += UPDATE_BYTES_PROCESSING_THRESHOLD;
-                next_threshold_byte_offset = next_threshold_byte_offset.min(update_len);
-                UPDATE_PROCESSING_TIME
-            } else {
-                SERVER_REQUEST_RECEIVE_TIME
-            };
+            for (byte_offset, len) in plan.chunks(UPDATE_BYTES_SIZE as u32) {
+                let (from, to) = (byte_offset as usize, (byte_offset + len) as usize);
+                let update: UpdateMessage<UPDATE_BYTES_SIZE> = UpdateMessage::Update(Update {
+                    byte_offset,
+                    bytes: heapless::Vec::from_slice(&UPDATE[from..to]).unwrap(),
+                });
 
-            println!("CLIENT {frame_counter}: sent update with offset {update_byte_offset} with len {}. Waiting {:?} for the server to process.", update_bytes.len(), delay);
-            time::sleep(delay).await;
+                create_update_request(&update_cipher, &update, *frame_counter, datagram_buf);
+                if tx.send(*datagram_buf).is_err() {
+                    return;
+                }
 
-            *frame_counter = frame_counter.wrapping_add(1);
+                // Retransmitted bytes are written out of order, so we always
+                // give servers the time to process them.
+                println!("CLIENT {frame_counter}: resent update with offset {byte_offset} with len {len}. Waiting {UPDATE_PROCESSING_TIME:?} for the server to process.");
+                time::sleep(UPDATE_PROCESSING_TIME).await;
 
-            update_byte_offset = to_update_byte_offset;
+                *frame_counter = frame_counter.wrapping_add(1);
+            }
         }
+    }
 
-        println!(
-            "CLIENT {frame_counter}: sent updates Waiting {:?} for the server to process.",
-            UPDATE_PROCESSING_TIME
+    async fn request_update_status<T: serde::de::DeserializeOwned>(
+        tx: &Datagrams,
+        reply_rx: &mut broadcast::Receiver<[u8; PACKET_SIZE]>,
+        servers: &[(u8, [u8; 16])],
+        server_address: u8,
+        request: &UpdateStatusRequest,
+        frame_counter: &mut u16,
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) -> Option<T> {
+        let (_, server_network_key) = servers.iter().find(|(a, _)| *a == server_address)?;
+        let server_network_cipher = AesCcm::new(GenericArray::from_slice(server_network_key));
+
+        let header = Header {
+            version: 0,
+            source: DataSource::Client,
+            server_address,
+            server_port: UPDATE_PORT,
+            frame_counter: *frame_counter,
+        };
+        to_datagram(
+            &server_network_cipher,
+            &header,
+            &postcard::to_vec::<UpdateStatusRequest, PACKET_SIZE>(request).unwrap(),
+            datagram_buf,
         );
-        time::sleep(UPDATE_PROCESSING_TIME).await;
+        tx.send(*datagram_buf).ok()?;
+        println!("CLIENT {frame_counter}: sent update status request to {server_address}.");
+        *frame_counter = frame_counter.wrapping_add(1);
+
+        let reply = time::timeout(SERVER_REPLY_TIME, reply_rx.recv())
+            .await
+            .ok()?
+            .ok()?;
+        let (_, payload) = from_datagram(
+            &reply,
+            |h| h.server_address == server_address && h.source == DataSource::Server,
+            &server_network_cipher,
+        )
+        .ok()?;
+        postcard::from_bytes::<T>(&payload).ok()
+    }
+
+    async fn update_servers_with_patch(
+        tx: &Datagrams,
+        reply_rx: &mut broadcast::Receiver<[u8; PACKET_SIZE]>,
+        servers: &[(u8, [u8; 16])],
+        update_key: &[u8; 16],
+        patch: &[u8],
+        frame_counter: &mut u16,
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) {
+        let update_cipher = AesCcm::new(GenericArray::from_slice(update_key));
+
+        // Each update conveys the offset of the patched bytes that it begins
+        // with, which we determine by patching the update they're running.
+        let updates = delta_updates::<UPDATE_BYTES_SIZE, _>(patch, &UPDATE[..]).collect::<Vec<_>>();
+
+        let request = UpdateStatusRequest {
+            missing_ranges: Some(1),
+            ..Default::default()
+        };
+
+        let mut resend_from = Some(0);
+        for _ in 0..MAX_RETRANSMIT_ROUNDS {
+            let Some(byte_offset) = resend_from else {
+                return;
+            };
+
+            // A patch must be received in order, so servers that miss an
+            // update need all those that follow it too.
+            let from = updates
+                .iter()
+                .rposition(|u| u.byte_offset <= byte_offset)
+                .unwrap_or_default();
+            for update in &updates[from..] {
+                let message = UpdateMessage::Update(Update {
+                    byte_offset: update.byte_offset,
+                    bytes: update.bytes.clone(),
+                });
+                create_update_request(&update_cipher, &message, *frame_counter, datagram_buf);
+                if tx.send(*datagram_buf).is_err() {
+                    return;
+                }
+
+                // A patch may produce many bytes, so we always give servers
+                // the time to process them.
+                println!("CLIENT {frame_counter}: sent patch with offset {} with len {}. Waiting {UPDATE_PROCESSING_TIME:?} for the server to process.", update.byte_offset, update.bytes.len());
+                time::sleep(UPDATE_PROCESSING_TIME).await;
+
+                *frame_counter = frame_counter.wrapping_add(1);
+            }
+
+            resend_from = None;
+            for (server_address, _) in servers {
+                if let Some(missing_ranges) = request_update_status::<MissingRanges>(
+                    tx,
+                    reply_rx,
+                    servers,
+                    *server_address,
+                    &request,
+                    frame_counter,
+                    datagram_buf,
+                )
+                .await
+                {
+                    if let Some((byte_offset, _)) = missing_ranges.ranges.first() {
+                        resend_from = resend_from.min(Some(*byte_offset)).or(Some(*byte_offset));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn commit_update(
+        tx: &Datagrams,
+        update_key: &[u8; 16],
+        version: &Version,
+        frame_counter: &mut u16,
+        datagram_buf: &mut [u8; PACKET_SIZE],
+    ) {
+        let update_cipher = AesCcm::new(GenericArray::from_slice(update_key));
+
+        let commit =
+            UpdateMessage::<0>::commit(version.clone(), UPDATE_ACTIVATE_DELAY.as_millis() as u32);
+        create_update_request(&update_cipher, &commit, *frame_counter, datagram_buf);
+        if tx.send(*datagram_buf).is_err() {
+            return;
+        }
+
+        let delay = UPDATE_ACTIVATE_DELAY + UPDATE_PROCESSING_TIME;
+        println!("CLIENT {frame_counter}: sent update commit. Waiting {delay:?} for the servers to apply it.");
+        *frame_counter = frame_counter.wrapping_add(1);
+        time::sleep(delay).await;
     }
 
     fn create_update_request<const N: usize>(
-        update_cipher: &impl AeadInPlace,
-        update: &Update<N>,
+        update_cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+        update: &UpdateMessage<N>,
         frame_counter: u16,
-        datagram_buf: &mut [u8; MIN_PACKET_SIZE],
+        datagram_buf: &mut [u8; PACKET_SIZE],
     ) {
         let header = Header {
             version: 0,
             source: DataSource::Client,
             server_address: 0,
-            server_port: 1,
+            server_port: UPDATE_PORT,
             frame_counter,
         };
 
-        to_datagram(
+        to_update_datagram(
             update_cipher,
             &header,
-            &postcard::to_vec::<Update<N>, MIN_PAYLOAD_SIZE>(update).unwrap(),
+            &postcard::to_vec::<UpdateMessage<N>, MIN_PAYLOAD_SIZE>(update).unwrap(),
             datagram_buf,
         );
     }
@@ -220,134 +799,272 @@ mod server {
 
     use super::*;
 
-    struct UpdateInfo {
-        cipher: AesCcm,
-        byte_len: usize,
-        next_byte_offset: usize,
+    fn trusted_key(key_id: u8) -> Option<VerifyingKey> {
+        (key_id == SIGNING_KEY_ID).then(|| SigningKey::from_bytes(&SIGNING_KEY).verifying_key())
     }
 
-    pub async fn task(tx: broadcast::Sender<[u8; MIN_PACKET_SIZE]>, server: &(u8, [u8; 16])) {
+    // A server that has not been told of our signing key.
+    fn untrusted_key(_key_id: u8) -> Option<VerifyingKey> {
+        None
+    }
+
+    pub async fn task(tx: Datagrams, reply_tx: Datagrams, server: &(u8, [u8; 16])) {
         let mut rx = tx.subscribe();
 
-        let (_server_address, server_network_key) = server;
+        let (server_address, server_network_key) = server;
+        let server_address = *server_address;
         let server_cipher = AesCcm::new(GenericArray::from_slice(server_network_key));
+        let mut frame_counter = 0;
+
+        // Our second server has slower flash memory, and so takes longer to
+        // process each threshold of bytes.
+        let mut receiver = UpdateReceiver::new("1.2.0".parse::<Version>().unwrap())
+            .with_pacing(ServerPacing {
+                chunk_size: UPDATE_BYTES_SIZE as u8,
+                threshold_bytes: UPDATE_BYTES_PROCESSING_THRESHOLD as u32,
+                processing_ticks: if server_address == 2 { 150 } else { 100 },
+            })
+            .with_hardware_rev(HARDWARE_REV);
+
+        // Where the update is staged e.g. a flash memory partition, and the
+        // update running, which a delta update patches.
+        let mut staged = vec![];
+        let mut running = vec![];
+
+        let started = time::Instant::now();
+        let now = || started.elapsed().as_millis() as u64;
 
-        let current_version = "1.2.0".parse::<Version>().unwrap();
-        let mut active_update_info: Option<UpdateInfo> = None;
+        loop {
+            // Await the next request, or the activation of a committed update.
+            let received = match receiver.activate_at() {
+                Some(activate_at) => tokio::select! {
+                    received = rx.recv() => received,
+                    _ = time::sleep_until(started + Duration::from_millis(activate_at)) => {
+                        if receiver.poll_activation(now()) == UpdateState::ReadyToApply {
+                            println!("SERVER {server_address}: Update activated. Do something heavy again e.g. update firmware.");
+                            // The security epoch would be persisted along with
+                            // the version running, and given to the receiver
+                            // when next starting.
+                            receiver.applied(|security_epoch| {
+                                println!("SERVER {server_address}: security epoch {security_epoch} persisted.")
+                            });
+                            running = std::mem::take(&mut staged);
+                        }
+                        continue;
+                    }
+                },
+                None => rx.recv().await,
+            };
+            let Ok(encrypted_payload) = received else {
+                break;
+            };
 
-        while let Ok(encrypted_payload) = rx.recv().await {
-            // First try processing an update request for an active update
-            if let Some((update_info, update)) = if let Some(update_info) = &mut active_update_info
+            // Updates are occasionally missed e.g. given interference on the
+            // wire, as are the other datagrams broadcast while receiving them.
+            if receiver.state() == UpdateState::Receiving
+                && is_broadcast(&encrypted_payload)
+                && rand::random::<u8>() < UPDATE_LOSS_CHANCE
             {
-                process_client_update_request::<UPDATE_BYTES_SIZE>(
-                    &update_info.cipher,
-                    &encrypted_payload,
-                )
-                .map(|update| (update_info, update))
-            } else {
-                None
-            } {
-                if process_active_update(update_info, &update) {
-                    active_update_info = None;
+                println!("SERVER {server_address}: missed a broadcast.");
+                continue;
+            }
+
+            // First try handling the datagram as one of an active update. The
+            // update running is only read if the update is a patch of it.
+            match receiver.handle_delta_datagram::<AesCcm, UPDATE_BYTES_SIZE, PACKET_SIZE>(
+                &encrypted_payload,
+                &mut &running[..],
+                &mut Staging {
+                    server_address,
+                    bytes: &mut staged,
+                },
+            ) {
+                UpdateEvent::Ignored => {}
+                UpdateEvent::Progress { received_bytes }
+                | UpdateEvent::Flushed { received_bytes } => {
+                    println!("SERVER {server_address}: received {received_bytes} bytes.");
+                    continue;
+                }
+                UpdateEvent::Staged => {
+                    println!("SERVER {server_address}: {} bytes received and the signature verified. Update staged until committed.", receiver.status().received_bytes);
                     continue;
                 }
+                UpdateEvent::Abandoned(e) => {
+                    println!("SERVER {server_address}: abandoning update given {e:?}.");
+                    continue;
+                }
+                UpdateEvent::Message(message) => {
+                    match message {
+                        UpdateMessage::Update(_) => {}
+                        UpdateMessage::Abort(abort) => {
+                            receiver.abort(|received_bytes| {
+                                println!("SERVER {server_address}: erasing the {received_bytes} bytes received.")
+                            });
+                            println!(
+                                "SERVER {server_address}: update aborted given {:?}.",
+                                abort.reason
+                            );
+                        }
+                        UpdateMessage::Commit(commit) => match receiver.commit(&commit, now()) {
+                            Ok(()) => println!(
+                                "SERVER {server_address}: update committed, activating in {} ticks.",
+                                commit.activate_delay_ticks
+                            ),
+                            Err(e) => {
+                                println!("SERVER {server_address}: cannot commit given {e:?}.")
+                            }
+                        },
+                        UpdateMessage::RollbackStaged(rollback) => {
+                            if receiver
+                                .rollback_staged(&rollback, |staged_bytes| {
+                                    println!("SERVER {server_address}: erasing the {staged_bytes} bytes staged.")
+                                })
+                                .is_ok()
+                            {
+                                println!("SERVER {server_address}: staged update rolled back.");
+                            }
+                        }
+                    }
+                    continue;
+                }
+            }
 
             // If we're not processing an active update then try handling the
             // request as one that prepares us for a new update.
-            } else if let Some(prepare_for_update) =
+            if let Some(prepare_for_update) =
                 process_client_prepare_for_update_request(&server_cipher, &encrypted_payload)
             {
-                if let Some(new_active_update_info) =
-                    activate_update_if_new_version(&prepare_for_update, &current_version)
-                {
-                    active_update_info = Some(new_active_update_info);
+                println!("SERVER {server_address}: received {prepare_for_update:?}.");
+                let trusted_key = if server_address == 1 {
+                    trusted_key
+                } else {
+                    untrusted_key
+                };
+                let verifier = UpdateVerifier::with_trusted_keys(&prepare_for_update, trusted_key);
+                match receiver.prepare(&prepare_for_update, verifier) {
+                    Preparation::Started => {
+                        println!(
+                            "SERVER {server_address}: updating from {} to {}.",
+                            receiver.current_version(),
+                            prepare_for_update.version
+                        );
+                        staged = vec![0; prepare_for_update.update_byte_len as usize];
+                    }
+                    Preparation::Resumed { byte_offset } => {
+                        println!("SERVER {server_address}: resuming the update from {byte_offset}.")
+                    }
+                    Preparation::DryRun(eligibility) => println!(
+                        "SERVER {server_address}: asked whether we'd update to {}, being {eligibility:?}.",
+                        prepare_for_update.version
+                    ),
+                    p => println!("SERVER {server_address}: not updating given {p:?}."),
+                }
+
+            // Otherwise, the client may be asking how our update went. We
+            // have just the one image to update.
+            } else if let Some(request) = process_client_update_status_request(
+                server_address,
+                &server_cipher,
+                &encrypted_payload,
+            )
+            .filter(|r| r.image_id == receiver.image_id())
+            {
+                // Our servers have the storage for any update, and so decline
+                // an update only when it is not eligible.
+                let reply = match request.missing_ranges {
+                    _ if request.eligibility => postcard::to_vec::<EligibilityReport, PACKET_SIZE>(
+                        &receiver.eligibility(PortSet::of(MY_APP_PORT), |_| None),
+                    ),
+                    Some(max_ranges) => postcard::to_vec::<MissingRanges, PACKET_SIZE>(
+                        &receiver.missing_ranges(max_ranges as usize),
+                    ),
+                    None => postcard::to_vec::<UpdateStatus, PACKET_SIZE>(&receiver.status()),
                 }
+                .unwrap();
+                let header = Header {
+                    version: 0,
+                    source: DataSource::Server,
+                    server_address,
+                    server_port: UPDATE_PORT,
+                    frame_counter,
+                };
+                let mut datagram_buf = [0; PACKET_SIZE];
+                to_datagram(&server_cipher, &header, &reply, &mut datagram_buf);
+                let _ = reply_tx.send(datagram_buf);
+                frame_counter = frame_counter.wrapping_add(1);
             }
         }
     }
 
-    fn process_client_update_request<const N: usize>(
-        cipher: &AesCcm,
-        datagram_buf: &[u8; MIN_PACKET_SIZE],
-    ) -> Option<Update<N>> {
-        from_datagram(
-            datagram_buf,
-            |h| h.server_address == 0x00 && h.server_port == 0x01 && h.source == DataSource::Client,
-            cipher,
-        )
-        .ok()
-        .and_then(|(_, b)| postcard::from_bytes::<Update<N>>(&b).ok())
+    // Where the update is staged e.g. a flash memory partition.
+    struct Staging<'a> {
+        server_address: u8,
+        bytes: &'a mut [u8],
     }
 
-    fn process_active_update<const N: usize>(
-        update_info: &mut UpdateInfo,
-        update: &Update<N>,
-    ) -> bool {
-        // Bail out if the next byte offset isn't what we expect.
-        if update.byte_offset as usize != update_info.next_byte_offset {
+    impl StagingWriter for Staging<'_> {
+        fn write(&mut self, offset: u32, bytes: &[u8]) {
+            let offset = offset as usize;
+            self.bytes[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+
+        fn flush(&mut self) {
             println!(
-                "SERVER: abandoning update given unexpected offset {} with len {}.",
-                update.byte_offset,
-                update.bytes.len()
+                "SERVER {}: Doing something heavy with our buffer e.g. flashing memory with firmware.",
+                self.server_address
             );
-            return true;
         }
 
-        println!(
-            "SERVER: received update with offset {} with len {}.",
-            update.byte_offset,
-            update.bytes.len()
-        );
-
-        update_info.next_byte_offset += update.bytes.len();
-
-        if update_info.next_byte_offset == update_info.byte_len {
-            println!("SERVER: Doing something heavy with the last bytes of our buffer e.g. flashing memory with firmware.");
+        fn finalize(&mut self) {
+            println!("SERVER {}: Doing something heavy with the last bytes of our buffer e.g. flashing memory with firmware.", self.server_address);
+        }
 
-            println!("SERVER: {} bytes received. Update finished. Do something heavy again e.g. update firmware.", update_info.next_byte_offset);
-            true
-        } else if update_info.next_byte_offset % UPDATE_BYTES_PROCESSING_THRESHOLD == 0 {
-            println!(
-                "SERVER: Doing something heavy with our buffer e.g. flashing memory with firmware."
-            );
-            false
-        } else {
-            false
+        fn read(&mut self, offset: u32, buf: &mut [u8]) {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.bytes[offset..offset + buf.len()]);
         }
     }
 
+    fn is_broadcast(datagram_buf: &[u8; PACKET_SIZE]) -> bool {
+        postcard::from_bytes::<DataFrame>(datagram_buf)
+            .ok()
+            .and_then(|data_frame| Header::parse(data_frame.header).ok())
+            .is_some_and(|h| h.server_address == 0x00)
+    }
+
     fn process_client_prepare_for_update_request(
         cipher: &AesCcm,
-        datagram_buf: &[u8; MIN_PACKET_SIZE],
+        datagram_buf: &[u8; PACKET_SIZE],
     ) -> Option<PrepareForUpdate> {
         from_datagram(
             datagram_buf,
-            |h| h.server_address == 0x00 && h.server_port == 0x01 && h.source == DataSource::Client,
+            |h| {
+                h.server_address == 0x00
+                    && h.server_port == UPDATE_PORT
+                    && h.source == DataSource::Client
+            },
             cipher,
         )
         .ok()
         .and_then(|(_, b)| postcard::from_bytes::<PrepareForUpdate>(&b).ok())
     }
 
-    fn activate_update_if_new_version(
-        prepare_for_update: &PrepareForUpdate,
-        current_version: &Version,
-    ) -> Option<UpdateInfo> {
-        // Is this a version we're interested in? If so then note something about it.
-        if &prepare_for_update.version > current_version {
-            println!(
-                "SERVER: updating from {:?} to {:?}.",
-                current_version, prepare_for_update.version
-            );
-
-            Some(UpdateInfo {
-                cipher: AesCcm::new(GenericArray::from_slice(&prepare_for_update.update_key.0)),
-                byte_len: prepare_for_update.update_byte_len as usize,
-                next_byte_offset: 0,
-            })
-        } else {
-            None
-        }
+    fn process_client_update_status_request(
+        server_address: u8,
+        cipher: &AesCcm,
+        datagram_buf: &[u8; PACKET_SIZE],
+    ) -> Option<UpdateStatusRequest> {
+        from_datagram(
+            datagram_buf,
+            |h| {
+                h.server_address == server_address
+                    && h.server_port == UPDATE_PORT
+                    && h.source == DataSource::Client
+            },
+            cipher,
+        )
+        .ok()
+        .and_then(|(_, b)| postcard::from_bytes::<UpdateStatusRequest>(&b).ok())
     }
 }
 
@@ -356,18 +1073,23 @@ async fn main() {
     // Both the client and server share a private key. Each server in a network
     // should have its own private key.
     let mut rng = rand::thread_rng();
-    let mut server_network_key = [0; 16];
-    rng.fill_bytes(&mut server_network_key);
-
-    let servers = vec![(1, server_network_key)];
+    let mut servers = vec![];
+    for server_address in 1..=2 {
+        let mut server_network_key = [0; 16];
+        rng.fill_bytes(&mut server_network_key);
+        servers.push((server_address, server_network_key));
+    }
 
     let (tx, _rx) = broadcast::channel(256);
+    let (reply_tx, _reply_rx) = broadcast::channel(8);
 
-    let task_tx = tx.clone();
-    let task_servers = servers.clone();
-    tokio::spawn(async move {
-        server::task(task_tx.clone(), &task_servers[0]).await;
-    });
+    for server in servers.clone() {
+        let task_tx = tx.clone();
+        let task_reply_tx = reply_tx.clone();
+        tokio::spawn(async move {
+            server::task(task_tx, task_reply_tx, &server).await;
+        });
+    }
 
-    client::task(&tx, &servers).await;
+    client::task(&tx, &reply_tx, &servers).await;
 }
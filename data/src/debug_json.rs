@@ -0,0 +1,317 @@
+//! Rendering headers and the payloads of discovery and updates as JSON e.g.
+//! to describe a hexdump attached to a bug report. Each is rendered as an
+//! object of its `decoded` fields and its `raw` bytes as they are encoded,
+//! written as lowercase hex:
+//!
+//! ```json
+//! {"decoded":{"frame_counter":1,"server_address":255,"server_port":7,"source":"server","version":0},"raw":"00013ffc"}
+//! ```
+//!
+//! The names of fields are those of the types rendered, and their order is
+//! alphabetical. The structure is a maintained contract that tools parse,
+//! and so is pinned by the tests of this module. Requires the `std`
+//! feature.
+
+extern crate std;
+
+use std::{fmt::Write, string::String, vec::Vec};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    discovery::{Capabilities, Identified, Identify, ServerDetails},
+    port::PortSet,
+    update::{Compression, Delta, PrepareForUpdate, Update, UpdateIntegrity, UpdateKey, Version},
+    DataSource, Header,
+};
+
+// The names of the capabilities, by their bit.
+const CAPABILITY_NAMES: [&str; 8] = [
+    "signed_updates",
+    "batched_events",
+    "extended_ports",
+    "server_status",
+    "filtered_events",
+    "client_time",
+    "max_reply_len",
+    "absolute_ticks",
+];
+
+/// Bytes written as lowercase hex digits, two for each.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{b:02x}");
+    }
+    s
+}
+
+// The object of the fields decoded and the bytes that encode them.
+fn rendered(decoded: Value, raw: &[u8]) -> Value {
+    json!({ "decoded": decoded, "raw": hex(raw) })
+}
+
+// A value as encoded by postcard.
+fn encoded(value: &impl Serialize) -> Vec<u8> {
+    postcard::to_extend(value, Vec::new()).unwrap_or_default()
+}
+
+fn ports(ports: PortSet) -> Value {
+    ports.iter().map(|port| port.get()).collect()
+}
+
+fn version(version: &Version) -> Value {
+    json!(std::format!("{version}"))
+}
+
+fn capabilities(capabilities: Capabilities) -> Value {
+    CAPABILITY_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| capabilities.contains(Capabilities(1 << bit)))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn details(details: &ServerDetails) -> Value {
+    json!({
+        "capabilities": capabilities(details.capabilities),
+        "network_id": details.network_id,
+        "protocol_versions": details.protocol_versions,
+        "version": version(&details.version),
+    })
+}
+
+impl Header {
+    /// The header's fields along with its packed bytes, see the
+    /// [module's documentation](crate::debug_json).
+    pub fn to_debug_json(&self) -> Value {
+        let (b0, b1, b2, b3) = self.to_packed();
+        rendered(
+            json!({
+                "frame_counter": self.frame_counter,
+                "server_address": self.server_address,
+                "server_port": self.server_port.get(),
+                "source": match self.source {
+                    DataSource::Client => "client",
+                    DataSource::Server => "server",
+                },
+                "version": self.version,
+            }),
+            &[b0, b1, b2, b3],
+        )
+    }
+}
+
+impl<const N: usize> Identify<N> {
+    /// The payload's fields along with its bytes, the addresses known to
+    /// the client being listed, see the
+    /// [module's documentation](crate::debug_json).
+    pub fn to_debug_json(&self) -> Value {
+        let addresses = self
+            .iter()
+            .enumerate()
+            .filter(|(_, set)| *set)
+            .map(|(address, _)| address)
+            .collect::<Vec<_>>();
+        rendered(
+            json!({
+                "addresses": addresses,
+                "contested": self.contested.as_slice(),
+                "network_id": self.network_id,
+                "protocol_version": self.protocol_version,
+            }),
+            &encoded(self),
+        )
+    }
+}
+
+impl Identified {
+    /// The payload's fields along with its bytes, see the
+    /// [module's documentation](crate::debug_json).
+    pub fn to_debug_json(&self) -> Value {
+        rendered(
+            json!({
+                "details": self.details.as_ref().map(details),
+                "server_address": self.server_address,
+                "server_ports": ports(self.server_ports),
+            }),
+            &encoded(self),
+        )
+    }
+}
+
+impl PrepareForUpdate {
+    /// The payload's fields along with its bytes, see the
+    /// [module's documentation](crate::debug_json). The update key is
+    /// rendered as `XXX`, and is zeroed within the bytes, so that it is
+    /// not disclosed.
+    pub fn to_debug_json(&self) -> Value {
+        let redacted = PrepareForUpdate {
+            update_key: UpdateKey([0; 16]),
+            ..self.clone()
+        };
+        rendered(
+            json!({
+                "compression": match self.compression {
+                    Compression::None => json!("none"),
+                    Compression::Heatshrink { window, lookahead } => json!({
+                        "heatshrink": { "lookahead": lookahead, "window": window },
+                    }),
+                },
+                "delta": self.delta.as_ref().map(|Delta { base_version, patch_format }| json!({
+                    "base_version": version(base_version),
+                    "patch_format": patch_format,
+                })),
+                "dry_run": self.dry_run,
+                "hardware_rev": self.hardware_rev,
+                "image_id": self.image_id,
+                "integrity": match &self.integrity {
+                    UpdateIntegrity::Digest(digest) => json!({ "digest": hex(digest) }),
+                    UpdateIntegrity::Signed(signature) => json!({
+                        "signed": {
+                            "key_id": signature.key_id,
+                            "signature": hex(&signature.signature),
+                        },
+                    }),
+                },
+                "security_epoch": self.security_epoch,
+                "server_ports": ports(self.server_ports),
+                "update_byte_len": self.update_byte_len,
+                "update_key": "XXX",
+                "version": version(&self.version),
+            }),
+            &encoded(&redacted),
+        )
+    }
+}
+
+impl<const N: usize> Update<N> {
+    /// The packet's fields along with its bytes, see the
+    /// [module's documentation](crate::debug_json).
+    pub fn to_debug_json(&self) -> Value {
+        rendered(
+            json!({
+                "byte_offset": self.byte_offset,
+                "bytes": hex(&self.bytes),
+                "len": self.bytes.len(),
+            }),
+            &encoded(self),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::*;
+    use crate::{
+        port::Port,
+        update::{PreRelease, UpdateSignature},
+    };
+
+    #[test]
+    fn test_header() {
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 255,
+            server_port: Port::new(7).unwrap(),
+            frame_counter: 1,
+        };
+        assert_eq!(
+            header.to_debug_json().to_string(),
+            r#"{"decoded":{"frame_counter":1,"server_address":255,"server_port":7,"source":"server","version":0},"raw":"00013ffc"}"#
+        );
+    }
+
+    #[test]
+    fn test_discovery() {
+        let mut identify = Identify::<2>::new();
+        identify.set_address(1);
+        identify.set_address(9);
+        identify.contested.push(3).unwrap();
+        identify.protocol_version = 1;
+        identify.network_id = Some(42);
+        assert_eq!(
+            identify.to_debug_json().to_string(),
+            r#"{"decoded":{"addresses":[1,9],"contested":[3],"network_id":42,"protocol_version":1},"raw":"02020103012a"}"#
+        );
+
+        let identified = Identified {
+            server_address: 5,
+            server_ports: PortSet::from_bits(0b101),
+            details: Some(ServerDetails {
+                version: Version {
+                    major: 1,
+                    minor: 2,
+                    patch: 3,
+                    pre: Some(PreRelease::Beta(4)),
+                },
+                capabilities: Capabilities::SIGNED_UPDATES | Capabilities::SERVER_STATUS,
+                protocol_versions: 0b11,
+                network_id: None,
+            }),
+        };
+        assert_eq!(
+            identified.to_debug_json().to_string(),
+            r#"{"decoded":{"details":{"capabilities":["signed_updates","server_status"],"network_id":null,"protocol_versions":3,"version":"1.2.3-beta.4"},"server_address":5,"server_ports":[0,2]},"raw":"05050102030101040903"}"#
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        let prepare = PrepareForUpdate {
+            version: Version {
+                major: 1,
+                minor: 0,
+                patch: 0,
+                pre: None,
+            },
+            server_ports: PortSet::from_bits(0b10),
+            image_id: 0,
+            security_epoch: 2,
+            update_key: UpdateKey([0xaa; 16]),
+            update_byte_len: 300,
+            integrity: UpdateIntegrity::Signed(UpdateSignature {
+                key_id: 1,
+                signature: [0xbb; 64],
+            }),
+            compression: Compression::Heatshrink {
+                window: 8,
+                lookahead: 4,
+            },
+            delta: None,
+            hardware_rev: Some(3),
+            dry_run: false,
+        };
+        let json = prepare.to_debug_json();
+        assert_eq!(
+            json["decoded"].to_string(),
+            [
+                r#"{"compression":{"heatshrink":{"lookahead":4,"window":8}},"delta":null,"#,
+                r#""dry_run":false,"hardware_rev":3,"image_id":0,"#,
+                r#""integrity":{"signed":{"key_id":1,"signature":""#,
+                &"bb".repeat(64),
+                r#""}},"security_epoch":2,"server_ports":[1],"update_byte_len":300,"#,
+                r#""update_key":"XXX","version":"1.0.0"}"#,
+            ]
+            .concat()
+        );
+        // The update key is not disclosed by the bytes.
+        let raw = json["raw"].as_str().unwrap();
+        assert!(!raw.contains("aaaa"));
+        assert!(raw.contains(&"0".repeat(32)));
+
+        let update = Update::<8> {
+            byte_offset: 128,
+            bytes: Vec::from_slice(&[1, 2, 3]).unwrap(),
+        };
+        assert_eq!(
+            update.to_debug_json().to_string(),
+            r#"{"decoded":{"byte_offset":128,"bytes":"010203","len":3},"raw":"800103010203"}"#
+        );
+    }
+}
@@ -1,7 +1,40 @@
+//! Discovery of the servers of a network, and their assignment of
+//! addresses, by the client broadcasting an [Identify] to which servers
+//! reply with an [IdentifyReply].
+//!
+//! A reply is conveyed on the wire as an [Identified]. Address 0 is always
+//! that of the client, and so a reply for it is normatively a deferral:
+//!
+//! * its ports are the number of rounds after which the server expects to
+//!   be ready, or 0 if unknown
+//! * it conveys no details
+//!
+//! A reply for address 0 conveying details is neither a deferral nor a
+//! request for an address, and is refused, see [InvalidDeferral].
+
+pub mod analysis;
+pub mod join;
+
+use core::{fmt, ops::BitOr};
+
+use heapless::Vec;
+use postcard::experimental::max_size::MaxSize;
 use rand::RngCore;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
-use crate::{HEADER_SIZE, MIC_SIZE};
+use crate::{
+    deserialise_last_field, last_field,
+    port::PortSet,
+    serialise_last_field,
+    timing::{SlotSchedule, SlotScheduleError},
+    update::Version,
+    HEADER_SIZE, MIC_SIZE,
+};
+use join::Uid;
 
 const ADDRESSES_PER_BYTE: usize = 8; // CANNOT CHANGE
 
@@ -11,48 +44,375 @@ pub const MAX_ADDRESSES: usize = 256;
 
 /// The minimum size of all payloads on the data link layer given
 /// the use of discovery.
-pub const MIN_PAYLOAD_SIZE: usize = MAX_ADDRESSES / ADDRESSES_PER_BYTE;
+pub const MIN_PAYLOAD_SIZE: usize = min_payload_size(MAX_ADDRESSES);
 
 /// The minimum size of all packets ((header + payload_len) + payload + MIC)
 ///  on the data link layer given the use of discovery.
-pub const MIN_PACKET_SIZE: usize = HEADER_SIZE + MIN_PAYLOAD_SIZE + MIC_SIZE;
+pub const MIN_PACKET_SIZE: usize = min_packet_size(MAX_ADDRESSES);
+
+/// The size of the largest [Identify] of a network of [MAX_ADDRESSES].
+pub const MAX_IDENTIFY_PAYLOAD_SIZE: usize = max_identify_payload_size(MAX_ADDRESSES);
+
+/// The maximum number of contested addresses that an [Identify] can report.
+pub const MAX_CONTESTED_ADDRESSES: usize = 4;
+
+/// The number of bytes required to represent the addresses of a network
+/// within an [Identify].
+pub const fn address_bytes(addresses: usize) -> usize {
+    addresses.div_ceil(ADDRESSES_PER_BYTE)
+}
+
+/// The minimum size of all payloads on the data link layer given
+/// the use of discovery on a network of a given number of addresses.
+/// This is the size of an [Identify] of its addresses alone, the
+/// optional fields that may follow them being conveyed only by those
+/// sending them, see [max_identify_payload_size].
+pub const fn min_payload_size(addresses: usize) -> usize {
+    address_bytes(addresses)
+}
+
+/// The size of the largest [Identify] of the addresses of a network
+/// i.e. including its list of contested addresses, the length of that
+/// list, the protocol version and the network identifier. A client
+/// conveying any of these requires payloads of this size.
+pub const fn max_identify_payload_size(addresses: usize) -> usize {
+    address_bytes(addresses) + 1 + MAX_CONTESTED_ADDRESSES + 1 + MAX_NETWORK_ID_SIZE
+}
+
+// The network identifier is encoded as a varint.
+const MAX_NETWORK_ID_SIZE: usize = 5;
+
+/// The minimum size of all packets ((header + payload_len) + payload + MIC)
+/// on the data link layer given the use of discovery on a network of a given
+/// number of addresses.
+pub const fn min_packet_size(addresses: usize) -> usize {
+    HEADER_SIZE + min_payload_size(addresses) + MIC_SIZE
+}
 
 /// The payload broadcast by a client so that servers not
 /// present in the known server addresses are able to reply
 /// with a requested address.
-#[derive(Deserialize, Serialize)]
-pub struct Identify {
-    pub addresses: [u8; MIN_PAYLOAD_SIZE],
+///
+/// The payload is sized by the number of bytes, `N`, required to
+/// represent the addresses of a network i.e. `N` is
+/// `address_bytes(addresses)`. Smaller networks therefore have
+/// smaller discovery frames. `N` defaults to a network of
+/// [MAX_ADDRESSES], and cannot exceed it.
+///
+/// Addresses replied to by more than one server in the previous round
+/// are reported as contested. A server holding a contested address must
+/// relinquish it and continue with discovery. The contested addresses
+/// are encoded after the address bit field and only when there are some,
+/// so that an [Identify] without them has the same encoding as a plain
+/// bit field.
+///
+/// The highest protocol version spoken by the client follows the contested
+/// addresses, and is encoded only when it is other than 0. An empty list of
+/// contested addresses is then encoded ahead of it. Servers unaware of the
+/// protocol version therefore ignore it.
+///
+/// The identifier of the client's network follows the protocol version,
+/// which is then always encoded, and is encoded only when there is one.
+/// Co-located networks sharing a discovery key are thereby kept apart:
+/// servers configured with a network identifier only reply to clients
+/// conveying it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Identify<const N: usize = DEFAULT_ADDRESS_BYTES> {
+    pub addresses: [u8; N],
+    #[cfg_attr(feature = "arbitrary", arbitrary(with = crate::arbitrary_vec))]
+    pub contested: Vec<u8, MAX_CONTESTED_ADDRESSES>,
+    pub protocol_version: u8,
+    pub network_id: Option<u32>,
 }
 
+const DEFAULT_ADDRESS_BYTES: usize = address_bytes(MAX_ADDRESSES);
+
 /// The payload a server replies with requesting an address
 /// to be assigned to.
-#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+///
+/// The server's details are optional and trailing so that servers
+/// not conveying them remain compatible with clients, and clients
+/// unaware of them decode the leading fields only.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Identified {
     /// The server address desired by the server.
     pub server_address: u8,
-    /// A bit field representing each port supported by
-    /// the server e.g. bit 1 represents that port 1 is
+    /// The ports supported by the server, conveyed as a
+    /// bit field e.g. bit 1 represents that port 1 is
     /// supported. The client application can then determine
     /// the type of server being represented given how
     /// each port is to be used.
-    pub server_ports: u8,
+    pub server_ports: PortSet,
+    /// The firmware version and capabilities of the server, if conveyed.
+    #[serde(
+        deserialize_with = "deserialise_last_field",
+        serialize_with = "serialise_last_field"
+    )]
+    pub details: Option<ServerDetails>,
+}
+
+/// The details are trailing and so are conveyed without an `Option`'s tag.
+impl MaxSize for Identified {
+    const POSTCARD_MAX_SIZE: usize =
+        u8::POSTCARD_MAX_SIZE + u8::POSTCARD_MAX_SIZE + ServerDetails::POSTCARD_MAX_SIZE;
+}
+
+/// Details of a server that a client may use to decide how to
+/// interact with it without further round-trips e.g. whether it
+/// requires a firmware update.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct ServerDetails {
+    /// The version of the firmware running on the server.
+    pub version: Version,
+    /// The protocol features supported by the server.
+    pub capabilities: Capabilities,
+    /// A bit field representing each protocol version spoken by the server
+    /// e.g. bit 1 represents that version 1 is spoken. Servers not conveying
+    /// it speak version 0 only.
+    #[serde(deserialize_with = "deserialise_protocol_versions")]
+    pub protocol_versions: u8,
+    /// The identifier of the network the server is joining, if any. See
+    /// [Identify].
+    #[serde(
+        deserialize_with = "deserialise_last_field",
+        serialize_with = "serialise_last_field"
+    )]
+    pub network_id: Option<u32>,
+}
+
+/// The network identifier is trailing and so is conveyed without an
+/// `Option`'s tag.
+impl MaxSize for ServerDetails {
+    const POSTCARD_MAX_SIZE: usize = Version::POSTCARD_MAX_SIZE
+        + Capabilities::POSTCARD_MAX_SIZE
+        + u8::POSTCARD_MAX_SIZE
+        + MAX_NETWORK_ID_SIZE;
+}
+
+/// The protocol versions of a server that does not convey them.
+pub const DEFAULT_PROTOCOL_VERSIONS: u8 = 0b00000001;
+
+// The protocol versions are trailing, having been introduced after the
+// other details.
+fn deserialise_protocol_versions<'de, D>(d: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(last_field(u8::deserialize(d).map(Some))?.unwrap_or(DEFAULT_PROTOCOL_VERSIONS))
+}
+
+/// A bit field of protocol features supported by a server.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Capabilities(pub u8);
+
+impl Capabilities {
+    /// Updates are signed.
+    pub const SIGNED_UPDATES: Self = Self(1 << 0);
+    /// Events may be replied to in batches.
+    pub const BATCHED_EVENTS: Self = Self(1 << 1);
+    /// Ports beyond those representable in the header are supported.
+    pub const EXTENDED_PORTS: Self = Self(1 << 2);
+    /// Requests may ask for the server's status.
+    pub const SERVER_STATUS: Self = Self(1 << 3);
+    /// Requests may filter the events replied by their class.
+    pub const FILTERED_EVENTS: Self = Self(1 << 4);
+    /// Requests may convey the client's time.
+    pub const CLIENT_TIME: Self = Self(1 << 5);
+    /// Requests may limit the length of the replies to them.
+    pub const MAX_REPLY_LEN: Self = Self(1 << 6);
+    /// Requests may ask for the ticks of events as of the server's ticks
+    /// rather than their ages.
+    pub const ABSOLUTE_TICKS: Self = Self(1 << 7);
+
+    /// No capabilities.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns true if all of the given capabilities are supported.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Declare support for the given capabilities.
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The payload a server replies to an [Identify] with.
+///
+/// A server that is busy e.g. writing to flash, may defer taking an address
+/// until a subsequent round of discovery. A deferral is conveyed as an
+/// [Identified] for address 0, which is always the client's, along with
+/// the number of rounds after which the server expects to be ready as its
+/// ports, if known, and no details. Clients unaware of deferrals therefore
+/// ignore them. See the module's documentation for the wire rule.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "Identified", into = "Identified")]
+pub enum IdentifyReply {
+    /// The server requests an address.
+    Identified(Identified),
+    /// The server is busy and defers to a later round.
+    Deferred { retry_after_rounds: Option<u8> },
+}
+
+impl Identified {
+    /// The identifier of the network the server is joining, if conveyed
+    /// along with its details.
+    pub fn network_id(&self) -> Option<u32> {
+        self.details.as_ref().and_then(|d| d.network_id)
+    }
+}
+
+/// Replies are conveyed as an [Identified].
+impl MaxSize for IdentifyReply {
+    const POSTCARD_MAX_SIZE: usize = Identified::POSTCARD_MAX_SIZE;
+}
+
+impl IdentifyReply {
+    /// The [Identified] replied with, if not deferred.
+    pub fn identified(&self) -> Option<&Identified> {
+        match self {
+            IdentifyReply::Identified(identified) => Some(identified),
+            IdentifyReply::Deferred { .. } => None,
+        }
+    }
+}
+
+/// An [Identified] for address 0 conveyed details, and so is neither a
+/// deferral nor a request for an address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidDeferral;
+
+impl fmt::Display for InvalidDeferral {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a deferral conveys no details")
+    }
+}
+
+impl TryFrom<Identified> for IdentifyReply {
+    type Error = InvalidDeferral;
+
+    fn try_from(identified: Identified) -> Result<Self, InvalidDeferral> {
+        match identified {
+            Identified {
+                server_address: 0,
+                server_ports,
+                details: None,
+            } => Ok(IdentifyReply::Deferred {
+                retry_after_rounds: Some(server_ports.bits()).filter(|r| *r != 0),
+            }),
+            Identified {
+                server_address: 0, ..
+            } => Err(InvalidDeferral),
+            identified => Ok(IdentifyReply::Identified(identified)),
+        }
+    }
+}
+
+impl From<IdentifyReply> for Identified {
+    fn from(reply: IdentifyReply) -> Self {
+        match reply {
+            IdentifyReply::Identified(identified) => identified,
+            IdentifyReply::Deferred { retry_after_rounds } => Identified {
+                server_address: 0,
+                server_ports: PortSet::from_bits(retry_after_rounds.unwrap_or(0)),
+                details: None,
+            },
+        }
+    }
+}
+
+/// The maximum number of ranges that can be reserved.
+pub const MAX_RESERVED_RANGES: usize = 8;
+
+/// Ranges of addresses that are permanently taken e.g. by servers with
+/// factory-fixed addresses configured via DIP switches. Reserved addresses
+/// are never selected by discovery, nor freed by the client. Ranges are
+/// inclusive and may overlap.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReservedRanges {
+    ranges: Vec<(u8, u8), MAX_RESERVED_RANGES>,
+}
+
+impl ReservedRanges {
+    /// No reserved addresses.
+    pub fn new() -> Self {
+        Self { ranges: Vec::new() }
+    }
+
+    /// Reserve the addresses from `start` to `end` inclusive. Returns false if
+    /// no more ranges can be reserved.
+    pub fn reserve(&mut self, start: u8, end: u8) -> bool {
+        self.ranges.push((start, end)).is_ok()
+    }
+
+    /// Returns true if a given address is reserved.
+    pub fn is_reserved(&self, address: u8) -> bool {
+        self.ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&address))
+    }
+
+    /// Iterate over the reserved ranges as their start and end addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.ranges.iter().copied()
+    }
 }
 
-impl Identify {
+/// An address is beyond those able to be represented on the network.
+#[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AddressOutOfRange(pub u8);
+
+impl<const N: usize> Identify<N> {
+    /// The number of addresses represented.
+    pub const ADDRESSES: usize = N * ADDRESSES_PER_BYTE;
+
+    /// A payload with no addresses known to the client.
+    pub fn new() -> Self {
+        Self {
+            addresses: [0; N],
+            contested: Vec::new(),
+            protocol_version: 0,
+            network_id: None,
+        }
+    }
+
+    /// Returns true if a given address has been reported as contested.
+    pub fn is_contested(&self, address: u8) -> bool {
+        self.contested.contains(&address)
+    }
+
     /// Returns true if a given address is known to the client.
     pub fn is_address_set(&self, address: u8) -> bool {
-        assert!((address as usize) < MAX_ADDRESSES);
-        (self.addresses[address as usize / ADDRESSES_PER_BYTE]
-            & 1 << (address % (ADDRESSES_PER_BYTE as u8)))
-            != 0
+        assert!((address as usize) < Self::ADDRESSES);
+        let (i, bit) = bit_position(address);
+        self.addresses[i] & bit != 0
     }
 
     /// An iterator that returns true for addresses known to the client.
-    pub fn iter(&self) -> AddressesIter {
+    pub fn iter(&self) -> AddressesIter<'_> {
+        const { assert!(N > 0 && N <= DEFAULT_ADDRESS_BYTES) };
         AddressesIter {
-            i: 0,
-            j: 1,
+            front: 0,
+            back: Self::ADDRESSES,
             addresses: &self.addresses,
         }
     }
@@ -60,201 +420,2860 @@ impl Identify {
     /// Modify the set of addresses known to the client with a new
     /// one.
     pub fn set_address(&mut self, address: u8) {
-        assert!((address as usize) < MAX_ADDRESSES);
-        self.addresses[address as usize / 8] |= 1 << (address % (ADDRESSES_PER_BYTE as u8));
+        self.try_set_address(address).unwrap()
+    }
+
+    /// As per `set_address`, but returns an error instead of panicking if
+    /// the address is out of range.
+    pub fn try_set_address(&mut self, address: u8) -> Result<(), AddressOutOfRange> {
+        if (address as usize) < Self::ADDRESSES {
+            let (i, bit) = bit_position(address);
+            self.addresses[i] |= bit;
+            Ok(())
+        } else {
+            Err(AddressOutOfRange(address))
+        }
+    }
+
+    /// Remove an address from the set of addresses known to the client e.g.
+    /// when a server has been decommissioned.
+    pub fn unset_address(&mut self, address: u8) {
+        self.try_unset_address(address).unwrap()
+    }
+
+    /// As per `unset_address`, but returns an error instead of panicking if
+    /// the address is out of range.
+    pub fn try_unset_address(&mut self, address: u8) -> Result<(), AddressOutOfRange> {
+        if (address as usize) < Self::ADDRESSES {
+            let (i, bit) = bit_position(address);
+            self.addresses[i] &= !bit;
+            Ok(())
+        } else {
+            Err(AddressOutOfRange(address))
+        }
+    }
+
+    /// Mark the addresses from `start` to `end` inclusive as taken e.g. for
+    /// servers with factory-fixed addresses. Nothing is marked if `end` is
+    /// out of range, and the range is empty if `start` is greater than `end`.
+    pub fn reserve_range(&mut self, start: u8, end: u8) -> Result<(), AddressOutOfRange> {
+        if start <= end && (end as usize) >= Self::ADDRESSES {
+            return Err(AddressOutOfRange(end));
+        }
+        for address in start..=end {
+            self.set_address(address);
+        }
+        Ok(())
+    }
+
+    /// Forget all addresses.
+    pub fn clear(&mut self) {
+        self.addresses = [0; N];
+    }
+
+    /// Set all addresses as known.
+    pub fn set_all(&mut self) {
+        self.addresses = [0xFF; N];
+    }
+
+    /// The number of addresses known to the client, including the client's
+    /// own address if set.
+    pub fn count_set(&self) -> usize {
+        self.addresses.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// The number of addresses that remain free.
+    pub fn count_free(&self) -> usize {
+        Self::ADDRESSES - self.count_set()
+    }
+
+    /// An iterator over the addresses known to the client, in ascending order.
+    pub fn iter_set(&self) -> impl Iterator<Item = u8> + '_ {
+        self.addresses
+            .iter()
+            .enumerate()
+            .flat_map(|(i, b)| BitsIter(*b).map(move |bit| (i * ADDRESSES_PER_BYTE) as u8 + bit))
+    }
+
+    /// An iterator over the addresses that remain free, in ascending order.
+    pub fn iter_free(&self) -> impl Iterator<Item = u8> + '_ {
+        self.addresses
+            .iter()
+            .enumerate()
+            .flat_map(|(i, b)| BitsIter(!*b).map(move |bit| (i * ADDRESSES_PER_BYTE) as u8 + bit))
+    }
+}
+
+/// Iterates over the positions of bits set within a byte, least
+/// significant first.
+struct BitsIter(u8);
+
+impl Iterator for BitsIter {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0 != 0 {
+            let bit = self.0.trailing_zeros() as u8;
+            self.0 &= self.0 - 1;
+            Some(bit)
+        } else {
+            None
+        }
+    }
+}
+
+impl<const N: usize> Default for Identify<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serde supports arrays of up to 32 elements only, so we provide our own
+/// encoding for arrays of any size. The encoding is identical i.e. a tuple
+/// of bytes.
+impl<const N: usize> Serialize for Identify<N> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let has_network_id = self.network_id.is_some();
+        let has_version = has_network_id || self.protocol_version != 0;
+        let has_contested = has_version || !self.contested.is_empty();
+        let mut t = s.serialize_tuple(
+            N + has_contested as usize + has_version as usize + has_network_id as usize,
+        )?;
+        for b in &self.addresses {
+            t.serialize_element(b)?;
+        }
+        if has_contested {
+            t.serialize_element(&self.contested)?;
+        }
+        if has_version {
+            t.serialize_element(&self.protocol_version)?;
+        }
+        if let Some(network_id) = &self.network_id {
+            t.serialize_element(network_id)?;
+        }
+        t.end()
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for Identify<N> {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct IdentifyVisitor<const N: usize>;
+
+        impl<'de, const N: usize> Visitor<'de> for IdentifyVisitor<N> {
+            type Value = Identify<N>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(
+                    f,
+                    "{N} bytes of addresses, optionally followed by those contested, a protocol version and a network identifier"
+                )
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut addresses = [0; N];
+                for (i, b) in addresses.iter_mut().enumerate() {
+                    *b = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+                // The remaining fields are optional and trailing, and so are
+                // absent should the input end before them.
+                let contested = last_field(seq.next_element())?.unwrap_or_default();
+                let protocol_version = last_field(seq.next_element())?.unwrap_or_default();
+                let network_id = last_field(seq.next_element())?;
+                Ok(Identify {
+                    addresses,
+                    contested,
+                    protocol_version,
+                    network_id,
+                })
+            }
+        }
+
+        d.deserialize_tuple(N + 3, IdentifyVisitor)
+    }
+}
+
+/// Construct the set of addresses known to the client from an iterator
+/// of addresses.
+impl<const N: usize> FromIterator<u8> for Identify<N> {
+    fn from_iter<T: IntoIterator<Item = u8>>(iter: T) -> Self {
+        let mut identify = Self::new();
+        for address in iter {
+            identify.set_address(address);
+        }
+        identify
     }
 }
 
 /// An iterator that returns true for each address known
-/// to the client.
+/// to the client. Addresses may also be iterated from the
+/// highest address downward.
+#[derive(Clone)]
 pub struct AddressesIter<'d> {
-    i: usize,
-    j: u8,
+    front: usize,
+    back: usize,
     addresses: &'d [u8],
 }
 
+impl AddressesIter<'_> {
+    fn is_set(&self, address: usize) -> bool {
+        self.addresses[address / ADDRESSES_PER_BYTE] & (1 << (address % ADDRESSES_PER_BYTE)) != 0
+    }
+}
+
 impl<'d> Iterator for AddressesIter<'d> {
     type Item = bool;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.i < MIN_PAYLOAD_SIZE {
-            let item = self.addresses[self.i] & self.j != 0;
-            self.j <<= 1;
-            if self.j == 0 {
-                self.i += 1;
-                self.j = 1;
-            }
+        if self.front < self.back {
+            let item = self.is_set(self.front);
+            self.front += 1;
             Some(item)
         } else {
             None
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.back - self.front;
+        (len, Some(len))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.front = self.front.saturating_add(n).min(self.back);
+        self.next()
+    }
 }
 
-impl Identified {
-    /// Attempt to determine an address given the addresses known to a client and
-    /// a random number generator. The function guarantees that no existing address
-    /// is returned, and randomly picks an address with the ones that remain to be
-    /// known to the client. A return value of None signals that no address can be
-    /// found. This can happen if there are no addresses left to be allocated.
-    ///
-    /// The `server_ports` parameter is as per the `Identified` structure's field
-    /// and conveys a bitmask of ports that are supported by the server. The returned
-    /// structure carries this field forward.
-    pub fn with_random_address<T>(
-        iter: AddressesIter<'_>,
-        rng: &mut T,
-        server_ports: u8,
-    ) -> Option<Self>
-    where
-        T: RngCore,
-    {
-        let mut spare_addresses = [0; MAX_ADDRESSES];
-        let mut j = 0;
-        for (i, taken) in iter.enumerate() {
-            if !taken {
-                spare_addresses[j] = i;
-                j += 1;
-            }
-        }
-        if j > 0 {
-            let j = (rng.next_u32() % (j as u32)) as usize;
-            Some(Self {
-                server_address: spare_addresses[j] as u8,
-                server_ports,
-            })
+impl<'d> DoubleEndedIterator for AddressesIter<'d> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front < self.back {
+            self.back -= 1;
+            Some(self.is_set(self.back))
         } else {
             None
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// There are always as many items as the addresses of the network to
+/// iterate over, less those already iterated.
+impl<'d> ExactSizeIterator for AddressesIter<'d> {}
+
+/// Where a client is within the discovery process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DiscoveryClientState {
+    Transmit,
+    AwaitingReplies,
+    Complete,
+}
 
-    struct RngFixture {
-        return_val: u32,
+/// A sans-IO state machine for the client side of discovery. The client
+/// broadcasts the [Identify] returned by `poll_transmit`, feeds each reply
+/// received within the time window to `handle_reply` (or `handle_corrupt_reply`
+/// when its MIC cannot be verified), and then calls `window_elapsed` once the
+/// window has passed. Rounds continue until no corrupt replies and no address
+/// conflicts have been observed within a window.
+///
+/// Servers may defer taking an address, in which case further rounds are
+/// also required. Deferrals are counted separately, and no more than a
+/// maximum number of rounds are repeated on account of them so that a
+/// server that is permanently busy does not prevent discovery completing.
+pub struct DiscoveryClient<const N: usize = DEFAULT_ADDRESS_BYTES> {
+    identify: Identify<N>,
+    reserved: ReservedRanges,
+    replied: [u8; N],
+    conflicted: [u8; N],
+    corrupt_replies: bool,
+    deferred: bool,
+    deferred_rounds: u32,
+    max_deferred_rounds: u32,
+    retry_after_rounds: Option<u8>,
+    rounds: u32,
+    state: DiscoveryClientState,
+}
+
+/// The default maximum number of rounds of discovery that are repeated on
+/// account of servers deferring.
+pub const DEFAULT_MAX_DEFERRED_ROUNDS: u32 = 3;
+
+impl<const N: usize> DiscoveryClient<N> {
+    /// Start discovery given those addresses already known to the client.
+    /// Address 0 always represents the client and is therefore set here.
+    pub fn new(identify: Identify<N>) -> Self {
+        Self::with_reserved(identify, ReservedRanges::new())
     }
 
-    impl RngCore for RngFixture {
-        fn next_u32(&mut self) -> u32 {
-            self.return_val
+    /// As per `new`, but with addresses that are reserved and therefore never
+    /// assigned through discovery, nor forgotten. Reserved addresses beyond the
+    /// network are ignored.
+    pub fn with_reserved(mut identify: Identify<N>, reserved: ReservedRanges) -> Self {
+        identify.set_address(0);
+        let last = (Identify::<N>::ADDRESSES - 1) as u8;
+        for (start, end) in reserved.iter().filter(|(start, _)| *start <= last) {
+            let _ = identify.reserve_range(start, end.min(last));
         }
-
-        fn next_u64(&mut self) -> u64 {
-            todo!()
+        Self {
+            identify,
+            reserved,
+            replied: [0; N],
+            conflicted: [0; N],
+            corrupt_replies: false,
+            deferred: false,
+            deferred_rounds: 0,
+            max_deferred_rounds: DEFAULT_MAX_DEFERRED_ROUNDS,
+            retry_after_rounds: None,
+            rounds: 0,
+            state: DiscoveryClientState::Transmit,
         }
+    }
+
+    /// The maximum number of rounds to repeat on account of servers deferring.
+    pub fn with_max_deferred_rounds(mut self, max_deferred_rounds: u32) -> Self {
+        self.max_deferred_rounds = max_deferred_rounds;
+        self
+    }
 
-        fn fill_bytes(&mut self, _dest: &mut [u8]) {
-            todo!()
+    /// Returns the [Identify] to broadcast if a new round is to begin. The
+    /// client should then await replies for its time window.
+    pub fn poll_transmit(&mut self) -> Option<Identify<N>> {
+        if self.state == DiscoveryClientState::Transmit {
+            self.replied = [0; N];
+            self.conflicted = [0; N];
+            self.corrupt_replies = false;
+            self.deferred = false;
+            self.rounds += 1;
+            self.state = DiscoveryClientState::AwaitingReplies;
+            let identify = self.identify.clone();
+            self.identify.contested.clear();
+            Some(identify)
+        } else {
+            None
         }
+    }
 
-        fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> {
-            todo!()
+    /// Note a valid reply from a server. Replies outside of a time window, for
+    /// addresses that are already known or beyond the network, or conveying the
+    /// identifier of another network, are ignored. Returns true if the reply was
+    /// noted e.g. for it to be recorded in an [AddressTable].
+    pub fn handle_reply(&mut self, identified: &Identified) -> bool {
+        if self.state != DiscoveryClientState::AwaitingReplies
+            || identified.server_address as usize >= Identify::<N>::ADDRESSES
+            || self.identify.is_address_set(identified.server_address)
+            || identified
+                .network_id()
+                .is_some_and(|id| self.identify.network_id != Some(id))
+        {
+            return false;
+        }
+        let (i, bit) = bit_position(identified.server_address);
+        if self.replied[i] & bit != 0 {
+            self.conflicted[i] |= bit;
+        } else {
+            self.replied[i] |= bit;
         }
+        true
     }
 
-    #[test]
-    fn test_set_get_bits() {
-        let mut identify = Identify {
-            addresses: [0; MIN_PAYLOAD_SIZE],
-        };
-        identify.set_address(1);
-        identify.set_address(9);
-        assert_eq!(identify.addresses[0], 0b00000010);
-        assert_eq!(identify.addresses[1], 0b00000010);
-        assert!(identify.is_address_set(1));
-        assert!(identify.is_address_set(9));
-        assert!(!identify.is_address_set(10));
+    /// Note a server deferring to a later round, along with the number of
+    /// rounds after which it expects to be ready, if known. Deferrals outside
+    /// of a time window are ignored.
+    pub fn handle_deferral(&mut self, retry_after_rounds: Option<u8>) {
+        if self.state == DiscoveryClientState::AwaitingReplies {
+            self.deferred = true;
+            self.retry_after_rounds = self.retry_after_rounds.max(retry_after_rounds);
+        }
     }
 
-    #[test]
-    fn test_identified_with_none_free() {
-        let mut identify = Identify {
-            addresses: [0; MIN_PAYLOAD_SIZE],
-        };
-        for address in 0..MAX_ADDRESSES {
-            identify.set_address(address as u8);
+    /// Note a reply that could not be decoded e.g. its MIC failed given that
+    /// two or more servers transmitted at the same time. Another round will
+    /// be required.
+    pub fn handle_corrupt_reply(&mut self) {
+        if self.state == DiscoveryClientState::AwaitingReplies {
+            self.corrupt_replies = true;
         }
-        let mut rng_fixture: RngFixture = RngFixture { return_val: 1 };
-        assert_eq!(
-            Identified::with_random_address(identify.iter(), &mut rng_fixture, 0b00000010),
-            None
-        );
     }
 
-    #[test]
-    fn test_identified_with_one_free() {
-        let mut identify = Identify {
-            addresses: [0; MIN_PAYLOAD_SIZE],
+    /// The time window for replies has passed. Addresses replied to by exactly
+    /// one server become known. Discovery completes when there were no corrupt
+    /// replies and no address conflicts.
+    pub fn window_elapsed(&mut self) {
+        if self.state != DiscoveryClientState::AwaitingReplies {
+            return;
+        }
+        let mut conflicts = false;
+        for i in 0..N {
+            self.identify.addresses[i] |= self.replied[i] & !self.conflicted[i];
+            let mut conflicted = BitsIter(self.conflicted[i]);
+            for bit in conflicted.by_ref() {
+                conflicts = true;
+                // Servers beyond those we can report will still find that
+                // their address is not known.
+                let _ = self
+                    .identify
+                    .contested
+                    .push((i * ADDRESSES_PER_BYTE) as u8 + bit);
+            }
+        }
+        let retry_deferred = if self.deferred {
+            self.deferred_rounds += 1;
+            self.deferred_rounds <= self.max_deferred_rounds
+        } else {
+            false
         };
-        for address in 2..MAX_ADDRESSES {
-            identify.set_address(address as u8);
+        self.state = if conflicts || self.corrupt_replies || retry_deferred {
+            DiscoveryClientState::Transmit
+        } else {
+            DiscoveryClientState::Complete
+        };
+    }
+
+    /// Report an address as contested e.g. when the application has detected
+    /// more than one server replying to it. The address is forgotten and a new
+    /// round of discovery begins. Returns false if the contested address cannot be
+    /// reported given that the report is full; discovery still recommences.
+    /// Reserved addresses cannot be contested.
+    pub fn contest_address(&mut self, address: u8) -> bool {
+        if (address as usize) >= Identify::<N>::ADDRESSES || self.reserved.is_reserved(address) {
+            return false;
         }
+        self.identify.unset_address(address);
+        self.state = DiscoveryClientState::Transmit;
+        self.identify.is_contested(address) || self.identify.contested.push(address).is_ok()
+    }
 
-        let mut rng_fixture: RngFixture = RngFixture { return_val: 1 };
-        assert_eq!(
-            Identified::with_random_address(identify.iter(), &mut rng_fixture, 0b00000010),
-            Some(Identified {
-                server_address: 1,
-                server_ports: 0b00000010,
-            })
-        );
+    /// Returns true when discovery has completed.
+    pub fn is_complete(&self) -> bool {
+        self.state == DiscoveryClientState::Complete
     }
 
-    #[test]
-    fn test_identified_with_three_free() {
-        let mut identify = Identify {
-            addresses: [0; MIN_PAYLOAD_SIZE],
-        };
-        identify.set_address(0);
-        for address in 4..MAX_ADDRESSES {
-            identify.set_address(address as u8);
-        }
+    /// The addresses known to the client so far.
+    pub fn identify(&self) -> &Identify<N> {
+        &self.identify
+    }
 
-        let mut rng_fixture: RngFixture = RngFixture { return_val: 2 };
-        assert_eq!(
-            Identified::with_random_address(identify.iter(), &mut rng_fixture, 0b00000010),
-            Some(Identified {
-                server_address: 3,
-                server_ports: 0b00000010,
-            })
-        );
+    /// The addresses reserved by the client.
+    pub fn reserved(&self) -> &ReservedRanges {
+        &self.reserved
     }
 
-    #[test]
-    fn test_identified_with_all_but_first_free() {
-        let mut identify = Identify {
-            addresses: [0; MIN_PAYLOAD_SIZE],
-        };
-        identify.set_address(0);
+    /// The number of rounds in which servers deferred.
+    pub fn deferred_rounds(&self) -> u32 {
+        self.deferred_rounds
+    }
 
-        let mut rng_fixture: RngFixture = RngFixture { return_val: 254 };
-        assert_eq!(
-            Identified::with_random_address(identify.iter(), &mut rng_fixture, 0b00000010),
-            Some(Identified {
-                server_address: 255,
-                server_ports: 0b00000010,
-            })
-        );
+    /// The greatest number of rounds that deferring servers have hinted at
+    /// requiring before being ready e.g. to determine when discovery should
+    /// be run again should it complete beforehand.
+    pub fn retry_after_rounds(&self) -> Option<u8> {
+        self.retry_after_rounds
+    }
+
+    /// The number of rounds of discovery that have begun.
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+}
+
+/// A server known to a client.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ServerEntry {
+    /// The address of the server.
+    pub server_address: u8,
+    /// True once the address is known to the client i.e. the server has been
+    /// assigned it.
+    pub assigned: bool,
+    /// The unique identifier of the server, if known e.g. from its request
+    /// to join.
+    pub uid: Option<Uid>,
+    /// The ports supported by the server as per [Identified].
+    pub server_ports: PortSet,
+    /// The firmware version and capabilities of the server, if conveyed.
+    #[serde(with = "persisted_details")]
+    pub details: Option<ServerDetails>,
+    /// When the server was last seen, in ticks of the client's clock.
+    pub last_seen: Option<u64>,
+    /// When the server's lease of its address expires, in ticks of the
+    /// client's clock, if ever.
+    pub lease_expiry: Option<u64>,
+}
+
+// Server details end with optional fields when conveyed by a server, which
+// cannot be followed by others. All of their fields are therefore persisted.
+mod persisted_details {
+    use super::*;
+
+    type Persisted = (Version, Capabilities, u8, Option<u32>);
+
+    pub fn serialize<S>(details: &Option<ServerDetails>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        details
+            .as_ref()
+            .map(|d| {
+                (
+                    &d.version,
+                    d.capabilities,
+                    d.protocol_versions,
+                    d.network_id,
+                )
+            })
+            .serialize(s)
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> Result<Option<ServerDetails>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<Persisted>::deserialize(d)?.map(
+            |(version, capabilities, protocol_versions, network_id)| ServerDetails {
+                version,
+                capabilities,
+                protocol_versions,
+                network_id,
+            },
+        ))
+    }
+}
+
+/// The outcome of reconciling a server's unique identifier with the address
+/// that it has been observed at.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Reconciliation {
+    /// The server is at the address recorded for it, and its identifier is
+    /// now recorded if it was not already.
+    Unchanged,
+    /// The address is not recorded.
+    Unknown,
+    /// The server's identifier is recorded at another address, or the address
+    /// is recorded with another identifier, e.g. the server changed address
+    /// while the client was down. The entries for both addresses have been
+    /// removed and the server should be rediscovered by contesting both.
+    Moved { from: Option<u8>, to: u8 },
+}
+
+/// The servers known to a client along with what they conveyed when
+/// identifying themselves, holding up to `M` servers. Each valid reply
+/// is recorded and, once a discovery window has elapsed, the table
+/// retains only those servers whose addresses became known.
+///
+/// The table may be persisted e.g. to flash, so that a client is able to
+/// resume with the addresses already assigned following a restart. Servers
+/// that have since changed their address are then reconciled by their
+/// unique identifiers.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AddressTable<const M: usize = MAX_ADDRESSES> {
+    entries: Vec<ServerEntry, M>,
+}
+
+impl<const M: usize> AddressTable<M> {
+    /// An empty table.
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Record a server's reply, replacing any entry for the same address.
+    /// Returns false if the table is full.
+    pub fn record(&mut self, identified: &Identified) -> bool {
+        let entry = ServerEntry {
+            server_address: identified.server_address,
+            assigned: false,
+            uid: None,
+            server_ports: identified.server_ports,
+            details: identified.details.clone(),
+            last_seen: None,
+            lease_expiry: None,
+        };
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| e.server_address == entry.server_address)
+        {
+            Some(existing) => {
+                *existing = entry;
+                true
+            }
+            None => self.entries.push(entry).is_ok(),
+        }
+    }
+
+    /// Remove those servers whose addresses are not known to the client,
+    /// including those that are contested. Those remaining are assigned.
+    pub fn retain_known<const N: usize>(&mut self, identify: &Identify<N>) {
+        self.entries.retain(|e| {
+            (e.server_address as usize) < Identify::<N>::ADDRESSES
+                && identify.is_address_set(e.server_address)
+                && !identify.is_contested(e.server_address)
+        });
+        for entry in self.entries.iter_mut() {
+            entry.assigned = true;
+        }
+    }
+
+    /// The addresses known to the client given those assigned, for seeding
+    /// discovery e.g. following a restart.
+    pub fn to_identify<const N: usize>(&self) -> Identify<N> {
+        let mut identify = Identify::new();
+        identify.set_address(0);
+        for entry in self.entries.iter().filter(|e| e.assigned) {
+            let _ = identify.try_set_address(entry.server_address);
+        }
+        identify
+    }
+
+    /// Record the unique identifier of a server. Returns false if the server
+    /// is not in the table.
+    pub fn set_uid(&mut self, server_address: u8, uid: Uid) -> bool {
+        self.get_mut(server_address)
+            .map(|e| e.uid = Some(uid))
+            .is_some()
+    }
+
+    /// Note when a server was last seen.
+    pub fn note_seen(&mut self, server_address: u8, now: u64) {
+        if let Some(entry) = self.get_mut(server_address) {
+            entry.last_seen = Some(now);
+        }
+    }
+
+    /// Set when a server's lease of its address expires.
+    pub fn renew_lease(&mut self, server_address: u8, lease_expiry: u64) {
+        if let Some(entry) = self.get_mut(server_address) {
+            entry.lease_expiry = Some(lease_expiry);
+        }
+    }
+
+    /// The servers whose leases have expired, other than those reserved.
+    pub fn expired_leases<'a>(
+        &'a self,
+        now: u64,
+        reserved: &'a ReservedRanges,
+    ) -> impl Iterator<Item = u8> + 'a {
+        self.entries
+            .iter()
+            .filter(move |e| e.lease_expiry.is_some_and(|expiry| expiry <= now))
+            .map(|e| e.server_address)
+            .filter(|a| !reserved.is_reserved(*a))
+    }
+
+    /// Remove a server, returning its entry if it was present.
+    pub fn remove(&mut self, server_address: u8) -> Option<ServerEntry> {
+        let i = self
+            .entries
+            .iter()
+            .position(|e| e.server_address == server_address)?;
+        Some(self.entries.swap_remove(i))
+    }
+
+    /// Reconcile a server's unique identifier with the address it has been
+    /// observed at e.g. having received its request to join after a restart.
+    pub fn reconcile(&mut self, uid: &Uid, server_address: u8) -> Reconciliation {
+        let from = self
+            .entries
+            .iter()
+            .find(|e| e.uid.as_ref() == Some(uid))
+            .map(|e| e.server_address);
+        match (from, self.get_mut(server_address)) {
+            (Some(from), Some(_)) if from == server_address => Reconciliation::Unchanged,
+            (None, Some(entry)) if entry.uid.is_none() => {
+                entry.uid = Some(*uid);
+                Reconciliation::Unchanged
+            }
+            (None, None) => Reconciliation::Unknown,
+            (from, _) => {
+                if let Some(from) = from {
+                    self.remove(from);
+                }
+                self.remove(server_address);
+                Reconciliation::Moved {
+                    from,
+                    to: server_address,
+                }
+            }
+        }
+    }
+
+    /// The entry for a given address, if any.
+    pub fn get(&self, server_address: u8) -> Option<&ServerEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.server_address == server_address)
+    }
+
+    /// Iterate over the servers known.
+    pub fn iter(&self) -> impl Iterator<Item = &ServerEntry> {
+        self.entries.iter()
+    }
+
+    fn get_mut(&mut self, server_address: u8) -> Option<&mut ServerEntry> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.server_address == server_address)
+    }
+
+    /// The highest protocol version, no higher than `highest`, spoken by each
+    /// of the given servers e.g. to determine the version of the header for
+    /// frames destined to them. Servers that are not known, or that have not
+    /// conveyed their details, speak version 0 only. None is returned if the
+    /// servers have no version in common.
+    pub fn highest_protocol_version(
+        &self,
+        server_addresses: impl IntoIterator<Item = u8>,
+        highest: u8,
+    ) -> Option<u8> {
+        let versions = server_addresses
+            .into_iter()
+            .map(|a| self.protocol_versions(a))
+            .fold(u8::MAX, |common, versions| common & versions);
+        let versions = versions & (u8::MAX >> (7 - highest.min(7)));
+        (versions != 0).then(|| 7 - versions.leading_zeros() as u8)
+    }
+
+    /// As per `highest_protocol_version`, for all of the servers known.
+    pub fn highest_common_protocol_version(&self, highest: u8) -> Option<u8> {
+        self.highest_protocol_version(self.entries.iter().map(|e| e.server_address), highest)
+    }
+
+    fn protocol_versions(&self, server_address: u8) -> u8 {
+        self.get(server_address)
+            .and_then(|e| e.details.as_ref())
+            .map(|d| d.protocol_versions)
+            .unwrap_or(DEFAULT_PROTOCOL_VERSIONS)
+    }
+
+    /// The servers to prepare for an update to a given version i.e. those
+    /// that conveyed an older version along with the capabilities required.
+    /// Servers that did not convey their details are not included.
+    pub fn update_candidates<'a>(
+        &'a self,
+        version: &'a Version,
+        required: Capabilities,
+    ) -> impl Iterator<Item = &'a ServerEntry> {
+        self.entries.iter().filter(move |e| {
+            e.details
+                .as_ref()
+                .map(|d| d.version < *version && d.capabilities.contains(required))
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// A reply that a server is to transmit in response to an [Identify].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiscoveryReply {
+    /// The payload to reply with.
+    pub reply: IdentifyReply,
+    /// The number of ticks to wait from having received the [Identify]
+    /// before transmitting the reply.
+    pub delay_ticks: u32,
+}
+
+impl DiscoveryReply {
+    /// The [Identified] to reply with, if not deferred.
+    pub fn identified(&self) -> Option<&Identified> {
+        self.reply.identified()
+    }
+}
+
+/// How a server selects the address it requests during discovery.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressPolicy {
+    /// Select an address randomly from those free.
+    Random,
+    /// Select an address derived from the server's unique identifier so that
+    /// the same address is requested whenever it is free. The hash is that of
+    /// [uid_hash]. Should the address be contested within a round then the
+    /// server falls back to random selection for the remainder of discovery.
+    Deterministic { uid_hash: u32 },
+}
+
+impl AddressPolicy {
+    /// Conveniently declare a deterministic policy from a unique identifier.
+    pub fn deterministic(uid: &[u8]) -> Self {
+        AddressPolicy::Deterministic {
+            uid_hash: uid_hash(uid),
+        }
+    }
+}
+
+/// Persists the address held by a server so that it may be preferred
+/// when discovery recommences after a restart.
+pub trait AddressStore {
+    /// Returns the address previously saved, if any.
+    fn load(&mut self) -> Option<u8>;
+    /// Save the address now known to the client.
+    fn save(&mut self, server_address: u8);
+}
+
+/// No storage of addresses.
+impl AddressStore for () {
+    fn load(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn save(&mut self, _server_address: u8) {}
+}
+
+/// A sans-IO state machine for the server side of discovery. Each [Identify]
+/// received is passed to `handle_identify` which determines whether to reply,
+/// with what, and when. Replies are spread over a number of slots within the
+/// client's time window so as to reduce the chance of collisions on the wire.
+/// Each slot is preceded by a guard time, and ticks are of whatever resolution
+/// the server's timer uses. See [SlotSchedule].
+///
+/// An [AddressStore] may be provided to persist the address once known to the
+/// client. The stored address is then preferred for the first reply following
+/// a restart.
+///
+/// A server configured with a network identifier only replies to an [Identify]
+/// conveying it. A server without one replies to any client, but once it has
+/// claimed an address it ignores other networks until the address is either
+/// known to the client, whereupon the server adopts the client's network
+/// identifier, or relinquished.
+pub struct DiscoveryServer<S = ()> {
+    store: S,
+    preferred: Option<u8>,
+    policy: AddressPolicy,
+    server_address: Option<u8>,
+    server_ports: PortSet,
+    details: Option<ServerDetails>,
+    busy: Busy,
+    schedule: SlotSchedule,
+    network_id: Option<u32>,
+    claimed_network_id: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Busy {
+    No,
+    Yes,
+    ForRounds(u8),
+}
+
+impl DiscoveryServer {
+    /// Create a new discovery responder for a server supporting the ports
+    /// given (see [Identified]), replying within a window of contiguous
+    /// slots as per [SlotSchedule::contiguous]. Each slot lasts the guard
+    /// time followed by the time on the wire of a reply's frame, and so
+    /// `frame_ticks + guard_ticks` in all. Addresses are selected randomly.
+    pub fn new(
+        server_ports: PortSet,
+        reply_slots: u32,
+        frame_ticks: u32,
+        guard_ticks: u32,
+    ) -> Result<Self, SlotScheduleError> {
+        Self::with_policy(
+            AddressPolicy::Random,
+            server_ports,
+            reply_slots,
+            frame_ticks,
+            guard_ticks,
+        )
+    }
+
+    /// As per `new`, but with a given policy for selecting addresses.
+    pub fn with_policy(
+        policy: AddressPolicy,
+        server_ports: PortSet,
+        reply_slots: u32,
+        frame_ticks: u32,
+        guard_ticks: u32,
+    ) -> Result<Self, SlotScheduleError> {
+        Ok(Self {
+            store: (),
+            preferred: None,
+            policy,
+            server_address: None,
+            server_ports,
+            details: None,
+            busy: Busy::No,
+            schedule: SlotSchedule::contiguous(reply_slots, guard_ticks, frame_ticks)?,
+            network_id: None,
+            claimed_network_id: None,
+        })
+    }
+
+    /// Provide storage for the server's address, restoring any preference
+    /// from it.
+    pub fn with_store<T>(self, mut store: T) -> DiscoveryServer<T>
+    where
+        T: AddressStore,
+    {
+        DiscoveryServer {
+            preferred: store.load(),
+            store,
+            policy: self.policy,
+            server_address: self.server_address,
+            server_ports: self.server_ports,
+            details: self.details,
+            busy: self.busy,
+            schedule: self.schedule,
+            network_id: self.network_id,
+            claimed_network_id: self.claimed_network_id,
+        }
+    }
+}
+
+impl<S> DiscoveryServer<S> {
+    /// Spread replies over the slots of a given schedule instead.
+    pub fn with_schedule(mut self, schedule: SlotSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Convey the server's firmware version and capabilities with each reply.
+    pub fn with_details(mut self, details: ServerDetails) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// Only reply to clients of a given network e.g. as previously adopted
+    /// and persisted by the application. The identifier is conveyed to the
+    /// client within the server's details, if any.
+    pub fn with_network_id(mut self, network_id: u32) -> Self {
+        self.network_id = Some(network_id);
+        self
+    }
+
+    /// The identifier of the network the server belongs to, if configured or
+    /// adopted.
+    pub fn network_id(&self) -> Option<u32> {
+        self.network_id
+    }
+}
+
+impl<S> DiscoveryServer<S>
+where
+    S: AddressStore,
+{
+    /// Declare the server as busy or not. A busy server that requires an
+    /// address defers to a later round of discovery.
+    pub fn set_busy(&mut self, busy: bool) {
+        self.busy = if busy { Busy::Yes } else { Busy::No };
+    }
+
+    /// Declare the server as busy for a number of rounds of discovery, hinting
+    /// as much to the client when deferring.
+    pub fn set_busy_for_rounds(&mut self, rounds: u8) {
+        self.busy = if rounds > 0 {
+            Busy::ForRounds(rounds)
+        } else {
+            Busy::No
+        };
+    }
+
+    /// Handle an [Identify] from the client. If the address held by the server
+    /// is already known to the client then no reply is required. Otherwise a new
+    /// address is selected and a reply returned along with the delay to wait
+    /// before transmitting it. None is also returned if no addresses remain. A
+    /// held address reported as contested is relinquished and is no longer
+    /// preferred. A busy server relinquishes any address not yet known to the
+    /// client and replies with a deferral. An [Identify] of another network is
+    /// ignored.
+    pub fn handle_identify<T, const N: usize>(
+        &mut self,
+        identify: &Identify<N>,
+        rng: &mut T,
+    ) -> Option<DiscoveryReply>
+    where
+        T: RngCore,
+    {
+        let other_network = match self.network_id {
+            Some(network_id) => identify.network_id != Some(network_id),
+            None => self.server_address.is_some() && identify.network_id != self.claimed_network_id,
+        };
+        if other_network {
+            return None;
+        }
+        self.claimed_network_id = identify.network_id;
+        let identified = match (self.server_address, self.policy) {
+            (Some(server_address), _)
+                if identify.is_address_set(server_address)
+                    && !identify.is_contested(server_address) =>
+            {
+                if self.preferred != Some(server_address) {
+                    self.store.save(server_address);
+                    self.preferred = Some(server_address);
+                }
+                if self.network_id.is_none() {
+                    self.network_id = identify.network_id;
+                }
+                return None;
+            }
+            (server_address, _) if self.busy != Busy::No => {
+                if server_address.is_some_and(|a| identify.is_contested(a))
+                    && self.preferred == server_address
+                {
+                    self.preferred = None;
+                }
+                self.server_address = None;
+                let retry_after_rounds = match self.busy {
+                    Busy::ForRounds(rounds) => {
+                        self.set_busy_for_rounds(rounds - 1);
+                        Some(rounds)
+                    }
+                    _ => None,
+                };
+                return Some(self.reply(IdentifyReply::Deferred { retry_after_rounds }, rng));
+            }
+            (None, AddressPolicy::Deterministic { uid_hash }) => {
+                Identified::with_free_preferred(identify.iter(), self.preferred, self.server_ports)
+                    .or_else(|| {
+                        Identified::with_address_from(identify.iter(), uid_hash, self.server_ports)
+                    })
+            }
+            (None, AddressPolicy::Random) => Identified::with_random_address(
+                identify.iter(),
+                rng,
+                self.server_ports,
+                self.preferred,
+            ),
+            (Some(server_address), _) => {
+                if identify.is_contested(server_address) && self.preferred == Some(server_address) {
+                    self.preferred = None;
+                }
+                Identified::with_random_address(identify.iter(), rng, self.server_ports, None)
+            }
+        };
+        self.server_address = identified.as_ref().map(|i| i.server_address);
+        identified.map(|mut identified| {
+            identified.details.clone_from(&self.details);
+            if let Some(details) = identified.details.as_mut() {
+                details.network_id = identify.network_id;
+            }
+            self.reply(IdentifyReply::Identified(identified), rng)
+        })
+    }
+
+    fn reply<T>(&self, reply: IdentifyReply, rng: &mut T) -> DiscoveryReply
+    where
+        T: RngCore,
+    {
+        let slot = self.schedule.random_slot(rng);
+        DiscoveryReply {
+            reply,
+            delay_ticks: self.schedule.transmit_offset(slot),
+        }
+    }
+
+    /// The address last claimed by the server, if any. The address is known
+    /// to the client once an [Identify] has it set.
+    pub fn server_address(&self) -> Option<u8> {
+        self.server_address
+    }
+}
+
+/// The hash of a server's unique identifier used to derive its preferred
+/// address. This is the 32 bit FNV-1a hash of the identifier's bytes and must
+/// remain stable across releases so that servers retain their address.
+pub fn uid_hash(uid: &[u8]) -> u32 {
+    uid.iter().fold(0x811c9dc5, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn bit_position(address: u8) -> (usize, u8) {
+    (
+        address as usize / ADDRESSES_PER_BYTE,
+        1 << (address % (ADDRESSES_PER_BYTE as u8)),
+    )
+}
+
+impl Identified {
+    /// Attempt to determine an address given the addresses known to a client and
+    /// a random number generator. The function guarantees that no existing address
+    /// is returned, and randomly picks an address with the ones that remain to be
+    /// known to the client. A return value of None signals that no address can be
+    /// found. This can happen if there are no addresses left to be allocated.
+    ///
+    /// The `server_ports` parameter is as per the `Identified` structure's field
+    /// and conveys the ports that are supported by the server. The returned
+    /// structure carries this field forward.
+    ///
+    /// The address chosen is the n-th free address where n is the random number
+    /// generator's `next_u32` modulo the number of free addresses. Test fixtures
+    /// supplying a fixed number can rely on this mapping.
+    ///
+    /// A `preferred` address, typically one held by the server prior to a restart,
+    /// is returned if it is free. Address 0 is reserved for the client and is never
+    /// preferred.
+    pub fn with_random_address<T>(
+        iter: AddressesIter<'_>,
+        rng: &mut T,
+        server_ports: PortSet,
+        preferred: Option<u8>,
+    ) -> Option<Self>
+    where
+        T: RngCore,
+    {
+        if let Some(identified) = Self::with_free_preferred(iter.clone(), preferred, server_ports) {
+            return Some(identified);
+        }
+        // Two passes over the addresses are made so that memory use remains
+        // constant: one to count those free and one to find the chosen one.
+        let free = iter.clone().filter(|taken| !taken).count();
+        if free > 0 {
+            let n = (rng.next_u32() % (free as u32)) as usize;
+            iter.enumerate()
+                .filter(|(_, taken)| !taken)
+                .nth(n)
+                .map(|(i, _)| Self {
+                    server_address: i as u8,
+                    server_ports,
+                    details: None,
+                })
+        } else {
+            None
+        }
+    }
+
+    /// Determine an address given the addresses known to a client and a server's
+    /// unique identifier. The identifier is hashed with [uid_hash] to an address
+    /// which is returned if it is free. Otherwise the addresses following it are
+    /// probed, wrapping around, and the first free one is returned. The same
+    /// identifier will therefore result in the same address whenever it is free.
+    /// A return value of None signals that no address can be found.
+    pub fn with_preferred_address(
+        iter: AddressesIter<'_>,
+        uid: &[u8],
+        server_ports: PortSet,
+    ) -> Option<Self> {
+        Self::with_address_from(iter, uid_hash(uid), server_ports)
+    }
+
+    fn with_free_preferred(
+        iter: AddressesIter<'_>,
+        preferred: Option<u8>,
+        server_ports: PortSet,
+    ) -> Option<Self> {
+        preferred
+            .filter(|p| *p != 0)
+            .filter(|p| iter.clone().nth(*p as usize) == Some(false))
+            .map(|server_address| Self {
+                server_address,
+                server_ports,
+                details: None,
+            })
+    }
+
+    fn with_address_from(
+        iter: AddressesIter<'_>,
+        hash: u32,
+        server_ports: PortSet,
+    ) -> Option<Self> {
+        let start = hash as usize % iter.len();
+        iter.clone()
+            .enumerate()
+            .skip(start)
+            .chain(iter.enumerate().take(start))
+            .find(|(_, taken)| !taken)
+            .map(|(i, _)| Self {
+                server_address: i as u8,
+                server_ports,
+                details: None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        port::Port,
+        test_util::{assert_frames_eq, Frame, StepRng},
+        update::PreRelease,
+        DataSource, Header,
+    };
+
+    #[test]
+    fn test_set_get_bits() {
+        let mut identify = <Identify>::new();
+        identify.set_address(1);
+        identify.set_address(9);
+        assert_eq!(identify.addresses[0], 0b00000010);
+        assert_eq!(identify.addresses[1], 0b00000010);
+        assert!(identify.is_address_set(1));
+        assert!(identify.is_address_set(9));
+        assert!(!identify.is_address_set(10));
+    }
+
+    #[test]
+    fn test_set_unset_round_trip() {
+        let mut identify = <Identify>::new();
+        for address in 0..=u8::MAX {
+            assert_eq!(identify.try_set_address(address), Ok(()));
+            assert!(identify.is_address_set(address));
+            assert!((0..=u8::MAX)
+                .filter(|a| *a != address)
+                .all(|a| !identify.is_address_set(a)));
+            assert_eq!(identify.try_unset_address(address), Ok(()));
+            assert_eq!(identify, <Identify>::new());
+        }
+    }
+
+    #[test]
+    fn test_clear_and_set_all() {
+        let mut identify = <Identify>::from_iter([0, 9, 255]);
+        assert!(identify.is_address_set(0));
+        assert!(identify.is_address_set(9));
+        assert!(identify.is_address_set(255));
+        assert!(!identify.is_address_set(10));
+
+        identify.set_all();
+        assert!(identify.iter().all(|is_set| is_set));
+        identify.unset_address(255);
+        assert!(!identify.is_address_set(255));
+
+        identify.clear();
+        assert!(identify.iter().all(|is_set| !is_set));
+    }
+
+    #[test]
+    fn test_counting_and_iterating_empty() {
+        let identify = <Identify>::new();
+        assert_eq!(identify.count_set(), 0);
+        assert_eq!(identify.count_free(), MAX_ADDRESSES);
+        assert_eq!(identify.iter_set().next(), None);
+        assert!(identify.iter_free().map(usize::from).eq(0..MAX_ADDRESSES));
+    }
+
+    #[test]
+    fn test_counting_and_iterating_full() {
+        let mut identify = <Identify>::new();
+        identify.set_all();
+        assert_eq!(identify.count_set(), MAX_ADDRESSES);
+        assert_eq!(identify.count_free(), 0);
+        assert!(identify.iter_set().map(usize::from).eq(0..MAX_ADDRESSES));
+        assert_eq!(identify.iter_free().next(), None);
+    }
+
+    #[test]
+    fn test_counting_and_iterating_sparse() {
+        let identify = <Identify>::from_iter([0, 7, 8, 100, 255]);
+        assert_eq!(identify.count_set(), 5);
+        assert_eq!(identify.count_free(), MAX_ADDRESSES - 5);
+        assert!(identify.iter_set().eq([0, 7, 8, 100, 255]));
+        assert_eq!(identify.iter_free().count(), MAX_ADDRESSES - 5);
+        assert!(identify.iter_free().take(7).eq([1, 2, 3, 4, 5, 6, 9]));
+        assert_eq!(identify.iter_free().last(), Some(254));
+    }
+
+    #[test]
+    fn test_identify_serialisation() {
+        let identify = <Identify>::from_iter([0, 9, 255]);
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identify).unwrap();
+        assert_eq!(serialised.len(), address_bytes(MAX_ADDRESSES));
+        assert_eq!(serialised[0], 0b00000001);
+        assert_eq!(serialised[1], 0b00000010);
+        assert_eq!(serialised[31], 0b10000000);
+        assert_eq!(
+            postcard::from_bytes::<Identify>(&serialised).unwrap(),
+            identify
+        );
+    }
+
+    #[test]
+    fn test_eight_address_network() {
+        const N: usize = address_bytes(8);
+        assert_eq!(N, 1);
+        assert_eq!(min_packet_size(8), HEADER_SIZE + 1 + MIC_SIZE);
+        assert_eq!(
+            max_identify_payload_size(8),
+            1 + 1 + MAX_CONTESTED_ADDRESSES + 1 + MAX_NETWORK_ID_SIZE
+        );
+        assert_eq!(Identify::<N>::ADDRESSES, 8);
+
+        let mut identify = Identify::<N>::new();
+        identify.set_address(0);
+        identify.set_address(7);
+        assert_eq!(identify.try_set_address(8), Err(AddressOutOfRange(8)));
+        assert_eq!(identify.count_free(), 6);
+        assert_eq!(identify.iter().len(), 8);
+        assert!(identify.iter_free().eq(1..=6));
+
+        let serialised = postcard::to_vec::<_, N>(&identify).unwrap();
+        assert_eq!(serialised, [0b10000001]);
+        assert_eq!(
+            postcard::from_bytes::<Identify<N>>(&serialised).unwrap(),
+            identify
+        );
+        assert!(postcard::from_bytes::<Identify<N>>(&[]).is_err());
+
+        // Every random number must land on one of the free addresses.
+        for return_val in 0..64 {
+            let mut rng = StepRng::new(return_val, 0);
+            let identified = Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0),
+                None,
+            )
+            .unwrap();
+            assert!((1..=6).contains(&identified.server_address));
+        }
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut StepRng::new(0, 0),
+                PortSet::from_bits(0),
+                Some(200)
+            ),
+            Some(Identified {
+                server_address: 1,
+                server_ports: PortSet::from_bits(0),
+                details: None,
+            })
+        );
+        assert!(Identified::with_preferred_address(
+            identify.iter(),
+            b"some uid",
+            PortSet::from_bits(0)
+        )
+        .map(|i| i.server_address < 8)
+        .unwrap());
+
+        identify.set_all();
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut StepRng::new(0, 0),
+                PortSet::from_bits(0),
+                None
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_sixty_four_address_network() {
+        const N: usize = address_bytes(64);
+        assert_eq!(N, 8);
+
+        let mut client = DiscoveryClient::new(Identify::<N>::new());
+        let identify = client.poll_transmit().unwrap();
+        assert_eq!(postcard::to_vec::<_, N>(&identify).unwrap().len(), N);
+
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1).unwrap();
+        let reply = server
+            .handle_identify(&identify, &mut StepRng::new(62, 0))
+            .unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 63);
+        client.handle_reply(reply.identified().unwrap());
+        client.handle_reply(&Identified {
+            server_address: 64,
+            server_ports: PortSet::from_bits(0),
+            details: None,
+        });
+        client.window_elapsed();
+        assert!(client.is_complete());
+        assert!(client.identify().iter_set().eq([0, 63]));
+    }
+
+    #[test]
+    fn test_identified_with_none_free() {
+        let mut identify = <Identify>::new();
+        for address in 0..MAX_ADDRESSES {
+            identify.set_address(address as u8);
+        }
+        let mut rng = StepRng::new(1, 0);
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0b00000010),
+                None
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_identified_with_one_free() {
+        let mut identify = <Identify>::new();
+        for address in 2..MAX_ADDRESSES {
+            identify.set_address(address as u8);
+        }
+
+        let mut rng = StepRng::new(1, 0);
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0b00000010),
+                None
+            ),
+            Some(Identified {
+                server_address: 1,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_identified_with_three_free() {
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+        for address in 4..MAX_ADDRESSES {
+            identify.set_address(address as u8);
+        }
+
+        let mut rng = StepRng::new(2, 0);
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0b00000010),
+                None
+            ),
+            Some(Identified {
+                server_address: 3,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_identified_with_all_but_first_free() {
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+
+        let mut rng = StepRng::new(254, 0);
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0b00000010),
+                None
+            ),
+            Some(Identified {
+                server_address: 255,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_identified_with_preferred_free() {
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+
+        let mut rng = StepRng::new(1, 0);
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0b00000010),
+                Some(42)
+            ),
+            Some(Identified {
+                server_address: 42,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_identified_with_preferred_taken() {
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+        identify.set_address(42);
+
+        let mut rng = StepRng::new(1, 0);
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0b00000010),
+                Some(42)
+            ),
+            Some(Identified {
+                server_address: 2,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_identified_with_preferred_reserved() {
+        let identify = <Identify>::new();
+
+        let mut rng = StepRng::new(1, 0);
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut rng,
+                PortSet::from_bits(0b00000010),
+                Some(0)
+            ),
+            Some(Identified {
+                server_address: 1,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_uid_hash() {
+        // Pinned so that derived addresses remain stable across releases.
+        assert_eq!(uid_hash(b""), 0x811c9dc5);
+        assert_eq!(uid_hash(b"a"), 0xe40c292c);
+        assert_eq!(uid_hash(b"foobar"), 0xbf9cf968);
+        assert_eq!(uid_hash(&[0x01, 0x02, 0x03, 0x04]), 0x5734a87d);
+    }
+
+    #[test]
+    fn test_identified_with_preferred_address() {
+        let uid = [0x01, 0x02, 0x03, 0x04];
+
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+        assert_eq!(
+            Identified::with_preferred_address(
+                identify.iter(),
+                &uid,
+                PortSet::from_bits(0b00000010)
+            ),
+            Some(Identified {
+                server_address: 125,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+
+        identify.set_address(125);
+        identify.set_address(126);
+        assert_eq!(
+            Identified::with_preferred_address(
+                identify.iter(),
+                &uid,
+                PortSet::from_bits(0b00000010)
+            ),
+            Some(Identified {
+                server_address: 127,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+
+        // Wrap around to the beginning.
+        for address in 125..MAX_ADDRESSES {
+            identify.set_address(address as u8);
+        }
+        assert_eq!(
+            Identified::with_preferred_address(
+                identify.iter(),
+                &uid,
+                PortSet::from_bits(0b00000010)
+            ),
+            Some(Identified {
+                server_address: 1,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+        );
+
+        for address in 0..MAX_ADDRESSES {
+            identify.set_address(address as u8);
+        }
+        assert_eq!(
+            Identified::with_preferred_address(
+                identify.iter(),
+                &uid,
+                PortSet::from_bits(0b00000010)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let identify = <Identify>::from_iter([0, 3, 8, 100, 254]);
+        let mut forward = identify.iter().collect::<std::vec::Vec<_>>();
+        forward.reverse();
+        assert!(identify.iter().rev().eq(forward));
+        assert_eq!(
+            identify.iter().rev().position(|is_set| is_set),
+            Some(MAX_ADDRESSES - 1 - 254)
+        );
+
+        let mut iter = identify.iter();
+        assert_eq!(iter.next(), Some(true));
+        assert_eq!(iter.next_back(), Some(false));
+        assert_eq!(iter.next_back(), Some(true));
+        assert_eq!(iter.len(), MAX_ADDRESSES - 3);
+    }
+
+    #[test]
+    fn test_iter_len() {
+        let identify = <Identify>::from_iter([1, 2]);
+        let mut iter = identify.iter();
+        assert_eq!(iter.len(), MAX_ADDRESSES);
+        for consumed in 1..=MAX_ADDRESSES {
+            iter.next().unwrap();
+            assert_eq!(iter.len() + consumed, MAX_ADDRESSES);
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.len(), 0);
+
+        let mut iter = identify.iter();
+        assert_eq!(iter.nth(2), Some(true));
+        assert_eq!(iter.len(), MAX_ADDRESSES - 3);
+        assert_eq!(iter.nth(MAX_ADDRESSES), None);
+    }
+
+    #[test]
+    fn test_iter_with_skip() {
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+        identify.set_address(3);
+
+        assert_eq!(
+            identify
+                .iter()
+                .enumerate()
+                .skip(1)
+                .find(|(_, is_set)| *is_set),
+            Some((3, true))
+        );
+    }
+
+    // Run discovery between a client and servers, conveying each frame as
+    // bytes, until the client completes or the rounds given elapse. Each
+    // server selects with its own generator, and replies are received in the
+    // order of their delays. The frames conveyed are returned for comparing
+    // with those scripted.
+    fn run_discovery(
+        client: &mut DiscoveryClient,
+        servers: &mut [(DiscoveryServer, StepRng)],
+        max_rounds: u32,
+    ) -> std::vec::Vec<Frame> {
+        let mut frames = std::vec::Vec::new();
+        while !client.is_complete() && client.rounds() < max_rounds {
+            let Some(identify) = client.poll_transmit() else {
+                break;
+            };
+            let frame = Frame::encoded("client", &identify);
+            let identify: Identify = postcard::from_bytes(&frame.bytes).unwrap();
+            frames.push(frame);
+
+            let mut replies = servers
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(i, (server, rng))| {
+                    let reply = server.handle_identify(&identify, rng)?;
+                    Some((
+                        reply.delay_ticks,
+                        Frame::encoded(format!("server {i}"), &reply.reply),
+                    ))
+                })
+                .collect::<std::vec::Vec<_>>();
+            replies.sort_by_key(|(delay_ticks, _)| *delay_ticks);
+            for (_, frame) in replies {
+                match postcard::from_bytes(&frame.bytes).unwrap() {
+                    IdentifyReply::Identified(identified) => {
+                        client.handle_reply(&identified);
+                    }
+                    IdentifyReply::Deferred { retry_after_rounds } => {
+                        client.handle_deferral(retry_after_rounds)
+                    }
+                }
+                frames.push(frame);
+            }
+            client.window_elapsed();
+        }
+        frames
+    }
+
+    fn identified(server_address: u8, server_ports: u8) -> Identified {
+        Identified {
+            server_address,
+            server_ports: PortSet::from_bits(server_ports),
+            details: None,
+        }
+    }
+
+    #[test]
+    fn test_discovery_client_completes_with_no_replies() {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        let frames = run_discovery(&mut client, &mut [], 10);
+        assert_frames_eq(
+            &[Frame::encoded("client", &<Identify>::from_iter([0]))],
+            &frames,
+        );
+        assert!(client.is_complete());
+        assert_eq!(client.poll_transmit(), None);
+        assert_eq!(client.rounds(), 1);
+    }
+
+    #[test]
+    fn test_discovery_client_with_duplicates_and_corruption() {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+
+        // Round 1: two servers want 5, one wants 9 and a reply is garbled.
+        client.poll_transmit().unwrap();
+        for server_address in [5, 9, 5] {
+            client.handle_reply(&Identified {
+                server_address,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            });
+        }
+        client.handle_corrupt_reply();
+        client.window_elapsed();
+        assert!(!client.is_complete());
+        assert!(client.identify().is_address_set(9));
+        assert!(!client.identify().is_address_set(5));
+
+        // Round 2: the contenders pick distinct addresses but one is garbled.
+        let identify = client.poll_transmit().unwrap();
+        assert!(identify.is_address_set(9));
+        client.handle_reply(&Identified {
+            server_address: 7,
+            server_ports: PortSet::from_bits(0b00000010),
+            details: None,
+        });
+        client.handle_corrupt_reply();
+        client.window_elapsed();
+        assert!(!client.is_complete());
+
+        // Round 3: the remaining server replies alone and we're done.
+        client.poll_transmit().unwrap();
+        client.handle_reply(&Identified {
+            server_address: 200,
+            server_ports: PortSet::from_bits(0b00000010),
+            details: None,
+        });
+        client.window_elapsed();
+        assert!(client.is_complete());
+        assert_eq!(client.rounds(), 3);
+
+        assert!(client.identify().iter_set().eq([0, 7, 9, 200]));
+    }
+
+    #[test]
+    fn test_discovery_server_replies_until_known() {
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1).unwrap();
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+
+        let mut rng = StepRng::new(3, 0);
+        let reply = server.handle_identify(&identify, &mut rng).unwrap();
+        assert_eq!(
+            reply,
+            DiscoveryReply {
+                reply: IdentifyReply::Identified(Identified {
+                    server_address: 4,
+                    server_ports: PortSet::from_bits(0b00000010),
+                    details: None,
+                }),
+                delay_ticks: 3 * 3 + 1,
+            }
+        );
+        assert_eq!(server.server_address(), Some(4));
+
+        // Not yet known, so we must reply again, albeit with a new address.
+        let mut rng = StepRng::new(0, 0);
+        let reply = server.handle_identify(&identify, &mut rng).unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 1);
+        assert_eq!(reply.delay_ticks, 1);
+
+        identify.set_address(1);
+        assert_eq!(server.handle_identify(&identify, &mut rng), None);
+        assert_eq!(server.server_address(), Some(1));
+    }
+
+    #[test]
+    fn test_discovery_server_with_deterministic_policy() {
+        let mut server = DiscoveryServer::with_policy(
+            AddressPolicy::deterministic(&[0x01, 0x02, 0x03, 0x04]),
+            PortSet::from_bits(0b00000010),
+            10,
+            2,
+            1,
+        )
+        .unwrap();
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+
+        let mut rng = StepRng::new(0, 0);
+        let reply = server.handle_identify(&identify, &mut rng).unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 125);
+
+        // Contested, so fall back to random selection.
+        let reply = server.handle_identify(&identify, &mut rng).unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 1);
+    }
+
+    #[test]
+    fn test_discovery_server_restores_preference() {
+        #[derive(Default)]
+        struct StoreFixture {
+            server_address: Option<u8>,
+            saves: u32,
+        }
+
+        impl AddressStore for &mut StoreFixture {
+            fn load(&mut self) -> Option<u8> {
+                self.server_address
+            }
+
+            fn save(&mut self, server_address: u8) {
+                self.server_address = Some(server_address);
+                self.saves += 1;
+            }
+        }
+
+        let mut store = StoreFixture::default();
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+        let mut rng = StepRng::new(41, 0);
+
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1)
+            .unwrap()
+            .with_store(&mut store);
+        let reply = server.handle_identify(&identify, &mut rng).unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 42);
+        identify.set_address(42);
+        assert_eq!(server.handle_identify(&identify, &mut rng), None);
+        assert_eq!(server.handle_identify(&identify, &mut rng), None);
+        assert_eq!(store.server_address, Some(42));
+        assert_eq!(store.saves, 1);
+
+        // The client restarts and the server is to prefer its previous address.
+        let mut identify = <Identify>::new();
+        identify.set_address(0);
+        let mut rng = StepRng::new(0, 0);
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1)
+            .unwrap()
+            .with_store(&mut store);
+        let reply = server.handle_identify(&identify, &mut rng).unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 42);
+    }
+
+    #[test]
+    fn test_discovery_server_drops_contested_preference() {
+        let mut saved = None;
+        struct StoreFixture<'a>(&'a mut Option<u8>);
+
+        impl AddressStore for StoreFixture<'_> {
+            fn load(&mut self) -> Option<u8> {
+                *self.0
+            }
+
+            fn save(&mut self, server_address: u8) {
+                *self.0 = Some(server_address);
+            }
+        }
+
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1)
+            .unwrap()
+            .with_store(StoreFixture(&mut saved));
+        let mut identify = <Identify>::from_iter([0]);
+        let reply = server
+            .handle_identify(&identify, &mut StepRng::new(41, 0))
+            .unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 42);
+        identify.set_address(42);
+        assert_eq!(
+            server.handle_identify(&identify, &mut StepRng::new(0, 0)),
+            None
+        );
+
+        // Another server is found to also hold 42.
+        identify.unset_address(42);
+        identify.contested.push(42).unwrap();
+        let reply = server
+            .handle_identify(&identify, &mut StepRng::new(6, 0))
+            .unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 7);
+        identify.contested.clear();
+        identify.set_address(7);
+        assert_eq!(
+            server.handle_identify(&identify, &mut StepRng::new(0, 0)),
+            None
+        );
+        assert_eq!(saved, Some(7));
     }
 
     #[test]
-    fn test_iter_with_skip() {
-        let mut identify = Identify {
-            addresses: [0; MIN_PAYLOAD_SIZE],
+    fn test_discovery_server_spreads_replies_across_slots() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        const SLOTS: u32 = 8;
+        const REPLIES: u32 = 8000;
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let identify = <Identify>::new();
+        let mut slot_counts = [0u32; SLOTS as usize];
+        for _ in 0..REPLIES {
+            let mut server =
+                DiscoveryServer::new(PortSet::from_bits(0b00000010), SLOTS, 5, 2).unwrap();
+            let reply = server.handle_identify(&identify, &mut rng).unwrap();
+            assert_eq!((reply.delay_ticks - 2) % 7, 0);
+            slot_counts[((reply.delay_ticks - 2) / 7) as usize] += 1;
+        }
+
+        // Each slot should be within 10% of a uniform distribution.
+        let expected = REPLIES / SLOTS;
+        for count in slot_counts {
+            assert!(count.abs_diff(expected) < expected / 10, "{slot_counts:?}");
+        }
+    }
+
+    #[test]
+    fn test_discovery_client_reports_contested() {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        client.poll_transmit().unwrap();
+        for server_address in [5, 9, 5, 200, 200, 200] {
+            client.handle_reply(&Identified {
+                server_address,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            });
+        }
+        client.window_elapsed();
+
+        let identify = client.poll_transmit().unwrap();
+        assert!(identify.contested.iter().eq(&[5, 200]));
+        assert!(!identify.is_address_set(5));
+        assert!(identify.is_address_set(9));
+        client.window_elapsed();
+        assert!(client.is_complete());
+        assert!(client.identify().contested.is_empty());
+
+        // The application detects that two servers hold 9.
+        assert!(client.contest_address(9));
+        assert!(!client.is_complete());
+        let identify = client.poll_transmit().unwrap();
+        assert!(identify.contested.iter().eq(&[9]));
+        assert!(!identify.is_address_set(9));
+    }
+
+    #[test]
+    fn test_discovery_server_relinquishes_contested() {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        let mut servers = [
+            (
+                DiscoveryServer::new(PortSet::from_bits(0b00000010), 10, 2, 1).unwrap(),
+                StepRng::new(4, 0),
+            ),
+            (
+                DiscoveryServer::new(PortSet::from_bits(0b00000100), 10, 2, 1).unwrap(),
+                StepRng::new(4, 1),
+            ),
+        ];
+        let frames = run_discovery(&mut client, &mut servers, 10);
+
+        // Both servers pick the same address, relinquish it and choose
+        // again, the second server's choice moving on with its generator.
+        let mut identify = <Identify>::from_iter([0]);
+        let first_round = Frame::encoded("client", &identify);
+        identify.contested.push(5).unwrap();
+        assert_frames_eq(
+            &[
+                first_round,
+                Frame::encoded("server 0", &identified(5, 0b00000010)),
+                Frame::encoded("server 1", &identified(5, 0b00000100)),
+                Frame::encoded("client", &identify),
+                Frame::encoded("server 0", &identified(5, 0b00000010)),
+                Frame::encoded("server 1", &identified(7, 0b00000100)),
+            ],
+            &frames,
+        );
+        assert!(client.is_complete());
+
+        let identify = client.identify();
+        assert!(identify.contested.is_empty());
+        for (server, rng) in servers.iter_mut() {
+            assert_eq!(server.handle_identify(identify, rng), None);
+        }
+        assert_eq!(servers[0].0.server_address(), Some(5));
+        assert_eq!(servers[1].0.server_address(), Some(7));
+    }
+
+    #[test]
+    fn test_discovery_client_ignores_replies_outside_of_window() {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        client.handle_reply(&Identified {
+            server_address: 1,
+            server_ports: PortSet::from_bits(0),
+            details: None,
+        });
+        client.handle_corrupt_reply();
+        client.poll_transmit().unwrap();
+        client.window_elapsed();
+        assert!(client.is_complete());
+        assert!(!client.identify().is_address_set(1));
+    }
+
+    fn details(major: u8, capabilities: Capabilities) -> ServerDetails {
+        ServerDetails {
+            version: Version {
+                major,
+                minor: 2,
+                patch: 3,
+                pre: None,
+            },
+            capabilities,
+            protocol_versions: DEFAULT_PROTOCOL_VERSIONS,
+            network_id: None,
+        }
+    }
+
+    #[test]
+    fn test_malformed_trailing_identify() {
+        // A network identifier of an overlong varint is malformed, and so is
+        // not taken as absent.
+        let mut serialised = [0; 32 + 7];
+        serialised[32..].copy_from_slice(&[0, 1, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(postcard::from_bytes::<Identify>(&serialised).is_err());
+        assert_eq!(
+            postcard::from_bytes::<Identify>(&serialised[..34]).unwrap(),
+            Identify {
+                protocol_version: 1,
+                ..Identify::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_malformed_trailing_details() {
+        // A network identifier of an overlong varint is malformed, and so is
+        // not taken as absent.
+        let serialised = [
+            5, 0b00000010, 1, 2, 3, 0, 0b00000011, 1, 0xff, 0xff, 0xff, 0xff, 0xff,
+        ];
+        assert!(postcard::from_bytes::<Identified>(&serialised).is_err());
+        let identified = postcard::from_bytes::<Identified>(&serialised[..8]).unwrap();
+        assert!(identified.details.is_some());
+        assert_eq!(identified.network_id(), None);
+
+        // Details of a malformed pre-release are not taken as absent either.
+        let serialised = [5, 0b00000010, 1, 2, 3, 2];
+        assert!(postcard::from_bytes::<Identified>(&serialised).is_err());
+    }
+
+    #[test]
+    fn test_identified_serialisation_with_details() {
+        let identified = Identified {
+            server_address: 5,
+            server_ports: PortSet::from_bits(0b00000010),
+            details: Some(details(
+                1,
+                Capabilities::SIGNED_UPDATES | Capabilities::BATCHED_EVENTS,
+            )),
         };
-        identify.set_address(0);
-        identify.set_address(3);
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identified).unwrap();
+        assert_eq!(
+            serialised,
+            [
+                5,
+                0b00000010,
+                1,
+                2,
+                3,
+                0,
+                0b00000011,
+                DEFAULT_PROTOCOL_VERSIONS
+            ]
+        );
+        assert_eq!(
+            postcard::from_bytes::<Identified>(&serialised).unwrap(),
+            identified
+        );
+
+        let largest = Identified {
+            server_address: 255,
+            server_ports: PortSet::from_bits(0xff),
+            details: Some(ServerDetails {
+                version: Version {
+                    major: 255,
+                    minor: 255,
+                    patch: 255,
+                    pre: Some(PreRelease::Beta(255)),
+                },
+                capabilities: Capabilities(0xff),
+                protocol_versions: 0xff,
+                network_id: Some(u32::MAX),
+            }),
+        };
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&largest).unwrap();
+        assert_eq!(serialised.len(), IdentifyReply::POSTCARD_MAX_SIZE);
+        const { assert!(IdentifyReply::POSTCARD_MAX_SIZE <= MIN_PAYLOAD_SIZE) };
+        assert_eq!(
+            postcard::from_bytes::<Identified>(&serialised).unwrap(),
+            largest
+        );
+    }
+
+    #[test]
+    fn test_identified_compatibility() {
+        // As decoded by a client unaware of a server's details.
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct OldIdentified {
+            server_address: u8,
+            server_ports: u8,
+        }
+
+        let identified = Identified {
+            server_address: 5,
+            server_ports: PortSet::from_bits(0b00000010),
+            details: Some(details(1, Capabilities::EXTENDED_PORTS)),
+        };
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identified).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<OldIdentified>(&serialised).unwrap(),
+            OldIdentified {
+                server_address: 5,
+                server_ports: 0b00000010,
+            }
+        );
+
+        // As sent by a server not conveying its details.
+        assert_eq!(
+            postcard::from_bytes::<Identified>(&[5, 0b00000010]).unwrap(),
+            Identified {
+                server_address: 5,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            }
+        );
+        assert_eq!(
+            postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&Identified {
+                server_address: 5,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: None,
+            })
+            .unwrap(),
+            [5, 0b00000010]
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut capabilities = Capabilities::empty();
+        assert!(!capabilities.contains(Capabilities::SIGNED_UPDATES));
+        capabilities.insert(Capabilities::SIGNED_UPDATES);
+        assert!(capabilities.contains(Capabilities::SIGNED_UPDATES));
+        assert!(!capabilities.contains(Capabilities::SIGNED_UPDATES | Capabilities::EXTENDED_PORTS));
+        assert!(capabilities.contains(Capabilities::empty()));
+    }
+
+    #[test]
+    fn test_address_table_records_server_details() {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        let mut table = <AddressTable>::new();
+
+        let identify = client.poll_transmit().unwrap();
+        let mut servers = [
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1)
+                .unwrap()
+                .with_details(details(1, Capabilities::SIGNED_UPDATES)),
+            DiscoveryServer::new(PortSet::from_bits(0b00000100), 1, 2, 1)
+                .unwrap()
+                .with_details(details(2, Capabilities::SIGNED_UPDATES)),
+            DiscoveryServer::new(PortSet::from_bits(0b00000100), 1, 2, 1)
+                .unwrap()
+                .with_details(details(1, Capabilities::empty())),
+            DiscoveryServer::new(PortSet::from_bits(0b00001000), 1, 2, 1).unwrap(),
+            DiscoveryServer::new(PortSet::from_bits(0b00010000), 1, 2, 1).unwrap(),
+        ];
+        for (return_val, server) in [0, 1, 2, 3, 3].into_iter().zip(servers.iter_mut()) {
+            let reply = server
+                .handle_identify(&identify, &mut StepRng::new(return_val, 0))
+                .unwrap();
+            client.handle_reply(reply.identified().unwrap());
+            assert!(table.record(reply.identified().unwrap()));
+        }
+        client.window_elapsed();
+        table.retain_known(client.identify());
+
+        // The conflict on address 4 leaves it out of the table.
+        assert!(table.iter().map(|e| e.server_address).eq([1, 2, 3]));
+        assert_eq!(
+            table.get(2).unwrap().details,
+            Some(details(2, Capabilities::SIGNED_UPDATES))
+        );
+        assert_eq!(table.get(4), None);
+
+        let version = Version {
+            major: 2,
+            minor: 0,
+            patch: 0,
+            pre: None,
+        };
+        assert!(table
+            .update_candidates(&version, Capabilities::SIGNED_UPDATES)
+            .map(|e| e.server_address)
+            .eq([1]));
+        assert!(table
+            .update_candidates(&version, Capabilities::empty())
+            .map(|e| e.server_address)
+            .eq([1, 3]));
+    }
+
+    #[test]
+    fn test_address_table_capacity() {
+        let mut table = AddressTable::<1>::new();
+        let identified = |server_address| Identified {
+            server_address,
+            server_ports: PortSet::from_bits(0),
+            details: None,
+        };
+        assert!(table.record(&identified(1)));
+        assert!(table.record(&identified(1)));
+        assert!(!table.record(&identified(2)));
+    }
+
+    #[test]
+    fn test_reserved_ranges_overlapping() {
+        let mut reserved = ReservedRanges::new();
+        assert!(reserved.reserve(10, 20));
+        assert!(reserved.reserve(15, 30));
+        assert!(reserved.reserve(40, 40));
+        assert!(!reserved.is_reserved(9));
+        assert!((10..=30).all(|a| reserved.is_reserved(a)));
+        assert!(!reserved.is_reserved(31));
+        assert!(reserved.is_reserved(40));
+
+        let mut client = DiscoveryClient::with_reserved(<Identify>::new(), reserved);
+        let identify = client.poll_transmit().unwrap();
+        assert_eq!(identify.count_set(), 1 + 21 + 1);
+        assert!(identify
+            .iter_set()
+            .eq([0].into_iter().chain(10..=30).chain([40])));
+        assert!(client.reserved().is_reserved(20));
+
+        // Every random number must avoid the reserved addresses.
+        for return_val in 0..256 {
+            let identified = Identified::with_random_address(
+                identify.iter(),
+                &mut StepRng::new(return_val, 0),
+                PortSet::from_bits(0),
+                Some(20),
+            )
+            .unwrap();
+            assert!(!client.reserved().is_reserved(identified.server_address));
+        }
+
+        client.window_elapsed();
+        assert!(!client.contest_address(20));
+        assert!(client.identify().is_address_set(20));
+    }
+
+    #[test]
+    fn test_reserve_range() {
+        let mut identify = Identify::<1>::new();
+        assert_eq!(identify.reserve_range(2, 8), Err(AddressOutOfRange(8)));
+        assert_eq!(identify.count_set(), 0);
+        assert_eq!(identify.reserve_range(5, 2), Ok(()));
+        assert_eq!(identify.count_set(), 0);
+        assert_eq!(identify.reserve_range(2, 7), Ok(()));
+        assert!(identify.iter_set().eq(2..=7));
+
+        // Reservations beyond a small network are clamped to it.
+        let mut reserved = ReservedRanges::new();
+        reserved.reserve(6, 200);
+        reserved.reserve(100, 200);
+        let client = DiscoveryClient::with_reserved(Identify::<1>::new(), reserved);
+        assert!(client.identify().iter_set().eq([0, 6, 7]));
+    }
+
+    #[test]
+    fn test_fully_reserved_space() {
+        let mut reserved = ReservedRanges::new();
+        reserved.reserve(1, 255);
+        let mut client = DiscoveryClient::with_reserved(<Identify>::new(), reserved);
+        let identify = client.poll_transmit().unwrap();
+        assert_eq!(identify.count_free(), 0);
+
+        assert_eq!(
+            Identified::with_random_address(
+                identify.iter(),
+                &mut StepRng::new(0, 0),
+                PortSet::from_bits(0),
+                None
+            ),
+            None
+        );
+        assert_eq!(
+            Identified::with_preferred_address(identify.iter(), b"some uid", PortSet::from_bits(0)),
+            None
+        );
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap();
+        assert_eq!(
+            server.handle_identify(&identify, &mut StepRng::new(0, 0)),
+            None
+        );
+
+        client.window_elapsed();
+        assert!(client.is_complete());
+    }
+
+    #[test]
+    fn test_identify_serialisation_with_protocol_version() {
+        let mut identify = Identify::<1>::from_iter([0, 7]);
+        identify.protocol_version = 1;
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identify).unwrap();
+        assert_eq!(serialised, [0b10000001, 0, 1]);
+        assert_eq!(
+            postcard::from_bytes::<Identify<1>>(&serialised).unwrap(),
+            identify
+        );
 
+        identify.contested.push(3).unwrap();
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identify).unwrap();
+        assert_eq!(serialised, [0b10000001, 1, 3, 1]);
         assert_eq!(
+            postcard::from_bytes::<Identify<1>>(&serialised).unwrap(),
             identify
+        );
+
+        // As sent by a client speaking version 0.
+        identify.protocol_version = 0;
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identify).unwrap();
+        assert_eq!(serialised, [0b10000001, 1, 3]);
+        assert_eq!(
+            postcard::from_bytes::<Identify<1>>(&serialised).unwrap(),
+            identify
+        );
+    }
+
+    #[test]
+    fn test_server_details_without_protocol_versions() {
+        let identified =
+            postcard::from_bytes::<Identified>(&[5, 0b00000010, 1, 2, 3, 0, 0b00000001]).unwrap();
+        assert_eq!(
+            identified.details,
+            Some(details(1, Capabilities::SIGNED_UPDATES))
+        );
+        assert_eq!(
+            identified.details.unwrap().protocol_versions,
+            DEFAULT_PROTOCOL_VERSIONS
+        );
+    }
+
+    #[test]
+    fn test_protocol_version_per_destination() {
+        const CLIENT_PROTOCOL_VERSION: u8 = 1;
+
+        let mut identify = <Identify>::new();
+        identify.protocol_version = CLIENT_PROTOCOL_VERSION;
+        let mut client = DiscoveryClient::new(identify);
+        let mut table = <AddressTable>::new();
+
+        let identify = client.poll_transmit().unwrap();
+        assert_eq!(identify.protocol_version, CLIENT_PROTOCOL_VERSION);
+        let mut servers = [
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1)
+                .unwrap()
+                .with_details(ServerDetails {
+                    protocol_versions: 0b00000011,
+                    ..details(1, Capabilities::empty())
+                }),
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap(),
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1)
+                .unwrap()
+                .with_details(ServerDetails {
+                    protocol_versions: 0b00000111,
+                    ..details(1, Capabilities::empty())
+                }),
+        ];
+        for (return_val, server) in servers.iter_mut().enumerate() {
+            let reply = server
+                .handle_identify(&identify, &mut StepRng::new(return_val as u64, 0))
+                .unwrap();
+            client.handle_reply(reply.identified().unwrap());
+            table.record(reply.identified().unwrap());
+        }
+        client.window_elapsed();
+        table.retain_known(client.identify());
+        assert!(client.is_complete());
+
+        // Each poll within the loop speaks the highest version in common.
+        let headers = client
+            .identify()
+            .iter_set()
+            .skip(1)
+            .map(|server_address| Header {
+                version: table
+                    .highest_protocol_version([server_address], CLIENT_PROTOCOL_VERSION)
+                    .unwrap(),
+                source: DataSource::Client,
+                server_address,
+                server_port: Port::new(1).unwrap(),
+                frame_counter: 0,
+            });
+        assert!(headers
+            .map(|h| (h.server_address, h.version))
+            .eq([(1, 1), (2, 0), (3, 1)]));
+
+        assert_eq!(
+            table.highest_common_protocol_version(CLIENT_PROTOCOL_VERSION),
+            Some(0)
+        );
+        assert_eq!(table.highest_protocol_version([1, 3], 7), Some(1));
+        assert_eq!(table.highest_protocol_version([3], 7), Some(2));
+        assert_eq!(table.highest_protocol_version([], 7), Some(7));
+        table.record(&Identified {
+            server_address: 4,
+            server_ports: PortSet::from_bits(0),
+            details: Some(ServerDetails {
+                protocol_versions: 0b00000010,
+                ..details(1, Capabilities::empty())
+            }),
+        });
+        assert_eq!(table.highest_common_protocol_version(7), None);
+    }
+
+    #[test]
+    fn test_identify_reply_serialisation() {
+        let deferred = IdentifyReply::Deferred {
+            retry_after_rounds: Some(2),
+        };
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&deferred).unwrap();
+        assert_eq!(serialised, [0, 2]);
+        assert_eq!(
+            postcard::from_bytes::<IdentifyReply>(&serialised).unwrap(),
+            deferred
+        );
+        let deferred = IdentifyReply::Deferred {
+            retry_after_rounds: None,
+        };
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&deferred).unwrap();
+        assert_eq!(serialised, [0, 0]);
+        assert_eq!(
+            postcard::from_bytes::<IdentifyReply>(&serialised).unwrap(),
+            deferred
+        );
+
+        // A deferral is ignored by clients unaware of them.
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        client.poll_transmit().unwrap();
+        client.handle_reply(&postcard::from_bytes::<Identified>(&serialised).unwrap());
+        client.window_elapsed();
+        assert!(client.is_complete());
+
+        let identified = Identified {
+            server_address: 5,
+            server_ports: PortSet::from_bits(0b00000010),
+            details: Some(details(1, Capabilities::empty())),
+        };
+        let reply = IdentifyReply::Identified(identified.clone());
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&reply).unwrap();
+        assert_eq!(
+            serialised,
+            postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identified).unwrap()
+        );
+        assert_eq!(
+            postcard::from_bytes::<IdentifyReply>(&serialised).unwrap(),
+            reply
+        );
+
+        // A reply for address 0 conveying details is not a deferral, and is
+        // refused rather than its details being dropped.
+        let identified = Identified {
+            server_address: 0,
+            ..identified
+        };
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identified).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<IdentifyReply>(&serialised),
+            Err(postcard::Error::SerdeDeCustom)
+        );
+        assert_eq!(IdentifyReply::try_from(identified), Err(InvalidDeferral));
+    }
+
+    #[test]
+    fn test_server_defers_twice_then_joins() {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap();
+        server.set_busy_for_rounds(2);
+
+        for retry_after_rounds in [2, 1] {
+            let identify = client.poll_transmit().unwrap();
+            let reply = server
+                .handle_identify(&identify, &mut StepRng::new(0, 0))
+                .unwrap();
+            assert_eq!(
+                reply.reply,
+                IdentifyReply::Deferred {
+                    retry_after_rounds: Some(retry_after_rounds)
+                }
+            );
+            assert_eq!(reply.delay_ticks, 1);
+            assert_eq!(server.server_address(), None);
+            client.handle_deferral(Some(retry_after_rounds));
+            client.window_elapsed();
+            assert!(!client.is_complete());
+        }
+        assert_eq!(client.deferred_rounds(), 2);
+        assert_eq!(client.retry_after_rounds(), Some(2));
+
+        let identify = client.poll_transmit().unwrap();
+        let reply = server
+            .handle_identify(&identify, &mut StepRng::new(0, 0))
+            .unwrap();
+        client.handle_reply(reply.identified().unwrap());
+        client.window_elapsed();
+        assert!(client.is_complete());
+        assert_eq!(client.rounds(), 3);
+        assert!(client.identify().is_address_set(1));
+        assert_eq!(
+            server.handle_identify(client.identify(), &mut StepRng::new(0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_permanently_busy_server() {
+        let mut client = DiscoveryClient::new(<Identify>::new()).with_max_deferred_rounds(2);
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap();
+        server.set_busy(true);
+
+        while let Some(identify) = client.poll_transmit() {
+            let reply = server
+                .handle_identify(&identify, &mut StepRng::new(0, 0))
+                .unwrap();
+            assert_eq!(
+                reply.reply,
+                IdentifyReply::Deferred {
+                    retry_after_rounds: None
+                }
+            );
+            client.handle_deferral(None);
+            client.window_elapsed();
+        }
+        assert!(client.is_complete());
+        assert_eq!(client.rounds(), 3);
+        assert_eq!(client.deferred_rounds(), 3);
+
+        // A busy server holding a contested address relinquishes it.
+        server.set_busy(false);
+        let mut identify = <Identify>::from_iter([0]);
+        let reply = server
+            .handle_identify(&identify, &mut StepRng::new(4, 0))
+            .unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 5);
+        server.set_busy(true);
+        identify.contested.push(5).unwrap();
+        assert!(server
+            .handle_identify(&identify, &mut StepRng::new(0, 0))
+            .unwrap()
+            .identified()
+            .is_none());
+        assert_eq!(server.server_address(), None);
+    }
+
+    fn assigned_table() -> AddressTable<8> {
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        let mut table = AddressTable::<8>::new();
+        client.poll_transmit().unwrap();
+        for server_address in [1, 2] {
+            let identified = Identified {
+                server_address,
+                server_ports: PortSet::from_bits(0b00000010),
+                details: Some(details(1, Capabilities::empty())),
+            };
+            client.handle_reply(&identified);
+            table.record(&identified);
+        }
+        client.window_elapsed();
+        table.retain_known(client.identify());
+        assert!(table.set_uid(1, *b"SN000001"));
+        assert!(table.set_uid(2, *b"SN000002"));
+        assert!(!table.set_uid(3, *b"SN000003"));
+        table.note_seen(1, 100);
+        table.renew_lease(1, 1_000);
+        table
+    }
+
+    #[test]
+    fn test_address_table_persistence() {
+        let table = assigned_table();
+        let entry = table.get(1).unwrap();
+        assert!(entry.assigned);
+        assert_eq!(entry.uid, Some(*b"SN000001"));
+        assert_eq!(entry.last_seen, Some(100));
+        assert_eq!(entry.lease_expiry, Some(1_000));
+
+        let serialised = postcard::to_vec::<_, 128>(&table).unwrap();
+        let restored = postcard::from_bytes::<AddressTable<8>>(&serialised).unwrap();
+        assert_eq!(restored, table);
+        assert!(postcard::from_bytes::<AddressTable<1>>(&serialised).is_err());
+
+        let identify = restored.to_identify::<DEFAULT_ADDRESS_BYTES>();
+        assert!(identify.iter_set().eq([0, 1, 2]));
+
+        // Servers not yet assigned are not seeded.
+        let mut table = restored;
+        table.record(&Identified {
+            server_address: 3,
+            server_ports: PortSet::from_bits(0),
+            details: None,
+        });
+        assert!(table
+            .to_identify::<DEFAULT_ADDRESS_BYTES>()
+            .iter_set()
+            .eq([0, 1, 2]));
+    }
+
+    #[test]
+    fn test_address_table_leases() {
+        let mut table = assigned_table();
+        table.renew_lease(2, 500);
+        let mut reserved = ReservedRanges::new();
+        assert_eq!(table.expired_leases(499, &reserved).count(), 0);
+        assert!(table.expired_leases(500, &reserved).eq([2]));
+        assert!(table.expired_leases(1_000, &reserved).eq([1, 2]));
+        reserved.reserve(2, 2);
+        assert!(table.expired_leases(1_000, &reserved).eq([1]));
+        assert_eq!(table.remove(1).map(|e| e.server_address), Some(1));
+        assert_eq!(table.remove(1), None);
+    }
+
+    #[test]
+    fn test_address_table_reconciliation() {
+        let serialised = postcard::to_vec::<_, 128>(&assigned_table()).unwrap();
+
+        // The client restarts, during which server SN000002 changes its
+        // address to 3.
+        let mut table = postcard::from_bytes::<AddressTable<8>>(&serialised).unwrap();
+        let mut client = DiscoveryClient::new(table.to_identify::<DEFAULT_ADDRESS_BYTES>());
+        let mut servers = [
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap(),
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap(),
+        ];
+        for (server, return_val) in servers.iter_mut().zip([0, 2]) {
+            server.handle_identify(
+                &<Identify>::from_iter([0]),
+                &mut StepRng::new(return_val, 0),
+            );
+        }
+        assert_eq!(servers[0].server_address(), Some(1));
+        assert_eq!(servers[1].server_address(), Some(3));
+
+        assert_eq!(table.reconcile(b"SN000001", 1), Reconciliation::Unchanged);
+        assert_eq!(
+            table.reconcile(b"SN000002", 3),
+            Reconciliation::Moved {
+                from: Some(2),
+                to: 3
+            }
+        );
+        assert!(client.contest_address(2));
+        assert!(client.contest_address(3));
+        assert!(table.iter().map(|e| e.server_address).eq([1]));
+
+        // Only the server that moved is rediscovered.
+        let identify = client.poll_transmit().unwrap();
+        assert!(identify.iter_set().eq([0, 1]));
+        assert_eq!(
+            servers[0].handle_identify(&identify, &mut StepRng::new(0, 0)),
+            None
+        );
+        let reply = servers[1]
+            .handle_identify(&identify, &mut StepRng::new(0, 0))
+            .unwrap();
+        assert_eq!(reply.identified().unwrap().server_address, 2);
+        client.handle_reply(reply.identified().unwrap());
+        table.record(reply.identified().unwrap());
+        client.window_elapsed();
+        table.retain_known(client.identify());
+        assert!(client.is_complete());
+        assert!(table.set_uid(2, *b"SN000002"));
+        assert_eq!(table.reconcile(b"SN000002", 2), Reconciliation::Unchanged);
+
+        // A server with an identifier unknown to the table at an address
+        // recorded for another.
+        assert_eq!(
+            table.reconcile(b"SN000009", 1),
+            Reconciliation::Moved { from: None, to: 1 }
+        );
+        assert_eq!(table.reconcile(b"SN000009", 9), Reconciliation::Unknown);
+    }
+
+    #[test]
+    fn test_identify_serialisation_with_network_id() {
+        let mut identify = Identify::<1>::from_iter([0, 7]);
+        identify.network_id = Some(0x1234);
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identify).unwrap();
+        assert_eq!(serialised, [0b10000001, 0, 0, 0xb4, 0x24]);
+        assert_eq!(
+            postcard::from_bytes::<Identify<1>>(&serialised).unwrap(),
+            identify
+        );
+
+        identify.network_id = Some(u32::MAX);
+        identify.protocol_version = 1;
+        identify.contested.extend_from_slice(&[1, 2, 3, 4]).unwrap();
+        let serialised = postcard::to_vec::<_, MAX_IDENTIFY_PAYLOAD_SIZE>(&identify).unwrap();
+        assert_eq!(serialised.len(), max_identify_payload_size(8));
+        assert_eq!(
+            postcard::from_bytes::<Identify<1>>(&serialised).unwrap(),
+            identify
+        );
+
+        // Conveyed within the details of a server's reply, which are also
+        // persisted by a client.
+        let identified = Identified {
+            server_address: 5,
+            server_ports: PortSet::from_bits(0b00000010),
+            details: Some(ServerDetails {
+                network_id: Some(0x1234),
+                ..details(1, Capabilities::empty())
+            }),
+        };
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&identified).unwrap();
+        assert_eq!(serialised[serialised.len() - 2..], [0xb4, 0x24]);
+        let identified = postcard::from_bytes::<Identified>(&serialised).unwrap();
+        assert_eq!(identified.network_id(), Some(0x1234));
+
+        let mut table = AddressTable::<2>::new();
+        table.record(&identified);
+        table.record(&Identified {
+            server_address: 6,
+            ..identified.clone()
+        });
+        let serialised = postcard::to_vec::<_, 128>(&table).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<AddressTable<2>>(&serialised).unwrap(),
+            table
+        );
+    }
+
+    #[test]
+    fn test_interleaved_networks() {
+        const NETWORKS: [u32; 2] = [0xA, 0xB];
+
+        let mut clients = NETWORKS.map(|network_id| {
+            let mut identify = <Identify>::new();
+            identify.network_id = Some(network_id);
+            (DiscoveryClient::new(identify), AddressTable::<8>::new())
+        });
+
+        // Two servers configured for each network, and two yet to join one.
+        let server = || {
+            DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1)
+                .unwrap()
+                .with_details(details(1, Capabilities::empty()))
+        };
+        let mut servers = [
+            (server().with_network_id(NETWORKS[0]), 0),
+            (server().with_network_id(NETWORKS[0]), 1),
+            (server().with_network_id(NETWORKS[1]), 0),
+            (server().with_network_id(NETWORKS[1]), 1),
+            (server(), 2),
+            (server(), 3),
+        ];
+
+        // Every frame is received by every station on the one channel, and
+        // the rounds of each network overlap.
+        for _ in 0..10 {
+            let identifies = clients.each_mut().map(|(client, _)| client.poll_transmit());
+            for identify in identifies.iter().flatten() {
+                for (server, return_val) in servers.iter_mut() {
+                    let Some(reply) =
+                        server.handle_identify(identify, &mut StepRng::new(*return_val, 0))
+                    else {
+                        continue;
+                    };
+                    let identified = reply.identified().unwrap();
+                    for (client, table) in clients.iter_mut() {
+                        if client.handle_reply(identified) {
+                            table.record(identified);
+                        }
+                    }
+                }
+            }
+            for (client, table) in clients.iter_mut() {
+                client.window_elapsed();
+                table.retain_known(client.identify());
+            }
+        }
+
+        assert!(clients.iter().all(|(client, _)| client.is_complete()));
+
+        // Servers learn that their address is known, and adopt the network
+        // identifier if yet to, when discovery is next run.
+        for (client, _) in clients.iter() {
+            for (server, return_val) in servers.iter_mut() {
+                let rng = &mut StepRng::new(*return_val, 0);
+                assert_eq!(server.handle_identify(client.identify(), rng), None);
+            }
+        }
+        // Both servers yet to join replied to the first client to transmit.
+        assert_eq!(servers[4].0.network_id(), Some(NETWORKS[0]));
+        assert_eq!(servers[5].0.network_id(), Some(NETWORKS[0]));
+        for (network_id, (client, table)) in NETWORKS.iter().zip(clients.iter()) {
+            let mut addresses = servers
                 .iter()
-                .enumerate()
-                .skip(1)
-                .find(|(_, is_set)| *is_set),
-            Some((3, true))
+                .filter(|(server, _)| server.network_id() == Some(*network_id))
+                .map(|(server, _)| server.server_address().unwrap())
+                .collect::<std::vec::Vec<_>>();
+            addresses.sort();
+            assert!(client.identify().iter_set().skip(1).eq(addresses.clone()));
+            assert!(table.iter().map(|e| e.server_address).eq(addresses));
+            assert!(table
+                .iter()
+                .all(|e| e.details.as_ref().unwrap().network_id == Some(*network_id)));
+        }
+    }
+
+    #[test]
+    fn test_network_id_ignored_by_others() {
+        let mut identify = <Identify>::from_iter([0]);
+        identify.network_id = Some(0xA);
+
+        // A configured server ignores other networks and clients without one.
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1)
+            .unwrap()
+            .with_network_id(0xB);
+        let rng = &mut StepRng::new(0, 0);
+        assert_eq!(server.handle_identify(&identify, rng), None);
+        assert_eq!(
+            server.handle_identify(&<Identify>::from_iter([0]), rng),
+            None
         );
+
+        // A server without details conveys no network identifier, and is
+        // noted by any client.
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap();
+        let reply = server.handle_identify(&identify, rng).unwrap();
+        assert_eq!(reply.identified().unwrap().network_id(), None);
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        client.poll_transmit().unwrap();
+        assert!(client.handle_reply(reply.identified().unwrap()));
+
+        // A client without a network identifier ignores servers conveying one.
+        let reply = DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1)
+            .unwrap()
+            .with_details(details(1, Capabilities::empty()))
+            .handle_identify(&identify, rng)
+            .unwrap();
+        assert_eq!(reply.identified().unwrap().network_id(), Some(0xA));
+        assert!(!client.handle_reply(reply.identified().unwrap()));
     }
 }
@@ -0,0 +1,201 @@
+//! A probability model of how many rounds discovery requires.
+//!
+//! For a server to be discovered it replies to the client with a message that
+//! (a) may collide on the wire with a message from another server and (b) may
+//! claim an address that another server claims. In each round there is an
+//! expected number of successes in phases (a) and (b) computed by
+//! `n * (1 - 1/m)^(n - 1)` where `n` is the number of remaining servers and `m`
+//! is the number of available time slots or remaining addresses.
+//! Ref: <https://math.stackexchange.com/q/35798>
+//!
+//! There is also a probability of any collision (meaning another round will be
+//! required). Ref: <https://en.wikipedia.org/wiki/Birthday_problem>
+//!
+//! Servers that claimed an address also claimed by another are told so by the
+//! client's next [super::Identify], and take part in the next round.
+
+/// The outcome of `n` servers each choosing one of `m` slots, be they time
+/// slots or addresses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Phase {
+    /// The number of servers choosing.
+    pub n: u32,
+    /// The number of slots to choose from.
+    pub m: u32,
+    /// The probability of at least two servers choosing the same slot.
+    pub collision_probability: f64,
+    /// The expected number of servers choosing a slot that no other did.
+    pub expected_successes: f64,
+}
+
+impl Phase {
+    /// The expected successes rounded to a whole number of servers.
+    pub fn successes(&self) -> u32 {
+        // Expected successes are never negative, so adding a half and
+        // truncating is rounding.
+        (self.expected_successes + 0.5) as u32
+    }
+}
+
+/// Compute the outcome of `n` servers each choosing one of `m` slots.
+pub fn phase(n: u32, m: u32) -> Phase {
+    // Probability of no collision.
+    let mut p_bar = 1.0;
+    for i in 1..n {
+        p_bar *= 1.0 - i as f64 / m as f64;
+    }
+
+    // Expected non collisions.
+    let mut e = n as f64;
+    for _ in 1..n {
+        e *= 1.0 - 1.0 / m as f64;
+    }
+
+    Phase {
+        n,
+        m,
+        collision_probability: 1.0 - p_bar,
+        expected_successes: e,
+    }
+}
+
+/// The outcome of a round of discovery.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Round {
+    /// The round number, starting at 1.
+    pub round: u32,
+    /// Replies arriving at the client without colliding on the wire.
+    pub received: Phase,
+    /// Replies received that claimed an address no other did.
+    pub assigned: Phase,
+    /// Servers that replied but claimed an address claimed by another.
+    pub contested: u32,
+    /// The number of servers assigned an address after this round.
+    pub cumulative_assigned: u32,
+}
+
+/// An iterator over the rounds of discovery expected for a network. Rounds
+/// continue until all servers are expected to have been assigned an address,
+/// or a round is expected to assign none, in which case discovery does not
+/// converge and servers remain unassigned, see [Simulation::unassigned].
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    stations: u32,
+    slots: u32,
+    addresses: u32,
+    round: u32,
+    cumulative_assigned: u32,
+    stalled: bool,
+}
+
+impl Simulation {
+    /// The number of servers yet to be assigned an address.
+    pub fn unassigned(&self) -> u32 {
+        self.stations
+    }
+}
+
+impl Iterator for Simulation {
+    type Item = Round;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stations == 0 || self.stalled {
+            return None;
+        }
+        let received = phase(self.stations, self.slots);
+        let assigned = phase(received.successes(), self.addresses);
+        let assigned_count = assigned.successes().min(self.stations);
+        if assigned_count == 0 {
+            self.stalled = true;
+            return None;
+        }
+        self.stations -= assigned_count;
+        self.addresses = self.addresses.saturating_sub(assigned_count);
+        self.round += 1;
+        self.cumulative_assigned += assigned_count;
+        Some(Round {
+            round: self.round,
+            received,
+            assigned,
+            contested: received.successes() - assigned_count,
+            cumulative_assigned: self.cumulative_assigned,
+        })
+    }
+}
+
+/// Model discovery of a number of servers (stations) given the time slots
+/// for replying and the addresses available.
+pub fn simulate(stations: u32, slots: u32, addresses: u32) -> Simulation {
+    Simulation {
+        stations,
+        slots,
+        addresses,
+        round: 0,
+        cumulative_assigned: 0,
+        stalled: false,
+    }
+}
+
+/// The number of rounds that discovery is expected to require, or `None`
+/// should it not be expected to assign every server an address.
+pub fn expected_rounds(stations: u32, slots: u32, addresses: u32) -> Option<u32> {
+    let mut simulation = simulate(stations, slots, addresses);
+    let rounds = simulation.by_ref().count() as u32;
+    (simulation.unassigned() == 0).then_some(rounds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.005, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn test_default_case() {
+        let rounds: Vec<Round> = simulate(128, 400, 255).collect();
+        assert_eq!(rounds.len(), 4);
+
+        assert!(rounds
+            .iter()
+            .map(|r| (r.received.n, r.received.m, r.assigned.n, r.assigned.m))
+            .eq([
+                (128, 400, 93, 255),
+                (63, 400, 54, 190),
+                (22, 400, 21, 149),
+                (4, 400, 4, 131)
+            ]));
+        assert!(rounds
+            .iter()
+            .map(|r| (r.contested, r.cumulative_assigned))
+            .eq([(28, 65), (13, 106), (3, 124), (0, 128)]));
+
+        assert_close(rounds[0].received.expected_successes, 93.14);
+        assert_close(rounds[0].assigned.expected_successes, 64.79);
+        assert_close(rounds[2].received.collision_probability, 0.44);
+        assert_close(rounds[2].assigned.collision_probability, 0.77);
+        assert_close(rounds[3].received.collision_probability, 0.01);
+        assert_close(rounds[3].assigned.collision_probability, 0.05);
+    }
+
+    #[test]
+    fn test_expected_rounds() {
+        assert_eq!(expected_rounds(8, 400, 255), Some(1));
+        assert_eq!(expected_rounds(32, 400, 255), Some(2));
+        assert_eq!(expected_rounds(64, 400, 255), Some(3));
+        assert_eq!(expected_rounds(128, 400, 255), Some(4));
+        assert_eq!(expected_rounds(0, 400, 255), Some(0));
+    }
+
+    #[test]
+    fn test_no_progress() {
+        assert_eq!(expected_rounds(10, 1, 255), None);
+        let mut simulation = simulate(10, 1, 255);
+        assert_eq!(simulation.next(), None);
+        assert_eq!(simulation.unassigned(), 10);
+
+        // More servers than addresses are never all assigned.
+        assert_eq!(expected_rounds(20, 400, 10), None);
+    }
+}
@@ -0,0 +1,617 @@
+//! A two-phase join so that only servers known to the client are able to
+//! take part in the network.
+//!
+//! In the first phase, a server replies to an [super::Identify] with a
+//! [JoinRequest] instead of a plain [Identified]. The request conveys the
+//! server's unique identifier along with its [Identified] sealed under a
+//! provisioning key particular to the server e.g. printed on its label. The
+//! client's [Commissioner] looks up the provisioning key given the identifier
+//! and opens the request, thereby authenticating the server.
+//!
+//! In the second phase, the client delivers a [JoinGrant] to the server
+//! conveying the network key that the server is to use from then on, sealed
+//! under the same provisioning key. The server's [Joiner] opens the grant,
+//! installs the network key, and switches ciphers.
+//!
+//! Sealing uses the same AEAD as the data link layer, with nonces that begin
+//! with a byte distinct from those of data frames. Each grant is sealed with
+//! a nonce freshly chosen by the client, and authenticates the nonce of the
+//! request that it replies to. A replayed request therefore never causes
+//! a second grant to be sealed with the same nonce.
+//!
+//! The client decodes the datagrams of servers that have joined with the
+//! [ServerKeys] of their network keys, a [crate::KeyProvider] looking up the
+//! cipher of each by its address.
+//!
+//! With the `zeroize` feature, the keys are zeroed when dropped, as is the
+//! network key opened from a grant once installed.
+
+use aead::{generic_array::GenericArray, AeadInPlace, KeyInit};
+use heapless::Vec;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use super::Identified;
+use crate::{Header, KeyProvider, NONCE_SIZE};
+
+/// The size of a server's unique identifier.
+pub const UID_SIZE: usize = 8;
+
+/// A server's unique identifier e.g. its serial number.
+pub type Uid = [u8; UID_SIZE];
+
+/// The capacity for sealed content, including its MIC.
+pub const SEALED_SIZE: usize = 32;
+
+const REQUEST_NONCE_PREFIX: u8 = 0x02;
+const GRANT_NONCE_PREFIX: u8 = 0x03;
+
+/// The key particular to a server and known to the client ahead of
+/// discovery e.g. by scanning a QR code on the server's label.
+#[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ProvisioningKey(pub [u8; 16]);
+impl core::fmt::Debug for ProvisioningKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("ProvisioningKey").field(&"XXX").finish()
+    }
+}
+#[cfg(feature = "defmt")]
+impl defmt::Format for ProvisioningKey {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "ProvisioningKey(XXX)");
+    }
+}
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for ProvisioningKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+#[cfg(feature = "zeroize")]
+impl Drop for ProvisioningKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for ProvisioningKey {}
+
+/// The key used by a server for the data link layer once it has joined.
+#[derive(Clone, Deserialize, Eq, PartialEq, Serialize)]
+pub struct NetworkKey(pub [u8; 16]);
+impl core::fmt::Debug for NetworkKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("NetworkKey").field(&"XXX").finish()
+    }
+}
+#[cfg(feature = "defmt")]
+impl defmt::Format for NetworkKey {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "NetworkKey(XXX)");
+    }
+}
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for NetworkKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+#[cfg(feature = "zeroize")]
+impl Drop for NetworkKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for NetworkKey {}
+
+/// The payload a server replies to an [super::Identify] with when joining.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct JoinRequest {
+    /// The unique identifier of the server, by which the client looks up
+    /// its provisioning key.
+    pub uid: Uid,
+    /// The nonce that the server's [Identified] is sealed with. The
+    /// client's [JoinGrant] authenticates it.
+    pub nonce: [u8; NONCE_SIZE],
+    /// The server's [Identified] sealed under its provisioning key.
+    pub sealed: Vec<u8, SEALED_SIZE>,
+}
+
+/// The payload a client sends to a server that it has accepted.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct JoinGrant {
+    /// The nonce that the network key is sealed with, chosen afresh by the
+    /// client for each grant.
+    pub nonce: [u8; NONCE_SIZE],
+    /// The server's [NetworkKey] sealed under its provisioning key.
+    pub sealed: Vec<u8, SEALED_SIZE>,
+}
+
+/// Problems in relation to joining.
+#[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JoinError {
+    /// The client has no provisioning key for the server.
+    UnknownUid,
+    /// The server has not requested to join.
+    NotRequested,
+    /// The sealed content could not be authenticated e.g. given the wrong
+    /// provisioning key.
+    CannotDecrypt,
+    /// The sealed content was authenticated but could not be decoded.
+    CannotDecode,
+    /// There is no room for the key of another server, see [ServerKeys].
+    TooManyServers,
+}
+
+fn nonce_with_prefix(prefix: u8, nonce: &[u8; NONCE_SIZE]) -> [u8; NONCE_SIZE] {
+    let mut nonce = *nonce;
+    nonce[0] = prefix;
+    nonce
+}
+
+fn grant_aad(uid: &Uid, request_nonce: &[u8; NONCE_SIZE]) -> [u8; UID_SIZE + NONCE_SIZE] {
+    let mut aad = [0; UID_SIZE + NONCE_SIZE];
+    aad[..UID_SIZE].copy_from_slice(uid);
+    aad[UID_SIZE..].copy_from_slice(request_nonce);
+    aad
+}
+
+fn seal<C>(
+    key: &[u8; 16],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    content: &impl Serialize,
+) -> Vec<u8, SEALED_SIZE>
+where
+    C: AeadInPlace + KeyInit,
+{
+    let cipher = C::new(GenericArray::from_slice(key));
+    let mut buf: Vec<u8, SEALED_SIZE> = postcard::to_vec(content).unwrap();
+    cipher
+        .encrypt_in_place(GenericArray::from_slice(nonce), aad, &mut buf)
+        .unwrap();
+    buf
+}
+
+fn open<C>(
+    key: &[u8; 16],
+    nonce: &[u8; NONCE_SIZE],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8, SEALED_SIZE>, JoinError>
+where
+    C: AeadInPlace + KeyInit,
+{
+    let cipher = C::new(GenericArray::from_slice(key));
+    let mut buf = Vec::new();
+    let _ = buf.extend_from_slice(sealed);
+    cipher
+        .decrypt_in_place(GenericArray::from_slice(nonce), aad, &mut buf)
+        .map_err(|_| JoinError::CannotDecrypt)?;
+    Ok(buf)
+}
+
+/// The client side of joining. Servers are authenticated given a lookup of
+/// their provisioning keys by unique identifier.
+pub struct Commissioner<F> {
+    lookup: F,
+}
+
+impl<F> Commissioner<F>
+where
+    F: FnMut(&Uid) -> Option<ProvisioningKey>,
+{
+    /// Create a commissioner given a lookup of provisioning keys.
+    pub fn new(lookup: F) -> Self {
+        Self { lookup }
+    }
+
+    /// Authenticate a server's request to join, returning its [Identified]
+    /// to be handled by the client's discovery. Requests that cannot be
+    /// authenticated should be ignored.
+    pub fn open_request<C>(&mut self, request: &JoinRequest) -> Result<Identified, JoinError>
+    where
+        C: AeadInPlace + KeyInit,
+    {
+        let key = (self.lookup)(&request.uid).ok_or(JoinError::UnknownUid)?;
+        let nonce = nonce_with_prefix(REQUEST_NONCE_PREFIX, &request.nonce);
+        let buf = open::<C>(&key.0, &nonce, &request.uid, &request.sealed)?;
+        postcard::from_bytes(&buf).map_err(|_| JoinError::CannotDecode)
+    }
+
+    /// Grant a server its network key in reply to its request to join, once
+    /// its address is known to the client. A random nonce is generated for
+    /// each grant so that granting a replayed request never reuses one.
+    pub fn grant<C, T>(
+        &mut self,
+        request: &JoinRequest,
+        network_key: &NetworkKey,
+        rng: &mut T,
+    ) -> Result<JoinGrant, JoinError>
+    where
+        C: AeadInPlace + KeyInit,
+        T: RngCore,
+    {
+        let key = (self.lookup)(&request.uid).ok_or(JoinError::UnknownUid)?;
+        let mut nonce = [0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        nonce = nonce_with_prefix(GRANT_NONCE_PREFIX, &nonce);
+        let aad = grant_aad(&request.uid, &request.nonce);
+        Ok(JoinGrant {
+            nonce,
+            sealed: seal::<C>(&key.0, &nonce, &aad, network_key),
+        })
+    }
+}
+
+/// The ciphers of up to `N` servers, each given the network key granted to
+/// it and looked up by the server's address, so that a client decodes the
+/// datagrams of servers with keys of their own, see
+/// [crate::from_datagram_with_provider].
+pub struct ServerKeys<C, const N: usize> {
+    ciphers: Vec<(u8, C), N>,
+}
+
+impl<C, const N: usize> ServerKeys<C, N>
+where
+    C: KeyInit,
+{
+    /// No keys.
+    pub const fn new() -> Self {
+        Self {
+            ciphers: Vec::new(),
+        }
+    }
+
+    /// Install the network key of a server's address, replacing any that it
+    /// had.
+    pub fn insert(
+        &mut self,
+        server_address: u8,
+        network_key: &NetworkKey,
+    ) -> Result<(), JoinError> {
+        let cipher = C::new(GenericArray::from_slice(&network_key.0));
+        match self.ciphers.iter_mut().find(|(a, _)| *a == server_address) {
+            Some((_, c)) => *c = cipher,
+            None => self
+                .ciphers
+                .push((server_address, cipher))
+                .map_err(|_| JoinError::TooManyServers)?,
+        }
+        Ok(())
+    }
+
+    /// Forget the network key of a server's address e.g. once it has left.
+    pub fn remove(&mut self, server_address: u8) {
+        self.ciphers.retain(|(a, _)| *a != server_address);
+    }
+}
+
+impl<C, const N: usize> Default for ServerKeys<C, N>
+where
+    C: KeyInit,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C, const N: usize> KeyProvider for ServerKeys<C, N>
+where
+    C: AeadInPlace,
+{
+    type Cipher = C;
+
+    fn cipher_for(&self, header: &Header) -> Option<&C> {
+        self.ciphers
+            .iter()
+            .find(|(address, _)| *address == header.server_address)
+            .map(|(_, cipher)| cipher)
+    }
+}
+
+/// The server side of joining. A request to join is produced for each
+/// [Identified] that the server replies with. Once granted, the server's
+/// network key is installed and used for its cipher.
+pub struct Joiner {
+    uid: Uid,
+    provisioning_key: ProvisioningKey,
+    nonce: Option<[u8; NONCE_SIZE]>,
+    network_key: Option<NetworkKey>,
+}
+
+impl Joiner {
+    /// Create a joiner given the server's unique identifier and provisioning
+    /// key.
+    pub fn new(uid: Uid, provisioning_key: ProvisioningKey) -> Self {
+        Self {
+            uid,
+            provisioning_key,
+            nonce: None,
+            network_key: None,
+        }
+    }
+
+    /// Seal a reply to an [super::Identify] as a request to join. A random
+    /// nonce is generated for each request.
+    pub fn join_request<C, T>(&mut self, identified: &Identified, rng: &mut T) -> JoinRequest
+    where
+        C: AeadInPlace + KeyInit,
+        T: RngCore,
+    {
+        let mut nonce = [0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        nonce = nonce_with_prefix(REQUEST_NONCE_PREFIX, &nonce);
+        self.nonce = Some(nonce);
+        JoinRequest {
+            uid: self.uid,
+            nonce,
+            sealed: seal::<C>(&self.provisioning_key.0, &nonce, &self.uid, identified),
+        }
+    }
+
+    /// Handle a grant from the client in reply to the last request, installing
+    /// the network key it conveys.
+    pub fn handle_grant<C>(&mut self, grant: &JoinGrant) -> Result<&NetworkKey, JoinError>
+    where
+        C: AeadInPlace + KeyInit,
+    {
+        let aad = grant_aad(&self.uid, &self.nonce.ok_or(JoinError::NotRequested)?);
+        if grant.nonce[0] != GRANT_NONCE_PREFIX {
+            return Err(JoinError::CannotDecrypt);
+        }
+        #[allow(unused_mut)]
+        let mut buf = open::<C>(&self.provisioning_key.0, &grant.nonce, &aad, &grant.sealed)?;
+        let network_key = postcard::from_bytes(&buf);
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut buf[..]);
+        let network_key = network_key.map_err(|_| JoinError::CannotDecode)?;
+        self.nonce = None;
+        Ok(self.network_key.insert(network_key))
+    }
+
+    /// The network key installed, if any.
+    pub fn network_key(&self) -> Option<&NetworkKey> {
+        self.network_key.as_ref()
+    }
+
+    /// The cipher to use for the data link layer once the network key has
+    /// been installed.
+    pub fn cipher<C>(&self) -> Option<C>
+    where
+        C: KeyInit,
+    {
+        self.network_key
+            .as_ref()
+            .map(|k| C::new(GenericArray::from_slice(&k.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aes::Aes128;
+    use ccm::{
+        consts::{U4, U7},
+        Ccm,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        discovery::{DiscoveryClient, DiscoveryServer, Identify, MIN_PAYLOAD_SIZE},
+        port::PortSet,
+    };
+
+    type AesCcm = Ccm<Aes128, U4, U7>;
+
+    const UID: Uid = *b"SN000001";
+    const PROVISIONING_KEY: ProvisioningKey = ProvisioningKey(*b"provisioning-key");
+    const NETWORK_KEY: NetworkKey = NetworkKey(*b"the-network-key!");
+
+    fn lookup(uid: &Uid) -> Option<ProvisioningKey> {
+        (*uid == UID).then_some(PROVISIONING_KEY)
+    }
+
+    #[test]
+    fn test_join() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut client = DiscoveryClient::new(<Identify>::new());
+        let mut commissioner = Commissioner::new(lookup);
+        let mut server = DiscoveryServer::new(PortSet::from_bits(0b00000010), 1, 2, 1).unwrap();
+        let mut joiner = Joiner::new(UID, PROVISIONING_KEY);
+        assert!(joiner.cipher::<AesCcm>().is_none());
+
+        let identify = client.poll_transmit().unwrap();
+        let reply = server.handle_identify(&identify, &mut rng).unwrap();
+        let request = joiner.join_request::<AesCcm, _>(reply.identified().unwrap(), &mut rng);
+        assert_eq!(request.nonce[0], REQUEST_NONCE_PREFIX);
+
+        let serialised = postcard::to_vec::<_, MIN_PAYLOAD_SIZE>(&request).unwrap();
+        let request = postcard::from_bytes::<JoinRequest>(&serialised).unwrap();
+        let identified = commissioner.open_request::<AesCcm>(&request).unwrap();
+        assert_eq!(Some(&identified), reply.identified());
+        client.handle_reply(&identified);
+        client.window_elapsed();
+        assert!(client.is_complete());
+
+        let grant = commissioner
+            .grant::<AesCcm, _>(&request, &NETWORK_KEY, &mut rng)
+            .unwrap();
+        assert_eq!(joiner.handle_grant::<AesCcm>(&grant), Ok(&NETWORK_KEY));
+        assert_eq!(joiner.network_key(), Some(&NETWORK_KEY));
+        assert!(joiner.cipher::<AesCcm>().is_some());
+
+        // The grant cannot be replayed.
+        assert_eq!(
+            joiner.handle_grant::<AesCcm>(&grant),
+            Err(JoinError::NotRequested)
+        );
+    }
+
+    #[test]
+    fn test_join_rejected() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let identified = Identified {
+            server_address: 1,
+            server_ports: PortSet::from_bits(0),
+            details: None,
+        };
+
+        // A server with the wrong provisioning key.
+        let mut joiner = Joiner::new(UID, ProvisioningKey(*b"not-the-real-key"));
+        let request = joiner.join_request::<AesCcm, _>(&identified, &mut rng);
+        let mut commissioner = Commissioner::new(lookup);
+        assert_eq!(
+            commissioner.open_request::<AesCcm>(&request),
+            Err(JoinError::CannotDecrypt)
+        );
+
+        // A server that is unknown.
+        let mut joiner = Joiner::new(*b"SN000002", PROVISIONING_KEY);
+        let request = joiner.join_request::<AesCcm, _>(&identified, &mut rng);
+        assert_eq!(
+            commissioner.open_request::<AesCcm>(&request),
+            Err(JoinError::UnknownUid)
+        );
+
+        // A grant in reply to an earlier request.
+        let mut joiner = Joiner::new(UID, PROVISIONING_KEY);
+        let request = joiner.join_request::<AesCcm, _>(&identified, &mut rng);
+        joiner.join_request::<AesCcm, _>(&identified, &mut rng);
+        let grant = commissioner
+            .grant::<AesCcm, _>(&request, &NETWORK_KEY, &mut rng)
+            .unwrap();
+        assert_eq!(
+            joiner.handle_grant::<AesCcm>(&grant),
+            Err(JoinError::CannotDecrypt)
+        );
+        assert_eq!(joiner.network_key(), None);
+    }
+
+    #[test]
+    fn test_join_replayed() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let identified = Identified {
+            server_address: 1,
+            server_ports: PortSet::from_bits(0),
+            details: None,
+        };
+        let mut commissioner = Commissioner::new(lookup);
+        let mut joiner = Joiner::new(UID, PROVISIONING_KEY);
+        let request = joiner.join_request::<AesCcm, _>(&identified, &mut rng);
+        let grant = commissioner
+            .grant::<AesCcm, _>(&request, &NETWORK_KEY, &mut rng)
+            .unwrap();
+        assert_eq!(grant.nonce[0], GRANT_NONCE_PREFIX);
+
+        // A replay of the request still authenticates, but a later grant in
+        // reply to it e.g. once the network key is rotated, is sealed with
+        // a nonce of its own.
+        assert_eq!(
+            commissioner.open_request::<AesCcm>(&request),
+            Ok(identified.clone())
+        );
+        let rotated_key = NetworkKey(*b"the-rotated-key!");
+        let replayed_grant = commissioner
+            .grant::<AesCcm, _>(&request, &rotated_key, &mut rng)
+            .unwrap();
+        assert_ne!(replayed_grant.nonce, grant.nonce);
+        assert_ne!(replayed_grant.nonce, request.nonce);
+
+        // Either grant is accepted only in reply to the server's request.
+        let mut other = Joiner::new(UID, PROVISIONING_KEY);
+        other.join_request::<AesCcm, _>(&identified, &mut rng);
+        assert_eq!(
+            other.handle_grant::<AesCcm>(&grant),
+            Err(JoinError::CannotDecrypt)
+        );
+        assert_eq!(joiner.handle_grant::<AesCcm>(&grant), Ok(&NETWORK_KEY));
+    }
+
+    #[test]
+    fn test_server_keys() {
+        use crate::{
+            from_datagram_with_provider, port::Port, to_datagram, DataSource, FromDatagramError,
+        };
+
+        fn datagram(cipher: &AesCcm, server_address: u8) -> [u8; 32] {
+            let header = Header {
+                version: 0,
+                source: DataSource::Server,
+                server_address,
+                server_port: Port::new(1).unwrap(),
+                frame_counter: 1,
+            };
+            let mut datagram_buf = [0; 32];
+            to_datagram(cipher, &header, b"some data", &mut datagram_buf);
+            datagram_buf
+        }
+
+        // Two servers join, each granted a key of its own.
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut commissioner = Commissioner::new(lookup);
+        let mut keys = ServerKeys::<AesCcm, 2>::new();
+        let mut joined = std::vec::Vec::new();
+        for (server_address, network_key) in
+            [(1, NETWORK_KEY), (2, NetworkKey(*b"another-network!"))]
+        {
+            let identified = Identified {
+                server_address,
+                server_ports: PortSet::from_bits(0),
+                details: None,
+            };
+            let mut joiner = Joiner::new(UID, PROVISIONING_KEY);
+            let request = joiner.join_request::<AesCcm, _>(&identified, &mut rng);
+            let grant = commissioner
+                .grant::<AesCcm, _>(&request, &network_key, &mut rng)
+                .unwrap();
+            joiner.handle_grant::<AesCcm>(&grant).unwrap();
+            keys.insert(server_address, &network_key).unwrap();
+            joined.push((server_address, joiner.cipher::<AesCcm>().unwrap()));
+        }
+        assert_eq!(keys.insert(3, &NETWORK_KEY), Err(JoinError::TooManyServers));
+
+        // The datagrams of each are decoded with the key of its address.
+        for (server_address, cipher) in &joined {
+            let (header, payload) =
+                from_datagram_with_provider(&datagram(cipher, *server_address), |_| true, &keys)
+                    .unwrap();
+            assert_eq!(header.server_address, *server_address);
+            assert_eq!(payload, b"some data");
+        }
+        let (_, first) = &joined[0];
+        assert_eq!(
+            from_datagram_with_provider(&datagram(first, 2), |_| true, &keys),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            from_datagram_with_provider(&datagram(first, 3), |_| true, &keys),
+            Err(FromDatagramError::UnknownPeer)
+        );
+
+        // A server's key may be replaced, or forgotten.
+        keys.insert(2, &NETWORK_KEY).unwrap();
+        assert!(from_datagram_with_provider(&datagram(first, 2), |_| true, &keys).is_ok());
+        keys.remove(1);
+        assert_eq!(
+            from_datagram_with_provider(&datagram(first, 1), |_| true, &keys),
+            Err(FromDatagramError::UnknownPeer)
+        );
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn test_keys_zeroized_on_drop() {
+        fn zeroize_on_drop<T: zeroize::ZeroizeOnDrop>() {}
+        zeroize_on_drop::<ProvisioningKey>();
+        zeroize_on_drop::<NetworkKey>();
+
+        let mut key = core::mem::ManuallyDrop::new(NETWORK_KEY);
+        // Safety: the key's bytes are only inspected once dropped.
+        unsafe { core::ptr::drop_in_place(&mut *key) };
+        assert_eq!(key.0, [0; 16]);
+    }
+}
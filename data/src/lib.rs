@@ -1,25 +1,63 @@
 #![cfg_attr(not(test), no_std)]
 #![doc = include_str!("../README.md")]
 
+// The derived implementations of Arbitrary refer to std.
+#[cfg(all(feature = "arbitrary", not(test)))]
+extern crate std;
+
+#[cfg(any(test, feature = "std"))]
+pub mod debug_json;
 pub mod discovery;
+#[cfg(any(test, feature = "std"))]
+pub mod pcap;
+pub mod port;
+pub mod presence;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod timing;
 pub mod update;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+use core::fmt::{self, Write};
 
-use aead::{generic_array::GenericArray, AeadInPlace};
+use aead::{
+    generic_array::{
+        typenum::{Unsigned, U12, U7},
+        ArrayLength, GenericArray,
+    },
+    AeadCore, AeadInPlace, Nonce, Tag,
+};
 use heapless::Vec;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::port::Port;
 
 /// The size of a data frame header including the byte length for the payload.
 /// The byte length value is not to exceed 127.
 pub const HEADER_SIZE: usize = 6;
 
-/// The size of the MIC code at the tail of the payload
+/// The size of the MIC code at the tail of the payload, given AES-128 CCM.
+/// Ciphers with a larger tag, such as AES-GCM and ChaCha20-Poly1305, append
+/// their own tag size.
 pub const MIC_SIZE: usize = 4;
 
-/// The size of the Nonce used for encryption
+/// The size of the Nonce used for encryption, given AES-128 CCM. Other
+/// ciphers are given nonces of their own size, see [NonceScheme].
 pub const NONCE_SIZE: usize = 7;
 
+/// The first byte of the nonce of frames encrypted under a network key.
+pub const NETWORK_NONCE_DOMAIN: u8 = 0x01;
+
+/// The first byte of the nonce of frames encrypted under an update key, see
+/// [update::to_update_datagram]. Frames of an update therefore never share a
+/// nonce with other frames, even should an update key be the same as a
+/// network key.
+pub const UPDATE_NONCE_DOMAIN: u8 = 0x02;
+
 /// Indicates where data is sourced from i.e. its direction.
-#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum DataSource {
     Client,
     Server,
@@ -32,15 +70,17 @@ pub struct HeaderParseError {}
 
 /// The haader fields of the data frame.
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Header {
     /// The protocol version. Should be 0.
+    #[cfg_attr(feature = "arbitrary", arbitrary(value = 0))]
     pub version: u8,
     /// The direction of data flow.
     pub source: DataSource,
     /// The address of the server 0..255.
     pub server_address: u8,
-    /// The port of the server 0..7.
-    pub server_port: u8,
+    /// The port of the server 0..=7.
+    pub server_port: Port,
     /// A frame counter for ensuring message authenticity by
     /// being able to vary a nonce. Should be incremented by
     /// the message source and is expected to overflow to zero
@@ -54,7 +94,7 @@ impl Header {
         let source = u32::from(self.source == DataSource::Server);
         let header = (source << 2)
             | (((self.server_address as u32) & 0xFF) << 3)
-            | (((self.server_port as u32) & 0x07) << 11)
+            | ((self.server_port.get() as u32) << 11)
             | (((self.frame_counter as u32) & 0xFFFF) << 16);
         (
             ((header & 0xff000000) >> 24) as u8,
@@ -65,8 +105,8 @@ impl Header {
     }
 
     /// Parse the contents of the data frame header.
-    /// If the data frame version is an incompatible value
-    /// then an error is returned. Otherwise, the header
+    /// If the data frame version is an incompatible value,
+    /// or a reserved bit is set, then an error is returned. Otherwise, the header
     /// and encrypted payload (including a MAC at the end)
     /// are returned.
     pub fn parse(header: (u8, u8, u8, u8)) -> Result<Header, HeaderParseError> {
@@ -74,7 +114,8 @@ impl Header {
             | ((header.1 as u32) << 16)
             | ((header.2 as u32) << 8)
             | (header.3 as u32);
-        let version = header & 0x02;
+        let version = header & 0x03;
+        let reserved = (header >> 14) & 0x03;
         let source = match (header >> 2) & 0x01 {
             0 => Some(DataSource::Client),
             1 => Some(DataSource::Server),
@@ -84,12 +125,12 @@ impl Header {
         let server_port = (header >> 11) & 0x07;
         let frame_counter = (header >> 16) & 0xFFFF;
 
-        match (version, source) {
-            (0, Some(source)) => Ok(Header {
+        match (version, reserved, source) {
+            (0, 0, Some(source)) => Ok(Header {
                 version: 0,
                 source,
                 server_address: server_address as _,
-                server_port: server_port as _,
+                server_port: Port::masked(server_port as _),
                 frame_counter: frame_counter as _,
             }),
             _ => Err(HeaderParseError {}),
@@ -100,6 +141,7 @@ impl Header {
 /// A data frame encapsulates client and server packets
 /// and provides for error checking.
 #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct DataFrame<'a> {
     /// Bits as follows:
     /// 00..=01 protocol version 00
@@ -110,7 +152,8 @@ pub struct DataFrame<'a> {
     /// 16..=31 frame counter
     pub header: (u8, u8, u8, u8),
     /// Payload data appended with a Message Authentication Code (MAC) using AES-128 CCM
-    /// with a 4 byte MIC and a 7 byte nonce derived using the [new_nonce] function.
+    /// with a 4 byte MIC and a 7 byte nonce derived using the [new_nonce] function,
+    /// or using another cipher with a nonce derived as per its [NonceScheme].
     /// This will be required to have a one byte length as the first byte.
     pub encrypted_payload: &'a [u8],
 }
@@ -119,13 +162,18 @@ pub struct DataFrame<'a> {
 /// Given that the header contains a frame counter, we should get
 /// a reasonable avoidance of the nonce repeating itself. The nonce is
 /// laid out as follows:
-/// 0..=0   always 0x01
+/// 0..=0   [NETWORK_NONCE_DOMAIN], or [UPDATE_NONCE_DOMAIN] for frames
+///         encrypted under an update key
 /// 1..=4   packed header in big endian form
 /// 5..=5   payload len
 /// 6..=6   always 0x00
 pub fn new_nonce(header: (u8, u8, u8, u8), payload_len: usize) -> [u8; 7] {
+    new_nonce_in(NETWORK_NONCE_DOMAIN, header, payload_len)
+}
+
+fn new_nonce_in(domain: u8, header: (u8, u8, u8, u8), payload_len: usize) -> [u8; 7] {
     [
-        0x01,
+        domain,
         header.0,
         header.1,
         header.2,
@@ -135,43 +183,361 @@ pub fn new_nonce(header: (u8, u8, u8, u8), payload_len: usize) -> [u8; 7] {
     ]
 }
 
+/// The construction of a nonce of a cipher's nonce size, being implemented
+/// by the size itself so that a cipher's scheme follows from its
+/// [AeadCore::NonceSize]. Frames are thereby encrypted given any cipher
+/// whose nonce size has a scheme:
+///
+/// * `U7`, the scheme of [new_nonce], for AES-128 CCM with a 4 byte MIC
+/// * `U12`, for AES-GCM and ChaCha20-Poly1305, being the scheme of
+///   [new_nonce] with the payload length as two bytes, and zero-padded
+///
+/// Both begin with a domain byte of [NETWORK_NONCE_DOMAIN] or
+/// [UPDATE_NONCE_DOMAIN]. The bytes that are otherwise zero may instead be
+/// given a salt e.g. one provisioned to each network, of which no more
+/// than fits is taken. Datagrams are salted by encoding and decoding them
+/// with a [Salted] cipher.
+pub trait NonceScheme: ArrayLength<u8> {
+    /// The nonce of a packed header and the length of its payload, before
+    /// encryption.
+    fn nonce(
+        domain: u8,
+        header: (u8, u8, u8, u8),
+        payload_len: usize,
+        salt: Option<&[u8]>,
+    ) -> GenericArray<u8, Self>;
+
+    /// Salt a nonce of this scheme, copying as much of the salt as fits
+    /// into the bytes that are otherwise zero.
+    fn salt(nonce: &mut GenericArray<u8, Self>, salt: &[u8]);
+}
+
+// Copies as much of a salt as fits into the tail of a nonce.
+fn salted(tail: &mut [u8], salt: Option<&[u8]>) {
+    for (byte, salt) in tail.iter_mut().zip(salt.unwrap_or_default()) {
+        *byte = *salt;
+    }
+}
+
+impl NonceScheme for U7 {
+    fn nonce(
+        domain: u8,
+        header: (u8, u8, u8, u8),
+        payload_len: usize,
+        salt: Option<&[u8]>,
+    ) -> GenericArray<u8, Self> {
+        let mut nonce = new_nonce_in(domain, header, payload_len).into();
+        Self::salt(&mut nonce, salt.unwrap_or_default());
+        nonce
+    }
+
+    fn salt(nonce: &mut GenericArray<u8, Self>, salt: &[u8]) {
+        salted(&mut nonce[6..], Some(salt));
+    }
+}
+
+/// The nonce is laid out as follows:
+/// 0..=0   [NETWORK_NONCE_DOMAIN] or [UPDATE_NONCE_DOMAIN]
+/// 1..=4   packed header in big endian form
+/// 5..=6   payload len in big endian form
+/// 7..=11  always 0x00, or the salt
+impl NonceScheme for U12 {
+    fn nonce(
+        domain: u8,
+        header: (u8, u8, u8, u8),
+        payload_len: usize,
+        salt: Option<&[u8]>,
+    ) -> GenericArray<u8, Self> {
+        let len = (payload_len as u16).to_be_bytes();
+        let mut nonce = [
+            domain, header.0, header.1, header.2, header.3, len[0], len[1], 0, 0, 0, 0, 0,
+        ]
+        .into();
+        Self::salt(&mut nonce, salt.unwrap_or_default());
+        nonce
+    }
+
+    fn salt(nonce: &mut GenericArray<u8, Self>, salt: &[u8]) {
+        salted(&mut nonce[7..], Some(salt));
+    }
+}
+
+/// The most bytes of salt that a [NonceScheme] takes.
+pub const MAX_SALT_SIZE: usize = 5;
+
+/// A cipher whose nonces are salted as per its [NonceScheme], e.g. with a
+/// salt provisioned to each network, so that datagrams encoded with it are
+/// decoded only with a cipher of the same key and salt. It is given in place
+/// of the cipher to any of the functions encoding and decoding datagrams,
+/// or as that of a [KeyProvider].
+///
+/// No more of the salt than fits the nonce scheme is taken, and a salt of
+/// zeros is that of the cipher without one.
+#[derive(Clone, Debug)]
+pub struct Salted<C> {
+    cipher: C,
+    salt: [u8; MAX_SALT_SIZE],
+}
+
+impl<C> Salted<C> {
+    /// A cipher salting its nonces with the salt given, of which no more than
+    /// [MAX_SALT_SIZE] bytes are taken.
+    pub fn new(cipher: C, salt: &[u8]) -> Self {
+        let mut salt_buf = [0; MAX_SALT_SIZE];
+        salted(&mut salt_buf, Some(salt));
+        Self {
+            cipher,
+            salt: salt_buf,
+        }
+    }
+
+    // The nonce given, salted.
+    fn salted<N: NonceScheme>(&self, nonce: &GenericArray<u8, N>) -> GenericArray<u8, N> {
+        let mut nonce = nonce.clone();
+        N::salt(&mut nonce, &self.salt);
+        nonce
+    }
+}
+
+impl<C: AeadCore> AeadCore for Salted<C> {
+    type NonceSize = C::NonceSize;
+    type TagSize = C::TagSize;
+    type CiphertextOverhead = C::CiphertextOverhead;
+}
+
+impl<C> AeadInPlace for Salted<C>
+where
+    C: AeadInPlace,
+    C::NonceSize: NonceScheme,
+{
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> aead::Result<Tag<Self>> {
+        self.cipher
+            .encrypt_in_place_detached(&self.salted(nonce), associated_data, buffer)
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<Self>,
+    ) -> aead::Result<()> {
+        self.cipher
+            .decrypt_in_place_detached(&self.salted(nonce), associated_data, buffer, tag)
+    }
+}
+
 /// Problems in relation to decoding a datagram
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum FromDatagramError {
     CannotParseDataFrame(postcard::Error),
     CannotParseHeader,
     FilterDoesNotMatch,
     CannotDecrypt,
+    /// There is no key for the datagram's header, see [KeyProvider].
+    UnknownPeer,
+}
+
+/// The lookup of the cipher that a datagram is encrypted under given its
+/// header, so that a client of many servers, each with their own key,
+/// decodes a datagram in a single pass, see [from_datagram_with_provider].
+pub trait KeyProvider {
+    type Cipher: AeadInPlace;
+
+    /// The cipher for a header, or None if there is no key for it.
+    fn cipher_for(&self, header: &Header) -> Option<&Self::Cipher>;
+}
+
+/// The ciphers of the servers at each address, as an array of pairs of
+/// server address and cipher.
+impl<C: AeadInPlace, const N: usize> KeyProvider for [(u8, C); N] {
+    type Cipher = C;
+
+    fn cipher_for(&self, header: &Header) -> Option<&C> {
+        self.iter()
+            .find(|(address, _)| *address == header.server_address)
+            .map(|(_, cipher)| cipher)
+    }
 }
 
 /// Conveniently decodes a datagram with a fixed length of N given a condition and,
 /// if successful, validates the header and decrypts the payload.
+/// A datagram encrypted by a cipher other than that given fails to decrypt,
+/// even where their nonces are of different sizes.
 pub fn from_datagram<const N: usize>(
     datagram_buf: &[u8; N],
     filter: impl FnOnce(&Header) -> bool,
-    cipher: &impl AeadInPlace,
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+) -> Result<(Header, Vec<u8, N>), FromDatagramError> {
+    from_datagram_in(NETWORK_NONCE_DOMAIN, datagram_buf, filter, cipher)
+}
+
+/// Decodes a datagram as per [from_datagram], the filter being given the
+/// length of the payload along with the header, being the length of the
+/// encrypted payload less the cipher's tag, so that e.g. a reply not of the
+/// size expected is rejected without decrypting it. The length is as the
+/// datagram declares it, and so may be chosen by an attacker until the
+/// payload is authenticated. A filter may only reject by it, never trust it.
+pub fn from_datagram_sized<const N: usize>(
+    datagram_buf: &[u8; N],
+    filter: impl FnOnce(&Header, usize) -> bool,
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
 ) -> Result<(Header, Vec<u8, N>), FromDatagramError> {
+    from_datagram_of(NETWORK_NONCE_DOMAIN, datagram_buf, filter, |_| Some(cipher))
+}
+
+/// A filter for [from_datagram_sized], built up from conditions on the
+/// header of a datagram and the length of its payload, all of which must
+/// hold. A filter with no conditions matches every datagram.
+///
+/// The length of the payload may be chosen by an attacker until the payload
+/// is authenticated, and so conditions of it only reject datagrams early,
+/// e.g. before paying for their decryption.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeaderFilter {
+    source: Option<DataSource>,
+    server_address: Option<u8>,
+    server_port: Option<Port>,
+    payload_len: Option<usize>,
+    max_payload_len: Option<usize>,
+}
+
+impl HeaderFilter {
+    /// A filter matching every datagram.
+    pub const fn new() -> Self {
+        Self {
+            source: None,
+            server_address: None,
+            server_port: None,
+            payload_len: None,
+            max_payload_len: None,
+        }
+    }
+
+    /// Match datagrams from the source given.
+    pub const fn source(mut self, source: DataSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Match datagrams of the server address given.
+    pub const fn server_address(mut self, server_address: u8) -> Self {
+        self.server_address = Some(server_address);
+        self
+    }
+
+    /// Match datagrams of the server port given.
+    pub const fn server_port(mut self, server_port: Port) -> Self {
+        self.server_port = Some(server_port);
+        self
+    }
+
+    /// Match datagrams whose payload is of exactly the length given.
+    pub const fn payload_len(mut self, len: usize) -> Self {
+        self.payload_len = Some(len);
+        self
+    }
+
+    /// Match datagrams whose payload is of no more than the length given.
+    pub const fn max_payload_len(mut self, len: usize) -> Self {
+        self.max_payload_len = Some(len);
+        self
+    }
+
+    /// Whether a datagram's header and the length of its payload match,
+    /// to be given as the filter of [from_datagram_sized].
+    pub fn matches(&self, header: &Header, payload_len: usize) -> bool {
+        self.source.is_none_or(|s| s == header.source)
+            && self
+                .server_address
+                .is_none_or(|a| a == header.server_address)
+            && self.server_port.is_none_or(|p| p == header.server_port)
+            && self.payload_len.is_none_or(|l| l == payload_len)
+            && self.max_payload_len.is_none_or(|l| payload_len <= l)
+    }
+}
+
+/// Decodes a datagram as per [from_datagram], decrypting it with the cipher
+/// that the provider has for its header. The header is parsed once, and
+/// [FromDatagramError::UnknownPeer] returned if the provider has no cipher
+/// for it.
+pub fn from_datagram_with_provider<K, const N: usize>(
+    datagram_buf: &[u8; N],
+    filter: impl FnOnce(&Header) -> bool,
+    provider: &K,
+) -> Result<(Header, Vec<u8, N>), FromDatagramError>
+where
+    K: KeyProvider,
+    <K::Cipher as AeadCore>::NonceSize: NonceScheme,
+{
+    from_datagram_of(
+        NETWORK_NONCE_DOMAIN,
+        datagram_buf,
+        |header, _| filter(header),
+        |header| provider.cipher_for(header),
+    )
+}
+
+pub(crate) fn from_datagram_in<C, const N: usize>(
+    domain: u8,
+    datagram_buf: &[u8; N],
+    filter: impl FnOnce(&Header) -> bool,
+    cipher: &C,
+) -> Result<(Header, Vec<u8, N>), FromDatagramError>
+where
+    C: AeadInPlace,
+    C::NonceSize: NonceScheme,
+{
+    from_datagram_of(
+        domain,
+        datagram_buf,
+        |header, _| filter(header),
+        |_| Some(cipher),
+    )
+}
+
+// Decodes a datagram with the cipher given for its header, once it and the
+// length of its payload have passed the filter.
+fn from_datagram_of<'a, C, const N: usize>(
+    domain: u8,
+    datagram_buf: &[u8; N],
+    filter: impl FnOnce(&Header, usize) -> bool,
+    cipher_for: impl FnOnce(&Header) -> Option<&'a C>,
+) -> Result<(Header, Vec<u8, N>), FromDatagramError>
+where
+    C: AeadInPlace + 'a,
+    C::NonceSize: NonceScheme,
+{
     let data_frame = postcard::from_bytes::<DataFrame>(datagram_buf)
         .map_err(FromDatagramError::CannotParseDataFrame)?;
 
     let header =
         Header::parse(data_frame.header).map_err(|_| FromDatagramError::CannotParseHeader)?;
 
-    if !filter(&header) {
+    let payload_len = data_frame
+        .encrypted_payload
+        .len()
+        .saturating_sub(C::TagSize::USIZE);
+
+    if !filter(&header, payload_len) {
         return Err(FromDatagramError::FilterDoesNotMatch);
     }
 
-    let nonce = new_nonce(
-        data_frame.header,
-        data_frame.encrypted_payload.len().max(MIC_SIZE) - MIC_SIZE,
-    );
+    let cipher = cipher_for(&header).ok_or(FromDatagramError::UnknownPeer)?;
+
+    let nonce = C::NonceSize::nonce(domain, data_frame.header, payload_len, None);
 
     let mut crypt_payload_buf = Vec::new();
     let _ = crypt_payload_buf.extend_from_slice(data_frame.encrypted_payload);
     cipher
         .decrypt_in_place(
-            GenericArray::from_slice(&nonce),
+            &nonce,
             &[
                 data_frame.header.0,
                 data_frame.header.1,
@@ -185,23 +551,146 @@ pub fn from_datagram<const N: usize>(
     Ok((header, crypt_payload_buf))
 }
 
+/// Decodes the header of a datagram of any length, and the encrypted payload
+/// following it as a view of the datagram, so that a datagram is not copied
+/// e.g. out of the buffer that it was received into. The payload may then be
+/// decrypted given that its header is of interest, see [decrypt_payload].
+pub fn parse_datagram(datagram_buf: &[u8]) -> Result<(Header, &[u8]), FromDatagramError> {
+    let data_frame = postcard::from_bytes::<DataFrame>(datagram_buf)
+        .map_err(FromDatagramError::CannotParseDataFrame)?;
+
+    let header =
+        Header::parse(data_frame.header).map_err(|_| FromDatagramError::CannotParseHeader)?;
+
+    Ok((header, data_frame.encrypted_payload))
+}
+
+/// Decrypts the payload of a datagram decoded by [parse_datagram] into the
+/// buffer given, returning the length of the payload.
+pub fn decrypt_payload<C>(
+    cipher: &C,
+    header: &Header,
+    encrypted_payload: &[u8],
+    payload_buf: &mut [u8],
+) -> Result<usize, FromDatagramError>
+where
+    C: AeadInPlace,
+    C::NonceSize: NonceScheme,
+{
+    let packed_header = header.to_packed();
+    let payload_len = encrypted_payload
+        .len()
+        .checked_sub(C::TagSize::USIZE)
+        .filter(|len| *len <= payload_buf.len())
+        .ok_or(FromDatagramError::CannotDecrypt)?;
+    let (payload, tag) = encrypted_payload.split_at(payload_len);
+
+    let nonce = C::NonceSize::nonce(NETWORK_NONCE_DOMAIN, packed_header, payload_len, None);
+
+    let payload_buf = &mut payload_buf[..payload_len];
+    payload_buf.copy_from_slice(payload);
+    cipher
+        .decrypt_in_place_detached(
+            &nonce,
+            &[
+                packed_header.0,
+                packed_header.1,
+                packed_header.2,
+                packed_header.3,
+            ],
+            payload_buf,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_| FromDatagramError::CannotDecrypt)?;
+
+    Ok(payload_len)
+}
+
+/// Encrypts a payload and encodes the header and encrypted payload into a
+/// datagram of any length within the buffer given, as per [to_datagram],
+/// returning the length of the datagram, or None should it not fit. The
+/// counterpart of [parse_datagram] and [decrypt_payload], so that datagrams
+/// need not be of a fixed length.
+pub fn encode_datagram<C>(
+    cipher: &C,
+    header: &Header,
+    payload_buf: &[u8],
+    datagram_buf: &mut [u8],
+) -> Option<usize>
+where
+    C: AeadInPlace,
+    C::NonceSize: NonceScheme,
+{
+    let packed_header = header.to_packed();
+    // The encrypted payload's length precedes it as a varint, as a u32 of
+    // its value is encoded.
+    let encrypted_len = payload_buf.len().checked_add(C::TagSize::USIZE)?;
+    let prefix_len = postcard::to_slice(
+        &(packed_header, u32::try_from(encrypted_len).ok()?),
+        datagram_buf,
+    )
+    .ok()?
+    .len();
+    let datagram_len = prefix_len.checked_add(encrypted_len)?;
+    let (payload, tag) = datagram_buf
+        .get_mut(prefix_len..datagram_len)?
+        .split_at_mut(payload_buf.len());
+    payload.copy_from_slice(payload_buf);
+
+    let nonce = C::NonceSize::nonce(NETWORK_NONCE_DOMAIN, packed_header, payload_buf.len(), None);
+    let mic = cipher
+        .encrypt_in_place_detached(
+            &nonce,
+            &[
+                packed_header.0,
+                packed_header.1,
+                packed_header.2,
+                packed_header.3,
+            ],
+            payload,
+        )
+        .ok()?;
+    tag.copy_from_slice(&mic);
+
+    Some(datagram_len)
+}
+
 /// Conveniently encrypts a payload and encodes the header and encrypted payload into
 /// a datagram with a fixed length of N.
 pub fn to_datagram<const N: usize>(
-    cipher: &impl AeadInPlace,
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
     header: &Header,
     payload_buf: &[u8],
     datagram_buf: &mut [u8; N],
 ) {
+    to_datagram_in(
+        NETWORK_NONCE_DOMAIN,
+        cipher,
+        header,
+        payload_buf,
+        datagram_buf,
+    )
+}
+
+pub(crate) fn to_datagram_in<C, const N: usize>(
+    domain: u8,
+    cipher: &C,
+    header: &Header,
+    payload_buf: &[u8],
+    datagram_buf: &mut [u8; N],
+) where
+    C: AeadInPlace,
+    C::NonceSize: NonceScheme,
+{
     let packed_header = header.to_packed();
 
-    let nonce = new_nonce(packed_header, payload_buf.len());
+    let nonce = C::NonceSize::nonce(domain, packed_header, payload_buf.len(), None);
 
     let mut crypt_payload_buf: Vec<u8, N> = Vec::new();
     crypt_payload_buf.extend_from_slice(payload_buf).unwrap();
     cipher
         .encrypt_in_place(
-            GenericArray::from_slice(&nonce),
+            &nonce,
             &[
                 packed_header.0,
                 packed_header.1,
@@ -219,16 +708,87 @@ pub fn to_datagram<const N: usize>(
     postcard::to_slice(&data_frame, datagram_buf).unwrap();
 }
 
+/// Arbitrary vectors of up to their capacity.
+#[cfg(feature = "arbitrary")]
+pub(crate) fn arbitrary_vec<'a, T, const N: usize>(
+    u: &mut arbitrary::Unstructured<'a>,
+) -> arbitrary::Result<Vec<T, N>>
+where
+    T: arbitrary::Arbitrary<'a>,
+{
+    let mut v = Vec::new();
+    for _ in 0..u.arbitrary_len::<T>()?.min(N) {
+        let _ = v.push(u.arbitrary()?);
+    }
+    Ok(v)
+}
+
+/// Deserialise an optional field that is conveyed only when present, being
+/// the last field of a message so that older messages remain decodable.
+pub(crate) fn deserialise_last_field<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    last_field(T::deserialize(d).map(Some))
+}
+
+/// The outcome of decoding a trailing field, being absent when the input
+/// ends before it. Any other error conveys that the field is malformed, and
+/// so is returned rather than the field being taken as absent.
+pub(crate) fn last_field<T, E: fmt::Display>(r: Result<Option<T>, E>) -> Result<Option<T>, E> {
+    match r {
+        Err(e) if is_end_of_input(&e) => Ok(None),
+        r => r,
+    }
+}
+
+/// Whether an error is that of postcard's input ending. Errors are only
+/// known to be so by their description, which is compared with postcard's
+/// without allocating. The version of postcard is pinned so that its
+/// description does not change unnoticed.
+pub(crate) fn is_end_of_input(e: &impl fmt::Display) -> bool {
+    struct Remaining<'a>(&'a str);
+
+    impl fmt::Write for Remaining<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let mut expected = heapless::String::<64>::new();
+    if write!(expected, "{}", postcard::Error::DeserializeUnexpectedEnd).is_err() {
+        return false;
+    }
+    let mut remaining = Remaining(&expected);
+    write!(remaining, "{e}").is_ok() && remaining.0.is_empty()
+}
+
+/// Serialise an optional field that is conveyed only when present. Must be
+/// the last field of a message.
+pub(crate) fn serialise_last_field<S, T>(o: &Option<T>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Serialize,
+{
+    match o {
+        Some(v) => v.serialize(s),
+        None => s.serialize_unit(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use aead::KeyInit;
+    use core::cell::Cell;
+
+    use aead::{consts::U0, KeyInit};
     use aes::Aes128;
-    use ccm::{
-        consts::{U4, U7},
-        Ccm,
-    };
+    use aes_gcm::Aes128Gcm;
+    use ccm::{consts::U4, Ccm};
+    use chacha20poly1305::ChaCha20Poly1305;
 
     #[test]
     fn test_datagram_serialisation() {
@@ -241,7 +801,7 @@ mod tests {
             version: 0,
             source: DataSource::Server,
             server_address: 255,
-            server_port: 7,
+            server_port: Port::new(7).unwrap(),
             frame_counter: 1,
         };
 
@@ -272,7 +832,11 @@ mod tests {
 
         let (header, payload_buf) = from_datagram(
             &datagram_buf,
-            |h| h.source == DataSource::Server && h.server_address == 255 && h.server_port == 7,
+            |h| {
+                h.source == DataSource::Server
+                    && h.server_address == 255
+                    && h.server_port == Port::new(7).unwrap()
+            },
             &cipher,
         )
         .unwrap();
@@ -283,11 +847,439 @@ mod tests {
                 version: 0,
                 source: DataSource::Server,
                 server_address: 255,
-                server_port: 7,
+                server_port: Port::new(7).unwrap(),
                 frame_counter: 1,
             }
         );
 
         assert_eq!(payload_buf, b"some data");
     }
+
+    #[test]
+    fn test_datagram_slice_decoding() {
+        type AesCcm = Ccm<Aes128, U4, U7>;
+
+        let key = GenericArray::from_slice(b"0123456789ABCDEF");
+        let cipher = AesCcm::new(key);
+
+        // The datagram is decoded as a view of its buffer, being no longer
+        // than its payload requires.
+        let datagram_buf = [
+            0, 1, 63, 252, 13, 145, 171, 66, 62, 129, 223, 68, 168, 6, 69, 126, 97, 64,
+        ];
+        let (header, encrypted_payload) = parse_datagram(&datagram_buf).unwrap();
+        assert_eq!(header.frame_counter, 1);
+        assert!(core::ptr::eq(&encrypted_payload[0], &datagram_buf[5]));
+
+        let mut payload_buf = [0; 9];
+        assert_eq!(
+            decrypt_payload(&cipher, &header, encrypted_payload, &mut payload_buf),
+            Ok(9)
+        );
+        assert_eq!(&payload_buf, b"some data");
+        assert_eq!(
+            decrypt_payload(&cipher, &header, encrypted_payload, &mut [0; 8]),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert!(parse_datagram(&datagram_buf[..10]).is_err());
+    }
+
+    #[test]
+    fn test_datagram_slice_encoding() {
+        type AesCcm = Ccm<Aes128, U4, U7>;
+
+        let key = GenericArray::from_slice(b"0123456789ABCDEF");
+        let cipher = AesCcm::new(key);
+
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 255,
+            server_port: Port::new(7).unwrap(),
+            frame_counter: 1,
+        };
+        let mut datagram_buf = [0; 18];
+        assert_eq!(
+            encode_datagram(&cipher, &header, b"some data", &mut datagram_buf),
+            Some(18)
+        );
+        assert_eq!(
+            datagram_buf,
+            [0, 1, 63, 252, 13, 145, 171, 66, 62, 129, 223, 68, 168, 6, 69, 126, 97, 64]
+        );
+        assert_eq!(
+            encode_datagram(&cipher, &header, b"some data", &mut [0; 17]),
+            None
+        );
+
+        // Payloads whose length takes more than a byte are encoded as by a
+        // datagram of a fixed length.
+        let payload = [7; 200];
+        let mut datagram_buf = [0; 210];
+        let mut fixed_buf = [0; 210];
+        assert_eq!(
+            encode_datagram(&cipher, &header, &payload, &mut datagram_buf),
+            Some(210)
+        );
+        to_datagram(&cipher, &header, &payload, &mut fixed_buf);
+        assert_eq!(datagram_buf, fixed_buf);
+    }
+
+    #[test]
+    fn test_nonce_schemes() {
+        let header = (0, 1, 63, 252);
+        assert_eq!(
+            U7::nonce(NETWORK_NONCE_DOMAIN, header, 9, None).as_slice(),
+            new_nonce(header, 9)
+        );
+        assert_eq!(
+            U7::nonce(NETWORK_NONCE_DOMAIN, header, 9, Some(&[0xaa, 0xbb])).as_slice(),
+            [1, 0, 1, 63, 252, 9, 0xaa]
+        );
+        assert_eq!(
+            U12::nonce(UPDATE_NONCE_DOMAIN, header, 300, None).as_slice(),
+            [2, 0, 1, 63, 252, 1, 44, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            U12::nonce(NETWORK_NONCE_DOMAIN, header, 9, Some(&[0xaa; 8])).as_slice(),
+            [1, 0, 1, 63, 252, 0, 9, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa]
+        );
+    }
+
+    #[test]
+    fn test_salted_datagrams() {
+        type AesCcm = Ccm<Aes128, U4, U7>;
+
+        let key = b"0123456789ABCDEF0123456789ABCDEF";
+        let ccm = AesCcm::new_from_slice(&key[..16]).unwrap();
+        let chacha = ChaCha20Poly1305::new_from_slice(key).unwrap();
+        let header = Header {
+            version: 0,
+            source: DataSource::Client,
+            server_address: 7,
+            server_port: Port::new(1).unwrap(),
+            frame_counter: 1,
+        };
+
+        // A datagram sealed with one salt opens only with the same salt.
+        let salted = Salted::new(ccm.clone(), &[0xaa]);
+        let mut datagram_buf = [0; 32];
+        to_datagram(&salted, &header, b"some data", &mut datagram_buf);
+        assert_eq!(
+            from_datagram(&datagram_buf, |_| true, &salted).unwrap().1,
+            b"some data"
+        );
+        assert_eq!(
+            from_datagram(&datagram_buf, |_| true, &Salted::new(ccm.clone(), &[0xbb])),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            from_datagram(&datagram_buf, |_| true, &ccm),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+
+        // A salt of zeros is that of no salt, and only so much of a salt as
+        // fits the scheme is taken.
+        let mut unsalted_buf = [0; 32];
+        to_datagram(
+            &Salted::new(ccm.clone(), &[0; 5]),
+            &header,
+            b"some data",
+            &mut unsalted_buf,
+        );
+        assert!(from_datagram(&unsalted_buf, |_| true, &ccm).is_ok());
+        assert!(from_datagram(&datagram_buf, |_| true, &Salted::new(ccm, &[0xaa, 0xcc])).is_ok());
+
+        // As for a 12 byte nonce, and datagrams of any length.
+        let salted = Salted::new(chacha.clone(), &[1, 2, 3, 4, 5]);
+        let mut datagram_buf = [0; 64];
+        let len = encode_datagram(&salted, &header, b"some data", &mut datagram_buf).unwrap();
+        let (header, encrypted_payload) = parse_datagram(&datagram_buf[..len]).unwrap();
+        let mut payload_buf = [0; 16];
+        assert_eq!(
+            decrypt_payload(&salted, &header, encrypted_payload, &mut payload_buf),
+            Ok(9)
+        );
+        assert_eq!(
+            decrypt_payload(
+                &Salted::new(chacha.clone(), &[1, 2, 3, 4, 6]),
+                &header,
+                encrypted_payload,
+                &mut payload_buf
+            ),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            decrypt_payload(&chacha, &header, encrypted_payload, &mut payload_buf),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+    }
+
+    #[test]
+    fn test_cipher_profiles() {
+        type AesCcm = Ccm<Aes128, U4, U7>;
+
+        let key = b"0123456789ABCDEF0123456789ABCDEF";
+        let ccm = AesCcm::new_from_slice(&key[..16]).unwrap();
+        let gcm = Aes128Gcm::new_from_slice(&key[..16]).unwrap();
+        let chacha = ChaCha20Poly1305::new_from_slice(key).unwrap();
+
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 255,
+            server_port: Port::new(7).unwrap(),
+            frame_counter: 1,
+        };
+
+        // The 12 byte nonce profiles round trip, their tags being 16 bytes.
+        let mut gcm_buf = [0; 32];
+        to_datagram(&gcm, &header, b"some data", &mut gcm_buf);
+        let (_, payload) = from_datagram(&gcm_buf, |_| true, &gcm).unwrap();
+        assert_eq!(payload, b"some data");
+
+        let mut chacha_buf = [0; 30];
+        assert_eq!(
+            encode_datagram(&chacha, &header, b"some data", &mut chacha_buf),
+            Some(30)
+        );
+        let (_, encrypted_payload) = parse_datagram(&chacha_buf).unwrap();
+        let mut payload_buf = [0; 14];
+        assert_eq!(
+            decrypt_payload(&chacha, &header, encrypted_payload, &mut payload_buf),
+            Ok(9)
+        );
+        assert_eq!(&payload_buf[..9], b"some data");
+
+        // A frame of one profile fails to decrypt under another, whatever
+        // the size of their nonces and tags, including under the same key.
+        let mut ccm_buf = [0; 32];
+        to_datagram(&ccm, &header, b"some data", &mut ccm_buf);
+        assert_eq!(
+            from_datagram(&ccm_buf, |_| true, &gcm),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            from_datagram(&gcm_buf, |_| true, &ccm),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            from_datagram(&gcm_buf, |_| true, &chacha),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            decrypt_payload(&ccm, &header, encrypted_payload, &mut payload_buf),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            decrypt_payload(&gcm, &header, &encrypted_payload[..4], &mut payload_buf),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+    }
+
+    #[test]
+    fn test_key_provider() {
+        type AesCcm = Ccm<Aes128, U4, U7>;
+
+        let cipher = |key: &[u8; 16]| AesCcm::new(GenericArray::from_slice(key));
+        let provider = [
+            (1, cipher(b"0123456789ABCDEF")),
+            (2, cipher(b"FEDCBA9876543210")),
+        ];
+        let header = |server_address| Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address,
+            server_port: Port::new(7).unwrap(),
+            frame_counter: 1,
+        };
+
+        let mut datagram_buf = [0; 32];
+        for (address, key) in [(1, b"0123456789ABCDEF"), (2, b"FEDCBA9876543210")] {
+            to_datagram(
+                &cipher(key),
+                &header(address),
+                b"some data",
+                &mut datagram_buf,
+            );
+            let (received, payload) =
+                from_datagram_with_provider(&datagram_buf, |_| true, &provider).unwrap();
+            assert_eq!(received, header(address));
+            assert_eq!(payload, b"some data");
+        }
+
+        // The third server is unknown to the provider, and so a datagram from
+        // it is not decrypted at all.
+        to_datagram(
+            &cipher(b"0123456789ABCDEF"),
+            &header(3),
+            b"some data",
+            &mut datagram_buf,
+        );
+        assert_eq!(
+            from_datagram_with_provider(&datagram_buf, |_| true, &provider),
+            Err(FromDatagramError::UnknownPeer)
+        );
+        assert_eq!(
+            from_datagram_with_provider(&datagram_buf, |h| h.server_address != 3, &provider),
+            Err(FromDatagramError::FilterDoesNotMatch)
+        );
+
+        // A datagram under the key of another server fails to decrypt.
+        to_datagram(
+            &cipher(b"0123456789ABCDEF"),
+            &header(2),
+            b"some data",
+            &mut datagram_buf,
+        );
+        assert_eq!(
+            from_datagram_with_provider(&datagram_buf, |_| true, &provider),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+    }
+
+    // Counts the payloads that it is asked to decrypt, delegating to AES-CCM.
+    struct CountingCipher(Ccm<Aes128, U4, U7>, Cell<usize>);
+
+    impl AeadCore for CountingCipher {
+        type NonceSize = U7;
+        type TagSize = U4;
+        type CiphertextOverhead = U0;
+    }
+
+    impl AeadInPlace for CountingCipher {
+        fn encrypt_in_place_detached(
+            &self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+        ) -> aead::Result<Tag<Self>> {
+            self.0
+                .encrypt_in_place_detached(nonce, associated_data, buffer)
+        }
+
+        fn decrypt_in_place_detached(
+            &self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+            tag: &Tag<Self>,
+        ) -> aead::Result<()> {
+            self.1.set(self.1.get() + 1);
+            self.0
+                .decrypt_in_place_detached(nonce, associated_data, buffer, tag)
+        }
+    }
+
+    #[test]
+    fn test_sized_filter() {
+        let cipher = CountingCipher(
+            Ccm::new(GenericArray::from_slice(b"0123456789ABCDEF")),
+            Cell::new(0),
+        );
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 255,
+            server_port: Port::new(7).unwrap(),
+            frame_counter: 1,
+        };
+        let mut datagram_buf = [0; 32];
+        to_datagram(&cipher, &header, b"some data", &mut datagram_buf);
+
+        // Payloads longer than the filter allows are rejected without being
+        // decrypted.
+        assert_eq!(
+            from_datagram_sized(&datagram_buf, |_, len| len <= 8, &cipher),
+            Err(FromDatagramError::FilterDoesNotMatch)
+        );
+        assert_eq!(cipher.1.get(), 0);
+
+        let (_, payload) = from_datagram_sized(&datagram_buf, |_, len| len == 9, &cipher).unwrap();
+        assert_eq!(payload, b"some data");
+        assert_eq!(cipher.1.get(), 1);
+    }
+
+    #[test]
+    fn test_header_filter() {
+        let cipher = CountingCipher(
+            Ccm::new(GenericArray::from_slice(b"0123456789ABCDEF")),
+            Cell::new(0),
+        );
+        let header = Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 255,
+            server_port: Port::new(7).unwrap(),
+            frame_counter: 1,
+        };
+        let mut datagram_buf = [0; 32];
+        to_datagram(&cipher, &header, b"some data", &mut datagram_buf);
+
+        // Datagrams not matching every condition are rejected without being
+        // decrypted.
+        let from_server = HeaderFilter::new()
+            .source(DataSource::Server)
+            .server_address(255)
+            .server_port(Port::new(7).unwrap());
+        for filter in [
+            from_server.max_payload_len(8),
+            from_server.payload_len(10),
+            from_server.payload_len(9).server_address(1),
+            HeaderFilter::new().source(DataSource::Client),
+            HeaderFilter::new().server_port(Port::new(6).unwrap()),
+        ] {
+            assert_eq!(
+                from_datagram_sized(&datagram_buf, |h, len| filter.matches(h, len), &cipher),
+                Err(FromDatagramError::FilterDoesNotMatch)
+            );
+        }
+        assert_eq!(cipher.1.get(), 0);
+
+        for filter in [
+            HeaderFilter::new(),
+            from_server.payload_len(9),
+            from_server.max_payload_len(9),
+        ] {
+            let (_, payload) =
+                from_datagram_sized(&datagram_buf, |h, len| filter.matches(h, len), &cipher)
+                    .unwrap();
+            assert_eq!(payload, b"some data");
+        }
+        assert_eq!(cipher.1.get(), 3);
+    }
+
+    #[test]
+    fn test_header_parsing() {
+        let header = Header::parse((0, 1, 63, 252)).unwrap();
+        assert_eq!(header.to_packed(), (0, 1, 63, 252));
+
+        // Headers of another version, or with reserved bits set, are not
+        // decoded as though they were those packed without them.
+        assert!(Header::parse((0, 1, 63, 253)).is_err());
+        assert!(Header::parse((0, 1, 63, 254)).is_err());
+        assert!(Header::parse((0, 1, 127, 252)).is_err());
+        assert!(Header::parse((0, 1, 191, 252)).is_err());
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        // The input ending is told apart by postcard's description of it,
+        // which must remain that of its error.
+        let end = postcard::Error::DeserializeUnexpectedEnd;
+        assert!(is_end_of_input(&end));
+        assert!(is_end_of_input(&end.to_string()));
+        assert!(is_end_of_input(
+            &postcard::from_bytes::<u32>(&[]).unwrap_err()
+        ));
+        assert!(!is_end_of_input(&postcard::Error::DeserializeBadVarint));
+        assert!(!is_end_of_input(&format_args!("{end}!")));
+        assert!(!is_end_of_input(&""));
+
+        assert_eq!(last_field::<u8, _>(Err(end)), Ok(None));
+        assert_eq!(
+            last_field::<u8, _>(Err(postcard::Error::DeserializeBadVarint)),
+            Err(postcard::Error::DeserializeBadVarint)
+        );
+    }
 }
@@ -0,0 +1,354 @@
+//! Writing datagrams to pcapng files e.g. to be viewed with Wireshark. Each
+//! datagram is written as a packet of [LINK_TYPE], being its bytes as they
+//! are conveyed, along with its direction and, given its header, a comment
+//! of the header rendered as JSON, see [crate::debug_json]:
+//!
+//! ```text
+//! {"decoded":{"frame_counter":1,"server_address":255,"server_port":7,"source":"server","version":0},"raw":"00013ffc"}
+//! ```
+//!
+//! A [CaptureRing] retains the last datagrams conveyed so that e.g. a
+//! gateway writes them only once errors accumulate. Requires the `std`
+//! feature.
+
+extern crate std;
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    string::ToString,
+    time::{SystemTime, UNIX_EPOCH},
+    vec::Vec,
+};
+
+use crate::Header;
+
+/// The link type of the packets written, being the first reserved for
+/// private use, `LINKTYPE_USER0`. Wireshark may be told to decode it as
+/// flip-flop datagrams with a dissector of its own.
+pub const LINK_TYPE: u16 = 147;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const EPB_FLAGS: u16 = 2;
+
+/// The direction of a datagram, as written to the flags of its packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Unknown,
+    Inbound,
+    Outbound,
+}
+
+/// A datagram conveyed at a time, in a direction, and its header if decoded.
+#[derive(Debug)]
+pub struct Record<'a> {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub datagram: &'a [u8],
+    pub header: Option<&'a Header>,
+}
+
+/// Writes records to a pcapng file of a single section and interface.
+pub struct PcapWriter<W: Write> {
+    out: W,
+}
+
+// A block of the type and body given, padded to 32 bits and framed by its
+// length.
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(block.len() + padding, 0);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+// An option of the code and value given, padded to 32 bits.
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// A writer to the output given, having written the section's header
+    /// and its interface of [LINK_TYPE].
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut section = Vec::new();
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        // The length of the section is not given.
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        out.write_all(&block(SECTION_HEADER_BLOCK, &section))?;
+
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&LINK_TYPE.to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        // Packets are of any length.
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        out.write_all(&block(INTERFACE_DESCRIPTION_BLOCK, &interface))?;
+        Ok(Self { out })
+    }
+
+    /// Write a record as a packet, its timestamp in microseconds.
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let micros = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let len = record.datagram.len() as u32;
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        packet.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&(micros as u32).to_le_bytes());
+        packet.extend_from_slice(&len.to_le_bytes());
+        packet.extend_from_slice(&len.to_le_bytes());
+        packet.extend_from_slice(record.datagram);
+        packet.resize(packet.len() + (4 - record.datagram.len() % 4) % 4, 0);
+        let flags: u32 = match record.direction {
+            Direction::Unknown => 0,
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        };
+        option(&mut packet, EPB_FLAGS, &flags.to_le_bytes());
+        if let Some(header) = record.header {
+            option(
+                &mut packet,
+                OPT_COMMENT,
+                header.to_debug_json().to_string().as_bytes(),
+            );
+        }
+        option(&mut packet, OPT_END, &[]);
+        self.out.write_all(&block(ENHANCED_PACKET_BLOCK, &packet))
+    }
+
+    /// The output written to.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+// A record retained by a ring, its header retained packed.
+struct Retained {
+    timestamp: SystemTime,
+    direction: Direction,
+    datagram: Vec<u8>,
+    header: Option<(u8, u8, u8, u8)>,
+}
+
+/// Retains the last records given, up to a capacity, so that they may be
+/// written once the errors counted reach a threshold e.g. by a gateway
+/// capturing on demand.
+pub struct CaptureRing {
+    capacity: usize,
+    error_threshold: usize,
+    errors: usize,
+    records: VecDeque<Retained>,
+}
+
+impl CaptureRing {
+    /// A ring of up to the records given, the threshold of errors being
+    /// reached at the number of errors given.
+    pub fn new(capacity: usize, error_threshold: usize) -> Self {
+        Self {
+            capacity,
+            error_threshold,
+            errors: 0,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Retain a record, forgetting the oldest once at capacity.
+    pub fn push(&mut self, record: &Record) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(Retained {
+            timestamp: record.timestamp,
+            direction: record.direction,
+            datagram: record.datagram.to_vec(),
+            header: record.header.map(Header::to_packed),
+        });
+    }
+
+    /// Count an error, returning true if the errors counted since the ring
+    /// was last written have reached the threshold.
+    pub fn error(&mut self) -> bool {
+        self.errors = self.errors.saturating_add(1);
+        self.errors >= self.error_threshold
+    }
+
+    /// The records retained.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether no records are retained.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Write the records retained as a pcapng file to the output given,
+    /// and start counting errors afresh. The records remain retained.
+    pub fn write_to<W: Write>(&mut self, out: W) -> io::Result<W> {
+        let mut writer = PcapWriter::new(out)?;
+        for retained in &self.records {
+            let header = retained.header.and_then(|h| Header::parse(h).ok());
+            writer.write(&Record {
+                timestamp: retained.timestamp,
+                direction: retained.direction,
+                datagram: &retained.datagram,
+                header: header.as_ref(),
+            })?;
+        }
+        self.errors = 0;
+        Ok(writer.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, string::String, time::Duration};
+
+    use super::*;
+    use crate::{port::Port, DataSource};
+
+    const DATAGRAM: [u8; 18] = [
+        0, 1, 63, 252, 13, 145, 171, 66, 62, 129, 223, 68, 168, 6, 69, 126, 97, 64,
+    ];
+
+    // The blocks of a pcapng file, being their types and bodies, checking
+    // that each is framed by its length.
+    fn blocks(mut file: &[u8]) -> Vec<(u32, &[u8])> {
+        let u32_at = |b: &[u8], i: usize| u32::from_le_bytes(b[i..i + 4].try_into().unwrap());
+        let mut blocks = Vec::new();
+        while !file.is_empty() {
+            let len = u32_at(file, 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(file, len - 4) as usize, len);
+            blocks.push((u32_at(file, 0), &file[8..len - 4]));
+            file = &file[len..];
+        }
+        blocks
+    }
+
+    // The options of the body of a block following the offset given.
+    fn options_of(body: &[u8], mut offset: usize) -> Vec<(u16, &[u8])> {
+        let mut options = Vec::new();
+        loop {
+            let code = u16::from_le_bytes([body[offset], body[offset + 1]]);
+            let len = u16::from_le_bytes([body[offset + 2], body[offset + 3]]) as usize;
+            if code == OPT_END {
+                return options;
+            }
+            options.push((code, &body[offset + 4..offset + 4 + len]));
+            offset += 4 + len.div_ceil(4) * 4;
+        }
+    }
+
+    fn header() -> Header {
+        Header {
+            version: 0,
+            source: DataSource::Server,
+            server_address: 255,
+            server_port: Port::new(7).unwrap(),
+            frame_counter: 1,
+        }
+    }
+
+    #[test]
+    fn test_pcapng_file() {
+        let path = std::env::temp_dir().join("flip-flop-test.pcapng");
+        let header = header();
+        let timestamp = UNIX_EPOCH + Duration::from_micros(0x1_0000_0002);
+        let mut writer = PcapWriter::new(fs::File::create(&path).unwrap()).unwrap();
+        writer
+            .write(&Record {
+                timestamp,
+                direction: Direction::Inbound,
+                datagram: &DATAGRAM,
+                header: Some(&header),
+            })
+            .unwrap();
+        writer
+            .write(&Record {
+                timestamp,
+                direction: Direction::Outbound,
+                datagram: &DATAGRAM[..3],
+                header: None,
+            })
+            .unwrap();
+        drop(writer);
+        let file = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let blocks = blocks(&file);
+        let types = blocks.iter().map(|(t, _)| *t).collect::<Vec<_>>();
+        assert_eq!(types, [SECTION_HEADER_BLOCK, 1, 6, 6]);
+        assert_eq!(blocks[0].1[..8], [0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0]);
+        assert_eq!(blocks[1].1[..2], LINK_TYPE.to_le_bytes());
+
+        // The packets are of the datagrams, their times and directions, and
+        // the header decoded as a comment.
+        let (_, packet) = blocks[2];
+        assert_eq!(
+            packet[4..20],
+            [1, 0, 0, 0, 2, 0, 0, 0, 18, 0, 0, 0, 18, 0, 0, 0]
+        );
+        assert_eq!(packet[20..38], DATAGRAM);
+        let options = options_of(packet, 40);
+        assert_eq!(options[0], (EPB_FLAGS, &[1, 0, 0, 0][..]));
+        assert_eq!(
+            String::from_utf8(options[1].1.to_vec()).unwrap(),
+            header.to_debug_json().to_string()
+        );
+
+        let (_, packet) = blocks[3];
+        assert_eq!(packet[12..20], [3, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(packet[20..23], DATAGRAM[..3]);
+        assert_eq!(options_of(packet, 24), [(EPB_FLAGS, &[2, 0, 0, 0][..])]);
+    }
+
+    #[test]
+    fn test_capture_ring() {
+        let header = header();
+        let mut ring = CaptureRing::new(2, 2);
+        for len in 1..=3 {
+            ring.push(&Record {
+                timestamp: UNIX_EPOCH,
+                direction: Direction::Unknown,
+                datagram: &DATAGRAM[..len],
+                header: Some(&header),
+            });
+        }
+        assert_eq!(ring.len(), 2);
+
+        // The last records are written once the errors reach the threshold,
+        // and errors are counted afresh.
+        assert!(!ring.error());
+        assert!(ring.error());
+        let file = ring.write_to(Vec::new()).unwrap();
+        let lens = blocks(&file)
+            .iter()
+            .filter(|(t, _)| *t == ENHANCED_PACKET_BLOCK)
+            .map(|(_, packet)| packet[12])
+            .collect::<Vec<_>>();
+        assert_eq!(lens, [2, 3]);
+        assert!(!ring.error());
+    }
+}
@@ -0,0 +1,253 @@
+//! The ports of a server, each used for a specific function, as conveyed by
+//! the header of a data frame, and sets of them as conveyed by discovery and
+//! updates.
+
+use core::fmt;
+
+use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
+
+/// A port of a server 0..=7, being those that the header of a data frame
+/// conveys. A port is encoded as a `u8`.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Hash, MaxSize, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Port(u8);
+
+impl Port {
+    /// The greatest port.
+    pub const MAX: Port = Port(7);
+
+    /// The port given, if no more than [Port::MAX].
+    pub const fn new(port: u8) -> Option<Self> {
+        if port <= Self::MAX.0 {
+            Some(Self(port))
+        } else {
+            None
+        }
+    }
+
+    // The port of the low bits given, the others being ignored.
+    pub(crate) const fn masked(bits: u8) -> Self {
+        Self(bits & Self::MAX.0)
+    }
+
+    /// The number of the port.
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// Every port, in ascending order.
+    pub fn all() -> impl Iterator<Item = Port> {
+        (0..=Self::MAX.0).map(Port)
+    }
+}
+
+/// A port beyond [Port::MAX] was given.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortOutOfRange(pub u8);
+
+impl fmt::Display for PortOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "port {} is beyond {}", self.0, Port::MAX.0)
+    }
+}
+
+/// Arbitrary ports are of the low bits of a byte.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Port {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::masked(u.arbitrary()?))
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        u8::size_hint(depth)
+    }
+}
+
+impl TryFrom<u8> for Port {
+    type Error = PortOutOfRange;
+
+    fn try_from(port: u8) -> Result<Self, Self::Error> {
+        Port::new(port).ok_or(PortOutOfRange(port))
+    }
+}
+
+impl From<Port> for u8 {
+    fn from(port: Port) -> Self {
+        port.0
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A set of the ports of a server e.g. those that it supports. A set is
+/// encoded as a `u8` bitmask, bit 1 representing port 1 and so on, and so
+/// every `u8` is a set.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, MaxSize, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[serde(from = "u8", into = "u8")]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct PortSet(u8);
+
+impl PortSet {
+    /// The set of no ports.
+    pub const EMPTY: PortSet = PortSet(0);
+    /// The set of every port.
+    pub const ALL: PortSet = PortSet(u8::MAX);
+
+    /// The set of the ports of a bitmask.
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// The bitmask of the set.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// The set of a single port.
+    pub const fn of(port: Port) -> Self {
+        Self(1 << port.0)
+    }
+
+    /// Add a port to the set, returning whether it was not already present.
+    pub fn insert(&mut self, port: Port) -> bool {
+        let absent = !self.contains(port);
+        self.0 |= 1 << port.0;
+        absent
+    }
+
+    /// Remove a port from the set, returning whether it was present.
+    pub fn remove(&mut self, port: Port) -> bool {
+        let present = self.contains(port);
+        self.0 &= !(1 << port.0);
+        present
+    }
+
+    /// Whether a port is in the set.
+    pub const fn contains(self, port: Port) -> bool {
+        self.0 & (1 << port.0) != 0
+    }
+
+    /// The ports in both this set and the one given.
+    pub const fn intersection(self, other: PortSet) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// The ports of the set, in ascending order.
+    pub fn iter(self) -> impl Iterator<Item = Port> {
+        Port::all().filter(move |p| self.contains(*p))
+    }
+
+    /// The number of ports in the set.
+    pub const fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Whether the set has no ports.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl From<u8> for PortSet {
+    fn from(bits: u8) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<PortSet> for u8 {
+    fn from(ports: PortSet) -> Self {
+        ports.0
+    }
+}
+
+impl From<Port> for PortSet {
+    fn from(port: Port) -> Self {
+        Self::of(port)
+    }
+}
+
+impl FromIterator<Port> for PortSet {
+    fn from_iter<I: IntoIterator<Item = Port>>(iter: I) -> Self {
+        let mut ports = PortSet::EMPTY;
+        for port in iter {
+            ports.insert(port);
+        }
+        ports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port() {
+        // The boundary ports are valid and 8 is not.
+        assert_eq!(Port::new(0).map(Port::get), Some(0));
+        assert_eq!(Port::new(7), Some(Port::MAX));
+        assert_eq!(Port::new(8), None);
+        assert_eq!(Port::try_from(7).map(u8::from), Ok(7));
+        assert_eq!(Port::try_from(8), Err(PortOutOfRange(8)));
+        assert_eq!(Port::try_from(255), Err(PortOutOfRange(255)));
+        assert_eq!(Port::all().count(), 8);
+
+        // A port is encoded as a u8, and decoding rejects those beyond 7.
+        for port in Port::all() {
+            let bytes = postcard::to_vec::<_, 1>(&port).unwrap();
+            assert_eq!(bytes, [port.get()]);
+            assert_eq!(postcard::from_bytes::<Port>(&bytes), Ok(port));
+        }
+        assert!(postcard::from_bytes::<Port>(&[8]).is_err());
+    }
+
+    #[test]
+    fn test_port_set() {
+        let port = |p| Port::new(p).unwrap();
+
+        let mut ports = PortSet::EMPTY;
+        assert!(ports.is_empty());
+        assert!(ports.insert(port(0)));
+        assert!(ports.insert(port(7)));
+        assert!(!ports.insert(port(7)));
+        assert_eq!(ports.bits(), 0b1000_0001);
+        assert_eq!(ports.len(), 2);
+        assert!(ports.contains(port(0)));
+        assert!(!ports.contains(port(1)));
+        assert_eq!(ports.iter().collect::<Vec<_>>(), [port(0), port(7)]);
+        assert!(ports.remove(port(0)));
+        assert!(!ports.remove(port(0)));
+        assert_eq!(ports, PortSet::of(port(7)));
+
+        assert_eq!(
+            PortSet::ALL.iter().collect::<Vec<_>>(),
+            Port::all().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            [port(1), port(3)].into_iter().collect::<PortSet>(),
+            PortSet::from_bits(0b1010)
+        );
+        assert_eq!(
+            PortSet::from_bits(0b1110).intersection(PortSet::from_bits(0b0111)),
+            PortSet::from_bits(0b0110)
+        );
+
+        // A set is encoded as its bitmask.
+        for bits in [0, 0b1000_0001, u8::MAX] {
+            let bytes = postcard::to_vec::<_, 2>(&PortSet::from_bits(bits)).unwrap();
+            assert_eq!(bytes, postcard::to_vec::<_, 2>(&bits).unwrap());
+            assert_eq!(
+                postcard::from_bytes::<PortSet>(&bytes),
+                Ok(PortSet::from_bits(bits))
+            );
+        }
+    }
+}
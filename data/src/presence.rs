@@ -0,0 +1,180 @@
+//! Tracking of the servers last seen by a client so that those that have
+//! vanished are noticed. Times are in ticks of whatever resolution the
+//! client's clock uses.
+
+use crate::discovery::MAX_ADDRESSES;
+
+/// The presence of a server as determined by when it was last seen.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PresenceState {
+    /// Seen recently.
+    Online,
+    /// Not seen for a while, but may still be present e.g. given a
+    /// few corrupt frames.
+    Suspect,
+    /// Not seen for so long that the address is considered free.
+    Expired,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Tracked {
+    Unknown,
+    Seen(u64),
+    Expired,
+}
+
+/// Records when each server was last seen so that the client may determine
+/// whether a server is online, suspect or has expired. A server becomes
+/// suspect when not seen for `suspect_after` ticks and returns to being
+/// online when next seen. A server not seen for `expire_after` ticks
+/// expires, whereupon its address may be freed and assigned through
+/// discovery. Expiry is latched: frames received from an expired address
+/// do not revive it until the address has been assigned again.
+pub struct Presence {
+    tracked: [Tracked; MAX_ADDRESSES],
+    suspect_after: u64,
+    expire_after: u64,
+}
+
+impl Presence {
+    /// Track the presence of servers given the durations after which they
+    /// become suspect and then expire. `expire_after` should be greater than
+    /// `suspect_after`.
+    pub fn new(suspect_after: u64, expire_after: u64) -> Self {
+        Self {
+            tracked: [Tracked::Unknown; MAX_ADDRESSES],
+            suspect_after,
+            expire_after,
+        }
+    }
+
+    /// Note that a frame from a server has been successfully decrypted.
+    pub fn note_seen(&mut self, server_address: u8, now: u64) {
+        let tracked = &mut self.tracked[server_address as usize];
+        if *tracked != Tracked::Expired {
+            *tracked = Tracked::Seen(now);
+        }
+    }
+
+    /// Note that a server has been assigned its address e.g. through
+    /// discovery. Any expiry is cleared and the server is considered seen.
+    pub fn note_assigned(&mut self, server_address: u8, now: u64) {
+        self.tracked[server_address as usize] = Tracked::Seen(now);
+    }
+
+    /// Stop tracking a server.
+    pub fn forget(&mut self, server_address: u8) {
+        self.tracked[server_address as usize] = Tracked::Unknown;
+    }
+
+    /// The presence of a server, or None if it has not been seen.
+    pub fn state(&self, server_address: u8, now: u64) -> Option<PresenceState> {
+        match self.tracked[server_address as usize] {
+            Tracked::Unknown => None,
+            Tracked::Expired => Some(PresenceState::Expired),
+            Tracked::Seen(seen) => {
+                let elapsed = now.saturating_sub(seen);
+                Some(if elapsed >= self.expire_after {
+                    PresenceState::Expired
+                } else if elapsed >= self.suspect_after {
+                    PresenceState::Suspect
+                } else {
+                    PresenceState::Online
+                })
+            }
+        }
+    }
+
+    /// Returns true if a server has been seen within a number of ticks.
+    pub fn seen_within(&self, server_address: u8, now: u64, threshold: u64) -> bool {
+        match self.tracked[server_address as usize] {
+            Tracked::Seen(seen) => now.saturating_sub(seen) < threshold,
+            Tracked::Unknown | Tracked::Expired => false,
+        }
+    }
+
+    /// The servers seen previously, but not within a number of ticks.
+    pub fn missing(&self, now: u64, threshold: u64) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(move |a| {
+            self.tracked[*a as usize] != Tracked::Unknown && !self.seen_within(*a, now, threshold)
+        })
+    }
+
+    /// Latch the expiry of those servers not seen for `expire_after` ticks,
+    /// returning those that have newly expired so that their addresses may
+    /// be freed. Expiry is latched as the iterator is consumed.
+    pub fn expire(&mut self, now: u64) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(move |a| {
+            let tracked = &mut self.tracked[*a as usize];
+            match *tracked {
+                Tracked::Seen(seen) if now.saturating_sub(seen) >= self.expire_after => {
+                    *tracked = Tracked::Expired;
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLL_TICKS: u64 = 100;
+
+    #[test]
+    fn test_irregular_traffic() {
+        let mut presence = Presence::new(4 * POLL_TICKS, 10 * POLL_TICKS);
+
+        // Server 1 replies to every other poll, with some jitter. Server 2
+        // replies to the first few polls and then vanishes.
+        for poll in 0..40u64 {
+            let now = poll * POLL_TICKS;
+            if poll % 2 == 0 {
+                presence.note_seen(1, now + poll % 3);
+            }
+            if poll < 5 {
+                presence.note_seen(2, now);
+            }
+            assert_eq!(presence.state(1, now), Some(PresenceState::Online));
+            assert!(presence.missing(now, 3 * POLL_TICKS).all(|a| a != 1));
+            assert_eq!(presence.expire(now).find(|a| *a == 1), None);
+        }
+
+        let now = 40 * POLL_TICKS;
+        assert!(presence.seen_within(1, now, 3 * POLL_TICKS));
+        assert!(!presence.seen_within(2, now, 3 * POLL_TICKS));
+        assert!(presence.missing(now, 3 * POLL_TICKS).eq([2]));
+        assert_eq!(presence.state(3, now), None);
+    }
+
+    #[test]
+    fn test_online_suspect_expired() {
+        let mut presence = Presence::new(4 * POLL_TICKS, 10 * POLL_TICKS);
+        presence.note_seen(2, 0);
+        assert_eq!(presence.state(2, 399), Some(PresenceState::Online));
+        assert_eq!(presence.state(2, 400), Some(PresenceState::Suspect));
+        assert_eq!(presence.expire(400).count(), 0);
+
+        // A suspect server returns to being online when seen.
+        presence.note_seen(2, 500);
+        assert_eq!(presence.state(2, 500), Some(PresenceState::Online));
+
+        assert_eq!(presence.state(2, 1500), Some(PresenceState::Expired));
+        assert!(presence.expire(1500).eq([2]));
+        assert_eq!(presence.expire(1600).count(), 0);
+
+        // Expiry is latched until the address is assigned again.
+        presence.note_seen(2, 1600);
+        assert_eq!(presence.state(2, 1600), Some(PresenceState::Expired));
+        assert!(!presence.seen_within(2, 1600, POLL_TICKS));
+        presence.note_assigned(2, 1700);
+        assert_eq!(presence.state(2, 1700), Some(PresenceState::Online));
+
+        presence.forget(2);
+        assert_eq!(presence.state(2, 1700), None);
+        assert_eq!(presence.missing(1700, POLL_TICKS).count(), 0);
+    }
+}
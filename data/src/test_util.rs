@@ -0,0 +1,231 @@
+//! Strategies generating the values conveyed by data frames, for property
+//! testing with proptest that they are encoded and decoded as they should
+//! be. Requires the `test-util` feature and the standard library.
+//!
+//! Tests of the state machines that convey those values are made
+//! reproducible by a [StepRng] in place of a random generator, their frames
+//! being compared with those scripted by [assert_frames_eq], which tells of
+//! any that differ.
+
+extern crate std;
+
+use std::{fmt::Write, string::String, vec, vec::Vec};
+
+use proptest::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    port::Port,
+    update::{PreRelease, Version},
+    DataSource, Header,
+};
+
+/// Either direction of data flow.
+pub fn data_source_strategy() -> impl Strategy<Value = DataSource> {
+    any::<bool>().prop_map(|server| {
+        if server {
+            DataSource::Server
+        } else {
+            DataSource::Client
+        }
+    })
+}
+
+/// Any of the ports of a server.
+pub fn port_strategy() -> impl Strategy<Value = Port> {
+    (0..=Port::MAX.get()).prop_map(Port::masked)
+}
+
+/// Any frame counter, favouring those either side of it wrapping.
+pub fn frame_counter_strategy() -> impl Strategy<Value = u16> {
+    prop_oneof![
+        3 => any::<u16>(),
+        1 => prop::sample::select(vec![0, 1, u16::MAX - 1, u16::MAX]),
+    ]
+}
+
+/// Any header of the current protocol version, across the range of each of
+/// its fields.
+pub fn header_strategy() -> impl Strategy<Value = Header> {
+    (
+        data_source_strategy(),
+        any::<u8>(),
+        port_strategy(),
+        frame_counter_strategy(),
+    )
+        .prop_map(
+            |(source, server_address, server_port, frame_counter)| Header {
+                version: 0,
+                source,
+                server_address,
+                server_port,
+                frame_counter,
+            },
+        )
+}
+
+/// Any version, whether a release or a pre-release.
+pub fn version_strategy() -> impl Strategy<Value = Version> {
+    let pre = prop_oneof![
+        any::<u8>().prop_map(PreRelease::Alpha),
+        any::<u8>().prop_map(PreRelease::Beta),
+        any::<u8>().prop_map(PreRelease::Rc),
+    ];
+    (any::<u8>(), any::<u8>(), any::<u8>(), prop::option::of(pre)).prop_map(
+        |(major, minor, patch, pre)| Version {
+            major,
+            minor,
+            patch,
+            pre,
+        },
+    )
+}
+
+/// A generator yielding a value that steps by the same increment each
+/// time, e.g. so that a server selects the address and reply slot that a
+/// test expects of it.
+pub use rand::rngs::mock::StepRng;
+
+/// A frame conveyed by, or expected of, a state machine under test.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Who conveyed the frame e.g. "client" or "server 1".
+    pub source: String,
+    /// The frame's bytes.
+    pub bytes: Vec<u8>,
+}
+
+impl Frame {
+    /// A frame of the bytes given.
+    pub fn new(source: impl Into<String>, bytes: impl Into<Vec<u8>>) -> Self {
+        Self {
+            source: source.into(),
+            bytes: bytes.into(),
+        }
+    }
+
+    /// A frame of a value encoded with postcard.
+    pub fn encoded<T: Serialize>(source: impl Into<String>, value: &T) -> Self {
+        Self::new(source, postcard::to_extend(value, Vec::new()).unwrap())
+    }
+}
+
+/// Assert that the frames conveyed are those scripted, else panic with a
+/// line per frame in the manner of a diff: frames that are the same are
+/// indented, those expected are marked with `-` and those conveyed instead
+/// are marked with `+`.
+#[track_caller]
+pub fn assert_frames_eq(expected: &[Frame], actual: &[Frame]) {
+    if expected == actual {
+        return;
+    }
+    let mut diff = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => write_frame(&mut diff, ' ', e),
+            (e, a) => {
+                if let Some(e) = e {
+                    write_frame(&mut diff, '-', e);
+                }
+                if let Some(a) = a {
+                    write_frame(&mut diff, '+', a);
+                }
+            }
+        }
+    }
+    panic!("frames differ from those scripted (-expected +actual):\n{diff}");
+}
+
+// A line of a diff, the bytes in hex.
+fn write_frame(diff: &mut String, mark: char, frame: &Frame) {
+    let _ = write!(diff, "{mark} {:<10}", frame.source);
+    for byte in &frame.bytes {
+        let _ = write!(diff, " {byte:02x}");
+    }
+    diff.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::string::ToString;
+
+    use postcard::experimental::max_size::MaxSize;
+
+    use aead::generic_array::typenum::U12;
+
+    use crate::{new_nonce, new_nonce_in, NonceScheme, NETWORK_NONCE_DOMAIN, UPDATE_NONCE_DOMAIN};
+
+    proptest! {
+        #[test]
+        fn test_header_round_trip(header in header_strategy()) {
+            prop_assert_eq!(Header::parse(header.to_packed()), Ok(header));
+        }
+
+        #[test]
+        fn test_packed_header_round_trip(packed in any::<(u8, u8, u8, u8)>()) {
+            if let Ok(header) = Header::parse(packed) {
+                prop_assert_eq!(header.to_packed(), packed);
+            }
+        }
+
+        #[test]
+        fn test_nonce_uniqueness(
+            header in header_strategy(),
+            other in header_strategy(),
+            len in 0..128usize,
+            other_len in 0..128usize,
+        ) {
+            let nonce = new_nonce(header.to_packed(), len);
+            prop_assert_eq!(
+                nonce == new_nonce(other.to_packed(), other_len),
+                header == other && len == other_len
+            );
+            prop_assert_ne!(
+                new_nonce_in(NETWORK_NONCE_DOMAIN, header.to_packed(), len),
+                new_nonce_in(UPDATE_NONCE_DOMAIN, header.to_packed(), len)
+            );
+            prop_assert_eq!(
+                U12::nonce(NETWORK_NONCE_DOMAIN, header.to_packed(), len, None)
+                    == U12::nonce(NETWORK_NONCE_DOMAIN, other.to_packed(), other_len, None),
+                header == other && len == other_len
+            );
+        }
+
+        #[test]
+        fn test_version_round_trip(version in version_strategy()) {
+            let mut buf = [0; Version::POSTCARD_MAX_SIZE];
+            let encoded = postcard::to_slice(&version, &mut buf).unwrap();
+            prop_assert_eq!(postcard::from_bytes::<Version>(encoded), Ok(version.clone()));
+            prop_assert_eq!(version.to_string().parse::<Version>(), Ok(version));
+        }
+    }
+
+    #[test]
+    fn test_frames_eq() {
+        let frames = [
+            Frame::new("client", [0, 1]),
+            Frame::encoded("server 1", &5u8),
+        ];
+        assert_frames_eq(&frames, &frames);
+    }
+
+    #[test]
+    #[should_panic(expected = "frames differ from those scripted (-expected +actual):
+  client     00 01
+- server 1   05
++ server 2   05
++ server 1   06
+")]
+    fn test_frames_differ() {
+        assert_frames_eq(
+            &[Frame::new("client", [0, 1]), Frame::new("server 1", [5])],
+            &[
+                Frame::new("client", [0, 1]),
+                Frame::new("server 2", [5]),
+                Frame::new("server 1", [6]),
+            ],
+        );
+    }
+}
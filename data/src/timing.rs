@@ -0,0 +1,248 @@
+//! Timing calculations for a shared medium. Durations are in integer ticks
+//! of whatever resolution the caller's timer uses, given its ticks per second.
+
+use rand::RngCore;
+
+/// The characteristics of a serial link that determine how long a frame
+/// occupies the wire.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkTiming {
+    /// The bit rate of the link e.g. 115200 baud.
+    pub bits_per_second: u32,
+    /// The number of bits transmitted for each byte including start, stop
+    /// and parity bits.
+    pub bits_per_byte: u32,
+}
+
+impl LinkTiming {
+    /// Time on the wire for a number of bytes, rounded up to whole ticks, or
+    /// None should the link have no bit rate or the time not fit a `u32`.
+    pub const fn time_on_wire(&self, bytes: usize, ticks_per_second: u32) -> Option<u32> {
+        if self.bits_per_second == 0 {
+            return None;
+        }
+        let Some(bits) = (bytes as u64).checked_mul(self.bits_per_byte as u64) else {
+            return None;
+        };
+        let Some(bit_ticks) = bits.checked_mul(ticks_per_second as u64) else {
+            return None;
+        };
+        let ticks = bit_ticks.div_ceil(self.bits_per_second as u64);
+        if ticks > u32::MAX as u64 {
+            None
+        } else {
+            Some(ticks as u32)
+        }
+    }
+}
+
+/// Convert milliseconds to ticks, rounding up.
+pub const fn ticks_from_millis(millis: u32, ticks_per_second: u32) -> u32 {
+    (millis as u64 * ticks_per_second as u64).div_ceil(1000) as u32
+}
+
+/// Problems constructing a [SlotSchedule].
+#[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SlotScheduleError {
+    /// There must be at least one slot.
+    NoSlots,
+    /// A slot cannot accommodate the guard time and the frame.
+    SlotTooShort {
+        slot_ticks: u32,
+        required_ticks: u32,
+    },
+    /// The window is too long to be represented in ticks.
+    WindowTooLong,
+}
+
+/// A window of time divided into slots, each beginning with a guard time,
+/// within which replies to a broadcast are transmitted e.g. replies to an
+/// [crate::discovery::Identify]. Spreading replies over slots reduces the
+/// chance of them colliding on a shared medium.
+///
+/// Slots are the window divided by the number of slots, rounded down. Any
+/// remaining ticks fall at the end of the window.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SlotSchedule {
+    window_ticks: u32,
+    slots: u32,
+    guard_ticks: u32,
+}
+
+impl SlotSchedule {
+    /// Divide a window into a number of slots, validating that each slot is
+    /// at least the guard time plus the time on the wire of a frame.
+    pub fn new(
+        window_ticks: u32,
+        slots: u32,
+        guard_ticks: u32,
+        frame_ticks: u32,
+    ) -> Result<Self, SlotScheduleError> {
+        if slots == 0 {
+            return Err(SlotScheduleError::NoSlots);
+        }
+        let schedule = Self {
+            window_ticks,
+            slots,
+            guard_ticks,
+        };
+        let required_ticks = guard_ticks.saturating_add(frame_ticks);
+        if schedule.slot_ticks() < required_ticks {
+            return Err(SlotScheduleError::SlotTooShort {
+                slot_ticks: schedule.slot_ticks(),
+                required_ticks,
+            });
+        }
+        Ok(schedule)
+    }
+
+    /// A window of slots that each accommodate exactly the guard time plus
+    /// the time on the wire of a frame, validated as per `new`.
+    pub fn contiguous(
+        slots: u32,
+        guard_ticks: u32,
+        frame_ticks: u32,
+    ) -> Result<Self, SlotScheduleError> {
+        let window_ticks = guard_ticks
+            .checked_add(frame_ticks)
+            .and_then(|slot_ticks| slot_ticks.checked_mul(slots))
+            .ok_or(SlotScheduleError::WindowTooLong)?;
+        Self::new(window_ticks, slots, guard_ticks, frame_ticks)
+    }
+
+    /// The duration of the window.
+    pub const fn window_ticks(&self) -> u32 {
+        self.window_ticks
+    }
+
+    /// The number of slots within the window.
+    pub const fn slots(&self) -> u32 {
+        self.slots
+    }
+
+    /// The guard time at the beginning of each slot.
+    pub const fn guard_ticks(&self) -> u32 {
+        self.guard_ticks
+    }
+
+    /// The duration of each slot.
+    pub const fn slot_ticks(&self) -> u32 {
+        self.window_ticks / self.slots
+    }
+
+    /// The delay from the start of the window at which to transmit in a
+    /// given slot i.e. after its guard time. Slots beyond the window wrap.
+    pub const fn transmit_offset(&self, slot: u32) -> u32 {
+        (slot % self.slots) * self.slot_ticks() + self.guard_ticks
+    }
+
+    /// Select a slot randomly.
+    pub fn random_slot<T>(&self, rng: &mut T) -> u32
+    where
+        T: RngCore,
+    {
+        rng.next_u32() % self.slots
+    }
+
+    /// Select a slot derived from an address so that servers with distinct
+    /// addresses avoid each other when there are enough slots.
+    pub const fn address_slot(&self, address: u8) -> u32 {
+        address as u32 % self.slots
+    }
+
+    /// When a window starting at a given time closes, saturating at the
+    /// latest time.
+    pub const fn window_closes_at(&self, start_ticks: u64) -> u64 {
+        start_ticks.saturating_add(self.window_ticks as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIAL: LinkTiming = LinkTiming {
+        bits_per_second: 115200,
+        bits_per_byte: 9,
+    };
+
+    #[test]
+    fn test_time_on_wire() {
+        // 12,800 bytes per second.
+        assert_eq!(SERIAL.time_on_wire(12800, 1000), Some(1000));
+        // 41 bytes is 3.2ms, and 104.96 ticks of a 32,768Hz clock.
+        assert_eq!(SERIAL.time_on_wire(41, 1000), Some(4));
+        assert_eq!(SERIAL.time_on_wire(41, 32768), Some(105));
+        assert_eq!(SERIAL.time_on_wire(0, 32768), Some(0));
+
+        // A link without a bit rate has no time on the wire, nor does one
+        // too long to count in ticks.
+        let stopped = LinkTiming {
+            bits_per_second: 0,
+            ..SERIAL
+        };
+        assert_eq!(stopped.time_on_wire(1, 1000), None);
+        assert_eq!(SERIAL.time_on_wire(12800 * 5_000_000, 1000), None);
+        assert_eq!(SERIAL.time_on_wire(usize::MAX, u32::MAX), None);
+        assert_eq!(ticks_from_millis(900, 32768), 29492);
+        assert_eq!(ticks_from_millis(900, 1000), 900);
+    }
+
+    #[test]
+    fn test_slot_schedule_rounding() {
+        // A 900ms window at 32,768Hz is 29,491.2 ticks, and 300 slots of
+        // 98.3 ticks are rounded down.
+        let frame_ticks = SERIAL.time_on_wire(13, 32768).unwrap();
+        assert_eq!(frame_ticks, 34);
+        let schedule =
+            SlotSchedule::new(ticks_from_millis(900, 32768), 300, 33, frame_ticks).unwrap();
+        assert_eq!(schedule.slot_ticks(), 98);
+        assert_eq!(schedule.transmit_offset(0), 33);
+        assert_eq!(schedule.transmit_offset(299), 299 * 98 + 33);
+        assert!(schedule.transmit_offset(299) + frame_ticks <= schedule.window_ticks());
+        assert_eq!(schedule.transmit_offset(300), 33);
+        assert_eq!(schedule.window_closes_at(1_000), 1_000 + 29_492);
+        assert_eq!(schedule.window_closes_at(u64::MAX - 1), u64::MAX);
+
+        assert_eq!(schedule.address_slot(7), 7);
+        assert_eq!(SlotSchedule::new(10, 3, 1, 2).unwrap().address_slot(7), 1);
+    }
+
+    #[test]
+    fn test_slot_schedule_validation() {
+        assert_eq!(
+            SlotSchedule::new(900, 0, 1, 2),
+            Err(SlotScheduleError::NoSlots)
+        );
+        assert_eq!(
+            SlotSchedule::new(899, 300, 1, 2),
+            Err(SlotScheduleError::SlotTooShort {
+                slot_ticks: 2,
+                required_ticks: 3
+            })
+        );
+        assert!(SlotSchedule::new(900, 300, 1, 2).is_ok());
+        assert_eq!(
+            SlotSchedule::new(900, 300, 1, 2),
+            SlotSchedule::contiguous(300, 1, 2)
+        );
+        assert_eq!(
+            SlotSchedule::contiguous(0, 1, 2),
+            Err(SlotScheduleError::NoSlots)
+        );
+
+        // A second's window of a 32 kHz timer fits, but not 2^17 of them.
+        assert!(SlotSchedule::contiguous(1000, 8, 24).is_ok());
+        assert_eq!(
+            SlotSchedule::contiguous(1 << 17, 32_768, 0),
+            Err(SlotScheduleError::WindowTooLong)
+        );
+        assert_eq!(
+            SlotSchedule::contiguous(1, u32::MAX, 1),
+            Err(SlotScheduleError::WindowTooLong)
+        );
+    }
+}
@@ -1,11 +1,38 @@
-use core::{cmp::Ordering, fmt::Display, str::FromStr};
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod delta;
+#[cfg(feature = "signing")]
+pub mod signing;
 
+use core::{
+    cmp::Ordering,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use aead::{AeadInPlace, KeyInit};
 use heapless::Vec;
-use serde::{Deserialize, Serialize};
+use postcard::experimental::max_size::MaxSize;
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::{SerializeStruct, SerializeTuple},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    deserialise_last_field, from_datagram_in,
+    port::{Port, PortSet},
+    serialise_last_field,
+    timing::LinkTiming,
+    to_datagram_in, DataSource, FromDatagramError, Header, NonceScheme, UPDATE_NONCE_DOMAIN,
+};
 
 /// Describes a key for the purposes of update message
-/// encryption and authentication.
-#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+/// encryption and authentication. With the `zeroize` feature, the key is
+/// zeroed when dropped.
+#[derive(Clone, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct UpdateKey(pub [u8; 16]);
 impl core::fmt::Debug for UpdateKey {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -18,28 +45,46 @@ impl defmt::Format for UpdateKey {
         defmt::write!(fmt, "UpdateKey(XXX)");
     }
 }
+#[cfg(feature = "zeroize")]
+impl zeroize::Zeroize for UpdateKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+#[cfg(feature = "zeroize")]
+impl Drop for UpdateKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(self);
+    }
+}
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for UpdateKey {}
 
 /// A constrained form of pre-release designators along with
 /// a numeric identifer.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Eq, MaxSize, PartialEq, Deserialize, Serialize)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[non_exhaustive]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum PreRelease {
     Alpha(u8),
     Beta(u8),
+    /// A release candidate, following betas.
+    Rc(u8),
+}
+impl PreRelease {
+    // The designator's precedence followed by its ident.
+    fn rank(&self) -> (u8, u8) {
+        match *self {
+            PreRelease::Alpha(ident) => (0, ident),
+            PreRelease::Beta(ident) => (1, ident),
+            PreRelease::Rc(ident) => (2, ident),
+        }
+    }
 }
 impl Ord for PreRelease {
     fn cmp(&self, other: &Self) -> Ordering {
-        match (self, other) {
-            (PreRelease::Alpha(self_ident), PreRelease::Alpha(other_ident)) => {
-                self_ident.cmp(other_ident)
-            }
-            (PreRelease::Alpha(_), PreRelease::Beta(_)) => Ordering::Less,
-            (PreRelease::Beta(_), PreRelease::Alpha(_)) => Ordering::Greater,
-            (PreRelease::Beta(self_ident), PreRelease::Beta(other_ident)) => {
-                self_ident.cmp(other_ident)
-            }
-        }
+        self.rank().cmp(&other.rank())
     }
 }
 impl PartialOrd for PreRelease {
@@ -50,10 +95,10 @@ impl PartialOrd for PreRelease {
 
 /// A compact and limited representation of a version based on
 /// https://semver.org. In particular, there is no provision for a
-/// build identifier. Also, pre-releases are constrained to Alpha
-/// and Beta and must always have an ident.
-#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+/// build identifier. Also, pre-releases are constrained to Alpha,
+/// Beta and Rc and must always have an ident.
+#[derive(Clone, Debug, Eq, MaxSize, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Version {
     pub major: u8,
     pub minor: u8,
@@ -66,40 +111,91 @@ impl Display for Version {
         match self.pre {
             Some(PreRelease::Alpha(ident)) => write!(f, "-alpha.{ident}"),
             Some(PreRelease::Beta(ident)) => write!(f, "-beta.{ident}"),
+            Some(PreRelease::Rc(ident)) => write!(f, "-rc.{ident}"),
             None => Ok(()),
         }
     }
 }
-#[derive(Debug)]
-pub struct ParseVersionErr;
+#[cfg(feature = "defmt")]
+impl defmt::Format for Version {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}.{}.{}", self.major, self.minor, self.patch);
+        match self.pre {
+            Some(PreRelease::Alpha(ident)) => defmt::write!(fmt, "-alpha.{}", ident),
+            Some(PreRelease::Beta(ident)) => defmt::write!(fmt, "-beta.{}", ident),
+            Some(PreRelease::Rc(ident)) => defmt::write!(fmt, "-rc.{}", ident),
+            None => {}
+        }
+    }
+}
+/// A component of a [Version] as written e.g. `1.2.3-beta.4`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VersionComponent {
+    Major,
+    Minor,
+    Patch,
+    /// The ident of the pre-release.
+    PreRelease,
+}
+
+/// Why a [Version] could not be parsed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseVersionErr {
+    /// A component is absent or empty.
+    MissingComponent { component: VersionComponent },
+    /// A component is not a number in the range of a u8, including where
+    /// there are characters following it.
+    InvalidNumber { component: VersionComponent },
+    /// The pre-release designator is other than alpha, beta or rc.
+    UnknownPreRelease,
+}
+impl Display for ParseVersionErr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ParseVersionErr::MissingComponent { component } => {
+                write!(f, "missing {component:?} component")
+            }
+            ParseVersionErr::InvalidNumber { component } => {
+                write!(f, "invalid number for the {component:?} component")
+            }
+            ParseVersionErr::UnknownPreRelease => write!(f, "unknown pre-release designator"),
+        }
+    }
+}
+
+fn parse_component(s: Option<&str>, component: VersionComponent) -> Result<u8, ParseVersionErr> {
+    let s = s
+        .filter(|s| !s.is_empty())
+        .ok_or(ParseVersionErr::MissingComponent { component })?;
+    s.parse::<u8>()
+        .map_err(|_| ParseVersionErr::InvalidNumber { component })
+}
+
 impl FromStr for Version {
     type Err = ParseVersionErr;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (l, r) = s.split_once('.').ok_or(ParseVersionErr)?;
-        let major = l.parse::<u8>().map_err(|_| ParseVersionErr)?;
-        let (l, r) = r.split_once('.').ok_or(ParseVersionErr)?;
-        let minor = l.parse::<u8>().map_err(|_| ParseVersionErr)?;
-        let r = if let Some((l, _)) = r.split_once('+') {
-            l
-        } else {
-            r
-        };
-        let (l, r) = if let Some((l, r)) = r.split_once('-') {
-            (l, r)
-        } else {
-            (r, "")
-        };
-        let patch = l.parse::<u8>().map_err(|_| ParseVersionErr)?;
-        let pre = if let Some((_, r)) = r.split_once("alpha.") {
-            let ident = r.parse::<u8>().map_err(|_| ParseVersionErr)?;
-            Some(PreRelease::Alpha(ident))
-        } else if let Some((_, r)) = r.split_once("beta.") {
-            let ident = r.parse::<u8>().map_err(|_| ParseVersionErr)?;
-            Some(PreRelease::Beta(ident))
-        } else {
-            None
-        };
+        // Build metadata is ignored.
+        let s = s.split_once('+').map_or(s, |(l, _)| l);
+        let (s, pre) = s.split_once('-').map_or((s, None), |(l, r)| (l, Some(r)));
+        let mut components = s.splitn(3, '.');
+        let major = parse_component(components.next(), VersionComponent::Major)?;
+        let minor = parse_component(components.next(), VersionComponent::Minor)?;
+        let patch = parse_component(components.next(), VersionComponent::Patch)?;
+        let pre = pre
+            .map(|pre| {
+                let (designator, ident) = pre.split_once('.').unwrap_or((pre, ""));
+                let pre = match designator {
+                    "alpha" => PreRelease::Alpha,
+                    "beta" => PreRelease::Beta,
+                    "rc" => PreRelease::Rc,
+                    _ => return Err(ParseVersionErr::UnknownPreRelease),
+                };
+                parse_component(Some(ident), VersionComponent::PreRelease).map(pre)
+            })
+            .transpose()?;
         Ok(Self {
             major,
             minor,
@@ -130,8 +226,10 @@ impl PartialOrd for Version {
 
 /// Prior to sending out an update, the client prepares one or more servers
 /// to receive an update. As the client knows the encryption key of
-/// a given server, it notifies it of a pending update.
-#[derive(Deserialize, Serialize)]
+/// a given server, it notifies it of a pending update. When formatted, the
+/// update key is shown as `XXX`.
+#[derive(Clone, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct PrepareForUpdate {
     /// The semantic version of the update. A server can use this to
     /// determine eligibility i.e. update only if greater than what
@@ -140,9 +238,18 @@ pub struct PrepareForUpdate {
     /// Those server ports that the update applies to. A server uses
     /// a port for a specific function. Thus, if the applicable ports
     /// matches the server's entire capability then it may elect
-    /// to be updated. The ports are passed as bits e.g. bit 1 relates
+    /// to be updated. The ports are conveyed as bits e.g. bit 1 relates
     /// to port 1, bit 3 relates to port 3 and so on.
-    pub server_ports: u8,
+    pub server_ports: PortSet,
+    /// The image of the server that the update is for e.g. its application
+    /// or the firmware of a co-processor. Servers with just the one image
+    /// update [PRIMARY_IMAGE_ID].
+    pub image_id: u8,
+    /// The security epoch of the update, which is raised when an update fixes
+    /// a vulnerability. Servers refuse updates of an epoch earlier than the
+    /// highest they have applied, whatever their version, so that they cannot
+    /// be rolled back to a vulnerable release. See [UpdateEligibility].
+    pub security_epoch: u16,
     /// The [UpdateKey] is generated for a sequence of update messages to
     /// follow and is used by all servers wishing to update based on this
     /// and the version matching.
@@ -151,117 +258,4929 @@ pub struct PrepareForUpdate {
     /// total update. This allows a server to understand if it has missed
     /// an update message and when it has received all of them.
     pub update_byte_len: u32,
-    /// When set, this will indicate that the update's last n bytes
-    /// will contain a signature. In the case where a signature is
-    /// flagged, the server must verify the bytes comprising the update.
-    /// How the server does this depends on the application-specific
-    /// contract in terms of what signing algoritm is used and how
-    /// many bytes comprise the signature.
-    pub signed: bool,
+    /// The means by which a server establishes that it has received the
+    /// update in full and exactly as intended, and optionally who it is
+    /// from. The update must be verified before it is applied, see
+    /// [UpdateVerifier].
+    pub integrity: UpdateIntegrity,
+    /// How the bytes of each [Update] are compressed, if at all. The update's
+    /// length, integrity and byte offsets are always those of its bytes once
+    /// decompressed. Updates that are not compressed do not convey this field
+    /// so that servers unaware of compression continue to decode them.
+    #[serde(default, deserialize_with = "deserialise_compression")]
+    pub compression: Compression,
+    /// The image that the update patches, if it is a delta update. The
+    /// update's length, integrity and byte offsets are then those of the
+    /// image once patched. Delta updates are not compressed.
+    #[serde(default, deserialize_with = "deserialise_delta")]
+    pub delta: Option<Delta>,
+    /// The hardware revision that a server must be of to accept the update,
+    /// if it is constrained to one. See [UpdateEligibility]. Revisions are
+    /// of up to 254.
+    #[serde(default, deserialize_with = "deserialise_hardware_rev")]
+    #[cfg_attr(feature = "arbitrary", arbitrary(with = arbitrary_hardware_rev))]
+    pub hardware_rev: Option<u8>,
+    /// Ask whether a server would accept the update without preparing for it,
+    /// the server answering with its [EligibilityReport] once asked. The
+    /// update key is not retained, and so none of the update is received.
+    /// Servers unaware of dry runs prepare for the update regardless, and so
+    /// the update key of a dry run should not be that of an update broadcast.
+    #[serde(default, deserialize_with = "deserialise_dry_run")]
+    pub dry_run: bool,
+}
+
+// Any hardware revision, being how the absence of a hardware revision is
+// conveyed when followed by a dry run. Servers aware of hardware revisions
+// but not of dry runs decline such updates as being for another revision.
+const ANY_HARDWARE_REV: u8 = u8::MAX;
+
+fn deserialise_hardware_rev<'de, D>(d: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialise_last_field(d).map(|rev: Option<u8>| rev.filter(|r| *r != ANY_HARDWARE_REV))
+}
+
+// Arbitrary hardware revisions are of up to 254, 255 conveying any.
+#[cfg(feature = "arbitrary")]
+fn arbitrary_hardware_rev(u: &mut arbitrary::Unstructured) -> arbitrary::Result<Option<u8>> {
+    Ok(u.arbitrary::<Option<u8>>()?
+        .filter(|r| *r != ANY_HARDWARE_REV))
+}
+
+fn deserialise_dry_run<'de, D>(d: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialise_last_field(d).map(|dry_run| dry_run.unwrap_or(false))
+}
+
+// A delta update patching nothing, being how the absence of a delta is
+// conveyed when followed by a hardware revision. Servers aware of delta
+// updates but not of hardware revisions decline such updates as being of an
+// unsupported patch format, rather than accepting an update that they cannot
+// check the constraint of.
+const NO_PATCH_FORMAT: u8 = u8::MAX;
+
+fn deserialise_delta<'de, D>(d: D) -> Result<Option<Delta>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialise_last_field(d)
+        .map(|delta: Option<Delta>| delta.filter(|d| d.patch_format != NO_PATCH_FORMAT))
+}
+
+/// The compression, delta, hardware revision and dry run fields are conveyed
+/// only when present, other than compression also being conveyed when
+/// followed by a delta, a delta patching nothing being conveyed when there is
+/// none but followed by a hardware revision, and any hardware revision being
+/// conveyed when there is none but followed by a dry run.
+impl Serialize for PrepareForUpdate {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let hardware_rev_conveyed = self.hardware_rev.is_some() || self.dry_run;
+        let mut t = s.serialize_struct("PrepareForUpdate", 11)?;
+        t.serialize_field("version", &self.version)?;
+        t.serialize_field("server_ports", &self.server_ports)?;
+        t.serialize_field("image_id", &self.image_id)?;
+        t.serialize_field("security_epoch", &self.security_epoch)?;
+        t.serialize_field("update_key", &self.update_key)?;
+        t.serialize_field("update_byte_len", &self.update_byte_len)?;
+        t.serialize_field("integrity", &self.integrity)?;
+        if self.compression != Compression::None || self.delta.is_some() || hardware_rev_conveyed {
+            t.serialize_field("compression", &self.compression)?;
+        } else {
+            t.skip_field("compression")?;
+        }
+        match &self.delta {
+            Some(delta) => t.serialize_field("delta", delta)?,
+            None if hardware_rev_conveyed => t.serialize_field(
+                "delta",
+                &Delta {
+                    base_version: Version {
+                        major: 0,
+                        minor: 0,
+                        patch: 0,
+                        pre: None,
+                    },
+                    patch_format: NO_PATCH_FORMAT,
+                },
+            )?,
+            None => t.skip_field("delta")?,
+        }
+        match self.hardware_rev {
+            Some(hardware_rev) => t.serialize_field("hardware_rev", &hardware_rev)?,
+            None if self.dry_run => t.serialize_field("hardware_rev", &ANY_HARDWARE_REV)?,
+            None => t.skip_field("hardware_rev")?,
+        }
+        if self.dry_run {
+            t.serialize_field("dry_run", &true)?;
+        } else {
+            t.skip_field("dry_run")?;
+        }
+        t.end()
+    }
+}
+
+impl core::fmt::Debug for PrepareForUpdate {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PrepareForUpdate")
+            .field("version", &self.version)
+            .field("server_ports", &self.server_ports.bits())
+            .field("image_id", &self.image_id)
+            .field("security_epoch", &self.security_epoch)
+            .field("update_key", &"XXX")
+            .field("update_byte_len", &self.update_byte_len)
+            .field("integrity", &self.integrity)
+            .field("compression", &self.compression)
+            .field("delta", &self.delta)
+            .field("hardware_rev", &self.hardware_rev)
+            .field("dry_run", &self.dry_run)
+            .finish()
+    }
+}
+#[cfg(feature = "defmt")]
+impl defmt::Format for PrepareForUpdate {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "PrepareForUpdate {{ version: {}, server_ports: {=u8:#b}, image_id: {}, security_epoch: {}, update_key: XXX, update_byte_len: {}, integrity: {}, compression: {}, delta: {}, hardware_rev: {}, dry_run: {} }}",
+            self.version,
+            self.server_ports.bits(),
+            self.image_id,
+            self.security_epoch,
+            self.update_byte_len,
+            self.integrity,
+            self.compression,
+            self.delta,
+            self.hardware_rev,
+            self.dry_run
+        );
+    }
+}
+
+/// The image patched by a delta update, see [delta].
+#[derive(Clone, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Delta {
+    /// The version that a server must be running to apply the patch.
+    pub base_version: Version,
+    /// The format of the patch e.g. [delta::COPY_INSERT_PATCH_FORMAT].
+    #[cfg_attr(
+        feature = "arbitrary",
+        arbitrary(with = |u: &mut arbitrary::Unstructured| u.int_in_range(0..=NO_PATCH_FORMAT - 1))
+    )]
+    pub patch_format: u8,
+}
+
+/// The trailing fields are conveyed without an `Option`'s tag, and so are
+/// at most the size of their values.
+impl MaxSize for PrepareForUpdate {
+    const POSTCARD_MAX_SIZE: usize = Version::POSTCARD_MAX_SIZE
+        + u8::POSTCARD_MAX_SIZE
+        + u8::POSTCARD_MAX_SIZE
+        + u16::POSTCARD_MAX_SIZE
+        + UpdateKey::POSTCARD_MAX_SIZE
+        + u32::POSTCARD_MAX_SIZE
+        + UpdateIntegrity::POSTCARD_MAX_SIZE
+        + Compression::POSTCARD_MAX_SIZE
+        + Delta::POSTCARD_MAX_SIZE
+        + u8::POSTCARD_MAX_SIZE
+        + bool::POSTCARD_MAX_SIZE;
+}
+
+/// The maximum size of an encoded [PrepareForUpdate]. This exceeds
+/// [crate::discovery::MIN_PAYLOAD_SIZE] and so packets conveying it must
+/// be sized accordingly.
+pub const MAX_PREPARE_FOR_UPDATE_SIZE: usize = PrepareForUpdate::POSTCARD_MAX_SIZE;
+
+/// The image of a server that is updated when no other is identified.
+pub const PRIMARY_IMAGE_ID: u8 = 0;
+
+// The image is conveyed only when other than the primary one so that messages
+// for it remain as they were.
+fn deserialise_image_id<'de, D>(d: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialise_last_field(d).map(|image_id| image_id.unwrap_or(PRIMARY_IMAGE_ID))
+}
+
+fn serialise_image_id<S>(image_id: &u8, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serialise_last_field(&Some(image_id).filter(|id| **id != PRIMARY_IMAGE_ID), s)
+}
+
+// A u32 is encoded as a varint.
+const MAX_U32_SIZE: usize = 5;
+
+/// The number of bytes in an update's digest.
+pub const DIGEST_SIZE: usize = 32;
+
+/// The SHA-256 digest of an update's bytes e.g. as computed by the host
+/// building it.
+pub fn update_digest(update: &[u8]) -> [u8; DIGEST_SIZE] {
+    Sha256::digest(update).into()
+}
+
+/// How an update is verified by a server.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum UpdateIntegrity {
+    /// The update's SHA-256 digest.
+    Digest([u8; DIGEST_SIZE]),
+    /// The update's signature, which also covers its bytes given that it
+    /// is of their digest.
+    Signed(UpdateSignature),
+}
+
+/// How the bytes of an update are compressed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Compression {
+    /// The bytes are not compressed.
+    #[default]
+    None,
+    /// The bytes are compressed with heatshrink, given its window and
+    /// lookahead sizes as powers of 2. The optional `compression` feature
+    /// provides for decompressing them.
+    Heatshrink { window: u8, lookahead: u8 },
+}
+
+fn deserialise_compression<'de, D>(d: D) -> Result<Compression, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialise_last_field(d).map(Option::unwrap_or_default)
+}
+
+/// The number of bytes in an [UpdateSignature]'s signature.
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// An Ed25519ph signature of an update, its version and its length, along
+/// with an identifier of the key that signed it so that servers are able to
+/// trust more than one key e.g. while keys are rotated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct UpdateSignature {
+    pub key_id: u8,
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+/// The key identifier followed by the bytes of the signature.
+impl MaxSize for UpdateSignature {
+    const POSTCARD_MAX_SIZE: usize = u8::POSTCARD_MAX_SIZE + SIGNATURE_SIZE;
+}
+
+/// Serde supports arrays of up to 32 elements only, so the signature is
+/// encoded as a tuple of its key identifier and bytes.
+impl Serialize for UpdateSignature {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut t = s.serialize_tuple(1 + SIGNATURE_SIZE)?;
+        t.serialize_element(&self.key_id)?;
+        for b in &self.signature {
+            t.serialize_element(b)?;
+        }
+        t.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for UpdateSignature {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct UpdateSignatureVisitor;
+
+        impl<'de> Visitor<'de> for UpdateSignatureVisitor {
+            type Value = UpdateSignature;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a key id followed by {SIGNATURE_SIZE} bytes")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let key_id = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let mut signature = [0; SIGNATURE_SIZE];
+                for (i, b) in signature.iter_mut().enumerate() {
+                    *b = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i + 1, &self))?;
+                }
+                Ok(UpdateSignature { key_id, signature })
+            }
+        }
+
+        d.deserialize_tuple(1 + SIGNATURE_SIZE, UpdateSignatureVisitor)
+    }
+}
+
+/// Problems verifying an update.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VerificationError {
+    /// A signed update is required, but the update is not signed.
+    Unsigned,
+    /// The update is signed with a key that is not trusted.
+    UntrustedKey(u8),
+    /// Bytes of the update have been missed or received out of order, or are
+    /// beyond its length.
+    UnexpectedOffset { expected: u32, received: u32 },
+    /// Verification was attempted before the update was received in full.
+    Incomplete { received: u32, expected: u32 },
+    /// The digest of the bytes received is not that of the update.
+    DigestMismatch,
+    /// The signature is not that of the update.
+    BadSignature,
+    /// The update is compressed in a way that is not supported.
+    UnsupportedCompression,
+    /// The compressed bytes of the update cannot be decompressed.
+    BadCompression,
+    /// The update is a patch in a format that is not supported.
+    UnsupportedPatch,
+    /// The update is a patch that cannot be applied to the image running.
+    BadPatch,
+}
+
+/// Where a verifier is within the verification of an update.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VerifierState {
+    /// The bytes of the update are being hashed as they arrive.
+    Hashing,
+    /// The update has been verified and may be applied.
+    Verified,
+    /// Verification has failed. The update must not be applied and the
+    /// verifier remains failed.
+    Failed(VerificationError),
+}
+
+// A verifier is long lived and there is no allocator to box the signature
+// state with.
+#[allow(clippy::large_enum_variant)]
+enum Check {
+    Digest {
+        hasher: Sha256,
+        expected: [u8; DIGEST_SIZE],
+    },
+    #[cfg(feature = "signing")]
+    Signature {
+        hasher: sha2::Sha512,
+        key: signing::VerifyingKey,
+        signature: ed25519_dalek::Signature,
+    },
+    Unverifiable,
+}
+
+/// Verifies an update as its bytes are received, for a server to call
+/// `verify` before applying the update. Any failure is terminal so that
+/// an update cannot be applied having failed once. A restarted update
+/// requires a new verifier.
+pub struct UpdateVerifier {
+    check: Check,
+    update_byte_len: u32,
+    next_byte_offset: u32,
+    state: VerifierState,
+}
+
+impl UpdateVerifier {
+    /// Prepare to verify an update by its digest. Signed updates cannot be
+    /// verified without trusting the key that signed them, see the `signing`
+    /// feature.
+    pub fn new(prepare_for_update: &PrepareForUpdate) -> Self {
+        match &prepare_for_update.integrity {
+            UpdateIntegrity::Digest(expected) => Self::checking(
+                prepare_for_update,
+                Check::Digest {
+                    hasher: Sha256::new(),
+                    expected: *expected,
+                },
+            ),
+            UpdateIntegrity::Signed(signature) => Self::failed(
+                prepare_for_update,
+                VerificationError::UntrustedKey(signature.key_id),
+            ),
+        }
+    }
+
+    fn checking(prepare_for_update: &PrepareForUpdate, check: Check) -> Self {
+        Self {
+            check,
+            update_byte_len: prepare_for_update.update_byte_len,
+            next_byte_offset: 0,
+            state: VerifierState::Hashing,
+        }
+    }
+
+    fn failed(prepare_for_update: &PrepareForUpdate, e: VerificationError) -> Self {
+        Self {
+            state: VerifierState::Failed(e),
+            ..Self::checking(prepare_for_update, Check::Unverifiable)
+        }
+    }
+
+    /// Hash the bytes of an update received at a given offset. Bytes must be
+    /// received in order.
+    pub fn update(&mut self, byte_offset: u32, bytes: &[u8]) -> Result<(), VerificationError> {
+        self.check_hashing()?;
+        let end = byte_offset as u64 + bytes.len() as u64;
+        if byte_offset != self.next_byte_offset || end > self.update_byte_len as u64 {
+            return Err(self.fail(VerificationError::UnexpectedOffset {
+                expected: self.next_byte_offset,
+                received: byte_offset,
+            }));
+        }
+        match &mut self.check {
+            Check::Digest { hasher, .. } => hasher.update(bytes),
+            #[cfg(feature = "signing")]
+            Check::Signature { hasher, .. } => hasher.update(bytes),
+            Check::Unverifiable => {}
+        }
+        self.next_byte_offset = end as u32;
+        Ok(())
+    }
+
+    /// Verify the update having received it in full. The update may be
+    /// applied only if Ok is returned.
+    pub fn verify(&mut self) -> Result<(), VerificationError> {
+        if self.state == VerifierState::Verified {
+            return Ok(());
+        }
+        self.check_hashing()?;
+        if self.next_byte_offset != self.update_byte_len {
+            return Err(self.fail(VerificationError::Incomplete {
+                received: self.next_byte_offset,
+                expected: self.update_byte_len,
+            }));
+        }
+        let verified = match core::mem::replace(&mut self.check, Check::Unverifiable) {
+            Check::Digest { hasher, expected } => (hasher.finalize()[..] == expected)
+                .then_some(())
+                .ok_or(VerificationError::DigestMismatch),
+            #[cfg(feature = "signing")]
+            Check::Signature {
+                hasher,
+                key,
+                signature,
+            } => key
+                .verify_prehashed(hasher, Some(signing::SIGNATURE_CONTEXT), &signature)
+                .map_err(|_| VerificationError::BadSignature),
+            Check::Unverifiable => Err(VerificationError::BadSignature),
+        };
+        match verified {
+            Ok(()) => {
+                self.state = VerifierState::Verified;
+                Ok(())
+            }
+            Err(e) => Err(self.fail(e)),
+        }
+    }
+
+    /// Where the verifier is within verification.
+    pub fn state(&self) -> VerifierState {
+        self.state
+    }
+
+    fn check_hashing(&self) -> Result<(), VerificationError> {
+        match self.state {
+            VerifierState::Hashing => Ok(()),
+            VerifierState::Failed(e) => Err(e),
+            VerifierState::Verified => Err(VerificationError::UnexpectedOffset {
+                expected: self.update_byte_len,
+                received: self.next_byte_offset,
+            }),
+        }
+    }
+
+    fn fail(&mut self, e: VerificationError) -> VerificationError {
+        self.state = VerifierState::Failed(e);
+        e
+    }
 }
 
 /// Update payload for the purposes of a client broadcasting to the
 /// servers it has previous shared an update key with. The size of
 /// record is determined by the application.
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, MaxSize, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Update<const N: usize> {
+    /// The offset of the bytes within the update. For a compressed or delta
+    /// update, this is the offset of the first byte decompressed or patched
+    /// from them.
     pub byte_offset: u32,
     /// The update bytes themselves. Cannot exceed 127 bytes.
+    #[cfg_attr(feature = "arbitrary", arbitrary(with = crate::arbitrary_vec))]
     pub bytes: Vec<u8, N>,
 }
 
-/// The number of bytes in an [Update] that are not part of the
-/// `update_bytes` field. Must be used when calculating the size
-/// of the update byte vectors in relation to the maximum number
-/// of bytes that can be sent.
-/// This field presently considers the `update_byte_offset` length
-/// and one byte for the length of the `update_bytes` field. `update_bytes`
-/// cannot exceed 127 bytes.
-pub const UPDATE_BYTES_OVERHEAD: usize = 4 + 1;
+/// Why an update has been aborted by the client.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AbortReason {
+    /// The update has been cancelled e.g. by an operator.
+    Cancelled,
+    /// The update has been found to be bad.
+    BadImage,
+    /// The update is to be replaced by another.
+    Superseded,
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Instructs the servers prepared for an update to abandon it, forgetting
+/// its update key and any of its bytes received.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdateAbort {
+    pub reason: AbortReason,
+    /// The image being updated.
+    #[serde(
+        default,
+        deserialize_with = "deserialise_image_id",
+        serialize_with = "serialise_image_id"
+    )]
+    pub image_id: u8,
+}
 
-    #[test]
-    fn test_parse_versions() {
-        assert_eq!(
-            "1.2.3".parse::<Version>().unwrap(),
-            Version {
-                major: 1,
-                minor: 2,
-                patch: 3,
-                pre: None
-            }
-        );
-        assert_eq!(
-            "1.2.3-alpha.1".parse::<Version>().unwrap(),
-            Version {
-                major: 1,
-                minor: 2,
-                patch: 3,
-                pre: Some(PreRelease::Alpha(1))
-            }
-        );
-        assert_eq!(
-            "1.2.3-beta.1".parse::<Version>().unwrap(),
-            Version {
-                major: 1,
-                minor: 2,
-                patch: 3,
-                pre: Some(PreRelease::Beta(1))
-            }
-        );
-        assert_eq!(
-            "1.2.3-beta.1+some-additional-ident"
-                .parse::<Version>()
-                .unwrap(),
-            Version {
-                major: 1,
-                minor: 2,
-                patch: 3,
-                pre: Some(PreRelease::Beta(1))
-            }
-        );
-        assert_eq!(
-            "1.2.3+some-additional-ident".parse::<Version>().unwrap(),
-            Version {
-                major: 1,
-                minor: 2,
-                patch: 3,
-                pre: None
-            }
-        );
+/// Instructs the servers that have staged an update to apply it once a
+/// number of ticks have elapsed from receiving the commit, so that they
+/// are able to apply it at about the same time.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdateCommit {
+    /// The version staged.
+    pub version: Version,
+    pub activate_delay_ticks: u32,
+    /// The image staged.
+    #[serde(
+        default,
+        deserialize_with = "deserialise_image_id",
+        serialize_with = "serialise_image_id"
+    )]
+    pub image_id: u8,
+}
+
+/// Instructs the servers that have staged an update to discard it e.g.
+/// because it has been found wanting on another server.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdateRollbackStaged {
+    /// The version staged.
+    pub version: Version,
+    /// The image staged.
+    #[serde(
+        default,
+        deserialize_with = "deserialise_image_id",
+        serialize_with = "serialise_image_id"
+    )]
+    pub image_id: u8,
+}
+
+/// The messages broadcast by the client to the servers prepared for an
+/// update, under the update key. Each image being updated has an update key
+/// of its own.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateMessage<const N: usize> {
+    Update(Update<N>),
+    Abort(UpdateAbort),
+    Commit(UpdateCommit),
+    RollbackStaged(UpdateRollbackStaged),
+}
+
+impl<const N: usize> UpdateMessage<N> {
+    /// Abort the update of the primary image for all of the servers prepared
+    /// for it.
+    pub fn abort(reason: AbortReason) -> Self {
+        Self::Abort(UpdateAbort {
+            reason,
+            image_id: PRIMARY_IMAGE_ID,
+        })
     }
 
-    #[test]
-    fn test_compare_versions() {
-        assert!("1.0.0".parse::<Version>().unwrap() == "1.0.0".parse::<Version>().unwrap());
-        assert!("1.0.0".parse::<Version>().unwrap() < "2.0.0".parse::<Version>().unwrap());
-        assert!("1.1.0".parse::<Version>().unwrap() < "1.2.0".parse::<Version>().unwrap());
-        assert!("1.1.1".parse::<Version>().unwrap() < "1.1.2".parse::<Version>().unwrap());
-        assert!("1.0.0-alpha.1".parse::<Version>().unwrap() < "1.0.0".parse::<Version>().unwrap());
-        assert!("1.0.0-beta.1".parse::<Version>().unwrap() < "1.0.0".parse::<Version>().unwrap());
-        assert!(
-            "1.0.0-alpha.1".parse::<Version>().unwrap()
-                < "1.0.0-beta.1".parse::<Version>().unwrap()
-        );
-        assert!(
-            "1.0.0-alpha.1".parse::<Version>().unwrap()
-                < "1.0.0-alpha.2".parse::<Version>().unwrap()
-        );
+    /// Commit the staged update of the primary image for all of the servers
+    /// prepared for it.
+    pub fn commit(version: Version, activate_delay_ticks: u32) -> Self {
+        Self::Commit(UpdateCommit {
+            version,
+            activate_delay_ticks,
+            image_id: PRIMARY_IMAGE_ID,
+        })
     }
 
-    #[test]
-    fn display_versions() {
-        assert_eq!("1.2.3".parse::<Version>().unwrap().to_string(), "1.2.3");
-        assert_eq!(
-            "1.2.3-alpha.4".parse::<Version>().unwrap().to_string(),
-            "1.2.3-alpha.4"
-        );
-        assert_eq!(
-            "1.2.3-beta.4".parse::<Version>().unwrap().to_string(),
-            "1.2.3-beta.4"
-        );
+    /// Discard the staged update of the primary image for all of the servers
+    /// prepared for it.
+    pub fn rollback_staged(version: Version) -> Self {
+        Self::RollbackStaged(UpdateRollbackStaged {
+            version,
+            image_id: PRIMARY_IMAGE_ID,
+        })
+    }
+}
+
+/// The number of bytes in an [UpdateMessage] conveying an [Update] that
+/// are not part of the `update_bytes` field. Must be used when calculating
+/// the size of the update byte vectors in relation to the maximum number
+/// of bytes that can be sent.
+/// This is the message's variant along with the maximum size of an [Update]
+/// without any bytes, being its `byte_offset` and one byte for the length of
+/// its `bytes`. `bytes` cannot exceed 127 bytes so that their length remains
+/// a single byte.
+pub const UPDATE_BYTES_OVERHEAD: usize = 1 + Update::<0>::POSTCARD_MAX_SIZE;
+
+/// Encrypts a payload under an update key e.g. an encoded [UpdateMessage],
+/// and encodes it into a datagram as per [crate::to_datagram], other than
+/// the nonce being of the [UPDATE_NONCE_DOMAIN]. Frames encrypted under an
+/// update key must always be encoded this way.
+pub fn to_update_datagram<const N: usize>(
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+    header: &Header,
+    payload_buf: &[u8],
+    datagram_buf: &mut [u8; N],
+) {
+    to_datagram_in(
+        UPDATE_NONCE_DOMAIN,
+        cipher,
+        header,
+        payload_buf,
+        datagram_buf,
+    )
+}
+
+/// Decodes a datagram encrypted under an update key as per
+/// [crate::from_datagram], other than the nonce being of the
+/// [UPDATE_NONCE_DOMAIN]. Datagrams encoded with [crate::to_datagram] fail
+/// to decrypt, even under the same key.
+pub fn from_update_datagram<const N: usize>(
+    datagram_buf: &[u8; N],
+    filter: impl FnOnce(&Header) -> bool,
+    cipher: &impl AeadInPlace<NonceSize = impl NonceScheme>,
+) -> Result<(Header, Vec<u8, N>), FromDatagramError> {
+    from_datagram_in(UPDATE_NONCE_DOMAIN, datagram_buf, filter, cipher)
+}
+
+/// Sent by the client to an individual server, under its network key, to
+/// learn how far it got with an update e.g. once all of the update has been
+/// broadcast.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdateStatusRequest {
+    /// Ask for up to this many of the ranges of the update that are missing,
+    /// in which case the server replies with its [MissingRanges] in place of
+    /// its [UpdateStatus].
+    #[serde(default, deserialize_with = "deserialise_missing_ranges")]
+    pub missing_ranges: Option<u8>,
+    /// The image being updated.
+    #[serde(default, deserialize_with = "deserialise_image_id")]
+    pub image_id: u8,
+    /// Ask for the server's eligibility for the update last prepared for, in
+    /// which case the server replies with its [EligibilityReport] in place of
+    /// its [UpdateStatus] or [MissingRanges].
+    #[serde(default, deserialize_with = "deserialise_eligibility")]
+    pub eligibility: bool,
+}
+
+fn deserialise_eligibility<'de, D>(d: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialise_last_field(d).map(|eligibility| eligibility.unwrap_or(false))
+}
+
+// Asking for no missing ranges is asking for the status, which is how the
+// status of an image other than the primary one is asked for.
+fn deserialise_missing_ranges<'de, D>(d: D) -> Result<Option<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialise_last_field(d).map(|missing_ranges| missing_ranges.filter(|n| *n > 0))
+}
+
+/// Fields are conveyed only when present, other than the number of missing
+/// ranges being conveyed as 0 when not present but followed by an image, and
+/// the image being conveyed when followed by asking for eligibility.
+impl Serialize for UpdateStatusRequest {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut t = s.serialize_struct("UpdateStatusRequest", 3)?;
+        let image_id_conveyed = self.image_id != PRIMARY_IMAGE_ID || self.eligibility;
+        match self.missing_ranges {
+            Some(missing_ranges) => t.serialize_field("missing_ranges", &missing_ranges)?,
+            None if image_id_conveyed => t.serialize_field("missing_ranges", &0u8)?,
+            None => t.skip_field("missing_ranges")?,
+        }
+        if image_id_conveyed {
+            t.serialize_field("image_id", &self.image_id)?;
+        } else {
+            t.skip_field("image_id")?;
+        }
+        if self.eligibility {
+            t.serialize_field("eligibility", &true)?;
+        } else {
+            t.skip_field("eligibility")?;
+        }
+        t.end()
+    }
+}
+
+/// The maximum number of ranges conveyed by [MissingRanges].
+pub const MAX_MISSING_RANGES: usize = 8;
+
+/// The maximum size of an encoded [MissingRanges].
+pub const MAX_MISSING_RANGES_SIZE: usize = 1 + MAX_MISSING_RANGES * 2 * MAX_U32_SIZE;
+
+/// A server's reply to an [UpdateStatusRequest] asking for the ranges of an
+/// update that it has yet to receive, being the byte offset and length of
+/// each in order. The ranges are those first missing where there are more
+/// than asked for.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MissingRanges {
+    pub ranges: Vec<(u32, u32), MAX_MISSING_RANGES>,
+}
+
+/// Where a server is within an update.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateState {
+    /// No update has been prepared for.
+    Idle,
+    /// The update's bytes are being received.
+    Receiving,
+    /// The update has been received in full and is being verified.
+    Verifying,
+    /// The update has been committed and is to be applied now.
+    ReadyToApply,
+    /// The update has been applied.
+    Applied,
+    /// The update has failed and will not be applied. Another update must be
+    /// prepared for.
+    Failed,
+    /// The update was aborted by the client, or its staging rolled back.
+    /// Another update must be prepared for.
+    Aborted,
+    /// The update has been verified and is awaiting its commitment.
+    Staged,
+    /// The update has been committed and will be applied once its activation
+    /// delay has elapsed.
+    Committing,
+}
+
+/// A server's reply to an [UpdateStatusRequest].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdateStatus {
+    pub state: UpdateState,
+    /// The number of bytes of the update received so far without any missing,
+    /// being the offset from which the update is to be resumed.
+    pub received_bytes: u32,
+    /// The version the server is running, which is that of the update once
+    /// applied.
+    pub current_version: Version,
+    /// Why the latest update failed, if it did.
+    pub last_error: Option<VerificationError>,
+    /// The image that the status is of.
+    #[serde(default, deserialize_with = "deserialise_image_id")]
+    pub image_id: u8,
+    /// How the server is to be paced when broadcasting an update to it, if
+    /// it declares this. See [UpdatePacing].
+    #[serde(default, deserialize_with = "deserialise_last_field")]
+    pub pacing: Option<ServerPacing>,
+}
+
+/// The image is conveyed only when other than the primary one, or when
+/// followed by the server's pacing, which is conveyed only when declared.
+impl Serialize for UpdateStatus {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut t = s.serialize_struct("UpdateStatus", 6)?;
+        t.serialize_field("state", &self.state)?;
+        t.serialize_field("received_bytes", &self.received_bytes)?;
+        t.serialize_field("current_version", &self.current_version)?;
+        t.serialize_field("last_error", &self.last_error)?;
+        if self.image_id != PRIMARY_IMAGE_ID || self.pacing.is_some() {
+            t.serialize_field("image_id", &self.image_id)?;
+        } else {
+            t.skip_field("image_id")?;
+        }
+        match &self.pacing {
+            Some(pacing) => t.serialize_field("pacing", pacing)?,
+            None => t.skip_field("pacing")?,
+        }
+        t.end()
+    }
+}
+
+/// The maximum size of an encoded [UpdateStatus]. This exceeds
+/// [crate::discovery::MIN_PAYLOAD_SIZE] and so packets conveying it must
+/// be sized accordingly.
+pub const MAX_UPDATE_STATUS_SIZE: usize = 1
+    + MAX_U32_SIZE
+    + Version::POSTCARD_MAX_SIZE
+    + 1
+    + 1
+    + 2 * MAX_U32_SIZE
+    + 1
+    + 1
+    + 2 * MAX_U32_SIZE;
+
+/// How a server declares that it is to be paced when broadcasting an update
+/// to it, given its buffers and the time it takes to write them out e.g. to
+/// a flash memory page.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ServerPacing {
+    /// The largest number of bytes that the server accepts in an [Update].
+    pub chunk_size: u8,
+    /// The number of bytes that the server buffers before processing them,
+    /// or 0 if it processes each [Update] as it arrives. Updates must not
+    /// straddle a multiple of this.
+    pub threshold_bytes: u32,
+    /// The number of ticks that the server takes to process each threshold
+    /// of bytes, during which no more are to be sent.
+    pub processing_ticks: u32,
+}
+
+/// Why a server declines an update for one of its ports.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeclineReason {
+    /// The update is not of a later version than the one running.
+    NotNewer,
+    /// The update is of an earlier security epoch than the highest applied.
+    EpochRollback,
+    /// The update is for another hardware revision.
+    WrongHardwareRevision,
+    /// There is not enough storage to stage the update.
+    InsufficientStorage,
+    /// The port is served from the image being updated, and so cannot be
+    /// updated while it runs.
+    RunningImage,
+}
+
+/// Whether a server accepts an update for a port targeted by it.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PortEligibility {
+    pub port: Port,
+    /// Why the update is declined for the port, if it is.
+    pub decline: Option<DeclineReason>,
+}
+
+/// The maximum size of an encoded [EligibilityReport].
+pub const MAX_ELIGIBILITY_REPORT_SIZE: usize = 1 + 1 + 8 * (1 + 1 + 1);
+
+/// A server's reply to an [UpdateStatusRequest] asking for its eligibility
+/// for the update last prepared for, being whether it accepts the update for
+/// each of its ports targeted by it, in order. A server may accept an update
+/// for some ports and not others.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EligibilityReport {
+    /// The image that the update is for.
+    pub image_id: u8,
+    pub ports: Vec<PortEligibility, 8>,
+}
+
+struct ReceivingUpdate {
+    version: Version,
+    security_epoch: u16,
+    update_key: UpdateKey,
+    update_byte_len: u32,
+    integrity: UpdateIntegrity,
+    compression: Compression,
+    delta: Option<Delta>,
+    decoding: Option<Decoding>,
+    verifier: UpdateVerifier,
+    activate_at: u64,
+}
+
+// How the bytes of an update are decoded into its image, which requires them
+// to be received in order.
+// There is no allocator to box the decompressor with.
+#[allow(clippy::large_enum_variant)]
+enum Decoding {
+    Patch(delta::Patcher),
+    #[cfg(feature = "compression")]
+    Decompress(compression::Decompressor),
+}
+
+impl Decoding {
+    fn new(prepare_for_update: &PrepareForUpdate) -> Result<Option<Self>, VerificationError> {
+        match (&prepare_for_update.delta, prepare_for_update.compression) {
+            (None, Compression::None) => Ok(None),
+            (Some(delta), Compression::None) => {
+                if delta.patch_format == delta::COPY_INSERT_PATCH_FORMAT {
+                    Ok(Some(Decoding::Patch(delta::Patcher::new())))
+                } else {
+                    Err(VerificationError::UnsupportedPatch)
+                }
+            }
+            (Some(_), _) => Err(VerificationError::UnsupportedCompression),
+            #[cfg(feature = "compression")]
+            (None, compression) => compression::Decompressor::new(compression)
+                .map(|d| Some(Decoding::Decompress(d)))
+                .ok_or(VerificationError::UnsupportedCompression),
+            #[cfg(not(feature = "compression"))]
+            (None, _) => Err(VerificationError::UnsupportedCompression),
+        }
+    }
+
+    // Decode the bytes given, which follow on from the number of bytes decoded
+    // so far, storing and hashing those that are decoded. Returns the number
+    // of bytes then decoded. A patch is not applied without its base.
+    fn decode<F>(
+        &mut self,
+        bytes: &[u8],
+        base: Option<&mut dyn delta::ReadBase>,
+        decoded: u32,
+        update_byte_len: u32,
+        verifier: &mut UpdateVerifier,
+        store: &mut F,
+    ) -> Result<u32, VerificationError>
+    where
+        F: FnMut(u32, &[u8]),
+    {
+        // Bytes decode to any number of bytes, and so those decoded are
+        // carried until there are enough to store.
+        let mut carry = Vec::<u8, DECODED_CARRY_SIZE>::new();
+        let mut to = decoded;
+        let mut flush = |carry: &mut Vec<u8, DECODED_CARRY_SIZE>, to: u32| {
+            let from = to - carry.len() as u32;
+            store(from, carry);
+            // Cannot fail given that the bytes are in order and within the
+            // update.
+            let _ = verifier.update(from, carry);
+            carry.clear();
+        };
+        let mut emit = |b| {
+            if to == update_byte_len {
+                return false;
+            }
+            // Cannot fail given that the carry is flushed when full.
+            let _ = carry.push(b);
+            to += 1;
+            if carry.is_full() {
+                flush(&mut carry, to);
+            }
+            true
+        };
+        match self {
+            Decoding::Patch(patcher) => {
+                let Some(base) = base else {
+                    return Ok(decoded);
+                };
+                patcher
+                    .patch(bytes, base, &mut emit)
+                    .map_err(|_| VerificationError::BadPatch)?;
+            }
+            #[cfg(feature = "compression")]
+            Decoding::Decompress(decompressor) => decompressor
+                .decompress(bytes, &mut emit)
+                .map_err(|_| VerificationError::BadCompression)?,
+        }
+        if !carry.is_empty() {
+            flush(&mut carry, to);
+        }
+        Ok(to)
+    }
+}
+
+/// Whether a server is to accept an update given the version and security
+/// epoch it is running, and its hardware revision.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateEligibility {
+    /// The update is of a later version, and of an epoch no earlier than the
+    /// one running.
+    Eligible,
+    /// The update is not of a later version than the one running.
+    NotNewer,
+    /// The update is of an earlier security epoch than the highest applied,
+    /// and so would roll the server back to a vulnerable release.
+    EpochRollback,
+    /// The update is constrained to a hardware revision other than the
+    /// server's, or the server's revision is not known.
+    WrongHardwareRevision,
+}
+
+impl UpdateEligibility {
+    /// Check an update's eligibility. The hardware revision is checked ahead
+    /// of the security epoch, which is checked ahead of the version.
+    pub fn check(
+        prepare_for_update: &PrepareForUpdate,
+        current_version: &Version,
+        current_epoch: u16,
+        hardware_rev: Option<u8>,
+    ) -> Self {
+        if prepare_for_update
+            .hardware_rev
+            .is_some_and(|rev| Some(rev) != hardware_rev)
+        {
+            Self::WrongHardwareRevision
+        } else if prepare_for_update.security_epoch < current_epoch {
+            Self::EpochRollback
+        } else if prepare_for_update.version <= *current_version {
+            Self::NotNewer
+        } else {
+            Self::Eligible
+        }
+    }
+
+    /// Why a server declines the update for each of its ports, if it is not
+    /// eligible.
+    pub fn decline_reason(self) -> Option<DeclineReason> {
+        match self {
+            Self::Eligible => None,
+            Self::NotNewer => Some(DeclineReason::NotNewer),
+            Self::EpochRollback => Some(DeclineReason::EpochRollback),
+            Self::WrongHardwareRevision => Some(DeclineReason::WrongHardwareRevision),
+        }
+    }
+}
+
+/// The outcome of preparing for an update.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Preparation {
+    /// The update starts from its first byte.
+    Started,
+    /// The update being received is resumed from the byte offset given, being
+    /// the number of bytes received so far.
+    Resumed { byte_offset: u32 },
+    /// The update is not of a later version than the one running.
+    NotNewer,
+    /// The update is of the version being received, but its key, length or
+    /// integrity differ, and so it cannot be resumed.
+    Mismatched,
+    /// The update key is that of an update that has been aborted.
+    Aborted,
+    /// The update cannot be verified e.g. it is signed with a key that is not
+    /// trusted, and so it has failed.
+    Unverifiable(VerificationError),
+    /// The update is compressed in a way that is not supported, and so it has
+    /// failed.
+    UnsupportedCompression,
+    /// The update is a patch in a format that is not supported, and so it
+    /// has failed.
+    UnsupportedPatch,
+    /// The update is a patch of a version other than the one running.
+    BaseMismatch,
+    /// The update is of an image other than those being received.
+    OtherImage,
+    /// The update key is that of an update of another image.
+    KeyInUse,
+    /// The update is of an earlier security epoch than the highest applied.
+    EpochRollback,
+    /// The update is for another hardware revision.
+    WrongHardwareRevision,
+    /// The update was asked about without being prepared for, given its
+    /// eligibility. Any update in progress is unaffected.
+    DryRun(UpdateEligibility),
+}
+
+/// Problems committing or rolling back a staged update.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommitError {
+    /// No update has been staged.
+    NotStaged,
+    /// The version is not that of the update staged.
+    VersionMismatch,
+    /// The image is not that of the update staged.
+    ImageMismatch,
+}
+
+// The number of gaps in the bytes received that are tracked. Bytes that would
+// leave more gaps are ignored.
+const MAX_GAPS: usize = 16;
+
+// The number of staged bytes read at a time when verifying them.
+const VERIFY_READ_SIZE: usize = 64;
+
+// The number of decoded bytes carried before being stored.
+const DECODED_CARRY_SIZE: usize = 64;
+
+/// Stores the bytes of an update as they are received e.g. to a flash memory
+/// partition, for servers that have their [UpdateReceiver] handle each
+/// datagram of an update.
+pub trait StagingWriter {
+    /// Write the bytes received at the offset given. Bytes may be written in
+    /// any order, other than for compressed and delta updates.
+    fn write(&mut self, offset: u32, bytes: &[u8]);
+    /// Persist the bytes written so far e.g. having buffered them so as to
+    /// write flash memory a page at a time. Called each time the bytes
+    /// received reach the threshold of the server's [ServerPacing].
+    fn flush(&mut self);
+    /// Persist the remainder of the bytes written, all of the update having
+    /// been received. Called before the update is verified.
+    fn finalize(&mut self);
+    /// Fill the buffer with the bytes written at the offset given, so that
+    /// those received out of order are able to be verified.
+    fn read(&mut self, offset: u32, buf: &mut [u8]);
+}
+
+/// What became of a datagram handled by an [UpdateReceiver].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateEvent<const N: usize> {
+    /// The datagram is not of the update in progress, or the update's bytes
+    /// are no longer being received.
+    Ignored,
+    /// Bytes of the update were received, with the number of bytes received
+    /// in order so far.
+    Progress { received_bytes: u32 },
+    /// Bytes of the update were received that reach the threshold of the
+    /// server's [ServerPacing], and so have been flushed. The server may take
+    /// up to its processing time before handling the next datagram.
+    Flushed { received_bytes: u32 },
+    /// All of the update has been received and verified, and is staged until
+    /// committed.
+    Staged,
+    /// The update has been abandoned given the error.
+    Abandoned(VerificationError),
+    /// Another message of the update in progress e.g. its commit, which the
+    /// server is to pass to its receiver.
+    Message(UpdateMessage<N>),
+}
+
+/// Tracks an update on behalf of a server from its preparation through to it
+/// being applied, so that the server is able to reply to an
+/// [UpdateStatusRequest]. The server may either decrypt each [Update] with
+/// the update key and store its bytes itself, or have the receiver handle
+/// each datagram and store its bytes with a [StagingWriter]. A receiver
+/// receives the updates of one image, see [MultiImageReceiver] for servers
+/// with more than one.
+pub struct UpdateReceiver {
+    image_id: u8,
+    current_version: Version,
+    security_epoch: u16,
+    pacing: Option<ServerPacing>,
+    hardware_rev: Option<u8>,
+    // The ports targeted by the update last prepared for, and its eligibility.
+    prepared: Option<(PortSet, UpdateEligibility)>,
+    update: Option<ReceivingUpdate>,
+    // The digest of the key of the update last aborted, so that the key is
+    // refused without being retained.
+    aborted_key_digest: Option<[u8; DIGEST_SIZE]>,
+    state: UpdateState,
+    // The end of the furthest bytes received, and the ranges of bytes before
+    // it yet to be received, in order.
+    received_to: u32,
+    gaps: Vec<(u32, u32), MAX_GAPS>,
+    last_error: Option<VerificationError>,
+}
+
+impl UpdateReceiver {
+    /// A receiver for a server running a given version of its primary image.
+    pub fn new(current_version: Version) -> Self {
+        Self::for_image(PRIMARY_IMAGE_ID, current_version)
+    }
+
+    /// A receiver for an image of a server running a given version.
+    pub fn for_image(image_id: u8, current_version: Version) -> Self {
+        Self {
+            image_id,
+            current_version,
+            security_epoch: 0,
+            pacing: None,
+            hardware_rev: None,
+            prepared: None,
+            update: None,
+            aborted_key_digest: None,
+            state: UpdateState::Idle,
+            received_to: 0,
+            gaps: Vec::new(),
+            last_error: None,
+        }
+    }
+
+    /// The receiver with the highest security epoch applied, as persisted when
+    /// last applying an update. Receivers otherwise start from an epoch of 0.
+    pub fn with_security_epoch(mut self, security_epoch: u16) -> Self {
+        self.security_epoch = security_epoch;
+        self
+    }
+
+    /// The receiver declaring how the server is to be paced in its status.
+    pub fn with_pacing(mut self, pacing: ServerPacing) -> Self {
+        self.pacing = Some(pacing);
+        self
+    }
+
+    /// The receiver of a server of the hardware revision given, so that
+    /// updates constrained to another revision are declined.
+    pub fn with_hardware_rev(mut self, hardware_rev: u8) -> Self {
+        self.hardware_rev = Some(hardware_rev);
+        self
+    }
+
+    /// Prepare for an update, verifying it with the verifier given. The update
+    /// is accepted only if it is eligible as per [UpdateEligibility], in
+    /// which case any other update in progress is replaced. Preparing for the
+    /// update already in progress resumes it, retaining the bytes received so
+    /// far, provided that it is otherwise identical. A delta update is
+    /// declined unless it patches the version running. A dry run is never
+    /// prepared for, only its eligibility being recorded for reporting.
+    pub fn prepare(
+        &mut self,
+        prepare_for_update: &PrepareForUpdate,
+        verifier: UpdateVerifier,
+    ) -> Preparation {
+        if prepare_for_update.image_id != self.image_id {
+            return Preparation::OtherImage;
+        }
+        let eligibility = UpdateEligibility::check(
+            prepare_for_update,
+            &self.current_version,
+            self.security_epoch,
+            self.hardware_rev,
+        );
+        self.prepared = Some((prepare_for_update.server_ports, eligibility));
+        if prepare_for_update.dry_run {
+            return Preparation::DryRun(eligibility);
+        }
+        match eligibility {
+            UpdateEligibility::Eligible => {}
+            UpdateEligibility::NotNewer => return Preparation::NotNewer,
+            UpdateEligibility::EpochRollback => return Preparation::EpochRollback,
+            UpdateEligibility::WrongHardwareRevision => return Preparation::WrongHardwareRevision,
+        }
+        if self
+            .aborted_key_digest
+            .is_some_and(|d| d == update_digest(&prepare_for_update.update_key.0))
+        {
+            return Preparation::Aborted;
+        }
+        if let Some(delta) = &prepare_for_update.delta {
+            if delta.base_version != self.current_version {
+                return Preparation::BaseMismatch;
+            }
+        }
+        if let Some(receiving) = &self.update {
+            if receiving.version == prepare_for_update.version {
+                return if receiving.update_key == prepare_for_update.update_key
+                    && receiving.security_epoch == prepare_for_update.security_epoch
+                    && receiving.update_byte_len == prepare_for_update.update_byte_len
+                    && receiving.integrity == prepare_for_update.integrity
+                    && receiving.compression == prepare_for_update.compression
+                    && receiving.delta == prepare_for_update.delta
+                {
+                    Preparation::Resumed {
+                        byte_offset: self.received_bytes(),
+                    }
+                } else {
+                    Preparation::Mismatched
+                };
+            }
+        }
+        if let VerifierState::Failed(e) = verifier.state() {
+            self.fail(e);
+            return Preparation::Unverifiable(e);
+        }
+        let decoding = match Decoding::new(prepare_for_update) {
+            Ok(decoding) => decoding,
+            Err(e) => {
+                self.fail(e);
+                return if e == VerificationError::UnsupportedPatch {
+                    Preparation::UnsupportedPatch
+                } else {
+                    Preparation::UnsupportedCompression
+                };
+            }
+        };
+        self.update = Some(ReceivingUpdate {
+            version: prepare_for_update.version.clone(),
+            security_epoch: prepare_for_update.security_epoch,
+            update_key: prepare_for_update.update_key.clone(),
+            update_byte_len: prepare_for_update.update_byte_len,
+            integrity: prepare_for_update.integrity,
+            compression: prepare_for_update.compression,
+            delta: prepare_for_update.delta.clone(),
+            decoding,
+            verifier,
+            activate_at: 0,
+        });
+        self.received_to = 0;
+        self.gaps.clear();
+        self.last_error = None;
+        self.state = if prepare_for_update.update_byte_len == 0 {
+            UpdateState::Verifying
+        } else {
+            UpdateState::Receiving
+        };
+        Preparation::Started
+    }
+
+    /// The key to decrypt each [UpdateMessage] with while an update is in
+    /// progress.
+    pub fn update_key(&self) -> Option<&UpdateKey> {
+        self.update.as_ref().map(|u| &u.update_key)
+    }
+
+    /// Receive the bytes of an update, returning the resulting state. Bytes
+    /// may be received in any order, with those not yet received being passed
+    /// to the function given to store along with their offset. Bytes already
+    /// received are ignored, as are those that would leave more gaps in the
+    /// update than can be tracked. Once all of the update is received, it is
+    /// to be verified.
+    ///
+    /// The bytes of a compressed update are decompressed before being
+    /// stored, and so must be received in order. Bytes that do not follow on
+    /// from those already received are therefore ignored. Delta updates are
+    /// received with `receive_delta`.
+    pub fn receive<const N: usize, F>(&mut self, update: &Update<N>, store: F) -> UpdateState
+    where
+        F: FnMut(u32, &[u8]),
+    {
+        self.receive_with(update, None, store)
+    }
+
+    /// Receive the bytes of a delta update, returning the resulting state. The
+    /// bytes are a patch of the image running, which is read from the base
+    /// given, and so must be received in order as per `receive`. The bytes of
+    /// the patched image are passed to the function given to store.
+    pub fn receive_delta<const N: usize, B, F>(
+        &mut self,
+        update: &Update<N>,
+        base: &mut B,
+        store: F,
+    ) -> UpdateState
+    where
+        B: delta::ReadBase,
+        F: FnMut(u32, &[u8]),
+    {
+        self.receive_with(update, Some(base), store)
+    }
+
+    /// Handle a datagram broadcast by the client while an update is in
+    /// progress, decrypting it with a cipher of type `C` under the update key.
+    /// The bytes of an [Update] are received as per `receive` and written to
+    /// the staging writer given, which is flushed at each threshold of the
+    /// server's [ServerPacing] and finalized once all of the update is
+    /// received, whereupon the update is verified. Delta updates are handled
+    /// with `handle_delta_datagram`.
+    pub fn handle_datagram<C, const N: usize, const P: usize>(
+        &mut self,
+        datagram: &[u8; P],
+        writer: &mut impl StagingWriter,
+    ) -> UpdateEvent<N>
+    where
+        C: AeadInPlace + KeyInit,
+        C::NonceSize: NonceScheme,
+    {
+        self.handle_datagram_with::<C, N, P>(datagram, None, writer)
+    }
+
+    /// Handle a datagram of a delta update as per `handle_datagram`, reading
+    /// the image running from the base given as per `receive_delta`.
+    pub fn handle_delta_datagram<C, const N: usize, const P: usize>(
+        &mut self,
+        datagram: &[u8; P],
+        base: &mut impl delta::ReadBase,
+        writer: &mut impl StagingWriter,
+    ) -> UpdateEvent<N>
+    where
+        C: AeadInPlace + KeyInit,
+        C::NonceSize: NonceScheme,
+    {
+        self.handle_datagram_with::<C, N, P>(datagram, Some(base), writer)
+    }
+
+    fn handle_datagram_with<C, const N: usize, const P: usize>(
+        &mut self,
+        datagram: &[u8; P],
+        base: Option<&mut dyn delta::ReadBase>,
+        writer: &mut dyn StagingWriter,
+    ) -> UpdateEvent<N>
+    where
+        C: AeadInPlace + KeyInit,
+        C::NonceSize: NonceScheme,
+    {
+        let Some(message) = self.update_key().and_then(|update_key| {
+            let cipher = C::new_from_slice(&update_key.0).ok()?;
+            let (_, payload) =
+                from_update_datagram(datagram, |h| h.source == DataSource::Client, &cipher).ok()?;
+            postcard::from_bytes::<UpdateMessage<N>>(&payload).ok()
+        }) else {
+            return UpdateEvent::Ignored;
+        };
+        let UpdateMessage::Update(update) = message else {
+            return UpdateEvent::Message(message);
+        };
+        if self.state != UpdateState::Receiving {
+            return UpdateEvent::Ignored;
+        }
+
+        let received_before = self.received_bytes();
+        match self.receive_with(&update, base, |offset, bytes| writer.write(offset, bytes)) {
+            UpdateState::Receiving => {
+                let received_bytes = self.received_bytes();
+                let threshold = self.pacing.map_or(0, |p| p.threshold_bytes);
+                if received_bytes.checked_div(threshold) > received_before.checked_div(threshold) {
+                    writer.flush();
+                    UpdateEvent::Flushed { received_bytes }
+                } else {
+                    UpdateEvent::Progress { received_bytes }
+                }
+            }
+            UpdateState::Verifying => {
+                writer.finalize();
+                match self.verify(|offset, buf| writer.read(offset, buf)) {
+                    UpdateState::Staged => UpdateEvent::Staged,
+                    _ => self
+                        .last_error
+                        .map_or(UpdateEvent::Ignored, UpdateEvent::Abandoned),
+                }
+            }
+            _ => self
+                .last_error
+                .map_or(UpdateEvent::Ignored, UpdateEvent::Abandoned),
+        }
+    }
+
+    fn receive_with<const N: usize, F>(
+        &mut self,
+        update: &Update<N>,
+        base: Option<&mut dyn delta::ReadBase>,
+        mut store: F,
+    ) -> UpdateState
+    where
+        F: FnMut(u32, &[u8]),
+    {
+        let Some(receiving) = self.update.as_mut() else {
+            return self.state;
+        };
+        if self.state != UpdateState::Receiving {
+            return self.state;
+        }
+        if let Some(decoding) = receiving.decoding.as_mut() {
+            if update.byte_offset != self.received_to {
+                return self.state;
+            }
+            match decoding.decode(
+                &update.bytes,
+                base,
+                self.received_to,
+                receiving.update_byte_len,
+                &mut receiving.verifier,
+                &mut store,
+            ) {
+                Ok(decoded) => {
+                    self.received_to = decoded;
+                    if decoded == receiving.update_byte_len {
+                        self.state = UpdateState::Verifying;
+                    }
+                }
+                Err(e) => self.fail(e),
+            }
+            return self.state;
+        }
+        let start = update.byte_offset;
+        let end = start as u64 + update.bytes.len() as u64;
+        if end > receiving.update_byte_len as u64 {
+            let e = VerificationError::UnexpectedOffset {
+                expected: self.received_to,
+                received: start,
+            };
+            self.fail(e);
+            return self.state;
+        }
+        let end = end as u32;
+
+        let mut accept = |from: u32, to: u32| {
+            let bytes = &update.bytes[(from - start) as usize..(to - start) as usize];
+            store(from, bytes);
+            // Bytes are hashed as they are received while in order, leaving any
+            // others to be read back when verifying.
+            if from == receiving.verifier.next_byte_offset {
+                // Cannot fail given that the bytes are in order and within the
+                // update.
+                let _ = receiving.verifier.update(from, bytes);
+            }
+        };
+
+        let mut i = 0;
+        while i < self.gaps.len() {
+            let (gap_start, gap_end) = self.gaps[i];
+            let (from, to) = (start.max(gap_start), end.min(gap_end));
+            if from >= to {
+                i += 1;
+                continue;
+            }
+            if from > gap_start && to < gap_end {
+                if self.gaps.insert(i + 1, (to, gap_end)).is_err() {
+                    i += 1;
+                    continue;
+                }
+                self.gaps[i].1 = from;
+                i += 2;
+            } else if from > gap_start {
+                self.gaps[i].1 = from;
+                i += 1;
+            } else if to < gap_end {
+                self.gaps[i].0 = to;
+                i += 1;
+            } else {
+                self.gaps.remove(i);
+            }
+            accept(from, to);
+        }
+
+        if end > self.received_to {
+            let from = start.max(self.received_to);
+            if from == self.received_to || self.gaps.push((self.received_to, from)).is_ok() {
+                accept(from, end);
+                self.received_to = end;
+            }
+        }
+
+        if self.gaps.is_empty() && self.received_to == receiving.update_byte_len {
+            self.state = UpdateState::Verifying;
+        }
+        self.state
+    }
+
+    /// The ranges of the update yet to be received, being up to the number
+    /// given.
+    pub fn missing_ranges(&self, max_ranges: usize) -> MissingRanges {
+        let mut missing = MissingRanges::default();
+        let Some(receiving) = self.update.as_ref() else {
+            return missing;
+        };
+        if self.state != UpdateState::Receiving {
+            return missing;
+        }
+        let tail = (self.received_to < receiving.update_byte_len)
+            .then_some((self.received_to, receiving.update_byte_len));
+        for (start, end) in self
+            .gaps
+            .iter()
+            .copied()
+            .chain(tail)
+            .take(max_ranges.min(MAX_MISSING_RANGES))
+        {
+            let _ = missing.ranges.push((start, end - start));
+        }
+        missing
+    }
+
+    /// Verify an update having received it in full e.g. once its final
+    /// bytes have been stored, returning the resulting state. Those bytes
+    /// received out of order are read back by the function given, which is
+    /// to fill the buffer with the bytes stored at the offset. A verified
+    /// update is staged until committed.
+    pub fn verify<F>(&mut self, mut read_staged: F) -> UpdateState
+    where
+        F: FnMut(u32, &mut [u8]),
+    {
+        if self.state != UpdateState::Verifying {
+            return self.state;
+        }
+        if let Some(receiving) = self.update.as_mut() {
+            let verifier = &mut receiving.verifier;
+            let mut buf = [0; VERIFY_READ_SIZE];
+            let mut verified = Ok(());
+            while verified.is_ok() && verifier.next_byte_offset < receiving.update_byte_len {
+                let offset = verifier.next_byte_offset;
+                let len = ((receiving.update_byte_len - offset) as usize).min(VERIFY_READ_SIZE);
+                read_staged(offset, &mut buf[..len]);
+                verified = verifier.update(offset, &buf[..len]);
+            }
+            match verified.and_then(|_| verifier.verify()) {
+                Ok(()) => self.state = UpdateState::Staged,
+                Err(e) => self.fail(e),
+            }
+        }
+        self.state
+    }
+
+    /// Commit the staged update, to be applied once its activation delay has
+    /// elapsed from now. A commit that is repeated is tolerated, with the
+    /// update being activated according to the first.
+    pub fn commit(&mut self, commit: &UpdateCommit, now: u64) -> Result<(), CommitError> {
+        let receiving = self
+            .update
+            .as_mut()
+            .filter(|_| matches!(self.state, UpdateState::Staged | UpdateState::Committing))
+            .ok_or(CommitError::NotStaged)?;
+        if commit.image_id != self.image_id {
+            return Err(CommitError::ImageMismatch);
+        }
+        if receiving.version != commit.version {
+            return Err(CommitError::VersionMismatch);
+        }
+        if self.state == UpdateState::Staged {
+            receiving.activate_at = now.saturating_add(commit.activate_delay_ticks as u64);
+            self.state = UpdateState::Committing;
+        }
+        Ok(())
+    }
+
+    /// Discard the staged update, including one that is committed but not yet
+    /// activated. The bytes staged may be erased by the function given, which
+    /// is passed the number staged. The update is then as if aborted.
+    pub fn rollback_staged<F>(
+        &mut self,
+        rollback: &UpdateRollbackStaged,
+        erase_staged: F,
+    ) -> Result<(), CommitError>
+    where
+        F: FnOnce(u32),
+    {
+        let receiving = self
+            .update
+            .as_ref()
+            .filter(|_| matches!(self.state, UpdateState::Staged | UpdateState::Committing))
+            .ok_or(CommitError::NotStaged)?;
+        if rollback.image_id != self.image_id {
+            return Err(CommitError::ImageMismatch);
+        }
+        if receiving.version != rollback.version {
+            return Err(CommitError::VersionMismatch);
+        }
+        self.abort(erase_staged);
+        Ok(())
+    }
+
+    /// Activate a committed update once its activation delay has elapsed,
+    /// returning the resulting state. The update is to be applied once the
+    /// state is [UpdateState::ReadyToApply].
+    pub fn poll_activation(&mut self, now: u64) -> UpdateState {
+        if let Some(receiving) = &self.update {
+            if self.state == UpdateState::Committing && now >= receiving.activate_at {
+                self.state = UpdateState::ReadyToApply;
+            }
+        }
+        self.state
+    }
+
+    /// The time at which a committed update is to be activated, if any.
+    pub fn activate_at(&self) -> Option<u64> {
+        self.update
+            .as_ref()
+            .filter(|_| self.state == UpdateState::Committing)
+            .map(|u| u.activate_at)
+    }
+
+    /// Note that an activated update has been applied, in which case its version
+    /// becomes the one running. Returns the resulting state. Should the update
+    /// raise the security epoch, the epoch is passed to the function given to
+    /// persist so that the receiver is able to be created with it once the
+    /// server restarts.
+    pub fn applied<F>(&mut self, persist_epoch: F) -> UpdateState
+    where
+        F: FnOnce(u16),
+    {
+        if self.state == UpdateState::ReadyToApply {
+            if let Some(receiving) = self.update.take() {
+                self.current_version = receiving.version;
+                if receiving.security_epoch > self.security_epoch {
+                    self.security_epoch = receiving.security_epoch;
+                    persist_epoch(self.security_epoch);
+                }
+                self.state = UpdateState::Applied;
+            }
+        }
+        self.state
+    }
+
+    /// Abort the update in progress, if any, returning true if there was one.
+    /// Any bytes of the update that have been received may be erased by the
+    /// function given, which is passed the number received. The update key is
+    /// no longer accepted and so another update must be prepared for.
+    pub fn abort<F>(&mut self, erase_received: F) -> bool
+    where
+        F: FnOnce(u32),
+    {
+        let Some(aborted) = self.update.take() else {
+            return false;
+        };
+        // The key is zeroed as the update is dropped, given the `zeroize`
+        // feature.
+        self.aborted_key_digest = Some(update_digest(&aborted.update_key.0));
+        if self.received_to > 0 {
+            erase_received(self.received_to);
+        }
+        self.state = UpdateState::Aborted;
+        true
+    }
+
+    /// Where the server is within an update.
+    pub fn state(&self) -> UpdateState {
+        self.state
+    }
+
+    /// The image that the receiver receives the updates of.
+    pub fn image_id(&self) -> u8 {
+        self.image_id
+    }
+
+    /// The version the server is running.
+    pub fn current_version(&self) -> &Version {
+        &self.current_version
+    }
+
+    /// The highest security epoch applied.
+    pub fn security_epoch(&self) -> u16 {
+        self.security_epoch
+    }
+
+    /// The reply to an [UpdateStatusRequest] asking for the server's
+    /// eligibility for the update last prepared for, being for each of the
+    /// server's ports given that the update targets. Each port is declined if
+    /// the update is not eligible, or otherwise for the reason returned by the
+    /// function given, if any e.g. there being insufficient storage.
+    pub fn eligibility<F>(&self, server_ports: PortSet, mut decline: F) -> EligibilityReport
+    where
+        F: FnMut(Port) -> Option<DeclineReason>,
+    {
+        let mut report = EligibilityReport {
+            image_id: self.image_id,
+            ports: Vec::new(),
+        };
+        if let Some((targeted, eligibility)) = self.prepared {
+            for port in targeted.intersection(server_ports).iter() {
+                // Cannot fail given that there are 8 ports at most.
+                let _ = report.ports.push(PortEligibility {
+                    port,
+                    decline: eligibility.decline_reason().or_else(|| decline(port)),
+                });
+            }
+        }
+        report
+    }
+
+    /// The reply to an [UpdateStatusRequest].
+    pub fn status(&self) -> UpdateStatus {
+        UpdateStatus {
+            state: self.state,
+            received_bytes: self.received_bytes(),
+            current_version: self.current_version.clone(),
+            last_error: self.last_error,
+            image_id: self.image_id,
+            pacing: self.pacing,
+        }
+    }
+
+    fn received_bytes(&self) -> u32 {
+        self.gaps
+            .first()
+            .map_or(self.received_to, |(gap_start, _)| *gap_start)
+    }
+
+    fn fail(&mut self, e: VerificationError) {
+        // The update key is forgotten so that no more of the update is received.
+        self.update = None;
+        self.state = UpdateState::Failed;
+        self.last_error = Some(e);
+    }
+}
+
+/// Tracks the updates of each image of a server with more than one e.g. its
+/// application and the firmware of a co-processor, each image having a
+/// receiver of its own. Updates of different images may be in progress at the
+/// same time, each under its own update key. Up to `I` images may be tracked.
+pub struct MultiImageReceiver<const I: usize = 4> {
+    receivers: Vec<UpdateReceiver, I>,
+}
+
+impl<const I: usize> Default for MultiImageReceiver<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const I: usize> MultiImageReceiver<I> {
+    /// A receiver without any images.
+    pub fn new() -> Self {
+        Self {
+            receivers: Vec::new(),
+        }
+    }
+
+    /// Add the receiver of an image e.g. one created with
+    /// [UpdateReceiver::for_image]. Returns false if the image has already
+    /// been added or there are already `I` images.
+    pub fn add_image(&mut self, receiver: UpdateReceiver) -> bool {
+        self.image(receiver.image_id).is_none() && self.receivers.push(receiver).is_ok()
+    }
+
+    /// Prepare the image that an update is for, as per
+    /// [UpdateReceiver::prepare]. Updates of images that have not been added
+    /// are declined, as are those with the key of an update of another image
+    /// in progress given that messages are associated with an image by their
+    /// key.
+    pub fn prepare(
+        &mut self,
+        prepare_for_update: &PrepareForUpdate,
+        verifier: UpdateVerifier,
+    ) -> Preparation {
+        if self.update_keys().any(|(image_id, update_key)| {
+            image_id != prepare_for_update.image_id && *update_key == prepare_for_update.update_key
+        }) {
+            return Preparation::KeyInUse;
+        }
+        match self.image_mut(prepare_for_update.image_id) {
+            Some(receiver) => receiver.prepare(prepare_for_update, verifier),
+            None => Preparation::OtherImage,
+        }
+    }
+
+    /// The receiver of an image, if it has been added.
+    pub fn image(&self, image_id: u8) -> Option<&UpdateReceiver> {
+        self.receivers.iter().find(|r| r.image_id == image_id)
+    }
+
+    /// The receiver of an image, if it has been added.
+    pub fn image_mut(&mut self, image_id: u8) -> Option<&mut UpdateReceiver> {
+        self.receivers.iter_mut().find(|r| r.image_id == image_id)
+    }
+
+    /// The receivers of each image, in the order added.
+    pub fn images(&self) -> impl Iterator<Item = &UpdateReceiver> {
+        self.receivers.iter()
+    }
+
+    /// The key of each image's update in progress. An [UpdateMessage] is for
+    /// the image whose key it decrypts with.
+    pub fn update_keys(&self) -> impl Iterator<Item = (u8, &UpdateKey)> {
+        self.receivers
+            .iter()
+            .filter_map(|r| r.update_key().map(|k| (r.image_id, k)))
+    }
+}
+
+/// The outcome of an update for a server, as reported by its [UpdateStatus].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateOutcome {
+    /// The server is running the version updated to.
+    Updated,
+    /// The update is still in progress.
+    InProgress(UpdateState),
+    /// The update failed.
+    Failed(Option<VerificationError>),
+    /// The update was aborted.
+    Aborted,
+    /// The server did not take the update e.g. because it missed being
+    /// prepared for it.
+    NotUpdated,
+    /// The server did not reply.
+    Unresponsive,
+}
+
+/// A server's status following an update.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ServerUpdateReport {
+    pub server_address: u8,
+    /// The server's reply, if any.
+    pub status: Option<UpdateStatus>,
+    pub outcome: UpdateOutcome,
+}
+
+/// Polls each of the servers prepared for an update once the update has been
+/// broadcast, producing a report of their outcomes. Up to `S` servers may be
+/// polled.
+pub struct UpdateStatusPoller<const S: usize = 8> {
+    image_id: u8,
+    version: Version,
+    reports: Vec<ServerUpdateReport, S>,
+    next_server: usize,
+}
+
+impl<const S: usize> UpdateStatusPoller<S> {
+    /// Poll the servers given for their outcome of updating their primary
+    /// image to a version. Returns None if there are more than `S` servers.
+    pub fn new(version: Version, server_addresses: &[u8]) -> Option<Self> {
+        Self::for_image(PRIMARY_IMAGE_ID, version, server_addresses)
+    }
+
+    /// Poll the servers given for their outcome of updating an image to a
+    /// version. Returns None if there are more than `S` servers.
+    pub fn for_image(image_id: u8, version: Version, server_addresses: &[u8]) -> Option<Self> {
+        let mut reports = Vec::new();
+        for server_address in server_addresses {
+            reports
+                .push(ServerUpdateReport {
+                    server_address: *server_address,
+                    status: None,
+                    outcome: UpdateOutcome::Unresponsive,
+                })
+                .ok()?;
+        }
+        Some(Self {
+            image_id,
+            version,
+            reports,
+            next_server: 0,
+        })
+    }
+
+    /// The next server to send an [UpdateStatusRequest] to, if any. A reply
+    /// should be awaited before polling the next server.
+    pub fn poll_transmit(&mut self) -> Option<(u8, UpdateStatusRequest)> {
+        let report = self.reports.get(self.next_server)?;
+        self.next_server += 1;
+        Some((
+            report.server_address,
+            UpdateStatusRequest {
+                image_id: self.image_id,
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// Handle a server's reply. Replies from servers not being polled, or of
+    /// another image, are ignored.
+    pub fn handle_reply(&mut self, server_address: u8, status: UpdateStatus) {
+        let Some(report) = self
+            .reports
+            .iter_mut()
+            .find(|r| r.server_address == server_address)
+            .filter(|_| status.image_id == self.image_id)
+        else {
+            return;
+        };
+        report.outcome = match status.state {
+            _ if status.current_version == self.version => UpdateOutcome::Updated,
+            UpdateState::Receiving
+            | UpdateState::Verifying
+            | UpdateState::Staged
+            | UpdateState::Committing
+            | UpdateState::ReadyToApply => UpdateOutcome::InProgress(status.state),
+            UpdateState::Failed => UpdateOutcome::Failed(status.last_error),
+            UpdateState::Aborted => UpdateOutcome::Aborted,
+            UpdateState::Idle | UpdateState::Applied => UpdateOutcome::NotUpdated,
+        };
+        report.status = Some(status);
+    }
+
+    /// The byte offset from which the update should be resumed so that each
+    /// server still receiving it is able to continue, if any are.
+    pub fn resume_from(&self) -> Option<u32> {
+        self.reports
+            .iter()
+            .filter(|r| r.outcome == UpdateOutcome::InProgress(UpdateState::Receiving))
+            .filter_map(|r| r.status.as_ref().map(|s| s.received_bytes))
+            .min()
+    }
+
+    /// True once all servers have been polled.
+    pub fn is_complete(&self) -> bool {
+        self.next_server >= self.reports.len()
+    }
+
+    /// The report of each server polled, in the order given.
+    pub fn report(&self) -> &[ServerUpdateReport] {
+        &self.reports
+    }
+}
+
+/// Tallies the [EligibilityReport]s of the servers targeted by an update, so
+/// as to decide whether to proceed with broadcasting it. Servers accepting the
+/// update for some of their ports and not others are partially accepting.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EligibilityTally {
+    quorum_percent: u8,
+    count_partial: bool,
+    accepting: usize,
+    partial: usize,
+    declining: usize,
+}
+
+impl EligibilityTally {
+    /// A tally requiring the percentage given of the servers targeted to
+    /// accept the update for all of their ports targeted.
+    pub fn new(quorum_percent: u8) -> Self {
+        Self {
+            quorum_percent: quorum_percent.min(100),
+            ..Default::default()
+        }
+    }
+
+    /// The tally counting partially accepting servers towards the quorum.
+    pub fn counting_partial(mut self) -> Self {
+        self.count_partial = true;
+        self
+    }
+
+    /// Add a server's report. Servers without any ports targeted decline.
+    pub fn add(&mut self, report: &EligibilityReport) {
+        let accepted = report.ports.iter().filter(|p| p.decline.is_none()).count();
+        if accepted == 0 {
+            self.declining += 1;
+        } else if accepted < report.ports.len() {
+            self.partial += 1;
+        } else {
+            self.accepting += 1;
+        }
+    }
+
+    /// The number of servers accepting the update for all of their ports
+    /// targeted.
+    pub fn accepting(&self) -> usize {
+        self.accepting
+    }
+
+    /// The number of servers accepting the update for some of their ports
+    /// targeted.
+    pub fn partial(&self) -> usize {
+        self.partial
+    }
+
+    /// The number of servers declining the update for all of their ports
+    /// targeted.
+    pub fn declining(&self) -> usize {
+        self.declining
+    }
+
+    /// Whether to proceed with broadcasting the update given the number of
+    /// servers targeted, those not having reported being taken as declining.
+    pub fn proceed(&self, servers_targeted: usize) -> bool {
+        let accepting = if self.count_partial {
+            self.accepting + self.partial
+        } else {
+            self.accepting
+        };
+        accepting > 0 && accepting * 100 >= servers_targeted * self.quorum_percent as usize
+    }
+}
+
+/// Surveys each of the servers asked about an update with a dry run of
+/// [PrepareForUpdate], so that a rollout may be gated on their
+/// [EligibilityReport]s before any server is prepared for it. Up to `S`
+/// servers may be surveyed.
+pub struct EligibilitySurvey<const S: usize = 8> {
+    image_id: u8,
+    reports: Vec<(u8, Option<EligibilityReport>), S>,
+    next_server: usize,
+}
+
+impl<const S: usize> EligibilitySurvey<S> {
+    /// Survey the servers given for their eligibility for an update of an
+    /// image. Returns None if there are more than `S` servers.
+    pub fn new(image_id: u8, server_addresses: &[u8]) -> Option<Self> {
+        let mut reports = Vec::new();
+        for server_address in server_addresses {
+            reports.push((*server_address, None)).ok()?;
+        }
+        Some(Self {
+            image_id,
+            reports,
+            next_server: 0,
+        })
+    }
+
+    /// The next server to send an [UpdateStatusRequest] to, if any. A reply
+    /// should be awaited before surveying the next server.
+    pub fn poll_transmit(&mut self) -> Option<(u8, UpdateStatusRequest)> {
+        let (server_address, _) = self.reports.get(self.next_server)?;
+        self.next_server += 1;
+        Some((
+            *server_address,
+            UpdateStatusRequest {
+                image_id: self.image_id,
+                eligibility: true,
+                ..Default::default()
+            },
+        ))
+    }
+
+    /// Handle a server's reply. Replies from servers not being surveyed, or
+    /// of another image, are ignored.
+    pub fn handle_reply(&mut self, server_address: u8, report: EligibilityReport) {
+        if let Some((_, r)) = self
+            .reports
+            .iter_mut()
+            .find(|(a, _)| *a == server_address)
+            .filter(|_| report.image_id == self.image_id)
+        {
+            *r = Some(report);
+        }
+    }
+
+    /// True once all servers have been surveyed.
+    pub fn is_complete(&self) -> bool {
+        self.next_server >= self.reports.len()
+    }
+
+    /// The report of each server surveyed, in the order given, being None for
+    /// those that did not reply.
+    pub fn survey(&self) -> &[(u8, Option<EligibilityReport>)] {
+        &self.reports
+    }
+
+    /// Add the reports of the servers surveyed to the tally given, each
+    /// server surveyed being targeted.
+    pub fn tally(&self, mut tally: EligibilityTally) -> EligibilityTally {
+        for report in self.reports.iter().filter_map(|(_, r)| r.as_ref()) {
+            tally.add(report);
+        }
+        tally
+    }
+}
+
+/// Merges the [MissingRanges] of servers into the ranges of an update to
+/// broadcast again. Up to `M` ranges are planned, beyond which the ranges
+/// closest to one another are merged, broadcasting some bytes that no server
+/// is missing rather than leaving some out.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RetransmitPlan<const M: usize = 16> {
+    // The start and end of each range, in order.
+    ranges: Vec<(u32, u32), M>,
+}
+
+impl<const M: usize> RetransmitPlan<M> {
+    /// Nothing to broadcast again.
+    pub fn new() -> Self {
+        Self { ranges: Vec::new() }
+    }
+
+    /// Add the ranges that a server is missing.
+    pub fn add(&mut self, missing: &MissingRanges) {
+        for (offset, len) in &missing.ranges {
+            self.add_range(*offset, offset.saturating_add(*len));
+        }
+    }
+
+    fn add_range(&mut self, start: u32, end: u32) {
+        if start >= end || M == 0 {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        self.ranges.retain(|(s, e)| {
+            let overlaps = *s <= end && start <= *e;
+            if overlaps {
+                start = start.min(*s);
+                end = end.max(*e);
+            }
+            !overlaps
+        });
+        if self.ranges.is_full() {
+            match (0..self.ranges.len().saturating_sub(1))
+                .min_by_key(|i| self.ranges[i + 1].0 - self.ranges[*i].1)
+            {
+                Some(i) => {
+                    self.ranges[i].1 = self.ranges[i + 1].1;
+                    self.ranges.remove(i + 1);
+                }
+                None => {
+                    let (s, e) = self.ranges.pop().unwrap();
+                    start = start.min(s);
+                    end = end.max(e);
+                }
+            }
+            // Merging may have left a range overlapping the one being added.
+            return self.add_range(start, end);
+        }
+        let i = self
+            .ranges
+            .iter()
+            .position(|(s, _)| *s > start)
+            .unwrap_or(self.ranges.len());
+        let _ = self.ranges.insert(i, (start, end));
+    }
+
+    /// True if there is nothing to broadcast again.
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// The ranges to broadcast again, being the byte offset and length of each,
+    /// in order.
+    pub fn ranges(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.ranges.iter().map(|(s, e)| (*s, e - s))
+    }
+
+    /// The ranges to broadcast again divided into chunks of up to the size
+    /// given, being the byte offset and length of each, in order.
+    pub fn chunks(&self, chunk_size: u32) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let chunk_size = chunk_size.max(1);
+        self.ranges.iter().flat_map(move |(s, e)| {
+            (*s..*e)
+                .step_by(chunk_size as usize)
+                .map(move |offset| (offset, chunk_size.min(e - offset)))
+        })
+    }
+}
+
+/// How a client paces the broadcast of an update so that each of the servers
+/// receiving it keeps up. Ticks are those of the client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdatePacing {
+    /// The largest number of bytes sent in an [Update].
+    pub chunk_size: u8,
+    /// The number of bytes after which to pause for the servers to process
+    /// them, or 0 to pause after each [Update] only.
+    pub threshold_bytes: u32,
+    /// The ticks to pause for after each threshold of bytes, and once all of
+    /// the update has been sent.
+    pub processing_ticks: u32,
+    /// The ticks to pause for after each other [Update] e.g. the time that it
+    /// takes to transmit it and for a server to receive it.
+    pub chunk_ticks: u32,
+}
+
+impl UpdatePacing {
+    /// The most conservative pacing of this and that declared by each of the
+    /// servers given. The smallest chunk size and the longest processing time
+    /// are taken. The threshold is the greatest common divisor of those
+    /// declared so that a pause falls on each server's threshold, which is
+    /// the smallest threshold given thresholds that are powers of 2.
+    pub fn for_servers<'a, I>(self, servers: I) -> Self
+    where
+        I: IntoIterator<Item = &'a ServerPacing>,
+    {
+        servers.into_iter().fold(self, |pacing, server| Self {
+            chunk_size: pacing.chunk_size.min(server.chunk_size),
+            threshold_bytes: gcd(pacing.threshold_bytes, server.threshold_bytes),
+            processing_ticks: pacing.processing_ticks.max(server.processing_ticks),
+            chunk_ticks: pacing.chunk_ticks,
+        })
+    }
+
+    /// The pacing with a pause after each [Update], other than those followed
+    /// by processing, of the time that a datagram of the size given occupies
+    /// the link. A link without a bit rate pauses for the most ticks.
+    pub const fn on_link(
+        self,
+        link: &LinkTiming,
+        datagram_size: usize,
+        ticks_per_second: u32,
+    ) -> Self {
+        Self {
+            chunk_ticks: match link.time_on_wire(datagram_size, ticks_per_second) {
+                Some(ticks) => ticks,
+                None => u32::MAX,
+            },
+            ..self
+        }
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Estimates how long it takes to broadcast an update as paced, which is the
+/// sum of the pauses following each [Update] that an [UpdateSender] yields,
+/// plus an allowance for retransmitting the bytes that servers miss. The
+/// chunk size of the pacing should be that used by the sender i.e. no more
+/// than its `N`, see [UpdateSender::estimate].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UpdateEstimate {
+    /// The number of bytes of the update.
+    pub update_byte_len: u32,
+    /// How the update is paced.
+    pub pacing: UpdatePacing,
+    /// The percentage of the bytes of the update that are expected to be
+    /// retransmitted. Retransmitted bytes are written out of order, and so
+    /// each [Update] of them is followed by the time for processing.
+    pub retransmit_percent: u8,
+}
+
+impl UpdateEstimate {
+    /// Estimate the broadcast of an update without retransmissions.
+    pub const fn new(update_byte_len: u32, pacing: UpdatePacing) -> Self {
+        Self {
+            update_byte_len,
+            pacing,
+            retransmit_percent: 0,
+        }
+    }
+
+    /// The estimate with an allowance for retransmitting a percentage of
+    /// the bytes of the update.
+    pub const fn with_retransmit_percent(mut self, retransmit_percent: u8) -> Self {
+        self.retransmit_percent = retransmit_percent;
+        self
+    }
+
+    /// The ticks taken to send the bytes of the update from the byte offset
+    /// given, without any allowance for retransmitting them.
+    pub fn ticks_from(&self, byte_offset: u32) -> u64 {
+        let update_len = self.update_byte_len;
+        if byte_offset >= update_len {
+            return 0;
+        }
+        let threshold = self.pacing.threshold_bytes;
+        if threshold == 0 {
+            return self.window_ticks(update_len - byte_offset);
+        }
+        let first_end = (byte_offset / threshold + 1)
+            .saturating_mul(threshold)
+            .min(update_len);
+        let rest = update_len - first_end;
+        self.window_ticks(first_end - byte_offset)
+            + (rest / threshold) as u64 * self.window_ticks(threshold)
+            + self.window_ticks(rest % threshold)
+    }
+
+    /// The ticks allowed for retransmitting the bytes that servers miss.
+    pub fn retransmit_ticks(&self) -> u64 {
+        let retransmit_bytes =
+            (self.update_byte_len as u64 * self.retransmit_percent as u64).div_ceil(100);
+        retransmit_bytes.div_ceil(self.chunk_size() as u64) * self.pacing.processing_ticks as u64
+    }
+
+    /// The ticks taken to send all of the update, including the allowance
+    /// for retransmitting it.
+    pub fn total_ticks(&self) -> u64 {
+        self.ticks_from(0) + self.retransmit_ticks()
+    }
+
+    fn chunk_size(&self) -> u32 {
+        (self.pacing.chunk_size as u32).max(1)
+    }
+
+    // The ticks taken to send a number of bytes up to a threshold, being a
+    // pause after each chunk other than the last, which is followed by
+    // processing.
+    fn window_ticks(&self, bytes: u32) -> u64 {
+        if bytes == 0 {
+            return 0;
+        }
+        (bytes.div_ceil(self.chunk_size()) - 1) as u64 * self.pacing.chunk_ticks as u64
+            + self.pacing.processing_ticks as u64
+    }
+}
+
+/// What the client is to do next when broadcasting an update.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SendStep<const N: usize> {
+    /// Broadcast an [Update] under the update key, and then pause for a
+    /// number of ticks.
+    Transmit(Update<N>, u32),
+    /// All of the update has been sent and processed.
+    Finished,
+}
+
+/// Chunks an update into [Update]s of up to `N` bytes and paces their
+/// broadcast, yielding each step of sending it in turn. Each [Update] is
+/// followed by a pause, being that for processing the bytes where a
+/// threshold is reached or the update ends. Updates never straddle a
+/// threshold.
+pub struct UpdateSender<'a, const N: usize> {
+    update: &'a [u8],
+    update_key: &'a UpdateKey,
+    pacing: UpdatePacing,
+    byte_offset: u32,
+    frame_counter: u16,
+    finished: bool,
+    on_progress: Option<&'a mut dyn FnMut(u32, u32, u64)>,
+}
+
+impl<'a, const N: usize> UpdateSender<'a, N> {
+    /// Send the bytes of an update under its key from the byte offset given
+    /// e.g. as resumed from.
+    pub fn new(
+        update: &'a [u8],
+        update_key: &'a UpdateKey,
+        byte_offset: u32,
+        pacing: UpdatePacing,
+    ) -> Self {
+        const { assert!(N > 0) };
+        Self {
+            update,
+            update_key,
+            pacing,
+            byte_offset,
+            frame_counter: 0,
+            finished: false,
+            on_progress: None,
+        }
+    }
+
+    /// The sender encoding datagrams from the frame counter given e.g. that
+    /// following the client's last datagram.
+    pub fn with_frame_counter(mut self, frame_counter: u16) -> Self {
+        self.frame_counter = frame_counter;
+        self
+    }
+
+    /// The sender calling back with its progress as each [Update] is yielded
+    /// e.g. for displaying it. The bytes sent so far, the number of bytes of
+    /// the update, and the ticks remaining until finished, including the
+    /// pause following the [Update], are given. The ticks remaining make no
+    /// allowance for retransmissions.
+    pub fn on_progress(mut self, on_progress: &'a mut dyn FnMut(u32, u32, u64)) -> Self {
+        self.on_progress = Some(on_progress);
+        self
+    }
+
+    /// The estimate of sending the update from the byte offset of the next
+    /// [Update], with a chunk size of no more than `N`.
+    pub fn estimate(&self) -> UpdateEstimate {
+        UpdateEstimate::new(
+            self.update.len() as u32,
+            UpdatePacing {
+                chunk_size: self.pacing.chunk_size.min(N.min(u8::MAX as usize) as u8),
+                ..self.pacing
+            },
+        )
+    }
+
+    /// The byte offset of the next [Update] to send.
+    pub fn byte_offset(&self) -> u32 {
+        self.byte_offset
+    }
+
+    /// The frame counter of the next datagram to encode.
+    pub fn frame_counter(&self) -> u16 {
+        self.frame_counter
+    }
+
+    /// Encode an [Update] as a datagram broadcast to the server port given,
+    /// encrypting it under the update key with a cipher of type `C`, which
+    /// must have a key of 16 bytes. The frame counter is advanced for each
+    /// datagram.
+    pub fn to_datagram<C, const P: usize>(
+        &mut self,
+        server_port: Port,
+        update: Update<N>,
+        datagram_buf: &mut [u8; P],
+    ) where
+        C: AeadInPlace + KeyInit,
+        C::NonceSize: NonceScheme,
+    {
+        let cipher = C::new_from_slice(&self.update_key.0).unwrap();
+        let header = Header {
+            version: 0,
+            source: DataSource::Client,
+            server_address: 0,
+            server_port,
+            frame_counter: self.frame_counter,
+        };
+        let payload = postcard::to_vec::<_, P>(&UpdateMessage::Update(update)).unwrap();
+        to_update_datagram(&cipher, &header, &payload, datagram_buf);
+        self.frame_counter = self.frame_counter.wrapping_add(1);
+    }
+}
+
+impl<const N: usize> Iterator for UpdateSender<'_, N> {
+    type Item = SendStep<N>;
+
+    fn next(&mut self) -> Option<SendStep<N>> {
+        if self.finished {
+            return None;
+        }
+        let update_len = self.update.len() as u32;
+        if self.byte_offset >= update_len {
+            self.finished = true;
+            return Some(SendStep::Finished);
+        }
+
+        let chunk_size = (self.pacing.chunk_size as u32).min(N as u32).max(1);
+        let threshold = self.pacing.threshold_bytes;
+        let next_threshold = self
+            .byte_offset
+            .checked_div(threshold)
+            .map_or(u32::MAX, |n| (n + 1).saturating_mul(threshold))
+            .min(update_len);
+        let to = self
+            .byte_offset
+            .saturating_add(chunk_size)
+            .min(next_threshold);
+
+        let update = Update {
+            byte_offset: self.byte_offset,
+            // Cannot fail given that the chunk is of N bytes at most.
+            bytes: Vec::from_slice(&self.update[self.byte_offset as usize..to as usize]).unwrap(),
+        };
+        let ticks = if to == next_threshold {
+            self.pacing.processing_ticks
+        } else {
+            self.pacing.chunk_ticks
+        };
+        self.byte_offset = to;
+        let estimate = self.estimate();
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(to, update_len, ticks as u64 + estimate.ticks_from(to));
+        }
+        Some(SendStep::Transmit(update, ticks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_versions() {
+        assert_eq!(
+            "1.2.3".parse::<Version>().unwrap(),
+            Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                pre: None
+            }
+        );
+        assert_eq!(
+            "1.2.3-alpha.1".parse::<Version>().unwrap(),
+            Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                pre: Some(PreRelease::Alpha(1))
+            }
+        );
+        assert_eq!(
+            "1.2.3-beta.1".parse::<Version>().unwrap(),
+            Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                pre: Some(PreRelease::Beta(1))
+            }
+        );
+        assert_eq!(
+            "1.2.3-rc.1".parse::<Version>().unwrap(),
+            Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                pre: Some(PreRelease::Rc(1))
+            }
+        );
+        assert_eq!(
+            "1.2.3-beta.1+some-additional-ident"
+                .parse::<Version>()
+                .unwrap(),
+            Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                pre: Some(PreRelease::Beta(1))
+            }
+        );
+        assert_eq!(
+            "1.2.3+some-additional-ident".parse::<Version>().unwrap(),
+            Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                pre: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_update_bytes_overhead() {
+        let update: UpdateMessage<127> = UpdateMessage::Update(Update {
+            byte_offset: u32::MAX,
+            bytes: Vec::from_slice(&[0xff; 127]).unwrap(),
+        });
+        let serialised = postcard::to_vec::<_, 256>(&update).unwrap();
+        assert_eq!(serialised.len(), 127 + UPDATE_BYTES_OVERHEAD);
+    }
+
+    #[test]
+    fn test_bad_versions() {
+        use VersionComponent::*;
+        for (s, err) in [
+            ("", ParseVersionErr::MissingComponent { component: Major }),
+            ("1", ParseVersionErr::MissingComponent { component: Minor }),
+            (
+                "1.2",
+                ParseVersionErr::MissingComponent { component: Patch },
+            ),
+            (
+                "1..3",
+                ParseVersionErr::MissingComponent { component: Minor },
+            ),
+            ("x.2.3", ParseVersionErr::InvalidNumber { component: Major }),
+            (
+                "1.256.3",
+                ParseVersionErr::InvalidNumber { component: Minor },
+            ),
+            (
+                "1. 2.3",
+                ParseVersionErr::InvalidNumber { component: Minor },
+            ),
+            (
+                "1.2.3x",
+                ParseVersionErr::InvalidNumber { component: Patch },
+            ),
+            (
+                "1.2.3.4",
+                ParseVersionErr::InvalidNumber { component: Patch },
+            ),
+            ("1.2.3-gamma.1", ParseVersionErr::UnknownPreRelease),
+            ("1.2.3-", ParseVersionErr::UnknownPreRelease),
+            (
+                "1.2.3-alpha",
+                ParseVersionErr::MissingComponent {
+                    component: PreRelease,
+                },
+            ),
+            (
+                "1.2.3-alpha.1x",
+                ParseVersionErr::InvalidNumber {
+                    component: PreRelease,
+                },
+            ),
+            (
+                "1.2.3-rc.1.2",
+                ParseVersionErr::InvalidNumber {
+                    component: PreRelease,
+                },
+            ),
+        ] {
+            assert_eq!(s.parse::<Version>(), Err(err), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_version_round_trip() {
+        let pres = [None]
+            .into_iter()
+            .chain((0..=u8::MAX).flat_map(|ident| {
+                [
+                    Some(PreRelease::Alpha(ident)),
+                    Some(PreRelease::Beta(ident)),
+                    Some(PreRelease::Rc(ident)),
+                ]
+            }))
+            .collect::<std::vec::Vec<_>>();
+        for n in 0..=u8::MAX {
+            for (i, pre) in pres.iter().enumerate() {
+                let version = Version {
+                    major: n,
+                    minor: n.wrapping_add(i as u8),
+                    patch: n.wrapping_mul(i as u8),
+                    pre: *pre,
+                };
+                assert_eq!(version.to_string().parse::<Version>(), Ok(version));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert!("1.0.0".parse::<Version>().unwrap() == "1.0.0".parse::<Version>().unwrap());
+        assert!("1.0.0".parse::<Version>().unwrap() < "2.0.0".parse::<Version>().unwrap());
+        assert!("1.1.0".parse::<Version>().unwrap() < "1.2.0".parse::<Version>().unwrap());
+        assert!("1.1.1".parse::<Version>().unwrap() < "1.1.2".parse::<Version>().unwrap());
+        assert!("1.0.0-alpha.1".parse::<Version>().unwrap() < "1.0.0".parse::<Version>().unwrap());
+        assert!("1.0.0-beta.1".parse::<Version>().unwrap() < "1.0.0".parse::<Version>().unwrap());
+        assert!(
+            "1.0.0-alpha.1".parse::<Version>().unwrap()
+                < "1.0.0-beta.1".parse::<Version>().unwrap()
+        );
+        assert!(
+            "1.0.0-alpha.1".parse::<Version>().unwrap()
+                < "1.0.0-alpha.2".parse::<Version>().unwrap()
+        );
+        assert!("1.0.0-rc.1".parse::<Version>().unwrap() < "1.0.0".parse::<Version>().unwrap());
+        assert!(
+            "1.0.0-beta.2".parse::<Version>().unwrap() < "1.0.0-rc.1".parse::<Version>().unwrap()
+        );
+        assert!(
+            "1.0.0-rc.1".parse::<Version>().unwrap() < "1.0.0-rc.2".parse::<Version>().unwrap()
+        );
+        assert!(
+            "1.0.0-rc.9".parse::<Version>().unwrap() < "1.0.1-alpha.1".parse::<Version>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pre_release_serialisation() {
+        // The encoding of existing designators is unchanged by those added.
+        for (pre, expected) in [
+            (None, [1, 2, 3, 0].as_slice()),
+            (Some(PreRelease::Alpha(4)), &[1, 2, 3, 1, 0, 4]),
+            (Some(PreRelease::Beta(4)), &[1, 2, 3, 1, 1, 4]),
+            (Some(PreRelease::Rc(4)), &[1, 2, 3, 1, 2, 4]),
+        ] {
+            let version = Version {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                pre,
+            };
+            let serialised =
+                postcard::to_vec::<_, { Version::POSTCARD_MAX_SIZE }>(&version).unwrap();
+            assert_eq!(serialised, expected);
+            assert_eq!(postcard::from_bytes::<Version>(expected).unwrap(), version);
+        }
+    }
+
+    fn prepare(update: &[u8]) -> PrepareForUpdate {
+        PrepareForUpdate {
+            version: "1.2.3".parse().unwrap(),
+            server_ports: PortSet::from_bits(0b00000100),
+            image_id: 0,
+            security_epoch: 0,
+            update_key: UpdateKey([1; 16]),
+            update_byte_len: update.len() as u32,
+            integrity: UpdateIntegrity::Digest(update_digest(update)),
+            compression: Compression::None,
+            delta: None,
+            hardware_rev: None,
+            dry_run: false,
+        }
+    }
+
+    fn receive(verifier: &mut UpdateVerifier, update: &[u8]) -> Result<(), VerificationError> {
+        for (i, chunk) in update.chunks(33).enumerate() {
+            verifier.update((i * 33) as u32, chunk)?;
+        }
+        verifier.verify()
+    }
+
+    #[test]
+    fn test_prepare_for_update_serialisation() {
+        let mut prepare_for_update = prepare(&[0x5a; 100]);
+        let serialised =
+            postcard::to_vec::<_, MAX_PREPARE_FOR_UPDATE_SIZE>(&prepare_for_update).unwrap();
+        assert_eq!(serialised.len(), 4 + 1 + 1 + 1 + 16 + 1 + 1 + DIGEST_SIZE);
+        assert_eq!(serialised[24], 0);
+        assert_eq!(serialised[25..], update_digest(&[0x5a; 100]));
+        let deserialised = postcard::from_bytes::<PrepareForUpdate>(&serialised).unwrap();
+        assert_eq!(deserialised, prepare_for_update);
+        assert_eq!(deserialised.compression, Compression::None);
+        assert_eq!(deserialised.delta, None);
+
+        // A delta update conveys its compression even when there is none.
+        prepare_for_update.delta = Some(Delta {
+            base_version: "1.2.0".parse().unwrap(),
+            patch_format: delta::COPY_INSERT_PATCH_FORMAT,
+        });
+        let serialised =
+            postcard::to_vec::<_, MAX_PREPARE_FOR_UPDATE_SIZE>(&prepare_for_update).unwrap();
+        assert_eq!(
+            serialised.len(),
+            4 + 1 + 1 + 1 + 16 + 1 + 1 + DIGEST_SIZE + 1 + 4 + 1
+        );
+        let deserialised = postcard::from_bytes::<PrepareForUpdate>(&serialised).unwrap();
+        assert_eq!(deserialised.compression, Compression::None);
+        assert_eq!(deserialised.delta, prepare_for_update.delta);
+        assert_eq!(deserialised.hardware_rev, None);
+
+        // A hardware revision is conveyed following a delta, which patches
+        // nothing when the update is not a delta update.
+        prepare_for_update.hardware_rev = Some(3);
+        let serialised =
+            postcard::to_vec::<_, MAX_PREPARE_FOR_UPDATE_SIZE>(&prepare_for_update).unwrap();
+        let deserialised = postcard::from_bytes::<PrepareForUpdate>(&serialised).unwrap();
+        assert_eq!(deserialised.delta, prepare_for_update.delta);
+        assert_eq!(deserialised.hardware_rev, Some(3));
+        prepare_for_update.delta = None;
+        let serialised =
+            postcard::to_vec::<_, MAX_PREPARE_FOR_UPDATE_SIZE>(&prepare_for_update).unwrap();
+        assert_eq!(
+            serialised[4 + 1 + 1 + 1 + 16 + 1 + 1 + DIGEST_SIZE..],
+            [0, 0, 0, 0, 0, NO_PATCH_FORMAT, 3]
+        );
+        let deserialised = postcard::from_bytes::<PrepareForUpdate>(&serialised).unwrap();
+        assert_eq!(deserialised.delta, None);
+        assert_eq!(deserialised.hardware_rev, Some(3));
+
+        // A dry run is conveyed following a hardware revision, which is any
+        // revision when the update is not constrained to one.
+        prepare_for_update.dry_run = true;
+        let serialised =
+            postcard::to_vec::<_, MAX_PREPARE_FOR_UPDATE_SIZE>(&prepare_for_update).unwrap();
+        assert_eq!(
+            serialised[4 + 1 + 1 + 1 + 16 + 1 + 1 + DIGEST_SIZE..],
+            [0, 0, 0, 0, 0, NO_PATCH_FORMAT, 3, 1]
+        );
+        let deserialised = postcard::from_bytes::<PrepareForUpdate>(&serialised).unwrap();
+        assert_eq!(deserialised.hardware_rev, Some(3));
+        assert!(deserialised.dry_run);
+        prepare_for_update.hardware_rev = None;
+        let serialised =
+            postcard::to_vec::<_, MAX_PREPARE_FOR_UPDATE_SIZE>(&prepare_for_update).unwrap();
+        assert_eq!(
+            serialised[4 + 1 + 1 + 1 + 16 + 1 + 1 + DIGEST_SIZE..],
+            [0, 0, 0, 0, 0, NO_PATCH_FORMAT, ANY_HARDWARE_REV, 1]
+        );
+        let deserialised = postcard::from_bytes::<PrepareForUpdate>(&serialised).unwrap();
+        assert_eq!(deserialised.delta, None);
+        assert_eq!(deserialised.hardware_rev, None);
+        assert!(deserialised.dry_run);
+
+        prepare_for_update.version.pre = Some(PreRelease::Beta(255));
+        prepare_for_update.image_id = 255;
+        prepare_for_update.security_epoch = u16::MAX;
+        prepare_for_update.update_byte_len = u32::MAX;
+        prepare_for_update.integrity = UpdateIntegrity::Signed(UpdateSignature {
+            key_id: 255,
+            signature: [0xff; SIGNATURE_SIZE],
+        });
+        prepare_for_update.compression = Compression::Heatshrink {
+            window: 255,
+            lookahead: 255,
+        };
+        prepare_for_update.delta = Some(Delta {
+            base_version: Version {
+                pre: Some(PreRelease::Beta(255)),
+                ..prepare_for_update.version.clone()
+            },
+            patch_format: 254,
+        });
+        prepare_for_update.hardware_rev = Some(ANY_HARDWARE_REV - 1);
+        let serialised =
+            postcard::to_vec::<_, MAX_PREPARE_FOR_UPDATE_SIZE>(&prepare_for_update).unwrap();
+        assert_eq!(serialised.len(), MAX_PREPARE_FOR_UPDATE_SIZE);
+        let deserialised = postcard::from_bytes::<PrepareForUpdate>(&serialised).unwrap();
+        assert_eq!(deserialised, prepare_for_update);
+        assert!(postcard::from_bytes::<PrepareForUpdate>(
+            &serialised[..serialised.len() - 4 - Version::POSTCARD_MAX_SIZE - 1 - 1 - 1]
+        )
+        .is_err());
+
+        // The payload length of a data frame cannot exceed 127 bytes.
+        const { assert!(MAX_PREPARE_FOR_UPDATE_SIZE + crate::MIC_SIZE <= 127) };
+    }
+
+    #[test]
+    fn test_prepare_for_update_debug() {
+        let prepare_for_update = PrepareForUpdate {
+            update_key: UpdateKey([0xab; 16]),
+            ..prepare(&[0x5a; 100])
+        };
+        let debug = format!("{prepare_for_update:?}");
+        assert!(debug.starts_with("PrepareForUpdate { version: Version { major: 1, minor: 2, patch: 3, pre: None }, server_ports: 4, image_id: 0, security_epoch: 0, update_key: \"XXX\", update_byte_len: 100, integrity: Digest("));
+        assert!(debug
+            .ends_with(", compression: None, delta: None, hardware_rev: None, dry_run: false }"));
+        assert!(!debug.contains("171, 171"));
+
+        let message = UpdateMessage::<4>::Update(Update {
+            byte_offset: 8,
+            bytes: Vec::from_slice(&[1, 2]).unwrap(),
+        });
+        assert_eq!(
+            format!("{message:?}"),
+            "Update(Update { byte_offset: 8, bytes: [1, 2] })"
+        );
+        assert_eq!(message.clone(), message);
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let update = [0x5a; 1000];
+        let mut verifier = UpdateVerifier::new(&prepare(&update));
+        assert_eq!(verifier.state(), VerifierState::Hashing);
+        assert_eq!(receive(&mut verifier, &update), Ok(()));
+        assert_eq!(verifier.state(), VerifierState::Verified);
+
+        let mut flipped = update;
+        flipped[500] ^= 0b00010000;
+        let mut verifier = UpdateVerifier::new(&prepare(&update));
+        assert_eq!(
+            receive(&mut verifier, &flipped),
+            Err(VerificationError::DigestMismatch)
+        );
+        assert_eq!(
+            verifier.state(),
+            VerifierState::Failed(VerificationError::DigestMismatch)
+        );
+        assert_eq!(verifier.verify(), Err(VerificationError::DigestMismatch));
+    }
+
+    #[test]
+    fn test_verify_restarted_update() {
+        let aborted = [0xa5; 1000];
+        let mut verifier = UpdateVerifier::new(&prepare(&aborted));
+        for (i, chunk) in aborted[..500].chunks(33).enumerate() {
+            verifier.update((i * 33) as u32, chunk).unwrap();
+        }
+
+        // The update is restarted with other bytes, and a stale chunk of the
+        // aborted update at the same offset and under the same key is
+        // received in place of one of them.
+        let update = [0x5a; 1000];
+        let mut verifier = UpdateVerifier::new(&prepare(&update));
+        let mut received = update;
+        received[33..66].copy_from_slice(&aborted[33..66]);
+        assert_eq!(
+            receive(&mut verifier, &received),
+            Err(VerificationError::DigestMismatch)
+        );
+
+        let mut verifier = UpdateVerifier::new(&prepare(&update));
+        assert_eq!(receive(&mut verifier, &update), Ok(()));
+    }
+
+    #[test]
+    fn test_unverifiable_update() {
+        let update = [0x5a; 100];
+
+        let mut verifier = UpdateVerifier::new(&PrepareForUpdate {
+            integrity: UpdateIntegrity::Signed(UpdateSignature {
+                key_id: 1,
+                signature: [0; SIGNATURE_SIZE],
+            }),
+            ..prepare(&update)
+        });
+        assert_eq!(
+            verifier.update(0, &update),
+            Err(VerificationError::UntrustedKey(1))
+        );
+
+        let mut verifier = UpdateVerifier::new(&prepare(&update));
+        assert_eq!(verifier.update(0, &update[..50]), Ok(()));
+        assert_eq!(
+            verifier.update(60, &update[60..]),
+            Err(VerificationError::UnexpectedOffset {
+                expected: 50,
+                received: 60
+            })
+        );
+
+        let mut verifier = UpdateVerifier::new(&prepare(&update));
+        assert_eq!(verifier.update(0, &update[..50]), Ok(()));
+        assert_eq!(
+            verifier.verify(),
+            Err(VerificationError::Incomplete {
+                received: 50,
+                expected: 100
+            })
+        );
+
+        let mut verifier = UpdateVerifier::new(&prepare(&update));
+        assert_eq!(
+            verifier.update(0, &[0; 101]),
+            Err(VerificationError::UnexpectedOffset {
+                expected: 0,
+                received: 0
+            })
+        );
+    }
+
+    #[test]
+    fn display_versions() {
+        assert_eq!("1.2.3".parse::<Version>().unwrap().to_string(), "1.2.3");
+        assert_eq!(
+            "1.2.3-alpha.4".parse::<Version>().unwrap().to_string(),
+            "1.2.3-alpha.4"
+        );
+        assert_eq!(
+            "1.2.3-beta.4".parse::<Version>().unwrap().to_string(),
+            "1.2.3-beta.4"
+        );
+        assert_eq!(
+            "1.2.3-rc.4".parse::<Version>().unwrap().to_string(),
+            "1.2.3-rc.4"
+        );
+    }
+
+    fn discard(_: u32, _: &[u8]) {}
+
+    fn in_order(_: u32, _: &mut [u8]) {
+        panic!("the update is received in order");
+    }
+
+    fn same_epoch(_: u16) {
+        panic!("the security epoch is unchanged");
+    }
+
+    fn image(len: usize) -> std::vec::Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn stage(staged: &mut [u8]) -> impl FnMut(u32, &[u8]) + '_ {
+        |offset, bytes| {
+            staged[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes)
+        }
+    }
+
+    fn read(staged: &[u8]) -> impl FnMut(u32, &mut [u8]) + '_ {
+        |offset, buf| buf.copy_from_slice(&staged[offset as usize..offset as usize + buf.len()])
+    }
+
+    fn updates(update: &[u8]) -> impl Iterator<Item = Update<33>> + '_ {
+        update.chunks(33).enumerate().map(|(i, chunk)| Update {
+            byte_offset: (i * 33) as u32,
+            bytes: Vec::from_slice(chunk).unwrap(),
+        })
+    }
+
+    #[test]
+    fn test_update_status_serialisation() {
+        let request = postcard::to_vec::<_, 1>(&UpdateStatusRequest::default()).unwrap();
+        assert!(request.is_empty());
+        assert_eq!(
+            postcard::from_bytes::<UpdateStatusRequest>(&request),
+            Ok(UpdateStatusRequest::default())
+        );
+        let request = UpdateStatusRequest {
+            missing_ranges: Some(4),
+            image_id: 0,
+            eligibility: false,
+        };
+        let serialised = postcard::to_vec::<_, 1>(&request).unwrap();
+        assert_eq!(serialised[..], [4]);
+        assert_eq!(
+            postcard::from_bytes::<UpdateStatusRequest>(&serialised),
+            Ok(request)
+        );
+
+        // The status of another image is asked for by asking for no missing
+        // ranges.
+        for (missing_ranges, expected) in [(None, [0, 2]), (Some(4), [4, 2])] {
+            let request = UpdateStatusRequest {
+                missing_ranges,
+                image_id: 2,
+                eligibility: false,
+            };
+            let serialised = postcard::to_vec::<_, 2>(&request).unwrap();
+            assert_eq!(serialised[..], expected);
+            assert_eq!(
+                postcard::from_bytes::<UpdateStatusRequest>(&serialised),
+                Ok(request)
+            );
+        }
+
+        // Eligibility is asked for following the image.
+        for (image_id, expected) in [(0, [0, 0, 1]), (2, [0, 2, 1])] {
+            let request = UpdateStatusRequest {
+                image_id,
+                eligibility: true,
+                ..Default::default()
+            };
+            let serialised = postcard::to_vec::<_, 3>(&request).unwrap();
+            assert_eq!(serialised[..], expected);
+            assert_eq!(
+                postcard::from_bytes::<UpdateStatusRequest>(&serialised),
+                Ok(request)
+            );
+        }
+
+        let mut report = EligibilityReport {
+            image_id: 255,
+            ports: Vec::new(),
+        };
+        for port in Port::all() {
+            report
+                .ports
+                .push(PortEligibility {
+                    port,
+                    decline: Some(DeclineReason::RunningImage),
+                })
+                .unwrap();
+        }
+        let serialised = postcard::to_vec::<_, MAX_ELIGIBILITY_REPORT_SIZE>(&report).unwrap();
+        assert_eq!(serialised.len(), MAX_ELIGIBILITY_REPORT_SIZE);
+        assert_eq!(
+            postcard::from_bytes::<EligibilityReport>(&serialised),
+            Ok(report)
+        );
+        const { assert!(MAX_ELIGIBILITY_REPORT_SIZE + crate::MIC_SIZE <= 127) };
+
+        let mut missing = MissingRanges::default();
+        for _ in 0..MAX_MISSING_RANGES {
+            missing.ranges.push((u32::MAX, u32::MAX)).unwrap();
+        }
+        let serialised = postcard::to_vec::<_, MAX_MISSING_RANGES_SIZE>(&missing).unwrap();
+        assert_eq!(serialised.len(), MAX_MISSING_RANGES_SIZE);
+        assert_eq!(
+            postcard::from_bytes::<MissingRanges>(&serialised),
+            Ok(missing)
+        );
+        const { assert!(MAX_MISSING_RANGES_SIZE + crate::MIC_SIZE <= 127) };
+
+        let status = UpdateStatus {
+            state: UpdateState::Failed,
+            received_bytes: u32::MAX,
+            current_version: "255.255.255-beta.255".parse().unwrap(),
+            last_error: Some(VerificationError::Incomplete {
+                received: u32::MAX,
+                expected: u32::MAX,
+            }),
+            image_id: 255,
+            pacing: Some(ServerPacing {
+                chunk_size: u8::MAX,
+                threshold_bytes: u32::MAX,
+                processing_ticks: u32::MAX,
+            }),
+        };
+        let serialised = postcard::to_vec::<_, MAX_UPDATE_STATUS_SIZE>(&status).unwrap();
+        assert_eq!(serialised.len(), MAX_UPDATE_STATUS_SIZE);
+        assert_eq!(
+            postcard::from_bytes::<UpdateStatus>(&serialised),
+            Ok(status)
+        );
+        const { assert!(MAX_UPDATE_STATUS_SIZE > crate::discovery::MIN_PAYLOAD_SIZE) };
+
+        let mut status = UpdateStatus {
+            state: UpdateState::Idle,
+            received_bytes: 0,
+            current_version: "1.2.0".parse().unwrap(),
+            last_error: None,
+            image_id: 0,
+            pacing: None,
+        };
+        let serialised = postcard::to_vec::<_, MAX_UPDATE_STATUS_SIZE>(&status).unwrap();
+        assert_eq!(serialised[..], [0, 0, 1, 2, 0, 0, 0]);
+        assert_eq!(
+            postcard::from_bytes::<UpdateStatus>(&serialised),
+            Ok(status.clone())
+        );
+        status.image_id = 2;
+        let serialised = postcard::to_vec::<_, MAX_UPDATE_STATUS_SIZE>(&status).unwrap();
+        assert_eq!(serialised[..], [0, 0, 1, 2, 0, 0, 0, 2]);
+        assert_eq!(
+            postcard::from_bytes::<UpdateStatus>(&serialised),
+            Ok(status.clone())
+        );
+
+        // The primary image is conveyed when followed by the server's pacing.
+        status.image_id = 0;
+        status.pacing = Some(ServerPacing {
+            chunk_size: 32,
+            threshold_bytes: 4,
+            processing_ticks: 100,
+        });
+        let serialised = postcard::to_vec::<_, MAX_UPDATE_STATUS_SIZE>(&status).unwrap();
+        assert_eq!(serialised[..], [0, 0, 1, 2, 0, 0, 0, 0, 32, 4, 100]);
+        assert_eq!(
+            postcard::from_bytes::<UpdateStatus>(&serialised),
+            Ok(status)
+        );
+    }
+
+    #[test]
+    fn test_receiver_states() {
+        let update = [0x5a; 100];
+        let prepare_for_update = prepare(&update);
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        assert_eq!(receiver.state(), UpdateState::Idle);
+        assert!(receiver.update_key().is_none());
+
+        // Chunks are ignored until prepared.
+        let mut chunks = updates(&update);
+        assert_eq!(
+            receiver.receive(&chunks.next().unwrap(), discard),
+            UpdateState::Idle
+        );
+
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Started
+        );
+        assert_eq!(receiver.update_key(), Some(&UpdateKey([1; 16])));
+        assert_eq!(receiver.state(), UpdateState::Receiving);
+
+        // Verifying and applying must wait for the update to be received.
+        assert_eq!(receiver.verify(in_order), UpdateState::Receiving);
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Receiving);
+
+        for chunk in updates(&update) {
+            receiver.receive(&chunk, discard);
+        }
+        assert_eq!(receiver.state(), UpdateState::Verifying);
+        assert_eq!(receiver.status().received_bytes, 100);
+
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Verifying);
+        assert_eq!(receiver.verify(in_order), UpdateState::Staged);
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Staged);
+        assert_eq!(
+            receiver.commit(
+                &UpdateCommit {
+                    version: "1.2.3".parse().unwrap(),
+                    activate_delay_ticks: 0,
+                    image_id: 0,
+                },
+                0
+            ),
+            Ok(())
+        );
+        assert_eq!(receiver.poll_activation(0), UpdateState::ReadyToApply);
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Applied);
+        assert!(receiver.update_key().is_none());
+        assert_eq!(
+            receiver.status(),
+            UpdateStatus {
+                state: UpdateState::Applied,
+                received_bytes: 100,
+                current_version: "1.2.3".parse().unwrap(),
+                last_error: None,
+                image_id: 0,
+                pacing: None,
+            }
+        );
+
+        // The same version cannot be updated to again.
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::NotNewer
+        );
+        assert_eq!(receiver.state(), UpdateState::Applied);
+    }
+
+    #[test]
+    fn test_receiver_failures() {
+        let update = [0x5a; 100];
+        let prepare_for_update = prepare(&update);
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+
+        // Bytes beyond the length of the update fail it.
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+        for chunk in updates(&update).take(3) {
+            receiver.receive(&chunk, discard);
+        }
+        let beyond = Update::<33> {
+            byte_offset: 99,
+            bytes: Vec::from_slice(&[0x5a; 33]).unwrap(),
+        };
+        assert_eq!(receiver.receive(&beyond, discard), UpdateState::Failed);
+        assert!(receiver.update_key().is_none());
+        assert_eq!(
+            receiver.status(),
+            UpdateStatus {
+                state: UpdateState::Failed,
+                received_bytes: 99,
+                current_version: "1.2.0".parse().unwrap(),
+                last_error: Some(VerificationError::UnexpectedOffset {
+                    expected: 99,
+                    received: 99
+                }),
+                image_id: 0,
+                pacing: None,
+            }
+        );
+        let last = updates(&update).last().unwrap();
+        assert_eq!(receiver.receive(&last, discard), UpdateState::Failed);
+
+        // A new preparation starts afresh, but a tampered update fails
+        // verification.
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+        assert_eq!(receiver.status().last_error, None);
+        for chunk in updates(&[0xa5; 100]) {
+            receiver.receive(&chunk, discard);
+        }
+        assert_eq!(receiver.verify(in_order), UpdateState::Failed);
+        assert_eq!(
+            receiver.status().last_error,
+            Some(VerificationError::DigestMismatch)
+        );
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Failed);
+        assert_eq!(receiver.current_version(), &"1.2.0".parse().unwrap());
+
+        // An update that cannot be verified fails when prepared for.
+        let signed = PrepareForUpdate {
+            integrity: UpdateIntegrity::Signed(UpdateSignature {
+                key_id: 3,
+                signature: [0; SIGNATURE_SIZE],
+            }),
+            ..prepare(&update)
+        };
+        assert_eq!(
+            receiver.prepare(&signed, UpdateVerifier::new(&signed)),
+            Preparation::Unverifiable(VerificationError::UntrustedKey(3))
+        );
+        assert!(receiver.update_key().is_none());
+        assert_eq!(receiver.state(), UpdateState::Failed);
+        assert_eq!(
+            receiver.status().last_error,
+            Some(VerificationError::UntrustedKey(3))
+        );
+
+        // As does an update compressed with a window that is too large.
+        let compressed = PrepareForUpdate {
+            compression: Compression::Heatshrink {
+                window: 15,
+                lookahead: 4,
+            },
+            ..prepare(&update)
+        };
+        assert_eq!(
+            receiver.prepare(&compressed, UpdateVerifier::new(&compressed)),
+            Preparation::UnsupportedCompression
+        );
+        assert_eq!(
+            receiver.status().last_error,
+            Some(VerificationError::UnsupportedCompression)
+        );
+    }
+
+    #[test]
+    fn test_update_eligibility() {
+        let prepare_for_update = PrepareForUpdate {
+            security_epoch: 2,
+            ..prepare(&[0x5a; 100])
+        };
+        let check = |current_version: &str, current_epoch| {
+            UpdateEligibility::check(
+                &prepare_for_update,
+                &current_version.parse().unwrap(),
+                current_epoch,
+                None,
+            )
+        };
+        assert_eq!(check("1.2.0", 2), UpdateEligibility::Eligible);
+        assert_eq!(check("1.2.0", 1), UpdateEligibility::Eligible);
+        assert_eq!(check("1.2.3", 2), UpdateEligibility::NotNewer);
+        assert_eq!(check("1.2.0", 3), UpdateEligibility::EpochRollback);
+        assert_eq!(check("1.2.3", 3), UpdateEligibility::EpochRollback);
+
+        // Updates constrained to a hardware revision are declined by servers
+        // of another, or of one that is not known, ahead of anything else.
+        let prepare_for_update = PrepareForUpdate {
+            hardware_rev: Some(3),
+            ..prepare_for_update
+        };
+        let check = |current_version: &str, current_epoch, hardware_rev| {
+            UpdateEligibility::check(
+                &prepare_for_update,
+                &current_version.parse().unwrap(),
+                current_epoch,
+                hardware_rev,
+            )
+        };
+        assert_eq!(check("1.2.0", 2, Some(3)), UpdateEligibility::Eligible);
+        assert_eq!(
+            check("1.2.0", 2, Some(2)),
+            UpdateEligibility::WrongHardwareRevision
+        );
+        assert_eq!(
+            check("1.2.0", 2, None),
+            UpdateEligibility::WrongHardwareRevision
+        );
+        assert_eq!(
+            check("1.2.3", 3, Some(2)),
+            UpdateEligibility::WrongHardwareRevision
+        );
+        assert_eq!(check("1.2.3", 2, Some(3)), UpdateEligibility::NotNewer);
+    }
+
+    #[test]
+    fn test_eligibility_report() {
+        let update = [0x5a; 100];
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap()).with_hardware_rev(3);
+        let storage_for =
+            |port: Port| (port.get() == 3).then_some(DeclineReason::InsufficientStorage);
+
+        // Nothing to report until prepared for an update.
+        assert!(receiver
+            .eligibility(PortSet::from_bits(0b1110), storage_for)
+            .ports
+            .is_empty());
+
+        // Ports targeted by the update are reported, being declined for the
+        // reason given when the update is eligible.
+        let prepare_for_update = PrepareForUpdate {
+            server_ports: PortSet::from_bits(0b1101),
+            ..prepare(&update)
+        };
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Started
+        );
+        assert_eq!(
+            receiver.eligibility(PortSet::from_bits(0b1110), storage_for),
+            EligibilityReport {
+                image_id: 0,
+                ports: Vec::from_slice(&[
+                    PortEligibility {
+                        port: Port::new(2).unwrap(),
+                        decline: None
+                    },
+                    PortEligibility {
+                        port: Port::new(3).unwrap(),
+                        decline: Some(DeclineReason::InsufficientStorage)
+                    },
+                ])
+                .unwrap(),
+            }
+        );
+
+        // An update that is not eligible is declined for every port.
+        let prepare_for_update = PrepareForUpdate {
+            hardware_rev: Some(2),
+            ..prepare_for_update
+        };
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::WrongHardwareRevision
+        );
+        let report = receiver.eligibility(PortSet::from_bits(0b1110), storage_for);
+        assert_eq!(report.ports.len(), 2);
+        assert!(report
+            .ports
+            .iter()
+            .all(|p| p.decline == Some(DeclineReason::WrongHardwareRevision)));
+    }
+
+    #[test]
+    fn test_eligibility_tally() {
+        let report = |declines: &[Option<DeclineReason>]| EligibilityReport {
+            image_id: 0,
+            ports: declines
+                .iter()
+                .zip(Port::all())
+                .map(|(decline, port)| PortEligibility {
+                    port,
+                    decline: *decline,
+                })
+                .collect(),
+        };
+        let accepting = report(&[None, None]);
+        let partial = report(&[None, Some(DeclineReason::RunningImage)]);
+        let declining = report(&[Some(DeclineReason::NotNewer)]);
+
+        let mut tally = EligibilityTally::new(50);
+        for r in [&accepting, &partial, &declining, &report(&[])] {
+            tally.add(r);
+        }
+        assert_eq!(
+            (tally.accepting(), tally.partial(), tally.declining()),
+            (1, 1, 2)
+        );
+        assert!(!tally.proceed(4));
+        assert!(tally.proceed(2));
+        assert!(tally.counting_partial().proceed(4));
+        // Servers that have not reported are taken as declining.
+        assert!(!tally.counting_partial().proceed(5));
+
+        // No quorum is reached without any servers accepting.
+        assert!(!EligibilityTally::new(0).proceed(1));
+        let mut tally = EligibilityTally::new(0);
+        tally.add(&partial);
+        assert!(!tally.proceed(1));
+        assert!(tally.counting_partial().proceed(1));
+    }
+
+    #[test]
+    fn test_security_epoch() {
+        let update = [0x5a; 100];
+        let commit = UpdateCommit {
+            version: "1.2.3".parse().unwrap(),
+            activate_delay_ticks: 0,
+            image_id: 0,
+        };
+        let prepare_for_update = PrepareForUpdate {
+            security_epoch: 2,
+            ..prepare(&update)
+        };
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap()).with_security_epoch(1);
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Started
+        );
+        for chunk in updates(&update) {
+            receiver.receive(&chunk, discard);
+        }
+        receiver.verify(in_order);
+        receiver.commit(&commit, 0).unwrap();
+        receiver.poll_activation(0);
+        let mut persisted = None;
+        assert_eq!(
+            receiver.applied(|security_epoch| persisted = Some(security_epoch)),
+            UpdateState::Applied
+        );
+        assert_eq!(persisted, Some(2));
+        assert_eq!(receiver.security_epoch(), 2);
+
+        // Following a restart, a later version of an earlier epoch is refused
+        // while one of the same epoch is accepted.
+        let mut receiver =
+            UpdateReceiver::new("1.2.3".parse().unwrap()).with_security_epoch(persisted.unwrap());
+        let rollback = PrepareForUpdate {
+            version: "1.3.0".parse().unwrap(),
+            security_epoch: 1,
+            ..prepare(&update)
+        };
+        assert_eq!(
+            receiver.prepare(&rollback, UpdateVerifier::new(&rollback)),
+            Preparation::EpochRollback
+        );
+        assert_eq!(receiver.state(), UpdateState::Idle);
+        let later = PrepareForUpdate {
+            version: "1.3.0".parse().unwrap(),
+            security_epoch: 2,
+            ..prepare(&update)
+        };
+        assert_eq!(
+            receiver.prepare(&later, UpdateVerifier::new(&later)),
+            Preparation::Started
+        );
+        for chunk in updates(&update) {
+            receiver.receive(&chunk, discard);
+        }
+        receiver.verify(in_order);
+        receiver
+            .commit(
+                &UpdateCommit {
+                    version: "1.3.0".parse().unwrap(),
+                    ..commit
+                },
+                0,
+            )
+            .unwrap();
+        receiver.poll_activation(0);
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Applied);
+        assert_eq!(receiver.security_epoch(), 2);
+    }
+
+    #[test]
+    fn test_resumed_update() {
+        let update = image(1000);
+        let prepare_for_update = prepare(&update);
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+        let mut staged = [0; 1000];
+
+        // The transfer is interrupted part way through, with a chunk also
+        // being missed.
+        let mut chunks = updates(&update);
+        for chunk in chunks.by_ref().take(24) {
+            receiver.receive(&chunk, stage(&mut staged));
+        }
+        chunks.next();
+        for chunk in chunks.take(3) {
+            assert_eq!(
+                receiver.receive(&chunk, stage(&mut staged)),
+                UpdateState::Receiving
+            );
+        }
+        assert_eq!(receiver.status().received_bytes, 24 * 33);
+
+        // Resuming with a different key, length or digest is refused, leaving
+        // the update as it was.
+        for mismatched in [
+            PrepareForUpdate {
+                update_key: UpdateKey([2; 16]),
+                ..prepare(&update)
+            },
+            prepare(&update[..999]),
+            prepare(&[0x5a; 1000]),
+        ] {
+            assert_eq!(
+                receiver.prepare(&mismatched, UpdateVerifier::new(&mismatched)),
+                Preparation::Mismatched
+            );
+        }
+        assert_eq!(receiver.update_key(), Some(&UpdateKey([1; 16])));
+
+        let mut poller = UpdateStatusPoller::<1>::new("1.2.3".parse().unwrap(), &[1]).unwrap();
+        poller.poll_transmit();
+        poller.handle_reply(1, receiver.status());
+        let byte_offset = poller.resume_from().unwrap();
+
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Resumed { byte_offset }
+        );
+
+        // Resuming from an earlier offset than necessary is also tolerated,
+        // with the bytes received beyond the missed chunk being read back to
+        // verify them.
+        for chunk in updates(&update).skip(20) {
+            receiver.receive(&chunk, stage(&mut staged));
+        }
+        assert_eq!(receiver.verify(read(&staged)), UpdateState::Staged);
+        assert_eq!(receiver.status().received_bytes, 1000);
+        assert_eq!(staged[..], update[..]);
+    }
+
+    #[test]
+    fn test_retransmit_plan() {
+        let missing = |ranges: &[(u32, u32)]| MissingRanges {
+            ranges: Vec::from_slice(ranges).unwrap(),
+        };
+
+        let mut plan = RetransmitPlan::<4>::new();
+        assert!(plan.is_empty());
+        plan.add(&missing(&[(100, 50), (400, 10)]));
+        plan.add(&missing(&[(120, 50), (410, 10), (0, 10)]));
+        plan.add(&missing(&[(900, 0)]));
+        assert_eq!(
+            plan.ranges().collect::<std::vec::Vec<_>>(),
+            [(0, 10), (100, 70), (400, 20)]
+        );
+
+        // Ranges spanning others subsume them.
+        plan.add(&missing(&[(50, 500)]));
+        assert_eq!(
+            plan.ranges().collect::<std::vec::Vec<_>>(),
+            [(0, 10), (50, 500)]
+        );
+
+        // Beyond capacity, the closest ranges are merged.
+        plan.add(&missing(&[(600, 10), (700, 10), (1000, 10)]));
+        assert_eq!(
+            plan.ranges().collect::<std::vec::Vec<_>>(),
+            [(0, 550), (600, 10), (700, 10), (1000, 10)]
+        );
+
+        assert_eq!(
+            plan.chunks(40).collect::<std::vec::Vec<_>>()[..5],
+            [(0, 40), (40, 40), (80, 40), (120, 40), (160, 40)]
+        );
+        assert_eq!(
+            plan.chunks(40).map(|(_, len)| len).sum::<u32>(),
+            550 + 10 + 10 + 10
+        );
+
+        let mut plan = RetransmitPlan::<1>::new();
+        plan.add(&missing(&[(0, 10), (100, 10)]));
+        assert_eq!(plan.ranges().collect::<std::vec::Vec<_>>(), [(0, 110)]);
+    }
+
+    #[test]
+    fn test_selective_retransmission() {
+        const CHUNK_SIZE: usize = 100;
+        let update = image(100 * CHUNK_SIZE);
+        let prepare_for_update = prepare(&update);
+
+        let mut seed = 1u32;
+        let mut random_loss = std::iter::from_fn(move || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            Some((seed >> 16).is_multiple_of(10))
+        });
+        let losses: [std::vec::Vec<bool>; 4] = [
+            (0..100).map(|i| i < 5).collect(),
+            (0..100).map(|i| i >= 93).collect(),
+            (0..100).map(|_| random_loss.next().unwrap()).collect(),
+            // More gaps than a receiver tracks.
+            (0..100).map(|i| i % 2 == 1).collect(),
+        ];
+
+        let mut servers = losses.map(|lost| {
+            let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update),
+            );
+            (receiver, vec![0; update.len()], lost)
+        });
+
+        let chunk = |offset: u32, len: u32| Update::<CHUNK_SIZE> {
+            byte_offset: offset,
+            bytes: Vec::from_slice(&update[offset as usize..(offset + len) as usize]).unwrap(),
+        };
+
+        for (i, offset) in (0..update.len()).step_by(CHUNK_SIZE).enumerate() {
+            for (receiver, staged, lost) in servers.iter_mut() {
+                if !lost[i] {
+                    receiver.receive(&chunk(offset as u32, CHUNK_SIZE as u32), stage(staged));
+                }
+            }
+        }
+        for (receiver, _, _) in &servers {
+            assert_eq!(receiver.state(), UpdateState::Receiving);
+        }
+        assert_eq!(
+            servers[0].0.missing_ranges(MAX_MISSING_RANGES).ranges[..],
+            [(0, 500)]
+        );
+        assert_eq!(
+            servers[1].0.missing_ranges(MAX_MISSING_RANGES).ranges[..],
+            [(9300, 700)]
+        );
+        assert_eq!(
+            servers[3].0.missing_ranges(2).ranges[..],
+            [(100, 100), (300, 100)]
+        );
+
+        let mut rounds = 0;
+        loop {
+            let mut plan = RetransmitPlan::<16>::new();
+            for (receiver, _, _) in &servers {
+                plan.add(&receiver.missing_ranges(MAX_MISSING_RANGES));
+            }
+            if plan.is_empty() {
+                break;
+            }
+            for (offset, len) in plan.chunks(CHUNK_SIZE as u32) {
+                for (receiver, staged, _) in servers.iter_mut() {
+                    receiver.receive(&chunk(offset, len), stage(staged));
+                }
+            }
+            rounds += 1;
+            assert!(rounds < 10);
+        }
+
+        for (receiver, staged, _) in servers.iter_mut() {
+            assert_eq!(receiver.state(), UpdateState::Verifying);
+            assert_eq!(receiver.verify(read(staged)), UpdateState::Staged);
+            assert_eq!(staged[..], update[..]);
+        }
+    }
+
+    #[test]
+    fn test_aborted_update() {
+        let update = [0x5a; 100];
+        let prepare_for_update = prepare(&update);
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        assert!(!receiver.abort(|_| panic!("nothing to erase")));
+
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+        let mut chunks = updates(&update);
+        receiver.receive(&chunks.next().unwrap(), discard);
+
+        let abort =
+            postcard::to_vec::<_, 2>(&UpdateMessage::<0>::abort(AbortReason::BadImage)).unwrap();
+        assert_eq!(abort[..], [1, 1]);
+        let UpdateMessage::<33>::Abort(abort) = postcard::from_bytes(&abort).unwrap() else {
+            panic!("expected an abort");
+        };
+        assert_eq!(abort.reason, AbortReason::BadImage);
+
+        let mut erased = None;
+        assert!(receiver.abort(|received| erased = Some(received)));
+        assert_eq!(erased, Some(33));
+        assert_eq!(receiver.state(), UpdateState::Aborted);
+        assert!(receiver.update_key().is_none());
+
+        // Subsequent chunks are ignored.
+        for chunk in chunks {
+            assert_eq!(receiver.receive(&chunk, discard), UpdateState::Aborted);
+        }
+        assert_eq!(receiver.verify(in_order), UpdateState::Aborted);
+        assert_eq!(receiver.status().received_bytes, 33);
+
+        // The aborted update cannot be prepared for again, but a fresh one can.
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Aborted
+        );
+        let prepare_for_update = PrepareForUpdate {
+            update_key: UpdateKey([2; 16]),
+            ..prepare(&update)
+        };
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Started
+        );
+        for chunk in updates(&update) {
+            receiver.receive(&chunk, discard);
+        }
+        assert_eq!(receiver.verify(in_order), UpdateState::Staged);
+    }
+
+    #[test]
+    fn test_committed_update() {
+        let update = [0x5a; 100];
+        let prepare_for_update = prepare(&update);
+        let commit = UpdateCommit {
+            version: "1.2.3".parse().unwrap(),
+            activate_delay_ticks: 100,
+            image_id: 0,
+        };
+        let staged = || {
+            let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update),
+            );
+            for chunk in updates(&update) {
+                receiver.receive(&chunk, discard);
+            }
+            receiver
+        };
+
+        // An update must be staged before it is committed.
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        assert_eq!(receiver.commit(&commit, 0), Err(CommitError::NotStaged));
+        let mut receiver = staged();
+        assert_eq!(receiver.commit(&commit, 0), Err(CommitError::NotStaged));
+        assert_eq!(receiver.verify(in_order), UpdateState::Staged);
+
+        // Only the version staged is committed.
+        let mismatched = UpdateCommit {
+            version: "1.2.4".parse().unwrap(),
+            ..commit.clone()
+        };
+        assert_eq!(
+            receiver.commit(&mismatched, 0),
+            Err(CommitError::VersionMismatch)
+        );
+        assert_eq!(receiver.state(), UpdateState::Staged);
+
+        // Servers receiving the commit at about the same time activate after
+        // the delay, and a repeated commit does not postpone activation.
+        let mut other = staged();
+        other.verify(in_order);
+        assert_eq!(receiver.commit(&commit, 1000), Ok(()));
+        assert_eq!(other.commit(&commit, 1001), Ok(()));
+        assert_eq!(receiver.commit(&commit, 1050), Ok(()));
+        assert_eq!(receiver.activate_at(), Some(1100));
+        assert_eq!(receiver.poll_activation(1099), UpdateState::Committing);
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Committing);
+        assert_eq!(other.poll_activation(1100), UpdateState::Committing);
+        assert_eq!(receiver.poll_activation(1100), UpdateState::ReadyToApply);
+        assert_eq!(other.poll_activation(1101), UpdateState::ReadyToApply);
+        assert_eq!(receiver.applied(same_epoch), UpdateState::Applied);
+        assert_eq!(receiver.current_version(), &commit.version);
+
+        // A staged update may be rolled back, including once committed.
+        let rollback = UpdateRollbackStaged {
+            version: "1.2.3".parse().unwrap(),
+            image_id: 0,
+        };
+        let mut receiver = staged();
+        assert_eq!(
+            receiver.rollback_staged(&rollback, |_| panic!("not staged")),
+            Err(CommitError::NotStaged)
+        );
+        receiver.verify(in_order);
+        receiver.commit(&commit, 0).unwrap();
+        assert_eq!(
+            receiver.rollback_staged(
+                &UpdateRollbackStaged {
+                    version: "1.2.4".parse().unwrap(),
+                    image_id: 0,
+                },
+                |_| panic!("mismatched")
+            ),
+            Err(CommitError::VersionMismatch)
+        );
+        assert_eq!(
+            receiver.rollback_staged(
+                &UpdateRollbackStaged {
+                    image_id: 1,
+                    ..rollback.clone()
+                },
+                |_| panic!("mismatched")
+            ),
+            Err(CommitError::ImageMismatch)
+        );
+        let mut erased = None;
+        assert_eq!(
+            receiver.rollback_staged(&rollback, |staged| erased = Some(staged)),
+            Ok(())
+        );
+        assert_eq!(erased, Some(100));
+        assert_eq!(receiver.state(), UpdateState::Aborted);
+        assert_eq!(receiver.poll_activation(100), UpdateState::Aborted);
+        assert_eq!(receiver.current_version(), &"1.2.0".parse().unwrap());
+        assert_eq!(receiver.commit(&commit, 0), Err(CommitError::NotStaged));
+    }
+
+    #[test]
+    fn test_status_poller() {
+        let version: Version = "1.2.3".parse().unwrap();
+        assert!(UpdateStatusPoller::<2>::new(version.clone(), &[1, 2, 3]).is_none());
+
+        let mut poller =
+            UpdateStatusPoller::<8>::new(version.clone(), &[1, 2, 3, 4, 5, 6]).unwrap();
+        let status = |state, current_version: &str, last_error| UpdateStatus {
+            state,
+            received_bytes: 100,
+            current_version: current_version.parse().unwrap(),
+            last_error,
+            image_id: 0,
+            pacing: None,
+        };
+
+        assert_eq!(
+            poller.poll_transmit(),
+            Some((1, UpdateStatusRequest::default()))
+        );
+        poller.handle_reply(1, status(UpdateState::Applied, "1.2.3", None));
+        assert_eq!(
+            poller.poll_transmit(),
+            Some((2, UpdateStatusRequest::default()))
+        );
+        poller.handle_reply(
+            2,
+            status(
+                UpdateState::Failed,
+                "1.2.0",
+                Some(VerificationError::BadSignature),
+            ),
+        );
+        assert_eq!(
+            poller.poll_transmit(),
+            Some((3, UpdateStatusRequest::default()))
+        );
+        poller.handle_reply(3, status(UpdateState::Staged, "1.2.0", None));
+        assert_eq!(
+            poller.poll_transmit(),
+            Some((4, UpdateStatusRequest::default()))
+        );
+        poller.handle_reply(4, status(UpdateState::Idle, "1.2.0", None));
+        assert_eq!(
+            poller.poll_transmit(),
+            Some((5, UpdateStatusRequest::default()))
+        );
+        poller.handle_reply(5, status(UpdateState::Aborted, "1.2.0", None));
+        assert!(!poller.is_complete());
+        assert_eq!(
+            poller.poll_transmit(),
+            Some((6, UpdateStatusRequest::default()))
+        );
+        assert_eq!(poller.poll_transmit(), None);
+        assert!(poller.is_complete());
+
+        // Server 6 did not reply, and a reply from an unknown server is ignored
+        // as is one about another image.
+        poller.handle_reply(7, status(UpdateState::Applied, "1.2.3", None));
+        poller.handle_reply(
+            6,
+            UpdateStatus {
+                image_id: 1,
+                ..status(UpdateState::Applied, "1.2.3", None)
+            },
+        );
+
+        let outcomes = poller
+            .report()
+            .iter()
+            .map(|r| (r.server_address, r.outcome))
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                (1, UpdateOutcome::Updated),
+                (
+                    2,
+                    UpdateOutcome::Failed(Some(VerificationError::BadSignature))
+                ),
+                (3, UpdateOutcome::InProgress(UpdateState::Staged)),
+                (4, UpdateOutcome::NotUpdated),
+                (5, UpdateOutcome::Aborted),
+                (6, UpdateOutcome::Unresponsive),
+            ]
+        );
+        assert_eq!(poller.report()[5].status, None);
+        assert_eq!(poller.resume_from(), None);
+    }
+
+    #[test]
+    fn test_update_pacing() {
+        let pacing = UpdatePacing {
+            chunk_size: 64,
+            threshold_bytes: 4096,
+            processing_ticks: 100,
+            chunk_ticks: 12,
+        };
+        assert_eq!(pacing.for_servers(&[]), pacing);
+
+        // The smallest chunk size and longest processing time are taken, and
+        // the client pauses on each server's threshold.
+        let servers = [
+            ServerPacing {
+                chunk_size: 48,
+                threshold_bytes: 2048,
+                processing_ticks: 80,
+            },
+            ServerPacing {
+                chunk_size: 96,
+                threshold_bytes: 4096,
+                processing_ticks: 150,
+            },
+            ServerPacing {
+                chunk_size: 64,
+                threshold_bytes: 0,
+                processing_ticks: 0,
+            },
+        ];
+        assert_eq!(
+            pacing.for_servers(&servers),
+            UpdatePacing {
+                chunk_size: 48,
+                threshold_bytes: 2048,
+                processing_ticks: 150,
+                chunk_ticks: 12,
+            }
+        );
+        let servers = [ServerPacing {
+            chunk_size: 64,
+            threshold_bytes: 3000,
+            processing_ticks: 100,
+        }];
+        assert_eq!(pacing.for_servers(&servers).threshold_bytes, 8);
+    }
+
+    fn send<const N: usize>(sender: &mut UpdateSender<N>) -> std::vec::Vec<(u32, usize, u32)> {
+        let mut sent = std::vec::Vec::new();
+        for step in sender.by_ref() {
+            match step {
+                SendStep::Transmit(update, ticks) => {
+                    sent.push((update.byte_offset, update.bytes.len(), ticks))
+                }
+                SendStep::Finished => break,
+            }
+        }
+        sent
+    }
+
+    type AesCcm = ccm::Ccm<aes::Aes128, ccm::consts::U4, ccm::consts::U7>;
+
+    const DATAGRAM_SIZE: usize = 64;
+
+    #[derive(Default)]
+    struct InMemoryStaging {
+        bytes: std::vec::Vec<u8>,
+        flushes: usize,
+        finalized: bool,
+    }
+
+    impl StagingWriter for InMemoryStaging {
+        fn write(&mut self, offset: u32, bytes: &[u8]) {
+            let (start, end) = (offset as usize, offset as usize + bytes.len());
+            if self.bytes.len() < end {
+                self.bytes.resize(end, 0);
+            }
+            self.bytes[start..end].copy_from_slice(bytes);
+        }
+
+        fn flush(&mut self) {
+            self.flushes += 1;
+        }
+
+        fn finalize(&mut self) {
+            self.finalized = true;
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.bytes[offset..offset + buf.len()]);
+        }
+    }
+
+    fn datagram<const N: usize>(
+        update_key: &UpdateKey,
+        message: &UpdateMessage<N>,
+    ) -> [u8; DATAGRAM_SIZE] {
+        let cipher = AesCcm::new_from_slice(&update_key.0).unwrap();
+        let header = crate::Header {
+            version: 0,
+            source: DataSource::Client,
+            server_address: 0,
+            server_port: Port::new(1).unwrap(),
+            frame_counter: 0,
+        };
+        let mut datagram_buf = [0; DATAGRAM_SIZE];
+        to_update_datagram(
+            &cipher,
+            &header,
+            &postcard::to_vec::<_, DATAGRAM_SIZE>(message).unwrap(),
+            &mut datagram_buf,
+        );
+        datagram_buf
+    }
+
+    fn chunk(update: &[u8], i: usize) -> [u8; DATAGRAM_SIZE] {
+        let bytes = update.chunks(16).nth(i).unwrap();
+        datagram(
+            &UpdateKey([1; 16]),
+            &UpdateMessage::<16>::Update(Update {
+                byte_offset: (i * 16) as u32,
+                bytes: Vec::from_slice(bytes).unwrap(),
+            }),
+        )
+    }
+
+    fn handle(
+        receiver: &mut UpdateReceiver,
+        staging: &mut InMemoryStaging,
+        datagram: &[u8; DATAGRAM_SIZE],
+    ) -> UpdateEvent<16> {
+        receiver.handle_datagram::<AesCcm, 16, DATAGRAM_SIZE>(datagram, staging)
+    }
+
+    #[test]
+    fn test_update_nonce_domain() {
+        // An update key that is mistakenly the same as a network key.
+        let cipher = AesCcm::new_from_slice(b"0123456789ABCDEF").unwrap();
+        let header = crate::Header {
+            version: 0,
+            source: DataSource::Client,
+            server_address: 0,
+            server_port: Port::new(1).unwrap(),
+            frame_counter: 7,
+        };
+        let payload = b"some data";
+
+        let mut network_datagram = [0; DATAGRAM_SIZE];
+        crate::to_datagram(&cipher, &header, payload, &mut network_datagram);
+        let mut update_datagram = [0; DATAGRAM_SIZE];
+        to_update_datagram(&cipher, &header, payload, &mut update_datagram);
+        assert_eq!(network_datagram[..5], update_datagram[..5]);
+        assert_ne!(network_datagram, update_datagram);
+
+        let (_, decrypted) = from_update_datagram(&update_datagram, |_| true, &cipher).unwrap();
+        assert_eq!(decrypted, payload);
+        assert_eq!(
+            from_update_datagram(&network_datagram, |_| true, &cipher),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+        assert_eq!(
+            crate::from_datagram(&update_datagram, |_| true, &cipher),
+            Err(FromDatagramError::CannotDecrypt)
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let update = (0..100).collect::<std::vec::Vec<u8>>();
+        let prepare_for_update = prepare(&update);
+        let dry_run = PrepareForUpdate {
+            dry_run: true,
+            ..prepare(&update)
+        };
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        let mut staging = InMemoryStaging::default();
+
+        // A dry run is reported as eligible without the update being prepared
+        // for, and so none of it is received even under the key given.
+        assert_eq!(
+            receiver.prepare(&dry_run, UpdateVerifier::new(&dry_run)),
+            Preparation::DryRun(UpdateEligibility::Eligible)
+        );
+        assert_eq!(
+            receiver
+                .eligibility(PortSet::from_bits(0b100), |_| None)
+                .ports,
+            [PortEligibility {
+                port: Port::new(2).unwrap(),
+                decline: None
+            }]
+        );
+        assert_eq!(receiver.state(), UpdateState::Idle);
+        assert!(receiver.update_key().is_none());
+        for i in 0..7 {
+            assert!(matches!(
+                handle(&mut receiver, &mut staging, &chunk(&update, i)),
+                UpdateEvent::Ignored
+            ));
+        }
+        let mut stored = false;
+        assert_eq!(
+            receiver.receive(
+                &Update::<16> {
+                    byte_offset: 0,
+                    bytes: Vec::from_slice(&update[..16]).unwrap(),
+                },
+                |_, _| stored = true
+            ),
+            UpdateState::Idle
+        );
+        assert!(!stored);
+        assert!(staging.bytes.is_empty());
+
+        // Ineligible updates are reported as such.
+        let not_newer = PrepareForUpdate {
+            version: "1.2.0".parse().unwrap(),
+            dry_run: true,
+            ..prepare(&update)
+        };
+        assert_eq!(
+            receiver.prepare(&not_newer, UpdateVerifier::new(&not_newer)),
+            Preparation::DryRun(UpdateEligibility::NotNewer)
+        );
+
+        // The update is then prepared for as normal, with a dry run leaving it
+        // unaffected once in progress.
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Started
+        );
+        for i in 0..3 {
+            handle(&mut receiver, &mut staging, &chunk(&update, i));
+        }
+        assert_eq!(
+            receiver.prepare(&dry_run, UpdateVerifier::new(&dry_run)),
+            Preparation::DryRun(UpdateEligibility::Eligible)
+        );
+        assert_eq!(receiver.state(), UpdateState::Receiving);
+        for i in 3..7 {
+            handle(&mut receiver, &mut staging, &chunk(&update, i));
+        }
+        assert_eq!(receiver.state(), UpdateState::Staged);
+        assert_eq!(staging.bytes, update);
+    }
+
+    #[test]
+    fn test_eligibility_survey() {
+        assert!(EligibilitySurvey::<2>::new(0, &[1, 2, 3]).is_none());
+
+        let report = |image_id, decline| EligibilityReport {
+            image_id,
+            ports: Vec::from_slice(&[PortEligibility {
+                port: Port::new(2).unwrap(),
+                decline,
+            }])
+            .unwrap(),
+        };
+        let mut survey = EligibilitySurvey::<4>::new(1, &[1, 2, 3]).unwrap();
+        let mut surveyed = std::vec::Vec::new();
+        while let Some((server_address, request)) = survey.poll_transmit() {
+            assert_eq!(
+                request,
+                UpdateStatusRequest {
+                    missing_ranges: None,
+                    image_id: 1,
+                    eligibility: true,
+                }
+            );
+            surveyed.push(server_address);
+            match server_address {
+                1 => survey.handle_reply(1, report(1, None)),
+                // A reply of another image is ignored.
+                2 => survey.handle_reply(2, report(0, None)),
+                _ => survey.handle_reply(3, report(1, Some(DeclineReason::NotNewer))),
+            }
+        }
+        // Replies from servers not surveyed are ignored.
+        survey.handle_reply(4, report(1, None));
+        assert!(survey.is_complete());
+        assert_eq!(surveyed, [1, 2, 3]);
+        assert_eq!(
+            survey.survey(),
+            [
+                (1, Some(report(1, None))),
+                (2, None),
+                (3, Some(report(1, Some(DeclineReason::NotNewer)))),
+            ]
+        );
+
+        let tally = survey.tally(EligibilityTally::new(50));
+        assert_eq!(
+            (tally.accepting(), tally.partial(), tally.declining()),
+            (1, 0, 1)
+        );
+        assert!(!tally.proceed(survey.survey().len()));
+        assert!(survey.tally(EligibilityTally::new(33)).proceed(3));
+    }
+
+    #[test]
+    fn test_handle_datagram() {
+        let update = (0..100).collect::<std::vec::Vec<u8>>();
+        let prepare_for_update = prepare(&update);
+        let mut receiver =
+            UpdateReceiver::new("1.2.0".parse().unwrap()).with_pacing(ServerPacing {
+                chunk_size: 16,
+                threshold_bytes: 32,
+                processing_ticks: 10,
+            });
+        let mut staging = InMemoryStaging::default();
+
+        // There is no update key to decrypt with until prepared.
+        assert!(matches!(
+            handle(&mut receiver, &mut staging, &chunk(&update, 0)),
+            UpdateEvent::Ignored
+        ));
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+
+        // Flushing once bytes in order reach each threshold, whether exactly
+        // or having filled a gap that crosses it, and with the final chunk
+        // being partial.
+        for (i, received, flushed) in [
+            (0, 16, false),
+            (2, 16, false),
+            (1, 48, true),
+            (3, 64, true),
+            (4, 80, false),
+            (5, 96, true),
+        ] {
+            match handle(&mut receiver, &mut staging, &chunk(&update, i)) {
+                UpdateEvent::Flushed { received_bytes } if flushed => {
+                    assert_eq!(received_bytes, received)
+                }
+                UpdateEvent::Progress { received_bytes } if !flushed => {
+                    assert_eq!(received_bytes, received)
+                }
+                _ => panic!("unexpected event for chunk {i}"),
+            }
+        }
+        assert!(!staging.finalized);
+        assert_eq!(staging.flushes, 3);
+
+        // Another key, and so another update.
+        let other = datagram(
+            &UpdateKey([2; 16]),
+            &UpdateMessage::<16>::abort(AbortReason::Cancelled),
+        );
+        assert!(matches!(
+            handle(&mut receiver, &mut staging, &other),
+            UpdateEvent::Ignored
+        ));
+
+        assert!(matches!(
+            handle(&mut receiver, &mut staging, &chunk(&update, 6)),
+            UpdateEvent::Staged
+        ));
+        assert!(staging.finalized);
+        assert_eq!(staging.flushes, 3);
+        assert_eq!(staging.bytes, update);
+        assert_eq!(receiver.state(), UpdateState::Staged);
+
+        // Bytes are no longer received, though other messages are conveyed.
+        assert!(matches!(
+            handle(&mut receiver, &mut staging, &chunk(&update, 0)),
+            UpdateEvent::Ignored
+        ));
+        let commit = datagram(
+            &UpdateKey([1; 16]),
+            &UpdateMessage::<16>::commit("1.2.3".parse().unwrap(), 0),
+        );
+        assert!(matches!(
+            handle(&mut receiver, &mut staging, &commit),
+            UpdateEvent::Message(UpdateMessage::Commit(_))
+        ));
+    }
+
+    #[test]
+    fn test_handle_datagram_abandoned() {
+        let update = [0x5a; 100];
+        let prepare_for_update = prepare(&update);
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        let mut staging = InMemoryStaging::default();
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+
+        // Without pacing, bytes are not flushed until finalized.
+        for i in 0..6 {
+            assert!(matches!(
+                receiver
+                    .handle_datagram::<AesCcm, 16, DATAGRAM_SIZE>(&chunk(&update, i), &mut staging),
+                UpdateEvent::Progress { .. }
+            ));
+        }
+        assert_eq!(staging.flushes, 0);
+
+        // The final bytes are not those of the update.
+        assert!(matches!(
+            handle(&mut receiver, &mut staging, &chunk(&[0xa5; 100], 6)),
+            UpdateEvent::Abandoned(VerificationError::DigestMismatch)
+        ));
+        assert!(staging.finalized);
+        assert_eq!(receiver.state(), UpdateState::Failed);
+
+        // Bytes beyond the end of the update.
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+        let beyond = datagram(
+            &UpdateKey([1; 16]),
+            &UpdateMessage::<16>::Update(Update {
+                byte_offset: 96,
+                bytes: Vec::from_slice(&[0x5a; 16]).unwrap(),
+            }),
+        );
+        assert!(matches!(
+            handle(&mut receiver, &mut staging, &beyond),
+            UpdateEvent::Abandoned(VerificationError::UnexpectedOffset {
+                expected: 0,
+                received: 96
+            })
+        ));
+    }
+
+    #[test]
+    fn test_update_sender() {
+        let update = image(250);
+        let update_key = UpdateKey([1; 16]);
+        let pacing = UpdatePacing {
+            chunk_size: 40,
+            threshold_bytes: 100,
+            processing_ticks: 100,
+            chunk_ticks: 12,
+        };
+
+        // Chunks never straddle a threshold, with processing awaited at each
+        // and at the end.
+        let mut sender = UpdateSender::<33>::new(&update, &update_key, 0, pacing);
+        assert_eq!(
+            send(&mut sender),
+            [
+                (0, 33, 12),
+                (33, 33, 12),
+                (66, 33, 12),
+                (99, 1, 100),
+                (100, 33, 12),
+                (133, 33, 12),
+                (166, 33, 12),
+                (199, 1, 100),
+                (200, 33, 12),
+                (233, 17, 100),
+            ]
+        );
+        assert_eq!(sender.byte_offset(), 250);
+        assert_eq!(sender.next(), None);
+
+        // Resuming part way through a threshold, with a smaller chunk size.
+        let mut sender = UpdateSender::<64>::new(&update, &update_key, 180, pacing);
+        assert_eq!(
+            send(&mut sender),
+            [(180, 20, 100), (200, 40, 12), (240, 10, 100)]
+        );
+
+        // Without a threshold, processing is awaited at the end only.
+        let mut sender = UpdateSender::<64>::new(
+            &update[..100],
+            &update_key,
+            0,
+            UpdatePacing {
+                threshold_bytes: 0,
+                ..pacing
+            },
+        );
+        assert_eq!(
+            send(&mut sender),
+            [(0, 40, 12), (40, 40, 12), (80, 20, 100)]
+        );
+
+        // The bytes sent are those of the update, in order.
+        let sender = UpdateSender::<33>::new(&update, &update_key, 0, pacing);
+        let sent = sender
+            .flat_map(|step| match step {
+                SendStep::Transmit(update, _) => update.bytes.to_vec(),
+                SendStep::Finished => std::vec::Vec::new(),
+            })
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(sent, update);
+    }
+
+    #[test]
+    fn test_update_sender_chunks() {
+        // Chunked a threshold at a time, and then a chunk at a time within
+        // it, with the last chunk of each being partial.
+        fn expected(len: u32, chunk_size: u32, threshold: u32) -> std::vec::Vec<(u32, usize, u32)> {
+            let mut chunks = std::vec::Vec::new();
+            for window in (0..len).step_by(threshold as usize) {
+                let window_end = (window + threshold).min(len);
+                for offset in (window..window_end).step_by(chunk_size as usize) {
+                    let end = (offset + chunk_size).min(window_end);
+                    let ticks = if end == window_end { 100 } else { 12 };
+                    chunks.push((offset, (end - offset) as usize, ticks));
+                }
+            }
+            chunks
+        }
+
+        let update_key = UpdateKey([1; 16]);
+        for len in [0, 1, 32, 33, 99, 100, 101, 250, 300, 1000, 1023] {
+            let update = image(len);
+            for (chunk_size, threshold) in [(33, 100), (25, 100), (33, 66), (33, 4096)] {
+                let mut sender = UpdateSender::<33>::new(
+                    &update,
+                    &update_key,
+                    0,
+                    UpdatePacing {
+                        chunk_size,
+                        threshold_bytes: threshold,
+                        processing_ticks: 100,
+                        chunk_ticks: 12,
+                    },
+                );
+                assert_eq!(
+                    send(&mut sender),
+                    expected(len as u32, chunk_size as u32, threshold),
+                    "{len} bytes in chunks of {chunk_size} with a threshold of {threshold}"
+                );
+            }
+        }
+    }
+
+    // The constants of the update example, with its chunk size being that
+    // fitting the smallest payload.
+    const EXAMPLE_UPDATE_LEN: u32 = 100 * 1024;
+    const EXAMPLE_CHUNK_SIZE: usize = crate::discovery::MIN_PAYLOAD_SIZE - UPDATE_BYTES_OVERHEAD;
+    const EXAMPLE_PACING: UpdatePacing = UpdatePacing {
+        chunk_size: EXAMPLE_CHUNK_SIZE as u8,
+        threshold_bytes: 4096,
+        processing_ticks: 100,
+        chunk_ticks: 12,
+    };
+
+    #[test]
+    fn test_update_estimate() {
+        // 25 thresholds of 4096 bytes, each sent as 164 chunks of up to 25
+        // bytes, with 163 of them followed by 12 ticks and the last by 100.
+        assert_eq!(EXAMPLE_CHUNK_SIZE, 25);
+        let estimate = UpdateEstimate::new(EXAMPLE_UPDATE_LEN, EXAMPLE_PACING);
+        assert_eq!(estimate.ticks_from(0), 25 * (163 * 12 + 100));
+        assert_eq!(estimate.total_ticks(), 51_400);
+
+        // 1% is 1,024 bytes, being 41 chunks, each followed by processing.
+        let estimate = estimate.with_retransmit_percent(1);
+        assert_eq!(estimate.retransmit_ticks(), 41 * 100);
+        assert_eq!(estimate.total_ticks(), 51_400 + 4_100);
+
+        // Resuming part way through the last threshold, and from the end.
+        assert_eq!(estimate.ticks_from(100 * 1024 - 100), 3 * 12 + 100);
+        assert_eq!(estimate.ticks_from(EXAMPLE_UPDATE_LEN), 0);
+
+        // A 64 byte datagram is 5ms on the wire at 115,200 baud.
+        let link = LinkTiming {
+            bits_per_second: 115200,
+            bits_per_byte: 9,
+        };
+        assert_eq!(EXAMPLE_PACING.on_link(&link, 64, 1000).chunk_ticks, 5);
+
+        // The estimate is that of the pauses yielded by a sender.
+        let update_key = UpdateKey([1; 16]);
+        for len in [0, 1, 32, 33, 99, 100, 101, 250, 1023] {
+            let update = image(len);
+            for (chunk_size, threshold) in [(33, 100), (40, 100), (25, 66), (33, 0)] {
+                for byte_offset in [0, 50, 100] {
+                    let pacing = UpdatePacing {
+                        chunk_size,
+                        threshold_bytes: threshold,
+                        ..EXAMPLE_PACING
+                    };
+                    let mut sender =
+                        UpdateSender::<33>::new(&update, &update_key, byte_offset, pacing);
+                    let estimate = sender.estimate();
+                    let ticks = send(&mut sender)
+                        .iter()
+                        .map(|(_, _, ticks)| *ticks as u64)
+                        .sum::<u64>();
+                    assert_eq!(
+                        estimate.ticks_from(byte_offset),
+                        ticks,
+                        "{len} bytes from {byte_offset} in chunks of {chunk_size} with a threshold of {threshold}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_progress() {
+        let update = image(250);
+        let update_key = UpdateKey([1; 16]);
+        let pacing = UpdatePacing {
+            chunk_size: 40,
+            threshold_bytes: 100,
+            processing_ticks: 100,
+            chunk_ticks: 12,
+        };
+
+        let mut progress = std::vec::Vec::new();
+        let mut on_progress =
+            |sent, of_total, eta_ticks| progress.push((sent, of_total, eta_ticks));
+        let mut sender = UpdateSender::<64>::new(&update, &update_key, 180, pacing)
+            .on_progress(&mut on_progress);
+        assert_eq!(sender.estimate().ticks_from(180), 100 + 12 + 100);
+        while let Some(SendStep::Transmit(_, _)) = sender.next() {}
+
+        assert_eq!(
+            progress,
+            [
+                (200, 250, 100 + 12 + 100),
+                (240, 250, 12 + 100),
+                (250, 250, 100)
+            ]
+        );
+    }
+
+    enum ToServer {
+        Datagram([u8; DATAGRAM_SIZE]),
+        MissingRanges(tokio::sync::oneshot::Sender<MissingRanges>),
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_update_estimate_accuracy() {
+        use tokio::{sync::mpsc, time};
+
+        let update = image(EXAMPLE_UPDATE_LEN as usize);
+        let prepare_for_update = prepare(&update);
+
+        // A server missing 1 in every 256 updates, leaving as many gaps as
+        // it tracks.
+        let (tx, mut rx) = mpsc::channel(1);
+        let server_prepare_for_update = prepare_for_update.clone();
+        let server = tokio::spawn(async move {
+            let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+            receiver.prepare(
+                &server_prepare_for_update,
+                UpdateVerifier::new(&server_prepare_for_update),
+            );
+            let mut staging = InMemoryStaging::default();
+            let mut received = 0;
+            while let Some(message) = rx.recv().await {
+                match message {
+                    ToServer::Datagram(datagram) => {
+                        received += 1;
+                        if received % 256 != 0 {
+                            receiver.handle_datagram::<AesCcm, EXAMPLE_CHUNK_SIZE, DATAGRAM_SIZE>(
+                                &datagram,
+                                &mut staging,
+                            );
+                        }
+                    }
+                    ToServer::MissingRanges(reply) => {
+                        let _ = reply.send(receiver.missing_ranges(MAX_MISSING_RANGES));
+                    }
+                }
+            }
+            receiver.state()
+        });
+
+        let started = time::Instant::now();
+
+        // The client sleeps for the ticks of each step, being milliseconds.
+        let mut sender = UpdateSender::<EXAMPLE_CHUNK_SIZE>::new(
+            &update,
+            &prepare_for_update.update_key,
+            0,
+            EXAMPLE_PACING,
+        );
+        let estimate = sender.estimate().with_retransmit_percent(1);
+        let mut datagram_buf = [0; DATAGRAM_SIZE];
+        while let Some(SendStep::Transmit(update, ticks)) = sender.next() {
+            sender.to_datagram::<AesCcm, DATAGRAM_SIZE>(
+                Port::new(1).unwrap(),
+                update,
+                &mut datagram_buf,
+            );
+            tx.send(ToServer::Datagram(datagram_buf)).await.unwrap();
+            time::sleep(time::Duration::from_millis(ticks as u64)).await;
+        }
+
+        // Missing ranges are then retransmitted, each chunk followed by
+        // processing.
+        loop {
+            let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+            tx.send(ToServer::MissingRanges(reply_tx)).await.unwrap();
+            let mut plan = RetransmitPlan::<16>::new();
+            plan.add(&reply_rx.await.unwrap());
+            if plan.is_empty() {
+                break;
+            }
+            for (byte_offset, len) in plan.chunks(EXAMPLE_CHUNK_SIZE as u32) {
+                let (from, to) = (byte_offset as usize, (byte_offset + len) as usize);
+                let message = UpdateMessage::<EXAMPLE_CHUNK_SIZE>::Update(Update {
+                    byte_offset,
+                    bytes: Vec::from_slice(&update[from..to]).unwrap(),
+                });
+                tx.send(ToServer::Datagram(datagram(
+                    &prepare_for_update.update_key,
+                    &message,
+                )))
+                .await
+                .unwrap();
+                time::sleep(time::Duration::from_millis(
+                    EXAMPLE_PACING.processing_ticks as u64,
+                ))
+                .await;
+            }
+        }
+
+        let elapsed = started.elapsed().as_millis() as u64;
+        drop(tx);
+        assert_eq!(server.await.unwrap(), UpdateState::Staged);
+
+        // Within 5% of the estimate.
+        let estimated = estimate.total_ticks();
+        assert!(
+            elapsed.abs_diff(estimated) * 100 <= estimated * 5,
+            "{elapsed} ticks elapsed given an estimate of {estimated}"
+        );
+    }
+
+    #[test]
+    fn test_update_sender_datagrams() {
+        let update = image(250);
+        let prepare_for_update = prepare(&update);
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+        let mut staging = InMemoryStaging::default();
+
+        let mut sender = UpdateSender::<16>::new(
+            &update,
+            &prepare_for_update.update_key,
+            0,
+            UpdatePacing {
+                chunk_size: 16,
+                threshold_bytes: 64,
+                processing_ticks: 100,
+                chunk_ticks: 12,
+            },
+        )
+        .with_frame_counter(u16::MAX);
+        let mut datagram_buf = [0; DATAGRAM_SIZE];
+        let mut sent = 0;
+        while let Some(SendStep::Transmit(update, _)) = sender.next() {
+            sender.to_datagram::<AesCcm, DATAGRAM_SIZE>(
+                Port::new(1).unwrap(),
+                update,
+                &mut datagram_buf,
+            );
+            sent += 1;
+            let event = handle(&mut receiver, &mut staging, &datagram_buf);
+            assert!(!matches!(
+                event,
+                UpdateEvent::Ignored | UpdateEvent::Abandoned(_)
+            ));
+        }
+        assert_eq!(receiver.state(), UpdateState::Staged);
+        assert_eq!(staging.bytes, update);
+        assert_eq!(sender.frame_counter(), (sent - 1) as u16);
+    }
+
+    #[test]
+    fn test_multi_image_update() {
+        let application = image(500);
+        let coprocessor = image(300).iter().map(|b| !b).collect::<std::vec::Vec<_>>();
+        let prepare_application = prepare(&application);
+        let prepare_coprocessor = PrepareForUpdate {
+            image_id: 2,
+            update_key: UpdateKey([2; 16]),
+            ..prepare(&coprocessor)
+        };
+
+        // A receiver of a single image declines the updates of others.
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        assert_eq!(
+            receiver.prepare(
+                &prepare_coprocessor,
+                UpdateVerifier::new(&prepare_coprocessor)
+            ),
+            Preparation::OtherImage
+        );
+
+        let mut receiver = MultiImageReceiver::<2>::new();
+        let version = || "1.2.0".parse().unwrap();
+        assert!(receiver.add_image(UpdateReceiver::new(version())));
+        assert!(!receiver.add_image(UpdateReceiver::new(version())));
+        assert!(receiver.add_image(UpdateReceiver::for_image(2, version())));
+        assert!(!receiver.add_image(UpdateReceiver::for_image(3, version())));
+
+        let prepare_other = PrepareForUpdate {
+            image_id: 3,
+            ..prepare(&coprocessor)
+        };
+        assert_eq!(
+            receiver.prepare(&prepare_other, UpdateVerifier::new(&prepare_other)),
+            Preparation::OtherImage
+        );
+        assert_eq!(
+            receiver.prepare(
+                &prepare_application,
+                UpdateVerifier::new(&prepare_application)
+            ),
+            Preparation::Started
+        );
+        let prepare_same_key = PrepareForUpdate {
+            image_id: 2,
+            ..prepare(&coprocessor)
+        };
+        assert_eq!(
+            receiver.prepare(&prepare_same_key, UpdateVerifier::new(&prepare_same_key)),
+            Preparation::KeyInUse
+        );
+        assert_eq!(
+            receiver.prepare(
+                &prepare_coprocessor,
+                UpdateVerifier::new(&prepare_coprocessor)
+            ),
+            Preparation::Started
+        );
+
+        // The chunks of each update are interleaved, and each is routed to
+        // the image whose key it is under.
+        let mut staged_application = std::vec![0; application.len()];
+        let mut staged_coprocessor = std::vec![0; coprocessor.len()];
+        let mut application_chunks = updates(&application).map(|u| (UpdateKey([1; 16]), u));
+        let mut coprocessor_chunks = updates(&coprocessor).map(|u| (UpdateKey([2; 16]), u));
+        loop {
+            let chunks = [application_chunks.next(), coprocessor_chunks.next()];
+            if chunks.iter().all(Option::is_none) {
+                break;
+            }
+            for (update_key, update) in chunks.into_iter().flatten() {
+                let (image_id, _) = receiver
+                    .update_keys()
+                    .find(|(_, k)| **k == update_key)
+                    .unwrap();
+                let staged = if image_id == 0 {
+                    &mut staged_application
+                } else {
+                    &mut staged_coprocessor
+                };
+                receiver
+                    .image_mut(image_id)
+                    .unwrap()
+                    .receive(&update, stage(staged));
+            }
+        }
+
+        for (image_id, staged) in [(0, &staged_application), (2, &staged_coprocessor)] {
+            let image = receiver.image_mut(image_id).unwrap();
+            assert_eq!(image.status().image_id, image_id);
+            assert_eq!(image.verify(read(staged)), UpdateState::Staged);
+        }
+        assert_eq!(staged_application, application);
+        assert_eq!(staged_coprocessor, coprocessor);
+
+        // Each image is committed independently.
+        let commit = UpdateCommit {
+            version: "1.2.3".parse().unwrap(),
+            activate_delay_ticks: 0,
+            image_id: 2,
+        };
+        assert_eq!(
+            receiver.image_mut(0).unwrap().commit(&commit, 0),
+            Err(CommitError::ImageMismatch)
+        );
+        let coprocessor_receiver = receiver.image_mut(2).unwrap();
+        assert_eq!(coprocessor_receiver.commit(&commit, 0), Ok(()));
+        assert_eq!(
+            coprocessor_receiver.poll_activation(0),
+            UpdateState::ReadyToApply
+        );
+        assert_eq!(
+            coprocessor_receiver.applied(same_epoch),
+            UpdateState::Applied
+        );
+        assert_eq!(
+            receiver
+                .images()
+                .map(|r| (r.image_id(), r.state()))
+                .collect::<std::vec::Vec<_>>(),
+            [(0, UpdateState::Staged), (2, UpdateState::Applied)]
+        );
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn test_update_key_zeroized_on_drop() {
+        fn zeroize_on_drop<T: zeroize::ZeroizeOnDrop>() {}
+        zeroize_on_drop::<UpdateKey>();
+
+        let mut key = core::mem::ManuallyDrop::new(UpdateKey([1; 16]));
+        // Safety: the key's bytes are only inspected once dropped.
+        unsafe { core::ptr::drop_in_place(&mut *key) };
+        assert_eq!(key.0, [0; 16]);
     }
 }
@@ -0,0 +1,357 @@
+//! Decompression of updates compressed with heatshrink, an LZSS variant
+//! suited to constrained devices. A compressed update is conveyed as a
+//! stream of bits where each token is either a tag bit of 1 followed by a
+//! literal byte, or a tag bit of 0 followed by the distance back into the
+//! bytes already decompressed and the number of bytes to copy from there.
+//! Each is conveyed less one and in the number of bits given by the window
+//! and lookahead of the [Compression]. Bits are taken from the most
+//! significant of each byte first, with the final byte padded with zeros.
+
+use super::{Compression, Update};
+
+/// The largest window, as a power of 2, that updates may be compressed
+/// with. Decompressing requires a buffer of the window's size.
+pub const MAX_WINDOW: u8 = 10;
+
+/// The smallest window, as a power of 2, that updates may be compressed
+/// with.
+pub const MIN_WINDOW: u8 = 4;
+
+/// The smallest lookahead, as a power of 2, that updates may be compressed
+/// with.
+pub const MIN_LOOKAHEAD: u8 = 3;
+
+const HISTORY_SIZE: usize = 1 << MAX_WINDOW;
+
+/// The compressed bytes refer to bytes before the start of the update, or
+/// decompress to more bytes than expected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DecompressionError;
+
+#[derive(Clone, Copy)]
+enum Token {
+    Tag,
+    Literal,
+    Index,
+    Count { index: u16 },
+}
+
+/// Decompresses an update as its compressed bytes are received, retaining
+/// those bits of a token that straddle the bytes given until the rest of
+/// them arrive.
+pub struct Decompressor {
+    window: u8,
+    lookahead: u8,
+    history: [u8; HISTORY_SIZE],
+    decompressed: u32,
+    bits: u32,
+    bit_count: u8,
+    token: Token,
+}
+
+impl Decompressor {
+    /// A decompressor for the compression given, if it is supported.
+    pub fn new(compression: Compression) -> Option<Self> {
+        let Compression::Heatshrink { window, lookahead } = compression else {
+            return None;
+        };
+        ((MIN_WINDOW..=MAX_WINDOW).contains(&window)
+            && (MIN_LOOKAHEAD..window).contains(&lookahead))
+        .then_some(Self {
+            window,
+            lookahead,
+            history: [0; HISTORY_SIZE],
+            decompressed: 0,
+            bits: 0,
+            bit_count: 0,
+            token: Token::Tag,
+        })
+    }
+
+    /// The number of bytes decompressed so far.
+    pub fn decompressed(&self) -> u32 {
+        self.decompressed
+    }
+
+    /// Decompress the bytes given, passing each byte decompressed to the
+    /// function given. The function returns false if it cannot accept the
+    /// byte, in which case decompression fails.
+    pub fn decompress<F>(&mut self, bytes: &[u8], mut output: F) -> Result<(), DecompressionError>
+    where
+        F: FnMut(u8) -> bool,
+    {
+        for byte in bytes {
+            self.bits = (self.bits << 8) | *byte as u32;
+            self.bit_count += 8;
+            while let Some(token) = self.next_token() {
+                match token {
+                    Token::Literal => {
+                        let b = self.take_bits(8) as u8;
+                        self.emit(b, &mut output)?;
+                    }
+                    Token::Count { index } => {
+                        let count = self.take_bits(self.lookahead) + 1;
+                        let distance = index as u32 + 1;
+                        if distance > self.decompressed {
+                            return Err(DecompressionError);
+                        }
+                        for _ in 0..count {
+                            let from = (self.decompressed - distance) as usize % HISTORY_SIZE;
+                            self.emit(self.history[from], &mut output)?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Advance through the tokens while there are enough bits, returning those
+    // that are ready to be emitted.
+    fn next_token(&mut self) -> Option<Token> {
+        loop {
+            match self.token {
+                Token::Tag if self.bit_count >= 1 => {
+                    self.token = if self.take_bits(1) == 1 {
+                        Token::Literal
+                    } else {
+                        Token::Index
+                    };
+                }
+                Token::Literal if self.bit_count >= 8 => {
+                    self.token = Token::Tag;
+                    return Some(Token::Literal);
+                }
+                Token::Index if self.bit_count >= self.window => {
+                    let index = self.take_bits(self.window) as u16;
+                    self.token = Token::Count { index };
+                }
+                Token::Count { index } if self.bit_count >= self.lookahead => {
+                    self.token = Token::Tag;
+                    return Some(Token::Count { index });
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn take_bits(&mut self, count: u8) -> u32 {
+        self.bit_count -= count;
+        let value = (self.bits >> self.bit_count) & ((1 << count) - 1);
+        self.bits &= (1 << self.bit_count) - 1;
+        value
+    }
+
+    fn emit<F>(&mut self, b: u8, output: &mut F) -> Result<(), DecompressionError>
+    where
+        F: FnMut(u8) -> bool,
+    {
+        if !output(b) {
+            return Err(DecompressionError);
+        }
+        self.history[self.decompressed as usize % HISTORY_SIZE] = b;
+        self.decompressed += 1;
+        Ok(())
+    }
+}
+
+/// Chunk a compressed update into [Update]s of up to N compressed bytes,
+/// each conveying the byte offset of the decompressed bytes that it
+/// begins with. Offsets are therefore determined by decompressing the
+/// update, which must be of a supported compression.
+pub fn compressed_updates<const N: usize>(
+    compression: Compression,
+    compressed: &[u8],
+) -> Option<impl Iterator<Item = Update<N>> + '_> {
+    let mut decompressor = Decompressor::new(compression)?;
+    Some(compressed.chunks(N).map(move |bytes| {
+        let byte_offset = decompressor.decompressed();
+        let _ = decompressor.decompress(bytes, |_| true);
+        Update {
+            byte_offset,
+            // Cannot fail given that the chunk is of N bytes at most.
+            bytes: heapless::Vec::from_slice(bytes).unwrap(),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::port::PortSet;
+    use crate::update::{
+        update_digest, PrepareForUpdate, UpdateIntegrity, UpdateKey, UpdateReceiver, UpdateState,
+        UpdateVerifier,
+    };
+
+    // A reference compressor, finding the longest match within the window
+    // for each byte.
+    fn compress(window: u8, lookahead: u8, input: &[u8]) -> std::vec::Vec<u8> {
+        let mut bits = std::vec::Vec::new();
+        let mut push = |value: u32, count: u8| {
+            for i in (0..count).rev() {
+                bits.push((value >> i) & 1 == 1);
+            }
+        };
+        let mut i = 0;
+        while i < input.len() {
+            let (mut best_distance, mut best_len) = (0, 0);
+            for distance in 1..=(1usize << window).min(i) {
+                let len = (0..(1usize << lookahead).min(input.len() - i))
+                    .take_while(|j| input[i + j] == input[i + j - distance])
+                    .count();
+                if len > best_len {
+                    (best_distance, best_len) = (distance, len);
+                }
+            }
+            if best_len > 1 {
+                push(0, 1);
+                push(best_distance as u32 - 1, window);
+                push(best_len as u32 - 1, lookahead);
+                i += best_len;
+            } else {
+                push(1, 1);
+                push(input[i] as u32, 8);
+                i += 1;
+            }
+        }
+        bits.chunks(8)
+            .map(|b| {
+                b.iter()
+                    .enumerate()
+                    .fold(0, |byte, (i, bit)| byte | ((*bit as u8) << (7 - i)))
+            })
+            .collect()
+    }
+
+    // A firmware-like image with runs, repetition and noise.
+    fn image() -> std::vec::Vec<u8> {
+        let mut seed = 1u32;
+        (0..5000u32)
+            .map(|i| match (i / 500) % 3 {
+                0 => 0xff,
+                1 => (i % 37) as u8,
+                _ => {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                }
+            })
+            .collect()
+    }
+
+    const COMPRESSION: Compression = Compression::Heatshrink {
+        window: 8,
+        lookahead: 4,
+    };
+
+    #[test]
+    fn test_decompress() {
+        let image = image();
+        let compressed = compress(8, 4, &image);
+        assert!(compressed.len() < image.len() * 3 / 4);
+
+        // The decompressed bytes are the same however the compressed bytes
+        // are split.
+        for chunk_size in [1, 3, 33, compressed.len()] {
+            let mut decompressor = Decompressor::new(COMPRESSION).unwrap();
+            let mut decompressed = std::vec::Vec::new();
+            for chunk in compressed.chunks(chunk_size) {
+                decompressor
+                    .decompress(chunk, |b| {
+                        decompressed.push(b);
+                        true
+                    })
+                    .unwrap();
+            }
+            assert_eq!(decompressed, image);
+        }
+    }
+
+    #[test]
+    fn test_bad_compression() {
+        assert!(Decompressor::new(Compression::None).is_none());
+        for (window, lookahead) in [(3, 2), (11, 4), (8, 2), (8, 8)] {
+            assert!(Decompressor::new(Compression::Heatshrink { window, lookahead }).is_none());
+        }
+
+        // A reference back before the first byte.
+        let mut decompressor = Decompressor::new(COMPRESSION).unwrap();
+        assert_eq!(
+            decompressor.decompress(&[0x00, 0x00], |_| true),
+            Err(DecompressionError)
+        );
+
+        // Output that cannot be accepted.
+        let mut decompressor = Decompressor::new(COMPRESSION).unwrap();
+        assert_eq!(
+            decompressor.decompress(&compress(8, 4, b"abc"), |_| false),
+            Err(DecompressionError)
+        );
+    }
+
+    #[test]
+    fn test_receive_compressed_update() {
+        let image = image();
+        let compressed = compress(8, 4, &image);
+
+        let prepare_for_update = PrepareForUpdate {
+            version: "1.2.3".parse().unwrap(),
+            server_ports: PortSet::from_bits(0b00000100),
+            image_id: 0,
+            security_epoch: 0,
+            update_key: UpdateKey([1; 16]),
+            update_byte_len: image.len() as u32,
+            integrity: UpdateIntegrity::Digest(update_digest(&image)),
+            compression: COMPRESSION,
+            delta: None,
+            hardware_rev: None,
+            dry_run: false,
+        };
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        receiver.prepare(
+            &prepare_for_update,
+            UpdateVerifier::new(&prepare_for_update),
+        );
+
+        let mut staged = std::vec![0; image.len()];
+        let mut stage = |offset: u32, bytes: &[u8]| {
+            staged[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes)
+        };
+
+        let updates = compressed_updates::<33>(COMPRESSION, &compressed)
+            .unwrap()
+            .collect::<std::vec::Vec<_>>();
+        let (first, rest) = updates.split_at(10);
+
+        // The updates that follow one that is missed are ignored until it is
+        // received again, as are those repeated.
+        for update in first.iter().chain(&rest[1..]) {
+            receiver.receive(update, &mut stage);
+        }
+        let missed_from = rest[0].byte_offset;
+        assert_eq!(
+            receiver.missing_ranges(8).ranges,
+            [(missed_from, image.len() as u32 - missed_from)]
+        );
+        assert_eq!(
+            receiver.receive(&first[9], &mut stage),
+            UpdateState::Receiving
+        );
+        assert_eq!(receiver.status().received_bytes, missed_from);
+
+        for update in rest {
+            receiver.receive(update, &mut stage);
+        }
+        assert_eq!(receiver.state(), UpdateState::Verifying);
+
+        // The bytes are hashed as decompressed, and so none are read back.
+        assert_eq!(
+            receiver.verify(|_, _| panic!("no bytes to read back")),
+            UpdateState::Staged
+        );
+        assert_eq!(staged, image);
+    }
+}
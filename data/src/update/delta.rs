@@ -0,0 +1,456 @@
+//! Delta updates, being patches of the image that a server is running. A
+//! patch is a sequence of operations, each an opcode followed by varint
+//! encoded arguments:
+//!
+//! * 0, length, bytes - insert the bytes given;
+//! * 1, offset, length - copy the bytes at the offset of the image being
+//!   patched.
+//!
+//! Lengths are never 0. Patches are applied as they are received, reading
+//! the image being patched via [ReadBase].
+
+use super::Update;
+
+/// The identifier of the copy/insert patch format described above.
+pub const COPY_INSERT_PATCH_FORMAT: u8 = 0;
+
+const INSERT: u8 = 0;
+const COPY: u8 = 1;
+
+// An opcode followed by two varints.
+const MAX_OP_HEADER_SIZE: usize = 1 + 5 + 5;
+
+// The number of bytes of the image being patched read at a time.
+const COPY_READ_SIZE: usize = 32;
+
+// A patch generated copies no fewer bytes than this, being where a copy is
+// smaller than inserting them.
+const MIN_COPY_LEN: usize = MAX_OP_HEADER_SIZE + 1;
+
+// How far a patch generated looks either side of where it expects the next
+// bytes to be copied from.
+const COPY_SEARCH_DISTANCE: usize = 512;
+
+/// Reads the image being patched e.g. from the flash memory a server runs
+/// its firmware from.
+pub trait ReadBase {
+    /// Fill the buffer with the bytes of the image at the offset given,
+    /// returning false if they are beyond the image.
+    fn read_base(&mut self, offset: u32, buf: &mut [u8]) -> bool;
+}
+
+impl ReadBase for &[u8] {
+    fn read_base(&mut self, offset: u32, buf: &mut [u8]) -> bool {
+        let offset = offset as usize;
+        match self.get(offset..offset + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The patch is malformed, refers to bytes beyond the image being patched,
+/// or produces more bytes than expected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PatchError;
+
+#[derive(Clone, Copy, Default)]
+struct Varint {
+    value: u32,
+    shift: u8,
+}
+
+impl Varint {
+    fn push(&mut self, b: u8) -> Result<Option<u32>, PatchError> {
+        if self.shift > 28 {
+            return Err(PatchError);
+        }
+        self.value |= ((b & 0x7f) as u32) << self.shift;
+        self.shift += 7;
+        Ok((b & 0x80 == 0).then_some(self.value))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Opcode,
+    InsertLen(Varint),
+    Insert { remaining: u32 },
+    CopyOffset(Varint),
+    CopyLen { offset: u32, len: Varint },
+}
+
+/// Applies a patch as its bytes are received, retaining those of an
+/// operation that straddle the bytes given until the rest of them arrive.
+pub struct Patcher {
+    op: Op,
+    patched: u32,
+}
+
+impl Default for Patcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Patcher {
+    pub fn new() -> Self {
+        Self {
+            op: Op::Opcode,
+            patched: 0,
+        }
+    }
+
+    /// The number of bytes of the patched image produced so far.
+    pub fn patched(&self) -> u32 {
+        self.patched
+    }
+
+    /// Apply the bytes of a patch given, reading the image being patched
+    /// from the base given and passing each byte of the patched image to the
+    /// function given. The function returns false if it cannot accept the
+    /// byte, in which case patching fails.
+    pub fn patch<B, F>(
+        &mut self,
+        bytes: &[u8],
+        base: &mut B,
+        mut output: F,
+    ) -> Result<(), PatchError>
+    where
+        B: ReadBase + ?Sized,
+        F: FnMut(u8) -> bool,
+    {
+        for b in bytes {
+            self.op = match self.op {
+                Op::Opcode => match *b {
+                    INSERT => Op::InsertLen(Varint::default()),
+                    COPY => Op::CopyOffset(Varint::default()),
+                    _ => return Err(PatchError),
+                },
+                Op::InsertLen(mut len) => match len.push(*b)? {
+                    Some(0) => return Err(PatchError),
+                    Some(remaining) => Op::Insert { remaining },
+                    None => Op::InsertLen(len),
+                },
+                Op::Insert { remaining } => {
+                    self.emit(*b, &mut output)?;
+                    if remaining > 1 {
+                        Op::Insert {
+                            remaining: remaining - 1,
+                        }
+                    } else {
+                        Op::Opcode
+                    }
+                }
+                Op::CopyOffset(mut offset) => match offset.push(*b)? {
+                    Some(offset) => Op::CopyLen {
+                        offset,
+                        len: Varint::default(),
+                    },
+                    None => Op::CopyOffset(offset),
+                },
+                Op::CopyLen { offset, mut len } => match len.push(*b)? {
+                    Some(0) => return Err(PatchError),
+                    Some(len) => {
+                        self.copy(offset, len, base, &mut output)?;
+                        Op::Opcode
+                    }
+                    None => Op::CopyLen { offset, len },
+                },
+            };
+        }
+        Ok(())
+    }
+
+    fn copy<B, F>(
+        &mut self,
+        mut offset: u32,
+        mut len: u32,
+        base: &mut B,
+        output: &mut F,
+    ) -> Result<(), PatchError>
+    where
+        B: ReadBase + ?Sized,
+        F: FnMut(u8) -> bool,
+    {
+        let mut buf = [0; COPY_READ_SIZE];
+        while len > 0 {
+            let read = (len as usize).min(COPY_READ_SIZE);
+            if !base.read_base(offset, &mut buf[..read]) {
+                return Err(PatchError);
+            }
+            for b in &buf[..read] {
+                self.emit(*b, output)?;
+            }
+            offset += read as u32;
+            len -= read as u32;
+        }
+        Ok(())
+    }
+
+    fn emit<F>(&mut self, b: u8, output: &mut F) -> Result<(), PatchError>
+    where
+        F: FnMut(u8) -> bool,
+    {
+        if !output(b) {
+            return Err(PatchError);
+        }
+        self.patched += 1;
+        Ok(())
+    }
+}
+
+/// Chunk a patch into [Update]s of N bytes, each conveying the byte offset
+/// of the patched image that it begins with. Offsets are therefore
+/// determined by applying the patch to the base given, being the image that
+/// servers are to patch.
+pub fn delta_updates<'a, const N: usize, B>(
+    patch: &'a [u8],
+    mut base: B,
+) -> impl Iterator<Item = Update<N>> + 'a
+where
+    B: ReadBase + 'a,
+{
+    // Each update must produce some bytes of the image so that no two have
+    // the same offset.
+    const { assert!(N > MAX_OP_HEADER_SIZE) };
+    let mut patcher = Patcher::new();
+    patch.chunks(N).map(move |bytes| {
+        let byte_offset = patcher.patched();
+        let _ = patcher.patch(bytes, &mut base, |_| true);
+        Update {
+            byte_offset,
+            // Cannot fail given that the chunk is of N bytes at most.
+            bytes: heapless::Vec::from_slice(bytes).unwrap(),
+        }
+    })
+}
+
+/// Generate a patch of a base image that produces the image given, passing
+/// the patch's bytes to the function given e.g. by the host that builds
+/// updates. Bytes of the image are copied from the base where they are found
+/// near to where the bytes before them were, which suits images that are
+/// changed in places, and are otherwise inserted.
+pub fn generate_patch<F>(base: &[u8], image: &[u8], mut out: F)
+where
+    F: FnMut(&[u8]),
+{
+    let mut op = |opcode: u8, args: &[u32], bytes: &[u8]| {
+        out(&[opcode]);
+        for arg in args {
+            let mut buf = [0; 5];
+            let mut len = 0;
+            let mut v = *arg;
+            loop {
+                buf[len] = (v & 0x7f) as u8;
+                v >>= 7;
+                len += 1;
+                if v == 0 {
+                    break;
+                }
+                buf[len - 1] |= 0x80;
+            }
+            out(&buf[..len]);
+        }
+        out(bytes);
+    };
+
+    let matching = |base_offset: usize, offset: usize| {
+        base[base_offset..]
+            .iter()
+            .zip(&image[offset..])
+            .take_while(|(a, b)| a == b)
+            .count()
+    };
+
+    let (mut offset, mut inserted_to, mut expected) = (0, 0, 0);
+    while offset < image.len() {
+        // The nearest copy to where the bytes are expected to be found.
+        let copy = (0..=COPY_SEARCH_DISTANCE)
+            .flat_map(|d| [expected + d, expected.wrapping_sub(d)])
+            .filter(|base_offset| *base_offset < base.len())
+            .map(|base_offset| (base_offset, matching(base_offset, offset)))
+            .find(|(_, len)| *len >= MIN_COPY_LEN.min(image.len() - offset));
+        match copy {
+            Some((base_offset, len)) => {
+                if inserted_to < offset {
+                    let bytes = &image[inserted_to..offset];
+                    op(INSERT, &[bytes.len() as u32], bytes);
+                }
+                op(COPY, &[base_offset as u32, len as u32], &[]);
+                offset += len;
+                inserted_to = offset;
+                expected = base_offset + len;
+            }
+            None => {
+                offset += 1;
+                expected += 1;
+            }
+        }
+    }
+    if inserted_to < offset {
+        let bytes = &image[inserted_to..offset];
+        op(INSERT, &[bytes.len() as u32], bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::port::PortSet;
+    use crate::update::{
+        update_digest, Compression, Delta, Preparation, PrepareForUpdate, UpdateIntegrity,
+        UpdateKey, UpdateReceiver, UpdateState, UpdateVerifier,
+    };
+
+    fn base() -> std::vec::Vec<u8> {
+        (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    // The base with some bytes changed, some removed and some added.
+    fn image() -> std::vec::Vec<u8> {
+        let base = base();
+        let mut image = base[..2000].to_vec();
+        image.extend_from_slice(&[0xaa; 100]);
+        image.extend_from_slice(&base[2100..6000]);
+        image.extend_from_slice(&base[6500..]);
+        image.extend_from_slice(b"some more bytes");
+        image
+    }
+
+    fn generate(base: &[u8], image: &[u8]) -> std::vec::Vec<u8> {
+        let mut patch = std::vec::Vec::new();
+        generate_patch(base, image, |bytes| patch.extend_from_slice(bytes));
+        patch
+    }
+
+    fn apply(patch: &[u8], base: &[u8]) -> Result<std::vec::Vec<u8>, PatchError> {
+        let mut image = std::vec::Vec::new();
+        Patcher::new().patch(patch, &mut &base[..], |b| {
+            image.push(b);
+            true
+        })?;
+        Ok(image)
+    }
+
+    #[test]
+    fn test_patch() {
+        let (base, image) = (base(), image());
+        let patch = generate(&base, &image);
+        assert!(patch.len() < 200);
+        assert_eq!(apply(&patch, &base), Ok(image.clone()));
+
+        // The patched image is the same however the patch is split.
+        let mut patcher = Patcher::new();
+        let mut patched = std::vec::Vec::new();
+        for chunk in patch.chunks(3) {
+            patcher
+                .patch(chunk, &mut &base[..], |b| {
+                    patched.push(b);
+                    true
+                })
+                .unwrap();
+        }
+        assert_eq!(patched, image);
+        assert_eq!(patcher.patched(), image.len() as u32);
+
+        // Patches of nothing in common, or of nothing at all.
+        assert_eq!(apply(&generate(&[], &image), &[]), Ok(image.clone()));
+        assert_eq!(apply(&generate(&base, &[]), &base), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_bad_patch() {
+        let base = base();
+        // An unknown opcode.
+        assert_eq!(apply(&[2], &base), Err(PatchError));
+        // Empty inserts and copies.
+        assert_eq!(apply(&[INSERT, 0], &base), Err(PatchError));
+        assert_eq!(apply(&[COPY, 0, 0], &base), Err(PatchError));
+        // A copy beyond the base.
+        assert_eq!(apply(&[COPY, 0x8f, 0x4e, 2], &base), Err(PatchError));
+        // A varint that overflows.
+        assert_eq!(
+            apply(&[INSERT, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], &base),
+            Err(PatchError)
+        );
+    }
+
+    #[test]
+    fn test_receive_delta_update() {
+        let (base, image) = (base(), image());
+        let patch = generate(&base, &image);
+
+        let prepare_for_update = PrepareForUpdate {
+            version: "1.2.3".parse().unwrap(),
+            server_ports: PortSet::from_bits(0b00000100),
+            image_id: 0,
+            security_epoch: 0,
+            update_key: UpdateKey([1; 16]),
+            update_byte_len: image.len() as u32,
+            integrity: UpdateIntegrity::Digest(update_digest(&image)),
+            compression: Compression::None,
+            delta: Some(Delta {
+                base_version: "1.2.0".parse().unwrap(),
+                patch_format: COPY_INSERT_PATCH_FORMAT,
+            }),
+            hardware_rev: None,
+            dry_run: false,
+        };
+
+        // Servers not running the base decline the update.
+        let mut receiver = UpdateReceiver::new("1.1.0".parse().unwrap());
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::BaseMismatch
+        );
+        assert_eq!(receiver.state(), UpdateState::Idle);
+
+        let mut receiver = UpdateReceiver::new("1.2.0".parse().unwrap());
+        assert_eq!(
+            receiver.prepare(
+                &prepare_for_update,
+                UpdateVerifier::new(&prepare_for_update)
+            ),
+            Preparation::Started
+        );
+
+        let mut staged = std::vec![0; image.len()];
+        let mut stage = |offset: u32, bytes: &[u8]| {
+            staged[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes)
+        };
+
+        let updates = delta_updates::<33, _>(&patch, &base[..]).collect::<std::vec::Vec<_>>();
+        assert!(updates.len() > 2);
+        // Delta updates are not received without the base to patch.
+        assert_eq!(
+            receiver.receive(&updates[0], &mut stage),
+            UpdateState::Receiving
+        );
+        assert_eq!(receiver.status().received_bytes, 0);
+
+        // Updates that do not follow on from the bytes patched are ignored.
+        for update in updates.iter().skip(1) {
+            receiver.receive_delta(update, &mut &base[..], &mut stage);
+        }
+        assert_eq!(receiver.status().received_bytes, 0);
+
+        for update in &updates {
+            receiver.receive_delta(update, &mut &base[..], &mut stage);
+        }
+        assert_eq!(receiver.state(), UpdateState::Verifying);
+        assert_eq!(
+            receiver.verify(|_, _| panic!("no bytes to read back")),
+            UpdateState::Staged
+        );
+        assert_eq!(staged, image);
+    }
+}
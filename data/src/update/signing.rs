@@ -0,0 +1,218 @@
+//! Signing and verification of updates. Updates are signed using Ed25519ph
+//! i.e. it is the SHA-512 digest of an update that is signed, so that a server
+//! is able to hash each [super::Update] as it arrives and then verify the
+//! signature once the update has been received in full. The version, security
+//! epoch and length of the update are hashed ahead of its bytes so that none
+//! can be altered without the signature failing.
+
+use ed25519_dalek::Signature;
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+use super::{
+    Check, PreRelease, PrepareForUpdate, UpdateIntegrity, UpdateSignature, UpdateVerifier,
+    VerificationError, Version,
+};
+
+/// The context of an update's signature, distinguishing it from any other use
+/// of the signing key.
+pub const SIGNATURE_CONTEXT: &[u8] = b"flip-flop-update";
+
+fn prehash(version: &Version, security_epoch: u16, update_byte_len: u32) -> Sha512 {
+    let pre = match version.pre {
+        None => [0, 0],
+        Some(PreRelease::Alpha(ident)) => [1, ident],
+        Some(PreRelease::Beta(ident)) => [2, ident],
+        Some(PreRelease::Rc(ident)) => [3, ident],
+    };
+    Sha512::new()
+        .chain_update([version.major, version.minor, version.patch])
+        .chain_update(pre)
+        .chain_update(security_epoch.to_le_bytes())
+        .chain_update(update_byte_len.to_le_bytes())
+}
+
+/// Sign an update for conveying with its [PrepareForUpdate] e.g. by the host
+/// that builds it. The key identifier conveys which of the keys trusted by
+/// servers verifies the signature. The security epoch is signed so that an
+/// old update cannot be passed off as being of a later epoch.
+pub fn sign_update(
+    signing_key: &SigningKey,
+    key_id: u8,
+    version: &Version,
+    security_epoch: u16,
+    update: &[u8],
+) -> UpdateSignature {
+    let prehashed = prehash(version, security_epoch, update.len() as u32).chain_update(update);
+    let signature = signing_key
+        .sign_prehashed(prehashed, Some(SIGNATURE_CONTEXT))
+        // Only contexts longer than 255 bytes are refused.
+        .unwrap();
+    UpdateSignature {
+        key_id,
+        signature: signature.to_bytes(),
+    }
+}
+
+impl UpdateVerifier {
+    /// Prepare to verify an update that must be signed, given a means of
+    /// looking up the trusted key for the signature's key identifier. The
+    /// verifier has failed if the update is not signed, or not signed by a
+    /// trusted key.
+    pub fn with_trusted_keys<F>(prepare_for_update: &PrepareForUpdate, trusted_key: F) -> Self
+    where
+        F: FnOnce(u8) -> Option<VerifyingKey>,
+    {
+        let UpdateIntegrity::Signed(signature) = &prepare_for_update.integrity else {
+            return Self::failed(prepare_for_update, VerificationError::Unsigned);
+        };
+        match trusted_key(signature.key_id) {
+            Some(key) => Self::checking(
+                prepare_for_update,
+                Check::Signature {
+                    hasher: prehash(
+                        &prepare_for_update.version,
+                        prepare_for_update.security_epoch,
+                        prepare_for_update.update_byte_len,
+                    ),
+                    key,
+                    signature: Signature::from_bytes(&signature.signature),
+                },
+            ),
+            None => Self::failed(
+                prepare_for_update,
+                VerificationError::UntrustedKey(signature.key_id),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::port::PortSet;
+    use crate::update::{update_digest, Compression, UpdateKey, VerifierState};
+
+    const KEY_ID: u8 = 1;
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn trusted_key(key_id: u8) -> Option<VerifyingKey> {
+        (key_id == KEY_ID).then(|| signing_key().verifying_key())
+    }
+
+    fn prepare(update: &[u8]) -> PrepareForUpdate {
+        let version = "1.2.3".parse().unwrap();
+        PrepareForUpdate {
+            integrity: UpdateIntegrity::Signed(sign_update(
+                &signing_key(),
+                KEY_ID,
+                &version,
+                0,
+                update,
+            )),
+            version,
+            server_ports: PortSet::from_bits(0b00000100),
+            image_id: 0,
+            security_epoch: 0,
+            update_key: UpdateKey([1; 16]),
+            update_byte_len: update.len() as u32,
+            compression: Compression::None,
+            delta: None,
+            hardware_rev: None,
+            dry_run: false,
+        }
+    }
+
+    fn receive(verifier: &mut UpdateVerifier, update: &[u8]) -> Result<(), VerificationError> {
+        for (i, chunk) in update.chunks(33).enumerate() {
+            verifier.update((i * 33) as u32, chunk)?;
+        }
+        verifier.verify()
+    }
+
+    #[test]
+    fn test_verify_signed_update() {
+        let update = [0x5a; 1000];
+        let mut verifier = UpdateVerifier::with_trusted_keys(&prepare(&update), trusted_key);
+        assert_eq!(verifier.state(), VerifierState::Hashing);
+        assert_eq!(receive(&mut verifier, &update), Ok(()));
+        assert_eq!(verifier.state(), VerifierState::Verified);
+        assert_eq!(verifier.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_tampered_update() {
+        let update = [0x5a; 1000];
+        let prepare_for_update = prepare(&update);
+
+        let mut tampered = update;
+        tampered[500] ^= 1;
+        let mut verifier = UpdateVerifier::with_trusted_keys(&prepare_for_update, trusted_key);
+        assert_eq!(
+            receive(&mut verifier, &tampered),
+            Err(VerificationError::BadSignature)
+        );
+
+        // Failure is terminal.
+        assert_eq!(
+            verifier.state(),
+            VerifierState::Failed(VerificationError::BadSignature)
+        );
+        assert_eq!(verifier.verify(), Err(VerificationError::BadSignature));
+        assert_eq!(
+            verifier.update(0, &update),
+            Err(VerificationError::BadSignature)
+        );
+
+        // The version and security epoch are also signed.
+        let mut verifier = UpdateVerifier::with_trusted_keys(
+            &PrepareForUpdate {
+                version: "1.2.4".parse().unwrap(),
+                ..prepare_for_update
+            },
+            trusted_key,
+        );
+        assert_eq!(
+            receive(&mut verifier, &update),
+            Err(VerificationError::BadSignature)
+        );
+        let mut verifier = UpdateVerifier::with_trusted_keys(
+            &PrepareForUpdate {
+                security_epoch: 1,
+                ..prepare(&update)
+            },
+            trusted_key,
+        );
+        assert_eq!(
+            receive(&mut verifier, &update),
+            Err(VerificationError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn test_unsigned_update() {
+        let update = [0x5a; 100];
+
+        let verifier = UpdateVerifier::with_trusted_keys(
+            &PrepareForUpdate {
+                integrity: UpdateIntegrity::Digest(update_digest(&update)),
+                ..prepare(&update)
+            },
+            trusted_key,
+        );
+        assert_eq!(
+            verifier.state(),
+            VerifierState::Failed(VerificationError::Unsigned)
+        );
+
+        let mut verifier = UpdateVerifier::with_trusted_keys(&prepare(&update), |_| None);
+        assert_eq!(
+            verifier.update(0, &update),
+            Err(VerificationError::UntrustedKey(KEY_ID))
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Decoding datagrams in a browser e.g. by a support tool describing the
+//! frames captured from a bus. Each function is exported by wasm-bindgen,
+//! a datagram and its key being given as a `Uint8Array`, and each renders
+//! as JSON as per [crate::debug_json]. Datagrams are decrypted with
+//! AES-128-CCM of a 4 byte tag, as by the app crate's data link layer.
+//! Requires the `wasm` feature.
+
+extern crate std;
+
+use std::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use aes::Aes128;
+use ccm::{
+    aead::KeyInit,
+    consts::{U4, U7},
+    Ccm,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    decrypt_payload,
+    discovery::{Identified, Identify},
+    parse_datagram,
+    update::{PrepareForUpdate, Update},
+    FromDatagramError,
+};
+
+type AesCcm = Ccm<Aes128, U4, U7>;
+
+// The most bytes of an update packet that may be rendered.
+const MAX_UPDATE_LEN: usize = 512;
+
+/// The header of a datagram rendered as JSON, without decrypting its
+/// payload.
+#[wasm_bindgen]
+pub fn peek_header(datagram: &[u8]) -> Result<String, JsError> {
+    peek(datagram).map_err(|e| JsError::new(&e))
+}
+
+/// The payload of a datagram of any length, decrypted with the 16 byte key
+/// given.
+#[wasm_bindgen]
+pub fn from_datagram_slice(datagram: &[u8], key: &[u8]) -> Result<Vec<u8>, JsError> {
+    decrypt(datagram, key).map_err(|e| JsError::new(&e))
+}
+
+/// A payload decoded as the message named, rendered as JSON. The messages
+/// are `Identify`, `Identified`, `PrepareForUpdate` and `Update`.
+#[wasm_bindgen]
+pub fn render_payload(message: &str, payload: &[u8]) -> Result<String, JsError> {
+    render(message, payload).map_err(|e| JsError::new(&e))
+}
+
+// A problem decoding a datagram, described for those using the tool.
+fn describe(e: FromDatagramError) -> String {
+    match e {
+        FromDatagramError::CannotParseDataFrame(e) => std::format!("not a datagram: {e}"),
+        FromDatagramError::CannotParseHeader => "unknown header".into(),
+        FromDatagramError::FilterDoesNotMatch => "filtered out".into(),
+        FromDatagramError::CannotDecrypt => "cannot decrypt with the key given".into(),
+        FromDatagramError::UnknownPeer => "no key for the datagram".into(),
+    }
+}
+
+fn peek(datagram: &[u8]) -> Result<String, String> {
+    let (header, _) = parse_datagram(datagram).map_err(describe)?;
+    Ok(header.to_debug_json().to_string())
+}
+
+fn decrypt(datagram: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    let cipher = AesCcm::new_from_slice(key).map_err(|_| "a key is 16 bytes")?;
+    let (header, encrypted) = parse_datagram(datagram).map_err(describe)?;
+    let mut payload = vec![0; encrypted.len()];
+    let len = decrypt_payload(&cipher, &header, encrypted, &mut payload).map_err(describe)?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+fn render(message: &str, payload: &[u8]) -> Result<String, String> {
+    fn decoded<T: DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+        postcard::from_bytes(payload).map_err(|e| std::format!("not the message named: {e}"))
+    }
+    let json: Value = match message {
+        "Identify" => decoded::<Identify>(payload)?.to_debug_json(),
+        "Identified" => decoded::<Identified>(payload)?.to_debug_json(),
+        "PrepareForUpdate" => decoded::<PrepareForUpdate>(payload)?.to_debug_json(),
+        "Update" => decoded::<Update<MAX_UPDATE_LEN>>(payload)?.to_debug_json(),
+        _ => return Err(std::format!("unknown message: {message}")),
+    };
+    Ok(json.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8; 16] = b"0123456789ABCDEF";
+    const DATAGRAM: [u8; 18] = [
+        0, 1, 63, 252, 13, 145, 171, 66, 62, 129, 223, 68, 168, 6, 69, 126, 97, 64,
+    ];
+
+    #[test]
+    fn test_golden_datagram() {
+        assert_eq!(
+            peek(&DATAGRAM).unwrap(),
+            r#"{"decoded":{"frame_counter":1,"server_address":255,"server_port":7,"source":"server","version":0},"raw":"00013ffc"}"#
+        );
+        assert_eq!(decrypt(&DATAGRAM, KEY).unwrap(), b"some data");
+
+        // The wrong key, or one of the wrong length, does not decrypt.
+        assert!(decrypt(&DATAGRAM, b"0123456789ABCDEX").is_err());
+        assert!(decrypt(&DATAGRAM, &KEY[..8]).is_err());
+        assert!(peek(&DATAGRAM[..2]).is_err());
+    }
+
+    #[test]
+    fn test_render_payload() {
+        let mut identify: Identify = Identify::new();
+        identify.set_address(9);
+        let payload = postcard::to_extend(&identify, Vec::new()).unwrap();
+        assert_eq!(
+            render("Identify", &payload).unwrap(),
+            identify.to_debug_json().to_string()
+        );
+        assert!(render("Identified", &[]).is_err());
+        assert!(render("Unknown", &payload).is_err());
+    }
+}
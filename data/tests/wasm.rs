@@ -0,0 +1,30 @@
+//! Decodes a golden datagram with the functions exported to JavaScript, as
+//! a browser would, headless under Node.js. Run with
+//! `wasm-bindgen-test-runner` as the runner of the `wasm32-unknown-unknown`
+//! target, its version being that of wasm-bindgen:
+//!
+//! ```text
+//! CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER=wasm-bindgen-test-runner \
+//!     cargo test -p flip-flop-data --target wasm32-unknown-unknown --features wasm --test wasm
+//! ```
+
+#![cfg(target_arch = "wasm32")]
+
+use flip_flop_data::wasm::{from_datagram_slice, peek_header, render_payload};
+use wasm_bindgen_test::wasm_bindgen_test;
+
+const KEY: &[u8; 16] = b"0123456789ABCDEF";
+const DATAGRAM: [u8; 18] = [
+    0, 1, 63, 252, 13, 145, 171, 66, 62, 129, 223, 68, 168, 6, 69, 126, 97, 64,
+];
+
+#[wasm_bindgen_test]
+fn test_golden_datagram() {
+    assert_eq!(
+        peek_header(&DATAGRAM).unwrap(),
+        r#"{"decoded":{"frame_counter":1,"server_address":255,"server_port":7,"source":"server","version":0},"raw":"00013ffc"}"#
+    );
+    assert_eq!(from_datagram_slice(&DATAGRAM, KEY).unwrap(), b"some data");
+    assert!(from_datagram_slice(&DATAGRAM, &KEY[..8]).is_err());
+    assert!(render_payload("Update", &[]).is_err());
+}
@@ -0,0 +1,226 @@
+//! Derives the dispatch of requests to the ports of a server, each port
+//! conveying its own commands and events. See `flip_flop_app::ports` for
+//! what is derived and how it is used.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitInt, Result, Type};
+
+// The most ports that the header of a datagram conveys.
+const MAX_PORT: u8 = 7;
+
+/// Derives the dispatch of requests to the ports of a server from an enum
+/// whose variants each bind a port to the types of the commands, events and
+/// ephemeral events conveyed on it e.g.
+///
+/// ```ignore
+/// #[derive(PortSuite)]
+/// enum Ports {
+///     #[port(1)]
+///     Lights(LightsCommand, LightsEvent, NoEE),
+///     #[port(2)]
+///     Pump(PumpCommand, PumpEvent, Progress<u8>),
+/// }
+/// ```
+///
+/// Each port is to be no more than 7 and bound once only. An enum of
+/// requests and one of replies are derived, being named for the suite
+/// e.g. `PortsRequest` and `PortsReply`, along with the suite's
+/// `dispatch_request` and `encode_reply`.
+#[proc_macro_derive(PortSuite, attributes(port))]
+pub fn derive_port_suite(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    port_suite(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+// A variant of the suite and the types conveyed on its port.
+struct PortVariant {
+    ident: syn::Ident,
+    port: u8,
+    command: Type,
+    event: Type,
+    ephemeral: Type,
+}
+
+fn port_suite(input: DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "a port suite must be an enum",
+        ));
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "a port suite cannot be generic",
+        ));
+    }
+
+    let mut variants = Vec::<PortVariant>::new();
+    for variant in &data.variants {
+        let attr = variant
+            .attrs
+            .iter()
+            .find(|a| a.path().is_ident("port"))
+            .ok_or_else(|| Error::new_spanned(&variant.ident, "expected a #[port(..)]"))?;
+        let lit = attr.parse_args::<LitInt>()?;
+        let port = lit
+            .base10_parse::<u8>()
+            .ok()
+            .filter(|port| *port <= MAX_PORT)
+            .ok_or_else(|| {
+                Error::new_spanned(&lit, format!("a port must be no more than {MAX_PORT}"))
+            })?;
+        if let Some(other) = variants.iter().find(|v| v.port == port) {
+            return Err(Error::new_spanned(
+                &lit,
+                format!("port {port} is already bound to {}", other.ident),
+            ));
+        }
+
+        let Fields::Unnamed(fields) = &variant.fields else {
+            return Err(Error::new_spanned(
+                &variant.ident,
+                "expected the types of commands, events and ephemeral events",
+            ));
+        };
+        let [command, event, ephemeral] = fields
+            .unnamed
+            .iter()
+            .map(|f| f.ty.clone())
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| {
+                Error::new_spanned(
+                    fields,
+                    "expected the types of commands, events and ephemeral events",
+                )
+            })?;
+        variants.push(PortVariant {
+            ident: variant.ident.clone(),
+            port,
+            command,
+            event,
+            ephemeral,
+        });
+    }
+    if variants.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "a port suite must bind at least one port",
+        ));
+    }
+
+    let vis = &input.vis;
+    let suite = &input.ident;
+    let request = format_ident!("{}Request", suite);
+    let reply = format_ident!("{}Reply", suite);
+    let idents = variants.iter().map(|v| &v.ident).collect::<Vec<_>>();
+    let ports = variants
+        .iter()
+        .map(|v| {
+            let port = v.port;
+            quote!(::flip_flop_app::ports::Port::new(#port).unwrap())
+        })
+        .collect::<Vec<_>>();
+    let port_numbers = variants.iter().map(|v| v.port).collect::<Vec<_>>();
+    let commands = variants.iter().map(|v| &v.command).collect::<Vec<_>>();
+    let events = variants.iter().map(|v| &v.event).collect::<Vec<_>>();
+    let ephemerals = variants.iter().map(|v| &v.ephemeral).collect::<Vec<_>>();
+    let port_count = variants.len();
+
+    let request_doc = format!("A request received on one of the ports of [{suite}].");
+    let reply_doc = format!("A reply to transmit on one of the ports of [{suite}].");
+
+    Ok(quote! {
+        #[doc = #request_doc]
+        #vis enum #request<const COMMANDS: usize> {
+            #(
+                #idents(::flip_flop_app::MultiCommandRequest<#commands, COMMANDS>),
+            )*
+        }
+
+        impl<const COMMANDS: usize> #request<COMMANDS> {
+            /// The port that the request was received on.
+            pub fn port(&self) -> ::flip_flop_app::ports::Port {
+                match self {
+                    #( Self::#idents(_) => #ports, )*
+                }
+            }
+        }
+
+        #[doc = #reply_doc]
+        #vis enum #reply<const EVENTS: usize> {
+            #(
+                #idents(
+                    ::flip_flop_app::EventBatchReply<
+                        ::flip_flop_app::EventOf<#events, #ephemerals>,
+                        EVENTS,
+                    >,
+                ),
+            )*
+        }
+
+        impl<const EVENTS: usize> #reply<EVENTS> {
+            /// The port that the reply is to be transmitted on.
+            pub fn port(&self) -> ::flip_flop_app::ports::Port {
+                match self {
+                    #( Self::#idents(_) => #ports, )*
+                }
+            }
+        }
+
+        impl #suite {
+            /// The ports of the suite, in the order of its variants.
+            pub const PORTS: [::flip_flop_app::ports::Port; #port_count] = [#(#ports),*];
+
+            /// Decode the bytes of a request received on the port given as
+            /// a request of the commands conveyed on it.
+            pub fn dispatch_request<const COMMANDS: usize>(
+                port: ::flip_flop_app::ports::Port,
+                bytes: &[u8],
+            ) -> ::core::result::Result<#request<COMMANDS>, ::flip_flop_app::ports::DispatchError>
+            {
+                match port.get() {
+                    #(
+                        #port_numbers => ::flip_flop_app::ports::decode_request(bytes)
+                            .map(#request::#idents),
+                    )*
+                    _ => ::core::result::Result::Err(
+                        ::flip_flop_app::ports::DispatchError::UnknownPort(port),
+                    ),
+                }
+            }
+
+            /// Encode a reply within the buffer given, returning the port to
+            /// transmit it on along with its bytes.
+            pub fn encode_reply<'a, const EVENTS: usize>(
+                reply: &#reply<EVENTS>,
+                buf: &'a mut [u8],
+            ) -> ::core::result::Result<(::flip_flop_app::ports::Port, &'a mut [u8]), ::flip_flop_app::ports::DispatchError>
+            {
+                match reply {
+                    #(
+                        #reply::#idents(batch) => {
+                            ::flip_flop_app::ports::encode_reply(batch, buf).map(|bytes| (#ports, bytes))
+                        }
+                    )*
+                }
+            }
+        }
+
+        // The variants of a suite only bind types to ports and so are never
+        // constructed, nor are their fields read.
+        const _: () = {
+            #( let _ = #suite::#idents; )*
+            let _ = |suite: #suite| match suite {
+                #( #suite::#idents(command, event, ephemeral) => {
+                    let _ = (command, event, ephemeral);
+                } )*
+            };
+        };
+    })
+}
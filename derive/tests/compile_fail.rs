@@ -0,0 +1,6 @@
+// The ports of a suite are checked as it is compiled.
+#[test]
+fn test_compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
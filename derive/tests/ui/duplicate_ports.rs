@@ -0,0 +1,11 @@
+use flip_flop_derive::PortSuite;
+
+#[derive(PortSuite)]
+enum Ports {
+    #[port(1)]
+    Lights(u8, u8, ()),
+    #[port(1)]
+    Pump(u16, u16, ()),
+}
+
+fn main() {}
@@ -0,0 +1,138 @@
+#ifndef FLIP_FLOP_H
+#define FLIP_FLOP_H
+
+/* Generated by cbindgen from ffi/src/lib.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The length of a key in bytes.
+ */
+#define FF_KEY_LEN 16
+
+/**
+ * The source of a datagram transmitted by a client.
+ */
+#define FF_SOURCE_CLIENT 0
+
+/**
+ * The source of a datagram transmitted by a server.
+ */
+#define FF_SOURCE_SERVER 1
+
+/**
+ * The datagram could not be decoded, see
+ * `FromDatagramError::CannotParseDataFrame`.
+ */
+#define FF_CANNOT_PARSE_DATA_FRAME -1
+
+/**
+ * The header of the datagram is not of this version of the protocol, see
+ * `FromDatagramError::CannotParseHeader`.
+ */
+#define FF_CANNOT_PARSE_HEADER -2
+
+/**
+ * The header of the datagram does not match the one it was filtered by, see
+ * `FromDatagramError::FilterDoesNotMatch`.
+ */
+#define FF_FILTER_DOES_NOT_MATCH -3
+
+/**
+ * The payload could not be authenticated with the key given, see
+ * `FromDatagramError::CannotDecrypt`.
+ */
+#define FF_CANNOT_DECRYPT -4
+
+/**
+ * The buffer given is too small for the datagram or payload.
+ */
+#define FF_BUFFER_TOO_SMALL -5
+
+/**
+ * An argument is null when it must not be, or is out of range e.g. a port
+ * beyond 7.
+ */
+#define FF_INVALID_ARGUMENT -6
+
+/**
+ * The library panicked, which is a bug.
+ */
+#define FF_PANIC -7
+
+/**
+ * There is no key for the datagram's header, see
+ * `FromDatagramError::UnknownPeer`. Not returned given a single key.
+ */
+#define FF_UNKNOWN_PEER -8
+
+/**
+ * The fields of a datagram's header.
+ */
+typedef struct FfHeader {
+  /**
+   * [FF_SOURCE_CLIENT] or [FF_SOURCE_SERVER].
+   */
+  uint8_t source;
+  /**
+   * The address of the server.
+   */
+  uint8_t server_address;
+  /**
+   * The port of the server 0..=7.
+   */
+  uint8_t server_port;
+  /**
+   * The frame counter of the source.
+   */
+  uint16_t frame_counter;
+} FfHeader;
+
+/**
+ * Encrypts a payload with a key and encodes it, along with a header of the
+ * fields given, into a datagram written to `out`. Returns the length of the
+ * datagram, or a negative `FF_` code of a problem.
+ *
+ * # Safety
+ *
+ * `key` must point to [FF_KEY_LEN] bytes, `payload` to `payload_len` bytes
+ * and `out` to `out_cap` bytes, and `out` must not overlap the others. A
+ * pointer may be null when its length is 0.
+ */
+int32_t ff_to_datagram(const uint8_t *key,
+                       uint8_t source,
+                       uint8_t server_address,
+                       uint8_t server_port,
+                       uint16_t frame_counter,
+                       const uint8_t *payload,
+                       size_t payload_len,
+                       uint8_t *out,
+                       size_t out_cap);
+
+/**
+ * Decodes a datagram and decrypts its payload with a key, writing its
+ * header to `header_out` and its payload to `payload_out`. Should `filter`
+ * not be null, the datagram must be of its source, server address and
+ * server port, its frame counter being ignored. Returns the length of the
+ * payload, or a negative `FF_` code of a problem.
+ *
+ * # Safety
+ *
+ * `key` must point to [FF_KEY_LEN] bytes, `datagram` to `datagram_len`
+ * bytes and `payload_out` to `payload_cap` bytes, and `payload_out` must
+ * not overlap the others. `filter` may be null, and `header_out` must point
+ * to an `FfHeader`. A pointer to a buffer may be null when its length is 0.
+ */
+int32_t ff_from_datagram(const uint8_t *key,
+                         const uint8_t *datagram,
+                         size_t datagram_len,
+                         const struct FfHeader *filter,
+                         struct FfHeader *header_out,
+                         uint8_t *payload_out,
+                         size_t payload_cap);
+
+#endif  /* FLIP_FLOP_H */
@@ -0,0 +1,460 @@
+//! C bindings for encoding and decoding the datagrams of the data link layer,
+//! so that servers written in C may call upon a static library for the
+//! encryption of their frames rather than implementing it again. Nothing is
+//! allocated: datagrams and payloads are written to the buffers given. Each
+//! function returns a length, or one of the negative `FF_` codes of a
+//! problem, with those of [FromDatagramError] mirrored. A panic is caught
+//! before it reaches C, and is returned as [FF_PANIC].
+//!
+//! The header of `include/flip_flop.h` is generated from this file by
+//! cbindgen.
+
+use std::{panic, slice};
+
+use aes::Aes128;
+use ccm::{
+    aead::{generic_array::GenericArray, KeyInit},
+    consts::{U4, U7},
+    Ccm,
+};
+use flip_flop_data::{
+    decrypt_payload, encode_datagram, parse_datagram, port::Port, DataSource, FromDatagramError,
+    Header,
+};
+
+/// The length of a key in bytes.
+pub const FF_KEY_LEN: usize = 16;
+
+/// The source of a datagram transmitted by a client.
+pub const FF_SOURCE_CLIENT: u8 = 0;
+/// The source of a datagram transmitted by a server.
+pub const FF_SOURCE_SERVER: u8 = 1;
+
+/// The datagram could not be decoded, see
+/// `FromDatagramError::CannotParseDataFrame`.
+pub const FF_CANNOT_PARSE_DATA_FRAME: i32 = -1;
+/// The header of the datagram is not of this version of the protocol, see
+/// `FromDatagramError::CannotParseHeader`.
+pub const FF_CANNOT_PARSE_HEADER: i32 = -2;
+/// The header of the datagram does not match the one it was filtered by, see
+/// `FromDatagramError::FilterDoesNotMatch`.
+pub const FF_FILTER_DOES_NOT_MATCH: i32 = -3;
+/// The payload could not be authenticated with the key given, see
+/// `FromDatagramError::CannotDecrypt`.
+pub const FF_CANNOT_DECRYPT: i32 = -4;
+/// The buffer given is too small for the datagram or payload.
+pub const FF_BUFFER_TOO_SMALL: i32 = -5;
+/// An argument is null when it must not be, or is out of range e.g. a port
+/// beyond 7.
+pub const FF_INVALID_ARGUMENT: i32 = -6;
+/// The library panicked, which is a bug.
+pub const FF_PANIC: i32 = -7;
+/// There is no key for the datagram's header, see
+/// `FromDatagramError::UnknownPeer`. Not returned given a single key.
+pub const FF_UNKNOWN_PEER: i32 = -8;
+
+/// The fields of a datagram's header.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FfHeader {
+    /// [FF_SOURCE_CLIENT] or [FF_SOURCE_SERVER].
+    pub source: u8,
+    /// The address of the server.
+    pub server_address: u8,
+    /// The port of the server 0..=7.
+    pub server_port: u8,
+    /// The frame counter of the source.
+    pub frame_counter: u16,
+}
+
+impl From<&Header> for FfHeader {
+    fn from(header: &Header) -> Self {
+        Self {
+            source: match header.source {
+                DataSource::Client => FF_SOURCE_CLIENT,
+                DataSource::Server => FF_SOURCE_SERVER,
+            },
+            server_address: header.server_address,
+            server_port: header.server_port.get(),
+            frame_counter: header.frame_counter,
+        }
+    }
+}
+
+impl TryFrom<&FfHeader> for Header {
+    type Error = i32;
+
+    fn try_from(header: &FfHeader) -> Result<Self, Self::Error> {
+        Ok(Header {
+            version: 0,
+            source: match header.source {
+                FF_SOURCE_CLIENT => DataSource::Client,
+                FF_SOURCE_SERVER => DataSource::Server,
+                _ => return Err(FF_INVALID_ARGUMENT),
+            },
+            server_address: header.server_address,
+            server_port: Port::new(header.server_port).ok_or(FF_INVALID_ARGUMENT)?,
+            frame_counter: header.frame_counter,
+        })
+    }
+}
+
+fn error_code(e: &FromDatagramError) -> i32 {
+    match e {
+        FromDatagramError::CannotParseDataFrame(_) => FF_CANNOT_PARSE_DATA_FRAME,
+        FromDatagramError::CannotParseHeader => FF_CANNOT_PARSE_HEADER,
+        FromDatagramError::FilterDoesNotMatch => FF_FILTER_DOES_NOT_MATCH,
+        FromDatagramError::CannotDecrypt => FF_CANNOT_DECRYPT,
+        FromDatagramError::UnknownPeer => FF_UNKNOWN_PEER,
+    }
+}
+
+// Runs the body of a function called from C, returning its length or the
+// code of its problem, including that of a panic, which must not unwind
+// into C.
+fn guard(f: impl FnOnce() -> Result<usize, i32>) -> i32 {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(Ok(len)) => i32::try_from(len).unwrap_or(FF_BUFFER_TOO_SMALL),
+        Ok(Err(code)) => code,
+        Err(_) => FF_PANIC,
+    }
+}
+
+// A view of a buffer given by C, which may be null when empty.
+unsafe fn view<'a>(ptr: *const u8, len: usize) -> Result<&'a [u8], i32> {
+    match (ptr.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(FF_INVALID_ARGUMENT),
+        (false, _) => Ok(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+// A mutable view of a buffer given by C, which may be null when empty.
+unsafe fn view_mut<'a>(ptr: *mut u8, len: usize) -> Result<&'a mut [u8], i32> {
+    match (ptr.is_null(), len) {
+        (_, 0) => Ok(&mut []),
+        (true, _) => Err(FF_INVALID_ARGUMENT),
+        (false, _) => Ok(slice::from_raw_parts_mut(ptr, len)),
+    }
+}
+
+unsafe fn cipher(key: *const u8) -> Result<Ccm<Aes128, U4, U7>, i32> {
+    if key.is_null() {
+        return Err(FF_INVALID_ARGUMENT);
+    }
+    let key = slice::from_raw_parts(key, FF_KEY_LEN);
+    Ok(Ccm::new(GenericArray::from_slice(key)))
+}
+
+/// Encrypts a payload with a key and encodes it, along with a header of the
+/// fields given, into a datagram written to `out`. Returns the length of the
+/// datagram, or a negative `FF_` code of a problem.
+///
+/// # Safety
+///
+/// `key` must point to [FF_KEY_LEN] bytes, `payload` to `payload_len` bytes
+/// and `out` to `out_cap` bytes, and `out` must not overlap the others. A
+/// pointer may be null when its length is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ff_to_datagram(
+    key: *const u8,
+    source: u8,
+    server_address: u8,
+    server_port: u8,
+    frame_counter: u16,
+    payload: *const u8,
+    payload_len: usize,
+    out: *mut u8,
+    out_cap: usize,
+) -> i32 {
+    guard(|| {
+        let cipher = cipher(key)?;
+        let header = Header::try_from(&FfHeader {
+            source,
+            server_address,
+            server_port,
+            frame_counter,
+        })?;
+        let payload = view(payload, payload_len)?;
+        let out = view_mut(out, out_cap)?;
+        encode_datagram(&cipher, &header, payload, out).ok_or(FF_BUFFER_TOO_SMALL)
+    })
+}
+
+/// Decodes a datagram and decrypts its payload with a key, writing its
+/// header to `header_out` and its payload to `payload_out`. Should `filter`
+/// not be null, the datagram must be of its source, server address and
+/// server port, its frame counter being ignored. Returns the length of the
+/// payload, or a negative `FF_` code of a problem.
+///
+/// # Safety
+///
+/// `key` must point to [FF_KEY_LEN] bytes, `datagram` to `datagram_len`
+/// bytes and `payload_out` to `payload_cap` bytes, and `payload_out` must
+/// not overlap the others. `filter` may be null, and `header_out` must point
+/// to an `FfHeader`. A pointer to a buffer may be null when its length is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ff_from_datagram(
+    key: *const u8,
+    datagram: *const u8,
+    datagram_len: usize,
+    filter: *const FfHeader,
+    header_out: *mut FfHeader,
+    payload_out: *mut u8,
+    payload_cap: usize,
+) -> i32 {
+    guard(|| {
+        let cipher = cipher(key)?;
+        let datagram = view(datagram, datagram_len)?;
+        let payload_out = view_mut(payload_out, payload_cap)?;
+        if header_out.is_null() {
+            return Err(FF_INVALID_ARGUMENT);
+        }
+
+        let (header, encrypted_payload) = parse_datagram(datagram).map_err(|e| error_code(&e))?;
+        let decoded = FfHeader::from(&header);
+        if let Some(filter) = filter.as_ref() {
+            if (filter.source, filter.server_address, filter.server_port)
+                != (decoded.source, decoded.server_address, decoded.server_port)
+            {
+                return Err(error_code(&FromDatagramError::FilterDoesNotMatch));
+            }
+        }
+        if encrypted_payload
+            .len()
+            .saturating_sub(flip_flop_data::MIC_SIZE)
+            > payload_out.len()
+        {
+            return Err(FF_BUFFER_TOO_SMALL);
+        }
+
+        let payload_len = decrypt_payload(&cipher, &header, encrypted_payload, payload_out)
+            .map_err(|e| error_code(&e))?;
+        *header_out = decoded;
+        Ok(payload_len)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Write, fs, path::Path, ptr};
+
+    use super::*;
+
+    const KEY: &[u8; FF_KEY_LEN] = b"0123456789ABCDEF";
+
+    // The datagrams that the C test expects, being the header, payload and
+    // datagram of each.
+    fn golden_vectors() -> Vec<(FfHeader, Vec<u8>, Vec<u8>)> {
+        [
+            (FF_SOURCE_SERVER, 255, 7, 1, b"some data".to_vec()),
+            (FF_SOURCE_CLIENT, 1, 0, 0, Vec::new()),
+            (FF_SOURCE_CLIENT, 42, 3, 0xffff, (0..200).collect()),
+        ]
+        .into_iter()
+        .map(
+            |(source, server_address, server_port, frame_counter, payload)| {
+                let header = FfHeader {
+                    source,
+                    server_address,
+                    server_port,
+                    frame_counter,
+                };
+                let mut out = [0; 256];
+                let len = unsafe {
+                    ff_to_datagram(
+                        KEY.as_ptr(),
+                        source,
+                        server_address,
+                        server_port,
+                        frame_counter,
+                        payload.as_ptr(),
+                        payload.len(),
+                        out.as_mut_ptr(),
+                        out.len(),
+                    )
+                };
+                (header, payload, out[..len as usize].to_vec())
+            },
+        )
+        .collect()
+    }
+
+    fn render_golden_vectors() -> String {
+        let bytes = |bytes: &[u8]| {
+            bytes
+                .chunks(16)
+                .map(|chunk| {
+                    let mut line = String::from("    ");
+                    for b in chunk {
+                        let _ = write!(line, "0x{b:02x}, ");
+                    }
+                    line.trim_end().to_string()
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let mut out = String::from(
+            "/* Generated by the tests of ffi/src/lib.rs; run them with UPDATE_GOLDEN=1 to update. */\n\n",
+        );
+        let _ = writeln!(out, "static const uint8_t GOLDEN_KEY[] = {{");
+        let _ = writeln!(out, "{}\n}};\n", bytes(KEY));
+        let vectors = golden_vectors();
+        for (i, (_, payload, datagram)) in vectors.iter().enumerate() {
+            // C has no arrays of no elements, and so an empty payload is NULL.
+            if payload.is_empty() {
+                let _ = writeln!(
+                    out,
+                    "static const uint8_t GOLDEN_DATAGRAM_{i}[] = {{\n{}\n}};\n",
+                    bytes(datagram)
+                );
+                continue;
+            }
+            let _ = writeln!(
+                out,
+                "static const uint8_t GOLDEN_PAYLOAD_{i}[] = {{\n{}\n}};\n",
+                bytes(payload)
+            );
+            let _ = writeln!(
+                out,
+                "static const uint8_t GOLDEN_DATAGRAM_{i}[] = {{\n{}\n}};\n",
+                bytes(datagram)
+            );
+        }
+        let _ = writeln!(out, "static const struct golden {{");
+        let _ = writeln!(out, "    FfHeader header;");
+        let _ = writeln!(out, "    const uint8_t *payload;");
+        let _ = writeln!(out, "    size_t payload_len;");
+        let _ = writeln!(out, "    const uint8_t *datagram;");
+        let _ = writeln!(out, "    size_t datagram_len;");
+        let _ = writeln!(out, "}} GOLDEN[] = {{");
+        for (i, (header, payload, datagram)) in vectors.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "    {{{{{}, {}, {}, {}}}, {}, {}, {}, {}}},",
+                header.source,
+                header.server_address,
+                header.server_port,
+                header.frame_counter,
+                if payload.is_empty() {
+                    "NULL".to_string()
+                } else {
+                    format!("GOLDEN_PAYLOAD_{i}")
+                },
+                payload.len(),
+                format_args!("GOLDEN_DATAGRAM_{i}"),
+                datagram.len()
+            );
+        }
+        let _ = writeln!(out, "}};");
+        out
+    }
+
+    #[test]
+    fn test_golden_vectors() {
+        // The first is the datagram of the data crate's tests.
+        assert_eq!(
+            golden_vectors()[0].2,
+            [0, 1, 63, 252, 13, 145, 171, 66, 62, 129, 223, 68, 168, 6, 69, 126, 97, 64]
+        );
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden.h");
+        let rendered = render_golden_vectors();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &rendered).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), rendered);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (header, payload, datagram) in golden_vectors() {
+            let mut header_out = FfHeader::default();
+            let mut payload_out = [0; 256];
+            let len = unsafe {
+                ff_from_datagram(
+                    KEY.as_ptr(),
+                    datagram.as_ptr(),
+                    datagram.len(),
+                    &header,
+                    &mut header_out,
+                    payload_out.as_mut_ptr(),
+                    payload_out.len(),
+                )
+            };
+            assert_eq!(header_out, header);
+            assert_eq!(&payload_out[..len as usize], payload);
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        let (header, payload, mut datagram) = golden_vectors().swap_remove(0);
+        let to_datagram = |key: *const u8, port: u8, out: &mut [u8]| unsafe {
+            ff_to_datagram(
+                key,
+                header.source,
+                header.server_address,
+                port,
+                header.frame_counter,
+                payload.as_ptr(),
+                payload.len(),
+                out.as_mut_ptr(),
+                out.len(),
+            )
+        };
+        assert_eq!(
+            to_datagram(ptr::null(), 7, &mut [0; 32]),
+            FF_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            to_datagram(KEY.as_ptr(), 8, &mut [0; 32]),
+            FF_INVALID_ARGUMENT
+        );
+        assert_eq!(
+            to_datagram(KEY.as_ptr(), 7, &mut [0; 17]),
+            FF_BUFFER_TOO_SMALL
+        );
+
+        let from_datagram = |datagram: &[u8], filter: &FfHeader, cap: usize| unsafe {
+            let mut header_out = FfHeader::default();
+            let mut payload_out = [0; 32];
+            ff_from_datagram(
+                KEY.as_ptr(),
+                datagram.as_ptr(),
+                datagram.len(),
+                filter,
+                &mut header_out,
+                payload_out.as_mut_ptr(),
+                cap,
+            )
+        };
+        let other_port = FfHeader {
+            server_port: 6,
+            ..header
+        };
+        assert_eq!(from_datagram(&datagram, &header, 9), 9);
+        assert_eq!(from_datagram(&datagram, &header, 8), FF_BUFFER_TOO_SMALL);
+        assert_eq!(
+            from_datagram(&datagram, &other_port, 32),
+            FF_FILTER_DOES_NOT_MATCH
+        );
+        assert_eq!(
+            from_datagram(&datagram[..1], &header, 32),
+            FF_CANNOT_PARSE_DATA_FRAME
+        );
+        datagram[3] ^= 1;
+        assert_eq!(
+            from_datagram(&datagram, &header, 32),
+            FF_CANNOT_PARSE_HEADER
+        );
+        datagram[3] ^= 1;
+        datagram[17] ^= 1;
+        assert_eq!(from_datagram(&datagram, &header, 32), FF_CANNOT_DECRYPT);
+    }
+
+    #[test]
+    fn test_panics_are_caught() {
+        assert_eq!(guard(|| panic!("a bug")), FF_PANIC);
+    }
+}
@@ -0,0 +1,63 @@
+/* Generated by the tests of ffi/src/lib.rs; run them with UPDATE_GOLDEN=1 to update. */
+
+static const uint8_t GOLDEN_KEY[] = {
+    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46,
+};
+
+static const uint8_t GOLDEN_PAYLOAD_0[] = {
+    0x73, 0x6f, 0x6d, 0x65, 0x20, 0x64, 0x61, 0x74, 0x61,
+};
+
+static const uint8_t GOLDEN_DATAGRAM_0[] = {
+    0x00, 0x01, 0x3f, 0xfc, 0x0d, 0x91, 0xab, 0x42, 0x3e, 0x81, 0xdf, 0x44, 0xa8, 0x06, 0x45, 0x7e,
+    0x61, 0x40,
+};
+
+static const uint8_t GOLDEN_DATAGRAM_1[] = {
+    0x00, 0x00, 0x00, 0x08, 0x04, 0x59, 0x72, 0xc9, 0x0c,
+};
+
+static const uint8_t GOLDEN_PAYLOAD_2[] = {
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+    0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x2b, 0x2c, 0x2d, 0x2e, 0x2f,
+    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, 0x3f,
+    0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f,
+    0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x5b, 0x5c, 0x5d, 0x5e, 0x5f,
+    0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f,
+    0x70, 0x71, 0x72, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x7c, 0x7d, 0x7e, 0x7f,
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f,
+    0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e, 0x9f,
+    0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad, 0xae, 0xaf,
+    0xb0, 0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xbb, 0xbc, 0xbd, 0xbe, 0xbf,
+    0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+};
+
+static const uint8_t GOLDEN_DATAGRAM_2[] = {
+    0xff, 0xff, 0x19, 0x50, 0xcc, 0x01, 0xbf, 0xce, 0x93, 0x7e, 0x75, 0xdb, 0xfa, 0x81, 0x0b, 0xaf,
+    0xf1, 0x88, 0x34, 0x72, 0xe3, 0x9a, 0xa6, 0x2c, 0x30, 0xd5, 0xdc, 0xa8, 0xab, 0x68, 0xa7, 0xe9,
+    0x2b, 0x8e, 0x3e, 0xe4, 0xdc, 0x98, 0x3b, 0x04, 0x4f, 0x69, 0xd8, 0x55, 0x2e, 0x94, 0x20, 0xb9,
+    0xe4, 0x23, 0x2e, 0x2d, 0xb5, 0x31, 0xf3, 0x09, 0x79, 0xab, 0xff, 0x39, 0x9f, 0x31, 0x39, 0xb0,
+    0x25, 0x0b, 0xeb, 0xa0, 0xc0, 0xb7, 0x8a, 0x20, 0xc7, 0xb9, 0x5d, 0x92, 0xb3, 0xe8, 0x10, 0xfe,
+    0x24, 0x7d, 0x7b, 0xaf, 0x1a, 0xdf, 0x87, 0x46, 0xcc, 0x65, 0x0b, 0x90, 0xd5, 0xff, 0xa2, 0xfa,
+    0xcc, 0x9a, 0x81, 0x73, 0x40, 0x44, 0x8f, 0xd3, 0xe0, 0xeb, 0x20, 0xaf, 0xa9, 0x58, 0x38, 0xef,
+    0xd0, 0x42, 0xf5, 0x4d, 0xb4, 0xb3, 0xdf, 0xce, 0x7b, 0x62, 0x41, 0x4c, 0xa5, 0x9a, 0x54, 0xea,
+    0xa3, 0x5a, 0xc3, 0x6f, 0x8c, 0x7d, 0x7b, 0xba, 0xc2, 0x6b, 0x9f, 0x84, 0xe5, 0xbd, 0x6c, 0xbd,
+    0x18, 0xfb, 0xf9, 0x8b, 0x21, 0xd2, 0xfa, 0xc7, 0x56, 0x17, 0x37, 0xae, 0x45, 0xf6, 0xe0, 0x2f,
+    0x16, 0x4f, 0xec, 0xf0, 0x76, 0x25, 0xbd, 0x84, 0xb4, 0x7f, 0x70, 0x16, 0xe2, 0xde, 0x2e, 0x16,
+    0xb4, 0xc1, 0x44, 0xb1, 0x50, 0x4d, 0x7b, 0x00, 0xb0, 0xdf, 0x51, 0xbf, 0xd5, 0x30, 0x79, 0x60,
+    0x76, 0x03, 0x36, 0x7a, 0xe7, 0x4a, 0xb7, 0xe1, 0x6c, 0xb3, 0x41, 0xc5, 0xbe, 0x21, 0x54, 0xdb,
+    0x58, 0x22,
+};
+
+static const struct golden {
+    FfHeader header;
+    const uint8_t *payload;
+    size_t payload_len;
+    const uint8_t *datagram;
+    size_t datagram_len;
+} GOLDEN[] = {
+    {{1, 255, 7, 1}, GOLDEN_PAYLOAD_0, 9, GOLDEN_DATAGRAM_0, 18},
+    {{0, 1, 0, 0}, NULL, 0, GOLDEN_DATAGRAM_1, 9},
+    {{0, 42, 3, 65535}, GOLDEN_PAYLOAD_2, 200, GOLDEN_DATAGRAM_2, 210},
+};
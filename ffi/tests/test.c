@@ -0,0 +1,101 @@
+/*
+ * Encodes and decodes the golden vectors generated by the Rust tests with the
+ * static library, proving that C interoperates with it. Built and run by CI:
+ *
+ *   cargo build -p flip-flop-ffi
+ *   cc -Iffi/include -o target/ffi-test ffi/tests/test.c \
+ *       target/debug/libflip_flop_ffi.a -lpthread -ldl -lm
+ *   target/ffi-test
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "flip_flop.h"
+#include "golden.h"
+
+static int failures = 0;
+
+#define CHECK(cond)                                                            \
+    do {                                                                       \
+        if (!(cond)) {                                                         \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #cond);         \
+            failures++;                                                        \
+        }                                                                      \
+    } while (0)
+
+static void test_golden(void) {
+    for (size_t i = 0; i < sizeof(GOLDEN) / sizeof(GOLDEN[0]); i++) {
+        const struct golden *g = &GOLDEN[i];
+        uint8_t datagram[256];
+        uint8_t payload[256];
+        FfHeader header;
+
+        int32_t len = ff_to_datagram(GOLDEN_KEY, g->header.source,
+                                     g->header.server_address,
+                                     g->header.server_port,
+                                     g->header.frame_counter, g->payload,
+                                     g->payload_len, datagram,
+                                     sizeof(datagram));
+        CHECK(len == (int32_t)g->datagram_len);
+        CHECK(len > 0 && memcmp(datagram, g->datagram, len) == 0);
+
+        len = ff_from_datagram(GOLDEN_KEY, g->datagram, g->datagram_len,
+                               &g->header, &header, payload, sizeof(payload));
+        CHECK(len == (int32_t)g->payload_len);
+        CHECK(len == 0 || memcmp(payload, g->payload, len) == 0);
+        CHECK(header.source == g->header.source);
+        CHECK(header.server_address == g->header.server_address);
+        CHECK(header.server_port == g->header.server_port);
+        CHECK(header.frame_counter == g->header.frame_counter);
+    }
+}
+
+static void test_problems(void) {
+    const struct golden *g = &GOLDEN[0];
+    uint8_t datagram[256];
+    uint8_t payload[256];
+    FfHeader header;
+    FfHeader other_port = g->header;
+    other_port.server_port = 6;
+
+    CHECK(ff_to_datagram(NULL, FF_SOURCE_SERVER, 1, 0, 0, NULL, 0, datagram,
+                         sizeof(datagram)) == FF_INVALID_ARGUMENT);
+    CHECK(ff_to_datagram(GOLDEN_KEY, FF_SOURCE_SERVER, 1, 8, 0, NULL, 0,
+                         datagram, sizeof(datagram)) == FF_INVALID_ARGUMENT);
+    CHECK(ff_to_datagram(GOLDEN_KEY, g->header.source,
+                         g->header.server_address, g->header.server_port,
+                         g->header.frame_counter, g->payload, g->payload_len,
+                         datagram, g->datagram_len - 1) == FF_BUFFER_TOO_SMALL);
+
+    CHECK(ff_from_datagram(GOLDEN_KEY, g->datagram, g->datagram_len,
+                           &other_port, &header, payload,
+                           sizeof(payload)) == FF_FILTER_DOES_NOT_MATCH);
+    CHECK(ff_from_datagram(GOLDEN_KEY, g->datagram, g->datagram_len, NULL,
+                           &header, payload,
+                           g->payload_len - 1) == FF_BUFFER_TOO_SMALL);
+    CHECK(ff_from_datagram(GOLDEN_KEY, g->datagram, 1, NULL, &header, payload,
+                           sizeof(payload)) == FF_CANNOT_PARSE_DATA_FRAME);
+
+    memcpy(datagram, g->datagram, g->datagram_len);
+    datagram[3] ^= 1;
+    CHECK(ff_from_datagram(GOLDEN_KEY, datagram, g->datagram_len, NULL,
+                           &header, payload,
+                           sizeof(payload)) == FF_CANNOT_PARSE_HEADER);
+    datagram[3] ^= 1;
+    datagram[g->datagram_len - 1] ^= 1;
+    CHECK(ff_from_datagram(GOLDEN_KEY, datagram, g->datagram_len, NULL,
+                           &header, payload,
+                           sizeof(payload)) == FF_CANNOT_DECRYPT);
+}
+
+int main(void) {
+    test_golden();
+    test_problems();
+    if (failures > 0) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,37 @@
+//! Datagrams of any bytes are decoded, and their payloads decrypted, without
+//! panicking. Headers decoded are those encoded.
+
+#![no_main]
+
+use aes::Aes128;
+use ccm::{
+    aead::{generic_array::GenericArray, KeyInit},
+    consts::{U4, U7},
+    Ccm,
+};
+use flip_flop_data::{
+    decrypt_payload, from_datagram, parse_datagram, update::from_update_datagram, DataFrame,
+};
+use libfuzzer_sys::fuzz_target;
+
+type AesCcm = Ccm<Aes128, U4, U7>;
+
+const DATAGRAM_LEN: usize = 128;
+
+fuzz_target!(|datagram: &[u8]| {
+    let cipher = AesCcm::new(GenericArray::from_slice(&[0; 16]));
+
+    if let Ok((header, encrypted)) = parse_datagram(datagram) {
+        let frame = postcard::from_bytes::<DataFrame>(datagram).unwrap();
+        assert_eq!(header.to_packed(), frame.header);
+        let mut payload = [0; DATAGRAM_LEN];
+        let _ = decrypt_payload(&cipher, &header, encrypted, &mut payload);
+        let _ = decrypt_payload(&cipher, &header, encrypted, &mut payload[..4]);
+    }
+
+    let mut buf = [0; DATAGRAM_LEN];
+    let len = datagram.len().min(DATAGRAM_LEN);
+    buf[..len].copy_from_slice(&datagram[..len]);
+    let _ = from_datagram(&buf, |_| true, &cipher);
+    let _ = from_update_datagram(&buf, |_| true, &cipher);
+});
@@ -0,0 +1,35 @@
+//! Payloads of any bytes are decoded as each of the messages conveyed without
+//! panicking. A message decoded is decoded the same once encoded again. The
+//! encoding need not be the bytes decoded, given that the trailing fields of
+//! messages are decoded leniently.
+
+#![no_main]
+
+use std::fmt::Debug;
+
+use flip_flop_app::{CommandRequest, EventReply, Logged};
+use flip_flop_data::{
+    discovery::{Identified, Identify},
+    update::{PrepareForUpdate, UpdateMessage, UpdateStatus, UpdateStatusRequest},
+};
+use libfuzzer_sys::fuzz_target;
+use serde::{de::DeserializeOwned, Serialize};
+
+fn decode<T: Debug + DeserializeOwned + PartialEq + Serialize>(payload: &[u8]) {
+    if let Ok(decoded) = postcard::from_bytes::<T>(payload) {
+        let encoded = postcard::to_allocvec(&decoded).unwrap();
+        assert_eq!(postcard::from_bytes::<T>(&encoded).unwrap(), decoded);
+    }
+}
+
+fuzz_target!(|payload: &[u8]| {
+    decode::<Identify>(payload);
+    decode::<Identified>(payload);
+    decode::<PrepareForUpdate>(payload);
+    decode::<UpdateMessage<127>>(payload);
+    decode::<UpdateStatusRequest>(payload);
+    decode::<UpdateStatus>(payload);
+    decode::<CommandRequest<u8>>(payload);
+    decode::<CommandRequest<(u16, Option<u8>)>>(payload);
+    decode::<EventReply<Logged<u8>>>(payload);
+});
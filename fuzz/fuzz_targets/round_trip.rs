@@ -0,0 +1,40 @@
+//! Messages of any value are decoded as they were encoded.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use flip_flop_app::{CommandRequest, EventReply, Logged};
+use flip_flop_data::{
+    discovery::{Identified, Identify},
+    update::{PrepareForUpdate, Update},
+    Header,
+};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+enum Message {
+    Header(Header),
+    Identify(Identify),
+    Identified(Identified),
+    PrepareForUpdate(PrepareForUpdate),
+    Update(Update<127>),
+    CommandRequest(CommandRequest<u8>),
+    EventReply(EventReply<Logged<u8>>),
+}
+
+fn round_trip<T: std::fmt::Debug + serde::de::DeserializeOwned + PartialEq + serde::Serialize>(
+    message: &T,
+) {
+    let encoded = postcard::to_allocvec(message).unwrap();
+    assert_eq!(&postcard::from_bytes::<T>(&encoded).unwrap(), message);
+}
+
+fuzz_target!(|message: Message| match message {
+    Message::Header(header) => assert_eq!(Header::parse(header.to_packed()), Ok(header)),
+    Message::Identify(identify) => round_trip(&identify),
+    Message::Identified(identified) => round_trip(&identified),
+    Message::PrepareForUpdate(prepare) => round_trip(&prepare),
+    Message::Update(update) => round_trip(&update),
+    Message::CommandRequest(request) => round_trip(&request),
+    Message::EventReply(reply) => round_trip(&reply),
+});
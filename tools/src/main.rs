@@ -0,0 +1,268 @@
+//! Decodes the frames captured from a bus, whether a capture of hex or
+//! binary, or a live serial port, describing each of its datagrams. The
+//! payloads of datagrams are decrypted and decoded given a file of the
+//! network's keys. A serial port is read as a file, and so is configured
+//! beforehand e.g. with `stty`. Datagrams are described by a line of text
+//! each, or of JSON e.g. to attach to a bug report, and may also be written
+//! to a pcapng file.
+
+mod sniff;
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    process::ExitCode,
+};
+
+use flip_flop_app::frames::Framing;
+
+use flip_flop_data::pcap::PcapWriter;
+
+use crate::sniff::{parse_hex, Keys, Schema, Sniffer};
+
+const USAGE: &str = "\
+Usage: flip-flop-sniff [OPTIONS] [CAPTURE]
+
+Decodes the frames of a capture, or of stdin when none is given. A serial port
+is read as a capture, and is to be configured beforehand e.g. with stty.
+
+Options:
+  --hex               The capture is pairs of hex digits rather than binary,
+                      ignoring whitespace and text following a #
+  --framing FRAMING   How frames are delimited: cobs (default) or length
+  --keys FILE         The keys of the network, a line of an address,
+                      `discovery` or `update` followed by 32 hex digits each
+  --command SCHEMA    The type of the commands of requests: u8, u16, u32,
+                      u64, i32, bool, bytes or str
+  --json              Describe each datagram by a line of JSON
+  --pcap FILE         Also write each datagram to a pcapng file
+  --help              Print this help";
+
+struct Args {
+    hex: bool,
+    framing: Framing,
+    keys: Keys,
+    schema: Option<Schema>,
+    json: bool,
+    pcap: Option<String>,
+    capture: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        hex: false,
+        framing: Framing::Cobs,
+        keys: Keys::default(),
+        schema: None,
+        json: false,
+        pcap: None,
+        capture: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} expects a value"));
+        match arg.as_str() {
+            "--hex" => parsed.hex = true,
+            "--framing" => {
+                parsed.framing = match value()?.as_str() {
+                    "cobs" => Framing::Cobs,
+                    "length" => Framing::LengthPrefixed,
+                    framing => return Err(format!("unknown framing: {framing}")),
+                }
+            }
+            "--keys" => {
+                let path = value()?;
+                let keys = fs::read_to_string(&path).map_err(|e| format!("{path}: {e}"))?;
+                parsed.keys = keys.parse().map_err(|e| format!("{path}: {e}"))?;
+            }
+            "--command" => parsed.schema = Some(value()?.parse()?),
+            "--json" => parsed.json = true,
+            "--pcap" => parsed.pcap = Some(value()?),
+            "--help" => return Err(USAGE.into()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option: {arg}")),
+            _ if parsed.capture.is_none() => parsed.capture = Some(arg),
+            _ => return Err(format!("unexpected argument: {arg}")),
+        }
+    }
+    Ok(parsed)
+}
+
+// Feed the capture to the sniffer as it is read, so that a live serial
+// port is described as its frames arrive.
+fn sniff(
+    capture: impl Read,
+    hex: bool,
+    sniffer: &mut Sniffer,
+    out: &mut impl Write,
+) -> Result<(), String> {
+    let mut capture = BufReader::new(capture);
+    if hex {
+        for line in capture.lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.split('#').next().unwrap_or_default();
+            let mut bytes = parse_hex(line)?;
+            sniffer.feed(&mut bytes, out).map_err(|e| e.to_string())?;
+        }
+    } else {
+        let mut buf = [0; 256];
+        loop {
+            let len = match capture.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            };
+            sniffer
+                .feed(&mut buf[..len], out)
+                .map_err(|e| e.to_string())?;
+            out.flush().map_err(|e| e.to_string())?;
+        }
+    }
+    writeln!(out, "{}", sniffer.summary()).map_err(|e| e.to_string())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(2);
+        }
+    };
+    let mut sniffer = Sniffer::new(args.framing, args.keys, args.schema);
+    if args.json {
+        sniffer = sniffer.with_json();
+    }
+    if let Some(path) = &args.pcap {
+        let pcap = File::create(path).and_then(|file| PcapWriter::new(Box::new(file) as _));
+        match pcap {
+            Ok(pcap) => sniffer = sniffer.with_pcap(pcap),
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let mut out = io::stdout().lock();
+    let sniffed = match &args.capture {
+        Some(path) => File::open(path)
+            .map_err(|e| format!("{path}: {e}"))
+            .and_then(|capture| sniff(capture, args.hex, &mut sniffer, &mut out)),
+        None => sniff(io::stdin().lock(), args.hex, &mut sniffer, &mut out),
+    };
+    match sniffed {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flip_flop_data::DataSource;
+
+    use super::*;
+
+    const CAPTURE: &str = include_str!("../testdata/capture.hex");
+    const KEYS: &str = include_str!("../testdata/keys.txt");
+    const GOLDEN: &str = include_str!("../testdata/capture.out");
+    const GOLDEN_JSON: &str = include_str!("../testdata/capture.jsonl");
+
+    fn sniffer() -> Sniffer {
+        Sniffer::new(Framing::Cobs, KEYS.parse().unwrap(), Some(Schema::U8))
+    }
+
+    #[test]
+    fn test_golden_output() {
+        let mut out = Vec::new();
+        sniff(CAPTURE.as_bytes(), true, &mut sniffer(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), GOLDEN);
+
+        // The same capture in binary is described the same, however it is
+        // split as it is read.
+        let mut binary = Vec::new();
+        for line in CAPTURE.lines() {
+            binary.extend(parse_hex(line.split('#').next().unwrap()).unwrap());
+        }
+        let mut out = Vec::new();
+        let mut sniffer = sniffer();
+        for chunk in binary.chunks(5) {
+            sniffer.feed(&mut chunk.to_vec(), &mut out).unwrap();
+        }
+        writeln!(out, "{}", sniffer.stats()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), GOLDEN);
+    }
+
+    #[test]
+    fn test_golden_json_output() {
+        let mut out = Vec::new();
+        sniff(
+            CAPTURE.as_bytes(),
+            true,
+            &mut sniffer().with_json(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), GOLDEN_JSON);
+    }
+
+    #[test]
+    fn test_pcap_output() {
+        let path = env::temp_dir().join("flip-flop-sniff-test.pcapng");
+        let pcap = PcapWriter::new(Box::new(File::create(&path).unwrap()) as _).unwrap();
+        let mut sniffer = sniffer().with_pcap(pcap);
+        sniff(CAPTURE.as_bytes(), true, &mut sniffer, &mut Vec::new()).unwrap();
+        let stats = *sniffer.stats();
+        drop(sniffer);
+        let file = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        // Each datagram is written as a packet of its bytes, in the order
+        // received, those of clients as outbound and of servers as inbound.
+        let mut packets = Vec::new();
+        let mut block = &file[..];
+        while !block.is_empty() {
+            let u32_at = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+            if u32_at(0) == 6 {
+                let len = u32_at(20) as usize;
+                let flags = u32_at(28 + len.div_ceil(4) * 4 + 4);
+                packets.push((block[28..28 + len].to_vec(), flags));
+            }
+            block = &block[u32_at(4) as usize..];
+        }
+        assert_eq!(packets.len() as u32, stats.decrypted + stats.undecrypted);
+        let sources = packets
+            .iter()
+            .take(2)
+            .map(|(datagram, flags)| {
+                let (header, _) = flip_flop_data::parse_datagram(datagram).unwrap();
+                (header.source, *flags)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sources, [(DataSource::Client, 2), (DataSource::Server, 1)]);
+    }
+
+    #[test]
+    fn test_args() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()));
+        let parsed = args(&["--hex", "--framing", "length", "--command", "str", "cap"]).unwrap();
+        assert!(parsed.hex);
+        assert!(!parsed.json);
+        assert!(args(&["--json"]).unwrap().json);
+        let parsed_pcap = args(&["--pcap", "out.pcapng"]).unwrap();
+        assert_eq!(parsed_pcap.pcap.as_deref(), Some("out.pcapng"));
+        assert!(args(&["--pcap"]).is_err());
+        assert_eq!(parsed.framing, Framing::LengthPrefixed);
+        assert_eq!(parsed.schema, Some(Schema::Str));
+        assert_eq!(parsed.capture.as_deref(), Some("cap"));
+        assert!(args(&["--framing"]).is_err());
+        assert!(args(&["--command", "f32"]).is_err());
+        assert!(args(&["a", "b"]).is_err());
+        assert!("1 0011".parse::<Keys>().is_err());
+        assert!("x 00000000000000000000000000000000"
+            .parse::<Keys>()
+            .is_err());
+    }
+}
@@ -0,0 +1,522 @@
+//! Decoding the datagrams captured from a bus. Frames are located by a
+//! [FrameReceiver] of the app crate, which parses the header of each, and
+//! the payloads of those with a known key are decrypted and decoded as the
+//! messages conveyed given their address, port and source. The update keys
+//! of the prepare-update commands decrypted are learnt, so that the update
+//! packets following them are decrypted too. Each datagram is described by a
+//! line of text, or of JSON as rendered by the crates' `to_debug_json`, and
+//! may also be written to a pcapng file.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Write as _},
+    io::{self, Write},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use aes::Aes128;
+use ccm::{
+    aead::{generic_array::GenericArray, KeyInit},
+    consts::{U4, U7},
+    Ccm,
+};
+use flip_flop_app::{
+    frames::{FrameError, FrameReceiver, Framing},
+    CommandRequest,
+};
+use flip_flop_data::{
+    debug_json, decrypt_payload,
+    discovery::{Identified, Identify},
+    parse_datagram,
+    pcap::{Direction, PcapWriter, Record},
+    port::Port,
+    update::{
+        from_update_datagram, PrepareForUpdate, UpdateMessage, UpdateStatus, UpdateStatusRequest,
+    },
+    DataFrame, DataSource, Header,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+
+type AesCcm = Ccm<Aes128, U4, U7>;
+
+/// The most bytes of a frame that may be decoded.
+pub const MAX_FRAME_LEN: usize = 512;
+
+// The port that discovery is conveyed on, at the broadcast address.
+const DISCOVERY_PORT: Port = Port::new(0).unwrap();
+
+// The port that updates are conveyed on.
+const UPDATE_PORT: Port = Port::new(1).unwrap();
+
+/// The type of the commands of requests, given that postcard does not
+/// describe the values that it encodes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Schema {
+    U8,
+    U16,
+    U32,
+    U64,
+    I32,
+    Bool,
+    Bytes,
+    Str,
+}
+
+impl Schema {
+    // Decode a command request of the schema's commands.
+    fn command_request(self, payload: &[u8]) -> Message {
+        let name = "CommandRequest";
+        match self {
+            Schema::U8 => typed::<CommandRequest<u8>>(name, payload),
+            Schema::U16 => typed::<CommandRequest<u16>>(name, payload),
+            Schema::U32 => typed::<CommandRequest<u32>>(name, payload),
+            Schema::U64 => typed::<CommandRequest<u64>>(name, payload),
+            Schema::I32 => typed::<CommandRequest<i32>>(name, payload),
+            Schema::Bool => typed::<CommandRequest<bool>>(name, payload),
+            Schema::Bytes => typed::<CommandRequest<Vec<u8>>>(name, payload),
+            Schema::Str => typed::<CommandRequest<String>>(name, payload),
+        }
+    }
+}
+
+impl FromStr for Schema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "u8" => Schema::U8,
+            "u16" => Schema::U16,
+            "u32" => Schema::U32,
+            "u64" => Schema::U64,
+            "i32" => Schema::I32,
+            "bool" => Schema::Bool,
+            "bytes" => Schema::Bytes,
+            "str" => Schema::Str,
+            _ => return Err(format!("unknown command schema: {s}")),
+        })
+    }
+}
+
+/// The keys of a network. A key file has a key on each line, being the
+/// address of the server whose key it is, `discovery` or `update`, followed
+/// by the key as 32 hex digits. Text following a `#` is ignored.
+#[derive(Debug, Default)]
+pub struct Keys {
+    discovery: Option<[u8; 16]>,
+    network: BTreeMap<u8, [u8; 16]>,
+    update: Vec<[u8; 16]>,
+}
+
+impl FromStr for Keys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = Keys::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(name) = fields.next() else {
+                continue;
+            };
+            let key = fields
+                .next()
+                .and_then(|key| parse_hex(key).ok())
+                .and_then(|key| <[u8; 16]>::try_from(key).ok())
+                .filter(|_| fields.next().is_none())
+                .ok_or_else(|| format!("line {}: expected a key of 32 hex digits", i + 1))?;
+            match name {
+                "discovery" => keys.discovery = Some(key),
+                "update" => keys.update.push(key),
+                address => {
+                    let address = address
+                        .parse()
+                        .map_err(|_| format!("line {}: unknown address {address}", i + 1))?;
+                    keys.network.insert(address, key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Counts of the frames received, and of what became of them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct Stats {
+    /// All frames received, whatever became of them.
+    pub frames: u32,
+    /// Frames longer than [MAX_FRAME_LEN].
+    pub too_long: u32,
+    /// Frames that could not be decoded from COBS.
+    pub corrupt: u32,
+    /// Frames that do not convey a datagram.
+    pub undecodable: u32,
+    /// Datagrams whose payload was decrypted.
+    pub decrypted: u32,
+    /// Datagrams whose payload could not be decrypted with the keys known.
+    pub undecrypted: u32,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames: {}, too long: {}, corrupt: {}, undecodable: {}, decrypted: {}, undecrypted: {}",
+            self.frames,
+            self.too_long,
+            self.corrupt,
+            self.undecodable,
+            self.decrypted,
+            self.undecrypted
+        )
+    }
+}
+
+/// Describes each of the datagrams of the bytes captured, a line for each.
+pub struct Sniffer {
+    receiver: FrameReceiver<MAX_FRAME_LEN>,
+    keys: Keys,
+    schema: Option<Schema>,
+    stats: Stats,
+    json: bool,
+    pcap: Option<PcapWriter<Box<dyn Write>>>,
+}
+
+impl Sniffer {
+    /// A sniffer of frames delimited as given, decrypting those of the keys
+    /// given and decoding requests given the schema of their commands.
+    pub fn new(framing: Framing, keys: Keys, schema: Option<Schema>) -> Self {
+        Self {
+            receiver: FrameReceiver::new(framing),
+            keys,
+            schema,
+            stats: Stats::default(),
+            json: false,
+            pcap: None,
+        }
+    }
+
+    /// The sniffer, describing each datagram by a line of JSON rather than
+    /// of text, see [flip_flop_data::debug_json].
+    pub fn with_json(self) -> Self {
+        Self { json: true, ..self }
+    }
+
+    /// The sniffer, also writing each datagram to the pcapng file given,
+    /// timestamped as it is received. Datagrams of clients are written as
+    /// outbound, and those of servers as inbound.
+    pub fn with_pcap(self, pcap: PcapWriter<Box<dyn Write>>) -> Self {
+        Self {
+            pcap: Some(pcap),
+            ..self
+        }
+    }
+
+    /// Describe the datagrams completed by the bytes captured next.
+    pub fn feed(&mut self, chunk: &mut [u8], out: &mut impl Write) -> io::Result<()> {
+        let Self {
+            receiver,
+            keys,
+            schema,
+            stats,
+            json,
+            pcap,
+        } = self;
+        let mut lines = Vec::new();
+        let mut datagrams = Vec::new();
+        receiver.feed(chunk, |frame| {
+            if let (Ok((header, encrypted)), Some(_)) = (&frame, &pcap) {
+                let direction = match header.source {
+                    DataSource::Client => Direction::Outbound,
+                    DataSource::Server => Direction::Inbound,
+                };
+                datagrams.push((direction, datagram(header, encrypted)));
+            }
+            let (text, value) = describe(frame, keys, *schema, stats);
+            lines.push(if *json { value.to_string() } else { text });
+        });
+        for line in lines {
+            writeln!(out, "{line}")?;
+        }
+        if let Some(pcap) = pcap {
+            let timestamp = SystemTime::now();
+            for (direction, datagram) in datagrams {
+                let header = parse_datagram(&datagram).ok().map(|(header, _)| header);
+                pcap.write(&Record {
+                    timestamp,
+                    direction,
+                    datagram: &datagram,
+                    header: header.as_ref(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// What became of the frames received so far.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// A line of what became of the frames received so far, of text or of
+    /// JSON as the datagrams are described.
+    pub fn summary(&self) -> String {
+        if self.json {
+            json!({ "stats": self.stats() }).to_string()
+        } else {
+            self.stats().to_string()
+        }
+    }
+}
+
+/// Parse bytes written as pairs of hex digits, ignoring whitespace.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits = s
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| {
+            c.to_digit(16)
+                .ok_or_else(|| format!("not a hex digit: {c}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if digits.len() % 2 != 0 {
+        return Err("an odd number of hex digits".into());
+    }
+    Ok(digits
+        .chunks(2)
+        .map(|pair| (pair[0] << 4 | pair[1]) as u8)
+        .collect())
+}
+
+// The bytes of a datagram as conveyed, given its header and payload.
+fn datagram(header: &Header, encrypted: &[u8]) -> Vec<u8> {
+    let data_frame = DataFrame {
+        header: header.to_packed(),
+        encrypted_payload: encrypted,
+    };
+    postcard::to_allocvec(&data_frame).unwrap_or_default()
+}
+
+// A frame described by a line of text and by JSON.
+fn describe(
+    frame: Result<(Header, &[u8]), FrameError>,
+    keys: &mut Keys,
+    schema: Option<Schema>,
+    stats: &mut Stats,
+) -> (String, Value) {
+    stats.frames += 1;
+    let error = |text: String| {
+        let value = json!({ "error": text });
+        (text, value)
+    };
+    let (header, encrypted) = match frame {
+        Ok(frame) => frame,
+        Err(FrameError::TooLong) => {
+            stats.too_long += 1;
+            return error("frame too long".into());
+        }
+        Err(FrameError::Corrupt) => {
+            stats.corrupt += 1;
+            return error("corrupt frame".into());
+        }
+        Err(FrameError::Datagram(e)) => {
+            stats.undecodable += 1;
+            return error(format!("undecodable frame: {e:?}"));
+        }
+    };
+    let source = match header.source {
+        DataSource::Client => "client",
+        DataSource::Server => "server",
+    };
+    let prefix = format!(
+        "{source} {}:{} #{}",
+        header.server_address, header.server_port, header.frame_counter
+    );
+    match decode(&header, encrypted, keys, schema) {
+        Some(message) => {
+            stats.decrypted += 1;
+            (
+                format!("{prefix} {}", message.text),
+                json!({ "header": header.to_debug_json(), "message": message.json }),
+            )
+        }
+        None => {
+            stats.undecrypted += 1;
+            (
+                format!("{prefix} undecrypted {}", hex(encrypted)),
+                json!({
+                    "header": header.to_debug_json(),
+                    "undecrypted": debug_json::hex(encrypted),
+                }),
+            )
+        }
+    }
+}
+
+// Decrypt and decode the payload of a datagram, given a key for it.
+fn decode(
+    header: &Header,
+    encrypted: &[u8],
+    keys: &mut Keys,
+    schema: Option<Schema>,
+) -> Option<Message> {
+    let mut buf = [0; MAX_FRAME_LEN];
+    let mut decrypt = |key: &[u8; 16]| {
+        let cipher = AesCcm::new(GenericArray::from_slice(key));
+        let len = decrypt_payload(&cipher, header, encrypted, &mut buf).ok()?;
+        Some(buf[..len].to_vec())
+    };
+    let broadcast = header.server_address == 0;
+    let client = header.source == DataSource::Client;
+
+    if broadcast && header.server_port == DISCOVERY_PORT {
+        let payload = decrypt(keys.discovery.as_ref()?)?;
+        return Some(if client {
+            typed::<Identify>("Identify", &payload)
+        } else {
+            typed::<Identified>("Identified", &payload)
+        });
+    }
+
+    if broadcast && header.server_port == UPDATE_PORT && client {
+        // Packets of an update are encrypted under an update key, whereas
+        // the prepare-update commands preceding them are encrypted under
+        // the key of each server prepared.
+        let mut datagram = [0; MAX_FRAME_LEN];
+        let data_frame = DataFrame {
+            header: header.to_packed(),
+            encrypted_payload: encrypted,
+        };
+        postcard::to_slice(&data_frame, &mut datagram).ok()?;
+        for key in &keys.update {
+            let cipher = AesCcm::new(GenericArray::from_slice(key));
+            if let Ok((_, payload)) = from_update_datagram(&datagram, |_| true, &cipher) {
+                return Some(update_message(&payload));
+            }
+        }
+        let payload = keys.network.values().find_map(&mut decrypt)?;
+        if let Ok(prepare) = postcard::from_bytes::<PrepareForUpdate>(&payload) {
+            if !keys.update.contains(&prepare.update_key.0) {
+                keys.update.push(prepare.update_key.0);
+            }
+        }
+        return Some(typed::<PrepareForUpdate>("PrepareForUpdate", &payload));
+    }
+
+    let payload = decrypt(keys.network.get(&header.server_address)?)?;
+    Some(match (client, header.server_port == UPDATE_PORT, schema) {
+        (true, true, _) => typed::<UpdateStatusRequest>("UpdateStatusRequest", &payload),
+        (false, true, _) => typed::<UpdateStatus>("UpdateStatus", &payload),
+        (true, false, Some(schema)) => schema.command_request(&payload),
+        _ => Message::payload(&payload, None),
+    })
+}
+
+// The bytes of an update are summarised by their offset and length.
+fn update_message(payload: &[u8]) -> Message {
+    match postcard::from_bytes::<UpdateMessage<MAX_FRAME_LEN>>(payload) {
+        Ok(UpdateMessage::Update(update)) => Message::new(
+            "Update",
+            format!(
+                "Update {{ byte_offset: {}, len: {} }}",
+                update.byte_offset,
+                update.bytes.len()
+            ),
+            update.to_debug_json(),
+        ),
+        Ok(message) => {
+            let text = format!("{message:?}");
+            Message::new("UpdateMessage", text.clone(), rendered(text, payload))
+        }
+        Err(_) => Message::payload(payload, None),
+    }
+}
+
+// A payload decoded as the type named, or its bytes should it not be one.
+fn typed<T: DeserializeOwned + DebugJson>(name: &str, payload: &[u8]) -> Message {
+    match postcard::from_bytes::<T>(payload) {
+        Ok(message) => Message::new(name, format!("{message:?}"), message.debug_json(payload)),
+        Err(_) => Message::payload(payload, Some(name)),
+    }
+}
+
+// A message decoded, described by a line of text and by JSON.
+struct Message {
+    text: String,
+    json: Value,
+}
+
+impl Message {
+    // A message of the type named, its JSON being that rendered of it.
+    fn new(name: &str, text: String, mut rendered: Value) -> Self {
+        rendered["type"] = json!(name);
+        Self {
+            text,
+            json: rendered,
+        }
+    }
+
+    // A payload that is not of the type named, if any, or of no known type.
+    fn payload(payload: &[u8], not_a: Option<&str>) -> Self {
+        let text = match not_a {
+            Some(name) => format!("payload {} (not a {name})", hex(payload)),
+            None => format!("payload {}", hex(payload)),
+        };
+        Self {
+            text,
+            json: json!({ "type": "payload", "not_a": not_a, "raw": debug_json::hex(payload) }),
+        }
+    }
+}
+
+// The JSON of a message without a rendering of its own, being its Debug.
+fn rendered(debug: String, payload: &[u8]) -> Value {
+    json!({ "decoded": debug, "raw": debug_json::hex(payload) })
+}
+
+// Rendering the messages decoded as JSON, being the crates' own rendering
+// for those that have one, and their Debug otherwise.
+trait DebugJson: Debug {
+    fn debug_json(&self, payload: &[u8]) -> Value {
+        rendered(format!("{self:?}"), payload)
+    }
+}
+
+impl DebugJson for Identify {
+    fn debug_json(&self, _: &[u8]) -> Value {
+        self.to_debug_json()
+    }
+}
+
+impl DebugJson for Identified {
+    fn debug_json(&self, _: &[u8]) -> Value {
+        self.to_debug_json()
+    }
+}
+
+impl DebugJson for PrepareForUpdate {
+    fn debug_json(&self, _: &[u8]) -> Value {
+        self.to_debug_json()
+    }
+}
+
+impl<C: Debug + Serialize> DebugJson for CommandRequest<C> {
+    fn debug_json(&self, _: &[u8]) -> Value {
+        self.to_debug_json()
+    }
+}
+
+impl DebugJson for UpdateStatusRequest {}
+
+impl DebugJson for UpdateStatus {}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::from("[");
+    for (i, b) in bytes.iter().enumerate() {
+        let separator = if i == 0 { "" } else { " " };
+        let _ = write!(s, "{separator}{b:02x}");
+    }
+    s.push(']');
+    s
+}